@@ -11,7 +11,14 @@
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Where a compressed OS is unpacked to - the start of `RAM_OS` in
+/// `memory.x`.
+const OS_RAM_START: u32 = 0x2000_0000;
+
+/// How big the ROM disk is - the length of `FLASH_ROMDISK` in `memory.x`.
+const ROMDISK_SIZE: usize = 508 * 1024;
 
 fn main() {
 	// Put `memory.x` in our output directory and ensure it's
@@ -29,6 +36,10 @@ fn main() {
 	// `memory.x` is changed.
 	println!("cargo:rerun-if-changed=memory.x");
 
+	// The git version and hash below go stale unless we run again when the
+	// checkout changes.
+	rerun_if_git_changes();
+
 	// Get git version
 	if let Ok(cmd_output) = std::process::Command::new("git")
 		.arg("describe")
@@ -46,4 +57,192 @@ fn main() {
 	} else {
 		println!("cargo:rustc-env=BIOS_VERSION={}", env!("CARGO_PKG_VERSION"));
 	}
+
+	// Get git commit hash
+	let git_hash = std::process::Command::new("git")
+		.arg("rev-parse")
+		.arg("--short=12")
+		.arg("HEAD")
+		.output()
+		.ok()
+		.and_then(|cmd_output| String::from_utf8(cmd_output.stdout).ok())
+		.map(|s| s.trim().to_owned())
+		.filter(|s| !s.is_empty())
+		.unwrap_or_else(|| String::from("unknown"));
+	println!("cargo:rustc-env=BIOS_GIT_HASH={}", git_hash);
+
+	// Get build time. Honour SOURCE_DATE_EPOCH so reproducible builds are
+	// possible.
+	println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+	let build_secs = env::var("SOURCE_DATE_EPOCH")
+		.ok()
+		.and_then(|s| s.parse::<u64>().ok())
+		.unwrap_or_else(|| {
+			std::time::SystemTime::now()
+				.duration_since(std::time::UNIX_EPOCH)
+				.map(|d| d.as_secs())
+				.unwrap_or(0)
+		});
+	println!("cargo:rustc-env=BIOS_BUILD_TIME={}", iso8601(build_secs));
+
+	// Get the list of enabled Cargo features
+	let mut features: Vec<String> = env::vars()
+		.filter_map(|(key, _value)| {
+			key.strip_prefix("CARGO_FEATURE_")
+				.map(|f| f.to_lowercase().replace('_', "-"))
+		})
+		.collect();
+	features.sort();
+	println!("cargo:rustc-env=BIOS_FEATURES={}", features.join(","));
+
+	// Get the OS image we embed - compressed, if asked - and work out the
+	// CRC-32 for its header, so we can check it hasn't been corrupted in
+	// flash.
+	let (os_image, load_address, unpacked_len) =
+		if env::var_os("CARGO_FEATURE_COMPRESSED_OS").is_some() {
+			println!("cargo:rerun-if-changed=src/ram2000.bin");
+			let ram_image = std::fs::read("src/ram2000.bin").expect(
+				"The compressed-os feature needs an OS linked to run from RAM, \
+				 copied to src/ram2000.bin (see the README)",
+			);
+			(
+				lz4_flex::block::compress(&ram_image),
+				OS_RAM_START,
+				ram_image.len(),
+			)
+		} else {
+			println!("cargo:rerun-if-changed=src/flash1002.bin");
+			let flash_image = std::fs::read("src/flash1002.bin").unwrap();
+			let len = flash_image.len();
+			(flash_image, 0, len)
+		};
+	File::create(out.join("os_image.bin"))
+		.unwrap()
+		.write_all(&os_image)
+		.unwrap();
+	println!("cargo:rustc-env=OS_IMAGE_CRC32={}", crc32(&os_image));
+	println!("cargo:rustc-env=OS_IMAGE_LOAD_ADDRESS={}", load_address);
+	println!("cargo:rustc-env=OS_IMAGE_UNPACKED_LEN={}", unpacked_len);
+
+	// Make the ROM disk from whatever is in the `romdisk` directory
+	println!("cargo:rerun-if-changed=romdisk");
+	let romdisk = build_romdisk(Path::new("romdisk"));
+	File::create(out.join("romdisk.img"))
+		.unwrap()
+		.write_all(&romdisk)
+		.unwrap();
+}
+
+/// Ask Cargo to run this script again whenever we move to another commit or
+/// branch, or the git index changes.
+///
+/// Does nothing if we aren't being built from a git checkout.
+fn rerun_if_git_changes() {
+	let git_dir = match std::process::Command::new("git")
+		.arg("rev-parse")
+		.arg("--git-dir")
+		.output()
+	{
+		Ok(cmd_output) if cmd_output.status.success() => {
+			PathBuf::from(String::from_utf8_lossy(&cmd_output.stdout).trim())
+		}
+		_ => return,
+	};
+	let mut watched = vec![
+		git_dir.join("HEAD"),
+		git_dir.join("index"),
+		git_dir.join("packed-refs"),
+	];
+	// HEAD usually names a branch, and it's the branch's ref which changes
+	// when we commit
+	if let Ok(head) = std::fs::read_to_string(git_dir.join("HEAD")) {
+		if let Some(branch) = head.trim().strip_prefix("ref: ") {
+			watched.push(git_dir.join(branch));
+		}
+	}
+	for path in watched.iter().filter(|path| path.exists()) {
+		println!("cargo:rerun-if-changed={}", path.display());
+	}
+}
+
+/// Make a FAT disk image holding the files in the given directory.
+///
+/// If the directory doesn't exist, the image is empty.
+fn build_romdisk(dir: &Path) -> Vec<u8> {
+	if !dir.is_dir() {
+		return Vec::new();
+	}
+	let mut image = std::io::Cursor::new(vec![0u8; ROMDISK_SIZE]);
+	fatfs::format_volume(
+		&mut image,
+		fatfs::FormatVolumeOptions::new().volume_label(*b"NEOTRON ROM"),
+	)
+	.unwrap();
+	{
+		let fs = fatfs::FileSystem::new(&mut image, fatfs::FsOptions::new()).unwrap();
+		copy_into_romdisk(dir, &fs.root_dir());
+	}
+	image.into_inner()
+}
+
+/// Copy the contents of a directory into the ROM disk, including any
+/// sub-directories.
+fn copy_into_romdisk<T: fatfs::ReadWriteSeek>(src: &Path, dest: &fatfs::Dir<'_, T>) {
+	let mut entries: Vec<_> = std::fs::read_dir(src)
+		.unwrap()
+		.map(|entry| entry.unwrap().path())
+		.collect();
+	// Sort them, so the image is the same every time
+	entries.sort();
+	for path in entries {
+		println!("cargo:rerun-if-changed={}", path.display());
+		let name = path.file_name().unwrap().to_str().unwrap();
+		if path.is_dir() {
+			let dir = dest.create_dir(name).unwrap();
+			copy_into_romdisk(&path, &dir);
+		} else {
+			let contents = std::fs::read(&path).unwrap();
+			let mut file = dest.create_file(name).unwrap();
+			file.write_all(&contents).unwrap();
+		}
+	}
+}
+
+/// Calculate a CRC-32 (IEEE), in the same way as `bootslot::crc32`.
+fn crc32(data: &[u8]) -> u32 {
+	let mut crc: u32 = 0xFFFF_FFFF;
+	for b in data.iter() {
+		crc ^= *b as u32;
+		for _ in 0..8 {
+			let mask = (crc & 1).wrapping_neg();
+			crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+		}
+	}
+	!crc
+}
+
+/// Convert seconds since the UNIX epoch into an ISO 8601 UTC date/time.
+///
+/// Uses Howard Hinnant's `civil_from_days` algorithm.
+fn iso8601(secs: u64) -> String {
+	let days = (secs / 86400) as i64;
+	let secs_of_day = secs % 86400;
+	let z = days + 719_468;
+	let era = z.div_euclid(146_097);
+	let doe = z.rem_euclid(146_097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+	format!(
+		"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+		year,
+		month,
+		day,
+		secs_of_day / 3600,
+		(secs_of_day / 60) % 60,
+		secs_of_day % 60
+	)
 }
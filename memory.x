@@ -17,19 +17,47 @@ MEMORY {
      * KiB for the BIOS, leaving the rest
      * for the OS and any user applications.
      */
-    FLASH : ORIGIN = 0x10000100, LENGTH = 128K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 128K - 0x100 - 1K
     /*
-     * This is the remainder of the 2048 KiB flash chip.
+     * The last 1 KiB of the BIOS flash holds the table of extra API calls,
+     * so the OS can always find it at 0x1001FC00.
      */
-    FLASH_OS : ORIGIN = 0x10020000, LENGTH = 2048K - 128K
+    FLASH_EXT_API : ORIGIN = 0x1001FC00, LENGTH = 1K
+    /*
+     * The next 380 KiB is for the OS.
+     */
+    FLASH_OS : ORIGIN = 0x10020000, LENGTH = 384K - 4K
+    /*
+     * The last 4 KiB sector of the OS area holds the length and CRC of the
+     * OS image, so we can check it before we boot it.
+     */
+    FLASH_OS_HEADER : ORIGIN = 0x1007F000, LENGTH = 4K
+    /*
+     * The next 1024 KiB is for data. The OS can erase and program it through
+     * the BIOS, for its own data or for updates.
+     */
+    FLASH_DATA : ORIGIN = 0x10080000, LENGTH = 1024K
+    /*
+     * This is the remainder of the 2048 KiB flash chip, apart from the last
+     * sector. It holds a read-only disk image, built from the `romdisk`
+     * directory.
+     */
+    FLASH_ROMDISK : ORIGIN = 0x10180000, LENGTH = 512K - 4K
+    /*
+     * The last 4 KiB sector holds a copy of the BIOS configuration, for
+     * systems without a battery-backed RTC.
+     */
+    FLASH_CONFIG : ORIGIN = 0x101FF000, LENGTH = 4K
     /*
      * This is the bottom of the four striped banks of SRAM in the RP2040.
      */
-    RAM_OS : ORIGIN = 0x20000000, LENGTH = 0x3C000
+    RAM_OS : ORIGIN = 0x20000000, LENGTH = 0x38000
     /*
-     * This is the top of the four striped banks of SRAM in the RP2040.
+     * This is the top of the four striped banks of SRAM in the RP2040. It
+     * must stay a power of two in size, and start on a multiple of its size,
+     * so the MPU can protect it.
      */
-    RAM : ORIGIN = 0x2003C000, LENGTH = 16K
+    RAM : ORIGIN = 0x20038000, LENGTH = 32K
     /*
      * This is the fifth bank, a 4KB block. We use this for Core 0 Stack.
      */
@@ -54,13 +82,24 @@ _stack_start = ORIGIN(RAM_CORE0_STACK) + LENGTH(RAM_CORE0_STACK);
 _core1_stack_bottom = ORIGIN(RAM_CORE1_STACK);
 _core1_stack_len = LENGTH(RAM_CORE1_STACK);
 
+/*
+ * This is where the BIOS keeps its static variables, so we can protect them.
+ */
+_bios_ram_start = ORIGIN(RAM);
+_bios_ram_len = LENGTH(RAM);
+
 /*
  * Export some symbols to tell the BIOS where it might find the OS.
  */
 _flash_os_start = ORIGIN(FLASH_OS);
 _flash_os_len = LENGTH(FLASH_OS);
+_flash_os_header_start = ORIGIN(FLASH_OS_HEADER);
 _ram_os_start = ORIGIN(RAM_OS);
 _ram_os_len = LENGTH(RAM_OS);
+_flash_data_start = ORIGIN(FLASH_DATA);
+_flash_data_len = LENGTH(FLASH_DATA);
+_flash_config_start = ORIGIN(FLASH_CONFIG);
+_flash_config_len = LENGTH(FLASH_CONFIG);
 
 SECTIONS {
     /* ### RP2040 Boot loader */
@@ -69,11 +108,29 @@ SECTIONS {
         KEEP(*(.boot2));
     } > BOOT2
 
+    /* ### Extra BIOS API table */
+    .flash_ext_api ORIGIN(FLASH_EXT_API) :
+    {
+        KEEP(*(.flash_ext_api));
+    } > FLASH_EXT_API
+
     /* ### Neotron OS */
     .flash_os ORIGIN(FLASH_OS) :
     {
         KEEP(*(.flash_os));
     } > FLASH_OS
+
+    /* ### ROM disk */
+    .flash_romdisk ORIGIN(FLASH_ROMDISK) :
+    {
+        KEEP(*(.flash_romdisk));
+    } > FLASH_ROMDISK
+
+    /* ### Neotron OS header */
+    .flash_os_header ORIGIN(FLASH_OS_HEADER) :
+    {
+        KEEP(*(.flash_os_header));
+    } > FLASH_OS_HEADER
 } INSERT BEFORE .text;
 
 
@@ -0,0 +1,1428 @@
+//! # Audio output for the Neotron Pico
+//!
+//! The Neotron Pico has a TI TLV320AIC23B audio codec. We set it up over the
+//! I2C bus it shares with the RTC, and feed it 16-bit stereo samples over
+//! I2S. The codec makes its own master clock from its 12 MHz crystal (in
+//! what TI call *USB mode*), and we generate the bit clock and word clock.
+//!
+//! | Signal | Pin    |
+//! |--------|--------|
+//! | DAC    | GPIO22 |
+//! | ADC    | GPIO26 |
+//! | BCLK   | GPIO27 |
+//! | LRCLK  | GPIO28 |
+//!
+//! A state machine on PIO1 shifts the samples out, fed by two DMA channels
+//! which take it in turns to play from a pair of small buffers. Whenever
+//! one buffer finishes, the DMA_IRQ_1 interrupt refills it from a queue of
+//! samples that the OS tops up.
+//!
+//! The pins are only taken over (via `board::reserve`) when the OS first
+//! configures the output.
+//!
+//! On top of that main output, there are `NUM_MIX_CHANNELS` mixer channels,
+//! so an OS can play a few sounds at once without a mixer of its own. Each
+//! has its own queue, sample format, sample rate and volume. When the
+//! interrupt refills a buffer, it steps through each channel's queue at that
+//! channel's rate (in 16.16 fixed point, interpolating between frames),
+//! scales the samples by the channel's volume, and adds them in, clipping
+//! anything too loud. The mixer channels only play while the main output is
+//! running.
+//!
+//! Audio input (from the line input or the microphone) comes back from the
+//! codec's ADC on GPIO26. A second state machine on PIO1 follows the bit
+//! and word clocks the first one makes, and a DMA channel copies each frame
+//! into a ring buffer, restarted at the end by a second DMA channel, so no
+//! interrupt is needed. The OS drains the ring with `input_read`, and if it
+//! falls more than `INPUT_RING_LEN` frames behind, the oldest frames are
+//! lost. The codec runs its ADC and DAC at the same rate, so input and
+//! output always share a sample rate.
+//!
+//! The master volume sets the codec's headphone amplifier, in 1 dB steps,
+//! and muting uses the codec's soft mute on the DAC (so it covers both
+//! outputs). The line output, which feeds the speaker amplifier, is always
+//! at line level. We watch the headphone socket's detect switch (on the I/O
+//! expander): while headphones are plugged in the speaker amplifier is
+//! turned off, and while they aren't the headphone amplifier is muted. The
+//! OS can change the volume and mute before the output is started - we
+//! remember them for when it is.
+//!
+//! For key clicks, alerts and the ASCII BEL, `tone` plays a square wave on
+//! top of everything else, generated by the interrupt, so the OS needn't
+//! stream any samples. It starts the output if the OS hasn't. On boards
+//! without the codec, it plays on the `beeper` instead.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{beeper, board, config, neobus, rtc};
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use defmt::{debug, info, warn};
+use heapless::spsc::{Consumer, Producer, Queue};
+use rp_pico::hal::{self, pac, pio::PIOExt};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The sample formats the OS can give us.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum SampleFormat {
+	/// Unsigned 8-bit samples, one channel
+	EightBitMono = 0,
+	/// Unsigned 8-bit samples, left then right
+	EightBitStereo = 1,
+	/// Signed little-endian 16-bit samples, one channel
+	SixteenBitMono = 2,
+	/// Signed little-endian 16-bit samples, left then right
+	SixteenBitStereo = 3,
+}
+
+/// The ways in which audio output can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// `init` hasn't been called, or failed
+	NotReady,
+	/// We can't play at that sample rate
+	BadSampleRate,
+	/// We don't know that sample format
+	BadFormat,
+	/// One of our pins is being used for something else
+	PinInUse(board::PinUse),
+	/// The codec didn't answer on the I2C bus
+	NoCodec(rtc::Error),
+	/// There's no such mixer channel
+	NoSuchChannel,
+	/// We don't know that input source
+	BadSource,
+	/// We can't play a tone at that frequency
+	BadFrequency,
+}
+
+/// Where audio input comes from.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum InputSource {
+	/// The line input
+	LineIn = 0,
+	/// The microphone input
+	Microphone = 1,
+}
+
+/// The debounced state of the headphone detect switch.
+struct HeadphoneDetect {
+	/// Are there headphones plugged in, as far as the codec knows?
+	present: bool,
+	/// What the switch said last time we looked
+	raw: bool,
+	/// When `raw` last changed
+	raw_changed_at: u32,
+}
+
+/// Where the DMA_IRQ_1 interrupt is up to in a mixer channel's queue.
+#[derive(Copy, Clone)]
+struct Resampler {
+	/// How far we are from `prev` to `next`, in 16.16 fixed point
+	phase: u32,
+	/// The frame we are moving away from, as in `BUFFERS`
+	prev: u32,
+	/// The frame we are moving towards, as in `BUFFERS`
+	next: u32,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The pin the samples go out on
+const DATA_PIN: u8 = 22;
+
+/// The pin the input samples come in on
+const ADC_PIN: u8 = 26;
+
+/// The I2S bit clock pin. The word clock pin must be the next one up.
+const BCLK_PIN: u8 = 27;
+
+/// The I2S word (left/right) clock pin
+const LRCLK_PIN: u8 = 28;
+
+/// The PIO1 function number, for `GPIOx_CTRL.FUNCSEL`
+const FUNCSEL_PIO1: u8 = 7;
+
+/// We use state machine 0 on PIO1
+const STATE_MACHINE: usize = 0;
+
+/// We use state machine 1 on PIO1 for the input
+const INPUT_STATE_MACHINE: usize = 1;
+
+/// PIO clock cycles per stereo frame (two per bit, 16 bits per channel)
+const CYCLES_PER_FRAME: u32 = 64;
+
+/// DMA channel for the first buffer (the video uses channels 0 and 1)
+const DMA_CHAN_A: usize = 2;
+
+/// DMA channel for the second buffer
+const DMA_CHAN_B: usize = 3;
+
+/// DMA channel which copies input frames into `INPUT_RING` (the Neotron
+/// Bus uses channels 4 to 6)
+const DMA_CHAN_IN: usize = 7;
+
+/// DMA channel which points `DMA_CHAN_IN` back at the start of
+/// `INPUT_RING`, and restarts it
+const DMA_CHAN_IN_RESTART: usize = 8;
+
+/// The TREQ_SEL value for a DMA channel which runs as fast as it can
+const DMA_TREQ_PERMANENT: u32 = 0x3F;
+
+/// How many stereo frames of input we keep (at the codec's rate). About
+/// 5 ms at 48 kHz - the BIOS is short of RAM.
+pub const INPUT_RING_LEN: usize = 256;
+
+/// How many stereo frames each DMA buffer holds
+const BLOCK_LEN: usize = 64;
+
+/// How many stereo frames the OS can queue up (one less than this, in fact)
+const QUEUE_LEN: usize = 256;
+
+/// How many mixer channels there are, on top of the main output
+pub const NUM_MIX_CHANNELS: usize = 4;
+
+/// How many stereo frames each mixer channel can queue up (one less than
+/// this, in fact). The BIOS is short of RAM, so these are smaller than the
+/// main queue.
+const MIX_QUEUE_LEN: usize = 128;
+
+/// The slowest sample rate a mixer channel can play at
+pub const MIN_MIX_RATE_HZ: u32 = 4_000;
+
+/// The fastest sample rate a mixer channel can play at
+pub const MAX_MIX_RATE_HZ: u32 = 48_000;
+
+/// A mixer channel's volume at full volume
+const MIX_FULL_VOLUME: u16 = 256;
+
+/// One in 16.16 fixed point
+const MIX_ONE: u32 = 1 << 16;
+
+/// The codec's 7-bit I2C address (with CS tied low)
+pub const CODEC_ADDRESS: u8 = 0x1A;
+
+/// Codec register: left line input volume
+const CODEC_LEFT_LINE_VOLUME: u8 = 0x00;
+
+/// Codec register: left headphone volume
+const CODEC_LEFT_HP_VOLUME: u8 = 0x02;
+
+/// Codec register: analogue audio path
+const CODEC_ANALOGUE_PATH: u8 = 0x04;
+
+/// Codec register: digital audio path
+const CODEC_DIGITAL_PATH: u8 = 0x05;
+
+/// Codec register: power down control
+const CODEC_POWER_DOWN: u8 = 0x06;
+
+/// Codec register: digital audio interface format
+const CODEC_INTERFACE_FORMAT: u8 = 0x07;
+
+/// Codec register: sample rate control
+const CODEC_SAMPLE_RATE: u8 = 0x08;
+
+/// Codec register: digital interface activation
+const CODEC_ACTIVE: u8 = 0x09;
+
+/// Codec register: reset
+const CODEC_RESET: u8 = 0x0F;
+
+/// Codec headphone volume for 0 dB. Each step down is 1 dB quieter.
+const CODEC_HP_VOLUME_MAX: u16 = 0x79;
+
+/// The quietest codec headphone volume (-73 dB). Anything below this is
+/// muted.
+const CODEC_HP_VOLUME_MIN: u16 = 0x30;
+
+/// How long the headphone detect switch must be still before we believe it
+const HEADPHONE_DETECT_DEBOUNCE_MS: u32 = 100;
+
+/// The pitch of the beep for an ASCII BEL
+pub const BEL_FREQUENCY_HZ: u32 = 880;
+
+/// The length of the beep for an ASCII BEL
+pub const BEL_DURATION_MS: u32 = 100;
+
+/// How loud a tone is (a quarter of full scale, so there's room for
+/// everything else)
+const TONE_LEVEL: i32 = 0x2000;
+
+/// How many frames have been sent to the codec (wrapping). Only written by
+/// the DMA_IRQ_1 interrupt.
+static FRAME_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// How far through a cycle of the tone we move for each frame sent to the
+/// codec, as a fraction of 2^32. Only written from Core 0.
+static TONE_STEP: AtomicU32 = AtomicU32::new(0);
+
+/// The value of `FRAME_COUNT` at which the tone stops. Only written from
+/// Core 0.
+static TONE_END: AtomicU32 = AtomicU32::new(0);
+
+/// How far through a cycle of the tone we are, as a fraction of 2^32.
+///
+/// Only accessed by the DMA_IRQ_1 interrupt.
+static mut TONE_PHASE: u32 = 0;
+
+/// Two stereo buffers, played in turn by `DMA_CHAN_A` and `DMA_CHAN_B`. Each
+/// word is the right sample in the top half and the left sample in the
+/// bottom half.
+///
+/// Only accessed by the DMA engine, and by the DMA_IRQ_1 interrupt once
+/// `init` has finished.
+static mut BUFFERS: [[u32; BLOCK_LEN]; 2] = [[0; BLOCK_LEN]; 2];
+
+/// Stereo frames waiting to be played, in the same form as `BUFFERS`.
+///
+/// Only accessed through `PRODUCER` and `CONSUMER`.
+static mut QUEUE: Queue<u32, QUEUE_LEN> = Queue::new();
+
+/// The writing end of `QUEUE`.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut PRODUCER: Option<Producer<'static, u32, QUEUE_LEN>> = None;
+
+/// The reading end of `QUEUE`.
+///
+/// Only accessed from the DMA_IRQ_1 interrupt (once `init` has finished).
+static mut CONSUMER: Option<Consumer<'static, u32, QUEUE_LEN>> = None;
+
+/// A mixer channel queue, before it's split.
+const EMPTY_MIX_QUEUE: Queue<u32, MIX_QUEUE_LEN> = Queue::new();
+
+/// Stereo frames waiting to be mixed in, for each mixer channel, in the
+/// same form as `BUFFERS`.
+///
+/// Only accessed through `MIX_PRODUCERS` and `MIX_CONSUMERS`.
+static mut MIX_QUEUES: [Queue<u32, MIX_QUEUE_LEN>; NUM_MIX_CHANNELS] =
+	[EMPTY_MIX_QUEUE; NUM_MIX_CHANNELS];
+
+/// The writing ends of `MIX_QUEUES`.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut MIX_PRODUCERS: [Option<Producer<'static, u32, MIX_QUEUE_LEN>>; NUM_MIX_CHANNELS] =
+	[None, None, None, None];
+
+/// The reading ends of `MIX_QUEUES`.
+///
+/// Only accessed from the DMA_IRQ_1 interrupt (once `init` has finished).
+static mut MIX_CONSUMERS: [Option<Consumer<'static, u32, MIX_QUEUE_LEN>>; NUM_MIX_CHANNELS] =
+	[None, None, None, None];
+
+/// The format and sample rate of each mixer channel, or `None` if it's
+/// stopped.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut MIX_SETTINGS: [Option<(SampleFormat, u32)>; NUM_MIX_CHANNELS] = [None; NUM_MIX_CHANNELS];
+
+/// A stopped mixer channel's step.
+#[allow(clippy::declare_interior_mutable_const)]
+const MIX_STOPPED: AtomicU32 = AtomicU32::new(0);
+
+/// How far through its queue each mixer channel moves for each frame sent
+/// to the codec, in 16.16 fixed point. Zero means the channel is stopped,
+/// and the interrupt throws away anything queued.
+static MIX_STEPS: [AtomicU32; NUM_MIX_CHANNELS] = [MIX_STOPPED; NUM_MIX_CHANNELS];
+
+/// A mixer channel's volume, before it has been set.
+#[allow(clippy::declare_interior_mutable_const)]
+const MIX_DEFAULT_VOLUME: AtomicU16 = AtomicU16::new(MIX_FULL_VOLUME);
+
+/// Each mixer channel's volume, where `MIX_FULL_VOLUME` leaves the samples
+/// as they are.
+static MIX_VOLUMES: [AtomicU16; NUM_MIX_CHANNELS] = [MIX_DEFAULT_VOLUME; NUM_MIX_CHANNELS];
+
+/// A mixer channel which hasn't played anything yet.
+const SILENT_RESAMPLER: Resampler = Resampler {
+	phase: 0,
+	prev: 0,
+	next: 0,
+};
+
+/// Where each mixer channel is up to.
+///
+/// Only accessed from the DMA_IRQ_1 interrupt.
+static mut RESAMPLERS: [Resampler; NUM_MIX_CHANNELS] = [SILENT_RESAMPLER; NUM_MIX_CHANNELS];
+
+/// Input frames, as they came from the codec: the left sample in the top
+/// half and the right sample in the bottom half.
+///
+/// Only written by the DMA engine, and only read from Core 0.
+static mut INPUT_RING: [u32; INPUT_RING_LEN] = [0; INPUT_RING_LEN];
+
+/// The address of `INPUT_RING`, for `DMA_CHAN_IN_RESTART` to copy.
+///
+/// Only written from Core 0, before the DMA engine reads it.
+static mut INPUT_RING_ADDRESS: u32 = 0;
+
+/// The next frame in `INPUT_RING` that the OS hasn't had.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut INPUT_READ: usize = 0;
+
+/// The format and source the OS last asked for, or `None` if the input
+/// hasn't been started.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut INPUT: Option<(SampleFormat, InputSource)> = None;
+
+/// The address of the input state machine's RX FIFO, and its DREQ number.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut INPUT_FIFO: Option<(u32, u8)> = None;
+
+/// The address of the state machine's TX FIFO, and its DREQ number.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut FIFO: Option<(u32, u8)> = None;
+
+/// The system clock frequency, which clocks the PIO.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut SYS_CLOCK_HZ: u32 = 0;
+
+/// The format and sample rate the OS last asked for, or `None` if the
+/// output hasn't been started.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut CURRENT: Option<(SampleFormat, u32)> = None;
+
+/// The master volume, from 0 (silent) to 255 (0 dB).
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut MASTER_VOLUME: u8 = 255;
+
+/// Is the DAC muted?
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut MUTED: bool = false;
+
+/// The headphone detect switch.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut HEADPHONES: HeadphoneDetect = HeadphoneDetect {
+	present: false,
+	raw: false,
+	raw_changed_at: 0,
+};
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Load the I2S program into PIO1, and get the DMA channels ready.
+///
+/// Nothing is output until `configure` is called. This must be called after
+/// `vga::init`, which resets the DMA controller.
+///
+/// Gives back PIO1, and its third state machine, for `psram`.
+pub fn init(
+	pio: pac::PIO1,
+	resets: &mut pac::RESETS,
+	sys_clock_hz: u32,
+) -> (
+	hal::pio::PIO<pac::PIO1>,
+	hal::pio::UninitStateMachine<(pac::PIO1, hal::pio::SM2)>,
+) {
+	let (mut pio, sm0, sm1, sm2, _sm3) = pio.split(resets);
+
+	// This program clocks out one stereo frame per 32-bit word, MSB first,
+	// in I2S format (each channel's data starts one bit clock after the word
+	// clock changes). It takes two PIO clock cycles per bit. Side-set bit 0
+	// is BCLK and side-set bit 1 is LRCLK.
+	//
+	// Note: autopull should be set to 32-bits, OSR is set to shift left.
+	let program = pio_proc::pio_asm!(
+		".side_set 2"
+		".wrap_target"
+		"set x, 14 side 0b11"
+		// The right channel, plus the LSB going out as LRCLK drops
+		"right:"
+			"out pins, 1 side 0b10"
+			"jmp x-- right side 0b11"
+		"out pins, 1 side 0b00"
+		"set x, 14 side 0b01"
+		// The left channel, plus the LSB going out as LRCLK rises
+		"left:"
+			"out pins, 1 side 0b00"
+			"jmp x-- left side 0b01"
+		"out pins, 1 side 0b10"
+		".wrap"
+	);
+
+	let installed = match pio.install(&program.program) {
+		Ok(installed) => installed,
+		Err(_) => {
+			warn!("Audio PIO program didn't fit");
+			return (pio, sm2);
+		}
+	};
+	let (mut sm, _, tx) = hal::pio::PIOBuilder::from_program(installed)
+		.buffers(hal::pio::Buffers::OnlyTx)
+		.out_pins(DATA_PIN, 1)
+		.side_set_pin_base(BCLK_PIN)
+		.autopull(true)
+		.out_shift_direction(hal::pio::ShiftDirection::Left)
+		.pull_threshold(32)
+		.build(sm0);
+	sm.set_pindirs([
+		(DATA_PIN, hal::pio::PinDir::Output),
+		(BCLK_PIN, hal::pio::PinDir::Output),
+		(LRCLK_PIN, hal::pio::PinDir::Output),
+	]);
+	// We drop the state machine here - `configure` starts and stops it
+	// through the registers instead, as the clock divider has to change
+	// with the sample rate.
+
+	// This program follows the clocks made by the one above, at full speed,
+	// and samples the ADC data on each rising edge of BCLK. It waits for
+	// LRCLK to fall, skips the bit clock that carries the LSB of the right
+	// channel, then shifts in 32 bits (left channel then right channel, MSB
+	// first) per frame, forever.
+	//
+	// Note: autopush should be set to 32-bits, ISR is set to shift left.
+	let input_program = pio_proc::pio_asm!(
+		"wait 1 gpio 28"
+		"wait 0 gpio 28"
+		"wait 1 gpio 27"
+		".wrap_target"
+		"wait 0 gpio 27"
+		"wait 1 gpio 27"
+		"in pins, 1"
+		".wrap"
+	);
+	let input_rx = match pio.install(&input_program.program) {
+		Ok(installed) => {
+			let (_sm, rx, _) = hal::pio::PIOBuilder::from_program(installed)
+				.buffers(hal::pio::Buffers::OnlyRx)
+				.in_pin_base(ADC_PIN)
+				.autopush(true)
+				.in_shift_direction(hal::pio::ShiftDirection::Left)
+				.push_threshold(32)
+				.build(sm1);
+			// Dropped for the same reason as the output state machine
+			Some(rx)
+		}
+		Err(_) => {
+			warn!("Audio input PIO program didn't fit");
+			None
+		}
+	};
+
+	// Note (safety): Only called from Core 0, before the DMA_IRQ_1 interrupt
+	// is enabled.
+	unsafe {
+		SYS_CLOCK_HZ = sys_clock_hz;
+		FIFO = Some((tx.fifo_address() as usize as u32, tx.dreq_value()));
+		INPUT_FIFO = input_rx.map(|rx| (rx.fifo_address() as usize as u32, rx.dreq_value()));
+		INPUT_RING_ADDRESS = INPUT_RING.as_ptr() as usize as u32;
+		let (producer, consumer) = QUEUE.split();
+		PRODUCER = Some(producer);
+		CONSUMER = Some(consumer);
+		for (idx, queue) in MIX_QUEUES.iter_mut().enumerate() {
+			let (producer, consumer) = queue.split();
+			MIX_PRODUCERS[idx] = Some(producer);
+			MIX_CONSUMERS[idx] = Some(consumer);
+		}
+	}
+	(pio, sm2)
+}
+
+/// Start (or change) the audio output.
+///
+/// We can play at 48 kHz, 44.1 kHz and 22.05 kHz (which is played at 44.1
+/// kHz, with each frame sent twice). Anything still queued is played at
+/// the new rate.
+pub fn configure(sample_rate_hz: u32, format: u8) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (fifo_address, dreq, sys_clock_hz) = match unsafe { (FIFO, SYS_CLOCK_HZ) } {
+		(Some((address, dreq)), hz) => (address, dreq, hz),
+		(None, _) => return Err(Error::NotReady),
+	};
+	let format = SampleFormat::from_u8(format).ok_or(Error::BadFormat)?;
+	// The codec's sample rate register, in USB mode (see Table 3-3 of the
+	// datasheet)
+	let (codec_rate_hz, rate_register) = match sample_rate_hz {
+		48_000 => (48_000, 0x001),
+		44_100 | 22_050 => (44_100, 0x023),
+		_ => return Err(Error::BadSampleRate),
+	};
+	for pin in [DATA_PIN, BCLK_PIN, LRCLK_PIN] {
+		board::reserve(pin, board::PinUse::Audio).map_err(|e| match e {
+			board::Error::InUse(usage) => Error::PinInUse(usage),
+			board::Error::NoSuchPin => Error::NotReady,
+		})?;
+	}
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let input_source = unsafe { INPUT }.map(|(_, source)| source);
+	if let Err(e) = setup_codec(rate_register, input_source) {
+		for pin in [DATA_PIN, BCLK_PIN, LRCLK_PIN] {
+			board::release(pin, board::PinUse::Audio);
+		}
+		return Err(Error::NoCodec(e));
+	}
+
+	// The divider is 16.8 fixed point
+	let divider = (u64::from(sys_clock_hz) * 256) / u64::from(codec_rate_hz * CYCLES_PER_FRAME);
+	debug!(
+		"Audio {} Hz {}, PIO divider {}/256",
+		sample_rate_hz, format, divider
+	);
+
+	// Note (safety): We own PIO1, and state machine 0 has our program in it.
+	let pio = unsafe { &*pac::PIO1::ptr() };
+	pio.sm[STATE_MACHINE].sm_clkdiv.write(|w| unsafe {
+		w.int()
+			.bits((divider >> 8) as u16)
+			.frac()
+			.bits((divider & 0xFF) as u8)
+	});
+	pio.ctrl
+		.modify(|_r, w| unsafe { w.clkdiv_restart().bits(1 << STATE_MACHINE) });
+
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let was_running = unsafe { CURRENT.replace((format, sample_rate_hz)) }.is_some();
+	// The mixer channels have to keep up with the new codec rate
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let mix_settings = unsafe { MIX_SETTINGS };
+	for (step, settings) in MIX_STEPS.iter().zip(mix_settings.iter()) {
+		if let Some((_, mix_rate_hz)) = settings {
+			step.store(mix_step(*mix_rate_hz, codec_rate_hz), Ordering::Relaxed);
+		}
+	}
+	if !was_running {
+		start_dma(fifo_address, dreq);
+		for pin in [DATA_PIN, BCLK_PIN, LRCLK_PIN] {
+			board::set_function(pin, FUNCSEL_PIO1);
+		}
+		pio.ctrl.modify(|r, w| unsafe {
+			w.sm_enable()
+				.bits(r.sm_enable().bits() | (1 << STATE_MACHINE))
+		});
+	}
+	Ok(())
+}
+
+/// Queue up some samples, in the format given to `configure`.
+///
+/// Returns how many bytes were taken, which is only ever whole frames. If
+/// the queue is full, that might be zero.
+pub fn write(data: &[u8]) -> Result<usize, Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (producer, (format, sample_rate_hz)) = match unsafe { (PRODUCER.as_mut(), CURRENT) } {
+		(Some(producer), Some(current)) => (producer, current),
+		_ => return Err(Error::NotReady),
+	};
+	let repeat = repeat_count(sample_rate_hz);
+	let frame_len = format.frame_len();
+	let mut used = 0;
+	for frame in data.chunks_exact(frame_len) {
+		if (producer.capacity() - producer.len()) < repeat {
+			break;
+		}
+		let word = format.to_word(frame);
+		for _ in 0..repeat {
+			let _ = producer.enqueue(word);
+		}
+		used += frame_len;
+	}
+	Ok(used)
+}
+
+/// How many bytes `write` would take right now.
+pub fn space() -> Result<usize, Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (producer, (format, sample_rate_hz)) = match unsafe { (PRODUCER.as_ref(), CURRENT) } {
+		(Some(producer), Some(current)) => (producer, current),
+		_ => return Err(Error::NotReady),
+	};
+	let free = producer.capacity() - producer.len();
+	Ok((free / repeat_count(sample_rate_hz)) * format.frame_len())
+}
+
+/// Start (or change) a mixer channel.
+///
+/// `volume` is as for `mix_set_volume`. The main output must be running,
+/// and anything still queued on the channel is played in the new format.
+pub fn mix_configure(
+	channel: u8,
+	sample_rate_hz: u32,
+	format: u8,
+	volume: u8,
+) -> Result<(), Error> {
+	let channel = usize::from(channel);
+	if channel >= NUM_MIX_CHANNELS {
+		return Err(Error::NoSuchChannel);
+	}
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (_, output_rate_hz) = unsafe { CURRENT }.ok_or(Error::NotReady)?;
+	let format = SampleFormat::from_u8(format).ok_or(Error::BadFormat)?;
+	if !(MIN_MIX_RATE_HZ..=MAX_MIX_RATE_HZ).contains(&sample_rate_hz) {
+		return Err(Error::BadSampleRate);
+	}
+	let codec_rate_hz = output_rate_hz * repeat_count(output_rate_hz) as u32;
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		MIX_SETTINGS[channel] = Some((format, sample_rate_hz));
+	}
+	MIX_VOLUMES[channel].store(mix_volume(volume), Ordering::Relaxed);
+	MIX_STEPS[channel].store(mix_step(sample_rate_hz, codec_rate_hz), Ordering::Relaxed);
+	debug!(
+		"Audio mixer channel {}: {} Hz {}, volume {}",
+		channel, sample_rate_hz, format, volume
+	);
+	Ok(())
+}
+
+/// Change a mixer channel's volume, where 255 is full volume (the samples
+/// as given) and 0 is silent.
+pub fn mix_set_volume(channel: u8, volume: u8) -> Result<(), Error> {
+	let volume_cell = MIX_VOLUMES
+		.get(usize::from(channel))
+		.ok_or(Error::NoSuchChannel)?;
+	volume_cell.store(mix_volume(volume), Ordering::Relaxed);
+	Ok(())
+}
+
+/// Stop a mixer channel, throwing away anything still queued on it.
+pub fn mix_stop(channel: u8) -> Result<(), Error> {
+	let channel = usize::from(channel);
+	if channel >= NUM_MIX_CHANNELS {
+		return Err(Error::NoSuchChannel);
+	}
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		MIX_SETTINGS[channel] = None;
+	}
+	MIX_STEPS[channel].store(0, Ordering::Relaxed);
+	Ok(())
+}
+
+/// Queue up some samples on a mixer channel, in the format given to
+/// `mix_configure`.
+///
+/// Returns how many bytes were taken, which is only ever whole frames. If
+/// the queue is full, that might be zero.
+pub fn mix_write(channel: u8, data: &[u8]) -> Result<usize, Error> {
+	let (producer, format) = mix_producer(channel)?;
+	let frame_len = format.frame_len();
+	let mut used = 0;
+	for frame in data.chunks_exact(frame_len) {
+		if producer.enqueue(format.to_word(frame)).is_err() {
+			break;
+		}
+		used += frame_len;
+	}
+	Ok(used)
+}
+
+/// How many bytes `mix_write` would take right now.
+pub fn mix_space(channel: u8) -> Result<usize, Error> {
+	let (producer, format) = mix_producer(channel)?;
+	Ok((producer.capacity() - producer.len()) * format.frame_len())
+}
+
+/// Start (or change) the audio input.
+///
+/// Input and output share the codec's clocks, so this also sets the output
+/// to `sample_rate_hz` (starting it, with silence, if it wasn't running).
+pub fn input_configure(sample_rate_hz: u32, format: u8, source: u8) -> Result<(), Error> {
+	let format = SampleFormat::from_u8(format).ok_or(Error::BadFormat)?;
+	let source = InputSource::from_u8(source).ok_or(Error::BadSource)?;
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (fifo_address, dreq) = unsafe { INPUT_FIFO }.ok_or(Error::NotReady)?;
+	board::reserve(ADC_PIN, board::PinUse::Audio).map_err(|e| match e {
+		board::Error::InUse(usage) => Error::PinInUse(usage),
+		board::Error::NoSuchPin => Error::NotReady,
+	})?;
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (old_input, output_format) = unsafe {
+		(
+			INPUT.replace((format, source)),
+			CURRENT.map(|(format, _)| format),
+		)
+	};
+	// This sets the codec up for our input source
+	let output_format = output_format.unwrap_or(SampleFormat::SixteenBitStereo);
+	if let Err(e) = configure(sample_rate_hz, output_format as u8) {
+		// Note (safety): Only called from Core 0, and never from an
+		// interrupt.
+		unsafe {
+			INPUT = old_input;
+		}
+		if old_input.is_none() {
+			board::release(ADC_PIN, board::PinUse::Audio);
+		}
+		return Err(e);
+	}
+	if old_input.is_none() {
+		start_input_dma(fifo_address, dreq);
+		board::set_function(ADC_PIN, FUNCSEL_PIO1);
+		// Note (safety): We own PIO1, and state machine 1 has our input
+		// program in it.
+		let pio = unsafe { &*pac::PIO1::ptr() };
+		pio.ctrl.modify(|r, w| unsafe {
+			w.sm_enable()
+				.bits(r.sm_enable().bits() | (1 << INPUT_STATE_MACHINE))
+		});
+	}
+	debug!(
+		"Audio input {} Hz {} from {}",
+		sample_rate_hz, format, source
+	);
+	Ok(())
+}
+
+/// Stop the audio input, and give its pin back.
+///
+/// The codec's ADC stays on until the output is next configured.
+pub fn input_stop() -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	if unsafe { INPUT.take() }.is_none() {
+		return Ok(());
+	}
+	// Note (safety): We own PIO1, and state machine 1.
+	let pio = unsafe { &*pac::PIO1::ptr() };
+	pio.ctrl.modify(|r, w| unsafe {
+		w.sm_enable()
+			.bits(r.sm_enable().bits() & !(1 << INPUT_STATE_MACHINE))
+	});
+	// Note (safety): Nothing else touches our channels.
+	let dma = unsafe { &*pac::DMA::ptr() };
+	for chan in [DMA_CHAN_IN, DMA_CHAN_IN_RESTART] {
+		dma.ch[chan].ch_al1_ctrl.write(|w| unsafe { w.bits(0) });
+	}
+	dma.chan_abort
+		.write(|w| unsafe { w.bits((1 << DMA_CHAN_IN) | (1 << DMA_CHAN_IN_RESTART)) });
+	while dma.chan_abort.read().bits() != 0 {}
+	board::set_function(ADC_PIN, board::FUNCSEL_NULL);
+	board::release(ADC_PIN, board::PinUse::Audio);
+	Ok(())
+}
+
+/// Take recorded samples, in the format given to `input_configure`.
+///
+/// Returns how many bytes were given, which is only ever whole frames. If
+/// nothing has come in, that might be zero.
+pub fn input_read(buffer: &mut [u8]) -> Result<usize, Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let ((format, _), (_, sample_rate_hz)) = match unsafe { (INPUT, CURRENT) } {
+		(Some(input), Some(current)) => (input, current),
+		_ => return Err(Error::NotReady),
+	};
+	let repeat = repeat_count(sample_rate_hz);
+	let frame_len = format.frame_len();
+	let write = input_write_index();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let read = unsafe { &mut INPUT_READ };
+	let mut used = 0;
+	for out in buffer.chunks_exact_mut(frame_len) {
+		if (write + INPUT_RING_LEN - *read) % INPUT_RING_LEN < repeat {
+			break;
+		}
+		// Note (safety): The DMA engine is writing elsewhere in the ring.
+		let word = unsafe { core::ptr::read_volatile(&INPUT_RING[*read]) };
+		format.write_frame(word, out);
+		*read = (*read + repeat) % INPUT_RING_LEN;
+		used += frame_len;
+	}
+	Ok(used)
+}
+
+/// How many bytes `input_read` could give right now.
+pub fn input_count() -> Result<usize, Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let ((format, _), (_, sample_rate_hz), read) = match unsafe { (INPUT, CURRENT, INPUT_READ) } {
+		(Some(input), Some(current), read) => (input, current, read),
+		_ => return Err(Error::NotReady),
+	};
+	let waiting = (input_write_index() + INPUT_RING_LEN - read) % INPUT_RING_LEN;
+	Ok((waiting / repeat_count(sample_rate_hz)) * format.frame_len())
+}
+
+/// Called when one of our DMA channels has finished playing its buffer.
+///
+/// The other channel has already started on its buffer, so we refill this
+/// one from the queue (with silence, if the OS hasn't kept up), add in the
+/// mixer channels, and point the channel back at the start of it.
+///
+/// # Safety
+///
+/// Only call this from the DMA_IRQ_1 interrupt.
+pub unsafe fn irq() {
+	let dma = &*pac::DMA::ptr();
+	let status = dma.ints1.read().bits();
+	for (idx, chan) in [DMA_CHAN_A, DMA_CHAN_B].iter().enumerate() {
+		if (status & (1 << chan)) == 0 {
+			continue;
+		}
+		dma.ints1.write(|w| w.bits(1 << chan));
+		let buffer = &mut BUFFERS[idx];
+		let mut consumer = CONSUMER.as_mut();
+		for slot in buffer.iter_mut() {
+			*slot = match consumer {
+				Some(ref mut consumer) => consumer.dequeue().unwrap_or(0),
+				None => 0,
+			};
+		}
+		for (channel, resampler) in RESAMPLERS.iter_mut().enumerate() {
+			if let Some(consumer) = MIX_CONSUMERS[channel].as_mut() {
+				mix_channel(channel, consumer, resampler, buffer);
+			}
+		}
+		mix_tone(buffer);
+		dma.ch[*chan]
+			.ch_read_addr
+			.write(|w| w.bits(buffer.as_ptr() as usize as u32));
+	}
+}
+
+/// Play a tone, in the background, replacing any tone already playing.
+///
+/// On boards with the codec, the tone is mixed in with everything else, and
+/// the output is started (at 48 kHz) if it isn't running. Otherwise it goes
+/// to the beeper. A frequency or duration of zero just stops the current
+/// tone.
+pub fn tone(frequency_hz: u32, duration_ms: u32) -> Result<(), Error> {
+	if beeper::is_enabled() {
+		return beeper::tone(frequency_hz, duration_ms).map_err(|e| match e {
+			beeper::Error::NotFitted => Error::NotReady,
+			beeper::Error::BadFrequency => Error::BadFrequency,
+			beeper::Error::PinInUse(usage) => Error::PinInUse(usage),
+		});
+	}
+	// Whatever was playing stops now
+	let now = FRAME_COUNT.load(Ordering::Relaxed);
+	TONE_END.store(now, Ordering::Relaxed);
+	if frequency_hz == 0 || duration_ms == 0 {
+		return Ok(());
+	}
+	if !(beeper::MIN_FREQUENCY_HZ..=beeper::MAX_FREQUENCY_HZ).contains(&frequency_hz) {
+		return Err(Error::BadFrequency);
+	}
+	if !is_running() {
+		configure(48_000, SampleFormat::SixteenBitStereo as u8)?;
+	}
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let codec_rate_hz = match unsafe { CURRENT } {
+		Some((_, sample_rate_hz)) => sample_rate_hz * repeat_count(sample_rate_hz) as u32,
+		None => return Err(Error::NotReady),
+	};
+	let step = ((u64::from(frequency_hz) << 32) / u64::from(codec_rate_hz)) as u32;
+	let frames = duration_ms.min(beeper::MAX_DURATION_MS) * codec_rate_hz / 1000;
+	TONE_STEP.store(step, Ordering::Relaxed);
+	TONE_END.store(
+		FRAME_COUNT.load(Ordering::Relaxed).wrapping_add(frames),
+		Ordering::Relaxed,
+	);
+	Ok(())
+}
+
+/// A short beep, for an ASCII BEL. Does nothing if we have nothing to beep
+/// with.
+pub fn beep() {
+	let _ = tone(BEL_FREQUENCY_HZ, BEL_DURATION_MS);
+}
+
+/// Set the master volume, from 0 (silent) to 255 (0 dB).
+///
+/// The codec works in 1 dB steps, so neighbouring values may sound the same.
+pub fn set_volume(volume: u8) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		MASTER_VOLUME = volume;
+	}
+	update_outputs()
+}
+
+/// Get the master volume.
+pub fn volume() -> u8 {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { MASTER_VOLUME }
+}
+
+/// Mute (or unmute) the output. The volume is kept.
+pub fn set_mute(muted: bool) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		MUTED = muted;
+	}
+	if is_running() {
+		codec_write(CODEC_DIGITAL_PATH, digital_path_register()).map_err(Error::NoCodec)?;
+	}
+	Ok(())
+}
+
+/// Is the output muted?
+pub fn is_muted() -> bool {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { MUTED }
+}
+
+/// Are there headphones plugged in?
+///
+/// Always `true` if the headphone detect switch is turned off in the BIOS
+/// configuration.
+pub fn headphones_present() -> bool {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	!config::get().headphone_detect || unsafe { HEADPHONES.present }
+}
+
+/// Sample the headphone detect switch, and switch between the headphones
+/// and the speaker if it has changed.
+///
+/// Call this regularly - the debounce timing is only as good as the rate at
+/// which this is called.
+pub fn poll_headphone_detect() {
+	if !config::get().headphone_detect {
+		return;
+	}
+	let raw = match neobus::read_inputs() {
+		Some(inputs) => (inputs & neobus::input_headphone_detect()) != 0,
+		None => return,
+	};
+	let now = crate::ticks_ms();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let detect = unsafe { &mut HEADPHONES };
+	if raw != detect.raw {
+		// Still bouncing - start timing again
+		detect.raw = raw;
+		detect.raw_changed_at = now;
+		return;
+	}
+	if raw == detect.present
+		|| now.wrapping_sub(detect.raw_changed_at) < HEADPHONE_DETECT_DEBOUNCE_MS
+	{
+		return;
+	}
+	detect.present = raw;
+	if raw {
+		info!("Headphones plugged in");
+	} else {
+		info!("Headphones unplugged");
+	}
+	if let Err(e) = update_outputs() {
+		warn!("Couldn't switch audio output: {}", e);
+	}
+}
+
+/// See if the codec answers on the I2C bus.
+///
+/// The codec is write-only, so we do that by resetting it. That's harmless
+/// before `configure` has been called, as it sets the codec up from scratch.
+pub fn probe_codec() -> Result<(), rtc::Error> {
+	rtc::bus_write(CODEC_ADDRESS, &[CODEC_RESET << 1, 0x00])
+}
+
+/// Set the codec up for I2S, and turn the DAC and headphone output on. If
+/// we have an input source, turn that and the ADC on too.
+fn setup_codec(rate_register: u16, input: Option<InputSource>) -> Result<(), rtc::Error> {
+	let (power_down, analogue_path) = match input {
+		// Power down the line input, microphone and ADC. DAC on, microphone
+		// muted.
+		None => (0x007, 0x012),
+		// Power down the microphone. DAC on, ADC fed from the line input,
+		// microphone muted.
+		Some(InputSource::LineIn) => (0x002, 0x012),
+		// Power down the line input. DAC on, ADC fed from the microphone.
+		Some(InputSource::Microphone) => (0x001, 0x014),
+	};
+	let setup = [
+		(CODEC_RESET, 0x000),
+		(CODEC_POWER_DOWN, power_down),
+		(CODEC_ANALOGUE_PATH, analogue_path),
+		// Both line input channels at 0 dB, not muted
+		(CODEC_LEFT_LINE_VOLUME, 0x117),
+		// No de-emphasis, DAC muted as the OS asked
+		(CODEC_DIGITAL_PATH, digital_path_register()),
+		// Both headphone channels at the master volume
+		(CODEC_LEFT_HP_VOLUME, headphone_register()),
+		// Slave mode, 16-bit, I2S format
+		(CODEC_INTERFACE_FORMAT, 0x002),
+		(CODEC_SAMPLE_RATE, rate_register),
+		(CODEC_ACTIVE, 0x001),
+	];
+	for (register, value) in setup.iter() {
+		codec_write(*register, *value)?;
+	}
+	neobus::set_speaker_enable(!headphones_present());
+	Ok(())
+}
+
+/// Write one of the codec's registers.
+fn codec_write(register: u8, value: u16) -> Result<(), rtc::Error> {
+	// Each register is 9 bits, so the top bit goes in with the address.
+	rtc::bus_write(
+		CODEC_ADDRESS,
+		&[(register << 1) | (value >> 8) as u8, value as u8],
+	)
+}
+
+/// Is the codec set up and playing?
+fn is_running() -> bool {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { CURRENT.is_some() }
+}
+
+/// Send the master volume to the codec (if it is running), and turn the
+/// speaker amplifier on unless there are headphones plugged in.
+fn update_outputs() -> Result<(), Error> {
+	if !is_running() {
+		return Ok(());
+	}
+	neobus::set_speaker_enable(!headphones_present());
+	codec_write(CODEC_LEFT_HP_VOLUME, headphone_register()).map_err(Error::NoCodec)
+}
+
+/// The codec's headphone volume register, for both channels at once.
+///
+/// The headphones are muted if they aren't plugged in. Changes wait for a
+/// zero crossing, so they don't click.
+fn headphone_register() -> u16 {
+	let volume = volume();
+	let level = if volume == 0 || !headphones_present() {
+		0
+	} else {
+		let range = CODEC_HP_VOLUME_MAX - CODEC_HP_VOLUME_MIN;
+		CODEC_HP_VOLUME_MIN + ((u16::from(volume) - 1) * range) / 254
+	};
+	// LRS (set both channels) and LZC (zero-cross detect)
+	0x180 | level
+}
+
+/// The codec's digital audio path register: no de-emphasis, and the DAC
+/// soft mute as the OS asked.
+fn digital_path_register() -> u16 {
+	if is_muted() {
+		0x008
+	} else {
+		0x000
+	}
+}
+
+/// Start both DMA channels. Each plays its buffer into the FIFO, then starts
+/// the other.
+fn start_dma(fifo_address: u32, dreq: u8) {
+	// Note (safety): The video owns the DMA controller, but only uses its
+	// own channels and interrupt. Nothing else touches these channels.
+	let dma = unsafe { &*pac::DMA::ptr() };
+	for (idx, (chan, next)) in [(DMA_CHAN_A, DMA_CHAN_B), (DMA_CHAN_B, DMA_CHAN_A)]
+		.iter()
+		.enumerate()
+	{
+		// Note (safety): Only called from Core 0, before the DMA_IRQ_1
+		// interrupt is enabled.
+		let buffer = unsafe { &BUFFERS[idx] };
+		let ch = &dma.ch[*chan];
+		ch.ch_read_addr
+			.write(|w| unsafe { w.bits(buffer.as_ptr() as usize as u32) });
+		ch.ch_write_addr.write(|w| unsafe { w.bits(fifo_address) });
+		ch.ch_trans_count
+			.write(|w| unsafe { w.bits(BLOCK_LEN as u32) });
+		// Write the control register through the alias which doesn't
+		// trigger the channel.
+		ch.ch_al1_ctrl.write(|w| unsafe {
+			w.bits(
+				(1 << 0) // EN
+					| (2 << 2) // DATA_SIZE = word
+					| (1 << 4) // INCR_READ
+					| ((*next as u32) << 11) // CHAIN_TO
+					| (u32::from(dreq) << 15), // TREQ_SEL
+			)
+		});
+	}
+	dma.inte1
+		.modify(|r, w| unsafe { w.bits(r.bits() | (1 << DMA_CHAN_A) | (1 << DMA_CHAN_B)) });
+	// Note (safety): Our interrupt handler is ready to go.
+	unsafe {
+		pac::NVIC::unpend(pac::Interrupt::DMA_IRQ_1);
+		pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_1);
+	}
+	dma.multi_chan_trigger
+		.write(|w| unsafe { w.bits(1 << DMA_CHAN_A) });
+}
+
+/// Where the DMA engine will put the next input frame in `INPUT_RING`.
+fn input_write_index() -> usize {
+	// Note (safety): We only read our own channel's registers.
+	let dma = unsafe { &*pac::DMA::ptr() };
+	// Note (safety): Only read from Core 0.
+	let base = unsafe { INPUT_RING_ADDRESS };
+	let address = dma.ch[DMA_CHAN_IN].ch_write_addr.read().bits();
+	(address.wrapping_sub(base) as usize / 4) % INPUT_RING_LEN
+}
+
+/// Start the input DMA channels, from the start of `INPUT_RING`.
+///
+/// `DMA_CHAN_IN` fills the ring from the FIFO, then chains to
+/// `DMA_CHAN_IN_RESTART`, which writes the ring's address back into
+/// `DMA_CHAN_IN` (through the register alias which triggers it).
+fn start_input_dma(fifo_address: u32, dreq: u8) {
+	// Note (safety): The video owns the DMA controller, but only uses its
+	// own channels and interrupt. Nothing else touches these channels.
+	let dma = unsafe { &*pac::DMA::ptr() };
+	// Note (safety): Only accessed from Core 0, and the DMA engine only
+	// reads it.
+	let ring_address = unsafe { INPUT_RING_ADDRESS };
+	let restart = &dma.ch[DMA_CHAN_IN_RESTART];
+	restart.ch_read_addr.write(|w| unsafe {
+		// Note (safety): We only take the address.
+		w.bits(&INPUT_RING_ADDRESS as *const u32 as usize as u32)
+	});
+	restart.ch_write_addr.write(|w| unsafe {
+		w.bits(dma.ch[DMA_CHAN_IN].ch_al2_write_addr_trig.as_ptr() as usize as u32)
+	});
+	restart.ch_trans_count.write(|w| unsafe { w.bits(1) });
+	restart.ch_al1_ctrl.write(|w| unsafe {
+		w.bits(
+			(1 << 0) // EN
+				| (2 << 2) // DATA_SIZE = word
+				| ((DMA_CHAN_IN_RESTART as u32) << 11) // CHAIN_TO itself (nothing)
+				| (DMA_TREQ_PERMANENT << 15), // TREQ_SEL
+		)
+	});
+	let ch = &dma.ch[DMA_CHAN_IN];
+	ch.ch_read_addr.write(|w| unsafe { w.bits(fifo_address) });
+	ch.ch_trans_count
+		.write(|w| unsafe { w.bits(INPUT_RING_LEN as u32) });
+	ch.ch_al1_ctrl.write(|w| unsafe {
+		w.bits(
+			(1 << 0) // EN
+				| (2 << 2) // DATA_SIZE = word
+				| (1 << 5) // INCR_WRITE
+				| ((DMA_CHAN_IN_RESTART as u32) << 11) // CHAIN_TO
+				| (u32::from(dreq) << 15), // TREQ_SEL
+		)
+	});
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		INPUT_READ = 0;
+	}
+	ch.ch_al2_write_addr_trig
+		.write(|w| unsafe { w.bits(ring_address) });
+}
+
+/// Add one mixer channel into a buffer, at its own rate and volume.
+///
+/// Only called from the DMA_IRQ_1 interrupt.
+fn mix_channel(
+	channel: usize,
+	consumer: &mut Consumer<'static, u32, MIX_QUEUE_LEN>,
+	resampler: &mut Resampler,
+	buffer: &mut [u32; BLOCK_LEN],
+) {
+	let step = MIX_STEPS[channel].load(Ordering::Relaxed);
+	if step == 0 {
+		// Stopped, so start from silence next time
+		while consumer.dequeue().is_some() {}
+		*resampler = SILENT_RESAMPLER;
+		return;
+	}
+	let volume = i32::from(MIX_VOLUMES[channel].load(Ordering::Relaxed));
+	for slot in buffer.iter_mut() {
+		resampler.phase += step;
+		while resampler.phase >= MIX_ONE {
+			resampler.phase -= MIX_ONE;
+			resampler.prev = resampler.next;
+			// If the OS hasn't kept up, fade to silence
+			resampler.next = consumer.dequeue().unwrap_or(0);
+		}
+		// Eight bits of fraction is plenty
+		let fraction = (resampler.phase >> 8) as i32;
+		let (prev_left, prev_right) = split_word(resampler.prev);
+		let (next_left, next_right) = split_word(resampler.next);
+		let left = prev_left + (((next_left - prev_left) * fraction) >> 8);
+		let right = prev_right + (((next_right - prev_right) * fraction) >> 8);
+		let (out_left, out_right) = split_word(*slot);
+		*slot = join_word(
+			out_left + ((left * volume) >> 8),
+			out_right + ((right * volume) >> 8),
+		);
+	}
+}
+
+/// Add the tone (if one is playing) into a buffer, and count the frames.
+///
+/// Only called from the DMA_IRQ_1 interrupt.
+fn mix_tone(buffer: &mut [u32; BLOCK_LEN]) {
+	let start = FRAME_COUNT.load(Ordering::Relaxed);
+	FRAME_COUNT.store(start.wrapping_add(BLOCK_LEN as u32), Ordering::Relaxed);
+	let end = TONE_END.load(Ordering::Relaxed);
+	let step = TONE_STEP.load(Ordering::Relaxed);
+	for (frame, slot) in (start..).zip(buffer.iter_mut()) {
+		// This copes with `FRAME_COUNT` wrapping
+		if (end.wrapping_sub(frame) as i32) <= 0 {
+			break;
+		}
+		// Note (safety): Only accessed by this interrupt.
+		let phase = unsafe {
+			TONE_PHASE = TONE_PHASE.wrapping_add(step);
+			TONE_PHASE
+		};
+		let level = if (phase as i32) < 0 {
+			TONE_LEVEL
+		} else {
+			-TONE_LEVEL
+		};
+		let (left, right) = split_word(*slot);
+		*slot = join_word(left + level, right + level);
+	}
+}
+
+/// Get the left and right samples out of a word in `BUFFERS`.
+fn split_word(word: u32) -> (i32, i32) {
+	(i32::from(word as i16), i32::from((word >> 16) as i16))
+}
+
+/// Make a word for `BUFFERS` from left and right samples, clipping them if
+/// they are too loud.
+fn join_word(left: i32, right: i32) -> u32 {
+	let clip = |sample: i32| sample.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as u16;
+	(u32::from(clip(right)) << 16) | u32::from(clip(left))
+}
+
+/// How far a mixer channel at `sample_rate_hz` moves through its queue for
+/// each frame sent to the codec, in 16.16 fixed point.
+fn mix_step(sample_rate_hz: u32, codec_rate_hz: u32) -> u32 {
+	((u64::from(sample_rate_hz) << 16) / u64::from(codec_rate_hz)) as u32
+}
+
+/// Convert a volume from the OS (255 is full volume) into our scale.
+fn mix_volume(volume: u8) -> u16 {
+	u16::from(volume) + u16::from(volume >> 7)
+}
+
+/// Get a running mixer channel's queue, and its format.
+fn mix_producer(
+	channel: u8,
+) -> Result<
+	(
+		&'static mut Producer<'static, u32, MIX_QUEUE_LEN>,
+		SampleFormat,
+	),
+	Error,
+> {
+	let channel = usize::from(channel);
+	if channel >= NUM_MIX_CHANNELS {
+		return Err(Error::NoSuchChannel);
+	}
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	match unsafe { (MIX_PRODUCERS[channel].as_mut(), MIX_SETTINGS[channel]) } {
+		(Some(producer), Some((format, _))) => Ok((producer, format)),
+		_ => Err(Error::NotReady),
+	}
+}
+
+/// How many times each frame is sent to the codec, at this sample rate.
+fn repeat_count(sample_rate_hz: u32) -> usize {
+	if sample_rate_hz == 22_050 {
+		2
+	} else {
+		1
+	}
+}
+
+impl InputSource {
+	/// Convert from the value the OS gives us.
+	pub const fn from_u8(value: u8) -> Option<InputSource> {
+		match value {
+			0 => Some(InputSource::LineIn),
+			1 => Some(InputSource::Microphone),
+			_ => None,
+		}
+	}
+}
+
+impl SampleFormat {
+	/// Convert from the value the OS gives us.
+	pub const fn from_u8(value: u8) -> Option<SampleFormat> {
+		match value {
+			0 => Some(SampleFormat::EightBitMono),
+			1 => Some(SampleFormat::EightBitStereo),
+			2 => Some(SampleFormat::SixteenBitMono),
+			3 => Some(SampleFormat::SixteenBitStereo),
+			_ => None,
+		}
+	}
+
+	/// Convert an input frame from the codec (left sample in the top half)
+	/// into this format. `out` must be `frame_len` bytes long.
+	fn write_frame(self, word: u32, out: &mut [u8]) {
+		let left = (word >> 16) as i16;
+		let right = word as i16;
+		let mono = ((i32::from(left) + i32::from(right)) / 2) as i16;
+		let to_u8 = |sample: i16| ((sample >> 8) as u8) ^ 0x80;
+		match self {
+			SampleFormat::EightBitMono => out[0] = to_u8(mono),
+			SampleFormat::EightBitStereo => {
+				out[0] = to_u8(left);
+				out[1] = to_u8(right);
+			}
+			SampleFormat::SixteenBitMono => out.copy_from_slice(&mono.to_le_bytes()),
+			SampleFormat::SixteenBitStereo => {
+				out[0..2].copy_from_slice(&left.to_le_bytes());
+				out[2..4].copy_from_slice(&right.to_le_bytes());
+			}
+		}
+	}
+
+	/// How many bytes there are in one frame (one sample per channel).
+	const fn frame_len(self) -> usize {
+		match self {
+			SampleFormat::EightBitMono => 1,
+			SampleFormat::EightBitStereo | SampleFormat::SixteenBitMono => 2,
+			SampleFormat::SixteenBitStereo => 4,
+		}
+	}
+
+	/// Convert one frame to the form the PIO program wants.
+	fn to_word(self, frame: &[u8]) -> u32 {
+		let from_u8 = |b: u8| u32::from(b ^ 0x80) << 8;
+		let from_le = |lo: u8, hi: u8| u32::from(u16::from_le_bytes([lo, hi]));
+		let (left, right) = match self {
+			SampleFormat::EightBitMono => (from_u8(frame[0]), from_u8(frame[0])),
+			SampleFormat::EightBitStereo => (from_u8(frame[0]), from_u8(frame[1])),
+			SampleFormat::SixteenBitMono => {
+				let sample = from_le(frame[0], frame[1]);
+				(sample, sample)
+			}
+			SampleFormat::SixteenBitStereo => {
+				(from_le(frame[0], frame[1]), from_le(frame[2], frame[3]))
+			}
+		};
+		(right << 16) | left
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
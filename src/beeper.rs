@@ -0,0 +1,211 @@
+//! # PWM beeper for the Neotron Pico
+//!
+//! Boards built without the audio codec can have a piezo sounder (or a
+//! small speaker, through a transistor) on GPIO22, which would otherwise be
+//! the codec's data input. We drive it with a square wave from PWM slice 3
+//! (output A), so the OS can make simple tones (through `audio::tone`), the
+//! text console can beep on an ASCII BEL, and the power-on self test can
+//! beep its error codes.
+//!
+//! A tone stops on its own: the timer's first alarm goes off when it's due
+//! to finish, and the TIMER_IRQ_0 interrupt silences the output. So nobody
+//! has to wait for a tone to end.
+//!
+//! The beeper only works once `enable` has been called, which the self test
+//! does if it can't find the codec. The pin is taken over (via
+//! `board::reserve`) on the first tone, and kept.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::board;
+use defmt::debug;
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which making a tone can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// There's no beeper, because the board has the audio codec instead
+	NotFitted,
+	/// The frequency is outside `MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ`
+	BadFrequency,
+	/// The beeper's pin is being used for something else
+	PinInUse(board::PinUse),
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The lowest tone we offer
+pub const MIN_FREQUENCY_HZ: u32 = 20;
+
+/// The highest tone we offer
+pub const MAX_FREQUENCY_HZ: u32 = 20_000;
+
+/// The longest tone we offer (anything longer is cut short)
+pub const MAX_DURATION_MS: u32 = 10_000;
+
+/// The beeper's GPIO pin
+const PIN: u8 = 22;
+
+/// The PWM slice for `PIN` (we use output A)
+const SLICE: usize = 3;
+
+/// The PWM function number, for `GPIOx_CTRL.FUNCSEL`
+const FUNCSEL_PWM: u8 = 4;
+
+/// The timer alarm which ends a tone
+const ALARM: u32 = 0;
+
+/// The system clock frequency, which clocks the PWM slices
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut SYS_CLOCK_HZ: u32 = 0;
+
+/// Set once we know there's a beeper fitted.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut ENABLED: bool = false;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Remember the system clock speed, ready for `enable`.
+pub fn init(sys_clock_hz: u32) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		SYS_CLOCK_HZ = sys_clock_hz;
+	}
+}
+
+/// Say there's a beeper fitted, and let the timer interrupt end our tones.
+///
+/// Must be called after `pwm::init`, which takes the PWM block out of reset.
+pub fn enable() {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		ENABLED = true;
+	}
+	// Note (safety): Nothing else uses the timer's alarms or interrupts.
+	let timer = unsafe { &*pac::TIMER::ptr() };
+	timer
+		.inte
+		.modify(|r, w| unsafe { w.bits(r.bits() | (1 << ALARM)) });
+	// Note (safety): The interrupt handler only touches our PWM slice and
+	// our alarm.
+	unsafe {
+		pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+	}
+	debug!("Beeper enabled on GPIO{}", PIN);
+}
+
+/// Is there a beeper?
+pub fn is_enabled() -> bool {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { ENABLED }
+}
+
+/// Play a tone, in the background, replacing any tone already playing.
+///
+/// A frequency or duration of zero just stops the current tone.
+pub fn tone(frequency_hz: u32, duration_ms: u32) -> Result<(), Error> {
+	if !is_enabled() {
+		return Err(Error::NotFitted);
+	}
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let sys_clock_hz = unsafe { SYS_CLOCK_HZ };
+	// Note (safety): We only touch our own slice, and our own alarm.
+	let pwm = unsafe { &*pac::PWM::ptr() };
+	let timer = unsafe { &*pac::TIMER::ptr() };
+
+	// Stop the old tone, so its alarm can't cut the new one short
+	timer.armed.write(|w| unsafe { w.bits(1 << ALARM) });
+	silence();
+	if frequency_hz == 0 || duration_ms == 0 {
+		return Ok(());
+	}
+	if !(MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ).contains(&frequency_hz) {
+		return Err(Error::BadFrequency);
+	}
+	board::reserve(PIN, board::PinUse::Beeper).map_err(|e| match e {
+		board::Error::InUse(usage) => Error::PinInUse(usage),
+		board::Error::NoSuchPin => Error::NotFitted,
+	})?;
+
+	// As for `pwm::configure`, with the output high for half of each period
+	let counts = sys_clock_hz / frequency_hz;
+	let divider = ((counts + 0xFFFF) / 0x10000).clamp(1, 255);
+	let top = ((counts / divider).max(2) - 1).min(0xFFFF);
+	let slice = &pwm.ch[SLICE];
+	// Note (safety): These are all valid register values.
+	unsafe {
+		slice
+			.div
+			.write(|w| w.int().bits(divider as u8).frac().bits(0));
+		slice.top.write(|w| w.top().bits(top as u16));
+		slice.cc.write(|w| w.a().bits(((top + 1) / 2) as u16));
+	}
+	slice.csr.modify(|_r, w| w.en().set_bit());
+	board::set_function(PIN, FUNCSEL_PWM);
+
+	// The alarm compares against the bottom 32 bits of the microsecond
+	// counter, which is plenty for `MAX_DURATION_MS`.
+	let end = timer
+		.timerawl
+		.read()
+		.bits()
+		.wrapping_add(duration_ms.min(MAX_DURATION_MS) * 1000);
+	timer.alarm0.write(|w| unsafe { w.bits(end) });
+	Ok(())
+}
+
+/// Called when the timer's first alarm goes off; i.e. when a tone should
+/// stop.
+///
+/// # Safety
+///
+/// Only call this from the TIMER_IRQ_0 interrupt handler.
+pub unsafe fn irq() {
+	let timer = &*pac::TIMER::ptr();
+	timer.intr.write(|w| w.bits(1 << ALARM));
+	silence();
+}
+
+/// Hold the output low. The slice keeps running, so the next tone starts
+/// cleanly.
+fn silence() {
+	// Note (safety): Only our slice's compare register, which is safe to
+	// write at any time (we don't use output B).
+	let pwm = unsafe { &*pac::PWM::ptr() };
+	pwm.ch[SLICE].cc.write(|w| unsafe { w.a().bits(0) });
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
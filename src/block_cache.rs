@@ -0,0 +1,287 @@
+//! # SD card sector cache for the Neotron Pico
+//!
+//! Every SD card command costs us a trip across the Neotron Bus, and a FAT
+//! filesystem reads the same few sectors (the FAT, and the directory it is
+//! looking at) over and over. So the OS's SD card transfers go through a
+//! small cache of recently used sectors, with the least recently used one
+//! thrown out to make room.
+//!
+//! Only single-sector transfers fill the cache, so reading a big file
+//! doesn't push out the filesystem's own sectors. Bigger transfers go
+//! straight to the card, but still pick up (or update) anything cached.
+//!
+//! Normally the cache is write-through: a write goes to the card before we
+//! return. The OS can switch to write-back, where single-sector writes stay
+//! in the cache until it calls `flush` (or the sector is thrown out), which
+//! is faster but loses data if the card is pulled out or the power goes.
+//!
+//! Anything else that uses the card directly (a background transfer, or a
+//! PC over USB) must `flush` first, and `invalidate` if it writes.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::bootslot::{Block, BLOCK_SIZE};
+use crate::sdcard;
+use core::convert::TryFrom;
+use defmt::debug;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// One cached sector.
+#[derive(Copy, Clone)]
+struct Entry {
+	/// Which sector this is, if the entry is in use
+	lba: Option<u64>,
+	/// Has it been written since it was read from (or written to) the card?
+	dirty: bool,
+	/// When it was last used, by `Cache::clock`
+	last_used: u32,
+	/// The sector's contents
+	data: Block,
+}
+
+/// The cached sectors, and how we use them.
+struct Cache {
+	entries: [Entry; CACHE_BLOCKS],
+	/// Counts up on every access, so we know which entry is the oldest
+	clock: u32,
+	/// Do single-sector writes stay in the cache?
+	write_back: bool,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How many sectors we cache. The BIOS only has 32 KiB of RAM, and the text
+/// buffer has a third of it.
+const CACHE_BLOCKS: usize = 4;
+
+/// An entry with nothing in it.
+const EMPTY_ENTRY: Entry = Entry {
+	lba: None,
+	dirty: false,
+	last_used: 0,
+	data: [0u8; BLOCK_SIZE],
+};
+
+/// The cache.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut CACHE: Cache = Cache {
+	entries: [EMPTY_ENTRY; CACHE_BLOCKS],
+	clock: 0,
+	write_back: false,
+};
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Read one or more whole blocks, from the cache or the card.
+pub fn read_blocks(start: u64, data: &mut [u8]) -> Result<(), sdcard::Error> {
+	let cache = cache();
+	if data.len() == BLOCK_SIZE {
+		let idx = match cache.find(start) {
+			Some(idx) => idx,
+			None => {
+				let idx = cache.make_room()?;
+				if let Err(e) = sdcard::read_blocks(start, &mut cache.entries[idx].data) {
+					return Err(cache.card_failed(e));
+				}
+				cache.entries[idx].lba = Some(start);
+				idx
+			}
+		};
+		cache.touch(idx);
+		data.copy_from_slice(&cache.entries[idx].data);
+		return Ok(());
+	}
+	sdcard::read_blocks(start, data).map_err(|e| cache.card_failed(e))?;
+	// Anything we have cached is at least as new as what's on the card
+	for entry in cache.entries.iter() {
+		if let Some(offset) = entry.lba.and_then(|lba| offset_of(start, data.len(), lba)) {
+			data[offset..offset + BLOCK_SIZE].copy_from_slice(&entry.data);
+		}
+	}
+	Ok(())
+}
+
+/// Write one or more whole blocks, through (or, in write-back mode, into)
+/// the cache.
+pub fn write_blocks(start: u64, data: &[u8]) -> Result<(), sdcard::Error> {
+	let cache = cache();
+	if data.len() == BLOCK_SIZE {
+		let idx = match cache.find(start) {
+			Some(idx) => idx,
+			None => cache.make_room()?,
+		};
+		if !cache.write_back {
+			// If this fails, the entry is left empty
+			cache.entries[idx].lba = None;
+			sdcard::write_blocks(start, data).map_err(|e| cache.card_failed(e))?;
+		}
+		let write_back = cache.write_back;
+		let entry = &mut cache.entries[idx];
+		entry.lba = Some(start);
+		entry.dirty = write_back;
+		entry.data.copy_from_slice(data);
+		cache.touch(idx);
+		return Ok(());
+	}
+	sdcard::write_blocks(start, data).map_err(|e| cache.card_failed(e))?;
+	// Anything we have cached from these blocks now matches the card
+	for entry in cache.entries.iter_mut() {
+		if let Some(offset) = entry.lba.and_then(|lba| offset_of(start, data.len(), lba)) {
+			entry
+				.data
+				.copy_from_slice(&data[offset..offset + BLOCK_SIZE]);
+			entry.dirty = false;
+		}
+	}
+	Ok(())
+}
+
+/// Check one or more whole blocks on the card match the given data.
+///
+/// Anything waiting in the cache is written first, so we check the card
+/// itself.
+pub fn verify_blocks(start: u64, data: &[u8]) -> Result<(), sdcard::Error> {
+	flush()?;
+	sdcard::verify_blocks(start, data)
+}
+
+/// Write every sector that's waiting in the cache to the card.
+pub fn flush() -> Result<(), sdcard::Error> {
+	let cache = cache();
+	for idx in 0..CACHE_BLOCKS {
+		cache.clean(idx)?;
+	}
+	Ok(())
+}
+
+/// Forget everything in the cache, without writing anything.
+///
+/// Call this when something else has written to the card.
+pub fn invalidate() {
+	let cache = cache();
+	for entry in cache.entries.iter_mut() {
+		entry.lba = None;
+		entry.dirty = false;
+	}
+}
+
+/// Turn write-back mode on or off. Turning it off writes out anything
+/// waiting in the cache.
+pub fn set_write_back(enabled: bool) -> Result<(), sdcard::Error> {
+	if !enabled {
+		flush()?;
+	}
+	cache().write_back = enabled;
+	debug!("SD card write-back cache: {}", enabled);
+	Ok(())
+}
+
+/// Get the cache.
+fn cache() -> &'static mut Cache {
+	// Note (safety): Only called from Core 0, and never from an interrupt,
+	// and nothing here calls back into this module.
+	unsafe { &mut CACHE }
+}
+
+/// If block `lba` is part of a transfer of `len` bytes starting at block
+/// `start`, find where it is in the transfer's buffer.
+fn offset_of(start: u64, len: usize, lba: u64) -> Option<usize> {
+	let offset = usize::try_from(lba.checked_sub(start)?)
+		.ok()?
+		.checked_mul(BLOCK_SIZE)?;
+	if offset < len {
+		Some(offset)
+	} else {
+		None
+	}
+}
+
+impl Cache {
+	/// Find the entry holding the given block, if any.
+	fn find(&self, lba: u64) -> Option<usize> {
+		self.entries.iter().position(|e| e.lba == Some(lba))
+	}
+
+	/// Find an empty entry, or empty the least recently used one.
+	fn make_room(&mut self) -> Result<usize, sdcard::Error> {
+		let idx = match self.entries.iter().position(|e| e.lba.is_none()) {
+			Some(idx) => idx,
+			None => {
+				let clock = self.clock;
+				let idx = self
+					.entries
+					.iter()
+					.enumerate()
+					.max_by_key(|(_, e)| clock.wrapping_sub(e.last_used))
+					.map(|(idx, _)| idx)
+					.unwrap_or(0);
+				self.clean(idx)?;
+				idx
+			}
+		};
+		self.entries[idx].lba = None;
+		Ok(idx)
+	}
+
+	/// Write an entry out to the card, if it's dirty.
+	fn clean(&mut self, idx: usize) -> Result<(), sdcard::Error> {
+		let entry = &mut self.entries[idx];
+		if let (Some(lba), true) = (entry.lba, entry.dirty) {
+			match sdcard::write_blocks(lba, &entry.data) {
+				Ok(()) => entry.dirty = false,
+				Err(e) => return Err(self.card_failed(e)),
+			}
+		}
+		Ok(())
+	}
+
+	/// Mark an entry as just used.
+	fn touch(&mut self, idx: usize) {
+		self.clock = self.clock.wrapping_add(1);
+		self.entries[idx].last_used = self.clock;
+	}
+
+	/// If the card has failed (or gone), nothing we have cached is any use.
+	fn card_failed(&mut self, error: sdcard::Error) -> sdcard::Error {
+		if error.is_card_fault() {
+			for entry in self.entries.iter_mut() {
+				entry.lba = None;
+				entry.dirty = false;
+			}
+		}
+		error
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
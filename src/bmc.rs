@@ -0,0 +1,180 @@
+//! # Board Management Controller driver for the Neotron Pico
+//!
+//! The Board Management Controller (BMC) is a small microcontroller on the
+//! Neotron Pico which looks after the PS/2 ports, the power rails and the
+//! reset button. We talk to it over the Neotron Bus. It can also turn the
+//! main power off for us, leaving itself running on the standby rail until
+//! the power button is pressed.
+//!
+//! The BMC exposes a set of 8-bit registers. Every transaction starts with a
+//! four byte request header:
+//!
+//! ```text
+//! <type> <register> <length> <crc8>
+//! ```
+//!
+//! where `<type>` is `0xC0` for a read or `0xC1` for a write. For a write, the
+//! header is followed by `<length>` data bytes and a CRC of those bytes. The
+//! BMC then clocks out `0xFF` until it is ready, followed by a result byte.
+//! For a read, the result byte is followed by `<length>` data bytes and a CRC
+//! of those bytes.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::neobus;
+use embedded_hal::blocking::spi::{Transfer as _, Write as _};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The registers the BMC makes available to us.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Register {
+	/// Read-only. The BMC protocol version.
+	ProtocolVersion = 0x00,
+	/// Read to fetch bytes received from PS/2 Port 0 (the keyboard port). The
+	/// first byte is how many valid bytes follow.
+	Ps2Port0Data = 0x10,
+	/// Write to send bytes to PS/2 Port 0.
+	Ps2Port0Command = 0x11,
+	/// Read to fetch bytes received from PS/2 Port 1 (the mouse port). The
+	/// first byte is how many valid bytes follow.
+	Ps2Port1Data = 0x20,
+	/// Write to send bytes to PS/2 Port 1.
+	Ps2Port1Command = 0x21,
+	/// Write-only. Write `POWER_OFF` to turn the main power rail off.
+	PowerControl = 0x30,
+}
+
+/// The ways in which a BMC transaction can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The Neotron Bus hasn't been set up yet
+	NoBus,
+	/// The BMC never produced a result byte
+	NoResponse,
+	/// The data we read back had a bad CRC
+	BadCrc,
+	/// The BMC rejected our request, with the given result code
+	Rejected(u8),
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// Request type for a register read
+const REQUEST_READ: u8 = 0xC0;
+
+/// Request type for a register write
+const REQUEST_WRITE: u8 = 0xC1;
+
+/// The result byte for a successful request
+const RESULT_OK: u8 = 0xA0;
+
+/// The value for `Register::PowerControl` which turns the power off
+pub const POWER_OFF: u8 = 0x00;
+
+/// How many bytes we clock out waiting for the BMC to respond
+const MAX_RESPONSE_POLLS: usize = 64;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Read `buffer.len()` bytes from a BMC register.
+pub fn read_register(register: Register, buffer: &mut [u8]) -> Result<(), Error> {
+	neobus::with_device(neobus::Device::Bmc, |spi| {
+		send_header(spi, REQUEST_READ, register, buffer.len() as u8);
+		wait_for_result(spi)?;
+		for b in buffer.iter_mut() {
+			*b = 0xFF;
+		}
+		let _ = spi.transfer(buffer);
+		let mut crc = [0xFF];
+		let _ = spi.transfer(&mut crc);
+		if crc[0] != crc8(buffer) {
+			return Err(Error::BadCrc);
+		}
+		Ok(())
+	})
+	.unwrap_or(Err(Error::NoBus))
+}
+
+/// Write some bytes to a BMC register.
+pub fn write_register(register: Register, data: &[u8]) -> Result<(), Error> {
+	neobus::with_device(neobus::Device::Bmc, |spi| {
+		send_header(spi, REQUEST_WRITE, register, data.len() as u8);
+		let _ = spi.write(data);
+		let _ = spi.write(&[crc8(data)]);
+		wait_for_result(spi)
+	})
+	.unwrap_or(Err(Error::NoBus))
+}
+
+/// Send a request header.
+fn send_header(spi: &mut neobus::Spi, request_type: u8, register: Register, length: u8) {
+	let mut header = [request_type, register as u8, length, 0];
+	header[3] = crc8(&header[0..3]);
+	// Writes to the SPI peripheral are infallible on the RP2040
+	let _ = spi.write(&header);
+}
+
+/// Clock out dummy bytes until the BMC gives us a result byte.
+fn wait_for_result(spi: &mut neobus::Spi) -> Result<(), Error> {
+	for _ in 0..MAX_RESPONSE_POLLS {
+		let mut result = [0xFF];
+		let _ = spi.transfer(&mut result);
+		match result[0] {
+			0xFF => {
+				// BMC is still busy
+			}
+			RESULT_OK => return Ok(()),
+			other => return Err(Error::Rejected(other)),
+		}
+	}
+	Err(Error::NoResponse)
+}
+
+/// Calculate a CRC-8 (polynomial 0x07, initial value 0x00) over some bytes.
+fn crc8(data: &[u8]) -> u8 {
+	let mut crc: u8 = 0;
+	for b in data.iter() {
+		crc ^= *b;
+		for _ in 0..8 {
+			if (crc & 0x80) != 0 {
+				crc = (crc << 1) ^ 0x07;
+			} else {
+				crc <<= 1;
+			}
+		}
+	}
+	crc
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
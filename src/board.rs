@@ -0,0 +1,241 @@
+//! # Board description for the Neotron Pico
+//!
+//! Keeps track of what each RP2040 GPIO pin is being used for. Pins which
+//! are wired to something on the board are reserved by `init`. Anything that
+//! wants a pin at run-time (e.g. a PWM output the OS asks for) must reserve
+//! it here first, so it can't take over a pin that is driving the video or
+//! the Neotron Bus.
+//!
+//! It also works out which revision of the board we're on. Neotron Pico 1.1
+//! boards have a 10k pull-down on GPIO22, which on 1.0 boards is left
+//! floating. Every use of GPIO22 is an output (the DAC data, the beeper and
+//! the PSRAM's data in), so the resistor doesn't get in the way.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use defmt::warn;
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The things a GPIO pin can be used for.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum PinUse {
+	/// VGA sync and colour outputs
+	Video,
+	/// The Neotron Bus SPI lines and the I/O expander chip-select
+	NeotronBus,
+	/// The RS-232 serial port
+	Serial,
+	/// The I2C bus to the Real Time Clock
+	I2c,
+	/// Used by the Raspberry Pi Pico module itself (power supply control,
+	/// VBUS sense, the LED and VSYS sense)
+	PicoModule,
+	/// An OS-controlled PWM output
+	Pwm,
+	/// An analogue paddle or joystick axis, on the ADC
+	Analogue,
+	/// The I2S link to the audio codec
+	Audio,
+	/// The PWM beeper, on boards without the audio codec
+	Beeper,
+	/// External PSRAM, on boards without the audio codec
+	Psram,
+}
+
+/// The revisions of the Neotron Pico board we can tell apart.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Revision {
+	/// Neotron Pico 1.0 (no strap)
+	V1_0 = 0,
+	/// Neotron Pico 1.1 (GPIO22 strapped low)
+	V1_1 = 1,
+}
+
+/// The ways in which reserving a pin can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// There is no such GPIO pin
+	NoSuchPin,
+	/// The pin is already being used for something else
+	InUse(PinUse),
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How many GPIO pins the RP2040 has
+pub const NUM_PINS: usize = 30;
+
+/// The pins which are wired to something on the board, and what to.
+///
+/// GPIO22 and GPIO26 to GPIO28 are spare, until the OS claims them for
+/// audio, the beeper, PWM or the paddles (or we find PSRAM on them).
+const FIXED_PINS: [(core::ops::RangeInclusive<u8>, PinUse); 6] = [
+	(0..=13, PinUse::Video),
+	(14..=15, PinUse::I2c),
+	(16..=19, PinUse::NeotronBus),
+	(20..=21, PinUse::Serial),
+	(23..=25, PinUse::PicoModule),
+	(29..=29, PinUse::PicoModule),
+];
+
+/// The pin which tells us the board revision
+const REVISION_STRAP_PIN: u8 = 22;
+
+/// How long to let the pull-up charge the pin before we read it, in clock
+/// cycles. That's a few microseconds at any of our clock speeds, which is
+/// plenty for a pin with nothing else on it.
+const REVISION_SETTLE_CYCLES: u32 = 1_000;
+
+/// The NULL function number, for `GPIOx_CTRL.FUNCSEL`. The pin isn't
+/// connected to any peripheral.
+pub const FUNCSEL_NULL: u8 = 0x1F;
+
+/// What each pin is currently used for.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut PIN_USES: [Option<PinUse>; NUM_PINS] = [None; NUM_PINS];
+
+/// Which board we're on, as found by `init`.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut REVISION: Revision = Revision::V1_0;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+impl Revision {
+	/// A name for the boot banner.
+	pub fn name(self) -> &'static str {
+		match self {
+			Revision::V1_0 => "Neotron Pico 1.0",
+			Revision::V1_1 => "Neotron Pico 1.1",
+		}
+	}
+}
+
+/// Reserve all the pins that are wired to something on the board, and work
+/// out which board revision this is.
+///
+/// Call this before anything else uses GPIO22.
+pub fn init() {
+	let revision = read_revision_strap();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		REVISION = revision;
+	}
+	for (pins, usage) in FIXED_PINS.iter() {
+		for pin in pins.clone() {
+			// Note (safety): Only called from Core 0, and never from an
+			// interrupt.
+			unsafe {
+				PIN_USES[pin as usize] = Some(*usage);
+			}
+		}
+	}
+}
+
+/// Claim a pin for the given use.
+///
+/// Fails if the pin is already being used for something else. Reserving a
+/// pin twice for the same use is fine.
+pub fn reserve(pin: u8, usage: PinUse) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let slot = unsafe { PIN_USES.get_mut(pin as usize) }.ok_or(Error::NoSuchPin)?;
+	match *slot {
+		Some(existing) if existing != usage => {
+			warn!("Can't use GPIO{} for {}: used for {}", pin, usage, existing);
+			Err(Error::InUse(existing))
+		}
+		_ => {
+			*slot = Some(usage);
+			Ok(())
+		}
+	}
+}
+
+/// Give back a pin that was claimed with `reserve`.
+///
+/// Does nothing if the pin is being used for something else.
+pub fn release(pin: u8, usage: PinUse) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	if let Some(slot) = unsafe { PIN_USES.get_mut(pin as usize) } {
+		if *slot == Some(usage) {
+			*slot = None;
+		}
+	}
+}
+
+/// Find out what a pin is being used for.
+pub fn pin_use(pin: u8) -> Option<PinUse> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { PIN_USES.get(pin as usize).copied().flatten() }
+}
+
+/// Which revision of the board we're on.
+pub fn revision() -> Revision {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { REVISION }
+}
+
+/// Connect a GPIO pin to a peripheral (`GPIOx_CTRL.FUNCSEL`).
+///
+/// The HAL's `Pins` structure owns IO_BANK0, but this must only be used on
+/// pins which have been reserved, and which the HAL isn't using.
+pub fn set_function(pin: u8, funcsel: u8) {
+	// Note (safety): See above. Each pin has its own control register.
+	let io_bank0 = unsafe { &*pac::IO_BANK0::ptr() };
+	io_bank0.gpio[pin as usize]
+		.gpio_ctrl
+		.write(|w| unsafe { w.funcsel().bits(funcsel) });
+}
+
+/// See if the revision strap pulls GPIO22 low, against the pad's pull-up.
+fn read_revision_strap() -> Revision {
+	let pin = REVISION_STRAP_PIN as usize;
+	// Note (safety): Nothing has been given GPIO22 yet. We only touch its
+	// own pad register, and only read the inputs.
+	let (pads, sio) = unsafe { (&*pac::PADS_BANK0::ptr(), &*pac::SIO::ptr()) };
+	pads.gpio[pin].modify(|_r, w| w.ie().set_bit().pue().set_bit().pde().clear_bit());
+	cortex_m::asm::delay(REVISION_SETTLE_CYCLES);
+	let strapped = (sio.gpio_in.read().bits() & (1 << pin)) == 0;
+	// Put the pad back to how it came out of reset
+	pads.gpio[pin].modify(|_r, w| w.pue().clear_bit().pde().set_bit());
+	if strapped {
+		Revision::V1_1
+	} else {
+		Revision::V1_0
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
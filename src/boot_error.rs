@@ -0,0 +1,263 @@
+//! # Early boot error reporting for the Neotron Pico
+//!
+//! If something goes wrong before the video is running, there is nowhere to
+//! print an error message. Instead, we blink the Pico's on-board LED a number
+//! of times (one blink for `Stage::Crystal`, two for `Stage::SysPll`, and so
+//! on) then pause, forever. If the Neotron Bus is up, we also show the stage
+//! number in binary on the keyboard LEDs.
+//!
+//! Some stages (like starting the video) fail now and again for reasons that
+//! a reset clears up. Those use `retry_or_fail`, which resets the chip and
+//! counts the attempts in `WATCHDOG.SCRATCH2`, only giving up after
+//! `MAX_RETRIES` resets in a row.
+//!
+//! The power-on self test uses `warn` instead, which blinks the code out
+//! just once and carries on. If there's a beeper, it beeps the code out
+//! too.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{beeper, bmc, serial, sysclock};
+use defmt::{error, warn};
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The stages of start-up that can fail.
+///
+/// The discriminant is how many times the LED blinks.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+#[repr(u8)]
+pub enum Stage {
+	/// The crystal oscillator didn't start
+	Crystal = 1,
+	/// The System PLL didn't lock
+	SysPll = 2,
+	/// The USB PLL didn't lock
+	UsbPll = 3,
+	/// We couldn't switch the clocks over to the PLLs
+	Clocks = 4,
+	/// We couldn't load the video programs into the PIO
+	VideoPio = 5,
+	/// The video timing DMA didn't complete any transfers
+	VideoTiming = 6,
+	/// The video pixel DMA didn't complete any transfers
+	VideoPixels = 7,
+	/// The power-on self test found a bad word in the OS RAM
+	PostRam = 8,
+	/// The OS image in flash doesn't match the checksum we were built with
+	PostOsImage = 9,
+	/// The real-time clock didn't answer on the I2C bus
+	PostRtc = 10,
+	/// The audio codec didn't answer on the I2C bus
+	PostCodec = 11,
+	/// The BMC didn't answer on the Neotron Bus
+	PostBmc = 12,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The Pico's on-board LED is on GPIO25
+const LED_PIN: usize = 25;
+
+/// The SIO function number, for `GPIOx_CTRL.FUNCSEL`
+const FUNCSEL_SIO: u8 = 5;
+
+/// Roughly how many CPU cycles per millisecond before the PLLs are running.
+/// The ring oscillator is nominally 6.5 MHz, but varies a lot.
+const ROSC_CYCLES_PER_MS: u32 = 6_500;
+
+/// The pitch we beep error codes at
+const BEEP_FREQUENCY_HZ: u32 = 440;
+
+/// Sets the keyboard LEDs
+const PS2_COMMAND_SET_LEDS: u8 = 0xED;
+
+/// How many times in a row we reset and try again before giving up
+const MAX_RETRIES: u32 = 3;
+
+/// The top half of the retry count in `WATCHDOG.SCRATCH2`, so we can tell
+/// our count from whatever was in there before.
+const RETRY_MAGIC: u32 = 0x4E45_0000;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Report that start-up failed at the given stage, and never return.
+pub fn fail(stage: Stage) -> ! {
+	error!("Boot failed at stage {} ({})", stage as u8, stage);
+
+	// We only get our fast clock once the clock set-up has worked
+	let cycles_per_ms = if stage > Stage::Clocks {
+		pll_cycles_per_ms()
+	} else {
+		ROSC_CYCLES_PER_MS
+	};
+
+	// Scroll Lock, Num Lock and Caps Lock are bits 0, 1 and 2. This does
+	// nothing if the Neotron Bus isn't running yet.
+	let _ = bmc::write_register(
+		bmc::Register::Ps2Port0Command,
+		&[PS2_COMMAND_SET_LEDS, stage as u8 & 0x07],
+	);
+
+	led_init();
+	loop {
+		blink(stage, cycles_per_ms, false);
+	}
+}
+
+/// Report a problem which doesn't stop start-up, by blinking the stage out
+/// on the LED just once.
+///
+/// Only for stages after the clocks are running.
+pub fn warn(stage: Stage) {
+	warn!("Boot stage {} had a problem ({})", stage as u8, stage);
+	led_init();
+	// A missing codec is why we have a beeper, so don't beep about it
+	let beep = stage != Stage::PostCodec;
+	blink(stage, pll_cycles_per_ms(), beep);
+}
+
+/// Report that start-up failed at the given stage, and reset to try again.
+///
+/// If we have already tried `MAX_RETRIES` times, give up and `fail`. The
+/// message also goes to the serial port, as the screen may not be working.
+pub fn retry_or_fail(stage: Stage) -> ! {
+	// Note (safety): Only we use `SCRATCH2` (see `watchdog` for who uses
+	// which scratch register).
+	let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+	let scratch = watchdog.scratch2.read().bits();
+	let retries = if (scratch & 0xFFFF_0000) == RETRY_MAGIC {
+		scratch & 0xFFFF
+	} else {
+		0
+	};
+	error!("Boot stage {} failed (retry {})", stage, retries);
+	serial::write_blocking(b"\r\nBIOS: ");
+	serial::write_blocking(stage.description().as_bytes());
+	if retries < MAX_RETRIES {
+		serial::write_blocking(b" - resetting to try again\r\n");
+		watchdog
+			.scratch2
+			.write(|w| unsafe { w.bits(RETRY_MAGIC | (retries + 1)) });
+		crate::watchdog::reboot();
+	}
+	serial::write_blocking(b" - giving up\r\n");
+	clear_retries();
+	fail(stage)
+}
+
+/// Start-up got past all the stages we retry, so start counting again next
+/// time.
+pub fn clear_retries() {
+	// Note (safety): Only we use `SCRATCH2`, as above.
+	let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+	watchdog.scratch2.write(|w| unsafe { w.bits(0) });
+}
+
+impl Stage {
+	/// A human readable description of what went wrong.
+	pub fn description(self) -> &'static str {
+		match self {
+			Stage::Crystal => "Crystal oscillator did not start",
+			Stage::SysPll => "System PLL did not lock",
+			Stage::UsbPll => "USB PLL did not lock",
+			Stage::Clocks => "Could not switch to the PLL clocks",
+			Stage::VideoPio => "Could not load the video PIO programs",
+			Stage::VideoTiming => "Video timing DMA did not start",
+			Stage::VideoPixels => "Video pixel DMA did not start",
+			Stage::PostRam => "OS RAM failed the self test",
+			Stage::PostOsImage => "OS image in flash is corrupt",
+			Stage::PostRtc => "Real-time clock not found",
+			Stage::PostCodec => "Audio codec not found",
+			Stage::PostBmc => "BMC not found",
+		}
+	}
+}
+
+/// Make the LED pin an output, without relying on the HAL.
+///
+/// We don't know how far start-up got, so we take the GPIO block out of
+/// reset ourselves.
+fn led_init() {
+	// Note (safety): We're never going to return to the code that owns
+	// these peripherals, so nothing else is using them.
+	let resets = unsafe { &*pac::RESETS::ptr() };
+	let io_bank0 = unsafe { &*pac::IO_BANK0::ptr() };
+	let sio = unsafe { &*pac::SIO::ptr() };
+
+	resets
+		.reset
+		.modify(|_r, w| w.io_bank0().clear_bit().pads_bank0().clear_bit());
+	while resets.reset_done.read().io_bank0().bit_is_clear()
+		|| resets.reset_done.read().pads_bank0().bit_is_clear()
+	{}
+
+	io_bank0.gpio[LED_PIN]
+		.gpio_ctrl
+		.write(|w| unsafe { w.funcsel().bits(FUNCSEL_SIO) });
+	sio.gpio_oe_set.write(|w| unsafe { w.bits(1 << LED_PIN) });
+}
+
+/// How many CPU cycles per millisecond once we are running from the PLL.
+fn pll_cycles_per_ms() -> u32 {
+	sysclock::current().sys_clock_hz() / 1000
+}
+
+/// Blink the stage number out on the LED (and maybe beep it out too), then
+/// pause.
+fn blink(stage: Stage, cycles_per_ms: u32, beep: bool) {
+	for _ in 0..(stage as u8) {
+		led_set(true);
+		if beep {
+			// This does nothing if there's no beeper
+			let _ = beeper::tone(BEEP_FREQUENCY_HZ, 200);
+		}
+		cortex_m::asm::delay(200 * cycles_per_ms);
+		led_set(false);
+		cortex_m::asm::delay(300 * cycles_per_ms);
+	}
+	cortex_m::asm::delay(1500 * cycles_per_ms);
+}
+
+/// Turn the on-board LED on or off.
+fn led_set(on: bool) {
+	// Note (safety): These are atomic set/clear registers.
+	let sio = unsafe { &*pac::SIO::ptr() };
+	if on {
+		sio.gpio_out_set.write(|w| unsafe { w.bits(1 << LED_PIN) });
+	} else {
+		sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << LED_PIN) });
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,250 @@
+//! # Raw boot slots for the Neotron Pico
+//!
+//! An SD card doesn't need a filesystem to be bootable. If block 0 doesn't
+//! look like a FAT volume (or an MBR with a FAT partition), we look for OS
+//! images in a number of fixed-size *boot slots* instead. This lets a test
+//! rig write an image with `dd` and boot it, without any partitioning.
+//!
+//! Slot `n` starts at block `SLOT_BASE_BLOCK + (n * SLOT_SIZE_BLOCKS)`. The
+//! first block of a slot is a header:
+//!
+//! | Offset | Size | Contents                                      |
+//! |:-------|:-----|:----------------------------------------------|
+//! | 0      | 8    | The magic bytes `NEOBOOT\0`                   |
+//! | 8      | 4    | Header version (currently 1)                  |
+//! | 12     | 4    | Image length, in bytes                        |
+//! | 16     | 4    | Load address                                  |
+//! | 20     | 4    | CRC-32 (IEEE) of the image                    |
+//! | 24     | 4    | Sequence number - the highest valid one boots |
+//! | 28     | 36   | Image name, null-padded ASCII                 |
+//!
+//! All values are little-endian. The image itself starts in the next block.
+//! Like the OS image in flash, the first word of the loaded image must be a
+//! pointer to the OS start function.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use defmt::{debug, info, warn};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// One block from a block device
+pub type Block = [u8; BLOCK_SIZE];
+
+/// A decoded boot slot header.
+#[derive(Clone, PartialEq, Eq, defmt::Format)]
+pub struct Header {
+	/// Which slot this header came from
+	pub slot: u8,
+	/// How long the image is, in bytes
+	pub length: u32,
+	/// Where the image must be copied to
+	pub load_address: u32,
+	/// The CRC-32 of the image
+	pub crc32: u32,
+	/// Higher numbers are newer
+	pub sequence: u32,
+}
+
+/// The ways in which loading from a slot can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The block device reported an error
+	ReadFailed,
+	/// The card has a FAT filesystem, so we don't look for slots
+	HasFilesystem,
+	/// None of the slots had a valid header
+	NoImage,
+	/// The image doesn't fit in the RAM we are allowed to load it into
+	BadLoadAddress,
+	/// The image we loaded didn't match its CRC
+	BadCrc,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The size of a block, in bytes
+pub const BLOCK_SIZE: usize = 512;
+
+/// How many boot slots we scan
+pub const NUM_SLOTS: u8 = 4;
+
+/// The first slot starts 1 MiB in, clear of any partition table.
+pub const SLOT_BASE_BLOCK: u64 = 2048;
+
+/// Each slot is 1 MiB long (including the header block)
+pub const SLOT_SIZE_BLOCKS: u64 = 2048;
+
+/// The magic bytes at the start of every slot header
+const HEADER_MAGIC: &[u8; 8] = b"NEOBOOT\0";
+
+/// The only header version we understand
+const HEADER_VERSION: u32 = 1;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Check whether block 0 of a card holds a FAT filesystem.
+///
+/// This is true for a bare FAT volume, and for an MBR with a FAT partition
+/// in it.
+pub fn has_fat_filesystem(block0: &Block) -> bool {
+	if block0[510..512] != [0x55, 0xAA] {
+		return false;
+	}
+	// A FAT boot sector says so in the Extended BIOS Parameter Block
+	if &block0[54..57] == b"FAT" || &block0[82..85] == b"FAT" {
+		return true;
+	}
+	// Otherwise look for a FAT partition type in the MBR
+	block0[446..510].chunks(16).any(|entry| {
+		matches!(
+			entry[4],
+			0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E | 0x11 | 0x14 | 0x16 | 0x1B | 0x1C | 0x1E
+		)
+	})
+}
+
+/// Find the newest valid boot slot on a card, and copy its image into RAM.
+///
+/// The `read_block` function reads one block from the card. The image must
+/// fit entirely within `ram`. Returns the header of the image we loaded.
+pub fn load<F>(mut read_block: F, ram: &mut [u8]) -> Result<Header, Error>
+where
+	F: FnMut(u64, &mut Block) -> Result<(), ()>,
+{
+	let mut block = [0u8; BLOCK_SIZE];
+	read_block(0, &mut block).map_err(|_| Error::ReadFailed)?;
+	if has_fat_filesystem(&block) {
+		return Err(Error::HasFilesystem);
+	}
+
+	let mut best: Option<Header> = None;
+	for slot in 0..NUM_SLOTS {
+		let first_block = slot_start(slot);
+		if read_block(first_block, &mut block).is_err() {
+			warn!("Boot slot {} unreadable", slot);
+			continue;
+		}
+		if let Some(header) = Header::parse(slot, &block) {
+			debug!("Boot slot {}: {}", slot, header);
+			let newer = best
+				.as_ref()
+				.map(|b| header.sequence > b.sequence)
+				.unwrap_or(true);
+			if newer {
+				best = Some(header);
+			}
+		}
+	}
+	let header = best.ok_or(Error::NoImage)?;
+
+	// The image has to fit inside the RAM we were given
+	let ram_start = ram.as_ptr() as usize as u32;
+	let offset = header
+		.load_address
+		.checked_sub(ram_start)
+		.ok_or(Error::BadLoadAddress)? as usize;
+	let dest = ram
+		.get_mut(offset..offset + header.length as usize)
+		.ok_or(Error::BadLoadAddress)?;
+
+	// Copy the image a block at a time
+	let mut lba = slot_start(header.slot) + 1;
+	for chunk in dest.chunks_mut(BLOCK_SIZE) {
+		read_block(lba, &mut block).map_err(|_| Error::ReadFailed)?;
+		chunk.copy_from_slice(&block[0..chunk.len()]);
+		lba += 1;
+	}
+
+	if crc32(dest) != header.crc32 {
+		return Err(Error::BadCrc);
+	}
+
+	info!(
+		"Loaded {} bytes from boot slot {} to 0x{:08x}",
+		header.length, header.slot, header.load_address
+	);
+	Ok(header)
+}
+
+/// Get the first block of the given slot.
+pub const fn slot_start(slot: u8) -> u64 {
+	SLOT_BASE_BLOCK + (slot as u64 * SLOT_SIZE_BLOCKS)
+}
+
+/// Calculate the IEEE CRC-32 (as used by zip, Ethernet, etc) of some bytes.
+pub fn crc32(data: &[u8]) -> u32 {
+	let mut crc: u32 = 0xFFFF_FFFF;
+	for b in data.iter() {
+		crc ^= *b as u32;
+		for _ in 0..8 {
+			let mask = (crc & 1).wrapping_neg();
+			crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+		}
+	}
+	!crc
+}
+
+impl Header {
+	/// Decode a header block. Returns `None` if the block isn't a valid header.
+	fn parse(slot: u8, block: &Block) -> Option<Header> {
+		if &block[0..8] != HEADER_MAGIC {
+			return None;
+		}
+		let word = |offset: usize| {
+			u32::from_le_bytes([
+				block[offset],
+				block[offset + 1],
+				block[offset + 2],
+				block[offset + 3],
+			])
+		};
+		if word(8) != HEADER_VERSION {
+			return None;
+		}
+		let header = Header {
+			slot,
+			length: word(12),
+			load_address: word(16),
+			crc32: word(20),
+			sequence: word(24),
+		};
+		// The image has to fit in the slot, after the header
+		let max_length = (SLOT_SIZE_BLOCKS - 1) * BLOCK_SIZE as u64;
+		if header.length == 0 || u64::from(header.length) > max_length {
+			return None;
+		}
+		Some(header)
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
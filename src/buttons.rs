@@ -0,0 +1,176 @@
+//! # Button input for the Neotron Pico
+//!
+//! Up to four push-buttons (a front panel, or a digital joystick) can be
+//! wired to the I/O expander. Mechanical switches bounce, so we only believe
+//! a button has changed once it has been stable for a while. A button that is
+//! held down repeats, like a key on a keyboard. The timings come from the
+//! BIOS configuration.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{config, neobus};
+use defmt::trace;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Something happened to a button.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum ButtonEvent {
+	/// The given button was pressed
+	Pressed(u8),
+	/// The given button has been held down long enough to repeat
+	Repeated(u8),
+	/// The given button was released
+	Released(u8),
+}
+
+/// The debounce state of one button.
+#[derive(Copy, Clone)]
+struct Button {
+	/// The state we have reported
+	pressed: bool,
+	/// The raw state we last sampled
+	raw: bool,
+	/// When `raw` last changed
+	raw_changed_at: u32,
+	/// When we should next send a repeat (if held)
+	next_repeat_at: u32,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How many buttons there are
+pub const NUM_BUTTONS: usize = 4;
+
+/// The state of each button.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut BUTTONS: [Button; NUM_BUTTONS] = [Button::new(); NUM_BUTTONS];
+
+/// Button events we haven't given to the OS yet.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut EVENTS: heapless::Deque<ButtonEvent, 16> = heapless::Deque::new();
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Sample the buttons and generate any events.
+///
+/// Call this regularly - the debounce and repeat timing is only as good as
+/// the rate at which this is called.
+pub fn poll() {
+	let inputs = match neobus::read_inputs() {
+		Some(inputs) => inputs,
+		None => return,
+	};
+	let now = crate::ticks_ms();
+	let config = config::get();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let buttons = unsafe { &mut BUTTONS };
+	for (idx, button) in buttons.iter_mut().enumerate() {
+		let raw = (inputs & neobus::input_button(idx)) != 0;
+		if let Some(event) = button.update(idx as u8, raw, now, &config) {
+			trace!("Button: {}", event);
+			push_event(event);
+		}
+	}
+}
+
+/// Get the next button event, if any.
+pub fn get_event() -> Option<ButtonEvent> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { EVENTS.pop_front() }
+}
+
+/// Queue up a button event. If the queue is full, the oldest event is dropped.
+fn push_event(event: ButtonEvent) {
+	// Someone is using the machine, so make sure they can see the screen
+	crate::vga::mark_dirty();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		if EVENTS.is_full() {
+			let _ = EVENTS.pop_front();
+		}
+		let _ = EVENTS.push_back(event);
+	}
+}
+
+impl Button {
+	/// A button which isn't pressed.
+	const fn new() -> Button {
+		Button {
+			pressed: false,
+			raw: false,
+			raw_changed_at: 0,
+			next_repeat_at: 0,
+		}
+	}
+
+	/// Update the button with a new sample, and return an event if one is due.
+	fn update(
+		&mut self,
+		idx: u8,
+		raw: bool,
+		now: u32,
+		config: &config::Config,
+	) -> Option<ButtonEvent> {
+		if raw != self.raw {
+			// Still bouncing - start timing again
+			self.raw = raw;
+			self.raw_changed_at = now;
+			return None;
+		}
+
+		let stable_for = now.wrapping_sub(self.raw_changed_at);
+		if raw != self.pressed && stable_for >= u32::from(config.button_debounce_ms) {
+			self.pressed = raw;
+			if raw {
+				self.next_repeat_at = now.wrapping_add(u32::from(config.button_repeat_delay_ms));
+				return Some(ButtonEvent::Pressed(idx));
+			} else {
+				return Some(ButtonEvent::Released(idx));
+			}
+		}
+
+		// This comparison copes with `ticks_ms` wrapping
+		let repeat_due = (now.wrapping_sub(self.next_repeat_at) as i32) >= 0;
+		if self.pressed && config.button_repeat_interval_ms != 0 && repeat_due {
+			self.next_repeat_at = now.wrapping_add(u32::from(config.button_repeat_interval_ms));
+			return Some(ButtonEvent::Repeated(idx));
+		}
+
+		None
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,587 @@
+//! # BIOS configuration for the Neotron Pico
+//!
+//! To the OS, the configuration is just a block of bytes it can get and set.
+//! To the BIOS, it is a `Config` structure. This module converts between the
+//! two, and holds the configuration currently in force.
+//!
+//! The encoded form starts with a version byte. Fields are only ever added
+//! to the end, so an older (shorter) block can still be read - any missing
+//! fields take their default value. The encoded form must never be more than
+//! `MAX_ENCODED_LEN` bytes.
+//!
+//! The configuration is stored as a 64 byte record, so it survives a power
+//! cycle:
+//!
+//! | Offset | Length | Contents                                           |
+//! |--------|--------|----------------------------------------------------|
+//! | 0      | 1      | Length of the encoded form                         |
+//! | 1      | 62     | The encoded form, padded with zeroes               |
+//! | 63     | 1      | Checksum - the inverse of the sum of bytes 0 to 62 |
+//!
+//! The record is saved in two places:
+//!
+//! * The battery-backed SRAM inside the RTC.
+//! * A journal in the last sector of flash, for systems without an RTC
+//!   battery. Each save goes in the next free slot of the sector, and the
+//!   sector is only erased when every slot has been used, so the flash
+//!   isn't worn out by frequent saves. The newest good record wins.
+//!
+//! At start-up we use the RTC copy if it is good, then the flash copy. If
+//! neither is good, we use the default configuration.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::vga::{self, charmap};
+use crate::{flash, keymap, logger, neobus, paddles, rtc, sysclock};
+use defmt::{debug, warn};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Everything the BIOS can be configured to do.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Config {
+	/// How long a button must be stable before we believe it, in ms
+	pub button_debounce_ms: u16,
+	/// How long a button must be held before it starts repeating, in ms
+	pub button_repeat_delay_ms: u16,
+	/// How often a held button repeats, in ms. Zero means no repeat.
+	pub button_repeat_interval_ms: u16,
+	/// Start the screen saver after this many seconds with no input and
+	/// nothing changing on screen. Zero means never.
+	pub video_blank_after_secs: u16,
+	/// How the text console turns characters into glyphs. Should match the
+	/// font.
+	pub char_encoding: charmap::Encoding,
+	/// Send the boot console to the serial port as a plain transcript (for
+	/// a braille display or speech device), rather than with ANSI escape
+	/// sequences.
+	pub serial_transcript: bool,
+	/// CRT-style video effects, as a combination of the `vga::EFFECT_xxx`
+	/// bits.
+	pub video_effects: u8,
+	/// Which analogue paddle axes are enabled, as a bit-mask (bit `n` is
+	/// axis `n`)
+	pub paddle_axes: u8,
+	/// The ADC reading for each paddle axis at one end of its travel
+	pub paddle_min: [u16; paddles::NUM_AXES],
+	/// The ADC reading for each paddle axis at the other end of its travel
+	pub paddle_max: [u16; paddles::NUM_AXES],
+	/// What the screen saver shows
+	pub video_screen_saver: vga::ScreenSaverStyle,
+	/// How the BIOS reads the keyboard, for anything typed before the OS
+	/// loads
+	pub keyboard_layout: keymap::Layout,
+	/// How much the BIOS says when it starts up
+	pub boot_style: BootStyle,
+	/// How many seconds we count down for before starting the OS. Not used
+	/// in `BootStyle::Quiet`.
+	pub boot_delay_secs: u8,
+	/// Wait for a new OS image over the serial port (with YMODEM) every
+	/// time we start up, rather than only when asked to.
+	pub serial_boot: bool,
+	/// Believe the SD card slot's card-detect switch. Turn this off if your
+	/// slot doesn't have one, and we'll look for a card every time.
+	pub sd_card_detect: bool,
+	/// The expansion slot (1 to 4) with an SD card adapter in it, which the
+	/// OS sees as block device 2. Zero means there isn't one.
+	pub storage_slot: u8,
+	/// Believe the headphone socket's detect switch, and only drive the
+	/// headphones when they are plugged in. Turn this off if your socket
+	/// doesn't have one, and we'll drive them all the time.
+	pub headphone_detect: bool,
+	/// How fast the system clock runs. Takes effect at the next reset.
+	pub clock_profile: sysclock::Profile,
+	/// The monitor timings to start up with (e.g. for a 15 kHz RGB
+	/// monitor, which can't show the standard modes). The OS can change
+	/// them with `video_set_timing`. Takes effect at the next reset.
+	pub video_timing: vga::TimingPreset,
+	/// Where to copy the BIOS's log messages, as a combination of the
+	/// `logger::MIRROR_xxx` bits. They always go to the debugger.
+	pub log_mirror: u8,
+	/// Where we look for the OS
+	pub boot_order: BootOrder,
+	/// The serial port's baud rate, until the OS changes it
+	pub serial_baud: u32,
+}
+
+/// How much the BIOS says when it starts up.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum BootStyle {
+	/// The version, the licence, the self test results, and a countdown
+	Verbose = 0,
+	/// The version and the self test results, and a countdown
+	Short = 1,
+	/// Nothing, unless the self test found a problem, and no countdown
+	Quiet = 2,
+}
+
+/// Where we look for the OS.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum BootOrder {
+	/// An SD card (a file, then the raw boot slots), then our flash
+	SdCardThenFlash = 0,
+	/// Only our flash, even if there's an SD card with an OS on it
+	FlashOnly = 1,
+}
+
+/// The ways in which a configuration block can be bad.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The block was empty, or has a version we don't understand
+	BadVersion,
+	/// The caller's buffer is too small for the encoded block
+	BufferTooSmall,
+	/// A field has a value we don't understand
+	BadValue,
+	/// The stored configuration has a bad checksum or length
+	BadChecksum,
+	/// We couldn't talk to the RTC
+	Storage(rtc::Error),
+	/// We couldn't read or write the flash journal
+	Flash(flash::Error),
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The current version of the encoded form
+const CONFIG_VERSION: u8 = 1;
+
+/// The encoded form is never longer than this (so it fits in the RTC's
+/// battery-backed SRAM, with room for a length and a checksum).
+pub const MAX_ENCODED_LEN: usize = rtc::SRAM_LEN - 2;
+
+/// The length of the encoded form for `CONFIG_VERSION`
+const ENCODED_LEN: usize = 41;
+
+/// How long a stored record is
+const RECORD_LEN: usize = rtc::SRAM_LEN;
+
+/// Holds a stored configuration
+type Record = [u8; RECORD_LEN];
+
+extern "C" {
+	static mut _flash_config_start: u32;
+	static mut _flash_config_len: u32;
+}
+
+/// The configuration currently in force.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut CURRENT: Config = Config::default();
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Get a copy of the configuration currently in force.
+pub fn get() -> Config {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { CURRENT }
+}
+
+/// Change the configuration currently in force.
+///
+/// Most modules call `get` whenever they need a setting. Anything used on
+/// Core 1 is pushed out from here instead.
+pub fn set(config: Config) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		CURRENT = config;
+	}
+	vga::set_blank_timeout(config.video_blank_after_secs);
+	vga::set_effects(config.video_effects);
+	vga::set_screen_saver_style(config.video_screen_saver);
+	paddles::set_enabled_axes(config.paddle_axes);
+	charmap::set_encoding(config.char_encoding);
+	logger::set_mirror(config.log_mirror);
+}
+
+/// Load the stored configuration, and put it in force.
+///
+/// If it can't be loaded, we use the default configuration.
+pub fn load() {
+	let config = match read_rtc() {
+		Ok(config) => {
+			debug!("Loaded config from RTC: {}", config);
+			config
+		}
+		Err(rtc_error) => match read_flash() {
+			Ok(config) => {
+				debug!("Loaded config from flash ({}): {}", rtc_error, config);
+				config
+			}
+			Err(flash_error) => {
+				warn!("Using default config: {}, {}", rtc_error, flash_error);
+				Config::default()
+			}
+		},
+	};
+	set(config);
+}
+
+/// Get the clock profile from the configuration stored in flash.
+///
+/// This is for use before the clocks are running, when we can't talk to the
+/// RTC. It doesn't need `flash::init`. If there's no good configuration, we
+/// use the default profile.
+pub fn stored_clock_profile() -> sysclock::Profile {
+	match read_flash() {
+		Ok(config) => config.clock_profile,
+		Err(_) => Config::default().clock_profile,
+	}
+}
+
+/// Save the given configuration, so `load` will find it after a power
+/// cycle.
+///
+/// It's only an error if we couldn't save it anywhere.
+pub fn save(config: &Config) -> Result<(), Error> {
+	let record = pack(config)?;
+	let rtc_result = rtc::write_sram(&record).map_err(Error::Storage);
+	if let Err(e) = rtc_result {
+		warn!("Can't save config to RTC: {}", e);
+	}
+	let flash_result = write_flash(&record);
+	if let Err(e) = flash_result {
+		warn!("Can't save config to flash: {}", e);
+	}
+	rtc_result.or(flash_result)
+}
+
+/// Read and check the configuration stored in the RTC.
+fn read_rtc() -> Result<Config, Error> {
+	let mut record: Record = [0u8; RECORD_LEN];
+	rtc::read_sram(&mut record).map_err(Error::Storage)?;
+	unpack(&record)
+}
+
+/// Find the newest good configuration in the flash journal.
+fn read_flash() -> Result<Config, Error> {
+	let (start, len) = flash_area();
+	let mut newest = Err(Error::BadChecksum);
+	let mut record: Record = [0u8; RECORD_LEN];
+	for slot in 0..(len / RECORD_LEN) {
+		flash::read(start + (slot * RECORD_LEN), &mut record).map_err(Error::Flash)?;
+		if is_erased(&record) {
+			// Records are written in order, so there are no more
+			break;
+		}
+		// Skip anything half-written (e.g. if we lost power)
+		if let Ok(config) = unpack(&record) {
+			newest = Ok(config);
+		}
+	}
+	newest
+}
+
+/// Append a record to the flash journal, erasing the sector first if it is
+/// full.
+///
+/// Does nothing if the newest record already holds this configuration.
+fn write_flash(record: &Record) -> Result<(), Error> {
+	let (start, len) = flash_area();
+	let mut existing: Record = [0u8; RECORD_LEN];
+	let mut last_used = None;
+	let mut free_slot = None;
+	for slot in 0..(len / RECORD_LEN) {
+		flash::read(start + (slot * RECORD_LEN), &mut existing).map_err(Error::Flash)?;
+		if is_erased(&existing) {
+			free_slot = Some(slot);
+			break;
+		}
+		last_used = Some(existing);
+	}
+	if last_used.as_ref() == Some(record) {
+		return Ok(());
+	}
+	let slot = match free_slot {
+		Some(slot) => slot,
+		None => {
+			// Note (safety): The linker script reserves this sector for us.
+			unsafe { flash::erase(start, len) }.map_err(Error::Flash)?;
+			0
+		}
+	};
+
+	// We can only program whole pages, but programming bytes of 0xFF
+	// leaves the other slots in the page alone.
+	let offset = slot * RECORD_LEN;
+	let page_offset = offset - (offset % flash::PAGE_SIZE);
+	let mut page = [0xFFu8; flash::PAGE_SIZE];
+	let in_page = offset - page_offset;
+	page[in_page..in_page + RECORD_LEN].copy_from_slice(record);
+	// Note (safety): The linker script reserves this sector for us, and
+	// `page` is on the stack.
+	unsafe { flash::program(start + page_offset, &page) }.map_err(Error::Flash)
+}
+
+/// Get the offset and length of the flash sector the journal lives in.
+fn flash_area() -> (usize, usize) {
+	// Note (safety): We're only taking the addresses of the linker symbols.
+	let start = unsafe { &mut _flash_config_start as *mut u32 as usize };
+	let len = unsafe { &mut _flash_config_len as *const u32 as usize };
+	(start - flash::XIP_BASE, len)
+}
+
+/// Is this record blank flash?
+fn is_erased(record: &Record) -> bool {
+	record.iter().all(|b| *b == 0xFF)
+}
+
+/// Encode a configuration into a record, ready to store.
+fn pack(config: &Config) -> Result<Record, Error> {
+	let mut record: Record = [0u8; RECORD_LEN];
+	let len = config.encode(&mut record[1..=MAX_ENCODED_LEN])?;
+	record[0] = len as u8;
+	record[RECORD_LEN - 1] = checksum(&record[0..RECORD_LEN - 1]);
+	Ok(record)
+}
+
+/// Check and decode a stored record.
+fn unpack(record: &Record) -> Result<Config, Error> {
+	if record[RECORD_LEN - 1] != checksum(&record[0..RECORD_LEN - 1]) {
+		return Err(Error::BadChecksum);
+	}
+	let len = usize::from(record[0]);
+	if len > MAX_ENCODED_LEN {
+		return Err(Error::BadChecksum);
+	}
+	Config::decode(&record[1..=len])
+}
+
+/// Work out the checksum for the stored configuration.
+///
+/// We invert the sum so that a record which is all zeroes fails the check.
+/// One which is all 0xFF (i.e. erased flash) fails too.
+fn checksum(data: &[u8]) -> u8 {
+	!data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+impl Config {
+	/// The configuration we use if nobody has set one.
+	pub const fn default() -> Config {
+		Config {
+			button_debounce_ms: 20,
+			button_repeat_delay_ms: 500,
+			button_repeat_interval_ms: 100,
+			video_blank_after_secs: 0,
+			char_encoding: charmap::Encoding::Cp850,
+			serial_transcript: false,
+			video_effects: 0,
+			paddle_axes: 0,
+			paddle_min: [0; paddles::NUM_AXES],
+			paddle_max: [paddles::ADC_MAX; paddles::NUM_AXES],
+			video_screen_saver: vga::ScreenSaverStyle::Blank,
+			keyboard_layout: keymap::Layout::Qwerty,
+			boot_style: BootStyle::Verbose,
+			boot_delay_secs: 5,
+			serial_boot: false,
+			sd_card_detect: true,
+			storage_slot: 0,
+			headphone_detect: true,
+			clock_profile: sysclock::Profile::Standard,
+			video_timing: vga::TimingPreset::Standard,
+			log_mirror: 0,
+			boot_order: BootOrder::SdCardThenFlash,
+			serial_baud: 115_200,
+		}
+	}
+
+	/// Encode this configuration into the given buffer.
+	///
+	/// Returns how many bytes were used.
+	pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+		let buffer = buffer
+			.get_mut(0..ENCODED_LEN)
+			.ok_or(Error::BufferTooSmall)?;
+		buffer[0] = CONFIG_VERSION;
+		buffer[1..3].copy_from_slice(&self.button_debounce_ms.to_le_bytes());
+		buffer[3..5].copy_from_slice(&self.button_repeat_delay_ms.to_le_bytes());
+		buffer[5..7].copy_from_slice(&self.button_repeat_interval_ms.to_le_bytes());
+		buffer[7..9].copy_from_slice(&self.video_blank_after_secs.to_le_bytes());
+		buffer[9] = self.char_encoding as u8;
+		buffer[10] = u8::from(self.serial_transcript);
+		buffer[11] = self.video_effects;
+		buffer[12] = self.paddle_axes;
+		for axis in 0..paddles::NUM_AXES {
+			let offset = 13 + (axis * 2);
+			buffer[offset..offset + 2].copy_from_slice(&self.paddle_min[axis].to_le_bytes());
+			let offset = 19 + (axis * 2);
+			buffer[offset..offset + 2].copy_from_slice(&self.paddle_max[axis].to_le_bytes());
+		}
+		buffer[25] = self.video_screen_saver as u8;
+		buffer[26] = self.keyboard_layout as u8;
+		buffer[27] = self.boot_style as u8;
+		buffer[28] = self.boot_delay_secs;
+		buffer[29] = u8::from(self.serial_boot);
+		buffer[30] = u8::from(self.sd_card_detect);
+		buffer[31] = self.storage_slot;
+		buffer[32] = u8::from(self.headphone_detect);
+		buffer[33] = self.clock_profile as u8;
+		buffer[34] = self.video_timing as u8;
+		buffer[35] = self.log_mirror;
+		buffer[36] = self.boot_order as u8;
+		buffer[37..41].copy_from_slice(&self.serial_baud.to_le_bytes());
+		Ok(ENCODED_LEN)
+	}
+
+	/// Decode a configuration from the given bytes.
+	///
+	/// Any fields missing from the end of the block take their default value.
+	pub fn decode(data: &[u8]) -> Result<Config, Error> {
+		match data.first() {
+			Some(&CONFIG_VERSION) => {}
+			_ => return Err(Error::BadVersion),
+		}
+		let mut config = Config::default();
+		let u16_at = |offset: usize| -> Option<u16> {
+			let bytes = data.get(offset..offset + 2)?;
+			Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+		};
+		if let Some(value) = u16_at(1) {
+			config.button_debounce_ms = value;
+		}
+		if let Some(value) = u16_at(3) {
+			config.button_repeat_delay_ms = value;
+		}
+		if let Some(value) = u16_at(5) {
+			config.button_repeat_interval_ms = value;
+		}
+		if let Some(value) = u16_at(7) {
+			config.video_blank_after_secs = value;
+		}
+		if let Some(&value) = data.get(9) {
+			config.char_encoding = charmap::Encoding::from_u8(value).ok_or(Error::BadValue)?;
+		}
+		if let Some(&value) = data.get(10) {
+			config.serial_transcript = match value {
+				0 => false,
+				1 => true,
+				_ => return Err(Error::BadValue),
+			};
+		}
+		if let Some(&value) = data.get(11) {
+			if (value & !vga::EFFECT_ALL) != 0 {
+				return Err(Error::BadValue);
+			}
+			config.video_effects = value;
+		}
+		if let Some(&value) = data.get(12) {
+			config.paddle_axes = value;
+		}
+		for axis in 0..paddles::NUM_AXES {
+			if let Some(value) = u16_at(13 + (axis * 2)) {
+				config.paddle_min[axis] = value;
+			}
+			if let Some(value) = u16_at(19 + (axis * 2)) {
+				config.paddle_max[axis] = value;
+			}
+		}
+		if let Some(&value) = data.get(25) {
+			config.video_screen_saver = match value {
+				0 => vga::ScreenSaverStyle::Blank,
+				1 => vga::ScreenSaverStyle::Logo,
+				_ => return Err(Error::BadValue),
+			};
+		}
+		if let Some(&value) = data.get(26) {
+			config.keyboard_layout = keymap::Layout::from_u8(value).ok_or(Error::BadValue)?;
+		}
+		if let Some(&value) = data.get(27) {
+			config.boot_style = match value {
+				0 => BootStyle::Verbose,
+				1 => BootStyle::Short,
+				2 => BootStyle::Quiet,
+				_ => return Err(Error::BadValue),
+			};
+		}
+		if let Some(&value) = data.get(28) {
+			config.boot_delay_secs = value;
+		}
+		if let Some(&value) = data.get(29) {
+			config.serial_boot = match value {
+				0 => false,
+				1 => true,
+				_ => return Err(Error::BadValue),
+			};
+		}
+		if let Some(&value) = data.get(30) {
+			config.sd_card_detect = match value {
+				0 => false,
+				1 => true,
+				_ => return Err(Error::BadValue),
+			};
+		}
+		if let Some(&value) = data.get(31) {
+			if value > neobus::NUM_SLOTS {
+				return Err(Error::BadValue);
+			}
+			config.storage_slot = value;
+		}
+		if let Some(&value) = data.get(32) {
+			config.headphone_detect = match value {
+				0 => false,
+				1 => true,
+				_ => return Err(Error::BadValue),
+			};
+		}
+		if let Some(&value) = data.get(33) {
+			config.clock_profile = sysclock::Profile::from_u8(value).ok_or(Error::BadValue)?;
+		}
+		if let Some(&value) = data.get(34) {
+			config.video_timing = vga::TimingPreset::from_u8(value).ok_or(Error::BadValue)?;
+		}
+		if let Some(&value) = data.get(35) {
+			if (value & !logger::MIRROR_ALL) != 0 {
+				return Err(Error::BadValue);
+			}
+			config.log_mirror = value;
+		}
+		if let Some(&value) = data.get(36) {
+			config.boot_order = match value {
+				0 => BootOrder::SdCardThenFlash,
+				1 => BootOrder::FlashOnly,
+				_ => return Err(Error::BadValue),
+			};
+		}
+		if let Some(bytes) = data.get(37..41) {
+			let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+			if value == 0 {
+				return Err(Error::BadValue);
+			}
+			config.serial_baud = value;
+		}
+		Ok(config)
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
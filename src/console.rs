@@ -0,0 +1,229 @@
+//! # Boot console for the Neotron Pico
+//!
+//! Everything the BIOS prints goes through the `Console` in this module. It
+//! writes to the VGA `TextConsole`, and can also send a copy to the serial
+//! port, so boot messages can be captured (or read at all, if the monitor
+//! isn't working). Text attributes are turned into ANSI colour escape
+//! sequences on the way out of the serial port.
+//!
+//! Alternatively, the serial copy can be a plain transcript: just the text,
+//! one line at a time, with no escape sequences or cursor movement. This
+//! suits a braille display or a speech device.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{crashlog, serial, vga};
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// How (and whether) console output is copied to the serial port.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum SerialMirror {
+	/// Nothing goes to the serial port
+	Off = 0,
+	/// Everything goes to the serial port, with ANSI escape sequences for
+	/// colours and cursor movement
+	Ansi = 1,
+	/// Only the text goes to the serial port, as complete lines
+	Transcript = 2,
+}
+
+/// A text console which writes to VGA, and optionally mirrors to the serial
+/// port.
+pub struct Console {
+	/// The on-screen console
+	vga: vga::TextConsole,
+	/// A `SerialMirror`, saying what we send to the serial port
+	serial_mirror: AtomicU8,
+	/// In transcript mode, have we sent some of the current line?
+	line_started: AtomicBool,
+}
+
+/// Lets us use `write!` to put small, formatted strings into the serial port.
+struct SerialWriter;
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// Maps VGA colour numbers (blue is 1) to ANSI colour numbers (red is 1).
+const VGA_TO_ANSI: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+impl Console {
+	/// Make a new console. It has no text buffer, and doesn't mirror to
+	/// serial.
+	pub const fn new() -> Console {
+		Console {
+			vga: vga::TextConsole::new(),
+			serial_mirror: AtomicU8::new(SerialMirror::Off as u8),
+			line_started: AtomicBool::new(false),
+		}
+	}
+
+	/// Get the on-screen part of this console.
+	pub fn vga(&self) -> &vga::TextConsole {
+		&self.vga
+	}
+
+	/// Start (or stop, or change how we are) sending a copy of everything to
+	/// the serial port.
+	///
+	/// When ANSI mirroring starts, the serial terminal is reset and set to
+	/// our current colours.
+	pub fn set_serial_mirror(&self, mode: SerialMirror) {
+		if self.serial_mirror() == SerialMirror::Transcript {
+			self.end_transcript_line();
+		}
+		self.serial_mirror.store(mode as u8, Ordering::Relaxed);
+		if mode == SerialMirror::Ansi {
+			serial::write_blocking(b"\x1b[0m");
+			self.send_serial_attr(self.vga.attr());
+		}
+	}
+
+	/// What are we sending to the serial port?
+	pub fn serial_mirror(&self) -> SerialMirror {
+		match self.serial_mirror.load(Ordering::Relaxed) {
+			1 => SerialMirror::Ansi,
+			2 => SerialMirror::Transcript,
+			_ => SerialMirror::Off,
+		}
+	}
+
+	/// Set the colours for any text written from now on.
+	pub fn set_attr(&self, attr: vga::Attr) {
+		self.vga.set_attr(attr);
+		if self.serial_mirror() == SerialMirror::Ansi {
+			self.send_serial_attr(attr);
+		}
+	}
+
+	/// Move the cursor to the given row and column.
+	///
+	/// A transcript can't move the cursor, so it just starts a new line.
+	pub fn move_to(&self, row: u16, col: u16) {
+		self.vga.move_to(row, col);
+		match self.serial_mirror() {
+			SerialMirror::Off => {}
+			SerialMirror::Ansi => {
+				// ANSI rows and columns count from 1
+				let _ = write!(SerialWriter, "\x1b[{};{}H", row + 1, col + 1);
+			}
+			SerialMirror::Transcript => self.end_transcript_line(),
+		}
+	}
+
+	/// Blank the screen, using the current attribute, and move the cursor
+	/// to the top-left.
+	pub fn clear(&self) {
+		// A crude way to clear the screen
+		for _row in 0..vga::MAX_TEXT_ROWS {
+			let _ = writeln!(&self.vga);
+		}
+		self.vga.move_to(0, 0);
+		match self.serial_mirror() {
+			SerialMirror::Off => {}
+			SerialMirror::Ansi => serial::write_blocking(b"\x1b[2J\x1b[H"),
+			SerialMirror::Transcript => self.end_transcript_line(),
+		}
+	}
+
+	/// If we've sent part of a line to the transcript, finish it.
+	fn end_transcript_line(&self) {
+		if self.line_started.load(Ordering::Relaxed) {
+			serial::write_blocking(b"\r\n");
+			self.line_started.store(false, Ordering::Relaxed);
+		}
+	}
+
+	/// Send text to the transcript.
+	///
+	/// Control characters are dropped, except for new-lines, which become CR
+	/// LF.
+	fn send_transcript(&self, s: &str) {
+		for (idx, line) in s.split('\n').enumerate() {
+			if idx != 0 {
+				// Send blank lines too, so paragraphs are kept apart
+				serial::write_blocking(b"\r\n");
+				self.line_started.store(false, Ordering::Relaxed);
+			}
+			for text in line.split(|c: char| c.is_control()) {
+				if !text.is_empty() {
+					serial::write_blocking(text.as_bytes());
+					self.line_started.store(true, Ordering::Relaxed);
+				}
+			}
+		}
+	}
+
+	/// Send an ANSI Select Graphic Rendition sequence for the given attribute.
+	fn send_serial_attr(&self, attr: vga::Attr) {
+		let fg = attr.fg();
+		let fg_ansi = VGA_TO_ANSI[(fg & 0x07) as usize] + if fg >= 8 { 90 } else { 30 };
+		let bg_ansi = VGA_TO_ANSI[attr.bg() as usize] + 40;
+		let _ = write!(SerialWriter, "\x1b[{};{}m", fg_ansi, bg_ansi);
+	}
+}
+
+impl core::fmt::Write for &Console {
+	/// Allows us to call `writeln!(some_console, "hello")`
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		let _ = (&self.vga).write_str(s);
+		crashlog::log_text(s);
+		match self.serial_mirror() {
+			SerialMirror::Off => {}
+			SerialMirror::Ansi => {
+				// Terminals want CR LF, not just LF
+				for (idx, line) in s.split('\n').enumerate() {
+					if idx != 0 {
+						serial::write_blocking(b"\r\n");
+					}
+					serial::write_blocking(line.as_bytes());
+				}
+			}
+			SerialMirror::Transcript => self.send_transcript(s),
+		}
+		Ok(())
+	}
+}
+
+impl core::fmt::Write for SerialWriter {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		serial::write_blocking(s.as_bytes());
+		Ok(())
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
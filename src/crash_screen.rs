@@ -0,0 +1,281 @@
+//! # Crash screen for the Neotron Pico
+//!
+//! Most people don't have a debug probe attached, so when the BIOS panics
+//! or takes a HardFault we also put a red-on-white "Guru Meditation" box on
+//! the screen, with the message, the program counter and whatever registers
+//! we have. A copy goes to the serial port.
+//!
+//! We then wait for a key press (or `WAIT_MS` to pass) and reset. The
+//! watchdog is running while we wait, so if the keyboard polling locks up we
+//! still reset.
+//!
+//! The video DMA interrupt is on Core 0, and we might be in an exception
+//! handler (or have interrupts disabled), so we turn interrupts off and
+//! service that interrupt by polling instead.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{common, hid, serial, vga, watchdog};
+use core::fmt::Write as _;
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Writes text into the box, wrapping at the right-hand edge and dropping
+/// anything below `last_row`.
+struct BoxWriter {
+	row: u16,
+	col: u16,
+	last_row: u16,
+}
+
+/// Lets us use `write!` to send formatted text to the serial port.
+struct SerialWriter;
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// Our own console, so we don't depend on what the rest of the BIOS has
+/// done to theirs.
+static SCREEN: vga::TextConsole = vga::TextConsole::new();
+
+/// Red text on a white background
+const CRASH_ATTR: vga::Attr = vga::Attr::from_u8(0x74);
+
+/// The box's top-left corner
+const BOX_TOP: u16 = 1;
+const BOX_LEFT: u16 = 2;
+
+/// The box's bottom-right corner
+const BOX_BOTTOM: u16 = 15;
+const BOX_RIGHT: u16 = 77;
+
+/// Text inside the box starts here...
+const TEXT_LEFT: u16 = BOX_LEFT + 2;
+
+/// ...and ends here
+const TEXT_RIGHT: u16 = BOX_RIGHT - 2;
+
+/// Where each part of the report goes
+const TITLE_ROW: u16 = BOX_TOP + 1;
+const GURU_ROW: u16 = BOX_TOP + 2;
+const MESSAGE_ROW: u16 = BOX_TOP + 4;
+const MESSAGE_LAST_ROW: u16 = BOX_TOP + 9;
+const REGISTER_ROW: u16 = BOX_TOP + 11;
+
+/// The names of the registers we are given, in order
+const REGISTER_NAMES: [&str; 8] = ["R0", "R1", "R2", "R3", "R12", "LR", "PC", "xPSR"];
+
+/// How long we wait for a key press before resetting anyway
+const WAIT_MS: u32 = 60_000;
+
+/// How often we poll the keyboard
+const POLL_INTERVAL_MS: u32 = 20;
+
+/// How long the watchdog waits to be fed while we wait
+const WATCHDOG_TIMEOUT_MS: u32 = 2_000;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Show a crash report, wait for a key press, then reset.
+///
+/// The `code` goes in the Guru Meditation number, along with the PC (the
+/// seventh of `registers`). Registers we don't know should be zero. If we
+/// aren't on Core 0, or the video isn't running, we just reset.
+pub fn show(code: u32, message: &[u8], registers: &[u32; 8]) -> ! {
+	// Note (safety): Reading the CPUID register has no side effects.
+	let core_id = unsafe { (*pac::SIO::ptr()).cpuid.read().bits() };
+	if core_id != 0 || !vga::is_running() {
+		watchdog::reboot();
+	}
+	let message = valid_utf8(message);
+
+	// Tell the serial port first, as it's slow and we can't keep the video
+	// going while we wait for it.
+	let _ = write!(
+		SerialWriter,
+		"\r\n*** Guru Meditation #{:08X}.{:08X}\r\n*** {}\r\n",
+		code, registers[6], message
+	);
+	for (name, value) in REGISTER_NAMES.iter().zip(registers.iter()) {
+		let _ = write!(SerialWriter, "{}=0x{:08x} ", name, value);
+	}
+	let _ = write!(SerialWriter, "\r\n");
+
+	draw(code, message, registers);
+
+	// Turning interrupts off has to come after the mode change, which turns
+	// them back on.
+	cortex_m::interrupt::disable();
+	watchdog::start(WATCHDOG_TIMEOUT_MS);
+
+	// Ignore anything typed before the crash
+	while hid::get_event().is_some() {}
+
+	let start = crate::ticks_ms();
+	let mut last_poll = start;
+	loop {
+		service_video();
+		let now = crate::ticks_ms();
+		if now.wrapping_sub(start) < WAIT_MS {
+			watchdog::feed();
+		}
+		if now.wrapping_sub(last_poll) >= POLL_INTERVAL_MS {
+			last_poll = now;
+			hid::poll();
+			while let Some(event) = hid::get_event() {
+				if let common::hid::HidEvent::KeyPress(_) = event {
+					watchdog::reboot();
+				}
+			}
+		}
+	}
+}
+
+/// Put the crash report on the screen, in the 80x30 text mode.
+fn draw(code: u32, message: &str, registers: &[u32; 8]) {
+	vga::set_video_mode(common::video::Mode::new(
+		common::video::Timing::T640x480,
+		common::video::Format::Text8x16,
+	));
+	// Note (safety): Null always works. It puts our own text buffer back on
+	// the screen, in case the OS's one was big enough to survive the mode
+	// change.
+	let _ = unsafe { vga::set_framebuffer(core::ptr::null_mut(), 0..0) };
+	vga::set_screen_saver_override(vga::ScreenSaverOverride::Inhibit);
+	let _ = vga::set_cursor_shape(0, 0, 0);
+	// Note (safety): Nothing else is going to write to the text buffer again.
+	SCREEN.set_text_buffer(unsafe { &mut vga::GLYPH_ATTR_ARRAY });
+	SCREEN.set_attr(CRASH_ATTR);
+
+	// Blank the screen by scrolling everything off it
+	let mut screen = &SCREEN;
+	for _row in 0..vga::MAX_TEXT_ROWS {
+		let _ = writeln!(screen);
+	}
+
+	for row in BOX_TOP..=BOX_BOTTOM {
+		let (corner, fill) = if row == BOX_TOP || row == BOX_BOTTOM {
+			('+', '-')
+		} else {
+			('|', ' ')
+		};
+		SCREEN.move_to(row, BOX_LEFT);
+		let _ = write!(screen, "{}", corner);
+		for _col in (BOX_LEFT + 1)..BOX_RIGHT {
+			let _ = write!(screen, "{}", fill);
+		}
+		let _ = write!(screen, "{}", corner);
+	}
+
+	let _ = write!(
+		BoxWriter::new(TITLE_ROW, TITLE_ROW),
+		"Software Failure.   Press any key to restart."
+	);
+	let _ = write!(
+		BoxWriter::new(GURU_ROW, GURU_ROW),
+		"Guru Meditation #{:08X}.{:08X}",
+		code,
+		registers[6]
+	);
+	let _ = write!(BoxWriter::new(MESSAGE_ROW, MESSAGE_LAST_ROW), "{}", message);
+	for (idx, (name, value)) in REGISTER_NAMES.iter().zip(registers.iter()).enumerate() {
+		// Four registers per row
+		let row = REGISTER_ROW + (idx / 4) as u16;
+		let mut writer = BoxWriter::new(row, row);
+		writer.col = TEXT_LEFT + ((idx % 4) as u16 * 18);
+		let _ = write!(writer, "{:<4} {:08x}", name, value);
+	}
+}
+
+/// Get the longest valid UTF-8 string from the start of some bytes.
+///
+/// The message may have been cut short in the middle of a character.
+fn valid_utf8(bytes: &[u8]) -> &str {
+	match core::str::from_utf8(bytes) {
+		Ok(text) => text,
+		Err(e) => core::str::from_utf8(&bytes[0..e.valid_up_to()]).unwrap_or(""),
+	}
+}
+
+/// Do what the video DMA interrupt handler would do, if it's pending.
+fn service_video() {
+	if pac::NVIC::is_pending(pac::Interrupt::DMA_IRQ_0) {
+		pac::NVIC::unpend(pac::Interrupt::DMA_IRQ_0);
+		// Note (safety): Interrupts are off, so the real handler can't run
+		// at the same time.
+		unsafe {
+			vga::irq();
+		}
+	}
+}
+
+impl BoxWriter {
+	/// Make a writer which starts at the left of the given row.
+	fn new(row: u16, last_row: u16) -> BoxWriter {
+		BoxWriter {
+			row,
+			col: TEXT_LEFT,
+			last_row,
+		}
+	}
+}
+
+impl core::fmt::Write for BoxWriter {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		let mut screen = &SCREEN;
+		for ch in s.chars() {
+			if ch == '\n' || self.col > TEXT_RIGHT {
+				self.row += 1;
+				self.col = TEXT_LEFT;
+			}
+			if self.row > self.last_row {
+				break;
+			}
+			if ch.is_control() {
+				continue;
+			}
+			screen.move_to(self.row, self.col);
+			let _ = write!(screen, "{}", ch);
+			self.col += 1;
+		}
+		Ok(())
+	}
+}
+
+impl core::fmt::Write for SerialWriter {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		serial::write_blocking(s.as_bytes());
+		Ok(())
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
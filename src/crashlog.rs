@@ -0,0 +1,394 @@
+//! # Crash log for the Neotron Pico
+//!
+//! When the BIOS panics, or the CPU takes a HardFault, we write a crash
+//! record to a reserved area of the SD card and then reset. The records can
+//! be read back later on a PC, so crashes that only happen once in a while
+//! (and never with a debugger attached) can still be investigated.
+//!
+//! The crash area is `NUM_RECORDS` raw blocks starting at `BASE_BLOCK`. This
+//! is in the gap between the partition table and the first partition on a
+//! normally formatted card, and below the raw boot slots on an unformatted
+//! card. We check before writing, as not every card leaves that gap:
+//!
+//! * If block 0 is an MBR, every partition in it must start after the crash
+//!   area. A GPT disk (with its protective MBR) fails this check.
+//! * If block 0 is a filesystem with no partition table (e.g. a bare FAT or
+//!   exFAT volume), the crash area belongs to the filesystem, so we never
+//!   write.
+//! * If block 0 is neither, we only overwrite blocks which are blank, or
+//!   which already hold a crash record.
+//!
+//! Each record is one block:
+//!
+//! | Offset | Length | Contents                                            |
+//! |--------|--------|-----------------------------------------------------|
+//! | 0      | 8      | `NEOCRASH`                                          |
+//! | 8      | 4      | Record version (1)                                  |
+//! | 12     | 4      | Sequence number - newer is higher, modulo 2**32     |
+//! | 16     | 4      | 1 for a panic, 2 for a HardFault                    |
+//! | 20     | 4      | Milliseconds since boot                             |
+//! | 24     | 32     | R0, R1, R2, R3, R12, LR, PC and xPSR (see below)    |
+//! | 56     | 2      | Message length                                      |
+//! | 58     | 2      | Recent log length                                   |
+//! | 60     | 196    | Message (UTF-8, e.g. the panic location and text)   |
+//! | 256    | 256    | The most recent console output, oldest first        |
+//!
+//! For a HardFault, the registers come from the exception frame. For a panic
+//! only LR and PC are filled in, and they point into the panic handler.
+//!
+//! All integers are little-endian. Nothing is written until a block device
+//! driver has called `set_storage`, or if we crashed while the Neotron Bus
+//! was in use, as the card might be half-way through a command. The card
+//! is written directly: we leave `block_cache` alone, as we might have
+//! crashed half-way through changing it, and anything the OS left in it is
+//! lost anyway when we reset.
+//!
+//! Once the record is written, `crash_screen` shows the crash on screen.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{
+	bootslot::{Block, BLOCK_SIZE},
+	crash_screen, neobus, watchdog,
+};
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m_rt::{exception, ExceptionFrame};
+use defmt::error;
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// How to read and write the blocks of the crash area.
+#[derive(Copy, Clone)]
+pub struct Storage {
+	/// Read one block from the card
+	pub read_block: fn(u64, &mut Block) -> Result<(), ()>,
+	/// Write one block to the card
+	pub write_block: fn(u64, &Block) -> Result<(), ()>,
+}
+
+/// What caused the crash.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u32)]
+enum Kind {
+	/// The BIOS called `panic!`
+	Panic = 1,
+	/// The CPU took a HardFault
+	HardFault = 2,
+}
+
+/// Writes formatted text into a fixed-size slice, dropping anything that
+/// doesn't fit.
+struct SliceWriter<'a> {
+	buffer: &'a mut [u8],
+	used: usize,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The first block of the crash area
+pub const BASE_BLOCK: u64 = 1024;
+
+/// How many crash records we keep before overwriting the oldest
+pub const NUM_RECORDS: u64 = 16;
+
+/// Where the partition table starts in an MBR
+const MBR_PARTITIONS_OFFSET: usize = 446;
+
+/// The partition type GPT uses for its protective MBR entry
+const PARTITION_TYPE_GPT: u8 = 0xEE;
+
+/// The magic bytes at the start of every crash record
+const RECORD_MAGIC: &[u8; 8] = b"NEOCRASH";
+
+/// The version of the crash record format
+const RECORD_VERSION: u32 = 1;
+
+/// Where the message starts in a record, and how long it can be
+const MESSAGE_OFFSET: usize = 60;
+const MESSAGE_LEN: usize = 196;
+
+/// Where the recent log starts in a record
+const LOG_OFFSET: usize = MESSAGE_OFFSET + MESSAGE_LEN;
+
+/// How much recent console output we keep
+const LOG_LEN: usize = BLOCK_SIZE - LOG_OFFSET;
+
+/// How to reach the card, once a driver has told us.
+///
+/// Only accessed from Core 0.
+static mut STORAGE: Option<Storage> = None;
+
+/// The most recent console output, as a ring buffer.
+///
+/// Only accessed from Core 0.
+static mut LOG_RING: [u8; LOG_LEN] = [0u8; LOG_LEN];
+
+/// Where the next byte goes in `LOG_RING`
+///
+/// Only accessed from Core 0.
+static mut LOG_HEAD: usize = 0;
+
+/// Set once we start handling a crash, so a crash while writing the record
+/// (e.g. in the SD card driver) doesn't loop forever.
+static IN_CRASH: AtomicBool = AtomicBool::new(false);
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Tell the crash logger how to reach the SD card.
+pub fn set_storage(storage: Storage) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		STORAGE = Some(storage);
+	}
+}
+
+/// Remember some console output, in case we crash.
+pub fn log_text(text: &str) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		for &b in text.as_bytes() {
+			LOG_RING[LOG_HEAD] = b;
+			LOG_HEAD = (LOG_HEAD + 1) % LOG_LEN;
+		}
+	}
+}
+
+/// Called when the BIOS panics.
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+	error!("PANIC: {}", defmt::Display2Format(info));
+	let mut registers = [0u32; 8];
+	// Note (safety): Reading our own LR and PC has no side effects.
+	unsafe {
+		core::arch::asm!("mov {}, lr", out(reg) registers[5]);
+		core::arch::asm!("mov {}, pc", out(reg) registers[6]);
+	}
+	// The Cortex-M0+ has no atomic swap, but a second crash can only come
+	// from inside this handler, so a load then a store will do.
+	if !IN_CRASH.load(Ordering::SeqCst) {
+		IN_CRASH.store(true, Ordering::SeqCst);
+		let mut message = [0u8; MESSAGE_LEN];
+		let mut writer = SliceWriter::new(&mut message);
+		let _ = write!(writer, "{}", info);
+		let message_len = writer.used;
+		write_record(Kind::Panic, &registers, &message[0..message_len]);
+		watchdog::record_crash();
+		crash_screen::show(Kind::Panic as u32, &message[0..message_len], &registers);
+	}
+	watchdog::reboot();
+}
+
+/// Called when the CPU takes a HardFault.
+#[exception]
+unsafe fn HardFault(ef: &ExceptionFrame) -> ! {
+	let registers = [
+		ef.r0(),
+		ef.r1(),
+		ef.r2(),
+		ef.r3(),
+		ef.r12(),
+		ef.lr(),
+		ef.pc(),
+		ef.xpsr(),
+	];
+	error!("HardFault at PC 0x{:08x}, LR 0x{:08x}", ef.pc(), ef.lr());
+	if !IN_CRASH.load(Ordering::SeqCst) {
+		IN_CRASH.store(true, Ordering::SeqCst);
+		write_record(Kind::HardFault, &registers, b"HardFault");
+		watchdog::record_crash();
+		crash_screen::show(Kind::HardFault as u32, b"HardFault", &registers);
+	}
+	watchdog::reboot();
+}
+
+/// Build a crash record and write it over the oldest record on the card.
+///
+/// Does nothing on Core 1, as Core 0 might be half-way through using the
+/// Neotron Bus, or on Core 0 if it was.
+fn write_record(kind: Kind, registers: &[u32; 8], message: &[u8]) {
+	// Note (safety): Reading the CPUID register has no side effects.
+	let core_id = unsafe { (*pac::SIO::ptr()).cpuid.read().bits() };
+	if core_id != 0 {
+		return;
+	}
+	// Note (safety): We're about to reset, so nothing else on Core 0 will
+	// use these again.
+	let storage = match unsafe { STORAGE } {
+		Some(storage) => storage,
+		None => return,
+	};
+	if neobus::in_use() {
+		error!("Crashed while using the Neotron Bus, so no crash record");
+		return;
+	}
+
+	let mut block: Block = [0u8; BLOCK_SIZE];
+	if (storage.read_block)(0, &mut block).is_err() {
+		return;
+	}
+	let has_partition_table = match check_block0(&block) {
+		Some(has_partition_table) => has_partition_table,
+		None => {
+			error!("No room for a crash record on this card");
+			return;
+		}
+	};
+
+	// Find the newest record, so we can write after it
+	let mut newest: Option<(u64, u32)> = None;
+	for idx in 0..NUM_RECORDS {
+		if (storage.read_block)(BASE_BLOCK + idx, &mut block).is_err() {
+			return;
+		}
+		if &block[0..8] != RECORD_MAGIC {
+			// Without a partition table, we can't be sure nobody else
+			// is using this block
+			if !has_partition_table && block.iter().any(|&b| b != 0) {
+				error!("Block {} isn't ours to write to", BASE_BLOCK + idx);
+				return;
+			}
+			continue;
+		}
+		let sequence = read_u32(&block, 12);
+		match newest {
+			Some((_, newest_sequence)) if !is_newer(sequence, newest_sequence) => {}
+			_ => newest = Some((idx, sequence)),
+		}
+	}
+	let (idx, sequence) = match newest {
+		Some((idx, sequence)) => ((idx + 1) % NUM_RECORDS, sequence.wrapping_add(1)),
+		None => (0, 0),
+	};
+
+	block = [0u8; BLOCK_SIZE];
+	block[0..8].copy_from_slice(RECORD_MAGIC);
+	write_u32(&mut block, 8, RECORD_VERSION);
+	write_u32(&mut block, 12, sequence);
+	write_u32(&mut block, 16, kind as u32);
+	write_u32(&mut block, 20, crate::ticks_ms());
+	for (reg_idx, value) in registers.iter().enumerate() {
+		write_u32(&mut block, 24 + (reg_idx * 4), *value);
+	}
+	let message_len = message.len().min(MESSAGE_LEN);
+	block[56..58].copy_from_slice(&(message_len as u16).to_le_bytes());
+	block[58..60].copy_from_slice(&(LOG_LEN as u16).to_le_bytes());
+	block[MESSAGE_OFFSET..MESSAGE_OFFSET + message_len].copy_from_slice(&message[0..message_len]);
+	// Unroll the ring buffer so the oldest byte comes first
+	// Note (safety): We're about to reset, so nothing else will write to
+	// the ring buffer.
+	unsafe {
+		let (newer, older) = LOG_RING.split_at(LOG_HEAD);
+		block[LOG_OFFSET..LOG_OFFSET + older.len()].copy_from_slice(older);
+		block[LOG_OFFSET + older.len()..].copy_from_slice(newer);
+	}
+
+	if (storage.write_block)(BASE_BLOCK + idx, &block).is_err() {
+		error!("Failed to write crash record");
+	} else {
+		error!(
+			"Wrote crash record {} to block {}",
+			sequence,
+			BASE_BLOCK + idx
+		);
+	}
+}
+
+/// Check block 0 of the card, to see if the crash area is free.
+///
+/// Returns `Some(true)` for an MBR which leaves room before the first
+/// partition, `Some(false)` for a card with no partition table or
+/// filesystem, and `None` if the crash area might be in use.
+fn check_block0(block0: &Block) -> Option<bool> {
+	if block0[510..512] != [0x55, 0xAA] {
+		return Some(false);
+	}
+	// A bare FAT or exFAT volume says so in its boot sector
+	if &block0[54..57] == b"FAT" || &block0[82..85] == b"FAT" || &block0[3..8] == b"EXFAT" {
+		return None;
+	}
+	let area_end = BASE_BLOCK + NUM_RECORDS;
+	for entry in block0[MBR_PARTITIONS_OFFSET..510].chunks(16) {
+		let partition_type = entry[4];
+		if partition_type == 0 {
+			continue;
+		}
+		let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+		if partition_type == PARTITION_TYPE_GPT || u64::from(start) < area_end {
+			return None;
+		}
+	}
+	Some(true)
+}
+
+/// Is sequence number `a` newer than `b`?
+///
+/// The numbers wrap, so anything less than half-way round ahead of `b`
+/// counts as newer.
+fn is_newer(a: u32, b: u32) -> bool {
+	(a.wrapping_sub(b) as i32) > 0
+}
+
+/// Read a little-endian `u32` from a block.
+fn read_u32(block: &Block, offset: usize) -> u32 {
+	u32::from_le_bytes([
+		block[offset],
+		block[offset + 1],
+		block[offset + 2],
+		block[offset + 3],
+	])
+}
+
+/// Write a little-endian `u32` into a block.
+fn write_u32(block: &mut Block, offset: usize, value: u32) {
+	block[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+impl<'a> SliceWriter<'a> {
+	/// Make a writer which fills the given buffer.
+	fn new(buffer: &'a mut [u8]) -> SliceWriter<'a> {
+		SliceWriter { buffer, used: 0 }
+	}
+}
+
+impl<'a> core::fmt::Write for SliceWriter<'a> {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		let space = self.buffer.len() - self.used;
+		let len = s.len().min(space);
+		self.buffer[self.used..self.used + len].copy_from_slice(&s.as_bytes()[0..len]);
+		self.used += len;
+		Ok(())
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,389 @@
+//! # Booting from a FAT filesystem for the Neotron Pico
+//!
+//! If the SD card has a FAT16 or FAT32 filesystem, either as a bare volume
+//! or in the first FAT partition of an MBR, we look for
+//! `/NEOTRON/KERNEL.BIN` on it and copy it into the OS RAM. That means a new
+//! OS release can be copied onto the card from a PC, rather than being built
+//! in to the BIOS.
+//!
+//! The kernel must be linked to run from the start of the OS RAM, and like
+//! the OS image in flash, its first word must be a pointer to the OS start
+//! function.
+//!
+//! Only short (8.3) names are matched, which is fine as `NEOTRON` and
+//! `KERNEL.BIN` both fit. FAT12 isn't supported - it's only used on volumes
+//! far smaller than any SD card.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::bootslot::{self, Block, BLOCK_SIZE};
+use defmt::{debug, info};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which loading the kernel can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The block device reported an error
+	ReadFailed,
+	/// The card doesn't have a FAT filesystem
+	NoFilesystem,
+	/// The filesystem is FAT12, or is damaged
+	BadFilesystem,
+	/// There's no `/NEOTRON/KERNEL.BIN`
+	NotFound,
+	/// The kernel doesn't fit in the RAM we are allowed to load it into
+	TooBig,
+}
+
+/// Where a directory lives.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Directory {
+	/// The FAT16 root directory, which is a fixed run of blocks
+	FixedRoot { start: u64, num_blocks: u32 },
+	/// Any other directory, which is a chain of clusters
+	Clusters(u32),
+}
+
+/// What we found in a directory.
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct DirEntry {
+	/// The first cluster of the file or directory
+	cluster: u32,
+	/// The length of the file, in bytes
+	size: u32,
+	/// This is a directory, not a file
+	is_dir: bool,
+}
+
+/// The layout of a FAT volume.
+struct Volume {
+	/// The first block of the first FAT
+	fat_start: u64,
+	/// The block which holds cluster 2
+	data_start: u64,
+	/// How many blocks there are in each cluster
+	blocks_per_cluster: u32,
+	/// FAT32, rather than FAT16
+	fat32: bool,
+	/// Where the root directory is
+	root: Directory,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The directory the kernel lives in, as an 8.3 name
+const KERNEL_DIR: &[u8; 11] = b"NEOTRON    ";
+
+/// The kernel's file name, as an 8.3 name
+const KERNEL_FILE: &[u8; 11] = b"KERNEL  BIN";
+
+/// Directory entry attribute: this is a volume label
+const ATTR_VOLUME_ID: u8 = 0x08;
+
+/// Directory entry attribute: this is a directory
+const ATTR_DIRECTORY: u8 = 0x10;
+
+/// Directory entry attributes which mark a long file name entry
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// The first byte of a deleted directory entry
+const ENTRY_DELETED: u8 = 0xE5;
+
+/// The first byte of the entry after the last one in a directory
+const ENTRY_END: u8 = 0x00;
+
+/// How long each directory entry is
+const ENTRY_LEN: usize = 32;
+
+/// Volumes with fewer clusters than this are FAT12
+const MIN_FAT16_CLUSTERS: u32 = 4085;
+
+/// Volumes with fewer clusters than this are FAT16
+const MIN_FAT32_CLUSTERS: u32 = 65525;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Find `/NEOTRON/KERNEL.BIN` on a card, and copy it to the start of `ram`.
+///
+/// The `read_block` function reads one block from the card. Returns the
+/// length of the kernel, in bytes.
+pub fn load<F>(mut read_block: F, ram: &mut [u8]) -> Result<usize, Error>
+where
+	F: FnMut(u64, &mut Block) -> Result<(), ()>,
+{
+	let mut block = [0u8; BLOCK_SIZE];
+	read_block(0, &mut block).map_err(|_| Error::ReadFailed)?;
+	if !bootslot::has_fat_filesystem(&block) {
+		return Err(Error::NoFilesystem);
+	}
+	let volume = Volume::open(&mut read_block, &block)?;
+	let dir = volume.find(&mut read_block, volume.root, KERNEL_DIR)?;
+	if !dir.is_dir {
+		return Err(Error::NotFound);
+	}
+	let file = volume.find(
+		&mut read_block,
+		Directory::Clusters(dir.cluster),
+		KERNEL_FILE,
+	)?;
+	if file.is_dir || file.size == 0 {
+		return Err(Error::NotFound);
+	}
+	let dest = ram.get_mut(0..file.size as usize).ok_or(Error::TooBig)?;
+	debug!("Loading {} byte kernel", file.size);
+
+	// Copy the file a block at a time, following the cluster chain
+	let mut cluster = file.cluster;
+	let mut chunks = dest.chunks_mut(BLOCK_SIZE);
+	'clusters: loop {
+		let first_block = volume.cluster_start(cluster)?;
+		for lba in first_block..first_block + u64::from(volume.blocks_per_cluster) {
+			let chunk = match chunks.next() {
+				Some(chunk) => chunk,
+				None => break 'clusters,
+			};
+			read_block(lba, &mut block).map_err(|_| Error::ReadFailed)?;
+			chunk.copy_from_slice(&block[0..chunk.len()]);
+		}
+		cluster = volume
+			.next_cluster(&mut read_block, cluster)?
+			.ok_or(Error::BadFilesystem)?;
+	}
+
+	info!("Loaded {} bytes from /NEOTRON/KERNEL.BIN", file.size);
+	Ok(file.size as usize)
+}
+
+/// Read a little-endian `u16` from a block.
+fn u16_at(block: &Block, offset: usize) -> u16 {
+	u16::from_le_bytes([block[offset], block[offset + 1]])
+}
+
+/// Read a little-endian `u32` from a block.
+fn u32_at(block: &Block, offset: usize) -> u32 {
+	u32::from_le_bytes([
+		block[offset],
+		block[offset + 1],
+		block[offset + 2],
+		block[offset + 3],
+	])
+}
+
+impl Volume {
+	/// Find the FAT volume, given block 0 of the card, and read its BIOS
+	/// Parameter Block.
+	fn open<F>(read_block: &mut F, block0: &Block) -> Result<Volume, Error>
+	where
+		F: FnMut(u64, &mut Block) -> Result<(), ()>,
+	{
+		let mut block = [0u8; BLOCK_SIZE];
+		let is_boot_sector = &block0[54..57] == b"FAT" || &block0[82..85] == b"FAT";
+		let volume_start = if is_boot_sector {
+			block.copy_from_slice(block0);
+			0
+		} else {
+			// Use the first FAT partition in the MBR
+			let entry = block0[446..510]
+				.chunks(16)
+				.find(|entry| {
+					matches!(
+						entry[4],
+						0x04 | 0x06 | 0x0B | 0x0C | 0x0E | 0x14 | 0x16 | 0x1B | 0x1C | 0x1E
+					)
+				})
+				.ok_or(Error::BadFilesystem)?;
+			let start = u64::from(u32::from_le_bytes([
+				entry[8], entry[9], entry[10], entry[11],
+			]));
+			read_block(start, &mut block).map_err(|_| Error::ReadFailed)?;
+			start
+		};
+
+		if usize::from(u16_at(&block, 11)) != BLOCK_SIZE {
+			return Err(Error::BadFilesystem);
+		}
+		let blocks_per_cluster = u32::from(block[13]);
+		let reserved_blocks = u32::from(u16_at(&block, 14));
+		let num_fats = u32::from(block[16]);
+		let root_entries = u32::from(u16_at(&block, 17));
+		let total_blocks = match u16_at(&block, 19) {
+			0 => u32_at(&block, 32),
+			n => u32::from(n),
+		};
+		let fat_blocks = match u16_at(&block, 22) {
+			0 => u32_at(&block, 36),
+			n => u32::from(n),
+		};
+		if blocks_per_cluster == 0 || num_fats == 0 || fat_blocks == 0 {
+			return Err(Error::BadFilesystem);
+		}
+		let root_blocks =
+			((root_entries * ENTRY_LEN as u32) + (BLOCK_SIZE as u32 - 1)) / BLOCK_SIZE as u32;
+		let overhead = reserved_blocks + (num_fats * fat_blocks) + root_blocks;
+		let num_clusters = total_blocks
+			.checked_sub(overhead)
+			.ok_or(Error::BadFilesystem)?
+			/ blocks_per_cluster;
+		if num_clusters < MIN_FAT16_CLUSTERS {
+			return Err(Error::BadFilesystem);
+		}
+		let fat32 = num_clusters >= MIN_FAT32_CLUSTERS;
+
+		let fat_start = volume_start + u64::from(reserved_blocks);
+		let root_start = fat_start + u64::from(num_fats * fat_blocks);
+		let root = if fat32 {
+			Directory::Clusters(u32_at(&block, 44))
+		} else {
+			Directory::FixedRoot {
+				start: root_start,
+				num_blocks: root_blocks,
+			}
+		};
+		debug!(
+			"FAT{} volume at block {}, {} clusters",
+			if fat32 { 32 } else { 16 },
+			volume_start,
+			num_clusters
+		);
+		Ok(Volume {
+			fat_start,
+			data_start: root_start + u64::from(root_blocks),
+			blocks_per_cluster,
+			fat32,
+			root,
+		})
+	}
+
+	/// Look for an entry with the given 8.3 name in a directory.
+	fn find<F>(
+		&self,
+		read_block: &mut F,
+		dir: Directory,
+		name: &[u8; 11],
+	) -> Result<DirEntry, Error>
+	where
+		F: FnMut(u64, &mut Block) -> Result<(), ()>,
+	{
+		let mut block = [0u8; BLOCK_SIZE];
+		let (mut lba, mut blocks_left, mut cluster) = match dir {
+			Directory::FixedRoot { start, num_blocks } => (start, num_blocks, None),
+			Directory::Clusters(cluster) => (
+				self.cluster_start(cluster)?,
+				self.blocks_per_cluster,
+				Some(cluster),
+			),
+		};
+		loop {
+			if blocks_left == 0 {
+				// Move on to the next cluster, if there is one
+				let next = match cluster {
+					Some(current) => self.next_cluster(read_block, current)?,
+					None => None,
+				};
+				match next {
+					Some(next) => {
+						lba = self.cluster_start(next)?;
+						blocks_left = self.blocks_per_cluster;
+						cluster = Some(next);
+					}
+					None => return Err(Error::NotFound),
+				}
+			}
+			read_block(lba, &mut block).map_err(|_| Error::ReadFailed)?;
+			for entry in block.chunks_exact(ENTRY_LEN) {
+				match entry[0] {
+					ENTRY_END => return Err(Error::NotFound),
+					ENTRY_DELETED => continue,
+					_ => {}
+				}
+				let attributes = entry[11];
+				if (attributes & ATTR_LONG_NAME) == ATTR_LONG_NAME
+					|| (attributes & ATTR_VOLUME_ID) != 0
+				{
+					continue;
+				}
+				if &entry[0..11] == name {
+					let high = if self.fat32 {
+						u32::from(u16::from_le_bytes([entry[20], entry[21]]))
+					} else {
+						0
+					};
+					let low = u32::from(u16::from_le_bytes([entry[26], entry[27]]));
+					return Ok(DirEntry {
+						cluster: (high << 16) | low,
+						size: u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]),
+						is_dir: (attributes & ATTR_DIRECTORY) != 0,
+					});
+				}
+			}
+			lba += 1;
+			blocks_left -= 1;
+		}
+	}
+
+	/// Get the first block of a cluster.
+	fn cluster_start(&self, cluster: u32) -> Result<u64, Error> {
+		// Clusters 0 and 1 don't exist
+		let index = cluster.checked_sub(2).ok_or(Error::BadFilesystem)?;
+		Ok(self.data_start + (u64::from(index) * u64::from(self.blocks_per_cluster)))
+	}
+
+	/// Look up the cluster after this one in the FAT. Returns `None` at the
+	/// end of the chain.
+	fn next_cluster<F>(&self, read_block: &mut F, cluster: u32) -> Result<Option<u32>, Error>
+	where
+		F: FnMut(u64, &mut Block) -> Result<(), ()>,
+	{
+		let mut block = [0u8; BLOCK_SIZE];
+		let entry_len = if self.fat32 { 4 } else { 2 };
+		let offset = cluster as usize * entry_len;
+		let lba = self.fat_start + (offset / BLOCK_SIZE) as u64;
+		read_block(lba, &mut block).map_err(|_| Error::ReadFailed)?;
+		let offset = offset % BLOCK_SIZE;
+		let (next, end_of_chain) = if self.fat32 {
+			(u32_at(&block, offset) & 0x0FFF_FFFF, 0x0FFF_FFF8)
+		} else {
+			(u32::from(u16_at(&block, offset)), 0xFFF8)
+		};
+		if next >= end_of_chain {
+			Ok(None)
+		} else if next < 2 {
+			// A free or reserved cluster in the middle of a chain
+			Err(Error::BadFilesystem)
+		} else {
+			Ok(Some(next))
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
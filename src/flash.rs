@@ -0,0 +1,720 @@
+//! # Flash memory access for the Neotron Pico
+//!
+//! The BIOS, the OS and all our constant data live in the QSPI flash chip,
+//! which the RP2040 reads through its execute-in-place (XIP) cache. Reading
+//! is just a memory access. To erase or program the chip we must turn XIP
+//! off, use the boot ROM's flash functions, and then turn XIP back on.
+//!
+//! While XIP is off, nothing may run from flash:
+//!
+//! * On Core 0, the flash operation itself runs from RAM with interrupts
+//!   disabled.
+//! * Core 1 draws the video, but the renderer and the fonts live in flash
+//!   (and the BIOS doesn't have enough RAM to hold them). So Core 1 blanks
+//!   the scan-line buffers and moves to a loop in RAM, where it does the
+//!   video DMA interrupt's job (which normally runs on Core 0) until we are
+//!   done. The monitor stays in sync, and the picture goes black briefly -
+//!   about 1 ms for a page program and 50 ms for a sector erase.
+//!
+//! To turn XIP back on at full speed we run the second-stage bootloader
+//! again, from a copy in RAM that `init` takes. That puts the QSPI clock
+//! divider back to the bootloader's, so we then put back any divider set with
+//! `set_clock_divider` (which `sysclock` uses to keep the flash chip within
+//! its limits on a faster system clock).
+//!
+//! The OS may only erase and program the data region (`FLASH_DATA` in
+//! `memory.x`), so it can't damage the BIOS, its own image, or our copy of
+//! the configuration. The BIOS itself can replace the OS image, when one is
+//! sent over the serial port (see `ymodem`).
+//!
+//! With the `xip-cache-ram` feature, we turn the XIP cache off and offer its
+//! 16 KiB of SRAM to the OS (see `memory_get_region`). Everything in flash
+//! still works, but every read goes out to the chip, so the BIOS and the OS
+//! both run a lot slower. The boot ROM turns the cache back on whenever it
+//! flushes it, so we turn it off again before we go back to running from
+//! flash, which leaves what the OS stored there alone.
+//!
+//! Nothing we run with XIP off may call into flash, even in a debug build
+//! where little gets inlined. So every function it calls is in
+//! `.data.ram_func` too, registers are read and written with the
+//! `ram_read*` and `ram_write*` functions (which use assembly, rather than
+//! `read_volatile` and `write_volatile`), and loops are plain `while` loops.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use defmt::{debug, warn};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which a flash operation can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// `init` hasn't been called
+	NotReady,
+	/// The address or length isn't aligned to a sector (erase) or page
+	/// (program)
+	Misaligned,
+	/// The range runs off the end of the flash chip
+	OutOfRange,
+	/// Core 1 didn't stop to let us at the flash chip
+	Core1Busy,
+	/// The OS isn't allowed to change this part of the flash chip
+	NotAllowed,
+}
+
+/// Part of the flash chip.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Region {
+	/// Offset from the start of the flash chip, in bytes
+	pub offset: u32,
+	/// Length, in bytes
+	pub length: u32,
+}
+
+/// The boot ROM functions we need, looked up in advance so we don't have
+/// to run the (flash-resident) look-up code while XIP is off.
+#[derive(Copy, Clone)]
+struct RomFunctions {
+	connect_internal_flash: extern "C" fn(),
+	flash_exit_xip: extern "C" fn(),
+	flash_range_erase: extern "C" fn(addr: u32, count: usize, block_size: u32, block_cmd: u8),
+	flash_range_program: extern "C" fn(addr: u32, data: *const u8, count: usize),
+	flash_flush_cache: extern "C" fn(),
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// Where the flash chip appears in the address map
+pub const XIP_BASE: usize = 0x1000_0000;
+
+/// How big the flash chip on a Raspberry Pi Pico is
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// The smallest area we can erase
+pub const SECTOR_SIZE: usize = 4096;
+
+/// The smallest area we can program
+pub const PAGE_SIZE: usize = 256;
+
+/// The 64 KiB block erase command (the ROM uses sector erases for anything
+/// smaller)
+const BLOCK_ERASE_CMD: u8 = 0xD8;
+
+/// The size of a block erased by `BLOCK_ERASE_CMD`
+const BLOCK_SIZE: u32 = 65536;
+
+/// How long to wait for Core 1 to park
+const PARK_TIMEOUT_MS: u32 = 100;
+
+/// The flash command to read the chip's 64-bit unique ID
+const READ_UNIQUE_ID_CMD: u8 = 0x4B;
+
+/// How many dummy bytes follow `READ_UNIQUE_ID_CMD`
+const UNIQUE_ID_DUMMY_BYTES: usize = 4;
+
+/// Where the XIP cache's memory appears, when the cache is turned off
+pub const XIP_SRAM_BASE: usize = 0x1500_0000;
+
+/// How big the XIP cache is
+pub const XIP_SRAM_LEN: usize = 16 * 1024;
+
+/// The XIP cache control register
+const XIP_CTRL: *mut u32 = 0x1400_0000 as *mut u32;
+
+/// In XIP_CTRL: the cache is turned on
+const XIP_CTRL_EN: u32 = 1 << 0;
+
+/// The SSI (QSPI controller) data register
+const SSI_DR0: *mut u32 = 0x1800_0060 as *mut u32;
+
+/// The SSI status register
+const SSI_SR: *const u32 = 0x1800_0028 as *const u32;
+
+/// In SSI_SR: the receive FIFO isn't empty
+const SSI_SR_RFNE: u32 = 1 << 3;
+
+/// The SSI enable register
+const SSI_SSIENR: *mut u32 = 0x1800_0008 as *mut u32;
+
+/// The SSI clock divider register
+const SSI_BAUDR: *mut u32 = 0x1800_0014 as *mut u32;
+
+/// The control register for the QSPI chip-select pin
+const IO_QSPI_SS_CTRL: *mut u32 = 0x4001_800C as *mut u32;
+
+/// In IO_QSPI_SS_CTRL: the output override field
+const SS_CTRL_OUTOVER_MASK: u32 = 0b11 << 8;
+
+/// In IO_QSPI_SS_CTRL: drive the chip-select low
+const SS_CTRL_OUTOVER_LOW: u32 = 0b10 << 8;
+
+/// In IO_QSPI_SS_CTRL: drive the chip-select high
+const SS_CTRL_OUTOVER_HIGH: u32 = 0b11 << 8;
+
+extern "C" {
+	static mut _flash_data_start: u32;
+	static mut _flash_data_len: u32;
+	static mut _flash_os_start: u32;
+	static mut _flash_os_len: u32;
+}
+
+/// The boot ROM functions, once `init` has looked them up.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut ROM_FUNCTIONS: Option<RomFunctions> = None;
+
+/// A copy of the second-stage bootloader, which sets XIP up for fast reads.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut BOOT2_COPY: [u32; 64] = [0u32; 64];
+
+/// The QSPI clock divider, or zero to leave the second-stage bootloader's.
+///
+/// Only accessed from Core 0.
+static mut CLOCK_DIVIDER: u32 = 0;
+
+/// Set once the XIP cache has been turned off, so we can use it as RAM.
+///
+/// Only accessed from Core 0.
+static mut CACHE_AS_RAM: bool = false;
+
+/// Set (to 1) by Core 0 when it wants Core 1 to stay off the flash chip.
+///
+/// Only accessed with volatile reads and writes.
+static mut PARK_REQUEST: u8 = 0;
+
+/// Set (to 1) by Core 0 once its interrupts are off, so Core 1 must run the
+/// video DMA. Cleared before Core 0 turns them back on.
+///
+/// Only accessed with volatile reads and writes.
+static mut HANDOVER: u8 = 0;
+
+/// Where Core 1 has got to: `PARKED_NO` when it is running as normal,
+/// `PARKED_YES` when it is waiting in RAM, and `PARKED_HANDED_BACK` when it
+/// has stopped running the video DMA.
+///
+/// Only accessed with volatile reads and writes.
+static mut PARKED: u8 = PARKED_NO;
+
+/// Core 1 is running as normal
+const PARKED_NO: u8 = 0;
+
+/// Core 1 is running from RAM
+const PARKED_YES: u8 = 1;
+
+/// Core 1 is running from RAM, and has stopped running the video DMA
+const PARKED_HANDED_BACK: u8 = 2;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Look up the boot ROM flash functions, and take a copy of the second-stage
+/// bootloader.
+pub fn init() {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	// The boot ROM and the bootloader are always there to read.
+	unsafe {
+		let boot2 = XIP_BASE as *const u32;
+		for (idx, word) in BOOT2_COPY.iter_mut().enumerate() {
+			*word = core::ptr::read_volatile(boot2.add(idx));
+		}
+		ROM_FUNCTIONS = Some(RomFunctions {
+			connect_internal_flash: core::mem::transmute(rom_func(b"IF")),
+			flash_exit_xip: core::mem::transmute(rom_func(b"EX")),
+			flash_range_erase: core::mem::transmute(rom_func(b"RE")),
+			flash_range_program: core::mem::transmute(rom_func(b"RP")),
+			flash_flush_cache: core::mem::transmute(rom_func(b"FC")),
+		});
+	}
+	debug!("Flash OK");
+}
+
+/// Change the QSPI clock divider, so the flash chip is clocked at the system
+/// clock divided by `divider` (which must be even).
+///
+/// Only call this before Core 1 starts, as it can't be parked yet.
+pub fn set_clock_divider(divider: u8) {
+	cortex_m::interrupt::free(|_cs| {
+		// Note (safety): Only called from Core 0, with interrupts off, before
+		// anything else is using the flash chip.
+		unsafe {
+			CLOCK_DIVIDER = u32::from(divider);
+			ram_set_clock_divider(CLOCK_DIVIDER);
+		}
+	});
+	debug!("Flash clock divider {}", divider);
+}
+
+/// Turn the XIP cache off, so its memory can be used as RAM.
+///
+/// Only call this before Core 1 starts.
+#[cfg(feature = "xip-cache-ram")]
+pub fn use_cache_as_ram() {
+	cortex_m::interrupt::free(|_cs| {
+		// Note (safety): Only called from Core 0, with interrupts off. Code
+		// in flash still runs with the cache off, just more slowly.
+		unsafe {
+			CACHE_AS_RAM = true;
+			ram_disable_cache();
+		}
+	});
+	debug!("XIP cache is now {} bytes of RAM", XIP_SRAM_LEN);
+}
+
+/// Where the XIP cache's memory is, if it is being used as RAM.
+pub fn cache_ram() -> Option<(*mut u8, usize)> {
+	// Note (safety): Only called from Core 0.
+	if unsafe { CACHE_AS_RAM } {
+		Some((XIP_SRAM_BASE as *mut u8, XIP_SRAM_LEN))
+	} else {
+		None
+	}
+}
+
+/// Look up a function in the boot ROM's function table, by its two-letter
+/// tag.
+///
+/// # Safety
+///
+/// The result must be transmuted to the right function type.
+unsafe fn rom_func(tag: &[u8; 2]) -> usize {
+	// The boot ROM keeps 16-bit pointers to the function table and the
+	// look-up function at fixed addresses.
+	let table = core::ptr::read_volatile(0x14 as *const u16) as usize as *const u16;
+	let lookup = core::ptr::read_volatile(0x18 as *const u16) as usize;
+	let lookup: extern "C" fn(*const u16, u32) -> usize = core::mem::transmute(lookup);
+	lookup(table, u32::from(tag[0]) | (u32::from(tag[1]) << 8))
+}
+
+/// Read bytes from the flash chip, at the given offset from the start.
+pub fn read(offset: usize, buffer: &mut [u8]) -> Result<(), Error> {
+	check_range(offset, buffer.len())?;
+	// Note (safety): We checked the range is inside the flash chip, which
+	// is always mapped.
+	let src =
+		unsafe { core::slice::from_raw_parts((XIP_BASE + offset) as *const u8, buffer.len()) };
+	buffer.copy_from_slice(src);
+	Ok(())
+}
+
+/// Erase one or more whole sectors, at the given offset from the start of
+/// the flash chip.
+///
+/// # Safety
+///
+/// The caller must make sure nothing we need (like the BIOS) lives in the
+/// sectors being erased.
+pub unsafe fn erase(offset: usize, len: usize) -> Result<(), Error> {
+	if (offset % SECTOR_SIZE) != 0 || (len % SECTOR_SIZE) != 0 {
+		return Err(Error::Misaligned);
+	}
+	check_range(offset, len)?;
+	debug!("Flash erase 0x{:06x}+0x{:x}", offset, len);
+	with_xip_off(|functions, boot2| {
+		ram_erase(functions, boot2, offset as u32, len);
+	})
+}
+
+/// Program one or more whole pages, at the given offset from the start of
+/// the flash chip. Programming can only turn 1 bits into 0 bits, so the
+/// pages should have been erased first.
+///
+/// # Safety
+///
+/// The caller must make sure nothing we need (like the BIOS) lives in the
+/// pages being programmed. The data must be in RAM, not flash.
+pub unsafe fn program(offset: usize, data: &[u8]) -> Result<(), Error> {
+	if (offset % PAGE_SIZE) != 0 || (data.len() % PAGE_SIZE) != 0 {
+		return Err(Error::Misaligned);
+	}
+	check_range(offset, data.len())?;
+	debug!("Flash program 0x{:06x}+0x{:x}", offset, data.len());
+	with_xip_off(|functions, boot2| {
+		ram_program(functions, boot2, offset as u32, data.as_ptr(), data.len());
+	})
+}
+
+/// Get the part of the flash chip the OS may erase and program.
+pub fn data_region() -> Region {
+	// Note (safety): We're only taking the addresses of the linker symbols.
+	let start = unsafe { &mut _flash_data_start as *mut u32 as usize };
+	let len = unsafe { &mut _flash_data_len as *const u32 as usize };
+	Region {
+		offset: (start - XIP_BASE) as u32,
+		length: len as u32,
+	}
+}
+
+/// Erase one sector of the data region, for the OS.
+pub fn erase_data_sector(offset: usize) -> Result<(), Error> {
+	check_data_region(offset, SECTOR_SIZE)?;
+	// Note (safety): The data region holds nothing of ours.
+	unsafe { erase(offset, SECTOR_SIZE) }
+}
+
+/// Program one page of the data region, for the OS.
+///
+/// The data is copied to RAM first, so it can come from anywhere.
+pub fn program_data_page(offset: usize, data: &[u8]) -> Result<(), Error> {
+	if data.len() != PAGE_SIZE {
+		return Err(Error::Misaligned);
+	}
+	check_data_region(offset, PAGE_SIZE)?;
+	let mut page = [0u8; PAGE_SIZE];
+	page.copy_from_slice(data);
+	// Note (safety): The data region holds nothing of ours, and `page` is on
+	// the stack.
+	unsafe { program(offset, &page) }
+}
+
+/// Get the part of the flash chip the OS image lives in.
+pub fn os_region() -> Region {
+	// Note (safety): We're only taking the addresses of the linker symbols.
+	let start = unsafe { &mut _flash_os_start as *mut u32 as usize };
+	let len = unsafe { &mut _flash_os_len as *const u32 as usize };
+	Region {
+		offset: (start - XIP_BASE) as u32,
+		length: len as u32,
+	}
+}
+
+/// Replace the OS image in flash with a new one.
+///
+/// We go a sector at a time, so the picture only goes black briefly. The
+/// rest of the last page is filled with 0xFF.
+///
+/// # Safety
+///
+/// Nothing may be running from the OS image. The image must be in RAM, not
+/// flash.
+pub unsafe fn program_os(image: &[u8]) -> Result<(), Error> {
+	let region = os_region();
+	if image.len() > region.length as usize {
+		return Err(Error::OutOfRange);
+	}
+	for (idx, sector) in image.chunks(SECTOR_SIZE).enumerate() {
+		let offset = region.offset as usize + (idx * SECTOR_SIZE);
+		erase(offset, SECTOR_SIZE)?;
+		let whole_pages = sector.len() - (sector.len() % PAGE_SIZE);
+		if whole_pages != 0 {
+			program(offset, &sector[0..whole_pages])?;
+		}
+		let tail = &sector[whole_pages..];
+		if !tail.is_empty() {
+			let mut page = [0xFFu8; PAGE_SIZE];
+			page[0..tail.len()].copy_from_slice(tail);
+			program(offset + whole_pages, &page)?;
+		}
+	}
+	Ok(())
+}
+
+/// Read the flash chip's 64-bit unique ID.
+pub fn unique_id() -> Result<u64, Error> {
+	let mut buffer = [0u8; 1 + UNIQUE_ID_DUMMY_BYTES + 8];
+	buffer[0] = READ_UNIQUE_ID_CMD;
+	let buffer_ptr = buffer.as_mut_ptr();
+	let buffer_len = buffer.len();
+	with_xip_off(|functions, boot2| {
+		// Note (safety): `buffer` is on the stack, so in RAM.
+		unsafe { ram_transfer(functions, boot2, buffer_ptr, buffer_len) };
+	})?;
+	let mut id = [0u8; 8];
+	id.copy_from_slice(&buffer[1 + UNIQUE_ID_DUMMY_BYTES..]);
+	Ok(u64::from_be_bytes(id))
+}
+
+/// Check a range is entirely inside the data region.
+fn check_data_region(offset: usize, len: usize) -> Result<(), Error> {
+	let region = data_region();
+	let start = region.offset as usize;
+	let end = start + region.length as usize;
+	match offset.checked_add(len) {
+		Some(range_end) if offset >= start && range_end <= end => Ok(()),
+		_ => Err(Error::NotAllowed),
+	}
+}
+
+/// Check a range is inside the flash chip.
+fn check_range(offset: usize, len: usize) -> Result<(), Error> {
+	match offset.checked_add(len) {
+		Some(end) if end <= FLASH_SIZE => Ok(()),
+		_ => Err(Error::OutOfRange),
+	}
+}
+
+/// Park Core 1, disable interrupts, hand the video DMA to Core 1, and run
+/// the given function, which must only call our RAM functions.
+fn with_xip_off<F>(f: F) -> Result<(), Error>
+where
+	F: FnOnce(&RomFunctions, *const u32),
+{
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let functions = unsafe { ROM_FUNCTIONS }.ok_or(Error::NotReady)?;
+	// Note (safety): We're only taking the address.
+	let boot2 = unsafe { BOOT2_COPY.as_ptr() };
+	park_core1()?;
+	cortex_m::interrupt::free(|_cs| {
+		// Note (safety): These are only accessed with volatile reads and
+		// writes. Core 1 is in RAM, so it answers straight away.
+		unsafe {
+			core::ptr::write_volatile(&mut HANDOVER, 1);
+			f(&functions, boot2);
+			core::ptr::write_volatile(&mut HANDOVER, 0);
+			// Don't take the DMA interrupt back until Core 1 has let go of it
+			while core::ptr::read_volatile(&PARKED) != PARKED_HANDED_BACK {}
+		}
+	});
+	unpark_core1();
+	Ok(())
+}
+
+/// Ask Core 1 to park itself in RAM, and wait until it has.
+fn park_core1() -> Result<(), Error> {
+	// Note (safety): These are only accessed with volatile reads and writes.
+	unsafe {
+		core::ptr::write_volatile(&mut PARK_REQUEST, 1);
+	}
+	let start = crate::ticks_ms();
+	while unsafe { core::ptr::read_volatile(&PARKED) } != PARKED_YES {
+		// Core 1 may be waiting for an event
+		cortex_m::asm::sev();
+		if crate::ticks_ms().wrapping_sub(start) > PARK_TIMEOUT_MS {
+			warn!("Core 1 didn't park");
+			unpark_core1();
+			return Err(Error::Core1Busy);
+		}
+	}
+	Ok(())
+}
+
+/// Let Core 1 carry on, and wait until it has left RAM (so it can't miss
+/// the next park request).
+fn unpark_core1() {
+	// Note (safety): These are only accessed with volatile reads and writes.
+	unsafe {
+		core::ptr::write_volatile(&mut PARK_REQUEST, 0);
+	}
+	let start = crate::ticks_ms();
+	while unsafe { core::ptr::read_volatile(&PARKED) } != PARKED_NO {
+		cortex_m::asm::sev();
+		if crate::ticks_ms().wrapping_sub(start) > PARK_TIMEOUT_MS {
+			// Core 1 never parked, so it isn't in RAM
+			break;
+		}
+	}
+}
+
+/// Called by Core 1 in between scan-lines. Moves Core 1 to RAM if Core 0
+/// wants to use the flash chip.
+///
+/// Must only be called on Core 1.
+pub fn core1_poll() {
+	// Note (safety): These are only accessed with volatile reads and writes.
+	if unsafe { core::ptr::read_volatile(&PARK_REQUEST) } != 0 {
+		// Note (safety): We're on Core 1, in between scan-lines, and
+		// `core1_park` runs from RAM. We work out what the video DMA needs
+		// first, while we can still run from flash.
+		unsafe {
+			let hold = crate::vga::dma_hold();
+			core1_park(&hold);
+		}
+	}
+}
+
+/// Wait in RAM until Core 0 has finished with the flash chip, running the
+/// video DMA while Core 0's interrupts are off.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn core1_park(hold: &Option<crate::vga::DmaHold>) {
+	ram_write8(&mut PARKED as *mut u8 as u32, PARKED_YES);
+	while ram_read8(&HANDOVER as *const u8 as u32) == 0 {
+		if ram_read8(&PARK_REQUEST as *const u8 as u32) == 0 {
+			// Core 0 gave up waiting for us
+			ram_write8(&mut PARKED as *mut u8 as u32, PARKED_NO);
+			return;
+		}
+	}
+	while ram_read8(&HANDOVER as *const u8 as u32) != 0 {
+		if let Some(hold) = hold {
+			crate::vga::ram_dma_hold_step(hold);
+		}
+	}
+	ram_write8(&mut PARKED as *mut u8 as u32, PARKED_HANDED_BACK);
+	while ram_read8(&PARK_REQUEST as *const u8 as u32) != 0 {}
+	ram_write8(&mut PARKED as *mut u8 as u32, PARKED_NO);
+}
+
+/// Erase some sectors, with XIP off. Runs from RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn ram_erase(functions: &RomFunctions, boot2: *const u32, addr: u32, len: usize) {
+	(functions.connect_internal_flash)();
+	(functions.flash_exit_xip)();
+	(functions.flash_range_erase)(addr, len, BLOCK_SIZE, BLOCK_ERASE_CMD);
+	(functions.flash_flush_cache)();
+	ram_enter_xip(boot2);
+}
+
+/// Program some pages, with XIP off. Runs from RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn ram_program(
+	functions: &RomFunctions,
+	boot2: *const u32,
+	addr: u32,
+	data: *const u8,
+	len: usize,
+) {
+	(functions.connect_internal_flash)();
+	(functions.flash_exit_xip)();
+	(functions.flash_range_program)(addr, data, len);
+	(functions.flash_flush_cache)();
+	ram_enter_xip(boot2);
+}
+
+/// Send the bytes in the buffer to the flash chip, replacing each with the
+/// byte that came back, with XIP off. Runs from RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn ram_transfer(functions: &RomFunctions, boot2: *const u32, buffer: *mut u8, len: usize) {
+	(functions.connect_internal_flash)();
+	(functions.flash_exit_xip)();
+	ram_set_chip_select(SS_CTRL_OUTOVER_LOW);
+	let mut idx = 0;
+	while idx < len {
+		let byte = (buffer as usize + idx) as *mut u8;
+		ram_write32(SSI_DR0 as u32, *byte as u32);
+		while (ram_read32(SSI_SR as u32) & SSI_SR_RFNE) == 0 {}
+		*byte = ram_read32(SSI_DR0 as u32) as u8;
+		idx += 1;
+	}
+	ram_set_chip_select(SS_CTRL_OUTOVER_HIGH);
+	(functions.flash_flush_cache)();
+	ram_enter_xip(boot2);
+}
+
+/// Force the QSPI chip-select pin high or low. Runs from RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn ram_set_chip_select(outover: u32) {
+	let value = ram_read32(IO_QSPI_SS_CTRL as u32);
+	ram_write32(
+		IO_QSPI_SS_CTRL as u32,
+		(value & !SS_CTRL_OUTOVER_MASK) | outover,
+	);
+}
+
+/// Run our copy of the second-stage bootloader, to put XIP back in fast
+/// mode, then put our clock divider back. Runs from RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn ram_enter_xip(boot2: *const u32) {
+	// Add 1 for a Thumb function pointer
+	let boot2: extern "C" fn() = core::mem::transmute(boot2 as usize + 1);
+	boot2();
+	if CLOCK_DIVIDER != 0 {
+		ram_set_clock_divider(CLOCK_DIVIDER);
+	}
+	// The boot ROM turned the cache back on when it flushed it
+	if CACHE_AS_RAM {
+		ram_disable_cache();
+	}
+}
+
+/// Turn the XIP cache off. Runs from RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn ram_disable_cache() {
+	let value = ram_read32(XIP_CTRL as u32);
+	ram_write32(XIP_CTRL as u32, value & !XIP_CTRL_EN);
+}
+
+/// Set the QSPI clock divider. The SSI has to be turned off to change it,
+/// which stops XIP, so this runs from RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn ram_set_clock_divider(divider: u32) {
+	ram_write32(SSI_SSIENR as u32, 0);
+	ram_write32(SSI_BAUDR as u32, divider);
+	ram_write32(SSI_SSIENR as u32, 1);
+}
+
+/// Read a 32-bit word, from code running in RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+pub unsafe fn ram_read32(addr: u32) -> u32 {
+	let value: u32;
+	core::arch::asm!("ldr {0}, [{1}]", out(reg) value, in(reg) addr, options(nostack, preserves_flags));
+	value
+}
+
+/// Write a 32-bit word, from code running in RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+pub unsafe fn ram_write32(addr: u32, value: u32) {
+	core::arch::asm!("str {0}, [{1}]", in(reg) value, in(reg) addr, options(nostack, preserves_flags));
+}
+
+/// Read a 16-bit value, from code running in RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+pub unsafe fn ram_read16(addr: u32) -> u16 {
+	let value: u32;
+	core::arch::asm!("ldrh {0}, [{1}]", out(reg) value, in(reg) addr, options(nostack, preserves_flags));
+	value as u16
+}
+
+/// Write a 16-bit value, from code running in RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+pub unsafe fn ram_write16(addr: u32, value: u16) {
+	core::arch::asm!("strh {0}, [{1}]", in(reg) value as u32, in(reg) addr, options(nostack, preserves_flags));
+}
+
+/// Read a byte, from code running in RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn ram_read8(addr: u32) -> u8 {
+	let value: u32;
+	core::arch::asm!("ldrb {0}, [{1}]", out(reg) value, in(reg) addr, options(nostack, preserves_flags));
+	value as u8
+}
+
+/// Write a byte, from code running in RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn ram_write8(addr: u32, value: u8) {
+	core::arch::asm!("strb {0}, [{1}]", in(reg) value as u32, in(reg) addr, options(nostack, preserves_flags));
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
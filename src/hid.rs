@@ -0,0 +1,970 @@
+//! # Human Interface Device support for the Neotron Pico
+//!
+//! The Neotron Pico has two PS/2 ports, which are run by the Board Management
+//! Controller. The BMC hands us the raw bytes each device sends, and passes
+//! on any command bytes we give it. Everything else - working out what is
+//! plugged in, and noticing when it is unplugged - happens here.
+//!
+//! PS/2 devices are not designed to be hot-plugged, but in practice they
+//! cope. A newly connected device runs its Basic Assurance Test (BAT) and
+//! sends `0xAA` if it passed. We treat that as an attach, identify the
+//! device, and then periodically poke it to check it is still there. A
+//! device that stops answering is treated as detached, and we keep sending
+//! it resets until something answers again.
+//!
+//! USB keyboards and mice aren't supported, as we have no USB host port (see
+//! `usb`). On a board without a BMC, type on the serial port instead.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{bmc, common, config, keymap, vga};
+use defmt::{debug, info, trace, warn};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The kinds of device we can find on a PS/2 port.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum DeviceKind {
+	/// A PS/2 keyboard
+	Keyboard = 0,
+	/// A PS/2 mouse
+	Mouse = 1,
+}
+
+/// Something happened to one of our PS/2 ports.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum DeviceEvent {
+	/// A device was plugged in to the given port and passed its self-test
+	Attached { port: u8, kind: DeviceKind },
+	/// The device on the given port has stopped responding
+	Detached { port: u8 },
+}
+
+/// Where we are with the device on a PS/2 port.
+///
+/// All the times are from `crate::ticks_ms`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum PortState {
+	/// Nothing is answering. We last sent a reset at the given time.
+	Detached { since: u32 },
+	/// We sent a reset at the given time, and are waiting for the BAT result.
+	Resetting { since: u32 },
+	/// We sent Read ID at the given time and are waiting for the answer.
+	Identifying {
+		since: u32,
+		got_ack: bool,
+		keyboard: bool,
+	},
+	/// We are sending the IntelliMouse knock sequence to a mouse, to turn
+	/// on its scroll wheel. We sent step `step` at the given time.
+	WheelProbe { since: u32, step: u8, got_ack: bool },
+	/// We sent Enable Data Reporting to a mouse at the given time.
+	Enabling { since: u32 },
+	/// The device is working. We last heard from it at the given time.
+	Attached { kind: DeviceKind, last_seen: u32 },
+	/// The device went quiet, so we poked it at the given time.
+	Probing { kind: DeviceKind, since: u32 },
+	/// We sent part of a Set LEDs command to a keyboard at the given time.
+	/// This is attempt number `tries` at the current byte.
+	SettingLeds {
+		since: u32,
+		sent_value: bool,
+		tries: u8,
+	},
+}
+
+/// The ways in which changing the keyboard LEDs can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// There's no keyboard plugged in
+	NoKeyboard,
+	/// The keyboard kept rejecting the command, or didn't answer
+	NoResponse,
+}
+
+/// One of the PS/2 ports on the BMC.
+struct Port {
+	/// Which port this is (0 or 1)
+	index: u8,
+	/// Where we read received bytes from
+	data_register: bmc::Register,
+	/// Where we write command bytes to
+	command_register: bmc::Register,
+	/// What is going on with the attached device
+	state: PortState,
+	/// A partially received mouse packet
+	packet: [u8; 4],
+	/// How many bytes of `packet` are valid
+	packet_len: usize,
+	/// The mouse has a scroll wheel, so it sends 4-byte packets
+	wheel: bool,
+	/// The keyboard sent a break (key released) prefix
+	key_break: bool,
+	/// The keyboard sent an extended key prefix
+	key_extended: bool,
+	/// Scroll Lock is held down, so the function keys pick a virtual console
+	scroll_lock_held: bool,
+	/// Either Shift key is held down (bit 0 is left, bit 1 is right)
+	shift_held: u8,
+	/// The Caps Lock key is held down
+	caps_lock_held: bool,
+	/// Caps Lock is on
+	caps_lock: bool,
+	/// The LEDs the OS wants on, as `LED_xxx` bits
+	leds: u8,
+	/// The last attempt to set the LEDs worked
+	leds_ok: bool,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The two PS/2 ports.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut PORTS: [Port; 2] = [
+	Port::new(
+		0,
+		bmc::Register::Ps2Port0Data,
+		bmc::Register::Ps2Port0Command,
+	),
+	Port::new(
+		1,
+		bmc::Register::Ps2Port1Data,
+		bmc::Register::Ps2Port1Command,
+	),
+];
+
+/// Attach and detach events we haven't given to the OS yet.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut DEVICE_EVENTS: heapless::Deque<DeviceEvent, 8> = heapless::Deque::new();
+
+/// HID events we haven't given to the OS yet.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut EVENTS: heapless::Deque<common::hid::HidEvent, 16> = heapless::Deque::new();
+
+/// How far the mouse scroll wheel has moved since the OS last asked.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut WHEEL_DELTA: i16 = 0;
+
+/// Characters typed on the keyboard that nobody has read yet.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut CHARS: heapless::Deque<char, 16> = heapless::Deque::new();
+
+/// Delete or F2 has been pressed, asking for the BIOS setup screen.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut SETUP_REQUESTED: bool = false;
+
+/// Scan Code Set 2 prefix for a key being released
+const SCANCODE_BREAK: u8 = 0xF0;
+
+/// Scan Code Set 2 prefix for an extended key
+const SCANCODE_EXTENDED: u8 = 0xE0;
+
+/// Scan Code Set 2 code for Scroll Lock
+const SCANCODE_SCROLL_LOCK: u8 = 0x7E;
+
+/// Scan Code Set 2 codes for F1, F2 and F3, which (with Scroll Lock held)
+/// pick virtual consoles 0, 1 and 2.
+const SCANCODE_CONSOLE_KEYS: [u8; vga::MAX_CONSOLES as usize] = [0x05, 0x06, 0x04];
+
+/// Scan Code Set 2 code for F2, which asks for the BIOS setup screen
+const SCANCODE_F2: u8 = 0x06;
+
+/// Scan Code Set 2 code for Delete (after `SCANCODE_EXTENDED`), which also
+/// asks for the BIOS setup screen
+const SCANCODE_EXTENDED_DELETE: u8 = 0x71;
+
+/// Scan Code Set 2 code for the left Shift key
+const SCANCODE_LEFT_SHIFT: u8 = 0x12;
+
+/// Scan Code Set 2 code for the right Shift key
+const SCANCODE_RIGHT_SHIFT: u8 = 0x59;
+
+/// Scan Code Set 2 code for Caps Lock
+const SCANCODE_CAPS_LOCK: u8 = 0x58;
+
+/// How long to wait between resets when nothing is plugged in.
+const RESCAN_INTERVAL_MS: u32 = 2000;
+
+/// How long a device gets to finish its BAT. The spec says 500 to 750 ms.
+const RESET_TIMEOUT_MS: u32 = 1000;
+
+/// How long a device gets to answer any other command. The spec says 20 ms,
+/// but we allow for the BMC only being polled occasionally.
+const COMMAND_TIMEOUT_MS: u32 = 100;
+
+/// How long a device can be silent before we check it is still there.
+const IDLE_PROBE_MS: u32 = 1000;
+
+/// Sent by a device which has passed its Basic Assurance Test
+const RESPONSE_BAT_PASSED: u8 = 0xAA;
+
+/// Sent by a device which has failed its Basic Assurance Test
+const RESPONSE_BAT_FAILED: u8 = 0xFC;
+
+/// Sent by a device to acknowledge a command
+const RESPONSE_ACK: u8 = 0xFA;
+
+/// Sent by a device which wants the last byte again
+const RESPONSE_RESEND: u8 = 0xFE;
+
+/// The first byte of a keyboard's ID (a mouse sends 0x00, 0x03 or 0x04)
+const KEYBOARD_ID: u8 = 0xAB;
+
+/// Reset the device and run the BAT
+const COMMAND_RESET: u8 = 0xFF;
+
+/// Ask the device what it is
+const COMMAND_READ_ID: u8 = 0xF2;
+
+/// Ask a mouse to change how often it sends packets. Followed by the rate.
+const COMMAND_SET_SAMPLE_RATE: u8 = 0xF3;
+
+/// Setting these sample rates, in order, and then asking for the ID, makes
+/// an IntelliMouse turn on its scroll wheel and change its ID.
+const WHEEL_KNOCK_SEQUENCE: [u8; 7] = [
+	COMMAND_SET_SAMPLE_RATE,
+	200,
+	COMMAND_SET_SAMPLE_RATE,
+	100,
+	COMMAND_SET_SAMPLE_RATE,
+	80,
+	COMMAND_READ_ID,
+];
+
+/// The ID of a mouse with a scroll wheel
+const WHEEL_MOUSE_ID: u8 = 0x03;
+
+/// The ID of a mouse with a scroll wheel and five buttons
+const FIVE_BUTTON_MOUSE_ID: u8 = 0x04;
+
+/// Set in the first byte of every mouse packet, so we can find the start
+const MOUSE_SYNC_BIT: u8 = 1 << 3;
+
+/// Ask a mouse to start sending packets
+const COMMAND_ENABLE_REPORTING: u8 = 0xF4;
+
+/// Ask a keyboard to reply with `0xEE`
+const COMMAND_ECHO: u8 = 0xEE;
+
+/// Ask a keyboard to change its LEDs. Followed by the `LED_xxx` bits.
+const COMMAND_SET_LEDS: u8 = 0xED;
+
+/// How many times we send each byte of a Set LEDs command before giving up
+const LED_MAX_TRIES: u8 = 3;
+
+/// The bit for the Scroll Lock LED
+pub const LED_SCROLL_LOCK: u8 = 1 << 0;
+
+/// The bit for the Num Lock LED
+pub const LED_NUM_LOCK: u8 = 1 << 1;
+
+/// The bit for the Caps Lock LED
+pub const LED_CAPS_LOCK: u8 = 1 << 2;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Fetch any new bytes from the BMC and update the state of each PS/2 port.
+///
+/// Call this regularly - it is where all the timeouts are checked.
+pub fn poll() {
+	let now = crate::ticks_ms();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let ports = unsafe { &mut PORTS };
+	for port in ports.iter_mut() {
+		port.poll(now);
+	}
+}
+
+/// Change the LEDs on every attached keyboard, to the given `LED_xxx` bits.
+///
+/// This waits until each keyboard has accepted the change, resending any
+/// byte it rejects. The LEDs are set again whenever a keyboard is plugged in.
+pub fn set_leds(leds: u8) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let ports = unsafe { &mut PORTS };
+	let now = crate::ticks_ms();
+	let mut found = false;
+	for port in ports.iter_mut() {
+		found |= port.start_set_leds(leds, now);
+	}
+	if !found {
+		return Err(Error::NoKeyboard);
+	}
+	// Each byte times out, so this can't wait forever
+	while ports
+		.iter()
+		.any(|port| matches!(port.state, PortState::SettingLeds { .. }))
+	{
+		let now = crate::ticks_ms();
+		for port in ports.iter_mut() {
+			port.poll(now);
+		}
+	}
+	let all_ok = ports
+		.iter()
+		.filter(|port| port.kind() == Some(DeviceKind::Keyboard))
+		.all(|port| port.leds_ok);
+	if all_ok {
+		Ok(())
+	} else {
+		Err(Error::NoResponse)
+	}
+}
+
+/// Get the next HID event, if any.
+pub fn get_event() -> Option<common::hid::HidEvent> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { EVENTS.pop_front() }
+}
+
+/// Find out how far the mouse scroll wheel has moved since the last call.
+///
+/// Positive values mean the wheel was turned towards the user.
+pub fn take_wheel_delta() -> i16 {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { core::mem::replace(&mut WHEEL_DELTA, 0) }
+}
+
+/// Get the next attach/detach event, if any.
+pub fn get_device_event() -> Option<DeviceEvent> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { DEVICE_EVENTS.pop_front() }
+}
+
+/// Get the next character typed on the keyboard, if any.
+///
+/// Key presses are turned into characters using the keyboard layout in the
+/// BIOS configuration. The queue is only updated when you call `poll`.
+pub fn get_char() -> Option<char> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { CHARS.pop_front() }
+}
+
+/// Has Delete or F2 been pressed since we last asked? Used for entering the
+/// BIOS setup screen during the boot countdown.
+pub fn take_setup_request() -> bool {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { core::mem::replace(&mut SETUP_REQUESTED, false) }
+}
+
+/// Find out what is plugged into the given PS/2 port.
+///
+/// Returns `None` if nothing is plugged in, or the port doesn't exist.
+pub fn get_device_kind(port: u8) -> Option<DeviceKind> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let port = unsafe { PORTS.get(port as usize)? };
+	port.kind()
+}
+
+/// Queue up a HID event for the OS.
+///
+/// If the queue is full, the oldest event is dropped.
+fn push_event(event: common::hid::HidEvent) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		if EVENTS.is_full() {
+			let _ = EVENTS.pop_front();
+		}
+		let _ = EVENTS.push_back(event);
+	}
+}
+
+/// Queue up a typed character.
+///
+/// If the queue is full, the character is dropped.
+fn push_char(ch: char) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		let _ = CHARS.push_back(ch);
+	}
+}
+
+/// Note that someone wants the BIOS setup screen.
+fn request_setup() {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		SETUP_REQUESTED = true;
+	}
+}
+
+/// Queue up an attach/detach event for the OS.
+///
+/// If the queue is full, the oldest event is dropped.
+fn push_device_event(event: DeviceEvent) {
+	info!("HID: {}", event);
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		if DEVICE_EVENTS.is_full() {
+			let _ = DEVICE_EVENTS.pop_front();
+		}
+		let _ = DEVICE_EVENTS.push_back(event);
+	}
+}
+
+impl Port {
+	/// Make a new port, which assumes nothing is plugged in.
+	const fn new(index: u8, data_register: bmc::Register, command_register: bmc::Register) -> Port {
+		Port {
+			index,
+			data_register,
+			command_register,
+			// This makes us send a reset on the first poll
+			state: PortState::Detached {
+				since: 0u32.wrapping_sub(RESCAN_INTERVAL_MS),
+			},
+			packet: [0; 4],
+			packet_len: 0,
+			wheel: false,
+			key_break: false,
+			key_extended: false,
+			scroll_lock_held: false,
+			shift_held: 0,
+			caps_lock_held: false,
+			caps_lock: false,
+			leds: 0,
+			leds_ok: false,
+		}
+	}
+
+	/// What kind of device is attached to this port (if any).
+	fn kind(&self) -> Option<DeviceKind> {
+		match self.state {
+			PortState::Attached { kind, .. } | PortState::Probing { kind, .. } => Some(kind),
+			PortState::SettingLeds { .. } => Some(DeviceKind::Keyboard),
+			_ => None,
+		}
+	}
+
+	/// Process any received bytes, then check for timeouts.
+	fn poll(&mut self, now: u32) {
+		let mut buffer = [0u8; 8];
+		match bmc::read_register(self.data_register, &mut buffer) {
+			Ok(()) => {
+				let count = (buffer[0] as usize).min(buffer.len() - 1);
+				for byte in buffer[1..=count].iter() {
+					self.handle_byte(*byte, now);
+				}
+			}
+			Err(e) => {
+				trace!("PS/2 port {} read failed: {}", self.index, e);
+			}
+		}
+		self.check_timeouts(now);
+	}
+
+	/// Process one byte from the device.
+	fn handle_byte(&mut self, byte: u8, now: u32) {
+		trace!("PS/2 port {} got {:02x}", self.index, byte);
+		match self.state {
+			PortState::Attached {
+				kind: DeviceKind::Mouse,
+				..
+			} => {
+				self.handle_mouse_byte(byte, now);
+			}
+			PortState::Identifying { .. } => {
+				self.handle_id_byte(byte, now);
+			}
+			PortState::WheelProbe { .. } => {
+				self.handle_wheel_probe_byte(byte, now);
+			}
+			PortState::Probing {
+				kind: DeviceKind::Mouse,
+				..
+			} => {
+				// We sent Read ID, so expect `FA 00` (or `FA 03`, etc). Once
+				// we have the ID byte, the mouse is still there.
+				if byte != RESPONSE_ACK {
+					self.packet_len = 0;
+					self.state = PortState::Attached {
+						kind: DeviceKind::Mouse,
+						last_seen: now,
+					};
+				}
+			}
+			_ if byte == RESPONSE_BAT_PASSED => {
+				self.replugged(now);
+			}
+			_ if byte == RESPONSE_BAT_FAILED => {
+				warn!("PS/2 port {} device failed self-test", self.index);
+				self.detach(now);
+			}
+			PortState::Detached { .. } | PortState::Resetting { .. } => {
+				// Probably the ACK for the reset - the BAT result follows.
+			}
+			PortState::Enabling { .. } => {
+				if byte == RESPONSE_ACK {
+					self.attach(DeviceKind::Mouse, now);
+				}
+			}
+			PortState::SettingLeds {
+				sent_value, tries, ..
+			} => match byte {
+				RESPONSE_ACK if !sent_value => {
+					self.send(self.leds);
+					self.state = PortState::SettingLeds {
+						since: now,
+						sent_value: true,
+						tries: 1,
+					};
+				}
+				RESPONSE_ACK => {
+					self.leds_ok = true;
+					self.state = PortState::Attached {
+						kind: DeviceKind::Keyboard,
+						last_seen: now,
+					};
+				}
+				RESPONSE_RESEND => {
+					self.retry_leds(sent_value, tries, now);
+				}
+				_ => {
+					// Someone is typing while we wait
+					vga::mark_dirty();
+					self.handle_key_byte(byte);
+				}
+			},
+			PortState::Attached {
+				kind: DeviceKind::Keyboard,
+				..
+			}
+			| PortState::Probing {
+				kind: DeviceKind::Keyboard,
+				..
+			} => {
+				if byte != COMMAND_ECHO {
+					// Someone is typing, so make sure they can see the screen
+					vga::mark_dirty();
+					self.handle_key_byte(byte);
+				}
+				self.state = PortState::Attached {
+					kind: DeviceKind::Keyboard,
+					last_seen: now,
+				};
+			}
+		}
+	}
+
+	/// Decode a byte from the keyboard.
+	///
+	/// Scroll Lock + F1/F2/F3 switches between the virtual consoles. F2 or
+	/// Delete on their own ask for the setup screen. Any other key press
+	/// which makes a character is queued for `get_char`.
+	///
+	/// Every key we recognise, including those, is also queued as a
+	/// `KeyPress` or `KeyRelease` event, using the configured layout.
+	fn handle_key_byte(&mut self, byte: u8) {
+		match byte {
+			SCANCODE_BREAK => {
+				self.key_break = true;
+				return;
+			}
+			SCANCODE_EXTENDED => {
+				self.key_extended = true;
+				return;
+			}
+			_ => {}
+		}
+		let released = self.key_break;
+		let extended = self.key_extended;
+		self.key_break = false;
+		self.key_extended = false;
+		let layout = config::get().keyboard_layout;
+		if let Some(key) = keymap::key_code(layout, byte, extended) {
+			push_event(if released {
+				common::hid::HidEvent::KeyRelease(key)
+			} else {
+				common::hid::HidEvent::KeyPress(key)
+			});
+		}
+		if extended {
+			// `E0 7E` is Ctrl+Break, not Scroll Lock
+			if byte == SCANCODE_EXTENDED_DELETE && !released {
+				request_setup();
+			}
+			return;
+		}
+		match byte {
+			SCANCODE_SCROLL_LOCK => {
+				self.scroll_lock_held = !released;
+			}
+			SCANCODE_LEFT_SHIFT | SCANCODE_RIGHT_SHIFT => {
+				let bit = if byte == SCANCODE_LEFT_SHIFT { 1 } else { 2 };
+				if released {
+					self.shift_held &= !bit;
+				} else {
+					self.shift_held |= bit;
+				}
+			}
+			SCANCODE_CAPS_LOCK => {
+				// A held key keeps sending its make code, so only toggle on
+				// the first one.
+				if !released && !self.caps_lock_held {
+					self.caps_lock = !self.caps_lock;
+				}
+				self.caps_lock_held = !released;
+			}
+			_ if released => {}
+			_ if self.scroll_lock_held => {
+				if let Some(idx) = SCANCODE_CONSOLE_KEYS.iter().position(|&code| code == byte) {
+					vga::set_active_console(idx as u8);
+				}
+			}
+			SCANCODE_F2 => request_setup(),
+			_ => {
+				let shift = self.shift_held != 0;
+				if let Some(ch) = keymap::translate(layout, byte, shift, self.caps_lock) {
+					push_char(ch);
+				}
+			}
+		}
+	}
+
+	/// Process a byte from a working mouse.
+	fn handle_mouse_byte(&mut self, byte: u8, now: u32) {
+		if self.packet_len == 0 && (byte & MOUSE_SYNC_BIT) == 0 {
+			// Not the start of a packet, so we've lost our place. Drop
+			// bytes until we find one that could be.
+			trace!("PS/2 port {} mouse out of sync", self.index);
+			return;
+		}
+		self.packet[self.packet_len] = byte;
+		self.packet_len += 1;
+		// Mouse packets can contain anything, so a mouse has only been
+		// re-plugged if we get `AA 00` at the start of a packet.
+		if self.packet_len == 2 && self.packet[0..2] == [RESPONSE_BAT_PASSED, 0x00] {
+			self.packet_len = 0;
+			// The mouse has already sent its ID, but the reset turned off
+			// any scroll wheel.
+			push_device_event(DeviceEvent::Detached { port: self.index });
+			self.start_wheel_probe(now);
+			return;
+		}
+		let packet_len = if self.wheel { 4 } else { 3 };
+		if self.packet_len == packet_len {
+			// Someone is using the mouse, so make sure they can see the screen
+			vga::mark_dirty();
+			self.handle_mouse_packet();
+			self.packet_len = 0;
+		}
+		self.state = PortState::Attached {
+			kind: DeviceKind::Mouse,
+			last_seen: now,
+		};
+	}
+
+	/// Turn a complete mouse packet into a HID event.
+	///
+	/// PS/2 mice count upwards as positive, but we flip the Y axis so it
+	/// matches the screen.
+	fn handle_mouse_packet(&mut self) {
+		let flags = self.packet[0];
+		let mut x = i16::from(self.packet[1]);
+		if (flags & (1 << 4)) != 0 {
+			x -= 256;
+		}
+		let mut y = i16::from(self.packet[2]);
+		if (flags & (1 << 5)) != 0 {
+			y -= 256;
+		}
+		let mut buttons = common::hid::MouseButtons::new();
+		buttons.set_left_pressed((flags & (1 << 0)) != 0);
+		buttons.set_right_pressed((flags & (1 << 1)) != 0);
+		buttons.set_middle_pressed((flags & (1 << 2)) != 0);
+		if self.wheel {
+			// The bottom four bits are the wheel movement, as a signed number
+			let wheel = ((self.packet[3] << 4) as i8) >> 4;
+			// Note (safety): Only called from Core 0, and never from an interrupt.
+			unsafe {
+				WHEEL_DELTA = WHEEL_DELTA.saturating_add(i16::from(wheel));
+			}
+		}
+		crate::vga::sprites::move_pointer(x, -y);
+		push_event(common::hid::HidEvent::MouseInput(common::hid::MouseData {
+			x,
+			y: -y,
+			buttons,
+		}));
+	}
+
+	/// Process a byte we got in reply to `COMMAND_READ_ID`.
+	fn handle_id_byte(&mut self, byte: u8, now: u32) {
+		match self.state {
+			PortState::Identifying {
+				got_ack: false,
+				since,
+				..
+			} => {
+				if byte == RESPONSE_ACK {
+					self.state = PortState::Identifying {
+						since,
+						got_ack: true,
+						keyboard: false,
+					};
+				}
+			}
+			PortState::Identifying { keyboard: true, .. } => {
+				// This is the second byte of the keyboard ID (usually 0x83)
+				self.attach(DeviceKind::Keyboard, now);
+			}
+			PortState::Identifying { since, .. } if byte == KEYBOARD_ID => {
+				self.state = PortState::Identifying {
+					since,
+					got_ack: true,
+					keyboard: true,
+				};
+			}
+			_ if byte == WHEEL_MOUSE_ID || byte == FIVE_BUTTON_MOUSE_ID => {
+				// A mouse which already has its scroll wheel turned on
+				self.wheel = true;
+				self.enable_mouse(now);
+			}
+			_ => {
+				// A plain mouse - see if it has a scroll wheel we can turn on
+				self.start_wheel_probe(now);
+			}
+		}
+	}
+
+	/// Start sending the IntelliMouse knock sequence.
+	fn start_wheel_probe(&mut self, now: u32) {
+		self.wheel = false;
+		self.send(WHEEL_KNOCK_SEQUENCE[0]);
+		self.state = PortState::WheelProbe {
+			since: now,
+			step: 0,
+			got_ack: false,
+		};
+	}
+
+	/// Process a byte we got in reply to the IntelliMouse knock sequence.
+	///
+	/// Every byte we send is ACKed. The final Read ID is also followed by the
+	/// ID, which tells us if the scroll wheel is now on.
+	fn handle_wheel_probe_byte(&mut self, byte: u8, now: u32) {
+		let step = match self.state {
+			PortState::WheelProbe { step, .. } => usize::from(step),
+			_ => return,
+		};
+		if step == WHEEL_KNOCK_SEQUENCE.len() - 1 {
+			// Waiting for the ACK, then the ID
+			if let PortState::WheelProbe { got_ack: false, .. } = self.state {
+				if byte == RESPONSE_ACK {
+					self.state = PortState::WheelProbe {
+						since: now,
+						step: step as u8,
+						got_ack: true,
+					};
+				}
+				return;
+			}
+			self.wheel = byte == WHEEL_MOUSE_ID || byte == FIVE_BUTTON_MOUSE_ID;
+			debug!("PS/2 port {} mouse ID {:02x}", self.index, byte);
+			self.enable_mouse(now);
+		} else if byte == RESPONSE_ACK {
+			let step = step + 1;
+			self.send(WHEEL_KNOCK_SEQUENCE[step]);
+			self.state = PortState::WheelProbe {
+				since: now,
+				step: step as u8,
+				got_ack: false,
+			};
+		} else {
+			// Probably a NAK - it can still be used without the wheel
+			self.enable_mouse(now);
+		}
+	}
+
+	/// Turn on data reporting, which a mouse needs before it sends
+	/// anything.
+	fn enable_mouse(&mut self, now: u32) {
+		self.send(COMMAND_ENABLE_REPORTING);
+		self.state = PortState::Enabling { since: now };
+	}
+
+	/// Check if anything has taken too long.
+	fn check_timeouts(&mut self, now: u32) {
+		match self.state {
+			PortState::Detached { since } => {
+				if now.wrapping_sub(since) >= RESCAN_INTERVAL_MS {
+					self.send(COMMAND_RESET);
+					self.state = PortState::Resetting { since: now };
+				}
+			}
+			PortState::Resetting { since } => {
+				if now.wrapping_sub(since) >= RESET_TIMEOUT_MS {
+					self.state = PortState::Detached { since };
+				}
+			}
+			PortState::Identifying { since, .. }
+			| PortState::WheelProbe { since, .. }
+			| PortState::Enabling { since } => {
+				if now.wrapping_sub(since) >= COMMAND_TIMEOUT_MS {
+					debug!("PS/2 port {} didn't identify", self.index);
+					self.state = PortState::Detached { since: now };
+				}
+			}
+			PortState::Attached { kind, last_seen } => {
+				if now.wrapping_sub(last_seen) >= IDLE_PROBE_MS {
+					// A mouse packet can't be in progress if it's gone
+					// this quiet, so any partial one is junk.
+					self.packet_len = 0;
+					self.send(match kind {
+						DeviceKind::Keyboard => COMMAND_ECHO,
+						DeviceKind::Mouse => COMMAND_READ_ID,
+					});
+					self.state = PortState::Probing { kind, since: now };
+				}
+			}
+			PortState::Probing { since, .. } => {
+				if now.wrapping_sub(since) >= COMMAND_TIMEOUT_MS {
+					self.detach(now);
+				}
+			}
+			PortState::SettingLeds {
+				since,
+				sent_value,
+				tries,
+			} => {
+				if now.wrapping_sub(since) >= COMMAND_TIMEOUT_MS {
+					self.retry_leds(sent_value, tries, now);
+				}
+			}
+		}
+	}
+
+	/// Start sending a Set LEDs command, if this port has a keyboard.
+	///
+	/// Returns `false` if there's no keyboard here.
+	fn start_set_leds(&mut self, leds: u8, now: u32) -> bool {
+		self.leds = leds;
+		self.leds_ok = false;
+		if self.kind() != Some(DeviceKind::Keyboard) {
+			return false;
+		}
+		self.send(COMMAND_SET_LEDS);
+		self.state = PortState::SettingLeds {
+			since: now,
+			sent_value: false,
+			tries: 1,
+		};
+		true
+	}
+
+	/// The keyboard rejected, or ignored, part of a Set LEDs command, so send
+	/// it again. Give up after `LED_MAX_TRIES` goes.
+	fn retry_leds(&mut self, sent_value: bool, tries: u8, now: u32) {
+		if tries >= LED_MAX_TRIES {
+			warn!("PS/2 port {} keyboard won't set LEDs", self.index);
+			// The keyboard may still be fine, so let the probe decide
+			self.state = PortState::Attached {
+				kind: DeviceKind::Keyboard,
+				last_seen: now,
+			};
+			return;
+		}
+		self.send(if sent_value {
+			self.leds
+		} else {
+			COMMAND_SET_LEDS
+		});
+		self.state = PortState::SettingLeds {
+			since: now,
+			sent_value,
+			tries: tries + 1,
+		};
+	}
+
+	/// The device has just run its BAT, so find out what it is.
+	fn replugged(&mut self, now: u32) {
+		if self.kind().is_some() {
+			// Whatever was here before has gone
+			push_device_event(DeviceEvent::Detached { port: self.index });
+		}
+		self.send(COMMAND_READ_ID);
+		self.state = PortState::Identifying {
+			since: now,
+			got_ack: false,
+			keyboard: false,
+		};
+	}
+
+	/// The device is fully working.
+	fn attach(&mut self, kind: DeviceKind, now: u32) {
+		self.packet_len = 0;
+		self.key_break = false;
+		self.key_extended = false;
+		self.scroll_lock_held = false;
+		self.shift_held = 0;
+		self.caps_lock_held = false;
+		self.caps_lock = false;
+		self.state = PortState::Attached {
+			kind,
+			last_seen: now,
+		};
+		push_device_event(DeviceEvent::Attached {
+			port: self.index,
+			kind,
+		});
+		if kind == DeviceKind::Keyboard && self.leds != 0 {
+			// A new keyboard starts with its LEDs off
+			self.start_set_leds(self.leds, now);
+		}
+	}
+
+	/// The device has stopped working.
+	fn detach(&mut self, now: u32) {
+		if self.kind().is_some() {
+			push_device_event(DeviceEvent::Detached { port: self.index });
+		}
+		self.packet_len = 0;
+		self.state = PortState::Detached { since: now };
+	}
+
+	/// Send a command byte to the device.
+	fn send(&mut self, command: u8) {
+		trace!("PS/2 port {} sending {:02x}", self.index, command);
+		if let Err(e) = bmc::write_register(self.command_register, &[command]) {
+			debug!("PS/2 port {} write failed: {}", self.index, e);
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,275 @@
+//! # Keyboard layouts for the Neotron Pico
+//!
+//! PS/2 keyboards send a scan-code for the position of each key, not the
+//! character printed on it. This module turns Scan Code Set 2 codes into
+//! characters, and into the HID key codes the OS gets from `hid_get_event`,
+//! for the layouts printed on US, UK, French and German keyboards.
+//!
+//! A key code names the key by what's printed on it, so the letter keys
+//! follow the layout (the key next to Tab is `A` on a French keyboard).
+//! Every other key is named by its position on a US keyboard - the key
+//! which types `&` and `1` on a French keyboard is still `Key1`.
+//!
+//! The OS is expected to have its own keyboard handling, but this means
+//! the BIOS and the OS agree on the keyboard until it loads its own map.
+//! Dead keys and AltGr aren't supported.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::common::hid::KeyCode;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The keyboard layouts we know about.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Layout {
+	/// US English QWERTY
+	Qwerty = 0,
+	/// French AZERTY
+	Azerty = 1,
+	/// German QWERTZ
+	Qwertz = 2,
+	/// UK English QWERTY
+	Uk = 3,
+}
+
+/// What one key produces: (scan-code, unshifted, shifted).
+type KeyMapping = (u8, char, char);
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// Keys which do the same thing on every layout.
+const COMMON_KEYS: [(u8, char); 5] = [
+	(0x29, ' '),
+	(0x5A, '\n'),
+	(0x66, '\u{0008}'),
+	(0x0D, '\t'),
+	(0x76, '\u{001B}'),
+];
+
+/// Which key each (non-extended) scan-code comes from, by its position on a
+/// US keyboard.
+#[rustfmt::skip]
+const KEY_CODES: [(u8, KeyCode); 86] = [
+	(0x01, KeyCode::F9), (0x03, KeyCode::F5), (0x04, KeyCode::F3),
+	(0x05, KeyCode::F1), (0x06, KeyCode::F2), (0x07, KeyCode::F12),
+	(0x09, KeyCode::F10), (0x0A, KeyCode::F8), (0x0B, KeyCode::F6),
+	(0x0C, KeyCode::F4), (0x0D, KeyCode::Tab), (0x0E, KeyCode::BackTick),
+	(0x11, KeyCode::AltLeft), (0x12, KeyCode::ShiftLeft), (0x14, KeyCode::ControlLeft),
+	(0x15, KeyCode::Q), (0x16, KeyCode::Key1), (0x1A, KeyCode::Z),
+	(0x1B, KeyCode::S), (0x1C, KeyCode::A), (0x1D, KeyCode::W),
+	(0x1E, KeyCode::Key2), (0x21, KeyCode::C), (0x22, KeyCode::X),
+	(0x23, KeyCode::D), (0x24, KeyCode::E), (0x25, KeyCode::Key4),
+	(0x26, KeyCode::Key3), (0x29, KeyCode::Spacebar), (0x2A, KeyCode::V),
+	(0x2B, KeyCode::F), (0x2C, KeyCode::T), (0x2D, KeyCode::R),
+	(0x2E, KeyCode::Key5), (0x31, KeyCode::N), (0x32, KeyCode::B),
+	(0x33, KeyCode::H), (0x34, KeyCode::G), (0x35, KeyCode::Y),
+	(0x36, KeyCode::Key6), (0x3A, KeyCode::M), (0x3B, KeyCode::J),
+	(0x3C, KeyCode::U), (0x3D, KeyCode::Key7), (0x3E, KeyCode::Key8),
+	(0x41, KeyCode::Comma), (0x42, KeyCode::K), (0x43, KeyCode::I),
+	(0x44, KeyCode::O), (0x45, KeyCode::Key0), (0x46, KeyCode::Key9),
+	(0x49, KeyCode::Fullstop), (0x4A, KeyCode::Slash), (0x4B, KeyCode::L),
+	(0x4C, KeyCode::SemiColon), (0x4D, KeyCode::P), (0x4E, KeyCode::Minus),
+	(0x52, KeyCode::Quote), (0x54, KeyCode::BracketSquareLeft), (0x55, KeyCode::Equals),
+	(0x58, KeyCode::CapsLock), (0x59, KeyCode::ShiftRight), (0x5A, KeyCode::Enter),
+	(0x5B, KeyCode::BracketSquareRight), (0x5D, KeyCode::BackSlash), (0x61, KeyCode::BackSlash),
+	(0x66, KeyCode::Backspace), (0x69, KeyCode::Numpad1), (0x6B, KeyCode::Numpad4),
+	(0x6C, KeyCode::Numpad7), (0x70, KeyCode::Numpad0), (0x71, KeyCode::NumpadPeriod),
+	(0x72, KeyCode::Numpad2), (0x73, KeyCode::Numpad5), (0x74, KeyCode::Numpad6),
+	(0x75, KeyCode::Numpad8), (0x76, KeyCode::Escape), (0x77, KeyCode::NumpadLock),
+	(0x78, KeyCode::F11), (0x79, KeyCode::NumpadPlus), (0x7A, KeyCode::Numpad3),
+	(0x7B, KeyCode::NumpadMinus), (0x7C, KeyCode::NumpadStar), (0x7D, KeyCode::Numpad9),
+	(0x7E, KeyCode::ScrollLock), (0x83, KeyCode::F7),
+];
+
+/// Which key each extended (`E0`) scan-code comes from. The "fake shift"
+/// codes some keys send along with their own are left out.
+#[rustfmt::skip]
+const EXTENDED_KEY_CODES: [(u8, KeyCode); 17] = [
+	(0x11, KeyCode::AltRight), (0x14, KeyCode::ControlRight), (0x1F, KeyCode::WindowsLeft),
+	(0x27, KeyCode::WindowsRight), (0x2F, KeyCode::Menus), (0x4A, KeyCode::NumpadSlash),
+	(0x5A, KeyCode::NumpadEnter), (0x69, KeyCode::End), (0x6B, KeyCode::ArrowLeft),
+	(0x6C, KeyCode::Home), (0x70, KeyCode::Insert), (0x71, KeyCode::Delete),
+	(0x72, KeyCode::ArrowDown), (0x74, KeyCode::ArrowRight), (0x75, KeyCode::ArrowUp),
+	(0x7A, KeyCode::PageDown), (0x7D, KeyCode::PageUp),
+];
+
+/// The key codes for the letters `a` to `z`.
+#[rustfmt::skip]
+const LETTER_KEY_CODES: [KeyCode; 26] = [
+	KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F,
+	KeyCode::G, KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L,
+	KeyCode::M, KeyCode::N, KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R,
+	KeyCode::S, KeyCode::T, KeyCode::U, KeyCode::V, KeyCode::W, KeyCode::X,
+	KeyCode::Y, KeyCode::Z,
+];
+
+/// The US English layout.
+#[rustfmt::skip]
+const QWERTY: [KeyMapping; 48] = [
+	(0x0E, '`', '~'), (0x16, '1', '!'), (0x1E, '2', '@'), (0x26, '3', '#'),
+	(0x25, '4', '$'), (0x2E, '5', '%'), (0x36, '6', '^'), (0x3D, '7', '&'),
+	(0x3E, '8', '*'), (0x46, '9', '('), (0x45, '0', ')'), (0x4E, '-', '_'),
+	(0x55, '=', '+'), (0x15, 'q', 'Q'), (0x1D, 'w', 'W'), (0x24, 'e', 'E'),
+	(0x2D, 'r', 'R'), (0x2C, 't', 'T'), (0x35, 'y', 'Y'), (0x3C, 'u', 'U'),
+	(0x43, 'i', 'I'), (0x44, 'o', 'O'), (0x4D, 'p', 'P'), (0x54, '[', '{'),
+	(0x5B, ']', '}'), (0x5D, '\\', '|'), (0x1C, 'a', 'A'), (0x1B, 's', 'S'),
+	(0x23, 'd', 'D'), (0x2B, 'f', 'F'), (0x34, 'g', 'G'), (0x33, 'h', 'H'),
+	(0x3B, 'j', 'J'), (0x42, 'k', 'K'), (0x4B, 'l', 'L'), (0x4C, ';', ':'),
+	(0x52, '\'', '"'), (0x1A, 'z', 'Z'), (0x22, 'x', 'X'), (0x21, 'c', 'C'),
+	(0x2A, 'v', 'V'), (0x32, 'b', 'B'), (0x31, 'n', 'N'), (0x3A, 'm', 'M'),
+	(0x41, ',', '<'), (0x49, '.', '>'), (0x4A, '/', '?'), (0x61, '\\', '|'),
+];
+
+/// The UK English layout.
+#[rustfmt::skip]
+const UK: [KeyMapping; 48] = [
+	(0x0E, '`', '¬'), (0x16, '1', '!'), (0x1E, '2', '"'), (0x26, '3', '£'),
+	(0x25, '4', '$'), (0x2E, '5', '%'), (0x36, '6', '^'), (0x3D, '7', '&'),
+	(0x3E, '8', '*'), (0x46, '9', '('), (0x45, '0', ')'), (0x4E, '-', '_'),
+	(0x55, '=', '+'), (0x15, 'q', 'Q'), (0x1D, 'w', 'W'), (0x24, 'e', 'E'),
+	(0x2D, 'r', 'R'), (0x2C, 't', 'T'), (0x35, 'y', 'Y'), (0x3C, 'u', 'U'),
+	(0x43, 'i', 'I'), (0x44, 'o', 'O'), (0x4D, 'p', 'P'), (0x54, '[', '{'),
+	(0x5B, ']', '}'), (0x5D, '#', '~'), (0x1C, 'a', 'A'), (0x1B, 's', 'S'),
+	(0x23, 'd', 'D'), (0x2B, 'f', 'F'), (0x34, 'g', 'G'), (0x33, 'h', 'H'),
+	(0x3B, 'j', 'J'), (0x42, 'k', 'K'), (0x4B, 'l', 'L'), (0x4C, ';', ':'),
+	(0x52, '\'', '@'), (0x1A, 'z', 'Z'), (0x22, 'x', 'X'), (0x21, 'c', 'C'),
+	(0x2A, 'v', 'V'), (0x32, 'b', 'B'), (0x31, 'n', 'N'), (0x3A, 'm', 'M'),
+	(0x41, ',', '<'), (0x49, '.', '>'), (0x4A, '/', '?'), (0x61, '\\', '|'),
+];
+
+/// The French layout.
+#[rustfmt::skip]
+const AZERTY: [KeyMapping; 48] = [
+	(0x0E, '²', '²'), (0x16, '&', '1'), (0x1E, 'é', '2'), (0x26, '"', '3'),
+	(0x25, '\'', '4'), (0x2E, '(', '5'), (0x36, '-', '6'), (0x3D, 'è', '7'),
+	(0x3E, '_', '8'), (0x46, 'ç', '9'), (0x45, 'à', '0'), (0x4E, ')', '°'),
+	(0x55, '=', '+'), (0x15, 'a', 'A'), (0x1D, 'z', 'Z'), (0x24, 'e', 'E'),
+	(0x2D, 'r', 'R'), (0x2C, 't', 'T'), (0x35, 'y', 'Y'), (0x3C, 'u', 'U'),
+	(0x43, 'i', 'I'), (0x44, 'o', 'O'), (0x4D, 'p', 'P'), (0x54, '^', '¨'),
+	(0x5B, '$', '£'), (0x5D, '*', 'µ'), (0x1C, 'q', 'Q'), (0x1B, 's', 'S'),
+	(0x23, 'd', 'D'), (0x2B, 'f', 'F'), (0x34, 'g', 'G'), (0x33, 'h', 'H'),
+	(0x3B, 'j', 'J'), (0x42, 'k', 'K'), (0x4B, 'l', 'L'), (0x4C, 'm', 'M'),
+	(0x52, 'ù', '%'), (0x1A, 'w', 'W'), (0x22, 'x', 'X'), (0x21, 'c', 'C'),
+	(0x2A, 'v', 'V'), (0x32, 'b', 'B'), (0x31, 'n', 'N'), (0x3A, ',', '?'),
+	(0x41, ';', '.'), (0x49, ':', '/'), (0x4A, '!', '§'), (0x61, '<', '>'),
+];
+
+/// The German layout.
+#[rustfmt::skip]
+const QWERTZ: [KeyMapping; 48] = [
+	(0x0E, '^', '°'), (0x16, '1', '!'), (0x1E, '2', '"'), (0x26, '3', '§'),
+	(0x25, '4', '$'), (0x2E, '5', '%'), (0x36, '6', '&'), (0x3D, '7', '/'),
+	(0x3E, '8', '('), (0x46, '9', ')'), (0x45, '0', '='), (0x4E, 'ß', '?'),
+	(0x55, '´', '`'), (0x15, 'q', 'Q'), (0x1D, 'w', 'W'), (0x24, 'e', 'E'),
+	(0x2D, 'r', 'R'), (0x2C, 't', 'T'), (0x35, 'z', 'Z'), (0x3C, 'u', 'U'),
+	(0x43, 'i', 'I'), (0x44, 'o', 'O'), (0x4D, 'p', 'P'), (0x54, 'ü', 'Ü'),
+	(0x5B, '+', '*'), (0x5D, '#', '\''), (0x1C, 'a', 'A'), (0x1B, 's', 'S'),
+	(0x23, 'd', 'D'), (0x2B, 'f', 'F'), (0x34, 'g', 'G'), (0x33, 'h', 'H'),
+	(0x3B, 'j', 'J'), (0x42, 'k', 'K'), (0x4B, 'l', 'L'), (0x4C, 'ö', 'Ö'),
+	(0x52, 'ä', 'Ä'), (0x1A, 'y', 'Y'), (0x22, 'x', 'X'), (0x21, 'c', 'C'),
+	(0x2A, 'v', 'V'), (0x32, 'b', 'B'), (0x31, 'n', 'N'), (0x3A, 'm', 'M'),
+	(0x41, ',', ';'), (0x49, '.', ':'), (0x4A, '-', '_'), (0x61, '<', '>'),
+];
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Turn the (non-extended) scan-code of a key being pressed into a
+/// character.
+///
+/// Caps Lock only affects letters. Returns `None` for keys which don't
+/// produce a character (e.g. Shift, or the function keys).
+pub fn translate(layout: Layout, scancode: u8, shift: bool, caps_lock: bool) -> Option<char> {
+	if let Some((_, ch)) = COMMON_KEYS.iter().find(|(code, _)| *code == scancode) {
+		return Some(*ch);
+	}
+	let (unshifted, shifted) = layout_mapping(layout, scancode)?;
+	// Caps Lock acts like Shift on letters, and Shift undoes it
+	let upper = if unshifted.is_alphabetic() {
+		shift != caps_lock
+	} else {
+		shift
+	};
+	Some(if upper { shifted } else { unshifted })
+}
+
+/// Turn the scan-code of a key (pressed or released) into its HID key
+/// code.
+///
+/// Letter keys are named for the letter the layout puts on them; anything
+/// else by where it is. Returns `None` for scan-codes we don't know.
+pub fn key_code(layout: Layout, scancode: u8, extended: bool) -> Option<KeyCode> {
+	let table: &[(u8, KeyCode)] = if extended {
+		&EXTENDED_KEY_CODES
+	} else {
+		if let Some((unshifted, _)) = layout_mapping(layout, scancode) {
+			if unshifted.is_ascii_lowercase() {
+				return Some(LETTER_KEY_CODES[usize::from(unshifted as u8 - b'a')]);
+			}
+		}
+		&KEY_CODES
+	};
+	table
+		.iter()
+		.find(|(code, _)| *code == scancode)
+		.map(|(_, key)| *key)
+}
+
+/// Find what a key types on the given layout: (unshifted, shifted).
+fn layout_mapping(layout: Layout, scancode: u8) -> Option<(char, char)> {
+	let table: &[KeyMapping] = match layout {
+		Layout::Qwerty => &QWERTY,
+		Layout::Azerty => &AZERTY,
+		Layout::Qwertz => &QWERTZ,
+		Layout::Uk => &UK,
+	};
+	table
+		.iter()
+		.find(|(code, _, _)| *code == scancode)
+		.map(|(_, unshifted, shifted)| (*unshifted, *shifted))
+}
+
+impl Layout {
+	/// Convert from the value stored in the configuration.
+	pub const fn from_u8(value: u8) -> Option<Layout> {
+		match value {
+			0 => Some(Layout::Qwerty),
+			1 => Some(Layout::Azerty),
+			2 => Some(Layout::Qwertz),
+			3 => Some(Layout::Uk),
+			_ => None,
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
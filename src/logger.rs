@@ -0,0 +1,397 @@
+//! # Log output for the Neotron Pico
+//!
+//! This is our `defmt` global logger. Every log frame goes to the debugger
+//! over RTT (just as `defmt-rtt` did), and can also be copied to:
+//!
+//! * The serial port (`MIRROR_SERIAL`). These are the raw `defmt` frames,
+//!   not text - capture them and decode them on a PC with `defmt-print -e
+//!   <bios elf file>`.
+//! * The bottom row of the screen (`MIRROR_SCREEN`), while the BIOS owns
+//!   it. `defmt` only sends the number of each message (the text lives in
+//!   the ELF file, not in flash), so we can't show the text. Instead the row
+//!   shows a message count and the bytes of the latest frame in hex, which
+//!   is enough to see how far the boot got, and to look the message up
+//!   later.
+//!
+//! Which copies are made is set by the `log_mirror` configuration field.
+//! Only Core 0 logs anything.
+//!
+//! We also keep encoded frames in RAM, so the OS can fetch them with
+//! `debug_log_read` (e.g. for a `dmesg` command to save to disk, or pass on
+//! to a PC to decode). Everything logged from power-on until we jump to the
+//! OS goes into the boot log, which is never overwritten (up to
+//! `BOOT_LOG_LEN` bytes). After that, frames go into a ring of the most
+//! recent `RECENT_LEN` bytes. Once the ring has wrapped, the oldest frame in
+//! it is cut off at the front; `defmt-print` skips ahead to the next zero
+//! byte, which ends each frame.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{serial, vga};
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Our `defmt` global logger.
+#[defmt::global_logger]
+struct Logger;
+
+/// The SEGGER RTT control block, which the debugger finds by searching RAM
+/// for its ID.
+#[repr(C)]
+struct RttHeader {
+	id: [u8; 16],
+	max_up_channels: usize,
+	max_down_channels: usize,
+	up_channel: RttChannel,
+}
+
+/// An RTT channel, from us to the debugger.
+#[repr(C)]
+struct RttChannel {
+	name: *const u8,
+	buffer: *mut u8,
+	size: usize,
+	/// Where we write next. Only we change this.
+	write: AtomicUsize,
+	/// Where the debugger reads next. Only the debugger changes this.
+	read: AtomicUsize,
+	/// The mode, which the debugger may change
+	flags: AtomicUsize,
+}
+
+/// Collects the debug row text.
+struct RowWriter {
+	text: [u8; vga::MAX_TEXT_COLS],
+	used: usize,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// In `log_mirror`: copy log frames to the serial port
+pub const MIRROR_SERIAL: u8 = 1 << 0;
+
+/// In `log_mirror`: show log frames on the bottom row of the screen
+pub const MIRROR_SCREEN: u8 = 1 << 1;
+
+/// All the `log_mirror` bits we understand
+pub const MIRROR_ALL: u8 = MIRROR_SERIAL | MIRROR_SCREEN;
+
+/// How big the RTT buffer is
+const RTT_BUFFER_LEN: usize = 1024;
+
+/// RTT mode: if the buffer is full, overwrite the oldest data
+const RTT_MODE_NON_BLOCKING_TRIM: usize = 1;
+
+/// RTT mode: if the buffer is full, wait for the debugger
+const RTT_MODE_BLOCK_IF_FULL: usize = 2;
+
+/// The bits of the RTT flags that hold the mode
+const RTT_MODE_MASK: usize = 0b11;
+
+/// How many bytes of each frame we keep for the debug row
+const FRAME_CAPTURE_LEN: usize = 24;
+
+/// How many bytes of encoded frames from boot we keep
+const BOOT_LOG_LEN: usize = 3072;
+
+/// How many bytes of the most recent encoded frames we keep, once the boot
+/// log is finished
+const RECENT_LEN: usize = 1024;
+
+/// How many bytes of encoded frames we keep in RAM, in all
+pub const HISTORY_LEN: usize = BOOT_LOG_LEN + RECENT_LEN;
+
+/// The colours for the debug row (white on red)
+const ROW_ATTR: vga::Attr = vga::Attr::from_u8(0x4F);
+
+/// The name of our RTT channel, which `probe-run` looks for
+static RTT_NAME: &[u8] = b"defmt\0";
+
+/// Only accessed by the logger (with interrupts off), and by the debugger.
+static mut RTT_BUFFER: [u8; RTT_BUFFER_LEN] = [0u8; RTT_BUFFER_LEN];
+
+/// Our RTT control block.
+///
+/// Only accessed by the logger (with interrupts off), and by the debugger.
+#[no_mangle]
+static mut _SEGGER_RTT: RttHeader = RttHeader {
+	id: *b"SEGGER RTT\0\0\0\0\0\0",
+	max_up_channels: 1,
+	max_down_channels: 0,
+	up_channel: RttChannel {
+		name: RTT_NAME as *const _ as *const u8,
+		buffer: unsafe { &mut RTT_BUFFER as *mut _ as *mut u8 },
+		size: RTT_BUFFER_LEN,
+		write: AtomicUsize::new(0),
+		read: AtomicUsize::new(0),
+		flags: AtomicUsize::new(RTT_MODE_NON_BLOCKING_TRIM),
+	},
+};
+
+/// Which copies we make, as `MIRROR_xxx` bits
+static MIRROR: AtomicU8 = AtomicU8::new(0);
+
+/// Set while a frame is being logged
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Were interrupts on before we took the logger?
+///
+/// Only accessed by the logger, with interrupts off.
+static mut INTERRUPTS_WERE_ON: bool = false;
+
+/// Turns log frames into the bytes we send.
+///
+/// Only accessed by the logger, with interrupts off.
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+/// The start of the frame being logged, for the debug row.
+///
+/// Only accessed by the logger, with interrupts off.
+static mut FRAME: [u8; FRAME_CAPTURE_LEN] = [0u8; FRAME_CAPTURE_LEN];
+
+/// How much of `FRAME` has been filled.
+///
+/// Only accessed by the logger, with interrupts off.
+static mut FRAME_LEN: usize = 0;
+
+/// The encoded frames from boot, oldest first.
+///
+/// Only written by the logger, with interrupts off, and each byte only
+/// once.
+static mut BOOT_LOG: [u8; BOOT_LOG_LEN] = [0u8; BOOT_LOG_LEN];
+
+/// How many bytes of `BOOT_LOG` hold something
+///
+/// Only accessed by the logger and `read_history`, with interrupts off.
+static mut BOOT_LOG_USED: usize = 0;
+
+/// Set once the OS is running, so nothing more goes in the boot log
+static BOOT_LOG_FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// The most recent encoded frames since the boot log, as a ring buffer.
+///
+/// Only written by the logger, with interrupts off.
+static mut RECENT: [u8; RECENT_LEN] = [0u8; RECENT_LEN];
+
+/// Where the next byte goes in `RECENT`
+///
+/// Only accessed by the logger and `read_history`, with interrupts off.
+static mut RECENT_HEAD: usize = 0;
+
+/// How many bytes of `RECENT` hold something
+///
+/// Only accessed by the logger and `read_history`, with interrupts off.
+static mut RECENT_USED: usize = 0;
+
+/// How many frames we have logged.
+///
+/// Only accessed by the logger, with interrupts off.
+static mut FRAME_COUNT: u32 = 0;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Choose where log frames are copied to, as `MIRROR_xxx` bits.
+pub fn set_mirror(mirror: u8) {
+	MIRROR.store(mirror & MIRROR_ALL, Ordering::Relaxed);
+}
+
+/// Stop showing log frames on screen, as it now belongs to the OS.
+pub fn stop_screen_mirror() {
+	let mirror = MIRROR.load(Ordering::Relaxed);
+	MIRROR.store(mirror & !MIRROR_SCREEN, Ordering::Relaxed);
+}
+
+/// Stop adding to the boot log, as we're about to jump to the OS.
+///
+/// Later frames only go in the ring of recent frames, so they can't push
+/// the boot log out.
+pub fn freeze_boot_log() {
+	BOOT_LOG_FROZEN.store(true, Ordering::Relaxed);
+}
+
+/// Copy the boot log, then the most recent encoded log frames, into
+/// `buffer`, oldest first.
+///
+/// If the buffer is too small for everything we have, you get the boot log
+/// and then the newest of the recent frames. Returns how many bytes were
+/// copied.
+pub fn read_history(buffer: &mut [u8]) -> usize {
+	// Note (safety): With interrupts off, the logger can't be running.
+	let (boot_used, head, used) =
+		cortex_m::interrupt::free(|_cs| unsafe { (BOOT_LOG_USED, RECENT_HEAD, RECENT_USED) });
+	let boot_len = boot_used.min(buffer.len());
+	let (boot_part, recent_part) = buffer.split_at_mut(boot_len);
+	for (idx, b) in boot_part.iter_mut().enumerate() {
+		// Note (safety): The logger never changes a byte of the boot log
+		// once it has written it.
+		*b = unsafe { core::ptr::read_volatile(&BOOT_LOG[idx]) };
+	}
+	let len = used.min(recent_part.len());
+	let start = (head + RECENT_LEN - len) % RECENT_LEN;
+	// We don't keep interrupts off for the copy, as it would hold up the
+	// video. If an interrupt logs something meanwhile, the oldest bytes we
+	// copy may be overwritten, which looks like a cut-off frame.
+	for (idx, b) in recent_part[0..len].iter_mut().enumerate() {
+		// Note (safety): Reading a `u8` can't tear.
+		*b = unsafe { core::ptr::read_volatile(&RECENT[(start + idx) % RECENT_LEN]) };
+	}
+	boot_len + len
+}
+
+/// Send encoded bytes to everywhere that wants them.
+fn send(bytes: &[u8]) {
+	// Note (safety): Only called by the logger, with interrupts off.
+	unsafe {
+		_SEGGER_RTT.up_channel.write_all(bytes);
+		let frozen = BOOT_LOG_FROZEN.load(Ordering::Relaxed);
+		for &b in bytes {
+			if !frozen && BOOT_LOG_USED < BOOT_LOG_LEN {
+				BOOT_LOG[BOOT_LOG_USED] = b;
+				BOOT_LOG_USED += 1;
+			} else {
+				RECENT[RECENT_HEAD] = b;
+				RECENT_HEAD = (RECENT_HEAD + 1) % RECENT_LEN;
+				RECENT_USED = (RECENT_USED + 1).min(RECENT_LEN);
+			}
+		}
+	}
+	if (MIRROR.load(Ordering::Relaxed) & MIRROR_SERIAL) != 0 {
+		serial::write_blocking(bytes);
+	}
+}
+
+/// Show the frame we just logged on the debug row.
+///
+/// # Safety
+///
+/// Only call this from the logger, with interrupts off.
+unsafe fn show_frame() {
+	let mut row = RowWriter {
+		text: [b' '; vga::MAX_TEXT_COLS],
+		used: 0,
+	};
+	let _ = write!(row, "BIOS log {:5}:", FRAME_COUNT);
+	for b in FRAME[0..FRAME_LEN].iter() {
+		let _ = write!(row, " {:02x}", b);
+	}
+	vga::write_status_row(&row.text[0..row.used], ROW_ATTR);
+}
+
+unsafe impl defmt::Logger for Logger {
+	fn acquire() {
+		let primask = cortex_m::register::primask::read();
+		cortex_m::interrupt::disable();
+		if TAKEN.load(Ordering::Relaxed) {
+			panic!("defmt logger taken reentrantly");
+		}
+		TAKEN.store(true, Ordering::Relaxed);
+		// Note (safety): Interrupts are off, and we hold the logger.
+		unsafe {
+			INTERRUPTS_WERE_ON = primask.is_active();
+			FRAME_LEN = 0;
+			ENCODER.start_frame(send);
+		}
+	}
+
+	unsafe fn flush() {}
+
+	unsafe fn release() {
+		ENCODER.end_frame(send);
+		FRAME_COUNT = FRAME_COUNT.wrapping_add(1);
+		if (MIRROR.load(Ordering::Relaxed) & MIRROR_SCREEN) != 0 {
+			show_frame();
+		}
+		TAKEN.store(false, Ordering::Relaxed);
+		if INTERRUPTS_WERE_ON {
+			cortex_m::interrupt::enable();
+		}
+	}
+
+	unsafe fn write(bytes: &[u8]) {
+		let space = FRAME_CAPTURE_LEN - FRAME_LEN;
+		let captured = bytes.len().min(space);
+		FRAME[FRAME_LEN..FRAME_LEN + captured].copy_from_slice(&bytes[0..captured]);
+		FRAME_LEN += captured;
+		ENCODER.write(bytes, send);
+	}
+}
+
+impl RttChannel {
+	/// Copy bytes into the buffer for the debugger.
+	///
+	/// If the debugger has asked us to, we wait for room. Otherwise we
+	/// overwrite whatever it hasn't read yet, so we never hang with no
+	/// debugger attached.
+	fn write_all(&self, mut bytes: &[u8]) {
+		let blocking =
+			(self.flags.load(Ordering::Relaxed) & RTT_MODE_MASK) == RTT_MODE_BLOCK_IF_FULL;
+		while !bytes.is_empty() {
+			let write = self.write.load(Ordering::Relaxed);
+			// How much we can write before we reach the end of the buffer
+			// (or catch up with the debugger)
+			let space = if !blocking {
+				self.size - write
+			} else {
+				let read = self.read.load(Ordering::Relaxed);
+				if read > write {
+					read - write - 1
+				} else if read == 0 {
+					self.size - write - 1
+				} else {
+					self.size - write
+				}
+			};
+			let len = bytes.len().min(space);
+			// Note (safety): `write + len` is inside the buffer.
+			unsafe {
+				core::ptr::copy_nonoverlapping(bytes.as_ptr(), self.buffer.add(write), len);
+			}
+			self.write
+				.store((write + len) % self.size, Ordering::Release);
+			bytes = &bytes[len..];
+		}
+	}
+}
+
+impl core::fmt::Write for RowWriter {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		let space = self.text.len() - self.used;
+		let len = s.len().min(space);
+		self.text[self.used..self.used + len].copy_from_slice(&s.as_bytes()[0..len]);
+		self.used += len;
+		Ok(())
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
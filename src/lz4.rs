@@ -0,0 +1,125 @@
+//! # LZ4 decompression for the Neotron Pico
+//!
+//! With the `compressed-os` feature, the OS image in flash is stored as one
+//! LZ4 block (not the LZ4 frame format - there's no frame header or
+//! checksum, as the OS image header does that job) and we unpack it into the
+//! OS RAM before we boot it.
+//!
+//! A block is a series of sequences. Each one is a token byte, some literal
+//! bytes to copy, and then a match - an offset back into what we've already
+//! written, and a length to copy from there. The last sequence has no match.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which decompression can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The compressed data stops part-way through a sequence
+	Truncated,
+	/// A match points back before the start of the output
+	BadOffset,
+	/// The output doesn't fit in the buffer
+	OutputFull,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// A length field of this value means more length bytes follow
+const MORE_LENGTH: usize = 15;
+
+/// The shortest match, which a match length of zero means
+const MIN_MATCH: usize = 4;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Unpack an LZ4 block into `output`, returning how many bytes were written.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+	let mut in_pos = 0;
+	let mut out_pos = 0;
+	while in_pos < input.len() {
+		let token = usize::from(input[in_pos]);
+		in_pos += 1;
+
+		// Copy the literals
+		let literal_len = read_length(input, &mut in_pos, token >> 4)?;
+		let literals = input
+			.get(in_pos..in_pos + literal_len)
+			.ok_or(Error::Truncated)?;
+		output
+			.get_mut(out_pos..out_pos + literal_len)
+			.ok_or(Error::OutputFull)?
+			.copy_from_slice(literals);
+		in_pos += literal_len;
+		out_pos += literal_len;
+
+		// The last sequence stops after its literals
+		if in_pos == input.len() {
+			break;
+		}
+
+		// Copy the match, a byte at a time as it may overlap itself
+		let offset = input
+			.get(in_pos..in_pos + 2)
+			.map(|bytes| usize::from(u16::from_le_bytes([bytes[0], bytes[1]])))
+			.ok_or(Error::Truncated)?;
+		in_pos += 2;
+		if offset == 0 || offset > out_pos {
+			return Err(Error::BadOffset);
+		}
+		let match_len = read_length(input, &mut in_pos, token & 0x0F)? + MIN_MATCH;
+		if out_pos + match_len > output.len() {
+			return Err(Error::OutputFull);
+		}
+		for idx in out_pos..out_pos + match_len {
+			output[idx] = output[idx - offset];
+		}
+		out_pos += match_len;
+	}
+	Ok(out_pos)
+}
+
+/// Read a length, which starts with the given four bits of the token. If
+/// they are all ones, more bytes follow, until one isn't 255.
+fn read_length(input: &[u8], in_pos: &mut usize, nibble: usize) -> Result<usize, Error> {
+	let mut length = nibble;
+	if nibble == MORE_LENGTH {
+		loop {
+			let byte = *input.get(*in_pos).ok_or(Error::Truncated)?;
+			*in_pos += 1;
+			length += usize::from(byte);
+			if byte != 255 {
+				break;
+			}
+		}
+	}
+	Ok(length)
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,251 @@
+//! # Core-to-core mailbox for the Neotron Pico
+//!
+//! Once Core 1 is running, the SIO FIFOs between the two cores are free.
+//! This module uses them to pass 32-bit messages between the OS on Core 0
+//! and whatever the OS wants to run on Core 1, without the two having to
+//! agree on any shared statics.
+//!
+//! * Core 0 to Core 1: The OS calls `send`, which puts the message in the
+//!   FIFO (which holds eight messages). Core 1 checks the FIFO between
+//!   scan-lines and passes each message to the handler the OS registered
+//!   with `set_handler`. With no handler, messages are dropped.
+//! * Core 1 to Core 0: The handler (or anything else on Core 1) calls
+//!   `reply`. The SIO interrupt on Core 0 moves the message into a queue,
+//!   and the OS collects it with `receive`.
+//!
+//! The handler runs on Core 1 in between drawing scan-lines, so it must be
+//! short or the picture will break up.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
+use defmt::debug;
+use heapless::spsc::{Consumer, Producer, Queue};
+use rp_pico::hal::{self, pac};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// A function the OS wants Core 1 to call with each message.
+pub type Handler = extern "C" fn(message: u32);
+
+/// The ways in which sending a message can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// `init` hasn't been called
+	NotReady,
+	/// The FIFO is full - the other core isn't keeping up
+	Full,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How many messages from Core 1 we can hold (one less than this, in fact).
+const QUEUE_LEN: usize = 16;
+
+/// Our end of the FIFOs.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut FIFO: Option<hal::sio::SioFifo> = None;
+
+/// Messages from Core 1, waiting for the OS to collect them.
+///
+/// Only accessed through `RX_PRODUCER` and `RX_CONSUMER`.
+static mut RX_QUEUE: Queue<u32, QUEUE_LEN> = Queue::new();
+
+/// The writing end of `RX_QUEUE`.
+///
+/// Only accessed from the SIO interrupt on Core 0 (once `init` has finished).
+static mut RX_PRODUCER: Option<Producer<'static, u32, QUEUE_LEN>> = None;
+
+/// The reading end of `RX_QUEUE`.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut RX_CONSUMER: Option<Consumer<'static, u32, QUEUE_LEN>> = None;
+
+/// The OS's `Handler`, or null if there isn't one.
+///
+/// Written by Core 0, read by Core 1.
+static HANDLER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Set once `init` has finished, so Core 1 knows it can use the FIFO.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// How many messages have been lost because a queue or FIFO was full.
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Take over the SIO FIFOs, once Core 1 has been started.
+pub fn init(mut fifo: hal::sio::SioFifo) {
+	// Anything left over from starting Core 1 is meaningless
+	fifo.drain();
+	// Note (safety): Only called from Core 0, before the SIO interrupt is
+	// enabled.
+	unsafe {
+		FIFO = Some(fifo);
+		let (producer, consumer) = RX_QUEUE.split();
+		RX_PRODUCER = Some(producer);
+		RX_CONSUMER = Some(consumer);
+		clear_errors();
+		pac::NVIC::unmask(pac::Interrupt::SIO_IRQ_PROC0);
+	}
+	READY.store(true, Ordering::Release);
+	debug!("Mailbox OK");
+}
+
+/// Send a message to Core 1.
+///
+/// Doesn't wait - if the FIFO is full, you get `Error::Full` and can try
+/// again later.
+pub fn send(message: u32) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let fifo = unsafe { FIFO.as_mut() }.ok_or(Error::NotReady)?;
+	if !fifo.is_write_ready() {
+		return Err(Error::Full);
+	}
+	fifo.write(message);
+	// Wake Core 1, in case it is waiting for an event
+	cortex_m::asm::sev();
+	Ok(())
+}
+
+/// Collect the next message from Core 1, if any.
+pub fn receive() -> Option<u32> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { RX_CONSUMER.as_mut() }.and_then(|consumer| consumer.dequeue())
+}
+
+/// Choose the function Core 1 calls with each message. `None` means
+/// messages are dropped.
+pub fn set_handler(handler: Option<Handler>) {
+	let ptr = match handler {
+		Some(handler) => handler as *mut (),
+		None => core::ptr::null_mut(),
+	};
+	HANDLER.store(ptr, Ordering::Release);
+}
+
+/// How many messages have been lost because a queue or FIFO was full.
+pub fn dropped() -> u32 {
+	DROPPED.load(Ordering::Relaxed)
+}
+
+/// Send a message from Core 1 to Core 0.
+///
+/// Returns false (and drops the message) if the FIFO is full. Must only be
+/// called on Core 1.
+pub fn reply(message: u32) -> bool {
+	if !READY.load(Ordering::Acquire) {
+		return false;
+	}
+	// Note (safety): Each core sees its own end of the FIFOs at the same
+	// address, and only Core 1 calls this.
+	let sio = unsafe { &*pac::SIO::ptr() };
+	if sio.fifo_st.read().rdy().bit_is_clear() {
+		DROPPED.store(
+			DROPPED.load(Ordering::Relaxed).wrapping_add(1),
+			Ordering::Relaxed,
+		);
+		return false;
+	}
+	// Note (safety): Any value is a valid message.
+	sio.fifo_wr.write(|w| unsafe { w.bits(message) });
+	true
+}
+
+/// Called by Core 1 in between scan-lines. Passes any messages from Core 0
+/// to the OS's handler.
+///
+/// Must only be called on Core 1.
+pub fn core1_poll() {
+	if !READY.load(Ordering::Acquire) {
+		return;
+	}
+	// Note (safety): Each core sees its own end of the FIFOs at the same
+	// address, and only Core 1 calls this.
+	let sio = unsafe { &*pac::SIO::ptr() };
+	while sio.fifo_st.read().vld().bit_is_set() {
+		let message = sio.fifo_rd.read().bits();
+		let handler = HANDLER.load(Ordering::Acquire);
+		if handler.is_null() {
+			DROPPED.store(
+				DROPPED.load(Ordering::Relaxed).wrapping_add(1),
+				Ordering::Relaxed,
+			);
+		} else {
+			// Note (safety): Only `set_handler` stores a non-null value
+			// here, and it is always a `Handler`.
+			let handler: Handler = unsafe { core::mem::transmute(handler) };
+			handler(message);
+		}
+	}
+}
+
+/// Called from the SIO interrupt on Core 0. Moves messages from Core 1 into
+/// our queue.
+///
+/// If the queue is full, the message is dropped.
+///
+/// # Safety
+///
+/// Only call this from the SIO_IRQ_PROC0 interrupt handler.
+pub unsafe fn irq() {
+	let sio = &*pac::SIO::ptr();
+	let mut producer = RX_PRODUCER.as_mut();
+	while sio.fifo_st.read().vld().bit_is_set() {
+		let message = sio.fifo_rd.read().bits();
+		let stored = match producer {
+			Some(ref mut producer) => producer.enqueue(message).is_ok(),
+			None => false,
+		};
+		if !stored {
+			DROPPED.store(
+				DROPPED.load(Ordering::Relaxed).wrapping_add(1),
+				Ordering::Relaxed,
+			);
+		}
+	}
+	clear_errors();
+}
+
+/// Clear the FIFO overflow/underflow flags, which also raise the interrupt.
+///
+/// # Safety
+///
+/// Only call this on Core 0.
+unsafe fn clear_errors() {
+	let sio = &*pac::SIO::ptr();
+	// Writing any value clears both error flags
+	sio.fifo_st.write(|w| w.bits(0xFF));
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
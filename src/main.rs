@@ -37,7 +37,47 @@
 // Sub-modules
 // -----------------------------------------------------------------------------
 
+pub mod audio;
+pub mod beeper;
+pub mod block_cache;
+pub mod bmc;
+pub mod board;
+pub mod boot_error;
+pub mod bootslot;
+pub mod buttons;
+pub mod config;
+pub mod console;
+pub mod crash_screen;
+pub mod crashlog;
+pub mod fatboot;
+pub mod flash;
+pub mod hid;
+pub mod keymap;
+pub mod logger;
+pub mod lz4;
+pub mod mailbox;
+pub mod memdma;
+pub mod mpu;
+pub mod neobus;
+pub mod os_image;
+pub mod paddles;
+pub mod post;
+pub mod psram;
+pub mod pwm;
+pub mod random;
+pub mod romdisk;
+pub mod rtc;
+pub mod sdcard;
+pub mod serial;
+pub mod setup;
+pub mod slot_irq;
+pub mod sysclock;
+pub mod sysdesc;
+pub mod usb;
+pub mod usb_storage;
 pub mod vga;
+pub mod watchdog;
+pub mod ymodem;
 
 // -----------------------------------------------------------------------------
 // Imports
@@ -45,13 +85,13 @@ pub mod vga;
 
 use common::MemoryRegion;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
 use cortex_m_rt::entry;
-use defmt::info;
-use defmt_rtt as _;
+use defmt::{info, warn};
+use embedded_hal::blocking::spi::{Transfer as _, Write as _};
 use embedded_hal::digital::v2::OutputPin;
 use embedded_time::rate::*;
 use neotron_common_bios as common;
-use panic_probe as _;
 use rp_pico::{
 	self,
 	hal::{
@@ -65,7 +105,289 @@ use rp_pico::{
 // Types
 // -----------------------------------------------------------------------------
 
-// None
+/// Describes exactly how this BIOS was built.
+///
+/// All the strings are null-terminated, like the BIOS version string.
+#[repr(C)]
+pub struct BuildInfo {
+	/// The BIOS version, as given by `bios_version_get`
+	pub version: common::ApiString<'static>,
+	/// The git commit the BIOS was built from (or `unknown`)
+	pub git_hash: common::ApiString<'static>,
+	/// When the BIOS was built, in ISO 8601 format (UTC)
+	pub build_time: common::ApiString<'static>,
+	/// The Cargo features that were enabled, separated by commas
+	pub features: common::ApiString<'static>,
+	/// The board this BIOS was built for
+	pub board: common::ApiString<'static>,
+}
+
+/// Identifies this particular Neotron Pico.
+#[repr(C)]
+pub struct SystemId {
+	/// The flash chip's 64-bit unique ID, which serves as a serial number
+	pub unique_id: u64,
+	/// Which revision of the board this is (0 for 1.0, 1 for 1.1)
+	pub board_revision: u8,
+}
+
+/// Extra BIOS calls that the Neotron Common BIOS API doesn't have a slot for
+/// (yet).
+///
+/// This table lives at the start of the `FLASH_EXT_API` region (see
+/// `memory.x`), so the OS can find it without being told. Check `magic`
+/// before using it, and use `size` to work out which calls this BIOS
+/// provides - new calls are only ever added to the end.
+#[repr(C)]
+pub struct ExtApi {
+	/// Always `EXT_API_MAGIC`
+	pub magic: u32,
+	/// The size of this structure, in bytes
+	pub size: u32,
+	/// Get the next PS/2 device attach/detach event, if any.
+	pub hid_get_device_event: extern "C" fn() -> common::Option<hid::DeviceEvent>,
+	/// Find out what is plugged in to the given PS/2 port.
+	pub hid_get_device_kind: extern "C" fn(port: u8) -> common::Option<hid::DeviceKind>,
+	/// Find out exactly how this BIOS was built.
+	pub bios_build_info_get: extern "C" fn() -> BuildInfo,
+	/// Draw an 8x8 icon into a bitmap video mode, with transparency.
+	pub video_blit_icon: unsafe extern "C" fn(
+		x: u16,
+		y: u16,
+		icon: *const vga::Icon,
+		transparent: u8,
+	) -> common::Result<()>,
+	/// Get the next front-panel button event, if any.
+	pub hid_get_button_event: extern "C" fn() -> common::Option<buttons::ButtonEvent>,
+	/// Get one entry from the 256-colour palette.
+	pub video_get_palette: extern "C" fn(index: u8) -> vga::RGBColour,
+	/// Change one or more entries in the 256-colour palette.
+	pub video_set_palette: unsafe extern "C" fn(
+		start: u8,
+		colours: *const vga::RGBColour,
+		count: usize,
+	) -> common::Result<()>,
+	/// Start (or change) one of the spare PWM outputs.
+	pub pwm_configure:
+		extern "C" fn(channel: u8, frequency_hz: u32, duty: u16) -> common::Result<()>,
+	/// Stop one of the spare PWM outputs.
+	pub pwm_disable: extern "C" fn(channel: u8) -> common::Result<()>,
+	/// Tell the BIOS the OS has changed what is on screen.
+	pub video_mark_dirty: extern "C" fn(),
+	/// Load the user-supplied character table for the text console.
+	pub video_set_char_map: extern "C" fn(table: common::ApiByteSlice) -> common::Result<()>,
+	/// Get the next analogue paddle/joystick axis event, if any.
+	pub hid_get_axis_event: extern "C" fn() -> common::Option<paddles::AxisEvent>,
+	/// Stop the screen saver from starting, or start it now.
+	pub video_screen_saver_control: extern "C" fn(control: u8) -> common::Result<()>,
+	/// Find out how many virtual consoles there are in this video mode.
+	pub video_num_consoles: extern "C" fn() -> u8,
+	/// Get the text buffer for a virtual console.
+	pub video_get_console_buffer: extern "C" fn(index: u8) -> *mut u8,
+	/// Choose which virtual console is on screen.
+	pub video_set_active_console: extern "C" fn(index: u8) -> common::Result<()>,
+	/// Find out which virtual console is on screen.
+	pub video_get_active_console: extern "C" fn() -> u8,
+	/// Send a message to Core 1.
+	pub core1_send: extern "C" fn(message: u32) -> common::Result<()>,
+	/// Collect the next message from Core 1, if any.
+	pub core1_receive: extern "C" fn() -> common::Option<u32>,
+	/// Choose the function Core 1 calls with each message.
+	pub core1_set_handler: extern "C" fn(handler: Option<mailbox::Handler>),
+	/// Send a message from Core 1 back to Core 0 (only call this on Core 1).
+	pub core1_reply: extern "C" fn(message: u32) -> bool,
+	/// Find out which part of flash the OS may erase and program.
+	pub flash_get_data_region: extern "C" fn() -> flash::Region,
+	/// Read the flash chip's 64-bit unique ID.
+	pub flash_get_unique_id: extern "C" fn() -> common::Result<u64>,
+	/// Read bytes from flash.
+	pub flash_read: extern "C" fn(offset: u32, buffer: common::ApiBuffer) -> common::Result<()>,
+	/// Erase one 4 KiB sector of the flash data region.
+	pub flash_erase_sector: extern "C" fn(offset: u32) -> common::Result<()>,
+	/// Program one 256 byte page of the flash data region.
+	pub flash_program_page:
+		extern "C" fn(offset: u32, data: common::ApiByteSlice) -> common::Result<()>,
+	/// Get the next character typed on the PS/2 keyboard, using the BIOS
+	/// keyboard layout.
+	pub hid_get_char: extern "C" fn() -> common::Option<u32>,
+	/// Find out how far the mouse scroll wheel has moved since the last call.
+	pub hid_get_wheel_delta: extern "C" fn() -> i16,
+	/// Start (or change) the audio output.
+	pub audio_output_set_config:
+		extern "C" fn(sample_rate_hz: u32, format: u8) -> common::Result<()>,
+	/// Queue up some samples to play.
+	pub audio_output_data: extern "C" fn(data: common::ApiByteSlice) -> common::Result<usize>,
+	/// Find out how many bytes of samples can be queued right now.
+	pub audio_output_get_space: extern "C" fn() -> common::Result<usize>,
+	/// Move the text cursor.
+	pub video_set_cursor_position: extern "C" fn(col: u16, row: u16),
+	/// Change the shape of the text cursor, or hide it.
+	pub video_set_cursor_shape:
+		extern "C" fn(start_line: u8, end_line: u8, flags: u8) -> common::Result<()>,
+	/// Replace the font used by the 8x8 or 8x16 text modes.
+	pub video_set_font: unsafe extern "C" fn(height: u8, data: *const u8) -> common::Result<()>,
+	/// Find out why the system last reset.
+	pub reset_reason_get: extern "C" fn() -> u8,
+	/// Find out how many times the system has crashed since power-on.
+	pub reset_crash_count_get: extern "C" fn() -> u16,
+	/// Tell the watchdog the OS hasn't locked up (starting it, the first time).
+	pub watchdog_feed: extern "C" fn(),
+	/// Select an expansion slot on the Neotron Bus (or nothing, with 0).
+	pub bus_select: extern "C" fn(slot: u8) -> common::Result<()>,
+	/// Write to, then read from, the selected expansion slot.
+	pub bus_write_read: extern "C" fn(
+		tx: common::ApiByteSlice,
+		tx2: common::ApiByteSlice,
+		rx: common::ApiBuffer,
+	) -> common::Result<()>,
+	/// Exchange bytes with the selected expansion slot.
+	pub bus_exchange: extern "C" fn(buffer: common::ApiBuffer) -> common::Result<()>,
+	/// Find out which expansion slots have asked for attention.
+	pub bus_interrupt_status: extern "C" fn() -> u32,
+	/// Set a function to be called when an expansion slot asks for attention.
+	pub bus_set_interrupt_callback: extern "C" fn(callback: Option<slot_irq::Callback>),
+	/// Put a virtual console (or page of text) on screen at the next frame.
+	pub video_flip_console: extern "C" fn(index: u8) -> common::Result<()>,
+	/// Find out if a flip is still waiting for the next frame.
+	pub video_is_flip_pending: extern "C" fn() -> bool,
+	/// Choose a scan-line for `video_wait_for_raster` to wait for.
+	pub video_set_raster_line: extern "C" fn(line: u16) -> common::Result<()>,
+	/// Sleep until the beam reaches the chosen scan-line.
+	pub video_wait_for_raster: extern "C" fn() -> common::Result<()>,
+	/// Sleep until the next vertical blanking interval starts.
+	pub video_wait_for_vblank: extern "C" fn() -> u32,
+	/// Find out how many frames have been sent to the monitor.
+	pub video_get_frame_count: extern "C" fn() -> u32,
+	/// Scroll the text modes by a number of scan-lines.
+	pub video_set_scroll_offset: extern "C" fn(lines: u16),
+	/// Find out how far the text modes are scrolled.
+	pub video_get_scroll_offset: extern "C" fn() -> u16,
+	/// Give a sprite its image.
+	pub video_sprite_set_image:
+		unsafe extern "C" fn(index: u8, image: *const vga::RGBColour) -> common::Result<()>,
+	/// Move a sprite.
+	pub video_sprite_set_position: extern "C" fn(index: u8, x: i16, y: i16) -> common::Result<()>,
+	/// Show or hide a sprite.
+	pub video_sprite_set_enabled: extern "C" fn(index: u8, enabled: bool) -> common::Result<()>,
+	/// Choose the sprite colour that isn't drawn.
+	pub video_sprite_set_transparent: extern "C" fn(colour: vga::RGBColour),
+	/// Show or hide the BIOS's mouse pointer.
+	pub video_pointer_set_enabled: extern "C" fn(enabled: bool),
+	/// Move the BIOS's mouse pointer.
+	pub video_pointer_set_position: extern "C" fn(x: i16, y: i16),
+	/// Find out where the BIOS's mouse pointer is.
+	pub video_pointer_get_position: extern "C" fn() -> u32,
+	/// Replace one of the two font banks used by the 8x8 or 8x16 text modes.
+	pub video_set_font_bank:
+		unsafe extern "C" fn(bank: u8, height: u8, data: *const u8) -> common::Result<()>,
+	/// Choose between blinking text and bright backgrounds.
+	pub video_set_text_blink: extern "C" fn(blink: bool),
+	/// Reset into the USB bootloader, ready for a new BIOS.
+	pub reset_to_usb_boot: extern "C" fn() -> !,
+	/// Offer the SD card to a PC over USB, or take it back.
+	pub usb_storage_set_shared: extern "C" fn(shared: bool) -> common::Result<()>,
+	/// Start reading sectors from a block device, in the background.
+	pub block_read_async: unsafe extern "C" fn(
+		device: u8,
+		block: u64,
+		num_blocks: u8,
+		data: common::ApiBuffer,
+	) -> common::Result<()>,
+	/// Start writing sectors to a block device, in the background.
+	pub block_write_async: unsafe extern "C" fn(
+		device: u8,
+		block: u64,
+		num_blocks: u8,
+		data: common::ApiByteSlice,
+	) -> common::Result<()>,
+	/// Find out if a background block transfer has finished.
+	pub block_poll: extern "C" fn(device: u8) -> common::Result<bool>,
+	/// Write out anything a block device has cached.
+	pub block_flush: extern "C" fn(device: u8) -> common::Result<()>,
+	/// Choose between write-through and write-back caching on a block device.
+	pub block_set_write_back: extern "C" fn(device: u8, enabled: bool) -> common::Result<()>,
+	/// Play a tone on the beeper, on boards without the audio codec.
+	pub audio_tone: extern "C" fn(frequency_hz: u32, duration_ms: u32) -> common::Result<()>,
+	/// Start (or change) one of the audio mixer channels.
+	pub audio_mixer_set_config: extern "C" fn(
+		channel: u8,
+		sample_rate_hz: u32,
+		format: u8,
+		volume: u8,
+	) -> common::Result<()>,
+	/// Change the volume of an audio mixer channel.
+	pub audio_mixer_set_volume: extern "C" fn(channel: u8, volume: u8) -> common::Result<()>,
+	/// Stop an audio mixer channel.
+	pub audio_mixer_stop: extern "C" fn(channel: u8) -> common::Result<()>,
+	/// Queue up some samples to play on an audio mixer channel.
+	pub audio_mixer_data:
+		extern "C" fn(channel: u8, data: common::ApiByteSlice) -> common::Result<usize>,
+	/// Find out how many bytes `audio_mixer_data` would take right now.
+	pub audio_mixer_get_space: extern "C" fn(channel: u8) -> common::Result<usize>,
+	/// Start (or change) the audio input.
+	pub audio_input_set_config:
+		extern "C" fn(sample_rate_hz: u32, format: u8, source: u8) -> common::Result<()>,
+	/// Take recorded audio samples.
+	pub audio_input_data: extern "C" fn(buffer: common::ApiBuffer) -> common::Result<usize>,
+	/// Find out how many bytes `audio_input_data` could give right now.
+	pub audio_input_get_count: extern "C" fn() -> common::Result<usize>,
+	/// Stop the audio input.
+	pub audio_input_stop: extern "C" fn() -> common::Result<()>,
+	/// Set the master audio output volume.
+	pub audio_output_set_volume: extern "C" fn(volume: u8) -> common::Result<()>,
+	/// Get the master audio output volume.
+	pub audio_output_get_volume: extern "C" fn() -> u8,
+	/// Mute (or unmute) the audio output.
+	pub audio_output_set_mute: extern "C" fn(muted: bool) -> common::Result<()>,
+	/// Are there headphones plugged in?
+	pub audio_headphones_present: extern "C" fn() -> bool,
+	/// Play a tone, on the codec or the beeper, without streaming samples.
+	pub audio_beep: extern "C" fn(frequency_hz: u32, duration_ms: u32) -> common::Result<()>,
+	/// Use custom monitor timings, or go back to the standard ones.
+	pub video_set_timing:
+		unsafe extern "C" fn(mode_line: *const vga::ModeLine) -> common::Result<()>,
+	/// Get one of the BIOS's built-in monitor timings.
+	pub video_get_timing_preset: extern "C" fn(preset: u8) -> common::Option<vga::ModeLine>,
+	/// Turn interlaced drawing on or off.
+	pub video_set_interlace: extern "C" fn(enabled: bool) -> common::Result<()>,
+	/// Blank the screen, or put the monitor into a power saving state.
+	pub video_set_power: extern "C" fn(state: u8) -> common::Result<()>,
+	/// Change palette entries part-way down each frame.
+	pub video_set_palette_changes: unsafe extern "C" fn(
+		changes: *const vga::PaletteChange,
+		count: usize,
+	) -> common::Result<()>,
+	/// Find out how long it took to draw the slowest scan-line last frame.
+	pub video_get_render_time: extern "C" fn() -> u32,
+	/// Get the video performance counters.
+	pub video_get_stats: extern "C" fn() -> vga::VideoStats,
+	/// Find out how big the external PSRAM is (zero if there isn't any).
+	pub psram_get_size: extern "C" fn() -> u32,
+	/// Read bytes from the external PSRAM.
+	pub psram_read: extern "C" fn(offset: u32, buffer: common::ApiBuffer) -> common::Result<()>,
+	/// Write bytes to the external PSRAM.
+	pub psram_write: extern "C" fn(offset: u32, data: common::ApiByteSlice) -> common::Result<()>,
+	/// Start copying memory with DMA, in the background.
+	pub mem_copy_dma: unsafe extern "C" fn(dest: *mut u8, src: *const u8, len: usize),
+	/// Start filling memory with DMA, in the background.
+	pub mem_fill_dma: unsafe extern "C" fn(dest: *mut u8, value: u8, len: usize),
+	/// Find out if a DMA copy or fill is still running.
+	pub mem_dma_busy: extern "C" fn() -> bool,
+	/// Reboot, turn the power off, or reset into the USB bootloader.
+	pub power_control: extern "C" fn(action: u8) -> common::Result<()>,
+	/// Sleep until something happens.
+	pub power_idle: extern "C" fn(),
+	/// Fill a buffer with random bytes, from the hardware entropy source.
+	pub random_get: extern "C" fn(buffer: common::ApiBuffer) -> common::Result<()>,
+	/// Get this unit's serial number and board revision.
+	pub system_id_get: extern "C" fn() -> common::Result<SystemId>,
+	/// Read the BIOS's recent log messages.
+	pub debug_log_read: extern "C" fn(buffer: common::ApiBuffer) -> common::Result<usize>,
+	/// Describe the devices the BIOS found, and where they are.
+	pub system_description_get: extern "C" fn(buffer: common::ApiBuffer) -> common::Result<usize>,
+	/// Do the BIOS's background work (card detect, USB mass storage).
+	pub system_poll: extern "C" fn(),
+}
 
 // -----------------------------------------------------------------------------
 // Static and Const Data
@@ -74,13 +396,67 @@ use rp_pico::{
 /// The BIOS version string
 static BIOS_VERSION: &str = concat!("Neotron Pico BIOS version ", env!("BIOS_VERSION"), "\0");
 
+/// The board this BIOS is built for
+static BOARD_NAME: &str = "Neotron Pico v0.5.0\0";
+
+/// The console the BIOS prints its own messages on
+static CONSOLE: console::Console = console::Console::new();
+
+/// How long the BMC gets to turn the power off, before `power_control`
+/// gives up
+const POWER_OFF_TIMEOUT_MS: u32 = 1000;
+
+/// The baud rate for the serial port, until we've loaded the configuration.
+const DEFAULT_SERIAL_BAUD: u32 = 115_200;
+
+/// How often we check the keyboard during the boot countdown
+const SIGN_ON_POLL_MS: u32 = 10;
+
+/// How often `hid_get_event` does the BIOS's background work. An OS polling
+/// for input in a tight loop would otherwise spend most of its time on it.
+const BACKGROUND_POLL_MS: u32 = 10;
+
+/// When `hid_get_event` last did the BIOS's background work
+static LAST_BACKGROUND_POLL: AtomicU32 = AtomicU32::new(0);
+
+/// How many rows of 16 bytes the recovery console's memory view shows
+const MEMORY_VIEW_ROWS: usize = 16;
+
+/// Where the boot ROM is, and how big it is
+const ROM_BASE: usize = 0x0000_0000;
+const ROM_LEN: usize = 16 * 1024;
+
+/// Where the SRAM is (all six banks), and how big it is
+const SRAM_BASE: usize = 0x2000_0000;
+const SRAM_LEN: usize = 264 * 1024;
+
 /// This is our Operating System. It must be compiled separately.
 ///
 /// The RP2040 requires an OS linked at `0x1002_0000`, which is the OS binary
 /// `flash1002`. Use `objdump` as per the README file to make a `flash1002.bin`.
+///
+/// With the `compressed-os` feature, it is instead an OS linked at
+/// `0x2000_0000` (`ram2000.bin`), compressed by `build.rs`, which we unpack
+/// into the OS RAM.
 #[link_section = ".flash_os"]
 #[used]
-pub static OS_IMAGE: [u8; include_bytes!("flash1002.bin").len()] = *include_bytes!("flash1002.bin");
+pub static OS_IMAGE: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/os_image.bin")).len()] =
+	*include_bytes!(concat!(env!("OUT_DIR"), "/os_image.bin"));
+
+/// The header for our Operating System, so we can check it before we boot it.
+#[link_section = ".flash_os_header"]
+#[used]
+pub static OS_IMAGE_HEADER: os_image::Header = os_image::Header::new(
+	OS_IMAGE.len() as u32,
+	os_image::parse_decimal(env!("OS_IMAGE_CRC32")),
+	if cfg!(feature = "compressed-os") {
+		os_image::Compression::Lz4
+	} else {
+		os_image::Compression::None
+	},
+	os_image::parse_decimal(env!("OS_IMAGE_LOAD_ADDRESS")),
+	os_image::parse_decimal(env!("OS_IMAGE_UNPACKED_LEN")),
+);
 
 /// The table of API calls we provide the OS
 static API_CALLS: common::Api = common::Api {
@@ -110,6 +486,118 @@ static API_CALLS: common::Api = common::Api {
 	block_verify,
 };
 
+/// Identifies a valid `ExtApi` table. Reads "NEXT" in a hex dump.
+const EXT_API_MAGIC: u32 = u32::from_le_bytes(*b"NEXT");
+
+/// The table of extra API calls we provide the OS
+#[link_section = ".flash_ext_api"]
+#[used]
+pub static EXT_API_CALLS: ExtApi = ExtApi {
+	magic: EXT_API_MAGIC,
+	size: core::mem::size_of::<ExtApi>() as u32,
+	hid_get_device_event,
+	hid_get_device_kind,
+	bios_build_info_get,
+	video_blit_icon,
+	hid_get_button_event,
+	video_get_palette,
+	video_set_palette,
+	pwm_configure,
+	pwm_disable,
+	video_mark_dirty,
+	video_set_char_map,
+	hid_get_axis_event,
+	video_screen_saver_control,
+	video_num_consoles,
+	video_get_console_buffer,
+	video_set_active_console,
+	video_get_active_console,
+	core1_send,
+	core1_receive,
+	core1_set_handler,
+	core1_reply,
+	flash_get_data_region,
+	flash_get_unique_id,
+	flash_read,
+	flash_erase_sector,
+	flash_program_page,
+	hid_get_char,
+	hid_get_wheel_delta,
+	audio_output_set_config,
+	audio_output_data,
+	audio_output_get_space,
+	video_set_cursor_position,
+	video_set_cursor_shape,
+	video_set_font,
+	reset_reason_get,
+	reset_crash_count_get,
+	watchdog_feed,
+	bus_select,
+	bus_write_read,
+	bus_exchange,
+	bus_interrupt_status,
+	bus_set_interrupt_callback,
+	video_flip_console,
+	video_is_flip_pending,
+	video_set_raster_line,
+	video_wait_for_raster,
+	video_wait_for_vblank,
+	video_get_frame_count,
+	video_set_scroll_offset,
+	video_get_scroll_offset,
+	video_sprite_set_image,
+	video_sprite_set_position,
+	video_sprite_set_enabled,
+	video_sprite_set_transparent,
+	video_pointer_set_enabled,
+	video_pointer_set_position,
+	video_pointer_get_position,
+	video_set_font_bank,
+	video_set_text_blink,
+	reset_to_usb_boot,
+	usb_storage_set_shared,
+	block_read_async,
+	block_write_async,
+	block_poll,
+	block_flush,
+	block_set_write_back,
+	audio_tone,
+	audio_mixer_set_config,
+	audio_mixer_set_volume,
+	audio_mixer_stop,
+	audio_mixer_data,
+	audio_mixer_get_space,
+	audio_input_set_config,
+	audio_input_data,
+	audio_input_get_count,
+	audio_input_stop,
+	audio_output_set_volume,
+	audio_output_get_volume,
+	audio_output_set_mute,
+	audio_headphones_present,
+	audio_beep,
+	video_set_timing,
+	video_get_timing_preset,
+	video_set_interlace,
+	video_set_power,
+	video_set_palette_changes,
+	video_get_render_time,
+	video_get_stats,
+	psram_get_size,
+	psram_read,
+	psram_write,
+	mem_copy_dma,
+	mem_fill_dma,
+	mem_dma_busy,
+	power_control,
+	power_idle,
+	random_get,
+	system_id_get,
+	debug_log_read,
+	system_description_get,
+	system_poll,
+};
+
 extern "C" {
 	static mut _flash_os_start: u32;
 	static mut _flash_os_len: u32;
@@ -129,48 +617,54 @@ fn main() -> ! {
 
 	// BIOS_VERSION has a trailing `\0` as that is what the BIOS/OS API requires.
 	info!("{} starting...", &BIOS_VERSION[0..BIOS_VERSION.len() - 1]);
+	info!(
+		"Built {} from {} with features [{}]",
+		env!("BIOS_BUILD_TIME"),
+		env!("BIOS_GIT_HASH"),
+		env!("BIOS_FEATURES")
+	);
 
 	// Grab the singleton containing all the RP2040 peripherals
 	let mut pp = pac::Peripherals::take().unwrap();
 	// Grab the singleton containing all the generic Cortex-M peripherals
 	let cp = pac::CorePeripherals::take().unwrap();
 
-	// Reset the DMA engine. If we don't do this, starting from probe-run
-	// (as opposed to a cold-start) is unreliable.
-	reset_dma_engine(&mut pp);
+	// Take the 1 MHz system timer out of reset, so we can measure timeouts
+	start_timer(&mut pp);
+
+	// Find out why we reset, before anything resets us again
+	watchdog::init();
 
 	// Needed by the clock setup
 	let mut watchdog = hal::watchdog::Watchdog::new(pp.WATCHDOG);
 
-	// Run at 126 MHz SYS_PLL, 48 MHz, USB_PLL. This is important, we as clock
-	// the PIO at ÷ 5, to give 25.2 MHz (which is close enough to the 25.175
-	// MHz standard VGA pixel clock).
+	// Run the SYS_PLL at a multiple of 126 MHz or 151.2 MHz, and the USB_PLL
+	// at 48 MHz. This is important, as we clock the PIO at ÷ 5 (or ÷ 6), to
+	// give 25.2 MHz (which is close enough to the 25.175 MHz standard VGA
+	// pixel clock). See `sysclock` for the speeds on offer.
+	let clock_profile = sysclock::select();
 
 	// Step 1. Turn on the crystal.
 	let xosc = hal::xosc::setup_xosc_blocking(pp.XOSC, rp_pico::XOSC_CRYSTAL_FREQ.Hz())
-		.map_err(|_x| false)
-		.unwrap();
+		.unwrap_or_else(|_x| boot_error::fail(boot_error::Stage::Crystal));
 	// Step 2. Configure watchdog tick generation to tick over every microsecond.
 	watchdog.enable_tick_generation((rp_pico::XOSC_CRYSTAL_FREQ / 1_000_000) as u8);
-	// Step 3. Create a clocks manager.
+	// Step 3. Raise the core voltage and slow the flash down, if the clock
+	// profile needs it.
+	sysclock::prepare(clock_profile);
+	// Step 4. Create a clocks manager.
 	let mut clocks = hal::clocks::ClocksManager::new(pp.CLOCKS);
-	// Step 4. Set up the system PLL. We take Crystal Oscillator (=12 MHz),
-	// ×126 (=1512 MHz), ÷6 (=252 MHz), ÷2 (=126 MHz)
+	// Step 5. Set up the system PLL. We take Crystal Oscillator (=12 MHz),
+	// ×126 (=1512 MHz), then divide down (e.g. ÷6 (=252 MHz), ÷2 (=126 MHz))
 	let pll_sys = hal::pll::setup_pll_blocking(
 		pp.PLL_SYS,
 		xosc.operating_frequency().into(),
-		hal::pll::PLLConfig {
-			vco_freq: Megahertz(1512),
-			refdiv: 1,
-			post_div1: 6,
-			post_div2: 2,
-		},
+		clock_profile.pll_config(),
 		&mut clocks,
 		&mut pp.RESETS,
 	)
-	.map_err(|_x| false)
-	.unwrap();
-	// Step 5. Set up a 48 MHz PLL for the USB system.
+	.unwrap_or_else(|_x| boot_error::fail(boot_error::Stage::SysPll));
+	// Step 6. Set up a 48 MHz PLL for the USB system.
 	let pll_usb = hal::pll::setup_pll_blocking(
 		pp.PLL_USB,
 		xosc.operating_frequency().into(),
@@ -178,13 +672,11 @@ fn main() -> ! {
 		&mut clocks,
 		&mut pp.RESETS,
 	)
-	.map_err(|_x| false)
-	.unwrap();
-	// Step 6. Set the system to run from the PLLs we just configured.
+	.unwrap_or_else(|_x| boot_error::fail(boot_error::Stage::UsbPll));
+	// Step 7. Set the system to run from the PLLs we just configured.
 	clocks
 		.init_default(&xosc, &pll_sys, &pll_usb)
-		.map_err(|_x| false)
-		.unwrap();
+		.unwrap_or_else(|_x| boot_error::fail(boot_error::Stage::Clocks));
 
 	info!("Clocks OK");
 
@@ -196,6 +688,10 @@ fn main() -> ! {
 	// Configure and grab all the RP2040 pins the Pico exposes.
 	let pins = rp_pico::Pins::new(pp.IO_BANK0, pp.PADS_BANK0, sio.gpio_bank0, &mut pp.RESETS);
 
+	// Note which pins are wired to what, so nothing else can take them over
+	board::init();
+	info!("Board: {}", board::revision());
+
 	// Disable power save mode to force SMPS into low-efficiency, low-noise mode.
 	let mut b_power_save = pins.b_power_save.into_push_pull_output();
 	b_power_save.set_high().unwrap();
@@ -216,27 +712,409 @@ fn main() -> ! {
 	let _blue2 = pins.gpio12.into_mode::<hal::gpio::FunctionPio0>();
 	let _blue3 = pins.gpio13.into_mode::<hal::gpio::FunctionPio0>();
 
+	// Give CIPO, SCK and COPI to SPI0, to run the Neotron Bus. GPIO17 is
+	// driven by hand, as the chip-select for the I/O expander.
+	let _spi_cipo = pins.gpio16.into_mode::<hal::gpio::FunctionSpi>();
+	let _spi_sclk = pins.gpio18.into_mode::<hal::gpio::FunctionSpi>();
+	let _spi_copi = pins.gpio19.into_mode::<hal::gpio::FunctionSpi>();
+	let iox_cs = pins.gpio17.into_push_pull_output();
+
+	// Give TX and RX to UART1, for the RS-232 header
+	let _uart_tx = pins.gpio20.into_mode::<hal::gpio::FunctionUart>();
+	let _uart_rx = pins.gpio21.into_mode::<hal::gpio::FunctionUart>();
+
+	// Give SDA and SCL to I2C1, for the Real Time Clock
+	let i2c_sda = pins.gpio14.into_mode::<hal::gpio::FunctionI2C>();
+	let i2c_scl = pins.gpio15.into_mode::<hal::gpio::FunctionI2C>();
+
 	info!("Pins OK");
 
-	vga::init(
+	let uart = hal::uart::UartPeripheral::<_, _>::new(pp.UART1, &mut pp.RESETS)
+		.enable(
+			hal::uart::UartConfig {
+				baudrate: DEFAULT_SERIAL_BAUD.Bd(),
+				data_bits: hal::uart::DataBits::Eight,
+				stop_bits: hal::uart::StopBits::One,
+				parity: None,
+			},
+			clocks.peripheral_clock.into(),
+		)
+		.unwrap();
+	serial::init(uart, clocks.peripheral_clock.freq().integer());
+
+	let spi = hal::spi::Spi::<_, _, 8>::new(pp.SPI0).init(
+		&mut pp.RESETS,
+		clocks.peripheral_clock.freq(),
+		neobus::DEFAULT_CLOCK_HZ.Hz(),
+		&embedded_hal::spi::MODE_0,
+	);
+	neobus::init(spi, iox_cs, clocks.peripheral_clock.freq().integer());
+
+	pwm::init(pp.PWM, &mut pp.RESETS, clocks.system_clock.freq().integer());
+	beeper::init(clocks.system_clock.freq().integer());
+	paddles::init(pp.ADC, &mut pp.RESETS);
+
+	let i2c = hal::i2c::I2C::i2c1(
+		pp.I2C1,
+		i2c_sda,
+		i2c_scl,
+		100_000u64.Hz(),
+		&mut pp.RESETS,
+		clocks.system_clock.freq(),
+	);
+	rtc::init(i2c);
+	flash::init();
+	#[cfg(feature = "xip-cache-ram")]
+	flash::use_cache_as_ram();
+	config::load();
+	set_serial_baud(config::get().serial_baud);
+
+	usb::init(hal::usb::UsbBus::new(
+		pp.USBCTRL_REGS,
+		pp.USBCTRL_DPRAM,
+		clocks.usb_clock,
+		true,
+		&mut pp.RESETS,
+	));
+
+	// Video sometimes fails to start after a restart from the debugger. A
+	// reset usually sorts it out, so try that a few times before giving up.
+	if let Err(e) = vga::init(
 		pp.PIO0,
 		pp.DMA,
 		&mut pp.RESETS,
 		&mut pp.PPB,
 		&mut sio.fifo,
 		&mut pp.PSM,
+		clocks.system_clock.freq().integer(),
+	) {
+		boot_error::retry_or_fail(match e {
+			vga::InitError::PioProgram => boot_error::Stage::VideoPio,
+			vga::InitError::NoTimingDma => boot_error::Stage::VideoTiming,
+			vga::InitError::NoPixelDma => boot_error::Stage::VideoPixels,
+			vga::InitError::BadClock => boot_error::Stage::Clocks,
+		});
+	}
+	boot_error::clear_retries();
+
+	// A 15 kHz monitor can't show anything until it gets its own timings
+	if let Some(mode_line) = config::get().video_timing.mode_line() {
+		if let Err(e) = vga::set_custom_timing(Some(mode_line)) {
+			warn!(
+				"Can't use video timing {}: {}",
+				config::get().video_timing,
+				e
+			);
+		}
+	}
+
+	// Core 1 is running, so we can have the FIFOs now
+	mailbox::init(sio.fifo);
+
+	// The video has reset the DMA controller, so we can set up our channels
+	let (pio1, pio1_sm2) = audio::init(
+		pp.PIO1,
+		&mut pp.RESETS,
+		clocks.system_clock.freq().integer(),
 	);
+	psram::init(pio1, pio1_sm2, clocks.system_clock.freq().integer());
+
+	// Check the hardware, before anything is loaded into the OS RAM
+	// Note (safety): Nothing else uses the OS RAM until we load the OS.
+	let post_report = unsafe {
+		post::run(core::slice::from_raw_parts_mut(
+			&mut _ram_os_start as *mut u32,
+			(&mut _ram_os_len as *const u32 as usize) / core::mem::size_of::<u32>(),
+		))
+	};
+	sysdesc::build(&post_report);
+
+	// Find the OS, which might mean copying it off the SD card
+	let (mut os_start, os_source) = find_os();
 
 	// Say hello over VGA (with a bit of a pause)
 	let mut delay = cortex_m::delay::Delay::new(cp.SYST, clocks.system_clock.freq().integer());
-	sign_on(&mut delay);
+	let serial_boot_requested = sign_on(&mut delay, os_source, &post_report);
+
+	// Maybe fetch a new OS over the serial port
+	if serial_boot_requested || config::get().serial_boot {
+		os_start = serial_boot(&mut delay, os_start);
+	}
+
+	// Don't jump into a damaged OS image, and unpack it if it's compressed
+	let flash_os = unsafe { &_flash_os_start as *const u32 };
+	if os_start == flash_os {
+		os_start = loop {
+			match os_image::load() {
+				Ok(start) => break start,
+				Err(e) => recovery_console(&mut delay, e),
+			}
+		};
+	}
+
+	// The screen is the OS's now
+	logger::stop_screen_mirror();
+	logger::freeze_boot_log();
+
+	// Catch the OS scribbling on memory it shouldn't
+	mpu::protect_bios_memory();
 
 	// Now jump to the OS
-	let code: &common::OsStartFn = unsafe { ::core::mem::transmute(&_flash_os_start) };
+	let code: &common::OsStartFn = unsafe { &*(os_start as *const common::OsStartFn) };
 	code(&API_CALLS);
 }
 
-fn sign_on(delay: &mut cortex_m::delay::Delay) {
+/// Work out where the OS is, and say where we found it.
+///
+/// We try `/NEOTRON/KERNEL.BIN` on an SD card first, then the raw boot slots
+/// on a card without a filesystem. Either gets copied into the OS RAM.
+/// Otherwise (or if the configuration says so) we use the OS built in to our
+/// flash. Either way, the first word of the image points to the OS start
+/// function.
+fn find_os() -> (*const u32, &'static str) {
+	let flash_os = unsafe { &_flash_os_start as *const u32 };
+	if config::get().boot_order == config::BootOrder::FlashOnly {
+		return (flash_os, "flash");
+	}
+	// Note (safety): Nothing else uses the OS RAM until the OS starts.
+	let ram = unsafe {
+		core::slice::from_raw_parts_mut(
+			&mut _ram_os_start as *mut u32 as *mut u8,
+			&mut _ram_os_len as *const u32 as usize,
+		)
+	};
+	let read_block =
+		|lba: u64, block: &mut bootslot::Block| sdcard::read_blocks(lba, block).map_err(|_| ());
+	match fatboot::load(read_block, ram) {
+		Ok(_) => return (ram.as_ptr() as *const u32, "SD card"),
+		Err(fatboot::Error::NoFilesystem) => match bootslot::load(read_block, ram) {
+			Ok(header) => {
+				info!("Booting from slot {}", header.slot);
+				return (header.load_address as *const u32, "SD card boot slot");
+			}
+			Err(e) => info!("No OS in boot slots: {}", e),
+		},
+		Err(e) => info!("No OS on SD card: {}", e),
+	}
+	(flash_os, "flash")
+}
+
+/// Run the serial port at the given speed, with 8 data bits, no parity and
+/// one stop bit.
+fn set_serial_baud(baud: u32) {
+	let serial_config = common::serial::Config {
+		data_rate_bps: baud,
+		data_bits: common::serial::DataBits::Eight,
+		stop_bits: common::serial::StopBits::One,
+		parity: common::serial::Parity::None,
+		handshaking: common::serial::Handshaking::None,
+	};
+	if let Err(e) = serial::configure(&serial_config) {
+		warn!("Can't run serial port at {} bps: {}", baud, e);
+	}
+}
+
+/// How the configuration says the boot console should go to the serial port.
+fn serial_mirror_mode() -> console::SerialMirror {
+	if config::get().serial_transcript {
+		console::SerialMirror::Transcript
+	} else {
+		console::SerialMirror::Ansi
+	}
+}
+
+/// Fetch an OS image over the serial port, with YMODEM, and write it to the
+/// OS slot in flash.
+///
+/// The image arrives in the OS RAM, so whatever `find_os` loaded there is
+/// lost. We therefore boot from flash afterwards, whether it worked or not -
+/// unless nobody sent anything, in which case the RAM is untouched.
+fn serial_boot(delay: &mut cortex_m::delay::Delay, os_start: *const u32) -> *const u32 {
+	let mut tc = &CONSOLE;
+	tc.clear();
+	writeln!(
+		tc,
+		"Waiting for an OS image over the serial port (YMODEM)..."
+	)
+	.unwrap();
+
+	// Note (safety): Nothing else uses the OS RAM until the OS starts.
+	let ram = unsafe {
+		core::slice::from_raw_parts_mut(
+			&mut _ram_os_start as *mut u32 as *mut u8,
+			&mut _ram_os_len as *const u32 as usize,
+		)
+	};
+	let flash_os = unsafe { &_flash_os_start as *const u32 };
+	let max_len = ram.len().min(flash::os_region().length as usize);
+
+	// Our messages mustn't get mixed up with the transfer
+	CONSOLE.set_serial_mirror(console::SerialMirror::Off);
+	let result = ymodem::receive(&mut ram[0..max_len]);
+	CONSOLE.set_serial_mirror(serial_mirror_mode());
+
+	let os_start = match result {
+		Ok(len) => {
+			writeln!(tc, "Received {} bytes. Writing to flash...", len).unwrap();
+			// Note (safety): Nothing is running from the OS image yet, and
+			// the new image is in RAM.
+			match unsafe { os_image::write(&ram[0..len]) } {
+				Ok(()) => writeln!(tc, "Done.").unwrap(),
+				Err(e) => {
+					warn!("Failed to program OS: {}", e);
+					writeln!(tc, "Failed to write the image: {}", e.description()).unwrap();
+				}
+			}
+			flash_os
+		}
+		Err(ymodem::Error::NoSender) => {
+			writeln!(tc, "Nothing was sent.").unwrap();
+			os_start
+		}
+		Err(e) => {
+			writeln!(tc, "Transfer failed: {}", e.description()).unwrap();
+			flash_os
+		}
+	};
+	delay.delay_ms(2000);
+	tc.clear();
+	os_start
+}
+
+/// Tell the user the OS image in flash is damaged (or missing), and let them
+/// fix it.
+///
+/// This is a small monitor, which can also show memory and run the self
+/// test, to help work out what went wrong. Only returns once there's an OS
+/// image in flash which passes its check. A key press on the serial port
+/// works as well as one on the keyboard, for systems with no monitor.
+fn recovery_console(delay: &mut cortex_m::delay::Delay, mut error: os_image::Error) {
+	let flash_os = unsafe { &_flash_os_start as *const u32 };
+	let mut tc = &CONSOLE;
+	loop {
+		tc.clear();
+		writeln!(tc, "{}", &BIOS_VERSION[0..BIOS_VERSION.len() - 1]).unwrap();
+		writeln!(
+			tc,
+			"The OS image in flash is damaged ({}).",
+			error.description()
+		)
+		.unwrap();
+		writeln!(tc, "S - Load an OS over the serial port (YMODEM)").unwrap();
+		writeln!(tc, "M - Show memory").unwrap();
+		writeln!(tc, "P - Run the power-on self test").unwrap();
+		writeln!(tc, "U - Reset into the USB bootloader").unwrap();
+		writeln!(tc, "R - Restart").unwrap();
+
+		match setup::wait_for_key(delay) {
+			's' | 'S' => {
+				serial_boot(delay, flash_os);
+				match os_image::check() {
+					Ok(_) => return,
+					Err(e) => error = e,
+				}
+			}
+			'm' | 'M' => show_memory(delay),
+			'p' | 'P' => {
+				tc.clear();
+				writeln!(tc, "Running the self test...").unwrap();
+				// Note (safety): We haven't loaded anything into the OS RAM
+				// that we still need - the OS image is in flash.
+				let report = unsafe {
+					post::run(core::slice::from_raw_parts_mut(
+						&mut _ram_os_start as *mut u32,
+						(&mut _ram_os_len as *const u32 as usize) / core::mem::size_of::<u32>(),
+					))
+				};
+				writeln!(tc, "{}", report).unwrap();
+				writeln!(tc, "Press a key.").unwrap();
+				setup::wait_for_key(delay);
+			}
+			'u' | 'U' => watchdog::reset_to_usb_boot(),
+			'r' | 'R' => watchdog::reboot(),
+			_ => {}
+		}
+	}
+}
+
+/// Ask for an address, and show the memory there in hex, a page at a time.
+///
+/// Only the ROM, the flash and the SRAM can be shown - reading a peripheral
+/// register can change things, and reading an unmapped address faults.
+fn show_memory(delay: &mut cortex_m::delay::Delay) {
+	let mut tc = &CONSOLE;
+	writeln!(tc).unwrap();
+	write!(tc, "Address (hex): ").unwrap();
+	let mut line = [0u8; 10];
+	let len = match setup::read_line(tc, delay, &mut line) {
+		Some(len) => len,
+		None => return,
+	};
+	let text = core::str::from_utf8(&line[0..len]).unwrap_or("");
+	let text = text.trim().trim_start_matches("0x");
+	let mut address = match u32::from_str_radix(text, 16) {
+		Ok(address) => address & !0x0F,
+		Err(_) => return,
+	};
+	loop {
+		tc.clear();
+		for _row in 0..MEMORY_VIEW_ROWS {
+			if !is_viewable(address) {
+				writeln!(tc, "{:08x}: (can't show this address)", address).unwrap();
+				break;
+			}
+			// Note (safety): `is_viewable` checked this is ROM, flash or SRAM,
+			// which is always mapped, and never changes when read.
+			let bytes = unsafe { core::slice::from_raw_parts(address as usize as *const u8, 16) };
+			write!(tc, "{:08x}:", address).unwrap();
+			for b in bytes.iter() {
+				write!(tc, " {:02x}", b).unwrap();
+			}
+			write!(tc, "  ").unwrap();
+			for &b in bytes.iter() {
+				let ch = if b.is_ascii_graphic() {
+					char::from(b)
+				} else {
+					'.'
+				};
+				write!(tc, "{}", ch).unwrap();
+			}
+			writeln!(tc).unwrap();
+			address = address.wrapping_add(16);
+		}
+		writeln!(tc, "Space - next page, any other key - go back").unwrap();
+		if setup::wait_for_key(delay) != ' ' {
+			return;
+		}
+	}
+}
+
+/// Can `show_memory` read the 16 bytes at this address?
+fn is_viewable(address: u32) -> bool {
+	let address = address as usize;
+	let ranges = [
+		(ROM_BASE, ROM_LEN),
+		(flash::XIP_BASE, flash::FLASH_SIZE),
+		(SRAM_BASE, SRAM_LEN),
+	];
+	let end = match address.checked_add(16) {
+		Some(end) => end,
+		None => return false,
+	};
+	ranges
+		.iter()
+		.any(|&(base, len)| address >= base && end <= (base + len))
+}
+
+/// Print the boot messages and count down to starting the OS.
+///
+/// Returns `true` if `S` was pressed during the countdown, asking us to
+/// fetch a new OS over the serial port.
+fn sign_on(
+	delay: &mut cortex_m::delay::Delay,
+	os_source: &str,
+	post_report: &post::Report,
+) -> bool {
 	static LICENCE_TEXT: &str = "\
         Copyright © Jonathan 'theJPster' Pallant and the Neotron Developers, 2022\n\
         \n\
@@ -253,41 +1131,106 @@ fn sign_on(delay: &mut cortex_m::delay::Delay) {
         You should have received a copy of the GNU General Public License\n\
         along with this program.  If not, see https://www.gnu.org/licenses/.\n";
 
-	// Create a new temporary console for some boot-up messages
-	let tc = vga::TextConsole::new();
-	tc.set_text_buffer(unsafe { &mut vga::GLYPH_ATTR_ARRAY });
-
-	// A crude way to clear the screen
-	for _col in 0..vga::MAX_TEXT_ROWS {
-		writeln!(&tc).unwrap();
+	// Set up the console for some boot-up messages. It goes out of the
+	// serial port too, in case there's no monitor.
+	CONSOLE
+		.vga()
+		.set_text_buffer(unsafe { &mut vga::GLYPH_ATTR_ARRAY });
+	CONSOLE.set_serial_mirror(serial_mirror_mode());
+	let mut tc = &CONSOLE;
+
+	let config = config::get();
+	if config.boot_style == config::BootStyle::Quiet && post_report.is_ok() {
+		return false;
 	}
 
-	tc.move_to(0, 0);
+	tc.clear();
+
+	writeln!(tc, "{}", &BIOS_VERSION[0..BIOS_VERSION.len() - 1]).unwrap();
+	match flash::unique_id() {
+		Ok(id) => writeln!(
+			tc,
+			"Board: {}, serial {:016X}",
+			board::revision().name(),
+			id
+		),
+		Err(_e) => writeln!(tc, "Board: {}", board::revision().name()),
+	}
+	.unwrap();
+	if config.boot_style == config::BootStyle::Verbose {
+		write!(tc, "{}", LICENCE_TEXT).unwrap();
+	}
 
-	writeln!(&tc, "{}", &BIOS_VERSION[0..BIOS_VERSION.len() - 1]).unwrap();
-	write!(&tc, "{}", LICENCE_TEXT).unwrap();
+	writeln!(
+		tc,
+		"Last reset: {} ({} crashes since power-on)",
+		watchdog::reset_reason().description(),
+		watchdog::crash_count()
+	)
+	.unwrap();
+	writeln!(tc, "{}", post_report).unwrap();
+	writeln!(tc, "Loading Neotron OS from {}...", os_source).unwrap();
+	writeln!(tc, "(Press S to load an OS over the serial port)").unwrap();
+	writeln!(tc, "(Press Delete or F2 for setup)").unwrap();
+
+	// Wait for a bit, so the messages can be read. If we're only here to
+	// show a self test failure, give it five seconds.
+	let delay_secs = if config.boot_style == config::BootStyle::Quiet {
+		5
+	} else {
+		config.boot_delay_secs
+	};
+	for n in (1..=delay_secs).rev() {
+		write!(tc, "{}...", n).unwrap();
+		for _tick in 0..(1000 / SIGN_ON_POLL_MS) {
+			delay.delay_ms(SIGN_ON_POLL_MS);
+			hid::poll();
+			if let Some('s' | 'S') = hid::get_char() {
+				return true;
+			}
+			if hid::take_setup_request() {
+				setup::run(&CONSOLE, delay);
+				return false;
+			}
+		}
+	}
 
-	writeln!(&tc, "Loading Neotron OS...").unwrap();
+	tc.clear();
+	false
+}
 
-	// Wait for a bit
-	for n in [5, 4, 3, 2, 1].iter() {
-		write!(&tc, "{}...", n).unwrap();
-		delay.delay_ms(1000);
-	}
+/// Take the system timer out of reset.
+///
+/// It counts in microseconds, using the tick generated by the watchdog.
+fn start_timer(pp: &mut pac::Peripherals) {
+	pp.RESETS.reset.modify(|_r, w| w.timer().clear_bit());
+	while pp.RESETS.reset_done.read().timer().bit_is_clear() {}
+}
 
-	// A crude way to clear the screen
-	for _col in 0..vga::MAX_TEXT_ROWS {
-		writeln!(&tc).unwrap();
-	}
-	tc.move_to(0, 0);
+/// Get the number of milliseconds since the BIOS started.
+///
+/// This wraps every 49.7 days, so use `wrapping_sub` to compare two values.
+pub fn ticks_ms() -> u32 {
+	// Note (safety): We only read the timer, and nothing else reads the
+	// latched TIMELR/TIMEHR pair.
+	let timer = unsafe { &*pac::TIMER::ptr() };
+	// Reading TIMELR latches the top half into TIMEHR
+	let low = timer.timelr.read().bits();
+	let high = timer.timehr.read().bits();
+	let micros = (u64::from(high) << 32) | u64::from(low);
+	(micros / 1000) as u32
 }
 
-/// Reset the DMA Peripheral.
-fn reset_dma_engine(pp: &mut pac::Peripherals) {
-	pp.RESETS.reset.modify(|_r, w| w.dma().set_bit());
-	cortex_m::asm::nop();
-	pp.RESETS.reset.modify(|_r, w| w.dma().clear_bit());
-	while pp.RESETS.reset_done.read().dma().bit_is_clear() {}
+/// Wait for something to happen, with Core 0 asleep.
+///
+/// Any interrupt wakes us, as does an event from Core 1. Once the video is
+/// running, its interrupt fires every scan-line, so we never sleep for more
+/// than about 32 µs and callers can still check their timeouts. Before
+/// then, there might be nothing to wake us, so we don't sleep at all.
+pub fn idle() {
+	if vga::is_irq_running() {
+		cortex_m::asm::wfe();
+	}
 }
 
 /// Returns the version number of the BIOS API.
@@ -307,6 +1250,21 @@ pub extern "C" fn bios_version_get() -> common::ApiString<'static> {
 	common::ApiString::new(BIOS_VERSION)
 }
 
+/// Returns a structure describing how this BIOS was built.
+///
+/// This gives more detail than `bios_version_get` - the exact git commit,
+/// the build time, the enabled Cargo features and the target board - which
+/// is what you want to put in a bug report or an "About" screen.
+pub extern "C" fn bios_build_info_get() -> BuildInfo {
+	BuildInfo {
+		version: common::ApiString::new(BIOS_VERSION),
+		git_hash: common::ApiString::new(concat!(env!("BIOS_GIT_HASH"), "\0")),
+		build_time: common::ApiString::new(concat!(env!("BIOS_BUILD_TIME"), "\0")),
+		features: common::ApiString::new(concat!(env!("BIOS_FEATURES"), "\0")),
+		board: common::ApiString::new(BOARD_NAME),
+	}
+}
+
 /// Get information about the Serial ports in the system.
 ///
 /// Serial ports are ordered octet-oriented pipes. You can push octets
@@ -319,17 +1277,44 @@ pub extern "C" fn bios_version_get() -> common::ApiString<'static> {
 /// that is an Operating System level design feature. These APIs just
 /// reflect the raw hardware, in a similar manner to the registers exposed
 /// by a memory-mapped UART peripheral.
-pub extern "C" fn serial_get_info(_device: u8) -> common::Option<common::serial::DeviceInfo> {
-	common::Option::None
+pub extern "C" fn serial_get_info(device: u8) -> common::Option<common::serial::DeviceInfo> {
+	match device {
+		0 => common::Option::Some(common::serial::DeviceInfo {
+			name: common::ApiString::new("RS232"),
+			device_type: common::serial::DeviceType::Rs232,
+		}),
+		1 => common::Option::Some(common::serial::DeviceInfo {
+			name: common::ApiString::new("USB"),
+			device_type: common::serial::DeviceType::UsbCdc,
+		}),
+		_ => common::Option::None,
+	}
 }
 
 /// Set the options for a given serial device. An error is returned if the
 /// options are invalid for that serial device.
+///
+/// The USB serial port (device 1) ignores the options - the host decides
+/// how fast it goes.
 pub extern "C" fn serial_configure(
-	_device: u8,
-	_config: common::serial::Config,
+	device: u8,
+	config: common::serial::Config,
 ) -> common::Result<()> {
-	common::Result::Err(common::Error::Unimplemented)
+	match device {
+		0 => {}
+		1 => return common::Result::Ok(()),
+		_ => return common::Result::Err(common::Error::InvalidDevice),
+	}
+	match serial::configure(&config) {
+		Ok(()) => common::Result::Ok(()),
+		Err(serial::Error::NoUart) => common::Result::Err(common::Error::InvalidDevice),
+		Err(serial::Error::BadBaudRate) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(0))
+		}
+		Err(serial::Error::BadHandshaking) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(1))
+		}
+	}
 }
 
 /// Write bytes to a serial port. There is no sense of 'opening' or
@@ -338,11 +1323,15 @@ pub extern "C" fn serial_configure(
 /// buffer. If so, that means not all of the data could be transmitted -
 /// only the first `n` bytes were.
 pub extern "C" fn serial_write(
-	_device: u8,
-	_data: common::ApiByteSlice,
-	_timeout: common::Option<common::Timeout>,
+	device: u8,
+	data: common::ApiByteSlice,
+	timeout: common::Option<common::Timeout>,
 ) -> common::Result<usize> {
-	common::Result::Err(common::Error::Unimplemented)
+	match device {
+		0 => common::Result::Ok(serial::write(data.as_slice(), timeout_ms(timeout))),
+		1 => common::Result::Ok(usb::write(data.as_slice(), timeout_ms(timeout))),
+		_ => common::Result::Err(common::Error::InvalidDevice),
+	}
 }
 
 /// Read bytes from a serial port. There is no sense of 'opening' or
@@ -351,11 +1340,27 @@ pub extern "C" fn serial_write(
 ///  If so, that means not all of the data could be received - only the
 ///  first `n` bytes were filled in.
 pub extern "C" fn serial_read(
-	_device: u8,
-	_data: common::ApiBuffer,
-	_timeout: common::Option<common::Timeout>,
+	device: u8,
+	mut data: common::ApiBuffer,
+	timeout: common::Option<common::Timeout>,
 ) -> common::Result<usize> {
-	common::Result::Err(common::Error::Unimplemented)
+	let buffer = match data.as_mut_slice() {
+		Some(buffer) => buffer,
+		None => return common::Result::Ok(0),
+	};
+	match device {
+		0 => common::Result::Ok(serial::read(buffer, timeout_ms(timeout))),
+		1 => common::Result::Ok(usb::read(buffer, timeout_ms(timeout))),
+		_ => common::Result::Err(common::Error::InvalidDevice),
+	}
+}
+
+/// Convert an API timeout into milliseconds (or `None` to wait forever).
+fn timeout_ms(timeout: common::Option<common::Timeout>) -> Option<u32> {
+	match timeout {
+		common::Option::Some(timeout) => Some(timeout.get_ms()),
+		common::Option::None => None,
+	}
 }
 
 /// Get the current wall time.
@@ -371,8 +1376,9 @@ pub extern "C" fn serial_read(
 /// If the BIOS does not have a battery-backed clock, or if that battery has
 /// failed to keep time, the system starts up assuming it is the epoch.
 pub extern "C" fn time_get() -> common::Time {
-	// TODO: Read from the MCP7940N
-	common::Time { secs: 0, nsecs: 0 }
+	// The RTC only counts whole seconds
+	let secs = rtc::get_time().unwrap_or(0);
+	common::Time { secs, nsecs: 0 }
 }
 
 /// Set the current wall time.
@@ -384,8 +1390,10 @@ pub extern "C" fn time_get() -> common::Time {
 /// time (e.g. the user has updated the current time, or if you get a GPS
 /// fix). The BIOS should push the time out to the battery-backed Real
 /// Time Clock, if it has one.
-pub extern "C" fn time_set(_time: common::Time) {
-	// TODO: Update the MCP7940N RTC
+pub extern "C" fn time_set(time: common::Time) {
+	if let Err(e) = rtc::set_time(time.secs) {
+		warn!("Failed to set RTC: {}", e);
+	}
 }
 
 /// Get the configuration data block.
@@ -393,29 +1401,56 @@ pub extern "C" fn time_set(_time: common::Time) {
 /// Configuration data is, to the BIOS, just a block of bytes of a given
 /// length. How it stores them is up to the BIOS - it could be EEPROM, or
 /// battery-backed SRAM.
-pub extern "C" fn configuration_get(_buffer: common::ApiBuffer) -> common::Result<usize> {
-	common::Result::Err(common::Error::Unimplemented)
+///
+/// We keep it in the battery-backed SRAM inside the RTC, and in the last
+/// sector of flash.
+pub extern "C" fn configuration_get(mut buffer: common::ApiBuffer) -> common::Result<usize> {
+	let buffer = match buffer.as_mut_slice() {
+		Some(buffer) => buffer,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	match config::get().encode(buffer) {
+		Ok(len) => common::Result::Ok(len),
+		Err(_e) => common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	}
 }
 
 /// Set the configuration data block.
 ///
-/// See `configuration_get`.
-pub extern "C" fn configuration_set(_buffer: common::ApiByteSlice) -> common::Result<()> {
-	common::Result::Err(common::Error::Unimplemented)
+/// See `configuration_get`. If the new configuration can't be saved (e.g.
+/// there is no RTC), it is still used until power-off, but you get
+/// `DeviceError(0)`. Saving to flash blanks the picture for a moment.
+pub extern "C" fn configuration_set(buffer: common::ApiByteSlice) -> common::Result<()> {
+	match config::Config::decode(buffer.as_slice()) {
+		Ok(new_config) => {
+			config::set(new_config);
+			if CONSOLE.serial_mirror() != console::SerialMirror::Off {
+				CONSOLE.set_serial_mirror(serial_mirror_mode());
+			}
+			match config::save(&new_config) {
+				Ok(()) => common::Result::Ok(()),
+				Err(e) => {
+					warn!("Failed to save config: {}", e);
+					common::Result::Err(common::Error::DeviceError(0))
+				}
+			}
+		}
+		Err(_e) => common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	}
 }
 
 /// Does this Neotron BIOS support this video mode?
 pub extern "C" fn video_is_valid_mode(mode: common::video::Mode) -> bool {
-	mode == common::video::Mode::new(
-		common::video::Timing::T640x480,
-		common::video::Format::Text8x16,
-	)
+	vga::is_valid_mode(mode)
 }
 
 /// Switch to a new video mode.
 ///
 /// The contents of the screen are undefined after a call to this function.
 ///
+/// A framebuffer from `video_set_framebuffer` is kept if it is big enough
+/// for the new mode, and forgotten if it isn't.
+///
 /// If the BIOS does not have enough reserved RAM (or dedicated VRAM) to
 /// support this mode, the change will succeed but a subsequent call to
 /// `video_get_framebuffer` will return `null`. You must then supply a
@@ -452,7 +1487,7 @@ pub extern "C" fn video_get_mode() -> common::video::Mode {
 /// to provide the 'basic' text buffer experience from reserves, so this
 /// function will never return `null` on start-up.
 pub extern "C" fn video_get_framebuffer() -> *mut u8 {
-	unsafe { vga::GLYPH_ATTR_ARRAY.as_mut_ptr() as *mut u8 }
+	vga::get_framebuffer()
 }
 
 /// Set the framebuffer address.
@@ -460,158 +1495,696 @@ pub extern "C" fn video_get_framebuffer() -> *mut u8 {
 /// Tell the BIOS where it should start fetching pixel or textual data from
 /// (depending on the current video mode).
 ///
-/// This value is forgotten after a video mode change and must be re-supplied.
+/// This value is kept across video mode changes, as long as the new mode
+/// needs no more memory than the mode it was supplied for (and a text mode
+/// gets a 2-byte aligned buffer). Otherwise it is forgotten after the mode
+/// change and must be re-supplied.
 ///
 /// # Safety
 ///
 /// The pointer must point to enough video memory to handle the current video
-/// mode, and any future video mode you set.
-pub unsafe extern "C" fn video_set_framebuffer(_buffer: *const u8) -> common::Result<()> {
-	common::Result::Err(common::Error::Unimplemented)
+/// mode, and must stay valid until you replace it or a mode change forgets
+/// it.
+///
+/// We check the whole framebuffer (at the size the current mode needs) sits
+/// inside the Application Region, and return `UnsupportedConfiguration(0)`
+/// if it doesn't. A text mode framebuffer must be 2-byte aligned, or you get
+/// `UnsupportedConfiguration(1)`. Passing null goes back to the BIOS's own
+/// text buffer.
+pub unsafe extern "C" fn video_set_framebuffer(buffer: *const u8) -> common::Result<()> {
+	let ram_start = &mut _ram_os_start as *mut u32 as usize;
+	let ram_len = &mut _ram_os_len as *const u32 as usize;
+	match vga::set_framebuffer(buffer as *mut u8, ram_start..ram_start + ram_len) {
+		Ok(()) => common::Result::Ok(()),
+		Err(vga::FramebufferError::OutsideRam) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(0))
+		}
+		Err(vga::FramebufferError::Misaligned) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(1))
+		}
+	}
 }
 
 /// Find out whether the given video mode needs more VRAM than we currently have.
 ///
-/// The answer is no for any currently supported video mode (which is just the four text modes right now).
-pub extern "C" fn video_mode_needs_vram(_mode: common::video::Mode) -> bool {
-	false
+/// The text modes fit in the BIOS's own RAM. The bitmap modes (e.g. the
+/// 76,800 byte 320x240 256-colour mode, or the 38,400 byte 320x240
+/// 16-colour and 640x480 monochrome modes) need the OS to supply a
+/// framebuffer.
+pub extern "C" fn video_mode_needs_vram(mode: common::video::Mode) -> bool {
+	vga::mode_needs_vram(mode)
 }
 
-/// Find out how large a given region of memory is.
+/// Draw an 8x8 icon (cursor, badge, etc) into the framebuffer.
 ///
-/// The first region is the 'main application region' and is defined to always
-/// start at address `0x2000_0000` on a standard Cortex-M system. This
-/// application region stops just before the BIOS reserved memory, at the top of
-/// the internal SRAM. The OS will have been linked to use the first 1 KiB of
-/// this region.
+/// The icon is 64 bytes, one palette index per pixel, top row first. Pixels
+/// equal to `transparent` are not drawn, so whatever is underneath shows
+/// through. The icon is clipped at the edges of the screen.
 ///
-/// Other regions may be located at other addresses (e.g. external DRAM or
-/// PSRAM).
+/// In 256-colour modes the pixels are copied by the same DMA channel as
+/// `mem_copy_dma`, so any copy or fill you started is finished first.
 ///
-/// The OS will always load non-relocatable applications into the bottom of
-/// Region 0. It can allocate OS specific structures from any other Region (if
-/// any), or from the top of Region 0 (although this reduces the maximum
-/// application space available). The OS will prefer lower numbered regions
-/// (other than Region 0), so faster memory should be listed first.
+/// Returns `UnsupportedConfiguration(0)` in a text mode, and
+/// `UnsupportedConfiguration(1)` if no framebuffer has been supplied.
 ///
-/// If the region number given is invalid, the function returns `(null, 0)`.
-pub extern "C" fn memory_get_region(region: u8) -> common::Result<common::MemoryRegion> {
-	match region {
-		0 => {
-			// Application Region
-			common::Result::Ok(MemoryRegion {
-				start: unsafe { &mut _ram_os_start as *mut u32 } as *mut u8,
-				length: unsafe { &mut _ram_os_len as *const u32 } as usize,
-				kind: common::MemoryKind::Ram,
-			})
+/// # Safety
+///
+/// `icon` must point to a valid `Icon`.
+pub unsafe extern "C" fn video_blit_icon(
+	x: u16,
+	y: u16,
+	icon: *const vga::Icon,
+	transparent: u8,
+) -> common::Result<()> {
+	match vga::blit_icon(x, y, &*icon, transparent) {
+		Ok(()) => common::Result::Ok(()),
+		Err(vga::BlitError::NotBitmapMode) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(0))
+		}
+		Err(vga::BlitError::NoFramebuffer) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(1))
 		}
-		_ => common::Result::Err(common::Error::InvalidDevice),
 	}
 }
 
-/// Get the next available HID event, if any.
+/// Tell the BIOS that something on screen has changed.
 ///
-/// This function doesn't block. It will return `Ok(None)` if there is no event ready.
-pub extern "C" fn hid_get_event() -> common::Result<common::Option<common::hid::HidEvent>> {
-	// TODO: Support some HID events
-	common::Result::Ok(common::Option::None)
+/// If the screen blank timeout is set in the configuration, the screen is
+/// blanked (and video rendering stops, to save power) when nothing has
+/// changed for that long. The BIOS sees its own console output, but it can't
+/// see you writing to the framebuffer, so call this when you do.
+pub extern "C" fn video_mark_dirty() {
+	vga::mark_dirty();
 }
 
-/// Control the keyboard LEDs.
-pub extern "C" fn hid_set_leds(_leds: common::hid::KeyboardLeds) -> common::Result<()> {
-	common::Result::Err(common::Error::Unimplemented)
+/// Load the user-supplied character table for the text console.
+///
+/// The table is 128 little-endian `u16` Unicode code points, one for each of
+/// glyphs 128 to 255 in the current font. It is used when the configuration
+/// selects the user encoding.
+pub extern "C" fn video_set_char_map(table: common::ApiByteSlice) -> common::Result<()> {
+	match vga::charmap::set_user_map(table.as_slice()) {
+		Ok(()) => common::Result::Ok(()),
+		Err(_e) => common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	}
 }
 
-/// Wait for the next occurence of the specified video scan-line.
-///
-/// In general we must assume that the video memory is read top-to-bottom
-/// as the picture is being drawn on the monitor (e.g. via a VGA video
-/// signal). If you modify video memory during this *drawing period*
-/// there is a risk that the image on the monitor (however briefly) may
-/// contain some parts from before the modification and some parts from
-/// after. This can given rise to the *tearing effect* where it looks
-/// like the screen has been torn (or ripped) across because there is a
-/// discontinuity part-way through the image.
+/// Override the screen saver.
 ///
-/// This function busy-waits until the video drawing has reached a
-/// specified scan-line on the video frame.
-///
-/// There is no error code here. If the line you ask for is beyond the
-/// number of visible scan-lines in the current video mode, it waits util
-/// the last visible scan-line is complete.
+/// * 0 - start the screen saver after the idle time in the configuration
+/// * 1 - never start the screen saver (e.g. while playing a video)
+/// * 2 - start the screen saver now; any input puts us back to 0
+pub extern "C" fn video_screen_saver_control(control: u8) -> common::Result<()> {
+	let control = match control {
+		0 => vga::ScreenSaverOverride::Auto,
+		1 => vga::ScreenSaverOverride::Inhibit,
+		2 => vga::ScreenSaverOverride::Activate,
+		_ => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	vga::set_screen_saver_override(control);
+	common::Result::Ok(())
+}
+
+/// Move the text cursor to the given column and row.
 ///
-/// If you wait for the last visible line until drawing, you stand the
-/// best chance of your pixels operations on the video RAM being
-/// completed before scan-lines start being sent to the monitor for the
-/// next frame.
+/// The BIOS draws the cursor over the text, so you don't have to change the
+/// text buffer to show one.
+pub extern "C" fn video_set_cursor_position(col: u16, row: u16) {
+	vga::set_cursor_position(col, row);
+}
+
+/// Change the shape of the text cursor.
 ///
-/// You can also use this for a crude `16.7 ms` delay but note that
-/// some video modes run at `70 Hz` and so this would then give you a
-/// `14.3ms` second delay.
-pub extern "C" fn video_wait_for_line(line: u16) {
-	let desired_line = line.min(vga::get_num_scan_lines());
-	loop {
-		let current_line = vga::get_scan_line();
-		if current_line == desired_line {
-			break;
-		}
+/// The cursor inverts lines `start_line..=end_line` of the character cell.
+/// `flags` bit 0 shows the cursor, and bit 1 makes it blink. The cursor is
+/// hidden until you call this.
+pub extern "C" fn video_set_cursor_shape(
+	start_line: u8,
+	end_line: u8,
+	flags: u8,
+) -> common::Result<()> {
+	if vga::set_cursor_shape(start_line, end_line, flags) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::UnsupportedConfiguration(0))
 	}
 }
 
-/// Get information about the Block Devices in the system.
+/// Replace the font used by the text modes which are `height` (8 or 16)
+/// lines tall.
 ///
-/// Block Devices are also known as *disk drives*. They can be read from
-/// (and often written to) but only in units called *blocks* or *sectors*.
+/// `data` is 256 glyphs of `height` bytes each, one byte per row with the
+/// left-most pixel in the top bit. The change happens at the start of the
+/// next frame. Pass a null pointer to go back to the built-in font.
 ///
-/// The BIOS should enumerate removable devices first, followed by fixed
-/// devices.
+/// # Safety
 ///
-/// The set of devices is not expected to change at run-time - removal of
-/// media is indicated with a boolean field in the
-/// `block_dev::DeviceInfo` structure.
-pub extern "C" fn block_dev_get_info(device: u8) -> common::Option<common::block_dev::DeviceInfo> {
-	match device {
-		0 => {
-			common::Option::Some(common::block_dev::DeviceInfo {
-				// This is the built-in SD card slot
-				name: common::types::ApiString::new("SdCard0"),
-				device_type: common::block_dev::DeviceType::SecureDigitalCard,
-				// This is the standard for SD cards
-				block_size: 512,
-				// TODO: scan the card here
-				num_blocks: 0,
-				// No motorised eject
-				ejectable: false,
-				// But you can take the card out
-				removable: true,
-				// Pretend the card is out
-				media_present: true,
-				// Don't care about this value when card is out
-				read_only: false,
-			})
-		}
-		_ => {
-			// Nothing else supported by this BIOS
-			common::Option::None
-		}
+/// `data` must point to `256 * height` bytes, which must not change or move
+/// until you set another font of that height. Fonts in RAM render a little
+/// faster than fonts in flash.
+pub unsafe extern "C" fn video_set_font(height: u8, data: *const u8) -> common::Result<()> {
+	if vga::set_font(height, data) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::UnsupportedConfiguration(0))
 	}
 }
 
-/// Write one or more sectors to a block device.
+/// Find out how many virtual consoles there are in this video mode.
 ///
-/// The function will block until all data is written. The array pointed
-/// to by `data` must be `num_blocks * block_size` in length, where
-/// `block_size` is given by `block_dev_get_info`.
+/// The virtual consoles share the BIOS's text buffer, so there are more of
+/// them in the smaller text modes. There is only ever one in a bitmap mode,
+/// or if you have supplied your own framebuffer.
+pub extern "C" fn video_num_consoles() -> u8 {
+	vga::num_consoles()
+}
+
+/// Get the text buffer for a virtual console, laid out like the one
+/// `video_get_framebuffer` returns (which is console 0).
 ///
-/// There are no requirements on the alignment of `data` but if it is
+/// Returns null if there is no such console. The consoles move when the
+/// video mode changes, so call this again after a mode change.
+pub extern "C" fn video_get_console_buffer(index: u8) -> *mut u8 {
+	match vga::get_console_buffer(index) {
+		Some(buffer) => buffer as *mut u8,
+		None => core::ptr::null_mut(),
+	}
+}
+
+/// Choose which virtual console is on screen.
+///
+/// The user can also do this by holding Scroll Lock and pressing F1, F2 or
+/// F3. Changing the video mode goes back to console 0.
+pub extern "C" fn video_set_active_console(index: u8) -> common::Result<()> {
+	if vga::set_active_console(index) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::InvalidDevice)
+	}
+}
+
+/// Find out which virtual console is on screen.
+pub extern "C" fn video_get_active_console() -> u8 {
+	vga::active_console()
+}
+
+/// Send a message to Core 1.
+///
+/// Core 1 passes each message to the handler set with `core1_set_handler`.
+/// Up to eight messages can be waiting - after that you get
+/// `DeviceError(0)` until Core 1 catches up.
+pub extern "C" fn core1_send(message: u32) -> common::Result<()> {
+	match mailbox::send(message) {
+		Ok(()) => common::Result::Ok(()),
+		Err(mailbox::Error::NotReady) => common::Result::Err(common::Error::Unimplemented),
+		Err(mailbox::Error::Full) => common::Result::Err(common::Error::DeviceError(0)),
+	}
+}
+
+/// Collect the next message Core 1 sent with `core1_reply`, if any.
+pub extern "C" fn core1_receive() -> common::Option<u32> {
+	mailbox::receive().into()
+}
+
+/// Choose the function Core 1 calls with each message from `core1_send`.
+///
+/// The function runs on Core 1, in between drawing scan-lines, so it must
+/// be quick. Pass `None` to drop messages instead.
+pub extern "C" fn core1_set_handler(handler: Option<mailbox::Handler>) {
+	mailbox::set_handler(handler);
+}
+
+/// Send a message from Core 1 to Core 0, where `core1_receive` will find
+/// it. Returns false if the message was dropped because the FIFO was full.
+///
+/// Only call this on Core 1 (e.g. from your `core1_set_handler` function).
+pub extern "C" fn core1_reply(message: u32) -> bool {
+	mailbox::reply(message)
+}
+
+/// Find out which part of flash the OS may erase and program, for its own
+/// data or for updates. Offsets are from the start of the flash chip (which
+/// appears at 0x1000_0000).
+pub extern "C" fn flash_get_data_region() -> flash::Region {
+	flash::data_region()
+}
+
+/// Read the flash chip's 64-bit unique ID, e.g. to make a serial number.
+///
+/// This blanks the picture for a moment.
+pub extern "C" fn flash_get_unique_id() -> common::Result<u64> {
+	match flash::unique_id() {
+		Ok(id) => common::Result::Ok(id),
+		Err(e) => common::Result::Err(flash_error(e)),
+	}
+}
+
+/// Read bytes from anywhere in flash, starting at the given offset from the
+/// start of the chip.
+pub extern "C" fn flash_read(offset: u32, mut buffer: common::ApiBuffer) -> common::Result<()> {
+	let buffer = match buffer.as_mut_slice() {
+		Some(buffer) => buffer,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	match flash::read(offset as usize, buffer) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(flash_error(e)),
+	}
+}
+
+/// Erase the 4 KiB sector at the given offset from the start of the flash
+/// chip. It must be inside the data region.
+///
+/// This blanks the picture for about 50 ms, but the monitor stays in sync.
+pub extern "C" fn flash_erase_sector(offset: u32) -> common::Result<()> {
+	match flash::erase_data_sector(offset as usize) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(flash_error(e)),
+	}
+}
+
+/// Program the 256 byte page at the given offset from the start of the
+/// flash chip. It must be inside the data region, and should have been
+/// erased. `data` must be exactly 256 bytes.
+///
+/// This blanks the picture for about 1 ms, but the monitor stays in sync.
+pub extern "C" fn flash_program_page(
+	offset: u32,
+	data: common::ApiByteSlice,
+) -> common::Result<()> {
+	match flash::program_data_page(offset as usize, data.as_slice()) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(flash_error(e)),
+	}
+}
+
+/// Convert a flash error into an API error.
+fn flash_error(error: flash::Error) -> common::Error {
+	match error {
+		flash::Error::NotReady => common::Error::DeviceError(0),
+		flash::Error::Core1Busy => common::Error::DeviceError(1),
+		flash::Error::Misaligned => common::Error::UnsupportedConfiguration(0),
+		flash::Error::OutOfRange | flash::Error::NotAllowed => {
+			common::Error::UnsupportedConfiguration(1)
+		}
+	}
+}
+
+/// Start (or change) the audio output.
+///
+/// `sample_rate_hz` must be 48000, 44100 or 22050. `format` is 0 for 8-bit
+/// mono, 1 for 8-bit stereo, 2 for 16-bit mono or 3 for 16-bit stereo.
+/// 8-bit samples are unsigned, and 16-bit samples are signed
+/// little-endian.
+pub extern "C" fn audio_output_set_config(sample_rate_hz: u32, format: u8) -> common::Result<()> {
+	match audio::configure(sample_rate_hz, format) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Queue up some samples to play, in the format given to
+/// `audio_output_set_config`.
+///
+/// Returns how many bytes were taken (always whole frames), which is zero
+/// if the queue is full. This function doesn't block.
+pub extern "C" fn audio_output_data(data: common::ApiByteSlice) -> common::Result<usize> {
+	match audio::write(data.as_slice()) {
+		Ok(n) => common::Result::Ok(n),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Find out how many bytes `audio_output_data` would take right now.
+pub extern "C" fn audio_output_get_space() -> common::Result<usize> {
+	match audio::space() {
+		Ok(n) => common::Result::Ok(n),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Convert an audio error into an API error.
+fn audio_error(error: audio::Error) -> common::Error {
+	match error {
+		audio::Error::NotReady => common::Error::DeviceError(0),
+		audio::Error::NoCodec(_) => common::Error::DeviceError(1),
+		audio::Error::BadSampleRate => common::Error::UnsupportedConfiguration(0),
+		audio::Error::BadFormat => common::Error::UnsupportedConfiguration(1),
+		audio::Error::PinInUse(_) => common::Error::UnsupportedConfiguration(2),
+		audio::Error::NoSuchChannel => common::Error::InvalidDevice,
+		audio::Error::BadSource => common::Error::UnsupportedConfiguration(3),
+		audio::Error::BadFrequency => common::Error::UnsupportedConfiguration(4),
+	}
+}
+
+/// Get one entry from the palette used by the indexed-colour video modes.
+///
+/// Colours are 12-bit, packed as `0x0BGR`.
+pub extern "C" fn video_get_palette(index: u8) -> vga::RGBColour {
+	vga::get_palette(index)
+}
+
+/// Change `count` entries in the palette, starting at `start`.
+///
+/// The change takes effect at the start of the next frame, so you never see
+/// half a screen in the old colours. Returns `UnsupportedConfiguration` if
+/// the entries would run past entry 255.
+///
+/// # Safety
+///
+/// `colours` must point to `count` valid colours.
+pub unsafe extern "C" fn video_set_palette(
+	start: u8,
+	colours: *const vga::RGBColour,
+	count: usize,
+) -> common::Result<()> {
+	let colours = core::slice::from_raw_parts(colours, count);
+	if vga::set_palette(start, colours) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::UnsupportedConfiguration(0))
+	}
+}
+
+/// Start (or change) a PWM output.
+///
+/// Channel 0 is on GPIO26 and channel 1 is on GPIO28. The frequency must be
+/// between 10 Hz and 1 MHz. The `duty` is the fraction of each period the
+/// output is high, from 0 (always low) to 65535 (almost always high).
+///
+/// Returns `InvalidDevice` for a bad channel, `UnsupportedConfiguration(0)`
+/// for a bad frequency and `UnsupportedConfiguration(1)` if the pin is being
+/// used for something else.
+pub extern "C" fn pwm_configure(channel: u8, frequency_hz: u32, duty: u16) -> common::Result<()> {
+	match pwm::configure(channel, frequency_hz, duty) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(pwm_error(e)),
+	}
+}
+
+/// Stop a PWM output, leaving the pin free for other uses.
+pub extern "C" fn pwm_disable(channel: u8) -> common::Result<()> {
+	match pwm::disable(channel) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(pwm_error(e)),
+	}
+}
+
+/// Convert a PWM error to a BIOS API error.
+fn pwm_error(error: pwm::Error) -> common::Error {
+	match error {
+		pwm::Error::NoSuchChannel => common::Error::InvalidDevice,
+		pwm::Error::BadFrequency => common::Error::UnsupportedConfiguration(0),
+		pwm::Error::PinInUse(_) => common::Error::UnsupportedConfiguration(1),
+	}
+}
+
+/// Find out how large a given region of memory is.
+///
+/// The first region is the 'main application region' and is defined to always
+/// start at address `0x2000_0000` on a standard Cortex-M system. This
+/// application region stops just before the BIOS reserved memory, at the top of
+/// the internal SRAM. The OS will have been linked to use the first 1 KiB of
+/// this region.
+///
+/// Other regions may be located at other addresses (e.g. external DRAM or
+/// PSRAM). We offer, in this order, skipping any that aren't available:
+///
+/// * The OS SRAM, from `memory.x`
+/// * The XIP cache, as 16 KiB of SRAM, with the `xip-cache-ram` feature
+///
+/// The two 4 KiB scratch banks (SRAM4 and SRAM5) aren't offered, as they
+/// hold the Core 0 and Core 1 stacks. Nor is external PSRAM, which the
+/// RP2040 can't map into the address space - see `psram_read`.
+///
+/// The OS will always load non-relocatable applications into the bottom of
+/// Region 0. It can allocate OS specific structures from any other Region (if
+/// any), or from the top of Region 0 (although this reduces the maximum
+/// application space available). The OS will prefer lower numbered regions
+/// (other than Region 0), so faster memory should be listed first.
+///
+/// If the region number given is invalid, the function returns `(null, 0)`.
+pub extern "C" fn memory_get_region(region: u8) -> common::Result<common::MemoryRegion> {
+	// Note (safety): These are linker symbols - only their addresses mean
+	// anything.
+	let os_ram = unsafe {
+		(
+			&mut _ram_os_start as *mut u32 as *mut u8,
+			&mut _ram_os_len as *const u32 as usize,
+		)
+	};
+	let regions = [Some(os_ram), flash::cache_ram()];
+	match regions.iter().flatten().nth(usize::from(region)) {
+		Some(&(start, length)) => common::Result::Ok(MemoryRegion {
+			start,
+			length,
+			kind: common::MemoryKind::Ram,
+		}),
+		None => common::Result::Err(common::Error::InvalidDevice),
+	}
+}
+
+/// Get the next available HID event, if any.
+///
+/// This function doesn't block. It will return `Ok(None)` if there is no event ready.
+///
+/// Every `BACKGROUND_POLL_MS` it also does the same background work as
+/// `system_poll`, so an OS which doesn't know about `system_poll` still
+/// sees SD cards and headphones come and go.
+pub extern "C" fn hid_get_event() -> common::Result<common::Option<common::hid::HidEvent>> {
+	watchdog::feed();
+	hid::poll();
+	// This has to come before anything else reads the I/O expander
+	slot_irq::poll();
+	buttons::poll();
+	paddles::poll();
+	let now = ticks_ms();
+	if now.wrapping_sub(LAST_BACKGROUND_POLL.load(Ordering::Relaxed)) >= BACKGROUND_POLL_MS {
+		LAST_BACKGROUND_POLL.store(now, Ordering::Relaxed);
+		background_poll();
+	}
+	common::Result::Ok(hid::get_event().into())
+}
+
+/// Get the next front-panel button event, if any.
+///
+/// Buttons are debounced, and repeat while held, using the timings in the
+/// BIOS configuration. The queue is only updated when you call
+/// `hid_get_event`.
+pub extern "C" fn hid_get_button_event() -> common::Option<buttons::ButtonEvent> {
+	buttons::get_event().into()
+}
+
+/// Get the next analogue paddle/joystick axis event, if any.
+///
+/// Axes are enabled, and calibrated, in the BIOS configuration. The queue is
+/// only updated when you call `hid_get_event`.
+pub extern "C" fn hid_get_axis_event() -> common::Option<paddles::AxisEvent> {
+	paddles::get_event().into()
+}
+
+/// Get the next PS/2 device attach/detach event, if any.
+///
+/// Devices can be plugged in and unplugged at any time. Each time a device
+/// passes its self-test, or stops responding, an event is queued. The queue
+/// is only updated when you call `hid_get_event`.
+pub extern "C" fn hid_get_device_event() -> common::Option<hid::DeviceEvent> {
+	hid::get_device_event().into()
+}
+
+/// Get the next character typed on the PS/2 keyboard, if any.
+///
+/// Key presses are translated using the keyboard layout in the BIOS
+/// configuration (US, UK, French or German), which is what the BIOS uses
+/// before the OS loads. The same layout names the letter keys in the
+/// `KeyPress` and `KeyRelease` events from `hid_get_event`. The queue is only
+/// updated when you call `hid_get_event`.
+pub extern "C" fn hid_get_char() -> common::Option<u32> {
+	hid::get_char().map(u32::from).into()
+}
+
+/// Find out how far the mouse scroll wheel has moved since the last call.
+///
+/// `MouseInput` events don't have room for the wheel, so it is collected
+/// here instead. Positive values mean the wheel was turned towards the
+/// user. Only mice which answer the IntelliMouse knock sequence report
+/// wheel movement.
+pub extern "C" fn hid_get_wheel_delta() -> i16 {
+	hid::take_wheel_delta()
+}
+
+/// Find out what is plugged in to the given PS/2 port.
+///
+/// Port 0 is the keyboard port and Port 1 is the mouse port, although
+/// either kind of device works in either port. Returns `None` if the port
+/// is empty or doesn't exist.
+pub extern "C" fn hid_get_device_kind(port: u8) -> common::Option<hid::DeviceKind> {
+	hid::get_device_kind(port).into()
+}
+
+/// Control the keyboard LEDs.
+///
+/// Blocks until every attached keyboard has accepted the change. Keyboards
+/// plugged in later get the same LEDs.
+pub extern "C" fn hid_set_leds(leds: common::hid::KeyboardLeds) -> common::Result<()> {
+	let mut bits = 0;
+	if leds.is_scroll_lock_on() {
+		bits |= hid::LED_SCROLL_LOCK;
+	}
+	if leds.is_num_lock_on() {
+		bits |= hid::LED_NUM_LOCK;
+	}
+	if leds.is_caps_lock_on() {
+		bits |= hid::LED_CAPS_LOCK;
+	}
+	match hid::set_leds(bits) {
+		Ok(()) => common::Result::Ok(()),
+		Err(hid::Error::NoKeyboard) => common::Result::Err(common::Error::InvalidDevice),
+		Err(hid::Error::NoResponse) => common::Result::Err(common::Error::DeviceError(0)),
+	}
+}
+
+/// Wait for the next occurence of the specified video scan-line.
+///
+/// In general we must assume that the video memory is read top-to-bottom
+/// as the picture is being drawn on the monitor (e.g. via a VGA video
+/// signal). If you modify video memory during this *drawing period*
+/// there is a risk that the image on the monitor (however briefly) may
+/// contain some parts from before the modification and some parts from
+/// after. This can given rise to the *tearing effect* where it looks
+/// like the screen has been torn (or ripped) across because there is a
+/// discontinuity part-way through the image.
+///
+/// This function busy-waits until the video drawing has reached a
+/// specified scan-line on the video frame.
+///
+/// There is no error code here. If the line you ask for is beyond the
+/// number of visible scan-lines in the current video mode, it waits util
+/// the last visible scan-line is complete.
+///
+/// If you wait for the last visible line until drawing, you stand the
+/// best chance of your pixels operations on the video RAM being
+/// completed before scan-lines start being sent to the monitor for the
+/// next frame.
+///
+/// You can also use this for a crude `16.7 ms` delay but note that
+/// some video modes run at `70 Hz` and so this would then give you a
+/// `14.3ms` second delay.
+pub extern "C" fn video_wait_for_line(line: u16) {
+	let desired_line = line.min(vga::get_num_scan_lines());
+	loop {
+		let current_line = vga::get_scan_line();
+		if current_line == desired_line {
+			break;
+		}
+		idle();
+	}
+}
+
+/// Get information about the Block Devices in the system.
+///
+/// Block Devices are also known as *disk drives*. They can be read from
+/// (and often written to) but only in units called *blocks* or *sectors*.
+///
+/// The BIOS should enumerate removable devices first, followed by fixed
+/// devices.
+///
+/// The set of devices is not expected to change at run-time - removal of
+/// media is indicated with a boolean field in the
+/// `block_dev::DeviceInfo` structure.
+pub extern "C" fn block_dev_get_info(device: u8) -> common::Option<common::block_dev::DeviceInfo> {
+	match device {
+		0 => {
+			// Looking for the card initialises it, if required. If a PC has
+			// it, it's as good as taken out.
+			let card = if usb_storage::is_shared() {
+				None
+			} else {
+				sdcard::card_info().ok()
+			};
+			common::Option::Some(common::block_dev::DeviceInfo {
+				// This is the built-in SD card slot
+				name: common::types::ApiString::new("SdCard0"),
+				device_type: common::block_dev::DeviceType::SecureDigitalCard,
+				// This is the standard for SD cards
+				block_size: 512,
+				num_blocks: card.map(|c| c.num_blocks).unwrap_or(0),
+				// No motorised eject
+				ejectable: false,
+				// But you can take the card out
+				removable: true,
+				media_present: card.is_some(),
+				// Don't care about this value when card is out
+				read_only: false,
+			})
+		}
+		1 => {
+			let num_blocks = romdisk::num_blocks();
+			common::Option::Some(common::block_dev::DeviceInfo {
+				// This is the read-only disk at the end of our flash
+				name: common::types::ApiString::new("RomDisk0"),
+				device_type: common::block_dev::DeviceType::HardDiskDrive,
+				block_size: bootslot::BLOCK_SIZE as u32,
+				num_blocks,
+				ejectable: false,
+				removable: false,
+				// The BIOS might have been built without one
+				media_present: num_blocks != 0,
+				read_only: true,
+			})
+		}
+		2 => {
+			let card = sdcard::expansion_card_info().ok();
+			common::Option::Some(common::block_dev::DeviceInfo {
+				// This is an SD card adapter in an expansion slot
+				name: common::types::ApiString::new("SdCard1"),
+				device_type: common::block_dev::DeviceType::SecureDigitalCard,
+				block_size: 512,
+				num_blocks: card.map(|c| c.num_blocks).unwrap_or(0),
+				ejectable: false,
+				removable: true,
+				media_present: card.is_some(),
+				read_only: false,
+			})
+		}
+		_ => {
+			// Nothing else supported by this BIOS
+			common::Option::None
+		}
+	}
+}
+
+/// Write one or more sectors to a block device.
+///
+/// The function will block until all data is written. The array pointed
+/// to by `data` must be `num_blocks * block_size` in length, where
+/// `block_size` is given by `block_dev_get_info`.
+///
+/// There are no requirements on the alignment of `data` but if it is
 /// aligned, the BIOS may be able to use a higher-performance code path.
 pub extern "C" fn block_write(
-	_device: u8,
-	_block: u64,
-	_num_blocks: u8,
-	_data: common::ApiByteSlice,
+	device: u8,
+	block: u64,
+	num_blocks: u8,
+	data: common::ApiByteSlice,
 ) -> common::Result<()> {
-	common::Result::Err(common::Error::Unimplemented)
+	let len = usize::from(num_blocks) * bootslot::BLOCK_SIZE;
+	let data = match data.as_slice().get(0..len) {
+		Some(data) => data,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	let result = match device {
+		0 if usb_storage::is_shared() => Err(common::Error::DeviceError(5)),
+		0 => block_cache::write_blocks(block, data).map_err(sdcard_error),
+		// The ROM disk is read-only
+		1 => Err(common::Error::UnsupportedConfiguration(2)),
+		2 => sdcard::expansion_write_blocks(block, data).map_err(sdcard_error),
+		_ => Err(common::Error::InvalidDevice),
+	};
+	match result {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(e),
+	}
 }
 
 /// Read one or more sectors to a block device.
@@ -623,12 +2196,27 @@ pub extern "C" fn block_write(
 /// There are no requirements on the alignment of `data` but if it is
 /// aligned, the BIOS may be able to use a higher-performance code path.
 pub extern "C" fn block_read(
-	_device: u8,
-	_block: u64,
-	_num_blocks: u8,
-	_data: common::ApiBuffer,
+	device: u8,
+	block: u64,
+	num_blocks: u8,
+	mut data: common::ApiBuffer,
 ) -> common::Result<()> {
-	common::Result::Err(common::Error::Unimplemented)
+	let len = usize::from(num_blocks) * bootslot::BLOCK_SIZE;
+	let data = match data.as_mut_slice().and_then(|d| d.get_mut(0..len)) {
+		Some(data) => data,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	let result = match device {
+		0 if usb_storage::is_shared() => Err(common::Error::DeviceError(5)),
+		0 => block_cache::read_blocks(block, data).map_err(sdcard_error),
+		1 => romdisk::read_blocks(block, data).map_err(romdisk_error),
+		2 => sdcard::expansion_read_blocks(block, data).map_err(sdcard_error),
+		_ => Err(common::Error::InvalidDevice),
+	};
+	match result {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(e),
+	}
 }
 
 /// Verify one or more sectors on a block device (that is read them and
@@ -641,20 +2229,1143 @@ pub extern "C" fn block_read(
 /// There are no requirements on the alignment of `data` but if it is
 /// aligned, the BIOS may be able to use a higher-performance code path.
 pub extern "C" fn block_verify(
-	_device: u8,
-	_block: u64,
-	_num_blocks: u8,
-	_data: common::ApiByteSlice,
+	device: u8,
+	block: u64,
+	num_blocks: u8,
+	data: common::ApiByteSlice,
 ) -> common::Result<()> {
-	common::Result::Err(common::Error::Unimplemented)
+	let len = usize::from(num_blocks) * bootslot::BLOCK_SIZE;
+	let data = match data.as_slice().get(0..len) {
+		Some(data) => data,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	let result = match device {
+		0 if usb_storage::is_shared() => Err(common::Error::DeviceError(5)),
+		0 => block_cache::verify_blocks(block, data).map_err(sdcard_error),
+		1 => romdisk::verify_blocks(block, data).map_err(romdisk_error),
+		2 => sdcard::expansion_verify_blocks(block, data).map_err(sdcard_error),
+		_ => Err(common::Error::InvalidDevice),
+	};
+	match result {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(e),
+	}
 }
 
-/// Called when DMA raises IRQ0; i.e. when a DMA transfer to the pixel FIFO or
-/// the timing FIFO has completed.
-#[interrupt]
-fn DMA_IRQ_0() {
-	unsafe {
-		vga::irq();
+/// Convert an SD card error into something the OS understands.
+///
+/// Card errors become `DeviceError`, with the card's own response code (if
+/// any) in the bottom byte.
+fn sdcard_error(error: sdcard::Error) -> common::Error {
+	match error {
+		sdcard::Error::NoBus | sdcard::Error::NoCard => common::Error::DeviceError(0),
+		sdcard::Error::BadVoltage => common::Error::DeviceError(1),
+		sdcard::Error::Timeout => common::Error::DeviceError(2),
+		sdcard::Error::BadCsd => common::Error::DeviceError(3),
+		sdcard::Error::VerifyFailed => common::Error::DeviceError(4),
+		sdcard::Error::CommandFailed(r1) => common::Error::DeviceError(0x100 | u16::from(r1)),
+		sdcard::Error::ReadFailed(token) => common::Error::DeviceError(0x200 | u16::from(token)),
+		sdcard::Error::WriteFailed(response) => {
+			common::Error::DeviceError(0x300 | u16::from(response))
+		}
+		sdcard::Error::BadLength => common::Error::UnsupportedConfiguration(0),
+		sdcard::Error::OutOfRange => common::Error::UnsupportedConfiguration(1),
+		sdcard::Error::Busy => common::Error::DeviceError(6),
+	}
+}
+
+/// Convert a ROM disk error into something the OS understands.
+///
+/// These match the equivalent SD card errors.
+fn romdisk_error(error: romdisk::Error) -> common::Error {
+	match error {
+		romdisk::Error::VerifyFailed => common::Error::DeviceError(4),
+		romdisk::Error::BadLength => common::Error::UnsupportedConfiguration(0),
+		romdisk::Error::OutOfRange => common::Error::UnsupportedConfiguration(1),
+	}
+}
+
+/// Find out why the system last reset.
+///
+/// 0 is power-on, 1 is the reset button, 2 is a soft reset (e.g. after a
+/// BIOS crash), 3 is the watchdog and 4 is a debugger.
+pub extern "C" fn reset_reason_get() -> u8 {
+	watchdog::reset_reason() as u8
+}
+
+/// Find out how many times the system has crashed (watchdog resets, BIOS
+/// panics and HardFaults) since power-on or the reset button.
+pub extern "C" fn reset_crash_count_get() -> u16 {
+	watchdog::crash_count()
+}
+
+/// Tell the watchdog the OS hasn't locked up.
+///
+/// The watchdog is off until the first time you call this. From then on,
+/// it resets the system if the OS goes `watchdog::TIMEOUT_MS` without
+/// calling this, `hid_get_event`, `system_poll` or `power_idle`.
+pub extern "C" fn watchdog_feed() {
+	if watchdog::is_started() {
+		watchdog::feed();
+	} else {
+		info!("OS started the watchdog");
+		watchdog::start(watchdog::TIMEOUT_MS);
+	}
+}
+
+/// Select an expansion slot on the Neotron Bus, ready for `bus_write_read`
+/// or `bus_exchange`.
+///
+/// Slots are numbered 1 to 4, and 0 selects nothing. The slot stays
+/// selected (so you can do a transaction in several calls) until you select
+/// something else. The BMC and SD card are the BIOS's, so you can't select
+/// them, and nor can you select the slot with the SD card adapter in it
+/// (see the `storage_slot` configuration option).
+pub extern "C" fn bus_select(slot: u8) -> common::Result<()> {
+	let device = match slot {
+		0 => None,
+		n => match neobus::slot(n) {
+			Some(device) if Some(device) != sdcard::expansion_slot() => Some(device),
+			_ => return common::Result::Err(common::Error::InvalidDevice),
+		},
+	};
+	match neobus::select(device) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(neobus_error(e)),
+	}
+}
+
+/// Send `tx` and then `tx2` to the selected expansion slot, then fill `rx`
+/// with what it sends back (clocking out `0xFF` bytes).
+///
+/// Having two transmit buffers means you don't have to copy a command and
+/// its data into one buffer. Either can be empty.
+pub extern "C" fn bus_write_read(
+	tx: common::ApiByteSlice,
+	tx2: common::ApiByteSlice,
+	mut rx: common::ApiBuffer,
+) -> common::Result<()> {
+	let result = neobus::with_selected(|spi| {
+		// Writes and transfers on the SPI peripheral are infallible on the
+		// RP2040
+		let _ = spi.write(tx.as_slice());
+		let _ = spi.write(tx2.as_slice());
+		if let Some(buffer) = rx.as_mut_slice() {
+			for b in buffer.iter_mut() {
+				*b = 0xFF;
+			}
+			let _ = spi.transfer(buffer);
+		}
+	});
+	match result {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(neobus_error(e)),
+	}
+}
+
+/// Send the contents of `buffer` to the selected expansion slot, replacing
+/// each byte with the one received at the same time.
+pub extern "C" fn bus_exchange(mut buffer: common::ApiBuffer) -> common::Result<()> {
+	let result = neobus::with_selected(|spi| {
+		if let Some(buffer) = buffer.as_mut_slice() {
+			// Transfers on the SPI peripheral are infallible on the RP2040
+			let _ = spi.transfer(buffer);
+		}
+	});
+	match result {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(neobus_error(e)),
+	}
+}
+
+/// Find out which expansion slots have asked for attention, since the last
+/// call, and clear them.
+///
+/// Bit 0 is slot 1, and so on. A slot which is still asserting its
+/// interrupt line is reported again next time.
+pub extern "C" fn bus_interrupt_status() -> u32 {
+	u32::from(slot_irq::take_pending())
+}
+
+/// Set (or clear, with null) a function to be called when an expansion slot
+/// asks for attention.
+///
+/// The callback is given the newly pending slots (bit 0 is slot 1). It is
+/// called from `hid_get_event`, `system_poll` or `bus_interrupt_status`,
+/// never from an interrupt, so it can call the BIOS.
+pub extern "C" fn bus_set_interrupt_callback(callback: Option<slot_irq::Callback>) {
+	slot_irq::set_callback(callback);
+}
+
+/// Convert a Neotron Bus error into something the OS understands.
+fn neobus_error(error: neobus::Error) -> common::Error {
+	match error {
+		neobus::Error::NoBus => common::Error::DeviceError(0),
+		neobus::Error::NothingSelected => common::Error::InvalidDevice,
+	}
+}
+
+/// Put a virtual console on screen, from the start of the next frame.
+///
+/// Each virtual console is a whole page of text (see
+/// `video_get_console_buffer`), so the OS can draw a screen into one page
+/// while showing another, and then flip to it without tearing. In the 80x30
+/// and 80x25 modes there are two pages; the 80x50 and 80x60 modes only
+/// have room for one. `video_is_flip_pending` says when the flip has
+/// happened.
+pub extern "C" fn video_flip_console(index: u8) -> common::Result<()> {
+	if vga::flip_console(index) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::InvalidDevice)
+	}
+}
+
+/// Is a flip from `video_flip_console` still waiting for the next frame?
+///
+/// Once this returns false, the old page is off the screen and can be drawn
+/// on.
+pub extern "C" fn video_is_flip_pending() -> bool {
+	vga::flip_pending()
+}
+
+/// Choose a scan-line for `video_wait_for_raster` to wait for, or pass
+/// `0xFFFF` to stop.
+///
+/// Line 0 is the first visible line. Lines after the last visible one are
+/// in the vertical blanking interval, up to the end of the frame (525 lines
+/// at 640x480, 449 at 640x400, 625 at 800x600). Changing to a mode with
+/// fewer lines clears the raster line if it no longer exists.
+pub extern "C" fn video_set_raster_line(line: u16) -> common::Result<()> {
+	let line = if line == 0xFFFF { None } else { Some(line) };
+	if vga::set_raster_line(line) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::UnsupportedConfiguration(0))
+	}
+}
+
+/// Sleep until the beam next reaches the line given to
+/// `video_set_raster_line`.
+///
+/// Unlike `video_wait_for_line`, this doesn't spin - the CPU sleeps between
+/// lines - so it's good for racing the beam, e.g. to update a status bar
+/// as soon as it has been drawn. Fails if no line has been set.
+pub extern "C" fn video_wait_for_raster() -> common::Result<()> {
+	if vga::wait_for_raster() {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::UnsupportedConfiguration(0))
+	}
+}
+
+/// Sleep until the next vertical blanking interval starts, and return the
+/// new frame count (see `video_get_frame_count`).
+///
+/// This is the best time to change what's on screen. Unlike
+/// `video_wait_for_line`, the CPU sleeps while it waits.
+pub extern "C" fn video_wait_for_vblank() -> u32 {
+	vga::wait_for_vblank()
+}
+
+/// Find out how many frames have been sent to the monitor since start-up.
+///
+/// It goes up by one at the start of each vertical blanking interval, and
+/// wraps around. If it has gone up by more than one since your last frame,
+/// you dropped a frame.
+pub extern "C" fn video_get_frame_count() -> u32 {
+	vga::vblank_count()
+}
+
+/// Scroll the text modes, so the top of the screen shows the given scan-line
+/// of the text buffer (counting down from the top of row 0).
+///
+/// The buffer wraps around, so the rows above the offset appear below the
+/// last row. Moving the offset one line per frame gives smooth scrolling
+/// without copying the text buffer. Takes effect from the next frame, and
+/// goes back to zero when the video mode changes. Has no effect in the
+/// bitmap modes.
+pub extern "C" fn video_set_scroll_offset(lines: u16) {
+	vga::set_scroll_offset(lines);
+}
+
+/// Find out how far the text modes are scrolled (see
+/// `video_set_scroll_offset`).
+pub extern "C" fn video_get_scroll_offset() -> u16 {
+	vga::scroll_offset()
+}
+
+/// Give a sprite its image, or take it away with null.
+///
+/// There are eight sprites (0 to 7), each 16x16 pixels, drawn over the top
+/// of the text or bitmap on screen with sprite 7 on top. The image is 256
+/// colours, a row at a time. Pixels in the transparent colour (see
+/// `video_sprite_set_transparent`) aren't drawn.
+///
+/// # Safety
+///
+/// The image must stay where it is until the sprite is given another one.
+/// You can change its colours whenever you like.
+pub unsafe extern "C" fn video_sprite_set_image(
+	index: u8,
+	image: *const vga::RGBColour,
+) -> common::Result<()> {
+	if vga::sprites::set_image(index, image) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::InvalidDevice)
+	}
+}
+
+/// Move a sprite, so its top-left corner is at the given pixel.
+///
+/// Positions are in the pixels of the current mode, and can be off the
+/// screen.
+pub extern "C" fn video_sprite_set_position(index: u8, x: i16, y: i16) -> common::Result<()> {
+	if vga::sprites::set_position(index, x, y) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::InvalidDevice)
+	}
+}
+
+/// Show or hide a sprite. A sprite with no image is never shown.
+pub extern "C" fn video_sprite_set_enabled(index: u8, enabled: bool) -> common::Result<()> {
+	if vga::sprites::set_enabled(index, enabled) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::InvalidDevice)
+	}
+}
+
+/// Choose the colour which isn't drawn, in every sprite. The default is
+/// magenta (`0x0F0F`).
+pub extern "C" fn video_sprite_set_transparent(colour: vga::RGBColour) {
+	vga::sprites::set_transparent(colour);
+}
+
+/// Show or hide the BIOS's mouse pointer.
+///
+/// The BIOS moves the pointer itself whenever the PS/2 mouse moves, and
+/// draws it over everything else on screen (including the sprites). The
+/// mouse events still go to the OS as usual.
+pub extern "C" fn video_pointer_set_enabled(enabled: bool) {
+	vga::sprites::set_pointer_enabled(enabled);
+}
+
+/// Move the tip of the BIOS's mouse pointer to the given pixel.
+///
+/// Positions are in the pixels of the current mode, and are kept on the
+/// screen.
+pub extern "C" fn video_pointer_set_position(x: i16, y: i16) {
+	vga::sprites::set_pointer_position(x, y);
+}
+
+/// Find out where the tip of the BIOS's mouse pointer is, as `(y << 16) | x`.
+pub extern "C" fn video_pointer_get_position() -> u32 {
+	let (x, y) = vga::sprites::pointer_position();
+	(u32::from(y as u16) << 16) | u32::from(x as u16)
+}
+
+/// Replace one of the two font banks used by the text modes which are
+/// `height` (8 or 16) lines tall.
+///
+/// Bank 0 is the font `video_set_font` sets. Text cells with bit 3 set in
+/// their attribute (the top bit of the foreground colour) are drawn from
+/// bank 1 instead, so you can mix 512 different glyphs on screen - say, the
+/// usual text font and a set of graphics tiles. Until you load it, bank 1 is
+/// the same as bank 0. Pass a null pointer to go back to the default.
+///
+/// # Safety
+///
+/// As for `video_set_font`.
+pub unsafe extern "C" fn video_set_font_bank(
+	bank: u8,
+	height: u8,
+	data: *const u8,
+) -> common::Result<()> {
+	if vga::set_font_bank(bank, height, data) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::UnsupportedConfiguration(0))
+	}
+}
+
+/// Choose what the top bit of a text attribute does.
+///
+/// If `blink` is true (the default), text in that cell blinks, being drawn
+/// in its background colour for 32 frames out of every 64. Otherwise the bit
+/// is the top bit of a 4-bit background colour, like VGA's bright background
+/// mode.
+pub extern "C" fn video_set_text_blink(blink: bool) {
+	vga::set_text_blink(blink);
+}
+
+/// Reset into the RP2040 boot ROM's USB bootloader, as if the BOOTSEL
+/// button had been held down.
+///
+/// The Neotron Pico then appears on USB as a drive called `RPI-RP2`. Copy a
+/// BIOS `.uf2` file onto it and it is programmed and started. Never
+/// returns - anything the OS hasn't saved is lost.
+pub extern "C" fn reset_to_usb_boot() -> ! {
+	watchdog::reset_to_usb_boot();
+}
+
+/// Offer the SD card to a PC over USB, or take it back.
+///
+/// The Neotron Pico's USB socket is a composite device: as well as the
+/// virtual COM port, it has a Mass Storage interface which shows the SD
+/// card as a drive on the PC. Until you share the card, the drive is empty.
+///
+/// Unmount the card before you share it - while it is shared, block device
+/// 0 has no media, and reading or writing it gives `DeviceError(5)`. The
+/// PC's reads and writes are done whenever you call `system_poll` (and
+/// every so often from `hid_get_event`), so keep calling it. Ask the user
+/// to eject the drive on the PC before you take the card back.
+///
+/// Returns `DeviceError(0)` if there is no card to share.
+pub extern "C" fn usb_storage_set_shared(shared: bool) -> common::Result<()> {
+	match usb_storage::set_shared(shared) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(sdcard_error(e)),
+	}
+}
+
+/// Start reading one or more sectors from a block device, in the
+/// background.
+///
+/// Returns once the first sector has been asked for. Call `block_poll`
+/// until it says the transfer is over - each call moves it along - and get
+/// on with something else in between. While the transfer is running, the
+/// device gives `DeviceError(6)` to any other transfer.
+///
+/// The ROM disk (device 1) and an expansion slot SD card (device 2) are
+/// read straight away.
+///
+/// # Safety
+///
+/// The buffer `data` points to must stay valid, and you mustn't touch it,
+/// until `block_poll` says the transfer is over.
+pub unsafe extern "C" fn block_read_async(
+	device: u8,
+	block: u64,
+	num_blocks: u8,
+	mut data: common::ApiBuffer,
+) -> common::Result<()> {
+	let len = usize::from(num_blocks) * bootslot::BLOCK_SIZE;
+	let data = match data.as_mut_slice().and_then(|d| d.get_mut(0..len)) {
+		Some(data) => data,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	let result = match device {
+		0 if usb_storage::is_shared() => Err(common::Error::DeviceError(5)),
+		0 => block_cache::flush()
+			.and_then(|_| sdcard::start_read(block, data.as_mut_ptr(), len))
+			.map_err(sdcard_error),
+		1 => romdisk::read_blocks(block, data).map_err(romdisk_error),
+		2 => sdcard::expansion_read_blocks(block, data).map_err(sdcard_error),
+		_ => Err(common::Error::InvalidDevice),
+	};
+	match result {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(e),
+	}
+}
+
+/// Start writing one or more sectors to a block device, in the background.
+///
+/// As for `block_read_async`.
+///
+/// # Safety
+///
+/// The buffer `data` points to must stay valid until `block_poll` says the
+/// transfer is over.
+pub unsafe extern "C" fn block_write_async(
+	device: u8,
+	block: u64,
+	num_blocks: u8,
+	data: common::ApiByteSlice,
+) -> common::Result<()> {
+	let len = usize::from(num_blocks) * bootslot::BLOCK_SIZE;
+	let data = match data.as_slice().get(0..len) {
+		Some(data) => data,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	let result = match device {
+		0 if usb_storage::is_shared() => Err(common::Error::DeviceError(5)),
+		0 => block_cache::flush()
+			.and_then(|_| {
+				block_cache::invalidate();
+				sdcard::start_write(block, data.as_ptr(), len)
+			})
+			.map_err(sdcard_error),
+		// The ROM disk is read-only
+		1 => Err(common::Error::UnsupportedConfiguration(2)),
+		2 => sdcard::expansion_write_blocks(block, data).map_err(sdcard_error),
+		_ => Err(common::Error::InvalidDevice),
+	};
+	match result {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(e),
+	}
+}
+
+/// Move a background block transfer along, and find out if it's over.
+///
+/// Returns `Ok(true)` once it has finished (or if there wasn't one), and
+/// `Ok(false)` while it's still going. If it fails, you get the error, and
+/// the transfer is over.
+pub extern "C" fn block_poll(device: u8) -> common::Result<bool> {
+	let result = match device {
+		0 => sdcard::poll_transfer().map_err(sdcard_error),
+		// ROM disk transfers are over before they start
+		1 | 2 => Ok(true),
+		_ => Err(common::Error::InvalidDevice),
+	};
+	match result {
+		Ok(done) => common::Result::Ok(done),
+		Err(e) => common::Result::Err(e),
+	}
+}
+
+/// Write out anything a block device has cached.
+///
+/// Only matters in write-back mode (see `block_set_write_back`). Call it
+/// before the card might be taken out, or the power turned off.
+pub extern "C" fn block_flush(device: u8) -> common::Result<()> {
+	let result = match device {
+		0 if usb_storage::is_shared() => Err(common::Error::DeviceError(5)),
+		0 => block_cache::flush().map_err(sdcard_error),
+		// Nothing cached for the ROM disk or the expansion slot card
+		1 | 2 => Ok(()),
+		_ => Err(common::Error::InvalidDevice),
+	};
+	match result {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(e),
+	}
+}
+
+/// Choose between write-through and write-back caching on a block device.
+///
+/// The BIOS keeps a few recently used SD card sectors in RAM. Normally a
+/// write goes straight to the card. In write-back mode, single-sector
+/// writes stay in RAM until they are pushed out, or you call
+/// `block_flush`, which is faster but loses data if the card is taken out
+/// (or the power goes) first. Turning write-back off flushes the cache.
+pub extern "C" fn block_set_write_back(device: u8, enabled: bool) -> common::Result<()> {
+	let result = match device {
+		0 => block_cache::set_write_back(enabled).map_err(sdcard_error),
+		// The ROM disk has nothing to cache, and the expansion slot card
+		// is always write-through
+		1 | 2 => Ok(()),
+		_ => Err(common::Error::InvalidDevice),
+	};
+	match result {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(e),
+	}
+}
+
+/// Play a square-wave tone on the beeper, in the background, replacing any
+/// tone already playing.
+///
+/// Only boards without the audio codec have a beeper (on GPIO22) - on the
+/// others, you get `DeviceError(0)`. The frequency must be between 20 Hz
+/// and 20 kHz, and tones longer than ten seconds are cut short. A
+/// frequency or duration of zero stops the current tone.
+pub extern "C" fn audio_tone(frequency_hz: u32, duration_ms: u32) -> common::Result<()> {
+	match beeper::tone(frequency_hz, duration_ms) {
+		Ok(()) => common::Result::Ok(()),
+		Err(beeper::Error::NotFitted) => common::Result::Err(common::Error::DeviceError(0)),
+		Err(beeper::Error::BadFrequency) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(0))
+		}
+		Err(beeper::Error::PinInUse(_)) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(2))
+		}
+	}
+}
+
+/// Start (or change) one of the audio mixer channels, which play on top of
+/// the main audio output.
+///
+/// There are four channels (0 to 3), each with its own queue. The main
+/// output must already be running (see `audio_output_set_config`), or you
+/// get `DeviceError(0)`. `sample_rate_hz` can be anything from 4000 to
+/// 48000 - the BIOS converts it to the main output's rate. `format` is as
+/// for `audio_output_set_config`, and `volume` is as for
+/// `audio_mixer_set_volume`.
+pub extern "C" fn audio_mixer_set_config(
+	channel: u8,
+	sample_rate_hz: u32,
+	format: u8,
+	volume: u8,
+) -> common::Result<()> {
+	match audio::mix_configure(channel, sample_rate_hz, format, volume) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Change the volume of an audio mixer channel, where 255 plays the samples
+/// as given and 0 is silent. Mixing several loud channels can clip.
+pub extern "C" fn audio_mixer_set_volume(channel: u8, volume: u8) -> common::Result<()> {
+	match audio::mix_set_volume(channel, volume) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Stop an audio mixer channel, throwing away anything still queued on it.
+pub extern "C" fn audio_mixer_stop(channel: u8) -> common::Result<()> {
+	match audio::mix_stop(channel) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Queue up some samples to play on an audio mixer channel, in the format
+/// given to `audio_mixer_set_config`.
+///
+/// Returns how many bytes were taken (always whole frames), which is zero
+/// if the queue is full. This function doesn't block.
+pub extern "C" fn audio_mixer_data(
+	channel: u8,
+	data: common::ApiByteSlice,
+) -> common::Result<usize> {
+	match audio::mix_write(channel, data.as_slice()) {
+		Ok(n) => common::Result::Ok(n),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Find out how many bytes `audio_mixer_data` would take right now.
+pub extern "C" fn audio_mixer_get_space(channel: u8) -> common::Result<usize> {
+	match audio::mix_space(channel) {
+		Ok(n) => common::Result::Ok(n),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Start (or change) the audio input.
+///
+/// `source` is 0 for the line input or 1 for the microphone. The sample
+/// rates and formats are as for `audio_output_set_config` - the codec runs
+/// its input and output at the same rate, so this sets the output rate too
+/// (starting the output, playing silence, if it wasn't running).
+pub extern "C" fn audio_input_set_config(
+	sample_rate_hz: u32,
+	format: u8,
+	source: u8,
+) -> common::Result<()> {
+	match audio::input_configure(sample_rate_hz, format, source) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Take recorded samples, in the format given to `audio_input_set_config`.
+///
+/// Returns how many bytes were given (always whole frames), which is zero
+/// if nothing has come in. This function doesn't block. The BIOS only keeps
+/// about 5 ms of audio, so call this often - anything older is lost.
+pub extern "C" fn audio_input_data(mut buffer: common::ApiBuffer) -> common::Result<usize> {
+	let buffer = match buffer.as_mut_slice() {
+		Some(buffer) => buffer,
+		None => return common::Result::Ok(0),
+	};
+	match audio::input_read(buffer) {
+		Ok(n) => common::Result::Ok(n),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Find out how many bytes `audio_input_data` could give right now.
+pub extern "C" fn audio_input_get_count() -> common::Result<usize> {
+	match audio::input_count() {
+		Ok(n) => common::Result::Ok(n),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Stop the audio input.
+pub extern "C" fn audio_input_stop() -> common::Result<()> {
+	match audio::input_stop() {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Set the master audio output volume, from 0 (silent) to 255 (0 dB).
+///
+/// This sets the headphone volume - the line output (and so the speaker) is
+/// always at line level. You can set it before the output is started.
+pub extern "C" fn audio_output_set_volume(volume: u8) -> common::Result<()> {
+	match audio::set_volume(volume) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Get the master audio output volume, from 0 (silent) to 255 (0 dB).
+pub extern "C" fn audio_output_get_volume() -> u8 {
+	audio::volume()
+}
+
+/// Mute (or unmute) the audio output, on both the headphones and the
+/// speaker. You can set it before the output is started.
+pub extern "C" fn audio_output_set_mute(muted: bool) -> common::Result<()> {
+	match audio::set_mute(muted) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Are there headphones plugged in?
+///
+/// The BIOS switches between the headphones and the speaker on its own;
+/// this is just so the OS can tell the user. The switch is only checked
+/// when you call `system_poll` or `hid_get_event`.
+pub extern "C" fn audio_headphones_present() -> bool {
+	audio::headphones_present()
+}
+
+/// Play a square-wave tone, in the background, replacing any tone already
+/// playing. Good for key clicks, alerts and bells.
+///
+/// With the audio codec, the tone plays on top of the audio output and the
+/// mixer channels, and the output is started (at 48 kHz) if it isn't
+/// running. Without the codec, it plays on the beeper, like `audio_tone`.
+/// The frequency must be between 20 Hz and 20 kHz, and tones longer than
+/// ten seconds are cut short. A frequency or duration of zero stops the
+/// current tone.
+pub extern "C" fn audio_beep(frequency_hz: u32, duration_ms: u32) -> common::Result<()> {
+	match audio::tone(frequency_hz, duration_ms) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(audio_error(e)),
+	}
+}
+
+/// Use custom monitor timings, instead of the standard timings, for every
+/// video mode. Pass null to go back to the standard timings.
+///
+/// This is for monitors that can't show any of the standard modes (e.g.
+/// unusual LCD panels, or 15 kHz CRTs). The system clock must be a whole
+/// multiple of five, six, seven or eight times the pixel clock. The
+/// visible width must be a multiple of 8, up to 800 pixels. Each part of
+/// the scan-line must be between 12 and 16,389 PIO clocks long.
+///
+/// The current mode is set up again with the new timings, so the screen
+/// contents (and any framebuffer) are lost, as with `video_set_mode`. The
+/// text modes get as many rows and columns as fit (e.g. a 640x240 timing
+/// gives 80x15 with the 8x16 font); if that's more than the BIOS's text
+/// buffer holds, the mode isn't available. The bitmap modes need a pixel
+/// clock no faster than 25.2 MHz, and no more than 640 visible pixels.
+///
+/// Returns `UnsupportedConfiguration(0)` if we can't make the pixel clock,
+/// `UnsupportedConfiguration(1)` if the horizontal timings won't work,
+/// `UnsupportedConfiguration(2)` if the vertical timings won't work, and
+/// `UnsupportedConfiguration(3)` if the current mode doesn't fit. Nothing
+/// changes if there is an error.
+///
+/// # Safety
+///
+/// `mode_line` must be null or point to a valid `ModeLine`. We take a copy.
+pub unsafe extern "C" fn video_set_timing(mode_line: *const vga::ModeLine) -> common::Result<()> {
+	let mode_line = if mode_line.is_null() {
+		None
+	} else {
+		Some(*mode_line)
+	};
+	match vga::set_custom_timing(mode_line) {
+		Ok(()) => common::Result::Ok(()),
+		Err(vga::TimingError::BadClock) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(0))
+		}
+		Err(vga::TimingError::BadHorizontal) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(1))
+		}
+		Err(vga::TimingError::BadVertical) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(2))
+		}
+		Err(vga::TimingError::BadMode) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(3))
+		}
+	}
+}
+
+/// Get one of the BIOS's built-in monitor timings, ready to give to
+/// `video_set_timing`.
+///
+/// * 1 is 320x240 at 60 Hz, for 15 kHz RGB monitors (through SCART, or an
+///   arcade monitor)
+/// * 2 is 320x256 at 50 Hz, for the same monitors
+///
+/// Both have composite sync on the H-Sync pin. Clear `MODE_LINE_CSYNC` in
+/// the flags if your monitor wants separate H-Sync and V-Sync. The text
+/// modes are 40 columns wide with these timings. Preset 0 (the standard
+/// timings) has no `ModeLine`, so you get `None`, as for a preset we don't
+/// have.
+pub extern "C" fn video_get_timing_preset(preset: u8) -> common::Option<vga::ModeLine> {
+	match vga::TimingPreset::from_u8(preset).and_then(|preset| preset.mode_line()) {
+		Some(mode_line) => common::Option::Some(mode_line),
+		None => common::Option::None,
+	}
+}
+
+/// Turn interlaced drawing on or off.
+///
+/// When interlaced, the BIOS only draws the even lines of the picture on one
+/// frame, and the odd lines on the next, showing each line it draws twice.
+/// This flickers, but it halves the work of drawing each frame, so the 16
+/// and 256 colour bitmap modes become available without pixel doubling
+/// (e.g. 640x480 with 16 colours). You can't turn interlacing off while in
+/// one of those modes.
+pub extern "C" fn video_set_interlace(enabled: bool) -> common::Result<()> {
+	if vga::set_interlace(enabled) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::UnsupportedConfiguration(0))
+	}
+}
+
+/// Blank the screen, or put the monitor into a power saving state.
+///
+/// * 0 - on
+/// * 1 - blank (a black screen, with the sync signals still running)
+/// * 2 - standby (no H-Sync)
+/// * 3 - suspend (no V-Sync)
+/// * 4 - off (no H-Sync or V-Sync)
+///
+/// Monitors that support DPMS save more power (but take longer to wake up)
+/// further down the list. The BIOS doesn't change this on its own, so an OS
+/// which wants to power down the monitor when idle should run its own timer.
+pub extern "C" fn video_set_power(state: u8) -> common::Result<()> {
+	let state = match state {
+		0 => vga::PowerState::On,
+		1 => vga::PowerState::Blank,
+		2 => vga::PowerState::Standby,
+		3 => vga::PowerState::Suspend,
+		4 => vga::PowerState::Off,
+		_ => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	vga::set_power(state);
+	common::Result::Ok(())
+}
+
+/// Change palette entries part-way down each frame, for raster bars and
+/// other copper-style effects.
+///
+/// Each change gives a visible scan-line, a palette entry and a colour, and
+/// they must be in order of scan-line. On every frame, the entries start off
+/// with the colours from `video_set_palette`, and switch to the new colour
+/// from the given scan-line down. To cycle colours, call this again with new
+/// colours; the new list is used from the next frame. A `count` of zero
+/// stops all the changes. Returns `UnsupportedConfiguration` if there are
+/// more than 64 changes, or they are out of order.
+///
+/// # Safety
+///
+/// `changes` must point to `count` valid changes (or `count` must be zero).
+pub unsafe extern "C" fn video_set_palette_changes(
+	changes: *const vga::PaletteChange,
+	count: usize,
+) -> common::Result<()> {
+	let changes: &[vga::PaletteChange] = if count == 0 {
+		&[]
+	} else {
+		core::slice::from_raw_parts(changes, count)
+	};
+	if vga::set_palette_changes(changes) {
+		common::Result::Ok(())
+	} else {
+		common::Result::Err(common::Error::UnsupportedConfiguration(0))
+	}
+}
+
+/// Find out how long it took to draw the slowest scan-line in the last
+/// frame, in system clock cycles.
+///
+/// Each scan-line must be drawn in less time than it takes to send one to
+/// the monitor (e.g. 4000 cycles at 640x480 with the standard 126 MHz clock
+/// profile), so this shows how close the current mode, sprites and effects
+/// are to the limit.
+pub extern "C" fn video_get_render_time() -> u32 {
+	vga::render_time()
+}
+
+/// Get the video performance counters.
+///
+/// As well as the frame count and `video_get_render_time`, these count the
+/// scan-lines which Core 1 finished drawing too late (which shows up as
+/// tearing or a line from the wrong place), and the frames in which the
+/// PIO ran out of pixels or timing words. If the late lines go up while your
+/// program is drawing, try reading and writing video memory less often (or
+/// in the vertical blanking interval), or choose a less demanding mode.
+pub extern "C" fn video_get_stats() -> vga::VideoStats {
+	vga::stats()
+}
+
+/// Find out how big the external PSRAM is, in bytes. Zero means there isn't
+/// any (which is always the case without the `psram` feature).
+pub extern "C" fn psram_get_size() -> u32 {
+	if psram::is_fitted() {
+		psram::SIZE as u32
+	} else {
+		0
+	}
+}
+
+/// Read bytes from the external PSRAM, starting at the given offset.
+///
+/// The PSRAM isn't in the address space, so this is the only way to get at
+/// it. Expect a few MiB/s.
+pub extern "C" fn psram_read(offset: u32, mut buffer: common::ApiBuffer) -> common::Result<()> {
+	let buffer = match buffer.as_mut_slice() {
+		Some(buffer) => buffer,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	match psram::read(offset as usize, buffer) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(psram_error(e)),
+	}
+}
+
+/// Write bytes to the external PSRAM, starting at the given offset.
+pub extern "C" fn psram_write(offset: u32, data: common::ApiByteSlice) -> common::Result<()> {
+	match psram::write(offset as usize, data.as_slice()) {
+		Ok(()) => common::Result::Ok(()),
+		Err(e) => common::Result::Err(psram_error(e)),
+	}
+}
+
+/// Convert a PSRAM error into an API error.
+fn psram_error(error: psram::Error) -> common::Error {
+	match error {
+		psram::Error::NotFitted => common::Error::InvalidDevice,
+		psram::Error::OutOfRange => common::Error::UnsupportedConfiguration(1),
+	}
+}
+
+/// Start copying `len` bytes from `src` to `dest` with DMA, and return
+/// straight away. Poll `mem_dma_busy` to find out when it's done.
+///
+/// This is much faster than the CPU for anything more than a few dozen
+/// bytes, especially if both addresses and the length are a multiple of
+/// four. If a copy or fill is already running, we wait for it first.
+///
+/// # Safety
+///
+/// Both areas must be valid for `len` bytes and mustn't overlap. Don't
+/// touch either until `mem_dma_busy` returns false.
+pub unsafe extern "C" fn mem_copy_dma(dest: *mut u8, src: *const u8, len: usize) {
+	memdma::start_copy(dest, src, len);
+}
+
+/// Start setting `len` bytes at `dest` to `value` with DMA, and return
+/// straight away. As for `mem_copy_dma`.
+///
+/// # Safety
+///
+/// The area must be valid for `len` bytes. Don't touch it until
+/// `mem_dma_busy` returns false.
+pub unsafe extern "C" fn mem_fill_dma(dest: *mut u8, value: u8, len: usize) {
+	memdma::start_fill(dest, value, len);
+}
+
+/// Is a copy or fill started by `mem_copy_dma` or `mem_fill_dma` still
+/// running?
+pub extern "C" fn mem_dma_busy() -> bool {
+	memdma::is_busy()
+}
+
+/// Reboot, turn the power off, or reset into the USB bootloader.
+///
+/// * `0` - reboot the whole machine, as if the reset button was pressed
+/// * `1` - ask the BMC to turn the main power off
+/// * `2` - reset into the USB bootloader, as for `reset_to_usb_boot`
+///
+/// Anything in the SD card's write cache is written out first. Only returns
+/// if something goes wrong (e.g. the BMC didn't turn the power off).
+pub extern "C" fn power_control(action: u8) -> common::Result<()> {
+	if action > 2 {
+		return common::Result::Err(common::Error::UnsupportedConfiguration(0));
+	}
+	if let Err(e) = block_cache::flush() {
+		warn!("Couldn't flush SD card before power change: {}", e);
+	}
+	match action {
+		0 => watchdog::reboot(),
+		2 => watchdog::reset_to_usb_boot(),
+		_ => {}
+	}
+	info!("Powering off");
+	if let Err(e) = bmc::write_register(bmc::Register::PowerControl, &[bmc::POWER_OFF]) {
+		warn!("BMC didn't take power off request: {}", e);
+		return common::Result::Err(common::Error::DeviceError(0));
+	}
+	// Give the rail a moment to drop
+	let start = ticks_ms();
+	while ticks_ms().wrapping_sub(start) < POWER_OFF_TIMEOUT_MS {
+		watchdog::feed();
+	}
+	common::Result::Err(common::Error::DeviceError(1))
+}
+
+/// Sleep until something happens - at the latest, the video interrupt at
+/// the end of the scan-line, which is never more than about 32 µs away.
+///
+/// Call this in any loop where the OS is waiting for input, to save power
+/// (and heat) at an idle prompt. Counts as calling the BIOS, for the
+/// watchdog.
+pub extern "C" fn power_idle() {
+	watchdog::feed();
+	idle();
+}
+
+/// Do the BIOS's background work: noticing SD cards and headphones going in
+/// and coming out, and the reads and writes of a PC using the SD card over
+/// USB.
+///
+/// `hid_get_event` does this too, but only every `BACKGROUND_POLL_MS`. Call
+/// this as well if you want the PC's reads and writes done faster, e.g. from
+/// the OS's main loop. It usually returns straight away, but while a PC is
+/// using the SD card it can take up to 20 ms. Counts as calling the BIOS,
+/// for the watchdog.
+pub extern "C" fn system_poll() {
+	watchdog::feed();
+	// This has to come before anything else reads the I/O expander
+	slot_irq::poll();
+	background_poll();
+}
+
+/// The background work shared by `system_poll` and `hid_get_event`. Call
+/// `slot_irq::poll` first.
+fn background_poll() {
+	if sdcard::poll_card_detect() {
+		block_cache::invalidate();
+		usb_storage::card_changed();
+	}
+	audio::poll_headphone_detect();
+	usb_storage::poll();
+}
+
+/// Fill a buffer with random bytes, from the hardware entropy source.
+///
+/// These are good enough to use as keys, but take a few milliseconds per 32
+/// bytes, so most programs should use them to seed their own generator.
+/// If the ring oscillator has stopped, this gives `DeviceError(0)`.
+pub extern "C" fn random_get(mut buffer: common::ApiBuffer) -> common::Result<()> {
+	let buffer = match buffer.as_mut_slice() {
+		Some(buffer) => buffer,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	match random::fill(buffer) {
+		Ok(()) => common::Result::Ok(()),
+		Err(random::Error::Stuck) => common::Result::Err(common::Error::DeviceError(0)),
+	}
+}
+
+/// Get this unit's serial number (the flash chip's unique ID) and board
+/// revision, e.g. to put in a bug report.
+///
+/// This blanks the picture for a moment.
+pub extern "C" fn system_id_get() -> common::Result<SystemId> {
+	match flash::unique_id() {
+		Ok(unique_id) => common::Result::Ok(SystemId {
+			unique_id,
+			board_revision: board::revision() as u8,
+		}),
+		Err(e) => common::Result::Err(flash_error(e)),
+	}
+}
+
+/// Read the BIOS's log messages: everything it logged at boot, then its
+/// most recent messages since the OS started.
+///
+/// These are encoded `defmt` frames, oldest first, which need the BIOS's
+/// ELF file to turn back into text (see `logger`). The boot messages are
+/// never pushed out by later ones. If the buffer is too small, you get the
+/// boot messages and then the newest of the rest. Returns how many bytes
+/// were copied; up to `logger::HISTORY_LEN`.
+pub extern "C" fn debug_log_read(mut buffer: common::ApiBuffer) -> common::Result<usize> {
+	let buffer = match buffer.as_mut_slice() {
+		Some(buffer) => buffer,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	common::Result::Ok(logger::read_history(buffer))
+}
+
+/// Get a description of the devices the BIOS found at boot: which serial
+/// port is which, whether the RTC, codec and BMC answered, the expansion
+/// slots, and their addresses and interrupts (see `sysdesc` for the
+/// format). Returns how many bytes were copied; at most `sysdesc::MAX_LEN`.
+///
+/// If the buffer is too small you get `UnsupportedConfiguration(1)`.
+pub extern "C" fn system_description_get(mut buffer: common::ApiBuffer) -> common::Result<usize> {
+	let buffer = match buffer.as_mut_slice() {
+		Some(buffer) => buffer,
+		None => return common::Result::Err(common::Error::UnsupportedConfiguration(0)),
+	};
+	match sysdesc::read(buffer) {
+		Ok(len) => common::Result::Ok(len),
+		Err(sysdesc::Error::BufferTooSmall) => {
+			common::Result::Err(common::Error::UnsupportedConfiguration(1))
+		}
+		Err(sysdesc::Error::NotReady) => common::Result::Err(common::Error::DeviceError(0)),
+	}
+}
+
+/// Called when DMA raises IRQ0; i.e. when a DMA transfer to the pixel FIFO or
+/// the timing FIFO has completed.
+#[interrupt]
+fn DMA_IRQ_0() {
+	unsafe {
+		vga::irq();
+	}
+}
+
+/// Called when DMA raises IRQ1; i.e. when one of the audio buffers has
+/// finished playing.
+#[interrupt]
+fn DMA_IRQ_1() {
+	unsafe {
+		audio::irq();
+	}
+}
+
+/// Called when the timer's first alarm goes off; i.e. when a beeper tone
+/// should stop.
+#[interrupt]
+fn TIMER_IRQ_0() {
+	unsafe {
+		beeper::irq();
+	}
+}
+
+/// Called when the USB device controller needs attention, or when we have
+/// queued bytes to send over USB.
+#[interrupt]
+fn USBCTRL_IRQ() {
+	unsafe {
+		usb::irq();
+	}
+}
+
+/// Called when Core 1 has sent us a message through the SIO FIFO.
+#[interrupt]
+fn SIO_IRQ_PROC0() {
+	unsafe {
+		mailbox::irq();
+	}
+}
+
+/// Called when UART1 has received some bytes.
+#[interrupt]
+fn UART1_IRQ() {
+	unsafe {
+		serial::irq();
 	}
 }
 
@@ -0,0 +1,154 @@
+//! # DMA memory copies for the Neotron Pico
+//!
+//! The Cortex-M0+ moves memory one load and one store at a time. A spare
+//! DMA channel can do it in the background, at up to one word per system
+//! clock, which is handy for moving framebuffers and disk buffers around.
+//!
+//! The OS starts a copy or a fill, and then calls `is_busy` until it's done.
+//! Starting another one first waits for the last one to finish. We use
+//! word transfers if the addresses and the length allow it, then
+//! half-words, then bytes.
+//!
+//! The channel runs flat out, but the DMA engine takes turns between its
+//! channels, so the video and audio channels (which are paced by their
+//! FIFOs) still get the bus when they need it.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use core::sync::atomic::{compiler_fence, Ordering};
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The DMA channel we use (`psram` has channels 9 and 10)
+const DMA_CHAN: usize = 11;
+
+/// In `CHx_CTRL_TRIG`: the channel is still running
+const DMA_CTRL_BUSY: u32 = 1 << 24;
+
+/// The TREQ_SEL value for a DMA channel which runs as fast as it can
+const DMA_TREQ_PERMANENT: u32 = 0x3F;
+
+/// The value a fill writes, repeated in every byte. The DMA engine reads it
+/// over and over, so it mustn't change while a fill is running.
+///
+/// Only accessed from Core 0 (when the channel is idle), and by the DMA
+/// engine.
+static mut FILL_VALUE: u32 = 0;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Start copying `len` bytes from `src` to `dest`, in the background.
+///
+/// # Safety
+///
+/// Both areas must be valid for `len` bytes, mustn't overlap, and must be
+/// left alone until `is_busy` returns false.
+pub unsafe fn start_copy(dest: *mut u8, src: *const u8, len: usize) {
+	wait();
+	let (size, count) = transfer_size(&[dest as usize, src as usize], len);
+	start(src as usize as u32, dest as usize as u32, count, size, true);
+}
+
+/// Start setting `len` bytes at `dest` to `value`, in the background.
+///
+/// # Safety
+///
+/// The area must be valid for `len` bytes, and must be left alone until
+/// `is_busy` returns false.
+pub unsafe fn start_fill(dest: *mut u8, value: u8, len: usize) {
+	wait();
+	FILL_VALUE = u32::from_ne_bytes([value; 4]);
+	let (size, count) = transfer_size(&[dest as usize], len);
+	start(
+		&FILL_VALUE as *const u32 as usize as u32,
+		dest as usize as u32,
+		count,
+		size,
+		false,
+	);
+}
+
+/// Is a copy or fill still running?
+pub fn is_busy() -> bool {
+	// Note (safety): We only read our own channel's registers.
+	let ch = unsafe { &(*pac::DMA::ptr()).ch[DMA_CHAN] };
+	let busy = ch.ch_trans_count.read().bits() != 0
+		|| (ch.ch_ctrl_trig.read().bits() & DMA_CTRL_BUSY) != 0;
+	// Don't let the compiler read the destination before we've looked
+	compiler_fence(Ordering::SeqCst);
+	busy
+}
+
+/// Wait for the last copy or fill to finish.
+pub fn wait() {
+	while is_busy() {}
+}
+
+/// Pick the widest transfer (as a `DATA_SIZE` value) that suits all the
+/// addresses and the length, and work out how many transfers we need.
+fn transfer_size(addresses: &[usize], len: usize) -> (u32, usize) {
+	let bits = addresses.iter().fold(len, |acc, address| acc | address);
+	if (bits % 4) == 0 {
+		(2, len / 4)
+	} else if (bits % 2) == 0 {
+		(1, len / 2)
+	} else {
+		(0, len)
+	}
+}
+
+/// Set our channel going.
+fn start(read: u32, write: u32, count: usize, size: u32, incr_read: bool) {
+	if count == 0 {
+		return;
+	}
+	// Make sure everything the CPU wrote to the source is really there
+	compiler_fence(Ordering::SeqCst);
+	// Note (safety): The video owns the DMA controller, but only uses its
+	// own channels and interrupt. Nothing else touches our channel.
+	let ch = unsafe { &(*pac::DMA::ptr()).ch[DMA_CHAN] };
+	ch.ch_read_addr.write(|w| unsafe { w.bits(read) });
+	ch.ch_write_addr.write(|w| unsafe { w.bits(write) });
+	ch.ch_trans_count.write(|w| unsafe { w.bits(count as u32) });
+	// Writing the control register through this alias starts the channel
+	ch.ch_ctrl_trig.write(|w| unsafe {
+		w.bits(
+			(1 << 0) // EN
+				| (size << 2) // DATA_SIZE
+				| (u32::from(incr_read) << 4) // INCR_READ
+				| (1 << 5) // INCR_WRITE
+				| ((DMA_CHAN as u32) << 11) // CHAIN_TO itself (nothing)
+				| (DMA_TREQ_PERMANENT << 15), // TREQ_SEL
+		)
+	});
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
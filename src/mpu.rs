@@ -0,0 +1,164 @@
+//! # Memory protection for the Neotron Pico
+//!
+//! Just before we jump to the OS, we set up Core 0's Memory Protection Unit
+//! so that a buggy OS program which writes somewhere it shouldn't gets a
+//! HardFault (and a crash report, see `crashlog`), rather than quietly
+//! breaking something which only shows up much later.
+//!
+//! | Region | Memory                              | Privileged | Unprivileged |
+//! |--------|-------------------------------------|------------|--------------|
+//! | 0      | Core 1 stack (SRAM bank 5)          | Read-only  | Read-only    |
+//! | 1      | BIOS statics, inc. VGA line buffers | Read/write | No access    |
+//!
+//! Core 1 has its own MPU, which we leave turned off, so it can still use
+//! its stack as normal - nothing on Core 0 should ever write to it.
+//!
+//! The OS calls the BIOS directly, so when the OS runs privileged (as
+//! Neotron OS does today) the MPU can't tell a write from the OS apart from
+//! a write from the BIOS, and region 1 can't stop it. It only catches
+//! programs that an OS has chosen to run unprivileged.
+//!
+//! Region 1 has to stay executable, as the routines that erase and program
+//! the flash (see `flash`) live in `.data.ram_func`, which is in the same
+//! block of SRAM as the statics.
+//!
+//! Everything else uses the default memory map, just as if the MPU was off.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use defmt::{debug, warn};
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The MPU region we use for Core 1's stack
+const CORE1_STACK_REGION: u32 = 0;
+
+/// The MPU region we use for the BIOS's statics
+const BIOS_RAM_REGION: u32 = 1;
+
+/// `MPU_CTRL.ENABLE`
+const CTRL_ENABLE: u32 = 1 << 0;
+
+/// `MPU_CTRL.PRIVDEFENA` - use the default memory map outside our regions
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+/// `MPU_RASR.ENABLE`
+const RASR_ENABLE: u32 = 1 << 0;
+
+/// `MPU_RASR.AP` for read-only, whether privileged or not
+const RASR_AP_READ_ONLY: u32 = 0b110 << 24;
+
+/// `MPU_RASR.AP` for read/write when privileged, and no access otherwise
+const RASR_AP_PRIVILEGED_ONLY: u32 = 0b001 << 24;
+
+/// `MPU_RASR.XN` - don't allow code to run from the region
+const RASR_XN: u32 = 1 << 28;
+
+/// `MPU_RASR` attributes for normal, shareable, write-through memory, like
+/// our SRAM (which has no cache, so they make no difference)
+const RASR_SRAM: u32 = (1 << 18) | (1 << 17);
+
+/// The smallest region the Cortex-M0+ MPU can have
+const MIN_REGION_SIZE: usize = 256;
+
+extern "C" {
+	static mut _core1_stack_bottom: usize;
+	static mut _core1_stack_len: usize;
+	static mut _bios_ram_start: usize;
+	static mut _bios_ram_len: usize;
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Turn on Core 0's MPU, with Core 1's stack made read-only and the BIOS's
+/// statics hidden from unprivileged code.
+///
+/// Call this just before jumping to the OS, once Core 1 is running.
+pub fn protect_bios_memory() {
+	// Note (safety): These are linker symbols - only their addresses mean
+	// anything.
+	let (stack_base, stack_len, ram_base, ram_len) = unsafe {
+		(
+			&_core1_stack_bottom as *const usize as usize,
+			&_core1_stack_len as *const usize as usize,
+			&_bios_ram_start as *const usize as usize,
+			&_bios_ram_len as *const usize as usize,
+		)
+	};
+	set_region(
+		CORE1_STACK_REGION,
+		stack_base,
+		stack_len,
+		RASR_AP_READ_ONLY | RASR_XN,
+	);
+	set_region(
+		BIOS_RAM_REGION,
+		ram_base,
+		ram_len,
+		// Not XN - the flash routines run from here
+		RASR_AP_PRIVILEGED_ONLY,
+	);
+	// Note (safety): Neither region stops Core 0 (which is privileged)
+	// writing anything it currently writes to.
+	unsafe {
+		let mpu = &*cortex_m::peripheral::MPU::ptr();
+		mpu.ctrl.write(CTRL_ENABLE | CTRL_PRIVDEFENA);
+	}
+	cortex_m::asm::dsb();
+	cortex_m::asm::isb();
+}
+
+/// Set up one MPU region, covering SRAM.
+///
+/// If the region isn't one the MPU can describe, we leave it turned off.
+fn set_region(number: u32, base: usize, len: usize, access: u32) {
+	// An MPU region must be a power of two in size, and start on a multiple
+	// of its size.
+	if !len.is_power_of_two() || len < MIN_REGION_SIZE || (base % len) != 0 {
+		warn!(
+			"Can't protect {} bytes at 0x{:08x} with MPU region {}",
+			len, base, number
+		);
+		return;
+	}
+	// `MPU_RASR.SIZE` is one less than log2 of the size
+	let size_field = (len.trailing_zeros() - 1) << 1;
+	// Note (safety): The MPU isn't turned on yet, so this can't fault.
+	unsafe {
+		let mpu = &*cortex_m::peripheral::MPU::ptr();
+		mpu.rnr.write(number);
+		mpu.rbar.write(base as u32);
+		mpu.rasr
+			.write(RASR_ENABLE | size_field | access | RASR_SRAM);
+	}
+	debug!("MPU region {}: {} bytes at 0x{:08x}", number, len, base);
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,800 @@
+//! # Neotron Bus driver for the Neotron Pico
+//!
+//! The Neotron Bus is the SPI bus shared by the Board Management Controller
+//! (BMC), the SD card slot and the expansion slots. SPI0 drives the clock and
+//! data lines (GPIO16, GPIO18 and GPIO19).
+//!
+//! There aren't enough spare GPIO pins for one chip-select per device, so
+//! GPIO17 selects an MCP23S17 I/O expander. Bits 0 to 2 of the expander's
+//! Port A drive a 3-to-8 decoder, and bit 3 drives the decoder's (active-low)
+//! enable input. Selecting a device is therefore one SPI write to the I/O
+//! expander.
+//!
+//! The other I/O expander pins are inputs, with pull-ups:
+//!
+//! * Port A, bits 4 to 7 - front-panel buttons 0 to 3 (active low)
+//! * Port B, bits 0 to 3 - interrupt requests from expansion slots 1 to 4
+//!   (active low)
+//! * Port B, bit 4 - the SD card slot's card-detect switch (low when there's
+//!   a card in)
+//! * Port B, bit 5 - the headphone socket's detect switch (low when there's
+//!   a plug in)
+//!
+//! Port B, bit 6 is an output, which turns the speaker amplifier on (high)
+//! or off (low). It starts high.
+//!
+//! The expander latches the slot interrupt requests for us (using its
+//! interrupt-on-change logic), so a short pulse isn't missed between polls.
+//! Its interrupt output isn't wired to the RP2040, so `slot_irq` collects the
+//! latched flags whenever the BIOS is polled.
+//!
+//! The OS can talk to the expansion slots (but not the BMC or the SD card)
+//! through `select` and `with_selected`. The BIOS's own transfers put the
+//! OS's selection back when they are done.
+//!
+//! A block of data can also be moved in the background, by DMA (see
+//! `with_device_then_dma`), leaving the device selected until it's done.
+//! Anything else that wants the bus waits for it to finish first. Big
+//! blocks can also be moved by DMA while the caller waits (see `read_dma`
+//! and `write_dma`), which is much quicker than feeding the SPI FIFOs a
+//! byte at a time.
+//!
+//! If we crash, `in_use` says whether the bus was part-way through
+//! something, in which case the crash handler leaves it alone.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::debug;
+use embedded_hal::blocking::spi::{Transfer as _, Write as _};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_time::rate::*;
+use rp_pico::hal::{self, pac};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The SPI peripheral that runs the Neotron Bus
+pub type Spi = hal::spi::Spi<hal::spi::Enabled, pac::SPI0, 8>;
+
+/// The GPIO pin that selects the I/O expander
+pub type IoExpanderChipSelect = hal::gpio::Pin<hal::gpio::bank0::Gpio17, hal::gpio::PushPullOutput>;
+
+/// The devices on the Neotron Bus.
+///
+/// The discriminant is the address we give the chip-select decoder.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Device {
+	/// The Board Management Controller
+	Bmc = 0,
+	/// The built-in SD card slot
+	SdCard = 1,
+	/// Expansion Slot 1
+	Slot1 = 2,
+	/// Expansion Slot 2
+	Slot2 = 3,
+	/// Expansion Slot 3
+	Slot3 = 4,
+	/// Expansion Slot 4
+	Slot4 = 5,
+}
+
+/// The ways in which an OS transfer on the bus can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The bus hasn't been set up yet
+	NoBus,
+	/// The OS hasn't selected a device
+	NothingSelected,
+}
+
+/// A block of data for the DMA engine to move, once the closure given to
+/// `with_device_then_dma` has finished.
+///
+/// Either way, `extra` filler bytes (0xFF) follow the data. Whatever comes
+/// back during them is thrown away, except the last byte, which
+/// `dma_finished` gives you.
+pub enum Dma {
+	/// Clock `len` bytes in from the device, into `data`
+	Read {
+		data: *mut u8,
+		len: usize,
+		extra: usize,
+	},
+	/// Clock `len` bytes out to the device, from `data`
+	Write {
+		data: *const u8,
+		len: usize,
+		extra: usize,
+	},
+}
+
+/// Everything we need to drive the bus.
+struct Bus {
+	/// The SPI peripheral, with SCK, COPI and CIPO attached
+	spi: Spi,
+	/// The chip-select for the I/O expander
+	iox_cs: IoExpanderChipSelect,
+	/// The clock feeding the SPI peripheral, in Hz
+	peripheral_clock_hz: u32,
+	/// The device the OS has selected, if any
+	selected: Option<Device>,
+	/// If a DMA transfer is running, the channel which finishes last
+	dma_channel: Option<usize>,
+}
+
+/// The bus, taken by `take_bus`. `IN_USE` stays set until this is dropped.
+struct BusGuard {
+	bus: &'static mut Bus,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The SPI clock speed everything on the bus can cope with
+pub const DEFAULT_CLOCK_HZ: u32 = 2_000_000;
+
+/// Holds the bus once `init` has been called.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut BUS: Option<Bus> = None;
+
+/// Set while something holds the bus, so a crash handler can tell it might
+/// have stopped half-way through a transfer.
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// DMA channel which feeds the SPI transmit FIFO (the video uses channels 0
+/// and 1, and the audio 2 and 3)
+const DMA_CHAN_TX: usize = 4;
+
+/// DMA channel which empties the SPI receive FIFO
+const DMA_CHAN_RX: usize = 5;
+
+/// DMA channel for the filler bytes after the data
+const DMA_CHAN_EXTRA: usize = 6;
+
+/// The DREQ for SPI0's transmit FIFO
+const DREQ_SPI0_TX: u32 = 16;
+
+/// The DREQ for SPI0's receive FIFO
+const DREQ_SPI0_RX: u32 = 17;
+
+/// The DMA control bit which moves the read address after each transfer
+const DMA_CTRL_INCR_READ: u32 = 1 << 4;
+
+/// The DMA control bit which moves the write address after each transfer
+const DMA_CTRL_INCR_WRITE: u32 = 1 << 5;
+
+/// The BUSY bit in a DMA channel's control register
+const DMA_CTRL_BUSY: u32 = 1 << 24;
+
+/// The DMA engine sends this, when there's no data to send.
+static DMA_FILL: u8 = 0xFF;
+
+/// The DMA engine puts received bytes here, when there's nowhere else for
+/// them to go.
+///
+/// Only accessed by the DMA engine, and by Core 0 once it has finished.
+static mut DMA_SINK: u8 = 0xFF;
+
+/// MCP23S17 opcode for a register write (hardware address 0)
+const IOX_OPCODE_WRITE: u8 = 0x40;
+
+/// MCP23S17 opcode for a register read (hardware address 0)
+const IOX_OPCODE_READ: u8 = 0x41;
+
+/// MCP23S17 Port A direction register (in `IOCON.BANK = 0` mode)
+const IOX_REG_IODIRA: u8 = 0x00;
+
+/// MCP23S17 Port B direction register (in `IOCON.BANK = 0` mode)
+const IOX_REG_IODIRB: u8 = 0x01;
+
+/// MCP23S17 Port A pull-up register (in `IOCON.BANK = 0` mode)
+const IOX_REG_GPPUA: u8 = 0x0C;
+
+/// MCP23S17 Port B pull-up register (in `IOCON.BANK = 0` mode)
+const IOX_REG_GPPUB: u8 = 0x0D;
+
+/// MCP23S17 Port B interrupt-on-change enable register (in `IOCON.BANK = 0`
+/// mode)
+const IOX_REG_GPINTENB: u8 = 0x05;
+
+/// MCP23S17 Port B default compare register (in `IOCON.BANK = 0` mode)
+const IOX_REG_DEFVALB: u8 = 0x07;
+
+/// MCP23S17 Port B interrupt control register (in `IOCON.BANK = 0` mode)
+const IOX_REG_INTCONB: u8 = 0x09;
+
+/// MCP23S17 Port A interrupt flag register (in `IOCON.BANK = 0` mode). Port
+/// B follows.
+const IOX_REG_INTFA: u8 = 0x0E;
+
+/// MCP23S17 Port A interrupt capture register (in `IOCON.BANK = 0` mode).
+/// Port B follows. Reading it clears the interrupt flags.
+const IOX_REG_INTCAPA: u8 = 0x10;
+
+/// MCP23S17 Port A input register (in `IOCON.BANK = 0` mode). Port B follows.
+const IOX_REG_GPIOA: u8 = 0x12;
+
+/// MCP23S17 Port A output latch register (in `IOCON.BANK = 0` mode)
+const IOX_REG_OLATA: u8 = 0x14;
+
+/// MCP23S17 Port B output latch register (in `IOCON.BANK = 0` mode)
+const IOX_REG_OLATB: u8 = 0x15;
+
+/// Setting this bit in Port A disables the chip-select decoder, so no device
+/// is selected.
+const IOX_DECODER_DISABLE: u8 = 1 << 3;
+
+/// These Port A pins are inputs, not outputs.
+const IOX_PORTA_INPUTS: u8 = 0xF0;
+
+/// These Port B pins are the slot interrupt inputs. The rest are left as
+/// inputs.
+const IOX_PORTB_INPUTS: u8 = 0x0F;
+
+/// This Port B pin is the SD card slot's card-detect switch.
+const IOX_PORTB_CARD_DETECT: u8 = 1 << 4;
+
+/// This Port B pin is the headphone socket's detect switch.
+const IOX_PORTB_HEADPHONE_DETECT: u8 = 1 << 5;
+
+/// This Port B pin turns the speaker amplifier on.
+const IOX_PORTB_SPEAKER_ENABLE: u8 = 1 << 6;
+
+/// The Port B pins which are low when active.
+const IOX_PORTB_ACTIVE_LOW: u8 =
+	IOX_PORTB_INPUTS | IOX_PORTB_CARD_DETECT | IOX_PORTB_HEADPHONE_DETECT;
+
+/// Bit 0 of the front-panel buttons, in the value from `read_inputs`.
+const INPUT_BUTTON0: u16 = 1 << 4;
+
+/// The SD card-detect switch, in the value from `read_inputs`.
+const INPUT_CARD_DETECT: u16 = (IOX_PORTB_CARD_DETECT as u16) << 8;
+
+/// The headphone detect switch, in the value from `read_inputs`.
+const INPUT_HEADPHONE_DETECT: u16 = (IOX_PORTB_HEADPHONE_DETECT as u16) << 8;
+
+/// The interrupt from expansion slot 1, in the value from `read_inputs`.
+const INPUT_SLOT1_IRQ: u16 = 1 << 8;
+
+/// How many expansion slots there are
+pub const NUM_SLOTS: u8 = 4;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Take ownership of the SPI bus and set up the chip-select decoder.
+///
+/// No device is selected when this function returns. The SPI peripheral
+/// should be running at `DEFAULT_CLOCK_HZ`.
+pub fn init(spi: Spi, mut iox_cs: IoExpanderChipSelect, peripheral_clock_hz: u32) {
+	iox_cs.set_high().unwrap();
+	let mut bus = Bus {
+		spi,
+		iox_cs,
+		peripheral_clock_hz,
+		selected: None,
+		dma_channel: None,
+	};
+	// Port A is decoder outputs at the bottom and pulled-up inputs at the top
+	bus.iox_write(IOX_REG_IODIRA, IOX_PORTA_INPUTS);
+	bus.iox_write(IOX_REG_GPPUA, IOX_PORTA_INPUTS);
+	// Port B is all inputs apart from the speaker enable, which starts
+	// high. The slot interrupts are flagged whenever they differ from their
+	// idle (high) level.
+	bus.iox_write(IOX_REG_OLATB, IOX_PORTB_SPEAKER_ENABLE);
+	bus.iox_write(IOX_REG_IODIRB, !IOX_PORTB_SPEAKER_ENABLE);
+	bus.iox_write(IOX_REG_GPPUB, IOX_PORTB_ACTIVE_LOW);
+	bus.iox_write(IOX_REG_DEFVALB, IOX_PORTB_INPUTS);
+	bus.iox_write(IOX_REG_INTCONB, IOX_PORTB_INPUTS);
+	bus.iox_write(IOX_REG_GPINTENB, IOX_PORTB_INPUTS);
+	bus.iox_write(IOX_REG_OLATA, IOX_DECODER_DISABLE);
+	unsafe {
+		BUS = Some(bus);
+	}
+	debug!("Neotron Bus OK");
+}
+
+/// Select a device on the bus, run the given closure, then de-select the
+/// device.
+///
+/// Returns `None` if the bus hasn't been initialised yet.
+pub fn with_device<F, T>(device: Device, f: F) -> Option<T>
+where
+	F: FnOnce(&mut Spi) -> T,
+{
+	with_device_at(device, DEFAULT_CLOCK_HZ, f)
+}
+
+/// Select a device on the bus, run the given closure with the SPI clock at
+/// the given speed, then de-select the device.
+///
+/// The I/O expander is always driven at `DEFAULT_CLOCK_HZ`, so only the
+/// selected device sees the other speed. Returns `None` if the bus hasn't
+/// been initialised yet.
+pub fn with_device_at<F, T>(device: Device, clock_hz: u32, f: F) -> Option<T>
+where
+	F: FnOnce(&mut Spi) -> T,
+{
+	let mut bus = take_bus()?;
+	bus.iox_write(IOX_REG_OLATA, device as u8);
+	if clock_hz != DEFAULT_CLOCK_HZ {
+		bus.set_clock_speed(clock_hz);
+	}
+	let result = f(&mut bus.spi);
+	if clock_hz != DEFAULT_CLOCK_HZ {
+		bus.set_clock_speed(DEFAULT_CLOCK_HZ);
+	}
+	bus.restore_selection();
+	Some(result)
+}
+
+/// Select a device on the bus, and run the given closure with the SPI clock
+/// at the given speed. If the closure gives us a block of data, the DMA
+/// engine moves it in the background, and the device stays selected until
+/// it's done. Otherwise, the device is de-selected.
+///
+/// Returns `None` if the bus hasn't been initialised yet.
+///
+/// # Safety
+///
+/// The buffer in the `Dma` must stay valid, and otherwise untouched, until
+/// `dma_finished` returns `Some`, or anything else uses the bus.
+pub unsafe fn with_device_then_dma<F, E>(
+	device: Device,
+	clock_hz: u32,
+	f: F,
+) -> Option<Result<(), E>>
+where
+	F: FnOnce(&mut Spi) -> Result<Dma, E>,
+{
+	let mut bus = take_bus()?;
+	bus.iox_write(IOX_REG_OLATA, device as u8);
+	if clock_hz != DEFAULT_CLOCK_HZ {
+		bus.set_clock_speed(clock_hz);
+	}
+	match f(&mut bus.spi) {
+		Ok(dma) => {
+			bus.start_dma(&dma);
+			Some(Ok(()))
+		}
+		Err(e) => {
+			if clock_hz != DEFAULT_CLOCK_HZ {
+				bus.set_clock_speed(DEFAULT_CLOCK_HZ);
+			}
+			bus.restore_selection();
+			Some(Err(e))
+		}
+	}
+}
+
+/// Has the DMA transfer started by `with_device_then_dma` finished?
+///
+/// Returns `None` while it's still going. Otherwise, the device is
+/// de-selected, and we return the last byte clocked in.
+pub fn dma_finished() -> Option<u8> {
+	// Note (safety): The bus is only used from Core 0 and never from an
+	// interrupt, so nothing else can be holding this reference.
+	let bus = unsafe { BUS.as_mut()? };
+	if let Some(channel) = bus.dma_channel {
+		if !dma_channel_done(channel) {
+			return None;
+		}
+		IN_USE.store(true, Ordering::SeqCst);
+		bus.finish_dma();
+		IN_USE.store(false, Ordering::SeqCst);
+	}
+	// Note (safety): The DMA engine has finished with it.
+	Some(unsafe { core::ptr::read_volatile(&DMA_SINK) })
+}
+
+/// Clock bytes in from the selected device, into `buffer`, using the DMA
+/// engine, and wait until they are all in.
+///
+/// Only the closure given to `with_device` (and friends) has the `Spi`, so
+/// this can only be called while we hold the bus.
+pub fn read_dma(_spi: &mut Spi, buffer: &mut [u8]) {
+	run_dma(&Dma::Read {
+		data: buffer.as_mut_ptr(),
+		len: buffer.len(),
+		extra: 0,
+	});
+}
+
+/// Clock the bytes in `data` out to the selected device, using the DMA
+/// engine, and wait until they have all gone.
+///
+/// As for `read_dma`.
+pub fn write_dma(_spi: &mut Spi, data: &[u8]) {
+	run_dma(&Dma::Write {
+		data: data.as_ptr(),
+		len: data.len(),
+		extra: 0,
+	});
+}
+
+/// Select an expansion slot (or nothing) on behalf of the OS.
+///
+/// The slot stays selected, between `with_selected` calls, until the OS
+/// selects something else.
+pub fn select(device: Option<Device>) -> Result<(), Error> {
+	let mut bus = take_bus().ok_or(Error::NoBus)?;
+	bus.selected = device;
+	bus.restore_selection();
+	Ok(())
+}
+
+/// Run the given closure on the device the OS has selected.
+pub fn with_selected<F, T>(f: F) -> Result<T, Error>
+where
+	F: FnOnce(&mut Spi) -> T,
+{
+	let mut bus = take_bus().ok_or(Error::NoBus)?;
+	if bus.selected.is_none() {
+		return Err(Error::NothingSelected);
+	}
+	Ok(f(&mut bus.spi))
+}
+
+/// Is the bus part-way through something?
+///
+/// True while anything holds the bus, or a background DMA transfer is
+/// running. A crash handler must leave the bus alone if so, as whatever
+/// was using it stopped at an unknown point.
+pub fn in_use() -> bool {
+	// Note (safety): We only read the DMA channel, and the bus is only
+	// changed from Core 0's main thread, which has stopped if we're
+	// handling a crash.
+	IN_USE.load(Ordering::SeqCst)
+		|| unsafe { BUS.as_ref() }.map_or(false, |bus| bus.dma_channel.is_some())
+}
+
+/// Get the bus, once any DMA transfer on it has finished.
+///
+/// `in_use` says true until the returned guard is dropped. Returns `None`
+/// if the bus hasn't been initialised yet.
+fn take_bus() -> Option<BusGuard> {
+	// Note (safety): The bus is only used from Core 0 and never from an
+	// interrupt, so nothing else can be holding this reference.
+	let bus = unsafe { BUS.as_mut()? };
+	IN_USE.store(true, Ordering::SeqCst);
+	bus.finish_dma();
+	Some(BusGuard { bus })
+}
+
+/// Move a block of data by DMA, and wait for it to finish.
+///
+/// The caller must hold the bus, and no background transfer can be running
+/// (`take_bus` finished it).
+fn run_dma(dma: &Dma) {
+	let channel = start_dma_channels(dma);
+	while !dma_channel_done(channel) {
+		core::hint::spin_loop();
+	}
+	stop_spi_dma();
+}
+
+/// Set up our DMA channels to move a block of data (see `Dma`) through the
+/// SPI FIFOs, and start them.
+///
+/// Returns the channel which finishes last.
+fn start_dma_channels(dma: &Dma) -> usize {
+	// Note (safety): We only touch the DMA enables, and the data register,
+	// which the HAL isn't using while we hold the bus.
+	let spi = unsafe { &*pac::SPI0::ptr() };
+	let fifo = spi.sspdr.as_ptr() as usize as u32;
+	let fill = &DMA_FILL as *const u8 as usize as u32;
+	// Note (safety): We only take the address.
+	let sink = unsafe { &mut DMA_SINK as *mut u8 as usize as u32 };
+	let last_channel = match *dma {
+		Dma::Read { data, len, extra } => {
+			let rx_chain = if extra > 0 {
+				DMA_CHAN_EXTRA
+			} else {
+				DMA_CHAN_RX
+			};
+			dma_channel_setup(
+				DMA_CHAN_TX,
+				fill,
+				fifo,
+				len + extra,
+				dma_ctrl(DMA_CHAN_TX, DREQ_SPI0_TX),
+			);
+			dma_channel_setup(
+				DMA_CHAN_RX,
+				fifo,
+				data as usize as u32,
+				len,
+				DMA_CTRL_INCR_WRITE | dma_ctrl(rx_chain, DREQ_SPI0_RX),
+			);
+			dma_channel_setup(
+				DMA_CHAN_EXTRA,
+				fifo,
+				sink,
+				extra,
+				dma_ctrl(DMA_CHAN_EXTRA, DREQ_SPI0_RX),
+			);
+			rx_chain
+		}
+		Dma::Write { data, len, extra } => {
+			let tx_chain = if extra > 0 {
+				DMA_CHAN_EXTRA
+			} else {
+				DMA_CHAN_TX
+			};
+			dma_channel_setup(
+				DMA_CHAN_TX,
+				data as usize as u32,
+				fifo,
+				len,
+				DMA_CTRL_INCR_READ | dma_ctrl(tx_chain, DREQ_SPI0_TX),
+			);
+			dma_channel_setup(
+				DMA_CHAN_EXTRA,
+				fill,
+				fifo,
+				extra,
+				dma_ctrl(DMA_CHAN_EXTRA, DREQ_SPI0_TX),
+			);
+			dma_channel_setup(
+				DMA_CHAN_RX,
+				fifo,
+				sink,
+				len + extra,
+				dma_ctrl(DMA_CHAN_RX, DREQ_SPI0_RX),
+			);
+			DMA_CHAN_RX
+		}
+	};
+	// TXDMAE and RXDMAE
+	spi.sspdmacr.write(|w| unsafe { w.bits(0b11) });
+	// Note (safety): As above.
+	let dma_regs = unsafe { &*pac::DMA::ptr() };
+	dma_regs
+		.multi_chan_trigger
+		.write(|w| unsafe { w.bits((1 << DMA_CHAN_TX) | (1 << DMA_CHAN_RX)) });
+	last_channel
+}
+
+/// Stop the SPI peripheral asking the DMA engine for data, once a transfer
+/// has finished.
+fn stop_spi_dma() {
+	// Note (safety): We only touch the DMA enables.
+	let spi = unsafe { &*pac::SPI0::ptr() };
+	spi.sspdmacr.write(|w| unsafe { w.bits(0) });
+}
+
+/// Has this DMA channel moved all its bytes?
+fn dma_channel_done(channel: usize) -> bool {
+	// Note (safety): We only read our own channel's registers.
+	let ch = unsafe { &(*pac::DMA::ptr()).ch[channel] };
+	ch.ch_trans_count.read().bits() == 0 && (ch.ch_ctrl_trig.read().bits() & DMA_CTRL_BUSY) == 0
+}
+
+/// Set up a DMA channel, without starting it. A channel which chains to
+/// itself doesn't chain to anything.
+fn dma_channel_setup(channel: usize, read: u32, write: u32, count: usize, ctrl: u32) {
+	// Note (safety): The video owns the DMA controller, but only uses its
+	// own channels and interrupt. Nothing else touches our channels.
+	let ch = unsafe { &(*pac::DMA::ptr()).ch[channel] };
+	ch.ch_read_addr.write(|w| unsafe { w.bits(read) });
+	ch.ch_write_addr.write(|w| unsafe { w.bits(write) });
+	ch.ch_trans_count.write(|w| unsafe { w.bits(count as u32) });
+	// Write the control register through the alias which doesn't trigger
+	// the channel.
+	ch.ch_al1_ctrl.write(|w| unsafe {
+		w.bits(
+			// EN, with DATA_SIZE (bits 2 and 3) of zero meaning bytes
+			(1 << 0) | ctrl,
+		)
+	});
+}
+
+/// The DMA control bits for chaining to a channel, and waiting on a DREQ.
+const fn dma_ctrl(chain_to: usize, dreq: u32) -> u32 {
+	((chain_to as u32) << 11) | (dreq << 15)
+}
+
+/// Get the expansion slot with the given number (1 to `NUM_SLOTS`).
+pub const fn slot(number: u8) -> Option<Device> {
+	match number {
+		1 => Some(Device::Slot1),
+		2 => Some(Device::Slot2),
+		3 => Some(Device::Slot3),
+		4 => Some(Device::Slot4),
+		_ => None,
+	}
+}
+
+/// Run the given closure with no device selected.
+///
+/// Some devices (e.g. SD cards) need clock pulses while they are not
+/// selected. Returns `None` if the bus hasn't been initialised yet.
+pub fn with_no_device<F, T>(f: F) -> Option<T>
+where
+	F: FnOnce(&mut Spi) -> T,
+{
+	let mut bus = take_bus()?;
+	bus.iox_write(IOX_REG_OLATA, IOX_DECODER_DISABLE);
+	let result = f(&mut bus.spi);
+	bus.restore_selection();
+	Some(result)
+}
+
+/// Change the SPI clock speed.
+///
+/// This affects every device on the bus (including the I/O expander), so put
+/// it back to `DEFAULT_CLOCK_HZ` when you are done.
+pub fn set_clock_speed(clock_hz: u32) {
+	if let Some(mut bus) = take_bus() {
+		bus.set_clock_speed(clock_hz);
+	}
+}
+
+/// Read all the I/O expander's input pins, with Port A in the bottom byte
+/// and Port B in the top byte.
+///
+/// Inputs are inverted as required, so a set bit always means 'active'. Use
+/// the `input_xxx` functions to find the bit you want. Returns `None` if the
+/// bus hasn't been initialised yet.
+pub fn read_inputs() -> Option<u16> {
+	let mut bus = take_bus()?;
+	let raw = bus.iox_read16(IOX_REG_GPIOA);
+	// The buttons, interrupt requests and detect switches are all active-low
+	Some(!raw & u16::from_le_bytes([IOX_PORTA_INPUTS, IOX_PORTB_ACTIVE_LOW]))
+}
+
+/// Turn the speaker amplifier on or off.
+///
+/// Does nothing if the bus hasn't been initialised yet.
+pub fn set_speaker_enable(enabled: bool) {
+	if let Some(mut bus) = take_bus() {
+		let value = if enabled { IOX_PORTB_SPEAKER_ENABLE } else { 0 };
+		bus.iox_write(IOX_REG_OLATB, value);
+	}
+}
+
+/// Read and clear the expansion slot interrupts the I/O expander has
+/// latched since we last asked. Slot 1 is the lowest bit.
+///
+/// Reading the inputs with `read_inputs` also clears the latch, so call this
+/// first. Returns `None` if the bus hasn't been initialised yet.
+pub fn take_slot_irq_flags() -> Option<u8> {
+	let mut bus = take_bus()?;
+	let flags = bus.iox_read16(IOX_REG_INTFA);
+	let _ = bus.iox_read16(IOX_REG_INTCAPA);
+	Some(input_slot_irqs(flags))
+}
+
+/// The bit in `read_inputs` for the given front-panel button (0 to 3).
+pub const fn input_button(idx: usize) -> u16 {
+	INPUT_BUTTON0 << idx
+}
+
+/// The bit in `read_inputs` which says there's a card in the SD card slot.
+pub const fn input_card_detect() -> u16 {
+	INPUT_CARD_DETECT
+}
+
+/// The bit in `read_inputs` which says there's a plug in the headphone
+/// socket.
+pub const fn input_headphone_detect() -> u16 {
+	INPUT_HEADPHONE_DETECT
+}
+
+/// The bits in `read_inputs` for the expansion slot interrupts. Slot 1 is
+/// the lowest bit.
+pub const fn input_slot_irqs(value: u16) -> u8 {
+	((value & ((IOX_PORTB_INPUTS as u16) << 8)) / INPUT_SLOT1_IRQ) as u8
+}
+
+impl Bus {
+	/// Start the DMA engine moving a block of data (see `Dma`), in the
+	/// background.
+	fn start_dma(&mut self, dma: &Dma) {
+		self.dma_channel = Some(start_dma_channels(dma));
+	}
+
+	/// Wait for any DMA transfer to finish, then put the bus back how we
+	/// found it.
+	fn finish_dma(&mut self) {
+		let channel = match self.dma_channel.take() {
+			Some(channel) => channel,
+			None => return,
+		};
+		while !dma_channel_done(channel) {
+			core::hint::spin_loop();
+		}
+		stop_spi_dma();
+		self.set_clock_speed(DEFAULT_CLOCK_HZ);
+		self.restore_selection();
+	}
+
+	/// Change the SPI clock speed. The peripheral picks the nearest speed it
+	/// can do, without going over.
+	fn set_clock_speed(&mut self, clock_hz: u32) {
+		let _ = self
+			.spi
+			.set_baudrate(self.peripheral_clock_hz.Hz(), clock_hz.Hz());
+	}
+
+	/// Point the chip-select decoder at whatever the OS has selected, or at
+	/// nothing.
+	fn restore_selection(&mut self) {
+		let value = match self.selected {
+			Some(device) => device as u8,
+			None => IOX_DECODER_DISABLE,
+		};
+		self.iox_write(IOX_REG_OLATA, value);
+	}
+
+	/// Write a value to one of the I/O expander's registers.
+	fn iox_write(&mut self, register: u8, value: u8) {
+		self.iox_cs.set_low().unwrap();
+		// Writes to the SPI peripheral are infallible on the RP2040
+		let _ = self.spi.write(&[IOX_OPCODE_WRITE, register, value]);
+		self.iox_cs.set_high().unwrap();
+	}
+
+	/// Read a pair of the I/O expander's registers (e.g. GPIOA and GPIOB).
+	///
+	/// The first register is in the bottom byte.
+	fn iox_read16(&mut self, register: u8) -> u16 {
+		let mut buffer = [IOX_OPCODE_READ, register, 0x00, 0x00];
+		self.iox_cs.set_low().unwrap();
+		// Transfers on the SPI peripheral are infallible on the RP2040
+		let _ = self.spi.transfer(&mut buffer);
+		self.iox_cs.set_high().unwrap();
+		u16::from_le_bytes([buffer[2], buffer[3]])
+	}
+}
+
+impl core::ops::Deref for BusGuard {
+	type Target = Bus;
+
+	fn deref(&self) -> &Bus {
+		self.bus
+	}
+}
+
+impl core::ops::DerefMut for BusGuard {
+	fn deref_mut(&mut self) -> &mut Bus {
+		self.bus
+	}
+}
+
+impl Drop for BusGuard {
+	fn drop(&mut self) {
+		IN_USE.store(false, Ordering::SeqCst);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
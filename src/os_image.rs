@@ -0,0 +1,353 @@
+//! # OS image checking for the Neotron Pico
+//!
+//! The OS in flash has a header in the sector after its slot
+//! (`FLASH_OS_HEADER` in `memory.x`), giving its length and CRC-32. The
+//! header for the OS built in to the BIOS is worked out by `build.rs`. An
+//! OS image sent over the serial port can start with its own header, which
+//! we check the rest of the file against before we erase anything; if it
+//! doesn't, `write` makes one. Either way, we read the image back out of
+//! flash before we write the header, so a bad write can't get a good
+//! header.
+//!
+//! We check the image against its header before we jump into it, so a
+//! damaged image (or a transfer that was cut short) gets the recovery
+//! console rather than a crash.
+//!
+//! The image may be compressed (see `lz4`), in which case it is unpacked
+//! into RAM at the load address, and run from there. Otherwise it runs
+//! from flash.
+//!
+//! | Offset | Size | Contents                                      |
+//! |:-------|:-----|:----------------------------------------------|
+//! | 0      | 8    | The magic bytes `NEOOSHDR`                    |
+//! | 8      | 4    | Image length in flash, in bytes               |
+//! | 12     | 4    | CRC-32 (IEEE) of the image in flash           |
+//! | 16     | 4    | Compression: 0 for none, 1 for an LZ4 block   |
+//! | 20     | 4    | Load address (compressed images only)         |
+//! | 24     | 4    | Unpacked length (compressed images only)      |
+//!
+//! All values are little-endian.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{bootslot, flash, lz4};
+use defmt::{debug, warn};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The header which describes the OS image in flash.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Header {
+	/// Always `HEADER_MAGIC`
+	magic: [u8; 8],
+	/// How long the image is, in bytes
+	length: u32,
+	/// The CRC-32 (IEEE) of the image
+	crc32: u32,
+	/// How the image is compressed (see `Compression`)
+	compression: u32,
+	/// Where a compressed image is unpacked to
+	load_address: u32,
+	/// How long a compressed image is once it's unpacked
+	unpacked_length: u32,
+}
+
+/// How the OS image is stored in flash.
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Compression {
+	/// As-is, to be run from flash
+	None = 0,
+	/// As one LZ4 block, to be unpacked into RAM
+	Lz4 = 1,
+}
+
+/// The ways in which the OS image can be bad, or fail to be written.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// There's no header (say, because writing a new image didn't finish)
+	NoHeader,
+	/// The header says the image is empty, or bigger than the OS slot
+	BadLength,
+	/// The image doesn't match the CRC in the header
+	BadCrc,
+	/// The header gives a compression we don't understand, or a load
+	/// address outside the OS RAM
+	BadHeader,
+	/// The compressed image wouldn't unpack
+	BadCompression,
+	/// Erasing or programming the flash failed
+	Flash(flash::Error),
+	/// The image we read back from flash isn't the one we wrote
+	VerifyFailed,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The first eight bytes of a valid header
+const HEADER_MAGIC: [u8; 8] = *b"NEOOSHDR";
+
+/// How many bytes a header takes up
+const HEADER_LEN: usize = core::mem::size_of::<Header>();
+
+extern "C" {
+	static mut _flash_os_start: u32;
+	static mut _flash_os_header_start: u32;
+	static mut _ram_os_start: u32;
+	static mut _ram_os_len: u32;
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Check the OS image in flash against its header.
+///
+/// Returns the header.
+pub fn check() -> Result<Header, Error> {
+	// Note (safety): The header sector is always mapped. We read it with a
+	// volatile read, as the compiler knows what the built-in header holds,
+	// and it might not be what's in flash now.
+	let header =
+		unsafe { core::ptr::read_volatile(&_flash_os_header_start as *const u32 as *const Header) };
+	if header.magic != HEADER_MAGIC {
+		return Err(Error::NoHeader);
+	}
+	let length = header.length as usize;
+	if length == 0 || length > flash::os_region().length as usize {
+		return Err(Error::BadLength);
+	}
+	// Note (safety): We checked the image fits in the OS slot, which is
+	// always mapped.
+	let image =
+		unsafe { core::slice::from_raw_parts(&_flash_os_start as *const u32 as *const u8, length) };
+	let crc32 = bootslot::crc32(image);
+	if crc32 != header.crc32 {
+		warn!(
+			"OS image CRC is {:08x}, expected {:08x}",
+			crc32, header.crc32
+		);
+		return Err(Error::BadCrc);
+	}
+	debug!("OS image OK ({} bytes)", length);
+	Ok(header)
+}
+
+/// Check the OS image in flash, and unpack it if it's compressed.
+///
+/// Returns where the image starts. Like any OS image, its first word points
+/// to the OS start function.
+pub fn load() -> Result<*const u32, Error> {
+	let header = check()?;
+	// Note (safety): We're only taking the addresses of the linker symbols.
+	let flash_start = unsafe { &_flash_os_start as *const u32 };
+	let ram_start = unsafe { &mut _ram_os_start as *mut u32 as usize };
+	let ram_len = unsafe { &mut _ram_os_len as *const u32 as usize };
+	match header.compression {
+		c if c == Compression::None as u32 => Ok(flash_start),
+		c if c == Compression::Lz4 as u32 => {
+			let load_address = header.load_address as usize;
+			let unpacked_length = header.unpacked_length as usize;
+			match load_address.checked_add(unpacked_length) {
+				Some(end) if load_address >= ram_start && end <= ram_start + ram_len => {}
+				_ => return Err(Error::BadHeader),
+			}
+			// Note (safety): We checked the image fits in the OS slot (in
+			// `check`), and that the output fits in the OS RAM, which
+			// nothing else is using until the OS starts.
+			let (input, output) = unsafe {
+				(
+					core::slice::from_raw_parts(flash_start as *const u8, header.length as usize),
+					core::slice::from_raw_parts_mut(load_address as *mut u8, unpacked_length),
+				)
+			};
+			match lz4::decompress(input, output) {
+				Ok(len) if len == unpacked_length => {
+					debug!("Unpacked OS image to 0x{:08x}", load_address);
+					Ok(load_address as *const u32)
+				}
+				Ok(_) | Err(_) => Err(Error::BadCompression),
+			}
+		}
+		_ => Err(Error::BadHeader),
+	}
+}
+
+/// Replace the OS image in flash with a new one, along with its header.
+///
+/// If `received` starts with a header, the image is what follows it, and
+/// must match it. Otherwise `received` is all image, uncompressed, and we
+/// make a header for it. The old header is erased first, so if we don't
+/// finish, `check` fails rather than passing a half-written image.
+///
+/// # Safety
+///
+/// As for `flash::program_os`.
+pub unsafe fn write(received: &[u8]) -> Result<(), Error> {
+	let (header, image) = match split_header(received)? {
+		Some(found) => found,
+		None => (
+			Header::new(
+				received.len() as u32,
+				bootslot::crc32(received),
+				Compression::None,
+				0,
+				received.len() as u32,
+			),
+			received,
+		),
+	};
+	let header_offset = (&_flash_os_header_start as *const u32 as usize) - flash::XIP_BASE;
+	flash::erase(header_offset, flash::SECTOR_SIZE).map_err(Error::Flash)?;
+	flash::program_os(image).map_err(Error::Flash)?;
+	// Note (safety): `program_os` checked the image fits in the OS slot,
+	// which is always mapped, and the XIP cache was flushed after writing.
+	let written =
+		core::slice::from_raw_parts(&_flash_os_start as *const u32 as *const u8, image.len());
+	if written != image {
+		warn!("OS image didn't read back from flash correctly");
+		return Err(Error::VerifyFailed);
+	}
+	let mut page = [0xFFu8; flash::PAGE_SIZE];
+	page[0..8].copy_from_slice(&header.magic);
+	let words = [
+		header.length,
+		header.crc32,
+		header.compression,
+		header.load_address,
+		header.unpacked_length,
+	];
+	for (idx, word) in words.iter().enumerate() {
+		let offset = 8 + (idx * 4);
+		page[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+	}
+	flash::program(header_offset, &page).map_err(Error::Flash)
+}
+
+/// If `received` starts with a header, check the image after it against
+/// the header, and return them both.
+///
+/// Any bytes after the image (like the padding on the last XMODEM block)
+/// are ignored.
+fn split_header(received: &[u8]) -> Result<Option<(Header, &[u8])>, Error> {
+	if received.len() < HEADER_LEN || received[0..8] != HEADER_MAGIC {
+		return Ok(None);
+	}
+	let word = |idx: usize| {
+		let offset = 8 + (idx * 4);
+		u32::from_le_bytes([
+			received[offset],
+			received[offset + 1],
+			received[offset + 2],
+			received[offset + 3],
+		])
+	};
+	let header = Header {
+		magic: HEADER_MAGIC,
+		length: word(0),
+		crc32: word(1),
+		compression: word(2),
+		load_address: word(3),
+		unpacked_length: word(4),
+	};
+	let length = header.length as usize;
+	let image = match HEADER_LEN.checked_add(length) {
+		Some(end) if length != 0 && end <= received.len() => &received[HEADER_LEN..end],
+		_ => return Err(Error::BadLength),
+	};
+	if header.compression != Compression::None as u32
+		&& header.compression != Compression::Lz4 as u32
+	{
+		return Err(Error::BadHeader);
+	}
+	let crc32 = bootslot::crc32(image);
+	if crc32 != header.crc32 {
+		warn!(
+			"Received OS image CRC is {:08x}, expected {:08x}",
+			crc32, header.crc32
+		);
+		return Err(Error::BadCrc);
+	}
+	Ok(Some((header, image)))
+}
+
+/// Parse a decimal number at compile time, for the CRC `build.rs` gives us.
+///
+/// Stops at the first character which isn't a digit.
+pub const fn parse_decimal(text: &str) -> u32 {
+	let bytes = text.as_bytes();
+	let mut value: u32 = 0;
+	let mut idx = 0;
+	while idx < bytes.len() && bytes[idx].is_ascii_digit() {
+		value = (value * 10) + (bytes[idx] - b'0') as u32;
+		idx += 1;
+	}
+	value
+}
+
+impl Header {
+	/// Make a header for an image.
+	///
+	/// The `length` and `crc32` are of the image as stored in flash.
+	pub const fn new(
+		length: u32,
+		crc32: u32,
+		compression: Compression,
+		load_address: u32,
+		unpacked_length: u32,
+	) -> Header {
+		Header {
+			magic: HEADER_MAGIC,
+			length,
+			crc32,
+			compression: compression as u32,
+			load_address,
+			unpacked_length,
+		}
+	}
+}
+
+impl Error {
+	/// A human readable description of the error.
+	pub fn description(self) -> &'static str {
+		match self {
+			Error::NoHeader => "no header",
+			Error::BadLength => "bad length",
+			Error::BadCrc => "CRC mismatch",
+			Error::BadHeader => "bad header",
+			Error::BadCompression => "won't decompress",
+			Error::Flash(_) => "flash write failed",
+			Error::VerifyFailed => "flash doesn't match",
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,272 @@
+//! # Analogue paddle and joystick inputs for the Neotron Pico
+//!
+//! Classic analogue paddles (and joysticks, which are just two paddles at
+//! right-angles) are potentiometers. Wired as a voltage divider to one of the
+//! RP2040's ADC inputs, each gives us a position along one axis.
+//!
+//! | Axis | Pin    | ADC input |
+//! |------|--------|-----------|
+//! | 0    | GPIO26 | 0         |
+//! | 1    | GPIO27 | 1         |
+//! | 2    | GPIO28 | 2         |
+//!
+//! GPIO26 and GPIO28 can also be PWM outputs, so axes are only enabled (and
+//! their pins reserved) when the BIOS configuration asks for them. Each
+//! enabled axis is sampled every `SAMPLE_INTERVAL_MS`, scaled using the
+//! calibration in the configuration, and reported as an `AxisEvent` when it
+//! moves.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{board, config};
+use defmt::{debug, trace, warn};
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// An analogue axis has moved.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct AxisEvent {
+	/// Which axis moved
+	pub axis: u8,
+	/// Where it is now, from 0 (the calibrated minimum) to 255 (the
+	/// calibrated maximum)
+	pub position: u8,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How many analogue axes there are
+pub const NUM_AXES: usize = 3;
+
+/// The GPIO pin for each axis. Axis `n` is ADC input `n`.
+const AXIS_PINS: [u8; NUM_AXES] = [26, 27, 28];
+
+/// How often we sample the axes
+const SAMPLE_INTERVAL_MS: u32 = 10;
+
+/// How many conversions we average for each sample, to reduce noise
+const OVERSAMPLE: u32 = 4;
+
+/// The biggest value the ADC gives
+pub const ADC_MAX: u16 = 4095;
+
+/// An axis has to move at least this far (out of 255) before we report it,
+/// so a paddle sitting still doesn't flood the queue with noise.
+const DEADBAND: u8 = 2;
+
+/// Holds the ADC peripheral, once `init` has been called.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut ADC_PERIPH: Option<pac::ADC> = None;
+
+/// Which axes are enabled, as a bit-mask.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut ENABLED_AXES: u8 = 0;
+
+/// The position we last reported for each axis.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut POSITIONS: [Option<u8>; NUM_AXES] = [None; NUM_AXES];
+
+/// When we last sampled the axes
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut LAST_SAMPLE_AT: u32 = 0;
+
+/// Axis events we haven't given to the OS yet.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut EVENTS: heapless::Deque<AxisEvent, 16> = heapless::Deque::new();
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Take the ADC out of reset and turn it on. No axes are enabled yet.
+pub fn init(adc: pac::ADC, resets: &mut pac::RESETS) {
+	resets.reset.modify(|_r, w| w.adc().clear_bit());
+	while resets.reset_done.read().adc().bit_is_clear() {}
+	adc.cs.write(|w| w.en().set_bit());
+	while adc.cs.read().ready().bit_is_clear() {}
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		ADC_PERIPH = Some(adc);
+	}
+}
+
+/// Choose which axes are sampled, as a bit-mask (bit `n` is axis `n`).
+///
+/// An axis whose pin is being used for something else stays disabled.
+pub fn set_enabled_axes(mask: u8) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let enabled = unsafe { &mut ENABLED_AXES };
+	for (axis, &pin) in AXIS_PINS.iter().enumerate() {
+		let bit = 1 << axis;
+		let wanted = (mask & bit) != 0;
+		let active = (*enabled & bit) != 0;
+		if wanted && !active {
+			if board::reserve(pin, board::PinUse::Analogue).is_ok() {
+				set_analogue_pad(pin, true);
+				*enabled |= bit;
+				debug!("Axis {} on GPIO{}", axis, pin);
+			} else {
+				warn!("Can't enable axis {}", axis);
+			}
+		} else if !wanted && active {
+			set_analogue_pad(pin, false);
+			board::release(pin, board::PinUse::Analogue);
+			*enabled &= !bit;
+		}
+		if !wanted {
+			// Note (safety): Only called from Core 0, and never from an
+			// interrupt.
+			unsafe {
+				POSITIONS[axis] = None;
+			}
+		}
+	}
+}
+
+/// Sample the enabled axes (if it's time to), and generate any events.
+///
+/// Call this regularly.
+pub fn poll() {
+	let now = crate::ticks_ms();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (adc, enabled) = match unsafe { (ADC_PERIPH.as_mut(), ENABLED_AXES) } {
+		(Some(adc), enabled) if enabled != 0 => (adc, enabled),
+		_ => return,
+	};
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		if now.wrapping_sub(LAST_SAMPLE_AT) < SAMPLE_INTERVAL_MS {
+			return;
+		}
+		LAST_SAMPLE_AT = now;
+	}
+
+	let config = config::get();
+	for axis in 0..NUM_AXES {
+		if (enabled & (1 << axis)) == 0 {
+			continue;
+		}
+		let mut total = 0;
+		for _ in 0..OVERSAMPLE {
+			total += u32::from(convert(adc, axis as u8));
+		}
+		let raw = (total / OVERSAMPLE) as u16;
+		let position = scale(raw, config.paddle_min[axis], config.paddle_max[axis]);
+		// Note (safety): Only called from Core 0, and never from an interrupt.
+		let last = unsafe { &mut POSITIONS[axis] };
+		let moved = match *last {
+			None => true,
+			// Always report reaching the ends, even if it's a small move
+			Some(old) => {
+				let distance = if old > position {
+					old - position
+				} else {
+					position - old
+				};
+				distance >= DEADBAND || (old != position && (position == 0 || position == 255))
+			}
+		};
+		if moved {
+			*last = Some(position);
+			let event = AxisEvent {
+				axis: axis as u8,
+				position,
+			};
+			trace!("Axis: {} (raw {})", event, raw);
+			push_event(event);
+		}
+	}
+}
+
+/// Get the next axis event, if any.
+pub fn get_event() -> Option<AxisEvent> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { EVENTS.pop_front() }
+}
+
+/// Queue up an axis event. If the queue is full, the oldest event is dropped.
+fn push_event(event: AxisEvent) {
+	// Someone is using the machine, so make sure they can see the screen
+	crate::vga::mark_dirty();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		if EVENTS.is_full() {
+			let _ = EVENTS.pop_front();
+		}
+		let _ = EVENTS.push_back(event);
+	}
+}
+
+/// Run one ADC conversion on the given input. Takes about 2 microseconds.
+fn convert(adc: &mut pac::ADC, input: u8) -> u16 {
+	// Note (safety): `input` is always less than NUM_AXES, which is a valid
+	// AINSEL value.
+	adc.cs
+		.modify(|_r, w| unsafe { w.ainsel().bits(input).start_once().set_bit() });
+	while adc.cs.read().ready().bit_is_clear() {}
+	adc.result.read().result().bits()
+}
+
+/// Scale a raw reading to 0..=255, given the readings at each end of travel.
+///
+/// If `min` is more than `max`, the axis is reversed.
+fn scale(raw: u16, min: u16, max: u16) -> u8 {
+	let (low, high, reversed) = if min <= max {
+		(min, max, false)
+	} else {
+		(max, min, true)
+	};
+	let span = u32::from(high - low).max(1);
+	let offset = u32::from(raw.clamp(low, high) - low);
+	let position = ((offset * 255) / span) as u8;
+	if reversed {
+		255 - position
+	} else {
+		position
+	}
+}
+
+/// Switch a pad between analogue (digital input and output both off) and
+/// normal digital use.
+fn set_analogue_pad(pin: u8, analogue: bool) {
+	// Note (safety): We only touch pads we have reserved.
+	let pads = unsafe { &*pac::PADS_BANK0::ptr() };
+	pads.gpio[pin as usize].modify(|_r, w| w.ie().bit(!analogue).od().bit(analogue));
+	board::set_function(pin, board::FUNCSEL_NULL);
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
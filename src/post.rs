@@ -0,0 +1,185 @@
+//! # Power-on self test for the Neotron Pico
+//!
+//! Before we sign on, we check the things most likely to stop the OS
+//! working, and most likely to be wrong on a newly built board:
+//!
+//! * Every word of the OS RAM, with a bit walked through it, and then with
+//!   its own address (to find address lines that are stuck or shorted).
+//! * The OS image in flash, against the CRC-32 in its header (see
+//!   `os_image`).
+//! * Whether the real-time clock and audio codec answer on the I2C bus.
+//! * Whether the BMC answers on the Neotron Bus.
+//!
+//! Nothing here stops the boot. Each problem is blinked out on the LED (see
+//! `boot_error::warn`) so a board with no working video can still be
+//! diagnosed, and the whole report is shown when we sign on. A board
+//! without the audio codec is assumed to have a beeper instead (see
+//! `beeper`), which beeps along with the LED.
+//!
+//! The RAM test wipes the OS RAM, so it must run before we load anything
+//! into it.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{audio, beeper, bmc, boot_error, os_image, rtc};
+use defmt::info;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// What the power-on self test found.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Report {
+	/// The address of the first bad word of OS RAM, if there is one
+	pub ram: Result<(), usize>,
+	/// Does the OS image in flash match its checksum?
+	pub os_image_ok: bool,
+	/// Did the real-time clock answer?
+	pub rtc: Result<(), rtc::Error>,
+	/// Did the audio codec answer?
+	pub codec: Result<(), rtc::Error>,
+	/// The BMC's protocol version, if it answered
+	pub bmc: Result<u8, bmc::Error>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Run the self test, blinking out anything that failed.
+///
+/// The `ram` is wiped.
+pub fn run(ram: &mut [u32]) -> Report {
+	let report = Report {
+		ram: test_ram(ram),
+		os_image_ok: os_image::check().is_ok(),
+		rtc: match rtc::get_time() {
+			// A stopped (or never set) clock still answered us
+			Ok(_) | Err(rtc::Error::NotRunning) | Err(rtc::Error::BadDateTime) => Ok(()),
+			Err(e) => Err(e),
+		},
+		codec: audio::probe_codec(),
+		bmc: {
+			let mut version = [0u8; 1];
+			bmc::read_register(bmc::Register::ProtocolVersion, &mut version).map(|_| version[0])
+		},
+	};
+	info!("POST: {}", report);
+
+	if report.codec.is_err() {
+		beeper::enable();
+	}
+
+	let checks = [
+		(report.ram.is_ok(), boot_error::Stage::PostRam),
+		(report.os_image_ok, boot_error::Stage::PostOsImage),
+		(report.rtc.is_ok(), boot_error::Stage::PostRtc),
+		(report.codec.is_ok(), boot_error::Stage::PostCodec),
+		(report.bmc.is_ok(), boot_error::Stage::PostBmc),
+	];
+	for (ok, stage) in checks.iter() {
+		if !ok {
+			boot_error::warn(*stage);
+		}
+	}
+	report
+}
+
+/// Check every word of `ram`, and leave it all zero.
+///
+/// Returns the address of the first word that doesn't read back what we
+/// wrote.
+fn test_ram(ram: &mut [u32]) -> Result<(), usize> {
+	let base = ram.as_mut_ptr();
+	let len = ram.len();
+	// Note (safety): Every offset we use is inside `ram`. We use volatile
+	// accesses so the compiler can't skip the read-backs.
+	unsafe {
+		// Walk a one through each word in turn
+		for idx in 0..len {
+			let word = base.add(idx);
+			for bit in 0..32 {
+				word.write_volatile(1 << bit);
+				if word.read_volatile() != (1 << bit) {
+					return Err(word as usize);
+				}
+			}
+		}
+		// Give every word its own address, then the inverse of it, so no
+		// two words hold the same value
+		for &invert in [0, !0].iter() {
+			for idx in 0..len {
+				let word = base.add(idx);
+				word.write_volatile((word as u32) ^ invert);
+			}
+			for idx in 0..len {
+				let word = base.add(idx);
+				if word.read_volatile() != (word as u32) ^ invert {
+					return Err(word as usize);
+				}
+			}
+		}
+		for idx in 0..len {
+			base.add(idx).write_volatile(0);
+		}
+	}
+	Ok(())
+}
+
+impl Report {
+	/// Did everything pass?
+	pub fn is_ok(&self) -> bool {
+		self.ram.is_ok()
+			&& self.os_image_ok
+			&& self.rtc.is_ok()
+			&& self.codec.is_ok()
+			&& self.bmc.is_ok()
+	}
+}
+
+impl core::fmt::Display for Report {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "Self test: RAM ")?;
+		match self.ram {
+			Ok(()) => write!(f, "OK")?,
+			Err(address) => write!(f, "FAILED at 0x{:08x}", address)?,
+		}
+		write!(
+			f,
+			", OS image {}, RTC {}, codec {}, BMC ",
+			if self.os_image_ok { "OK" } else { "CORRUPT" },
+			if self.rtc.is_ok() { "OK" } else { "missing" },
+			if self.codec.is_ok() { "OK" } else { "missing" },
+		)?;
+		match self.bmc {
+			Ok(version) => write!(f, "v{} OK", version),
+			Err(_) => write!(f, "missing"),
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
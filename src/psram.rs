@@ -0,0 +1,419 @@
+//! # External PSRAM for the Neotron Pico
+//!
+//! With the `psram` feature, we look for an 8 MiB SPI PSRAM chip (e.g. an
+//! APS6404L) wired to the four spare GPIO pins, on a board built without the
+//! audio codec (the pins are the codec's, and the beeper and paddles can't
+//! have them either):
+//!
+//! | GPIO | PSRAM pin    | Audio codec pin |
+//! |------|--------------|-----------------|
+//! | 22   | SI (SIO0)    | DIN             |
+//! | 26   | SO (SIO1)    | DOUT            |
+//! | 27   | SCLK         | BCLK            |
+//! | 28   | CE#          | LRCLK           |
+//!
+//! These chips can also do quad SPI, but that needs six pins, so we talk to
+//! it one bit at a time. A state machine on PIO1 clocks the bits, and drops
+//! CE# for as long as there are bytes waiting in its FIFO. Two DMA channels
+//! feed the FIFO from a buffer, and empty the bytes that come back into the
+//! same buffer. The SPI clock runs at up to 33 MHz, which is as fast as the
+//! chip's plain read command goes, giving a few MiB/s once the command bytes
+//! are taken into account.
+//!
+//! The chip refreshes itself while CE# is high, so it must not be held low
+//! for more than 8 µs at a time. We split every transfer into bursts of
+//! `BURST_LEN` bytes, which also keeps each burst inside one of the chip's 1
+//! KiB pages.
+//!
+//! The RP2040 has no way to map the chip into the address space, so it
+//! can't be offered through `memory_get_region` - the OS has to copy data
+//! in and out with `psram_read` and `psram_write`. For the same reason it
+//! can't be used as a framebuffer: even the 1-bpp modes would need most of
+//! the link's bandwidth, every scan-line.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::board;
+use defmt::{debug, info, warn};
+use rp_pico::hal::{self, pac};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which a PSRAM transfer can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// There's no PSRAM chip
+	NotFitted,
+	/// The transfer runs off the end of the chip
+	OutOfRange,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How big the PSRAM chip is
+pub const SIZE: usize = 8 * 1024 * 1024;
+
+/// The pin we send data out on
+const SI_PIN: u8 = 22;
+
+/// The pin data comes back in on
+const SO_PIN: u8 = 26;
+
+/// The clock pin. The chip-select pin must be the next one up.
+const SCLK_PIN: u8 = 27;
+
+/// The chip-select pin (active low)
+const CE_PIN: u8 = 28;
+
+/// The PIO1 function number, for `GPIOx_CTRL.FUNCSEL`
+const FUNCSEL_PIO1: u8 = 7;
+
+/// We use state machine 2 on PIO1 (the audio has 0 and 1)
+const STATE_MACHINE: usize = 2;
+
+/// PIO clock cycles per bit
+const CYCLES_PER_BIT: u32 = 4;
+
+/// The fastest SPI clock the chip's plain read command works at
+const MAX_SPI_CLOCK_HZ: u32 = 33_000_000;
+
+/// DMA channel which feeds the PIO (the audio uses channels up to 8)
+const DMA_CHAN_TX: usize = 9;
+
+/// DMA channel which empties the PIO
+const DMA_CHAN_RX: usize = 10;
+
+/// In `CHx_CTRL_TRIG`: the channel is still running
+const DMA_CTRL_BUSY: u32 = 1 << 24;
+
+/// The chip's command to read, with no wait cycles
+const CMD_READ: u8 = 0x03;
+
+/// The chip's command to write
+const CMD_WRITE: u8 = 0x02;
+
+/// The chip's command to read its ID
+const CMD_READ_ID: u8 = 0x9F;
+
+/// The chip's command to allow a reset
+const CMD_RESET_ENABLE: u8 = 0x66;
+
+/// The chip's command to reset, once allowed
+const CMD_RESET: u8 = 0x99;
+
+/// The "Known Good Die" byte in the chip's ID
+const KGD_PASS: u8 = 0x5D;
+
+/// A command byte, then a 24-bit address
+const HEADER_LEN: usize = 4;
+
+/// The most data we move with CE# low. At 33 MHz, with the header, this
+/// takes about 5 µs.
+const BURST_LEN: usize = 16;
+
+/// The chip's page size. A burst mustn't cross from one page into the next.
+const PAGE_SIZE: usize = 1024;
+
+/// The bytes for the current burst, going out and coming back.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut TRANSFER: [u8; HEADER_LEN + BURST_LEN] = [0u8; HEADER_LEN + BURST_LEN];
+
+/// The PIO FIFO addresses and DREQ numbers (TX then RX), once we have found
+/// a chip.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut FIFOS: Option<((u32, u8), (u32, u8))> = None;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Load our SPI program into PIO1, and see if there's a PSRAM chip.
+///
+/// Does nothing without the `psram` feature. Must be called after
+/// `audio::init`, which hands over what's left of PIO1, and after
+/// `paddles::init`, so any paddles the configuration asks for get their pins
+/// first.
+pub fn init(
+	mut pio: hal::pio::PIO<pac::PIO1>,
+	sm: hal::pio::UninitStateMachine<(pac::PIO1, hal::pio::SM2)>,
+	sys_clock_hz: u32,
+) {
+	if !cfg!(feature = "psram") {
+		return;
+	}
+	for pin in [SI_PIN, SO_PIN, SCLK_PIN, CE_PIN] {
+		if board::reserve(pin, board::PinUse::Psram).is_err() {
+			release_pins();
+			return;
+		}
+	}
+
+	// This program clocks out (and in) one byte per FIFO entry, MSB first,
+	// in SPI mode 0. It keeps CE# low for as long as there are bytes in the
+	// TX FIFO, then raises it. Side-set bit 0 is SCLK and side-set bit 1 is
+	// CE#. X and Y must start at 6 (bits per byte, minus two).
+	//
+	// Note: autopull and autopush should be set to 8-bits, both shifting
+	// left.
+	let program = pio_proc::pio_asm!(
+		".side_set 2"
+		".wrap_target"
+		"pull ifempty side 0b10 [1]"
+		"bitloop:"
+			"out pins, 1 side 0b00 [1]"
+			"in pins, 1 side 0b01"
+			"jmp x-- bitloop side 0b01"
+		"out pins, 1 side 0b00"
+		"mov x, y side 0b00"
+		"in pins, 1 side 0b01"
+		"jmp !osre bitloop side 0b01"
+		"nop side 0b00 [1]"
+		".wrap"
+	);
+	let installed = match pio.install(&program.program) {
+		Ok(installed) => installed,
+		Err(_) => {
+			warn!("PSRAM PIO program didn't fit");
+			release_pins();
+			return;
+		}
+	};
+	// The divider is 16.8 fixed point, rounded up to keep under the limit
+	let divider = ((u64::from(sys_clock_hz) * 256) + u64::from(MAX_SPI_CLOCK_HZ * CYCLES_PER_BIT)
+		- 1) / u64::from(MAX_SPI_CLOCK_HZ * CYCLES_PER_BIT);
+	let divider = divider.max(256);
+	let (mut sm, rx, tx) = hal::pio::PIOBuilder::from_program(installed)
+		.buffers(hal::pio::Buffers::RxTx)
+		.out_pins(SI_PIN, 1)
+		.in_pin_base(SO_PIN)
+		.side_set_pin_base(SCLK_PIN)
+		.autopull(true)
+		.autopush(true)
+		.out_shift_direction(hal::pio::ShiftDirection::Left)
+		.in_shift_direction(hal::pio::ShiftDirection::Left)
+		.pull_threshold(8)
+		.push_threshold(8)
+		.build(sm);
+	sm.set_pindirs([
+		(SI_PIN, hal::pio::PinDir::Output),
+		(SO_PIN, hal::pio::PinDir::Input),
+		(SCLK_PIN, hal::pio::PinDir::Output),
+		(CE_PIN, hal::pio::PinDir::Output),
+	]);
+	// We drop the state machine here, as `audio` does, and run it through
+	// the registers.
+
+	// Note (safety): We own state machine 2 on PIO1.
+	let regs = unsafe { &*pac::PIO1::ptr() };
+	regs.sm[STATE_MACHINE].sm_clkdiv.write(|w| unsafe {
+		w.int()
+			.bits((divider >> 8) as u16)
+			.frac()
+			.bits((divider & 0xFF) as u8)
+	});
+	// `set x, 6 side 0b10` then `set y, 6 side 0b10`
+	for instr in [0xF026, 0xF046] {
+		regs.sm[STATE_MACHINE]
+			.sm_instr
+			.write(|w| unsafe { w.bits(instr) });
+	}
+	for pin in [SI_PIN, SO_PIN, SCLK_PIN, CE_PIN] {
+		board::set_function(pin, FUNCSEL_PIO1);
+	}
+	regs.ctrl.modify(|r, w| unsafe {
+		w.sm_enable()
+			.bits(r.sm_enable().bits() | (1 << STATE_MACHINE))
+	});
+
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		FIFOS = Some((
+			(tx.fifo_address() as usize as u32, tx.dreq_value()),
+			(rx.fifo_address() as usize as u32, rx.dreq_value()),
+		));
+	}
+	for cmd in [CMD_RESET_ENABLE, CMD_RESET] {
+		burst(&[cmd], 1);
+	}
+	// The maker's ID, then the Known Good Die byte
+	let id = &burst(&[CMD_READ_ID, 0, 0, 0], HEADER_LEN + 2)[HEADER_LEN..];
+	if id[1] != KGD_PASS {
+		debug!("No PSRAM (KGD 0x{:02x})", id[1]);
+		// Note (safety): Only called from Core 0, and never from an
+		// interrupt.
+		unsafe {
+			FIFOS = None;
+		}
+		regs.ctrl.modify(|r, w| unsafe {
+			w.sm_enable()
+				.bits(r.sm_enable().bits() & !(1 << STATE_MACHINE))
+		});
+		release_pins();
+		return;
+	}
+	info!(
+		"PSRAM: {} KiB, maker 0x{:02x}, divider {}/256",
+		SIZE / 1024,
+		id[0],
+		divider
+	);
+}
+
+/// Is there a PSRAM chip?
+pub fn is_fitted() -> bool {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { FIFOS.is_some() }
+}
+
+/// Read bytes from the PSRAM chip, starting at the given offset.
+pub fn read(offset: usize, buffer: &mut [u8]) -> Result<(), Error> {
+	check(offset, buffer.len())?;
+	let mut done = 0;
+	while done < buffer.len() {
+		let address = offset + done;
+		let len = burst_len(address, buffer.len() - done);
+		let data = burst(&header(CMD_READ, address), HEADER_LEN + len);
+		buffer[done..done + len].copy_from_slice(&data[HEADER_LEN..]);
+		done += len;
+	}
+	Ok(())
+}
+
+/// Write bytes to the PSRAM chip, starting at the given offset.
+pub fn write(offset: usize, data: &[u8]) -> Result<(), Error> {
+	check(offset, data.len())?;
+	let mut done = 0;
+	while done < data.len() {
+		let address = offset + done;
+		let len = burst_len(address, data.len() - done);
+		// Note (safety): Only called from Core 0, and never from an
+		// interrupt.
+		unsafe {
+			TRANSFER[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&data[done..done + len]);
+		}
+		burst(&header(CMD_WRITE, address), HEADER_LEN + len);
+		done += len;
+	}
+	Ok(())
+}
+
+/// Check a transfer fits on the chip.
+fn check(offset: usize, len: usize) -> Result<(), Error> {
+	if !is_fitted() {
+		return Err(Error::NotFitted);
+	}
+	match offset.checked_add(len) {
+		Some(end) if end <= SIZE => Ok(()),
+		_ => Err(Error::OutOfRange),
+	}
+}
+
+/// How much of a transfer we can do in one burst, from the given address.
+fn burst_len(address: usize, remaining: usize) -> usize {
+	remaining
+		.min(BURST_LEN)
+		.min(PAGE_SIZE - (address % PAGE_SIZE))
+}
+
+/// A command byte and a 24-bit address, MSB first.
+fn header(cmd: u8, address: usize) -> [u8; HEADER_LEN] {
+	[
+		cmd,
+		(address >> 16) as u8,
+		(address >> 8) as u8,
+		address as u8,
+	]
+}
+
+/// Send `header` and then whatever is in `TRANSFER` after it, `total` bytes
+/// in all, with CE# held low. Gives back the `total` bytes that came back.
+fn burst(header: &[u8], total: usize) -> &'static [u8] {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let ((tx_fifo, tx_dreq), (rx_fifo, rx_dreq)) = match unsafe { FIFOS } {
+		Some(fifos) => fifos,
+		None => return &[],
+	};
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	// The DMA engine only touches the buffer until we've seen it finish.
+	let buffer = unsafe { &mut TRANSFER };
+	let total = total.clamp(header.len(), buffer.len());
+	buffer[0..header.len()].copy_from_slice(header);
+	let address = buffer.as_mut_ptr() as usize as u32;
+
+	// Note (safety): The video owns the DMA controller, but only uses its
+	// own channels and interrupt. Nothing else touches these channels.
+	let dma = unsafe { &*pac::DMA::ptr() };
+	let rx = &dma.ch[DMA_CHAN_RX];
+	rx.ch_read_addr.write(|w| unsafe { w.bits(rx_fifo) });
+	rx.ch_write_addr.write(|w| unsafe { w.bits(address) });
+	rx.ch_trans_count.write(|w| unsafe { w.bits(total as u32) });
+	rx.ch_al1_ctrl.write(|w| unsafe {
+		w.bits(
+			(1 << 0) // EN, with DATA_SIZE = byte
+				| (1 << 5) // INCR_WRITE
+				| ((DMA_CHAN_RX as u32) << 11) // CHAIN_TO itself (nothing)
+				| (u32::from(rx_dreq) << 15), // TREQ_SEL
+		)
+	});
+	let tx = &dma.ch[DMA_CHAN_TX];
+	tx.ch_read_addr.write(|w| unsafe { w.bits(address) });
+	tx.ch_write_addr.write(|w| unsafe { w.bits(tx_fifo) });
+	tx.ch_trans_count.write(|w| unsafe { w.bits(total as u32) });
+	tx.ch_al1_ctrl.write(|w| unsafe {
+		w.bits(
+			(1 << 0) // EN, with DATA_SIZE = byte
+				| (1 << 4) // INCR_READ
+				| ((DMA_CHAN_TX as u32) << 11) // CHAIN_TO itself (nothing)
+				| (u32::from(tx_dreq) << 15), // TREQ_SEL
+		)
+	});
+	// Start both together, so the RX FIFO never fills up and stalls the
+	// clock with CE# low
+	dma.multi_chan_trigger
+		.write(|w| unsafe { w.bits((1 << DMA_CHAN_TX) | (1 << DMA_CHAN_RX)) });
+	// Every byte sent comes back, so this is the end of the burst
+	while (rx.ch_ctrl_trig.read().bits() & DMA_CTRL_BUSY) != 0 {}
+
+	&buffer[0..total]
+}
+
+/// Give back any of our pins we managed to claim.
+fn release_pins() {
+	for pin in [SI_PIN, SO_PIN, SCLK_PIN, CE_PIN] {
+		if board::pin_use(pin) == Some(board::PinUse::Psram) {
+			board::set_function(pin, board::FUNCSEL_NULL);
+			board::release(pin, board::PinUse::Psram);
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,172 @@
+//! # PWM outputs for the Neotron Pico
+//!
+//! The OS can use a couple of the RP2040's PWM slices to drive a fan, dim an
+//! LED or run a small motor. Each output is on a spare GPIO pin, and has its
+//! own slice, so each can run at its own frequency.
+//!
+//! | Channel | Pin    | Slice |
+//! |---------|--------|-------|
+//! | 0       | GPIO26 | 5 (A) |
+//! | 1       | GPIO28 | 6 (A) |
+//!
+//! A pin is only taken over (via `board::reserve`) when its channel is first
+//! configured, and is given back when the channel is disabled.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::board;
+use defmt::debug;
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which configuring a PWM output can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// There is no such channel, or `init` hasn't been called
+	NoSuchChannel,
+	/// The frequency is outside `MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ`
+	BadFrequency,
+	/// The channel's pin is being used for something else
+	PinInUse(board::PinUse),
+}
+
+/// Where a channel comes out.
+struct Channel {
+	/// The GPIO pin
+	pin: u8,
+	/// The PWM slice (we always use output A)
+	slice: usize,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How many PWM channels the OS can use
+pub const NUM_CHANNELS: u8 = 2;
+
+/// The slowest PWM frequency we offer
+pub const MIN_FREQUENCY_HZ: u32 = 10;
+
+/// The fastest PWM frequency we offer
+pub const MAX_FREQUENCY_HZ: u32 = 1_000_000;
+
+/// The pin and slice for each channel
+const CHANNELS: [Channel; NUM_CHANNELS as usize] =
+	[Channel { pin: 26, slice: 5 }, Channel { pin: 28, slice: 6 }];
+
+/// The PWM function number, for `GPIOx_CTRL.FUNCSEL`
+const FUNCSEL_PWM: u8 = 4;
+
+/// Holds the PWM peripheral, once `init` has been called.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut PWM_PERIPH: Option<pac::PWM> = None;
+
+/// The system clock frequency, which clocks the PWM slices
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut SYS_CLOCK_HZ: u32 = 0;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Take the PWM block out of reset. All the outputs start disabled.
+pub fn init(pwm: pac::PWM, resets: &mut pac::RESETS, sys_clock_hz: u32) {
+	resets.reset.modify(|_r, w| w.pwm().clear_bit());
+	while resets.reset_done.read().pwm().bit_is_clear() {}
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		PWM_PERIPH = Some(pwm);
+		SYS_CLOCK_HZ = sys_clock_hz;
+	}
+}
+
+/// Start (or change) a PWM output.
+///
+/// `duty` is the fraction of each period the output is high, where 0 is
+/// always low and 65535 is as close to always high as we can get.
+pub fn configure(channel: u8, frequency_hz: u32, duty: u16) -> Result<(), Error> {
+	let channel = CHANNELS.get(channel as usize).ok_or(Error::NoSuchChannel)?;
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (pwm, sys_clock_hz) = match unsafe { (PWM_PERIPH.as_mut(), SYS_CLOCK_HZ) } {
+		(Some(pwm), sys_clock_hz) => (pwm, sys_clock_hz),
+		(None, _) => return Err(Error::NoSuchChannel),
+	};
+	if !(MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ).contains(&frequency_hz) {
+		return Err(Error::BadFrequency);
+	}
+	board::reserve(channel.pin, board::PinUse::Pwm).map_err(|e| match e {
+		board::Error::InUse(usage) => Error::PinInUse(usage),
+		board::Error::NoSuchPin => Error::NoSuchChannel,
+	})?;
+
+	// The counter is 16 bits, so use the smallest integer divider that gets
+	// the period down to 65536 counts or fewer.
+	let counts = sys_clock_hz / frequency_hz;
+	let divider = ((counts + 0xFFFF) / 0x10000).clamp(1, 255);
+	let top = ((counts / divider).max(2) - 1).min(0xFFFF);
+	let compare = ((u32::from(duty) * (top + 1)) >> 16).min(top);
+	debug!(
+		"PWM GPIO{}: {} Hz, div {}, top {}, cc {}",
+		channel.pin, frequency_hz, divider, top, compare
+	);
+
+	let slice = &pwm.ch[channel.slice];
+	// Note (safety): We own the PWM peripheral, and these are all valid
+	// register values.
+	unsafe {
+		slice
+			.div
+			.write(|w| w.int().bits(divider as u8).frac().bits(0));
+		slice.top.write(|w| w.top().bits(top as u16));
+		slice.cc.modify(|_r, w| w.a().bits(compare as u16));
+	}
+	slice.csr.modify(|_r, w| w.en().set_bit());
+	board::set_function(channel.pin, FUNCSEL_PWM);
+	Ok(())
+}
+
+/// Stop a PWM output, and give its pin back.
+pub fn disable(channel: u8) -> Result<(), Error> {
+	let channel = CHANNELS.get(channel as usize).ok_or(Error::NoSuchChannel)?;
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let pwm = unsafe { PWM_PERIPH.as_mut() }.ok_or(Error::NoSuchChannel)?;
+	if board::pin_use(channel.pin) != Some(board::PinUse::Pwm) {
+		// Never configured, so nothing to do
+		return Ok(());
+	}
+	pwm.ch[channel.slice].csr.modify(|_r, w| w.en().clear_bit());
+	board::set_function(channel.pin, board::FUNCSEL_NULL);
+	board::release(channel.pin, board::PinUse::Pwm);
+	Ok(())
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,198 @@
+//! # Random numbers for the Neotron Pico
+//!
+//! The RP2040's ring oscillator (ROSC) runs freely, at a speed which wanders
+//! with temperature, voltage and noise, and its `RANDOMBIT` register lets us
+//! sample its output. On its own that isn't good randomness - the bits are
+//! biased and we read them far faster than the oscillator runs, so
+//! neighbouring bits are related. So:
+//!
+//! 1. We take the raw bits in pairs, and keep the first of each pair that
+//!    differ (von Neumann's method), which removes the bias.
+//! 2. We gather 1024 of those bits, and hash them with SHA-256 to get 256
+//!    bits of output, which hides any pattern left over.
+//!
+//! This is slow (a few milliseconds per 32 bytes) so it's best used to seed
+//! a faster generator, rather than for every random number.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which getting random numbers can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The ROSC gave us the same bit over and over, so it has probably
+	/// stopped
+	Stuck,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How many bytes of debiased ROSC bits we hash for each output block
+const POOL_LEN: usize = 128;
+
+/// How many bytes each hash gives us
+const OUTPUT_LEN: usize = 32;
+
+/// How many pairs of raw bits we try before deciding the ROSC is stuck. A
+/// working oscillator gives a differing pair every few goes.
+const MAX_ATTEMPTS: u32 = 10_000;
+
+/// The SHA-256 initial hash value
+const SHA256_H: [u32; 8] = [
+	0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// The SHA-256 round constants
+const SHA256_K: [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Fill the buffer with random bytes.
+///
+/// Fails if the ROSC has stopped, in which case the buffer is left partly
+/// filled and shouldn't be used.
+pub fn fill(buffer: &mut [u8]) -> Result<(), Error> {
+	for chunk in buffer.chunks_mut(OUTPUT_LEN) {
+		// This can take a while, so don't let the watchdog bite
+		crate::watchdog::feed();
+		let mut pool = [0u8; POOL_LEN];
+		for b in pool.iter_mut() {
+			*b = debiased_byte()?;
+		}
+		let output = sha256(&pool);
+		chunk.copy_from_slice(&output[0..chunk.len()]);
+	}
+	Ok(())
+}
+
+/// Eight debiased bits from the ROSC.
+fn debiased_byte() -> Result<u8, Error> {
+	let mut value = 0;
+	for _ in 0..8 {
+		value = (value << 1) | debiased_bit()?;
+	}
+	Ok(value)
+}
+
+/// One debiased bit from the ROSC: the first of the next pair of raw bits
+/// that differ, giving up after `MAX_ATTEMPTS` pairs.
+fn debiased_bit() -> Result<u8, Error> {
+	for _ in 0..MAX_ATTEMPTS {
+		let first = raw_bit();
+		if first != raw_bit() {
+			return Ok(first);
+		}
+	}
+	Err(Error::Stuck)
+}
+
+/// One raw bit from the ROSC.
+fn raw_bit() -> u8 {
+	// Note (safety): Reading RANDOMBIT has no side effects.
+	let rosc = unsafe { &*pac::ROSC::ptr() };
+	rosc.randombit.read().randombit().bit() as u8
+}
+
+/// The SHA-256 hash of exactly `POOL_LEN` bytes.
+fn sha256(data: &[u8; POOL_LEN]) -> [u8; OUTPUT_LEN] {
+	let mut state = SHA256_H;
+	for block in data.chunks(64) {
+		let mut words = [0u32; 16];
+		for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+			*word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+		}
+		sha256_compress(&mut state, &words);
+	}
+	// The padding: a one bit, zeros, then the length in bits
+	let mut padding = [0u32; 16];
+	padding[0] = 0x8000_0000;
+	padding[15] = (POOL_LEN * 8) as u32;
+	sha256_compress(&mut state, &padding);
+
+	let mut output = [0u8; OUTPUT_LEN];
+	for (bytes, word) in output.chunks_mut(4).zip(state.iter()) {
+		bytes.copy_from_slice(&word.to_be_bytes());
+	}
+	output
+}
+
+/// Run one 64 byte block through the SHA-256 compression function.
+fn sha256_compress(state: &mut [u32; 8], block: &[u32; 16]) {
+	let mut w = [0u32; 64];
+	w[0..16].copy_from_slice(block);
+	for i in 16..64 {
+		let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+		let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+		w[i] = w[i - 16]
+			.wrapping_add(s0)
+			.wrapping_add(w[i - 7])
+			.wrapping_add(s1);
+	}
+	let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+	for i in 0..64 {
+		let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+		let ch = (e & f) ^ (!e & g);
+		let temp1 = h
+			.wrapping_add(s1)
+			.wrapping_add(ch)
+			.wrapping_add(SHA256_K[i])
+			.wrapping_add(w[i]);
+		let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+		let maj = (a & b) ^ (a & c) ^ (b & c);
+		let temp2 = s0.wrapping_add(maj);
+		h = g;
+		g = f;
+		f = e;
+		e = d.wrapping_add(temp1);
+		d = c;
+		c = b;
+		b = a;
+		a = temp1.wrapping_add(temp2);
+	}
+	for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+		*word = word.wrapping_add(value);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,104 @@
+//! # ROM disk for the Neotron Pico
+//!
+//! The end of the flash chip (`FLASH_ROMDISK` in `memory.x`) holds a
+//! read-only disk image, which the OS sees as block device 1. `build.rs`
+//! makes it at build time - a FAT volume holding whatever is in the
+//! `romdisk` directory - so the OS always has a volume to boot from, even
+//! with no SD card in.
+//!
+//! If there is no `romdisk` directory, the image is empty and the device
+//! says it has no media.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::bootslot::BLOCK_SIZE;
+use core::convert::TryFrom;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which reading the ROM disk can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The buffer isn't a whole number of blocks
+	BadLength,
+	/// The blocks run off the end of the disk
+	OutOfRange,
+	/// The blocks don't hold what we were given
+	VerifyFailed,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The disk image `build.rs` made for us.
+#[link_section = ".flash_romdisk"]
+#[used]
+static IMAGE: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/romdisk.img")).len()] =
+	*include_bytes!(concat!(env!("OUT_DIR"), "/romdisk.img"));
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// How many blocks the ROM disk has. Zero means there's no ROM disk.
+pub fn num_blocks() -> u64 {
+	(IMAGE.len() / BLOCK_SIZE) as u64
+}
+
+/// Read whole blocks from the ROM disk.
+pub fn read_blocks(block: u64, data: &mut [u8]) -> Result<(), Error> {
+	data.copy_from_slice(blocks(block, data.len())?);
+	Ok(())
+}
+
+/// Check whole blocks on the ROM disk hold the given data.
+pub fn verify_blocks(block: u64, data: &[u8]) -> Result<(), Error> {
+	if blocks(block, data.len())? == data {
+		Ok(())
+	} else {
+		Err(Error::VerifyFailed)
+	}
+}
+
+/// Get `len` bytes of the disk image, starting at the given block.
+fn blocks(block: u64, len: usize) -> Result<&'static [u8], Error> {
+	if (len % BLOCK_SIZE) != 0 {
+		return Err(Error::BadLength);
+	}
+	let start = usize::try_from(block)
+		.ok()
+		.and_then(|block| block.checked_mul(BLOCK_SIZE))
+		.ok_or(Error::OutOfRange)?;
+	start
+		.checked_add(len)
+		.and_then(|end| IMAGE.get(start..end))
+		.ok_or(Error::OutOfRange)
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,336 @@
+//! # Real Time Clock driver for the Neotron Pico
+//!
+//! The Neotron Pico has a battery-backed Microchip MCP7940N Real Time Clock
+//! on its I2C bus (I2C1, with SDA on GPIO14 and SCL on GPIO15). The RTC
+//! keeps the date and time as BCD fields. We convert those to and from the
+//! Neotron epoch (seconds since 2000-01-01T00:00:00), which is conveniently
+//! also the start of the RTC's 100 year range.
+//!
+//! If the RTC isn't fitted, or its oscillator has stopped (e.g. the battery
+//! went flat), we report the epoch until someone sets the time.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use defmt::{debug, warn};
+use embedded_hal::blocking::i2c::{Write as _, WriteRead as _};
+use rp_pico::hal::{self, pac};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The I2C bus, with SDA and SCL attached
+pub type I2c = hal::i2c::I2C<
+	pac::I2C1,
+	(
+		hal::gpio::Pin<hal::gpio::bank0::Gpio14, hal::gpio::FunctionI2C>,
+		hal::gpio::Pin<hal::gpio::bank0::Gpio15, hal::gpio::FunctionI2C>,
+	),
+>;
+
+/// The ways in which talking to the RTC can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// `init` hasn't been called
+	NoBus,
+	/// The RTC didn't acknowledge us, so probably isn't fitted
+	NoResponse,
+	/// The RTC's oscillator isn't running, so the time is meaningless
+	NotRunning,
+	/// The RTC holds a date or time which doesn't exist (e.g. month 13)
+	BadDateTime,
+}
+
+/// A calendar date and time, as the RTC holds it.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct DateTime {
+	/// Years since 2000 (0 to 99)
+	pub year: u8,
+	/// Month of the year (1 to 12)
+	pub month: u8,
+	/// Day of the month (1 to 31)
+	pub day: u8,
+	/// Hours (0 to 23)
+	pub hours: u8,
+	/// Minutes (0 to 59)
+	pub minutes: u8,
+	/// Seconds (0 to 59)
+	pub seconds: u8,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The MCP7940N's 7-bit I2C address
+pub const RTC_ADDRESS: u8 = 0x6F;
+
+/// The first of the seven time-keeping registers (seconds)
+const REG_RTCSEC: u8 = 0x00;
+
+/// The weekday register, which also holds the status bits
+const REG_RTCWKDAY: u8 = 0x03;
+
+/// The first byte of the battery-backed SRAM
+const REG_SRAM: u8 = 0x20;
+
+/// How many bytes of battery-backed SRAM there are
+pub const SRAM_LEN: usize = 64;
+
+/// In RTCSEC: start the oscillator
+const RTCSEC_ST: u8 = 1 << 7;
+
+/// In RTCWKDAY: the oscillator is running
+const RTCWKDAY_OSCRUN: u8 = 1 << 5;
+
+/// In RTCWKDAY: switch to the battery when the main supply fails
+const RTCWKDAY_VBATEN: u8 = 1 << 3;
+
+/// How long we wait for the oscillator to stop before setting the time
+const STOP_TIMEOUT_MS: u32 = 10;
+
+/// Seconds in a day
+const SECONDS_PER_DAY: u32 = 86_400;
+
+/// Days in each month, in a year that isn't a leap year
+const DAYS_IN_MONTH: [u8; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+/// Holds the I2C bus, once `init` has been called.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut I2C_BUS: Option<I2c> = None;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Take ownership of the I2C bus.
+pub fn init(i2c: I2c) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		I2C_BUS = Some(i2c);
+	}
+	match get_time() {
+		Ok(secs) => debug!("RTC OK: {} secs", secs),
+		Err(e) => warn!("RTC: {}", e),
+	}
+}
+
+/// Get the time, in seconds since the Neotron epoch.
+pub fn get_time() -> Result<u32, Error> {
+	let mut regs = [0u8; 7];
+	read_registers(REG_RTCSEC, &mut regs)?;
+	if (regs[3] & RTCWKDAY_OSCRUN) == 0 {
+		return Err(Error::NotRunning);
+	}
+	let date_time = DateTime {
+		seconds: from_bcd(regs[0] & 0x7F),
+		minutes: from_bcd(regs[1] & 0x7F),
+		// We always use 24-hour mode
+		hours: from_bcd(regs[2] & 0x3F),
+		day: from_bcd(regs[4] & 0x3F),
+		month: from_bcd(regs[5] & 0x1F),
+		year: from_bcd(regs[6]),
+	};
+	date_time.to_seconds()
+}
+
+/// Set the time, in seconds since the Neotron epoch, and make sure the
+/// oscillator and the battery backup are on.
+///
+/// The RTC only goes up to the end of 2099, so later times are clamped.
+pub fn set_time(secs: u32) -> Result<(), Error> {
+	let date_time = DateTime::from_seconds(secs);
+	debug!("Setting RTC to {}", date_time);
+
+	// The datasheet says to stop the oscillator before changing the time
+	write_registers(REG_RTCSEC, &[0x00])?;
+	let start = crate::ticks_ms();
+	loop {
+		let mut wkday = [0u8];
+		read_registers(REG_RTCWKDAY, &mut wkday)?;
+		if (wkday[0] & RTCWKDAY_OSCRUN) == 0 {
+			break;
+		}
+		if crate::ticks_ms().wrapping_sub(start) > STOP_TIMEOUT_MS {
+			warn!("RTC oscillator didn't stop");
+			break;
+		}
+	}
+
+	// 2000-01-01 was a Saturday. We use 1 for Monday.
+	let weekday = (((secs / SECONDS_PER_DAY) + 5) % 7) as u8 + 1;
+	write_registers(
+		REG_RTCSEC,
+		&[
+			RTCSEC_ST | to_bcd(date_time.seconds),
+			to_bcd(date_time.minutes),
+			to_bcd(date_time.hours),
+			RTCWKDAY_VBATEN | weekday,
+			to_bcd(date_time.day),
+			to_bcd(date_time.month),
+			to_bcd(date_time.year),
+		],
+	)
+}
+
+/// Read the whole of the battery-backed SRAM.
+pub fn read_sram(buffer: &mut [u8; SRAM_LEN]) -> Result<(), Error> {
+	read_registers(REG_SRAM, buffer)
+}
+
+/// Write the whole of the battery-backed SRAM.
+pub fn write_sram(data: &[u8; SRAM_LEN]) -> Result<(), Error> {
+	write_registers(REG_SRAM, data)
+}
+
+/// Write some bytes to another chip on the RTC's I2C bus (e.g. the audio
+/// codec).
+pub fn bus_write(address: u8, data: &[u8]) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let i2c = unsafe { I2C_BUS.as_mut() }.ok_or(Error::NoBus)?;
+	i2c.write(address, data).map_err(|_| Error::NoResponse)
+}
+
+/// Read one or more consecutive RTC registers.
+fn read_registers(first: u8, buffer: &mut [u8]) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let i2c = unsafe { I2C_BUS.as_mut() }.ok_or(Error::NoBus)?;
+	i2c.write_read(RTC_ADDRESS, &[first], buffer)
+		.map_err(|_| Error::NoResponse)
+}
+
+/// Write one or more consecutive RTC registers.
+fn write_registers(first: u8, data: &[u8]) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let i2c = unsafe { I2C_BUS.as_mut() }.ok_or(Error::NoBus)?;
+	let mut buffer = [0u8; SRAM_LEN + 1];
+	let buffer = buffer.get_mut(0..data.len() + 1).ok_or(Error::NoResponse)?;
+	buffer[0] = first;
+	buffer[1..].copy_from_slice(data);
+	i2c.write(RTC_ADDRESS, buffer)
+		.map_err(|_| Error::NoResponse)
+}
+
+/// Convert a two-digit BCD value to binary.
+const fn from_bcd(value: u8) -> u8 {
+	((value >> 4) * 10) + (value & 0x0F)
+}
+
+/// Convert a binary value (0 to 99) to two-digit BCD.
+const fn to_bcd(value: u8) -> u8 {
+	((value / 10) << 4) | (value % 10)
+}
+
+/// Is this year (since 2000) a leap year? This is right until 2100.
+const fn is_leap_year(year: u8) -> bool {
+	(year % 4) == 0
+}
+
+/// How many days are in the given month (1 to 12) of the given year.
+fn days_in_month(year: u8, month: u8) -> u32 {
+	if month == 2 && is_leap_year(year) {
+		29
+	} else {
+		u32::from(DAYS_IN_MONTH[usize::from(month.clamp(1, 12) - 1)])
+	}
+}
+
+impl DateTime {
+	/// Is this a real date and time, which the RTC can hold?
+	pub fn is_valid(&self) -> bool {
+		self.year <= 99
+			&& (1..=12).contains(&self.month)
+			&& self.day >= 1
+			&& u32::from(self.day) <= days_in_month(self.year, self.month)
+			&& self.hours <= 23
+			&& self.minutes <= 59
+			&& self.seconds <= 59
+	}
+
+	/// Convert to seconds since the Neotron epoch.
+	///
+	/// Fails if this isn't a valid date and time (see `is_valid`), as the
+	/// answer would be meaningless, if it fitted in a `u32` at all.
+	pub fn to_seconds(&self) -> Result<u32, Error> {
+		if !self.is_valid() {
+			return Err(Error::BadDateTime);
+		}
+		let mut days: u32 = 0;
+		for year in 0..self.year {
+			days += if is_leap_year(year) { 366 } else { 365 };
+		}
+		for month in 1..self.month {
+			days += days_in_month(self.year, month);
+		}
+		days += u32::from(self.day - 1);
+		Ok((days * SECONDS_PER_DAY)
+			+ (u32::from(self.hours) * 3600)
+			+ (u32::from(self.minutes) * 60)
+			+ u32::from(self.seconds))
+	}
+
+	/// Convert from seconds since the Neotron epoch.
+	pub fn from_seconds(secs: u32) -> DateTime {
+		let mut days = secs / SECONDS_PER_DAY;
+		let secs_of_day = secs % SECONDS_PER_DAY;
+		let mut year = 0;
+		loop {
+			let days_in_year = if is_leap_year(year) { 366 } else { 365 };
+			if days < days_in_year {
+				break;
+			}
+			if year == 99 {
+				// Past the end of what the RTC can hold
+				return DateTime {
+					year: 99,
+					month: 12,
+					day: 31,
+					hours: 23,
+					minutes: 59,
+					seconds: 59,
+				};
+			}
+			days -= days_in_year;
+			year += 1;
+		}
+		let mut month = 1;
+		while days >= days_in_month(year, month) {
+			days -= days_in_month(year, month);
+			month += 1;
+		}
+		DateTime {
+			year,
+			month,
+			day: days as u8 + 1,
+			hours: (secs_of_day / 3600) as u8,
+			minutes: ((secs_of_day / 60) % 60) as u8,
+			seconds: (secs_of_day % 60) as u8,
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,971 @@
+//! # SD card driver for the Neotron Pico
+//!
+//! The built-in SD card slot is on the Neotron Bus, and we talk to the card
+//! in SPI mode. The card is initialised the first time anyone asks about it,
+//! and again after any error (in case it has been swapped for another one).
+//!
+//! Standard capacity cards (SDSC) are addressed in bytes and high capacity
+//! cards (SDHC and SDXC) in blocks, but callers always use block numbers.
+//! Blocks are always `BLOCK_SIZE` bytes.
+//!
+//! Once a card has been found, it is also used for the crash log.
+//!
+//! The slot's card-detect switch (on the I/O expander) is debounced by
+//! `poll_card_detect`. When a card goes in, we initialise it straight away;
+//! when it comes out, we forget it. With no card in, we don't waste time
+//! trying to initialise one. Slots without a switch can turn this off in the
+//! BIOS configuration.
+//!
+//! An SD card adapter in an expansion slot can be used too. Nothing on the
+//! bus says what sort of card is in each slot, and SD commands might upset
+//! some other card, so the BIOS configuration says which slot to use. That
+//! card has no card-detect switch, no background transfers, and isn't used
+//! for the crash log.
+//!
+//! The OS can also start a transfer with `start_read` or `start_write`, and
+//! get on with something else while the DMA engine moves each block (see
+//! `neobus::with_device_then_dma`). `poll_transfer` sends the command for
+//! the next block, and waits for the card to finish writing, a step at a
+//! time. Only one such transfer can run at once, and nothing else can use
+//! the card until it's done.
+//!
+//! Transfers of more than one block use the card's multiple block commands
+//! (CMD18 and CMD25), and the data itself is moved by DMA (see
+//! `neobus::read_dma`), so sequential transfers run at the full SPI clock
+//! rate.
+//!
+//! The card is initialised at `INIT_CLOCK_HZ`, as the SD specification
+//! requires, but blocks are moved at `DATA_CLOCK_HZ`. The rest of the bus
+//! (including the I/O expander that selects the card) stays at the normal
+//! bus speed.
+//!
+//! We only support SPI mode. The native 4-bit SD mode would need CLK, CMD
+//! and DAT0 to DAT3 on GPIO pins (with DAT0 to DAT3 on four consecutive pins,
+//! so a PIO state machine could drive them), but the slot on the Neotron Pico
+//! is wired to the Neotron Bus, and there aren't four consecutive spare GPIO
+//! pins anyway.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::bootslot::{Block, BLOCK_SIZE};
+use crate::{config, crashlog, neobus};
+use defmt::{debug, info, warn};
+use embedded_hal::blocking::spi::{Transfer as _, Write as _};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which talking to the SD card can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The Neotron Bus hasn't been set up yet
+	NoBus,
+	/// There's no card in the slot, or it isn't answering
+	NoCard,
+	/// The card can't run at 3.3V
+	BadVoltage,
+	/// The card took too long to do something
+	Timeout,
+	/// The card gave this R1 response to a command
+	CommandFailed(u8),
+	/// The card gave this error token instead of a block of data
+	ReadFailed(u8),
+	/// The card gave this data response to a block of data
+	WriteFailed(u8),
+	/// The card's CSD register is a version we don't understand
+	BadCsd,
+	/// The request goes past the end of the card
+	OutOfRange,
+	/// The data isn't a whole number of blocks
+	BadLength,
+	/// The data on the card doesn't match the data given
+	VerifyFailed,
+	/// A background transfer is still running
+	Busy,
+}
+
+/// The kinds of SD card we can talk to.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum CardType {
+	/// Version 1.x Standard Capacity card (addressed in bytes)
+	SdV1,
+	/// Version 2.0 or later Standard Capacity card (addressed in bytes)
+	SdV2,
+	/// High or Extended Capacity card (addressed in blocks)
+	Sdhc,
+}
+
+/// What we know about the card in the slot.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct CardInfo {
+	/// What sort of card it is
+	pub card_type: CardType,
+	/// How many blocks the card holds
+	pub num_blocks: u64,
+}
+
+/// The debounced state of the card-detect switch.
+struct CardDetect {
+	/// Is there a card in the slot, as far as we've told anyone?
+	present: bool,
+	/// What the switch said last time we looked
+	raw: bool,
+	/// When `raw` last changed
+	raw_changed_at: u32,
+}
+
+/// A background transfer, started by `start_read` or `start_write`.
+struct Transfer {
+	/// Are we writing to the card (or reading from it)?
+	write: bool,
+	/// The next block to move
+	lba: u64,
+	/// Where the next block comes from, or goes to
+	data: *mut u8,
+	/// How many blocks are left, including the one in flight
+	blocks_left: usize,
+	/// What we're waiting for
+	stage: TransferStage,
+	/// When we started waiting for the card to finish writing
+	busy_since_ms: u32,
+}
+
+/// Where a background transfer is up to, for each block.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum TransferStage {
+	/// The next block's command needs sending
+	Command,
+	/// The DMA engine is moving a block
+	Data,
+	/// The card is writing a block
+	Busy,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The SPI clock speed we use while the card is being initialised
+const INIT_CLOCK_HZ: u32 = 400_000;
+
+/// The SPI clock speed we use to read and write blocks. All SD cards can
+/// manage 25 MHz, but the bus runs through the slot connectors, so we don't
+/// push our luck.
+const DATA_CLOCK_HZ: u32 = 12_000_000;
+
+/// GO_IDLE_STATE - reset the card, and put it into SPI mode
+const CMD0: u8 = 0;
+
+/// SEND_IF_COND - check the card can run at our voltage
+const CMD8: u8 = 8;
+
+/// SEND_CSD - read the Card Specific Data register
+const CMD9: u8 = 9;
+
+/// STOP_TRANSMISSION - end a multiple block read
+const CMD12: u8 = 12;
+
+/// SET_BLOCKLEN - set the block size (Standard Capacity cards only)
+const CMD16: u8 = 16;
+
+/// READ_SINGLE_BLOCK
+const CMD17: u8 = 17;
+
+/// READ_MULTIPLE_BLOCK
+const CMD18: u8 = 18;
+
+/// WRITE_BLOCK
+const CMD24: u8 = 24;
+
+/// WRITE_MULTIPLE_BLOCK
+const CMD25: u8 = 25;
+
+/// APP_CMD - the next command is an application specific command
+const CMD55: u8 = 55;
+
+/// READ_OCR - read the Operating Conditions Register
+const CMD58: u8 = 58;
+
+/// SD_SEND_OP_COND (application specific) - start the card's initialisation
+const ACMD41: u8 = 41;
+
+/// The CMD8 argument: 2.7V to 3.6V, and a check pattern of `0xAA`
+const CMD8_ARG: u32 = 0x0000_01AA;
+
+/// The ACMD41 argument bit that says we can cope with high capacity cards
+const ACMD41_HCS: u32 = 1 << 30;
+
+/// The OCR bit that says this is a high capacity card (in the top byte)
+const OCR_CCS: u8 = 1 << 6;
+
+/// The R1 response when everything is fine
+const R1_READY: u8 = 0x00;
+
+/// The R1 response when the card is still initialising
+const R1_IDLE: u8 = 0x01;
+
+/// The R1 bit that says the card didn't recognise the command
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+/// Comes before a block of data, except in a multiple block write
+const TOKEN_START_BLOCK: u8 = 0xFE;
+
+/// Comes before each block of data in a multiple block write
+const TOKEN_START_MULTI: u8 = 0xFC;
+
+/// Ends a multiple block write
+const TOKEN_STOP_TRAN: u8 = 0xFD;
+
+/// The (masked) data response when a block was written OK
+const DATA_ACCEPTED: u8 = 0x05;
+
+/// How many times we send CMD0 before deciding there's no card
+const CMD0_ATTEMPTS: usize = 10;
+
+/// How many bytes we wait for a response to a command
+const MAX_RESPONSE_BYTES: usize = 8;
+
+/// How long a card may take to initialise
+const INIT_TIMEOUT_MS: u32 = 1000;
+
+/// How long a card may take to start sending a block
+const READ_TIMEOUT_MS: u32 = 100;
+
+/// How long a card may be busy after a write
+const WRITE_TIMEOUT_MS: u32 = 500;
+
+/// How long the card-detect switch must be still before we believe it. A
+/// card being pushed in also needs a moment for its supply to settle.
+const CARD_DETECT_DEBOUNCE_MS: u32 = 250;
+
+/// The card we found, or `None` if we haven't looked (or it went away).
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut CARD: Option<CardInfo> = None;
+
+/// The card in the expansion slot, and which slot it was in, or `None` if
+/// we haven't looked (or it went away).
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut EXPANSION_CARD: Option<(neobus::Device, CardInfo)> = None;
+
+/// The card-detect switch.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut DETECT: CardDetect = CardDetect {
+	present: true,
+	raw: true,
+	raw_changed_at: 0,
+};
+
+/// The background transfer, if there is one.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut TRANSFER: Option<Transfer> = None;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Find out about the card in the slot, initialising it if required.
+pub fn card_info() -> Result<CardInfo, Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	if let Some(card) = unsafe { CARD } {
+		return Ok(card);
+	}
+	if !switch_says_card() {
+		return Err(Error::NoCard);
+	}
+	let card = find_card(neobus::Device::SdCard)?;
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		CARD = Some(card);
+	}
+	crashlog::set_storage(crashlog::Storage {
+		read_block: crash_read_block,
+		write_block: crash_write_block,
+	});
+	Ok(card)
+}
+
+/// Find out about the card in the expansion slot given in the BIOS
+/// configuration, initialising it if required.
+pub fn expansion_card_info() -> Result<CardInfo, Error> {
+	let device = expansion_slot().ok_or(Error::NoCard)?;
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	match unsafe { EXPANSION_CARD } {
+		Some((slot, card)) if slot == device => return Ok(card),
+		_ => {}
+	}
+	let card = find_card(device)?;
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		EXPANSION_CARD = Some((device, card));
+	}
+	Ok(card)
+}
+
+/// Which expansion slot has an SD card in it, according to the BIOS
+/// configuration. The OS mustn't use that slot itself.
+pub fn expansion_slot() -> Option<neobus::Device> {
+	neobus::slot(config::get().storage_slot)
+}
+
+/// Sample the card-detect switch.
+///
+/// Returns `true` if a card has just been put in or taken out. Call this
+/// regularly - the debounce timing is only as good as the rate at which this
+/// is called.
+pub fn poll_card_detect() -> bool {
+	if !config::get().sd_card_detect {
+		return false;
+	}
+	let raw = match neobus::read_inputs() {
+		Some(inputs) => (inputs & neobus::input_card_detect()) != 0,
+		None => return false,
+	};
+	let now = crate::ticks_ms();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let detect = unsafe { &mut DETECT };
+	if raw != detect.raw {
+		// Still bouncing - start timing again
+		detect.raw = raw;
+		detect.raw_changed_at = now;
+		return false;
+	}
+	if raw == detect.present || now.wrapping_sub(detect.raw_changed_at) < CARD_DETECT_DEBOUNCE_MS {
+		return false;
+	}
+	detect.present = raw;
+	// Whatever the card was, it isn't now
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		CARD = None;
+	}
+	if raw {
+		info!("SD card inserted");
+		let _ = card_info();
+	} else {
+		info!("SD card removed");
+	}
+	true
+}
+
+/// Read one or more whole blocks from the card, starting at `start`.
+pub fn read_blocks(start: u64, data: &mut [u8]) -> Result<(), Error> {
+	with_card(start, data.len(), |spi, card| read(spi, card, start, data))
+}
+
+/// Write one or more whole blocks to the card, starting at `start`.
+pub fn write_blocks(start: u64, data: &[u8]) -> Result<(), Error> {
+	with_card(start, data.len(), |spi, card| write(spi, card, start, data))
+}
+
+/// Check one or more whole blocks on the card, starting at `start`, match
+/// the given data.
+pub fn verify_blocks(start: u64, data: &[u8]) -> Result<(), Error> {
+	with_card(start, data.len(), |spi, card| {
+		verify(spi, card, start, data)
+	})
+}
+
+/// Read one or more whole blocks from the expansion slot card, starting at
+/// `start`.
+pub fn expansion_read_blocks(start: u64, data: &mut [u8]) -> Result<(), Error> {
+	with_expansion_card(start, data.len(), |spi, card| read(spi, card, start, data))
+}
+
+/// Write one or more whole blocks to the expansion slot card, starting at
+/// `start`.
+pub fn expansion_write_blocks(start: u64, data: &[u8]) -> Result<(), Error> {
+	with_expansion_card(start, data.len(), |spi, card| write(spi, card, start, data))
+}
+
+/// Check one or more whole blocks on the expansion slot card, starting at
+/// `start`, match the given data.
+pub fn expansion_verify_blocks(start: u64, data: &[u8]) -> Result<(), Error> {
+	with_expansion_card(start, data.len(), |spi, card| {
+		verify(spi, card, start, data)
+	})
+}
+
+/// Start reading whole blocks from the card into `data`, in the background.
+///
+/// # Safety
+///
+/// `data` must point to `len` bytes, which stay valid (and which nothing
+/// else touches) until `poll_transfer` says the transfer is over.
+pub unsafe fn start_read(start: u64, data: *mut u8, len: usize) -> Result<(), Error> {
+	start_transfer(false, start, data, len)
+}
+
+/// Start writing whole blocks from `data` to the card, in the background.
+///
+/// # Safety
+///
+/// `data` must point to `len` bytes, which stay valid until `poll_transfer`
+/// says the transfer is over.
+pub unsafe fn start_write(start: u64, data: *const u8, len: usize) -> Result<(), Error> {
+	start_transfer(true, start, data as *mut u8, len)
+}
+
+/// Move the background transfer along, if there is one.
+///
+/// Returns `Ok(true)` once it has finished (or if there wasn't one), and
+/// `Ok(false)` while it's still going. If it fails, we return the error,
+/// and the transfer is over.
+pub fn poll_transfer() -> Result<bool, Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let transfer = match unsafe { TRANSFER.as_mut() } {
+		Some(transfer) => transfer,
+		None => return Ok(true),
+	};
+	let result = step_transfer(transfer);
+	if result != Ok(false) {
+		// Note (safety): As above.
+		unsafe {
+			TRANSFER = None;
+		}
+		if let Err(e) = result {
+			forget_card_after(e);
+		}
+	}
+	result
+}
+
+/// Check a transfer fits on the card, then select the card and run the
+/// given closure.
+///
+/// If the card fails, we forget about it, so it will be initialised again
+/// next time.
+fn with_card<F>(start: u64, len: usize, f: F) -> Result<(), Error>
+where
+	F: FnOnce(&mut neobus::Spi, &CardInfo) -> Result<(), Error>,
+{
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	if unsafe { TRANSFER.is_some() } {
+		return Err(Error::Busy);
+	}
+	let card = check_range(card_info, start, len)?;
+	let result = neobus::with_device_at(neobus::Device::SdCard, DATA_CLOCK_HZ, |spi| f(spi, &card))
+		.unwrap_or(Err(Error::NoBus));
+	if let Err(e) = result {
+		forget_card_after(e);
+	}
+	result
+}
+
+/// As `with_card`, but for the card in the expansion slot.
+fn with_expansion_card<F>(start: u64, len: usize, f: F) -> Result<(), Error>
+where
+	F: FnOnce(&mut neobus::Spi, &CardInfo) -> Result<(), Error>,
+{
+	let card = check_range(expansion_card_info, start, len)?;
+	let device = expansion_slot().ok_or(Error::NoCard)?;
+	let result = neobus::with_device_at(device, DATA_CLOCK_HZ, |spi| f(spi, &card))
+		.unwrap_or(Err(Error::NoBus));
+	if let Err(e) = result {
+		if e.is_card_fault() {
+			warn!("Expansion slot SD card failed: {}", e);
+			// Note (safety): Only called from Core 0, and never from an
+			// interrupt.
+			unsafe {
+				EXPANSION_CARD = None;
+			}
+		}
+	}
+	result
+}
+
+/// Initialise the card on the given device.
+fn find_card(device: neobus::Device) -> Result<CardInfo, Error> {
+	// Cards must be initialised slowly, with 74 or more clock pulses first
+	neobus::set_clock_speed(INIT_CLOCK_HZ);
+	neobus::with_no_device(|spi| {
+		let _ = spi.write(&[0xFF; 10]);
+	});
+	let result = neobus::with_device(device, init_card);
+	neobus::set_clock_speed(neobus::DEFAULT_CLOCK_HZ);
+
+	let card = result.unwrap_or(Err(Error::NoBus))?;
+	info!("SD card on {}: {}", device, card);
+	Ok(card)
+}
+
+/// Check a transfer is whole blocks, and fits on the card we get from
+/// `get_card`.
+fn check_range(
+	get_card: fn() -> Result<CardInfo, Error>,
+	start: u64,
+	len: usize,
+) -> Result<CardInfo, Error> {
+	if len == 0 || (len % BLOCK_SIZE) != 0 {
+		return Err(Error::BadLength);
+	}
+	let card = get_card()?;
+	let end = start
+		.checked_add((len / BLOCK_SIZE) as u64)
+		.ok_or(Error::OutOfRange)?;
+	if end > card.num_blocks {
+		return Err(Error::OutOfRange);
+	}
+	Ok(card)
+}
+
+/// If an error means the card has failed, forget about it, so it will be
+/// initialised again next time.
+fn forget_card_after(error: Error) {
+	if error.is_card_fault() {
+		warn!("SD card failed: {}", error);
+		// Note (safety): Only called from Core 0, and never from an interrupt.
+		unsafe {
+			CARD = None;
+		}
+	}
+}
+
+/// Set up a background transfer, and send the first command.
+///
+/// # Safety
+///
+/// As for `start_read` and `start_write`.
+unsafe fn start_transfer(write: bool, start: u64, data: *mut u8, len: usize) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	if TRANSFER.is_some() {
+		return Err(Error::Busy);
+	}
+	check_range(card_info, start, len)?;
+	TRANSFER = Some(Transfer {
+		write,
+		lba: start,
+		data,
+		blocks_left: len / BLOCK_SIZE,
+		stage: TransferStage::Command,
+		busy_since_ms: 0,
+	});
+	poll_transfer().map(|_| ())
+}
+
+/// Take the background transfer as far as we can without waiting.
+fn step_transfer(transfer: &mut Transfer) -> Result<bool, Error> {
+	loop {
+		match transfer.stage {
+			TransferStage::Command => {
+				if transfer.blocks_left == 0 {
+					return Ok(true);
+				}
+				let card = card_info()?;
+				let (write, lba, data) = (transfer.write, transfer.lba, transfer.data);
+				// Note (safety): Whoever started the transfer promised the
+				// buffer stays valid until it's over.
+				unsafe {
+					neobus::with_device_then_dma(neobus::Device::SdCard, DATA_CLOCK_HZ, |spi| {
+						start_block(spi, &card, write, lba, data)
+					})
+				}
+				.unwrap_or(Err(Error::NoBus))?;
+				transfer.stage = TransferStage::Data;
+			}
+			TransferStage::Data => {
+				let last_byte = match neobus::dma_finished() {
+					Some(last_byte) => last_byte,
+					None => return Ok(false),
+				};
+				if transfer.write {
+					// The last byte is the card's data response
+					let response = last_byte & 0x1F;
+					if response != DATA_ACCEPTED {
+						return Err(Error::WriteFailed(response));
+					}
+					transfer.stage = TransferStage::Busy;
+					transfer.busy_since_ms = crate::ticks_ms();
+				} else {
+					transfer.next_block();
+				}
+			}
+			TransferStage::Busy => {
+				// The card can be de-selected while it's busy, so we just
+				// check in on it each time we're polled.
+				let busy = neobus::with_device_at(neobus::Device::SdCard, DATA_CLOCK_HZ, |spi| {
+					read_byte(spi) != 0xFF
+				})
+				.ok_or(Error::NoBus)?;
+				if busy {
+					if crate::ticks_ms().wrapping_sub(transfer.busy_since_ms) > WRITE_TIMEOUT_MS {
+						return Err(Error::Timeout);
+					}
+					return Ok(false);
+				}
+				transfer.next_block();
+			}
+		}
+	}
+}
+
+/// Send the command for one block of a background transfer, and get the
+/// data ready for the DMA engine.
+fn start_block(
+	spi: &mut neobus::Spi,
+	card: &CardInfo,
+	write: bool,
+	lba: u64,
+	data: *mut u8,
+) -> Result<neobus::Dma, Error> {
+	if write {
+		check_ready(command(spi, CMD24, card.address(lba))?)?;
+		let _ = spi.write(&[0xFF, TOKEN_START_BLOCK]);
+		// The data, then the (dummy) CRC, then the data response
+		Ok(neobus::Dma::Write {
+			data,
+			len: BLOCK_SIZE,
+			extra: 3,
+		})
+	} else {
+		check_ready(command(spi, CMD17, card.address(lba))?)?;
+		wait_for_token(spi)?;
+		// The data, then the CRC (which we throw away)
+		Ok(neobus::Dma::Read {
+			data,
+			len: BLOCK_SIZE,
+			extra: 2,
+		})
+	}
+}
+
+/// Does the card-detect switch say there's a card in the slot? If we're
+/// not using the switch, it always does.
+fn switch_says_card() -> bool {
+	if !config::get().sd_card_detect {
+		return true;
+	}
+	neobus::read_inputs()
+		.map(|inputs| (inputs & neobus::input_card_detect()) != 0)
+		.unwrap_or(true)
+}
+
+/// Take a newly selected card from power-up to ready-for-data.
+fn init_card(spi: &mut neobus::Spi) -> Result<CardInfo, Error> {
+	// CMD0 puts the card into SPI mode. It sometimes takes a few goes.
+	let mut r1 = 0xFF;
+	for _ in 0..CMD0_ATTEMPTS {
+		r1 = command(spi, CMD0, 0).unwrap_or(0xFF);
+		if r1 == R1_IDLE {
+			break;
+		}
+	}
+	if r1 != R1_IDLE {
+		return Err(Error::NoCard);
+	}
+
+	// Only Version 2.0 cards understand CMD8
+	let r1 = command(spi, CMD8, CMD8_ARG)?;
+	let mut card_type = if (r1 & R1_ILLEGAL_COMMAND) != 0 {
+		CardType::SdV1
+	} else {
+		let mut r7 = [0xFF; 4];
+		let _ = spi.transfer(&mut r7);
+		if (r7[2] & 0x0F) != 0x01 || r7[3] != 0xAA {
+			return Err(Error::BadVoltage);
+		}
+		CardType::SdV2
+	};
+
+	// Wait for the card to finish initialising
+	let arg = if card_type == CardType::SdV2 {
+		ACMD41_HCS
+	} else {
+		0
+	};
+	let start = crate::ticks_ms();
+	loop {
+		command(spi, CMD55, 0)?;
+		match command(spi, ACMD41, arg)? {
+			R1_READY => break,
+			R1_IDLE => {}
+			r1 => return Err(Error::CommandFailed(r1)),
+		}
+		if crate::ticks_ms().wrapping_sub(start) > INIT_TIMEOUT_MS {
+			return Err(Error::Timeout);
+		}
+	}
+
+	if card_type == CardType::SdV2 {
+		check_ready(command(spi, CMD58, 0)?)?;
+		let mut ocr = [0xFF; 4];
+		let _ = spi.transfer(&mut ocr);
+		if (ocr[0] & OCR_CCS) != 0 {
+			card_type = CardType::Sdhc;
+		}
+	}
+	if card_type != CardType::Sdhc {
+		check_ready(command(spi, CMD16, BLOCK_SIZE as u32)?)?;
+	}
+
+	check_ready(command(spi, CMD9, 0)?)?;
+	let mut csd = [0u8; 16];
+	read_data(spi, &mut csd)?;
+	let num_blocks = parse_csd(&csd)?;
+	debug!("SD CSD: {=[u8]:02x}", csd);
+
+	Ok(CardInfo {
+		card_type,
+		num_blocks,
+	})
+}
+
+/// Read whole blocks from the selected card.
+///
+/// More than one block is read with a single CMD18, so the card streams
+/// them to us one after another.
+fn read(spi: &mut neobus::Spi, card: &CardInfo, start: u64, data: &mut [u8]) -> Result<(), Error> {
+	let multiple = data.len() > BLOCK_SIZE;
+	let cmd = if multiple { CMD18 } else { CMD17 };
+	check_ready(command(spi, cmd, card.address(start))?)?;
+	for block in data.chunks_exact_mut(BLOCK_SIZE) {
+		read_data(spi, block)?;
+	}
+	if multiple {
+		stop_reading(spi)?;
+	}
+	Ok(())
+}
+
+/// Write whole blocks to the selected card.
+///
+/// More than one block is written with a single CMD25, so we don't send a
+/// command (and wait for the reply) for every block.
+fn write(spi: &mut neobus::Spi, card: &CardInfo, start: u64, data: &[u8]) -> Result<(), Error> {
+	if data.len() == BLOCK_SIZE {
+		check_ready(command(spi, CMD24, card.address(start))?)?;
+		write_data(spi, TOKEN_START_BLOCK, data)?;
+	} else {
+		check_ready(command(spi, CMD25, card.address(start))?)?;
+		for block in data.chunks_exact(BLOCK_SIZE) {
+			write_data(spi, TOKEN_START_MULTI, block)?;
+		}
+		let _ = spi.write(&[TOKEN_STOP_TRAN, 0xFF]);
+		wait_not_busy(spi, WRITE_TIMEOUT_MS)?;
+	}
+	Ok(())
+}
+
+/// Check whole blocks on the selected card match the given data.
+fn verify(spi: &mut neobus::Spi, card: &CardInfo, start: u64, data: &[u8]) -> Result<(), Error> {
+	let mut block: Block = [0u8; BLOCK_SIZE];
+	check_ready(command(spi, CMD18, card.address(start))?)?;
+	let mut matched = true;
+	for expected in data.chunks_exact(BLOCK_SIZE) {
+		read_data(spi, &mut block)?;
+		if block[..] != expected[..] {
+			matched = false;
+			break;
+		}
+	}
+	stop_reading(spi)?;
+	if matched {
+		Ok(())
+	} else {
+		Err(Error::VerifyFailed)
+	}
+}
+
+/// Stop a CMD18 multiple block read.
+fn stop_reading(spi: &mut neobus::Spi) -> Result<(), Error> {
+	check_ready(command(spi, CMD12, 0)?)?;
+	wait_not_busy(spi, READ_TIMEOUT_MS)
+}
+
+/// Send a command, and get the R1 response.
+///
+/// Any extra response bytes (e.g. for R3 or R7) are left for the caller to
+/// read.
+fn command(spi: &mut neobus::Spi, cmd: u8, arg: u32) -> Result<u8, Error> {
+	let arg = arg.to_be_bytes();
+	let mut frame = [0x40 | cmd, arg[0], arg[1], arg[2], arg[3], 0x00];
+	frame[5] = (crc7(&frame[0..5]) << 1) | 1;
+	// One byte of clocks first, so the card is ready to listen
+	let _ = spi.write(&[0xFF]);
+	let _ = spi.write(&frame);
+	if cmd == CMD12 {
+		// The byte after CMD12 is junk
+		read_byte(spi);
+	}
+	for _ in 0..MAX_RESPONSE_BYTES {
+		let r1 = read_byte(spi);
+		if (r1 & 0x80) == 0 {
+			return Ok(r1);
+		}
+	}
+	Err(Error::NoCard)
+}
+
+/// Turn an R1 response into an error, unless it says everything is fine.
+fn check_ready(r1: u8) -> Result<(), Error> {
+	if r1 == R1_READY {
+		Ok(())
+	} else {
+		Err(Error::CommandFailed(r1))
+	}
+}
+
+/// Wait for a data start token, then read a block of data (and throw away
+/// the CRC).
+fn read_data(spi: &mut neobus::Spi, buffer: &mut [u8]) -> Result<(), Error> {
+	wait_for_token(spi)?;
+	neobus::read_dma(spi, buffer);
+	let mut crc = [0xFF; 2];
+	let _ = spi.transfer(&mut crc);
+	Ok(())
+}
+
+/// Wait for the card to send a data start token.
+fn wait_for_token(spi: &mut neobus::Spi) -> Result<(), Error> {
+	let start = crate::ticks_ms();
+	loop {
+		match read_byte(spi) {
+			TOKEN_START_BLOCK => return Ok(()),
+			0xFF => {}
+			token => return Err(Error::ReadFailed(token)),
+		}
+		if crate::ticks_ms().wrapping_sub(start) > READ_TIMEOUT_MS {
+			return Err(Error::Timeout);
+		}
+	}
+}
+
+/// Send a block of data (with a dummy CRC), and wait for the card to
+/// finish writing it.
+fn write_data(spi: &mut neobus::Spi, token: u8, data: &[u8]) -> Result<(), Error> {
+	let _ = spi.write(&[0xFF, token]);
+	neobus::write_dma(spi, data);
+	let _ = spi.write(&[0xFF, 0xFF]);
+	let response = read_byte(spi) & 0x1F;
+	if response != DATA_ACCEPTED {
+		return Err(Error::WriteFailed(response));
+	}
+	wait_not_busy(spi, WRITE_TIMEOUT_MS)
+}
+
+/// The card holds its output low while it is busy.
+fn wait_not_busy(spi: &mut neobus::Spi, timeout_ms: u32) -> Result<(), Error> {
+	let start = crate::ticks_ms();
+	while read_byte(spi) != 0xFF {
+		if crate::ticks_ms().wrapping_sub(start) > timeout_ms {
+			return Err(Error::Timeout);
+		}
+	}
+	Ok(())
+}
+
+/// Clock one byte in from the card.
+fn read_byte(spi: &mut neobus::Spi) -> u8 {
+	let mut buffer = [0xFF];
+	// Transfers on the SPI peripheral are infallible on the RP2040
+	let _ = spi.transfer(&mut buffer);
+	buffer[0]
+}
+
+/// Work out how many blocks a card has, from its CSD register.
+fn parse_csd(csd: &[u8; 16]) -> Result<u64, Error> {
+	match csd[0] >> 6 {
+		0 => {
+			// CSD Version 1.0 (Standard Capacity)
+			let read_bl_len = u32::from(csd[5] & 0x0F);
+			let c_size = (u64::from(csd[6] & 0x03) << 10)
+				| (u64::from(csd[7]) << 2)
+				| (u64::from(csd[8]) >> 6);
+			let c_size_mult = u32::from(((csd[9] & 0x03) << 1) | (csd[10] >> 7));
+			let bytes = (c_size + 1) << (c_size_mult + 2 + read_bl_len);
+			Ok(bytes / BLOCK_SIZE as u64)
+		}
+		1 => {
+			// CSD Version 2.0 (High and Extended Capacity)
+			let c_size =
+				(u64::from(csd[7] & 0x3F) << 16) | (u64::from(csd[8]) << 8) | u64::from(csd[9]);
+			Ok((c_size + 1) * 1024)
+		}
+		_ => Err(Error::BadCsd),
+	}
+}
+
+/// Calculate the 7-bit CRC for a command frame.
+fn crc7(data: &[u8]) -> u8 {
+	let mut crc = 0u8;
+	for &b in data {
+		let mut b = b;
+		for _ in 0..8 {
+			crc <<= 1;
+			if ((b ^ crc) & 0x80) != 0 {
+				crc ^= 0x09;
+			}
+			b <<= 1;
+		}
+	}
+	crc & 0x7F
+}
+
+/// Lets the crash logger read from the card.
+fn crash_read_block(lba: u64, block: &mut Block) -> Result<(), ()> {
+	read_blocks(lba, block).map_err(|_| ())
+}
+
+/// Lets the crash logger write to the card.
+fn crash_write_block(lba: u64, block: &Block) -> Result<(), ()> {
+	write_blocks(lba, block).map_err(|_| ())
+}
+
+impl Error {
+	/// Does this error mean the card needs initialising again?
+	pub fn is_card_fault(self) -> bool {
+		!matches!(
+			self,
+			Error::OutOfRange | Error::BadLength | Error::VerifyFailed | Error::Busy
+		)
+	}
+}
+
+impl Transfer {
+	/// The current block is done, so move on to the next one.
+	fn next_block(&mut self) {
+		self.lba += 1;
+		self.blocks_left -= 1;
+		self.data = self.data.wrapping_add(BLOCK_SIZE);
+		self.stage = TransferStage::Command;
+	}
+}
+
+impl CardInfo {
+	/// Convert a block number to the address the card wants.
+	fn address(&self, lba: u64) -> u32 {
+		match self.card_type {
+			CardType::Sdhc => lba as u32,
+			CardType::SdV1 | CardType::SdV2 => (lba * BLOCK_SIZE as u64) as u32,
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
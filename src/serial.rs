@@ -0,0 +1,302 @@
+//! # Serial port driver for the Neotron Pico
+//!
+//! The RS-232 header on the Neotron Pico is wired to UART1, with TX on
+//! GPIO20 and RX on GPIO21. Only TX and RX are wired, so hardware
+//! handshaking isn't available.
+//!
+//! Received bytes are moved from the UART's FIFO into a ring buffer by the
+//! UART1 interrupt, so we don't lose data while the OS is busy elsewhere.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::common;
+use defmt::debug;
+use heapless::spsc::{Consumer, Producer, Queue};
+use rp_pico::hal::{self, pac};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The UART wired to the RS-232 header
+pub type Uart = hal::uart::UartPeripheral<hal::uart::Enabled, pac::UART1>;
+
+/// The ways in which configuring the serial port can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// `init` hasn't been called
+	NoUart,
+	/// We can't get close enough to that baud rate
+	BadBaudRate,
+	/// We don't support that handshaking mode
+	BadHandshaking,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// Holds the UART once `init` has been called.
+///
+/// Only accessed from Core 0.
+static mut UART: Option<Uart> = None;
+
+/// How many received bytes we can hold (one less than this, in fact).
+const RX_BUFFER_LEN: usize = 256;
+
+/// Received bytes, waiting for the OS to read them.
+///
+/// Only accessed through `RX_PRODUCER` and `RX_CONSUMER`.
+static mut RX_QUEUE: Queue<u8, RX_BUFFER_LEN> = Queue::new();
+
+/// The writing end of `RX_QUEUE`.
+///
+/// Only accessed from the UART1 interrupt (once `init` has finished).
+static mut RX_PRODUCER: Option<Producer<'static, u8, RX_BUFFER_LEN>> = None;
+
+/// The reading end of `RX_QUEUE`.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut RX_CONSUMER: Option<Consumer<'static, u8, RX_BUFFER_LEN>> = None;
+
+/// The clock feeding the UART, which we need to work out baud rates.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut PERIPHERAL_CLOCK_HZ: u32 = 0;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Take ownership of the (already enabled) UART, and start receiving.
+pub fn init(uart: Uart, peripheral_clock_hz: u32) {
+	// Note (safety): Only called from Core 0, before the UART1 interrupt is
+	// enabled.
+	unsafe {
+		UART = Some(uart);
+		PERIPHERAL_CLOCK_HZ = peripheral_clock_hz;
+		let (producer, consumer) = RX_QUEUE.split();
+		RX_PRODUCER = Some(producer);
+		RX_CONSUMER = Some(consumer);
+		// Interrupt when the RX FIFO is half full, or when bytes have been
+		// sitting in it for a while.
+		let uart = &*pac::UART1::ptr();
+		uart.uartimsc
+			.modify(|_r, w| w.rxim().set_bit().rtim().set_bit());
+		pac::NVIC::unmask(pac::Interrupt::UART1_IRQ);
+	}
+}
+
+/// Change the baud rate, data bits, parity and stop bits.
+///
+/// Waits for anything already queued to finish being sent first.
+pub fn configure(config: &common::serial::Config) -> Result<(), Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let peripheral_clock_hz = match unsafe { (UART.as_ref(), PERIPHERAL_CLOCK_HZ) } {
+		(Some(_), hz) => hz,
+		(None, _) => return Err(Error::NoUart),
+	};
+	match config.handshaking {
+		common::serial::Handshaking::None => {}
+		_ => return Err(Error::BadHandshaking),
+	}
+	let (ibrd, fbrd) = baud_divisors(peripheral_clock_hz, config.data_rate_bps)?;
+	let wlen = match config.data_bits {
+		common::serial::DataBits::Five => 0,
+		common::serial::DataBits::Six => 1,
+		common::serial::DataBits::Seven => 2,
+		common::serial::DataBits::Eight => 3,
+	};
+	let (pen, eps) = match config.parity {
+		common::serial::Parity::None => (false, false),
+		common::serial::Parity::Odd => (true, false),
+		common::serial::Parity::Even => (true, true),
+	};
+	let stp2 = match config.stop_bits {
+		common::serial::StopBits::One => false,
+		common::serial::StopBits::Two => true,
+	};
+	debug!(
+		"UART: {} bps, div {}+{}/64, wlen {}, pen {}, eps {}, stp2 {}",
+		config.data_rate_bps, ibrd, fbrd, wlen, pen, eps, stp2
+	);
+
+	// Note (safety): We own UART1 (via `UART`). The HAL doesn't let us
+	// change the settings of an enabled UART without disabling it, which
+	// would throw away the `Uart` if the new settings were rejected.
+	let uart = unsafe { &*pac::UART1::ptr() };
+	// The PL011 mustn't have its line control or divisors changed while it
+	// is enabled, so wait for the last character to go, turn it off, change
+	// them, and turn it back on.
+	while uart.uartfr.read().busy().bit_is_set() {}
+	cortex_m::interrupt::free(|_cs| {
+		uart.uartcr.modify(|_r, w| w.uarten().clear_bit());
+		// Note (safety): These are all in range. Writing LCR_H latches the
+		// new divisors.
+		unsafe {
+			uart.uartibrd.write(|w| w.baud_divint().bits(ibrd));
+			uart.uartfbrd.write(|w| w.baud_divfrac().bits(fbrd));
+			uart.uartlcr_h.write(|w| {
+				w.wlen()
+					.bits(wlen)
+					.fen()
+					.set_bit()
+					.pen()
+					.bit(pen)
+					.eps()
+					.bit(eps)
+					.stp2()
+					.bit(stp2)
+			});
+		}
+		uart.uartcr.modify(|_r, w| w.uarten().set_bit());
+	});
+	Ok(())
+}
+
+/// Work out the integer and fractional (in 64ths) baud rate divisors, as
+/// described in the RP2040 datasheet.
+fn baud_divisors(peripheral_clock_hz: u32, baud_rate: u32) -> Result<(u16, u8), Error> {
+	if baud_rate == 0 || baud_rate > peripheral_clock_hz / 16 {
+		return Err(Error::BadBaudRate);
+	}
+	let divisor = (8 * u64::from(peripheral_clock_hz)) / u64::from(baud_rate);
+	let ibrd = divisor >> 7;
+	if ibrd == 0 || ibrd >= 0xFFFF {
+		return Err(Error::BadBaudRate);
+	}
+	let fbrd = ((divisor & 0x7F) + 1) / 2;
+	Ok((ibrd as u16, fbrd as u8))
+}
+
+/// Write some bytes to the serial port.
+///
+/// With no timeout we wait until every byte has been queued in the UART's
+/// FIFO. Otherwise we give up when the timeout expires. Returns how many
+/// bytes were queued.
+pub fn write(data: &[u8], timeout_ms: Option<u32>) -> usize {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	if unsafe { UART.is_none() } {
+		return 0;
+	}
+	// Note (safety): We own UART1 (via `UART`), and only we write to the
+	// data register.
+	let uart = unsafe { &*pac::UART1::ptr() };
+	let start = crate::ticks_ms();
+	let mut remaining = data;
+	while let Some((&b, rest)) = remaining.split_first() {
+		if uart.uartfr.read().txff().bit_is_clear() {
+			// Note (safety): Any byte is a valid data value
+			uart.uartdr.write(|w| unsafe { w.data().bits(b) });
+			remaining = rest;
+			continue;
+		}
+		if let Some(timeout_ms) = timeout_ms {
+			if crate::ticks_ms().wrapping_sub(start) >= timeout_ms {
+				break;
+			}
+		}
+		crate::idle();
+	}
+	data.len() - remaining.len()
+}
+
+/// Read some bytes from the serial port.
+///
+/// If nothing has been received, we wait until something is, or the
+/// timeout expires. With no timeout we wait forever. Returns how many bytes
+/// were copied into the buffer.
+pub fn read(buffer: &mut [u8], timeout_ms: Option<u32>) -> usize {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let consumer = match unsafe { RX_CONSUMER.as_mut() } {
+		Some(consumer) => consumer,
+		None => return 0,
+	};
+	if buffer.is_empty() {
+		return 0;
+	}
+	let start = crate::ticks_ms();
+	while !consumer.ready() {
+		if let Some(timeout_ms) = timeout_ms {
+			if crate::ticks_ms().wrapping_sub(start) >= timeout_ms {
+				return 0;
+			}
+		}
+		// The UART interrupt wakes us when something arrives
+		crate::idle();
+	}
+	let mut count = 0;
+	for slot in buffer.iter_mut() {
+		match consumer.dequeue() {
+			Some(b) => *slot = b,
+			None => break,
+		}
+		count += 1;
+	}
+	count
+}
+
+/// Called from the UART1 interrupt. Moves received bytes from the FIFO into
+/// our ring buffer.
+///
+/// If the ring buffer is full, new bytes are dropped.
+///
+/// # Safety
+///
+/// Only call this from the UART1 interrupt handler.
+pub unsafe fn irq() {
+	let uart = &*pac::UART1::ptr();
+	let mut producer = RX_PRODUCER.as_mut();
+	let mut dropped = 0u32;
+	// Reading the data register clears the RX and RX timeout interrupts
+	// once the FIFO is empty.
+	while uart.uartfr.read().rxfe().bit_is_clear() {
+		let b = uart.uartdr.read().data().bits();
+		let stored = match producer {
+			Some(ref mut producer) => producer.enqueue(b).is_ok(),
+			None => false,
+		};
+		if !stored {
+			dropped += 1;
+		}
+	}
+	if dropped != 0 {
+		debug!("UART RX dropped {} bytes", dropped);
+	}
+}
+
+/// Write some bytes to the serial port, waiting until they have all been
+/// queued in the UART's FIFO.
+///
+/// The bytes are dropped if `init` hasn't been called yet.
+pub fn write_blocking(data: &[u8]) {
+	// Note (safety): Only called from Core 0.
+	if let Some(uart) = unsafe { UART.as_mut() } {
+		uart.write_full_blocking(data);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,280 @@
+//! # Setup screen for the Neotron Pico BIOS
+//!
+//! Pressing Delete or F2 during the boot countdown brings up this menu, so
+//! you can change the settings that would otherwise need an OS program
+//! (and `configuration_set`): where we look for the OS, the monitor
+//! timings, the serial port speed, the keyboard layout, and the date and
+//! time.
+//!
+//! Everything is driven by single key presses, from the keyboard or the
+//! serial port, so it works without a monitor too. Saving writes the
+//! configuration out (see `config::save`) and restarts, as the timings and
+//! the serial speed are only picked up at reset. The date and time are
+//! written to the RTC straight away.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{config, console, hid, keymap, rtc, serial, vga, watchdog};
+use core::fmt::Write;
+use defmt::{info, warn};
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How often we look for a key press
+const POLL_MS: u32 = 10;
+
+/// The serial port speeds we offer
+const BAUD_RATES: [u32; 6] = [9_600, 19_200, 38_400, 57_600, 115_200, 230_400];
+
+/// The longest line we let you type (e.g. a date and time)
+const MAX_LINE_LEN: usize = 19;
+
+/// Backspace, as the keyboard and most terminals send it
+const BACKSPACE: char = '\u{0008}';
+
+/// Delete, which many terminals send for the Backspace key
+const DELETE: char = '\u{007F}';
+
+/// Escape
+const ESCAPE: char = '\u{001B}';
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Show the setup screen, until the user leaves without saving.
+///
+/// If they save, we restart instead of returning.
+pub fn run(mut tc: &console::Console, delay: &mut cortex_m::delay::Delay) {
+	info!("Entering setup");
+	let mut config = config::get();
+	loop {
+		show_menu(tc, &config);
+		match wait_for_key(delay) {
+			'1' => {
+				config.boot_order = match config.boot_order {
+					config::BootOrder::SdCardThenFlash => config::BootOrder::FlashOnly,
+					config::BootOrder::FlashOnly => config::BootOrder::SdCardThenFlash,
+				};
+			}
+			'2' => {
+				let next = (config.video_timing as u8) + 1;
+				config.video_timing =
+					vga::TimingPreset::from_u8(next).unwrap_or(vga::TimingPreset::Standard);
+			}
+			'3' => {
+				config.serial_baud = BAUD_RATES
+					.iter()
+					.position(|&baud| baud == config.serial_baud)
+					.and_then(|idx| BAUD_RATES.get(idx + 1))
+					.copied()
+					.unwrap_or(BAUD_RATES[0]);
+			}
+			'4' => set_date_time(tc, delay),
+			'5' => {
+				let next = (config.keyboard_layout as u8) + 1;
+				config.keyboard_layout =
+					keymap::Layout::from_u8(next).unwrap_or(keymap::Layout::Qwerty);
+			}
+			's' | 'S' => {
+				writeln!(tc).unwrap();
+				match config::save(&config) {
+					Ok(()) => {
+						writeln!(tc, "Saved. Restarting...").unwrap();
+						delay.delay_ms(1000);
+						watchdog::reboot();
+					}
+					Err(e) => {
+						warn!("Setup couldn't save config: {}", e);
+						writeln!(tc, "Couldn't save the settings! Press a key.").unwrap();
+						wait_for_key(delay);
+					}
+				}
+			}
+			'q' | 'Q' | ESCAPE => {
+				info!("Leaving setup without saving");
+				tc.clear();
+				return;
+			}
+			_ => {}
+		}
+	}
+}
+
+/// Draw the menu, with the current (unsaved) settings.
+fn show_menu(mut tc: &console::Console, config: &config::Config) {
+	tc.clear();
+	writeln!(tc, "Neotron Pico BIOS Setup").unwrap();
+	writeln!(tc, "=======================").unwrap();
+	writeln!(tc).unwrap();
+	let boot_order = match config.boot_order {
+		config::BootOrder::SdCardThenFlash => "SD card, then flash",
+		config::BootOrder::FlashOnly => "Flash only",
+	};
+	writeln!(tc, "1 - Boot from:       {}", boot_order).unwrap();
+	let timing = match config.video_timing {
+		vga::TimingPreset::Standard => "Standard VGA",
+		vga::TimingPreset::Rgb15kHz240 => "15 kHz RGB, 320x240 at 60 Hz",
+		vga::TimingPreset::Rgb15kHz256 => "15 kHz RGB, 320x256 at 50 Hz",
+	};
+	writeln!(tc, "2 - Video timing:    {}", timing).unwrap();
+	writeln!(tc, "3 - Serial speed:    {} bps", config.serial_baud).unwrap();
+	match rtc::get_time() {
+		Ok(secs) => {
+			let now = rtc::DateTime::from_seconds(secs);
+			writeln!(
+				tc,
+				"4 - Date and time:   20{:02}-{:02}-{:02} {:02}:{:02}:{:02}",
+				now.year, now.month, now.day, now.hours, now.minutes, now.seconds
+			)
+			.unwrap();
+		}
+		Err(_e) => writeln!(tc, "4 - Date and time:   Not set").unwrap(),
+	}
+	let layout = match config.keyboard_layout {
+		keymap::Layout::Qwerty => "US",
+		keymap::Layout::Uk => "UK",
+		keymap::Layout::Azerty => "French",
+		keymap::Layout::Qwertz => "German",
+	};
+	writeln!(tc, "5 - Keyboard layout: {}", layout).unwrap();
+	writeln!(tc).unwrap();
+	writeln!(tc, "S - Save and restart").unwrap();
+	writeln!(tc, "Q - Carry on booting, without saving").unwrap();
+}
+
+/// Ask for a new date and time, and set the RTC.
+fn set_date_time(mut tc: &console::Console, delay: &mut cortex_m::delay::Delay) {
+	writeln!(tc).unwrap();
+	write!(tc, "New date and time (YYYY-MM-DD HH:MM:SS): ").unwrap();
+	let mut line = [0u8; MAX_LINE_LEN];
+	let len = match read_line(tc, delay, &mut line) {
+		Some(len) => len,
+		None => return,
+	};
+	let message = match parse_date_time(&line[0..len]) {
+		Some(date_time) => match date_time.to_seconds().and_then(rtc::set_time) {
+			Ok(()) => "Time set.",
+			Err(e) => {
+				warn!("Setup couldn't set RTC: {}", e);
+				"Couldn't set the clock!"
+			}
+		},
+		None => "That's not a date and time I understand.",
+	};
+	writeln!(tc, "{} Press a key.", message).unwrap();
+	wait_for_key(delay);
+}
+
+/// Turn `YYYY-MM-DD HH:MM:SS` into a date and time the RTC can hold.
+fn parse_date_time(text: &[u8]) -> Option<rtc::DateTime> {
+	if text.len() != MAX_LINE_LEN {
+		return None;
+	}
+	for (idx, separator) in [(4, b'-'), (7, b'-'), (10, b' '), (13, b':'), (16, b':')] {
+		if text[idx] != separator {
+			return None;
+		}
+	}
+	let number = |start: usize, len: usize| -> Option<u16> {
+		text[start..start + len].iter().try_fold(0u16, |acc, &b| {
+			if b.is_ascii_digit() {
+				Some((acc * 10) + u16::from(b - b'0'))
+			} else {
+				None
+			}
+		})
+	};
+	let year = number(0, 4)?;
+	if !(2000..=2099).contains(&year) {
+		return None;
+	}
+	let date_time = rtc::DateTime {
+		year: (year - 2000) as u8,
+		month: number(5, 2)? as u8,
+		day: number(8, 2)? as u8,
+		hours: number(11, 2)? as u8,
+		minutes: number(14, 2)? as u8,
+		seconds: number(17, 2)? as u8,
+	};
+	if date_time.is_valid() {
+		Some(date_time)
+	} else {
+		None
+	}
+}
+
+/// Read a line of printable ASCII, echoing it as it's typed.
+///
+/// Returns the length, or `None` if Escape was pressed.
+pub fn read_line(
+	mut tc: &console::Console,
+	delay: &mut cortex_m::delay::Delay,
+	line: &mut [u8],
+) -> Option<usize> {
+	let mut len = 0;
+	loop {
+		match wait_for_key(delay) {
+			'\n' | '\r' => {
+				writeln!(tc).unwrap();
+				return Some(len);
+			}
+			ESCAPE => return None,
+			BACKSPACE | DELETE if len > 0 => {
+				len -= 1;
+				// Cursor left, blank it out, cursor left again
+				write!(tc, "\x1b[D \x1b[D").unwrap();
+			}
+			ch if ch.is_ascii_graphic() || ch == ' ' => {
+				if len < line.len() {
+					line[len] = ch as u8;
+					len += 1;
+					write!(tc, "{}", ch).unwrap();
+				}
+			}
+			_ => {}
+		}
+	}
+}
+
+/// Wait for a key press on the keyboard, or a byte on the serial port.
+pub fn wait_for_key(delay: &mut cortex_m::delay::Delay) -> char {
+	loop {
+		hid::poll();
+		if let Some(ch) = hid::get_char() {
+			return ch;
+		}
+		let mut byte = [0u8; 1];
+		if serial::read(&mut byte, Some(0)) != 0 {
+			return char::from(byte[0]);
+		}
+		delay.delay_ms(POLL_MS);
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,114 @@
+//! # Expansion slot interrupts for the Neotron Pico
+//!
+//! Each expansion slot has an interrupt request line, which goes to the I/O
+//! expander on the Neotron Bus (see `neobus`). We collect them when we are
+//! polled, and keep a pending bit per slot until the OS reads them with
+//! `take_pending`.
+//!
+//! So an OS driver doesn't have to keep asking, the OS can register a
+//! callback. It is called (from whichever BIOS call did the polling, never
+//! from an interrupt) with the slots that have just become pending.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::neobus;
+use defmt::trace;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// A function the OS wants called when a slot asks for attention. It is
+/// given the newly pending slots, with slot 1 in bit 0.
+pub type Callback = extern "C" fn(slots: u32);
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The slots which have asked for attention since the OS last looked, with
+/// slot 1 in bit 0.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut PENDING: u8 = 0;
+
+/// The OS's callback, if it has given us one.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut CALLBACK: Option<Callback> = None;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Collect the slot interrupt requests, and tell the OS about any new ones.
+///
+/// A slot is pending if the I/O expander latched its interrupt since the
+/// last poll, or if it is still asserting its interrupt now.
+pub fn poll() {
+	// The latched flags must be read before the levels, which clear them
+	let flags = match neobus::take_slot_irq_flags() {
+		Some(flags) => flags,
+		None => return,
+	};
+	let levels = neobus::read_inputs()
+		.map(neobus::input_slot_irqs)
+		.unwrap_or(0);
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (new, callback) = unsafe {
+		let new = (flags | levels) & !PENDING;
+		PENDING |= new;
+		(new, CALLBACK)
+	};
+	if new != 0 {
+		trace!("Slot IRQs: {=u8:b}", new);
+		if let Some(callback) = callback {
+			// The callback may call back into the BIOS, so we mustn't be
+			// holding onto any of our state.
+			callback(u32::from(new));
+		}
+	}
+}
+
+/// Get the pending slots (slot 1 is bit 0), and clear them.
+///
+/// A slot which is still asserting its interrupt becomes pending again on the
+/// next poll.
+pub fn take_pending() -> u8 {
+	poll();
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { core::mem::replace(&mut PENDING, 0) }
+}
+
+/// Set (or clear) the function we call when a slot becomes pending.
+pub fn set_callback(callback: Option<Callback>) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		CALLBACK = callback;
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,192 @@
+//! # System clock profiles for the Neotron Pico
+//!
+//! The system clock comes from the system PLL, and everything that counts
+//! clock cycles (the video most of all) has to agree on its speed. Rather
+//! than allow any speed at all, we offer a few profiles which are known to
+//! work:
+//!
+//! | Profile  | System clock | PLL (MHz)    | Core voltage | Flash clock |
+//! |----------|--------------|--------------|--------------|-------------|
+//! | Standard | 126 MHz      | 1512 ÷ 6 ÷ 2 | 1.10 V       | 63 MHz      |
+//! | Fast     | 151.2 MHz    | 1512 ÷ 5 ÷ 2 | 1.10 V       | 75.6 MHz    |
+//! | Turbo    | 252 MHz      | 1512 ÷ 6 ÷ 1 | 1.20 V       | 63 MHz      |
+//!
+//! Each is a whole multiple of the 25.2 MHz VGA pixel clock, so the video
+//! can use a whole-number PIO clock divider, which doesn't add any jitter
+//! (see `vga::init`). Turbo is also seven times the 36 MHz SVGA pixel clock,
+//! so it is the only profile which offers the 800x600 video mode.
+//!
+//! The profile is chosen in the BIOS configuration, and takes effect at the
+//! next reset. We have to pick it before the clocks are running, when we
+//! can't yet talk to the RTC, so we use the copy of the configuration in
+//! flash (which is saved at the same time). If the watchdog reset us, we
+//! use the standard profile, in case the faster clock was why we hung.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{config, flash, watchdog};
+use defmt::{info, warn};
+use embedded_time::rate::Megahertz;
+use rp_pico::hal::{self, pac};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The system clock speeds we offer.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Profile {
+	/// 126 MHz - five system clocks per pixel
+	Standard = 0,
+	/// 151.2 MHz - six system clocks per pixel
+	Fast = 1,
+	/// 252 MHz - ten system clocks per pixel (or seven at 800x600), with a
+	/// raised core voltage
+	Turbo = 2,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The system PLL's VCO frequency, which all the profiles share
+const VCO_MHZ: u32 = 1512;
+
+/// The core voltage setting for 1.10 V (the power-on default)
+const VSEL_1V10: u8 = 0b1011;
+
+/// The core voltage setting for 1.20 V
+const VSEL_1V20: u8 = 0b1101;
+
+/// How long to let the core voltage settle before we speed up
+const VOLTAGE_SETTLE_MS: u32 = 10;
+
+/// The profile we are running (or about to run) at.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut CURRENT: Profile = Profile::Standard;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Pick the profile to run at, from the stored configuration.
+///
+/// Call this after `watchdog::init`, which works out why we reset.
+pub fn select() -> Profile {
+	let profile = config::stored_clock_profile();
+	if profile != Profile::Standard && watchdog::reset_reason() == watchdog::ResetReason::Watchdog {
+		warn!("Watchdog reset, so not using clock profile {}", profile);
+		return Profile::Standard;
+	}
+	profile
+}
+
+/// Get everything ready for the system PLL to run at the given profile's
+/// speed: raise the core voltage if needed, and slow the flash chip down.
+///
+/// Call this once the timer is ticking, before the system PLL is set up and
+/// before Core 1 starts.
+pub fn prepare(profile: Profile) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		CURRENT = profile;
+	}
+	let vsel = profile.voltage();
+	if vsel != VSEL_1V10 {
+		// Note (safety): Nothing else touches the voltage regulator.
+		let vreg = unsafe { &*pac::VREG_AND_CHIP_RESET::ptr() };
+		vreg.vreg.modify(|_r, w| unsafe { w.vsel().bits(vsel) });
+		let start = crate::ticks_ms();
+		while crate::ticks_ms().wrapping_sub(start) < VOLTAGE_SETTLE_MS {}
+	}
+	flash::set_clock_divider(profile.flash_divider());
+	info!("Clock profile {}: {} Hz", profile, profile.sys_clock_hz());
+}
+
+/// The profile we are running at.
+pub fn current() -> Profile {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { CURRENT }
+}
+
+impl Profile {
+	/// Convert from the value stored in the configuration.
+	pub const fn from_u8(value: u8) -> Option<Profile> {
+		match value {
+			0 => Some(Profile::Standard),
+			1 => Some(Profile::Fast),
+			2 => Some(Profile::Turbo),
+			_ => None,
+		}
+	}
+
+	/// The system clock frequency for this profile.
+	pub const fn sys_clock_hz(self) -> u32 {
+		let (post_div1, post_div2) = self.post_dividers();
+		(VCO_MHZ * 1_000_000) / (post_div1 as u32 * post_div2 as u32)
+	}
+
+	/// The system PLL settings for this profile.
+	pub fn pll_config(self) -> hal::pll::PLLConfig<Megahertz> {
+		let (post_div1, post_div2) = self.post_dividers();
+		hal::pll::PLLConfig {
+			vco_freq: Megahertz(VCO_MHZ),
+			refdiv: 1,
+			post_div1,
+			post_div2,
+		}
+	}
+
+	/// The system PLL's two post dividers.
+	const fn post_dividers(self) -> (u8, u8) {
+		match self {
+			Profile::Standard => (6, 2),
+			Profile::Fast => (5, 2),
+			Profile::Turbo => (6, 1),
+		}
+	}
+
+	/// The core voltage setting, for `VREG.VSEL`.
+	const fn voltage(self) -> u8 {
+		match self {
+			Profile::Standard | Profile::Fast => VSEL_1V10,
+			Profile::Turbo => VSEL_1V20,
+		}
+	}
+
+	/// The QSPI clock divider, which keeps the flash chip's clock well
+	/// inside its limits.
+	const fn flash_divider(self) -> u8 {
+		match self {
+			Profile::Standard | Profile::Fast => 2,
+			Profile::Turbo => 4,
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,272 @@
+//! # System description for the Neotron Pico
+//!
+//! Once the self test has run, we write a short description of the devices
+//! we found, so the OS can find out (with `system_description_get`) what it
+//! is running on, rather than assuming it knows which serial port is which,
+//! or that there's an RTC.
+//!
+//! The description is a block of bytes. All integers are little-endian.
+//!
+//! | Offset | Length | Contents                                            |
+//! |--------|--------|-----------------------------------------------------|
+//! | 0      | 4      | `NSYS`                                              |
+//! | 4      | 1      | Format version (1)                                  |
+//! | 5      | 1      | Board revision (see `board::Revision`)              |
+//! | 6      | 1      | How many device records follow                      |
+//! | 7      | 1      | Reserved (0)                                        |
+//! | 8      | 8 * n  | The device records                                  |
+//!
+//! Each device record is:
+//!
+//! | Offset | Length | Contents                                            |
+//! |--------|--------|-----------------------------------------------------|
+//! | 0      | 1      | What it is (see `DeviceKind`)                       |
+//! | 1      | 1      | Which one - the number the OS API uses for it       |
+//! | 2      | 1      | Where it is (see `Bus`)                             |
+//! | 3      | 1      | Its interrupt, or 0xFF if it has none               |
+//! | 4      | 4      | Its address, on that bus                            |
+//!
+//! For on-chip devices, the address is where the registers are, and the
+//! interrupt is the RP2040's interrupt number. For Neotron Bus devices, the
+//! address is the chip-select number, and the interrupt is the bit in
+//! `slot_irq::take_pending`. For I2C devices, the address is the 7-bit bus
+//! address.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::{audio, board, neobus, post, psram, rtc};
+use defmt::debug;
+use rp_pico::hal::pac;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The kinds of device in a description.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum DeviceKind {
+	/// The RS-232 serial port
+	Rs232 = 0,
+	/// The USB virtual serial port
+	UsbSerial = 1,
+	/// The Board Management Controller (keyboard, mouse and power)
+	Bmc = 2,
+	/// The real-time clock
+	Rtc = 3,
+	/// The audio codec
+	AudioCodec = 4,
+	/// The built-in SD card slot
+	SdCardSlot = 5,
+	/// An expansion slot
+	ExpansionSlot = 6,
+	/// External PSRAM
+	Psram = 7,
+	/// The video output
+	Video = 8,
+}
+
+/// Where a device is.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Bus {
+	/// Inside the RP2040
+	OnChip = 0,
+	/// On the Neotron Bus (SPI)
+	NeotronBus = 1,
+	/// On the I2C bus
+	I2c = 2,
+}
+
+/// The ways in which fetching the description can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// `build` hasn't been called yet
+	NotReady,
+	/// The caller's buffer is too small
+	BufferTooSmall,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The first four bytes of a description
+const MAGIC: [u8; 4] = *b"NSYS";
+
+/// The version of the description format
+const VERSION: u8 = 1;
+
+/// How long the header is
+const HEADER_LEN: usize = 8;
+
+/// How long each device record is
+const RECORD_LEN: usize = 8;
+
+/// The most device records we'll write
+const MAX_RECORDS: usize = 16;
+
+/// The longest a description can be
+pub const MAX_LEN: usize = HEADER_LEN + (MAX_RECORDS * RECORD_LEN);
+
+/// The interrupt number for a device without one
+const NO_IRQ: u8 = 0xFF;
+
+/// The description, once `build` has been called.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut DESCRIPTION: [u8; MAX_LEN] = [0u8; MAX_LEN];
+
+/// How much of `DESCRIPTION` is used, or zero if it hasn't been built.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut DESCRIPTION_LEN: usize = 0;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Work out what's in the system, using what the self test found.
+pub fn build(report: &post::Report) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let description = unsafe { &mut DESCRIPTION };
+	let mut count = 0;
+	let mut add = |kind: DeviceKind, instance: u8, bus: Bus, irq: u8, address: u32| {
+		if count == MAX_RECORDS {
+			return;
+		}
+		debug!("Found {} {} at {}:{:x}", kind, instance, bus, address);
+		let record = &mut description[HEADER_LEN + (count * RECORD_LEN)..][..RECORD_LEN];
+		record[0] = kind as u8;
+		record[1] = instance;
+		record[2] = bus as u8;
+		record[3] = irq;
+		record[4..8].copy_from_slice(&address.to_le_bytes());
+		count += 1;
+	};
+
+	add(
+		DeviceKind::Rs232,
+		0,
+		Bus::OnChip,
+		pac::Interrupt::UART1_IRQ as u8,
+		pac::UART1::ptr() as u32,
+	);
+	add(
+		DeviceKind::UsbSerial,
+		1,
+		Bus::OnChip,
+		pac::Interrupt::USBCTRL_IRQ as u8,
+		pac::USBCTRL_REGS::ptr() as u32,
+	);
+	add(
+		DeviceKind::Video,
+		0,
+		Bus::OnChip,
+		pac::Interrupt::DMA_IRQ_0 as u8,
+		pac::PIO0::ptr() as u32,
+	);
+	if report.bmc.is_ok() {
+		add(
+			DeviceKind::Bmc,
+			0,
+			Bus::NeotronBus,
+			NO_IRQ,
+			neobus::Device::Bmc as u32,
+		);
+	}
+	if report.rtc.is_ok() {
+		add(
+			DeviceKind::Rtc,
+			0,
+			Bus::I2c,
+			NO_IRQ,
+			u32::from(rtc::RTC_ADDRESS),
+		);
+	}
+	if report.codec.is_ok() {
+		add(
+			DeviceKind::AudioCodec,
+			0,
+			Bus::I2c,
+			NO_IRQ,
+			u32::from(audio::CODEC_ADDRESS),
+		);
+	}
+	add(
+		DeviceKind::SdCardSlot,
+		0,
+		Bus::NeotronBus,
+		NO_IRQ,
+		neobus::Device::SdCard as u32,
+	);
+	for number in 1..=neobus::NUM_SLOTS {
+		if let Some(device) = neobus::slot(number) {
+			add(
+				DeviceKind::ExpansionSlot,
+				number,
+				Bus::NeotronBus,
+				number - 1,
+				device as u32,
+			);
+		}
+	}
+	if psram::is_fitted() {
+		add(
+			DeviceKind::Psram,
+			0,
+			Bus::OnChip,
+			NO_IRQ,
+			pac::PIO1::ptr() as u32,
+		);
+	}
+
+	description[0..4].copy_from_slice(&MAGIC);
+	description[4] = VERSION;
+	description[5] = board::revision() as u8;
+	description[6] = count as u8;
+	description[7] = 0;
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		DESCRIPTION_LEN = HEADER_LEN + (count * RECORD_LEN);
+	}
+}
+
+/// Copy the description into the given buffer.
+///
+/// Returns how many bytes it took.
+pub fn read(buffer: &mut [u8]) -> Result<usize, Error> {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let (description, len) = unsafe { (&DESCRIPTION, DESCRIPTION_LEN) };
+	if len == 0 {
+		return Err(Error::NotReady);
+	}
+	let buffer = buffer.get_mut(0..len).ok_or(Error::BufferTooSmall)?;
+	buffer.copy_from_slice(&description[0..len]);
+	Ok(len)
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,327 @@
+//! # USB serial port for the Neotron Pico
+//!
+//! The Pico's USB socket appears to the host as a CDC-ACM virtual COM port,
+//! which the OS sees as serial device 1. It uses the RP2040's USB device
+//! controller, clocked from the 48 MHz USB PLL.
+//!
+//! The USB stack runs in the USBCTRL interrupt. Bytes pass between the OS
+//! and the interrupt through a receive ring buffer and a transmit ring
+//! buffer. When the OS queues bytes to send, we pend the interrupt so it can
+//! push them out.
+//!
+//! The device is a composite one: next to the COM port is a USB Mass Storage
+//! interface, which can offer the SD card to the host (see `usb_storage`).
+//!
+//! We don't support USB host mode, so USB flash drives can't be used as block
+//! devices. The RP2040's controller can only be a device or a host at once,
+//! so it would cost us this serial port; the HAL has no host driver; and the
+//! Pico doesn't power its USB socket when it isn't plugged into a PC. A
+//! PIO-based host port is out too, as video has PIO0 and audio has PIO1.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::usb_storage::MassStorage;
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::debug;
+use heapless::spsc::{Consumer, Producer, Queue};
+use rp_pico::hal::{pac, usb::UsbBus};
+use usb_device::{class_prelude::UsbBusAllocator, prelude::*};
+use usbd_serial::SerialPort;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Bytes the interrupt has taken from the transmit queue, but the USB stack
+/// hasn't accepted yet.
+struct TxStaging {
+	data: [u8; USB_PACKET_LEN],
+	len: usize,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// Our USB Vendor ID. This is the shared pid.codes/V-USB ID for CDC-ACM
+/// devices, which the host identifies by the product string. Hosts bind
+/// drivers to our interfaces by their class, so the extra Mass Storage
+/// interface doesn't need an ID of its own.
+const USB_VID: u16 = 0x16C0;
+
+/// Our USB Product ID (see `USB_VID`)
+const USB_PID: u16 = 0x27DD;
+
+/// Device class: Miscellaneous, with sub-class and protocol saying that
+/// Interface Association Descriptors group our interfaces into functions.
+const USB_CLASS_MISC: u8 = 0xEF;
+const USB_SUBCLASS_COMMON: u8 = 0x02;
+const USB_PROTOCOL_IAD: u8 = 0x01;
+
+/// The biggest packet on a full-speed bulk endpoint
+const USB_PACKET_LEN: usize = 64;
+
+/// How many bytes each ring buffer can hold (one less than this, in fact).
+const BUFFER_LEN: usize = 256;
+
+/// The USB bus, which the device and the class borrow.
+///
+/// Only written by `init`, before the USBCTRL interrupt is enabled.
+static mut USB_BUS: Option<UsbBusAllocator<UsbBus>> = None;
+
+/// The USB device.
+///
+/// Only accessed from the USBCTRL interrupt (once `init` has finished).
+static mut USB_DEVICE: Option<UsbDevice<'static, UsbBus>> = None;
+
+/// The CDC-ACM class on our USB device.
+///
+/// Only accessed from the USBCTRL interrupt (once `init` has finished).
+static mut USB_SERIAL: Option<SerialPort<'static, UsbBus>> = None;
+
+/// The Mass Storage class on our USB device.
+///
+/// Only accessed from the USBCTRL interrupt (once `init` has finished).
+static mut USB_STORAGE: Option<MassStorage<'static, UsbBus>> = None;
+
+/// Bytes from the host, waiting for the OS to read them.
+///
+/// Only accessed through `RX_PRODUCER` and `RX_CONSUMER`.
+static mut RX_QUEUE: Queue<u8, BUFFER_LEN> = Queue::new();
+
+/// The writing end of `RX_QUEUE`.
+///
+/// Only accessed from the USBCTRL interrupt (once `init` has finished).
+static mut RX_PRODUCER: Option<Producer<'static, u8, BUFFER_LEN>> = None;
+
+/// The reading end of `RX_QUEUE`.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut RX_CONSUMER: Option<Consumer<'static, u8, BUFFER_LEN>> = None;
+
+/// Bytes from the OS, waiting to go to the host.
+///
+/// Only accessed through `TX_PRODUCER` and `TX_CONSUMER`.
+static mut TX_QUEUE: Queue<u8, BUFFER_LEN> = Queue::new();
+
+/// The writing end of `TX_QUEUE`.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut TX_PRODUCER: Option<Producer<'static, u8, BUFFER_LEN>> = None;
+
+/// The reading end of `TX_QUEUE`.
+///
+/// Only accessed from the USBCTRL interrupt (once `init` has finished).
+static mut TX_CONSUMER: Option<Consumer<'static, u8, BUFFER_LEN>> = None;
+
+/// See `TxStaging`.
+///
+/// Only accessed from the USBCTRL interrupt.
+static mut TX_STAGING: TxStaging = TxStaging {
+	data: [0u8; USB_PACKET_LEN],
+	len: 0,
+};
+
+/// Set when the host has configured us and opened the port (i.e. set DTR).
+/// Until then, there's no point waiting for bytes to be sent.
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Start the USB device, and enable the USBCTRL interrupt.
+pub fn init(bus: UsbBus) {
+	// Note (safety): Only called from Core 0, before the USBCTRL interrupt
+	// is enabled.
+	unsafe {
+		USB_BUS = Some(UsbBusAllocator::new(bus));
+		let bus = match USB_BUS.as_ref() {
+			Some(bus) => bus,
+			None => return,
+		};
+		USB_SERIAL = Some(SerialPort::new(bus));
+		USB_STORAGE = Some(MassStorage::new(bus));
+		USB_DEVICE = Some(
+			UsbDeviceBuilder::new(bus, UsbVidPid(USB_VID, USB_PID))
+				.manufacturer("Neotron")
+				.product("Neotron Pico")
+				.serial_number("0")
+				.device_class(USB_CLASS_MISC)
+				.device_sub_class(USB_SUBCLASS_COMMON)
+				.device_protocol(USB_PROTOCOL_IAD)
+				.build(),
+		);
+		let (producer, consumer) = RX_QUEUE.split();
+		RX_PRODUCER = Some(producer);
+		RX_CONSUMER = Some(consumer);
+		let (producer, consumer) = TX_QUEUE.split();
+		TX_PRODUCER = Some(producer);
+		TX_CONSUMER = Some(consumer);
+		pac::NVIC::unmask(pac::Interrupt::USBCTRL_IRQ);
+	}
+	debug!("USB OK");
+}
+
+/// Is a host connected, with the port open?
+pub fn is_connected() -> bool {
+	CONNECTED.load(Ordering::Relaxed)
+}
+
+/// Queue some bytes to send to the host.
+///
+/// If the transmit buffer fills up, we wait for space until the timeout
+/// expires (or forever, with no timeout). We give up straight away if the
+/// host hasn't opened the port. Returns how many bytes were queued.
+pub fn write(data: &[u8], timeout_ms: Option<u32>) -> usize {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let producer = match unsafe { TX_PRODUCER.as_mut() } {
+		Some(producer) => producer,
+		None => return 0,
+	};
+	let start = crate::ticks_ms();
+	let mut count = 0;
+	while count < data.len() && is_connected() {
+		if producer.ready() {
+			let _ = producer.enqueue(data[count]);
+			count += 1;
+			continue;
+		}
+		// Full, so make sure the interrupt is emptying it
+		pac::NVIC::pend(pac::Interrupt::USBCTRL_IRQ);
+		if let Some(timeout_ms) = timeout_ms {
+			if crate::ticks_ms().wrapping_sub(start) >= timeout_ms {
+				break;
+			}
+		}
+	}
+	pac::NVIC::pend(pac::Interrupt::USBCTRL_IRQ);
+	count
+}
+
+/// Read some bytes from the host.
+///
+/// If nothing has been received, we wait until something is, or the
+/// timeout expires. With no timeout we wait forever. Returns how many bytes
+/// were copied into the buffer.
+pub fn read(buffer: &mut [u8], timeout_ms: Option<u32>) -> usize {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let consumer = match unsafe { RX_CONSUMER.as_mut() } {
+		Some(consumer) => consumer,
+		None => return 0,
+	};
+	if buffer.is_empty() {
+		return 0;
+	}
+	let start = crate::ticks_ms();
+	while !consumer.ready() {
+		if let Some(timeout_ms) = timeout_ms {
+			if crate::ticks_ms().wrapping_sub(start) >= timeout_ms {
+				return 0;
+			}
+		}
+	}
+	let mut count = 0;
+	for slot in buffer.iter_mut() {
+		match consumer.dequeue() {
+			Some(b) => *slot = b,
+			None => break,
+		}
+		count += 1;
+	}
+	if count != 0 {
+		// There's room now, so let the interrupt take anything the USB stack
+		// was holding on to
+		pac::NVIC::pend(pac::Interrupt::USBCTRL_IRQ);
+	}
+	count
+}
+
+/// Called from the USBCTRL interrupt. Runs the USB stack, moves bytes
+/// between it and our ring buffers, and moves any mass storage command
+/// along.
+///
+/// If the receive buffer is full, we leave bytes with the USB stack, which
+/// makes the host wait.
+///
+/// # Safety
+///
+/// Only call this from the USBCTRL interrupt handler.
+pub unsafe fn irq() {
+	let (device, serial, storage) = match (
+		USB_DEVICE.as_mut(),
+		USB_SERIAL.as_mut(),
+		USB_STORAGE.as_mut(),
+	) {
+		(Some(device), Some(serial), Some(storage)) => (device, serial, storage),
+		_ => return,
+	};
+	device.poll(&mut [serial, storage]);
+	storage.process();
+	let connected = device.state() == UsbDeviceState::Configured && serial.dtr();
+	CONNECTED.store(connected, Ordering::Relaxed);
+
+	if let Some(producer) = RX_PRODUCER.as_mut() {
+		let mut buffer = [0u8; USB_PACKET_LEN];
+		let space = (producer.capacity() - producer.len()).min(buffer.len());
+		if space > 0 {
+			if let Ok(count) = serial.read(&mut buffer[0..space]) {
+				for b in buffer[0..count].iter() {
+					let _ = producer.enqueue(*b);
+				}
+			}
+		}
+	}
+
+	let staging = &mut TX_STAGING;
+	if !connected {
+		// Nobody is listening, so throw it away
+		staging.len = 0;
+		if let Some(consumer) = TX_CONSUMER.as_mut() {
+			while consumer.dequeue().is_some() {}
+		}
+		return;
+	}
+	if let Some(consumer) = TX_CONSUMER.as_mut() {
+		while staging.len < staging.data.len() {
+			match consumer.dequeue() {
+				Some(b) => {
+					staging.data[staging.len] = b;
+					staging.len += 1;
+				}
+				None => break,
+			}
+		}
+	}
+	if staging.len > 0 {
+		if let Ok(sent) = serial.write(&staging.data[0..staging.len]) {
+			staging.data.copy_within(sent..staging.len, 0);
+			staging.len -= sent;
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
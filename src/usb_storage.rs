@@ -0,0 +1,677 @@
+//! # USB mass storage for the Neotron Pico
+//!
+//! Alongside the virtual COM port (see `usb`), the Pico's USB socket offers
+//! a USB Mass Storage interface (Bulk-Only Transport, with SCSI commands)
+//! backed by the SD card, so files can be copied to and from a PC without
+//! taking the card out.
+//!
+//! A PC and the OS writing to the same filesystem at once would wreck it, so
+//! the card is only offered to the PC once the OS has let go of it, with
+//! `set_shared`. Until then the PC sees an empty card reader, and while it
+//! is shared the OS can't use the card.
+//!
+//! The USB stack runs in the USBCTRL interrupt, but the SD card is on the
+//! Neotron Bus, which we only drive from Core 0's main thread. So the
+//! interrupt asks for each block to be read or written, and `poll` (called
+//! whenever the OS calls `system_poll`, and every so often from
+//! `hid_get_event`) does the work. `REQUEST` says who owns `BUFFER`: while a
+//! request is pending it belongs to `poll`, otherwise it belongs to the
+//! interrupt.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::bootslot::BLOCK_SIZE;
+use crate::{block_cache, sdcard};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use defmt::{debug, info};
+use rp_pico::hal::pac;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// A USB Mass Storage class, with one logical unit - the SD card.
+pub struct MassStorage<'a, B: UsbBus> {
+	interface: InterfaceNumber,
+	ep_in: EndpointIn<'a, B>,
+	ep_out: EndpointOut<'a, B>,
+	stage: Stage,
+	/// The tag from the current command, which goes back in its status
+	tag: u32,
+	/// How many bytes the host said the data phase would have
+	expected: u32,
+	/// How many bytes of the data phase we've actually dealt with
+	transferred: u32,
+	/// Did the host say the data phase goes from us to it?
+	data_in: bool,
+	/// Did the current command fail?
+	failed: bool,
+	/// The problem REQUEST SENSE reports
+	sense: Sense,
+	/// The next block to read or write
+	lba: u32,
+	/// How many blocks are still to be read or written
+	blocks_left: u32,
+	/// How much of `BUFFER` holds data to send, or has been filled
+	buffer_len: usize,
+	/// How much of `BUFFER` has been sent
+	buffer_pos: usize,
+	/// How many bytes we're still throwing away
+	discard_left: u32,
+}
+
+/// Where we are in a Bulk-Only Transport command.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Stage {
+	/// Waiting for a Command Block Wrapper
+	Command,
+	/// Sending `BUFFER` to the host
+	DataIn,
+	/// Ending the data phase early with a zero-length packet
+	ZeroLength,
+	/// Waiting for `poll` to read a block
+	WaitRead,
+	/// Filling `BUFFER` from the host
+	DataOut,
+	/// Waiting for `poll` to write a block
+	WaitWrite,
+	/// Throwing away the rest of the data the host sends
+	Discard,
+	/// Sending the Command Status Wrapper
+	Status,
+}
+
+/// SCSI sense data: the key, and the additional sense code and qualifier.
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Sense(u8, u8, u8);
+
+/// What running a SCSI command gave us.
+enum Outcome {
+	/// It worked, and this many bytes of `BUFFER` go back to the host
+	Data(usize),
+	/// It worked, and the data phase is handled by `stage`
+	Started,
+	/// It failed
+	Failed(Sense),
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// The biggest packet on a full-speed bulk endpoint
+const PACKET_LEN: usize = 64;
+
+/// Interface class: Mass Storage
+const CLASS_MSC: u8 = 0x08;
+
+/// Interface sub-class: the SCSI transparent command set
+const SUBCLASS_SCSI: u8 = 0x06;
+
+/// Interface protocol: Bulk-Only Transport
+const PROTOCOL_BOT: u8 = 0x50;
+
+/// Class request: reset the Bulk-Only Transport
+const REQ_BOT_RESET: u8 = 0xFF;
+
+/// Class request: how many logical units do we have (less one)?
+const REQ_GET_MAX_LUN: u8 = 0xFE;
+
+/// The start of every Command Block Wrapper ("USBC")
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+
+/// The start of every Command Status Wrapper ("USBS")
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+
+/// How long a Command Block Wrapper is
+const CBW_LEN: usize = 31;
+
+/// SCSI commands we understand
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_MODE_SENSE_6: u8 = 0x1A;
+const SCSI_START_STOP_UNIT: u8 = 0x1B;
+const SCSI_PREVENT_ALLOW_REMOVAL: u8 = 0x1E;
+const SCSI_READ_FORMAT_CAPACITIES: u8 = 0x23;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+const SCSI_VERIFY_10: u8 = 0x2F;
+
+/// All is well
+const SENSE_NONE: Sense = Sense(0x00, 0x00, 0x00);
+
+/// There's no card (or it isn't shared)
+const SENSE_NO_MEDIUM: Sense = Sense(0x02, 0x3A, 0x00);
+
+/// The card has come or gone since the host last looked
+const SENSE_MEDIUM_CHANGED: Sense = Sense(0x06, 0x28, 0x00);
+
+/// We don't understand the command
+const SENSE_INVALID_COMMAND: Sense = Sense(0x05, 0x20, 0x00);
+
+/// The blocks run off the end of the card
+const SENSE_OUT_OF_RANGE: Sense = Sense(0x05, 0x21, 0x00);
+
+/// The card wouldn't give us a block
+const SENSE_READ_ERROR: Sense = Sense(0x03, 0x11, 0x00);
+
+/// The card wouldn't take a block
+const SENSE_WRITE_ERROR: Sense = Sense(0x03, 0x03, 0x00);
+
+/// What INQUIRY tells the host about us
+const INQUIRY_DATA: [u8; 36] = *b"\x00\x80\x04\x02\x1f\x00\x00\x00Neotron Pico SD Card    0.1 ";
+
+/// Values for `REQUEST`
+const REQUEST_NONE: u8 = 0;
+const REQUEST_READ: u8 = 1;
+const REQUEST_WRITE: u8 = 2;
+const REQUEST_DONE: u8 = 3;
+const REQUEST_FAILED: u8 = 4;
+
+/// After doing a block, `poll` waits this long for the next request...
+const POLL_WAIT_MS: u32 = 2;
+
+/// ...but never keeps the OS waiting longer than this
+const POLL_BUDGET_MS: u32 = 20;
+
+/// What the interrupt wants `poll` to do (see `REQUEST_xxx`).
+static REQUEST: AtomicU8 = AtomicU8::new(REQUEST_NONE);
+
+/// The block the interrupt wants read or written.
+static REQUEST_LBA: AtomicU32 = AtomicU32::new(0);
+
+/// One block of data, on its way between the host and the card.
+///
+/// Owned by `poll` while `REQUEST` is `REQUEST_READ` or `REQUEST_WRITE`, and
+/// by the USBCTRL interrupt otherwise.
+static mut BUFFER: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
+
+/// Is the card offered to the host?
+static SHARED: AtomicBool = AtomicBool::new(false);
+
+/// How many blocks the shared card has (zero if it isn't shared).
+static NUM_BLOCKS: AtomicU32 = AtomicU32::new(0);
+
+/// Set when the card comes or goes, so we can tell the host.
+static MEDIUM_CHANGED: AtomicBool = AtomicBool::new(false);
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Offer the SD card to the host, or take it back.
+///
+/// While the card is shared, the OS mustn't use it.
+pub fn set_shared(shared: bool) -> Result<(), sdcard::Error> {
+	if shared {
+		// The PC reads the card directly, so it needs everything the OS
+		// wrote
+		block_cache::flush()?;
+		let card = sdcard::card_info()?;
+		NUM_BLOCKS.store(
+			card.num_blocks.min(u64::from(u32::MAX)) as u32,
+			Ordering::Relaxed,
+		);
+	} else {
+		NUM_BLOCKS.store(0, Ordering::Relaxed);
+	}
+	// Whatever the PC wrote isn't in the cache
+	block_cache::invalidate();
+	SHARED.store(shared, Ordering::Relaxed);
+	MEDIUM_CHANGED.store(true, Ordering::Relaxed);
+	info!("SD card shared over USB: {}", shared);
+	Ok(())
+}
+
+/// The card has been put in or taken out, so stop sharing it. The OS has to
+/// offer the new card again, if it wants to.
+pub fn card_changed() {
+	if is_shared() {
+		NUM_BLOCKS.store(0, Ordering::Relaxed);
+		SHARED.store(false, Ordering::Relaxed);
+		MEDIUM_CHANGED.store(true, Ordering::Relaxed);
+		info!("SD card no longer shared over USB");
+	}
+}
+
+/// Is the SD card offered to the host?
+pub fn is_shared() -> bool {
+	SHARED.load(Ordering::Relaxed)
+}
+
+/// Read or write the blocks the host is asking for.
+///
+/// Call this from Core 0's main thread, often. If the host is waiting on us,
+/// we keep going while it keeps asking, for up to `POLL_BUDGET_MS`.
+pub fn poll() {
+	let request = REQUEST.load(Ordering::Acquire);
+	if request != REQUEST_READ && request != REQUEST_WRITE {
+		// Nothing to do, so don't keep the OS waiting
+		return;
+	}
+	let start = crate::ticks_ms();
+	let mut last_request = start;
+	loop {
+		let now = crate::ticks_ms();
+		let request = REQUEST.load(Ordering::Acquire);
+		if request == REQUEST_READ || request == REQUEST_WRITE {
+			let lba = u64::from(REQUEST_LBA.load(Ordering::Relaxed));
+			// Note (safety): The interrupt leaves `BUFFER` alone while a
+			// request is pending.
+			let buffer = unsafe { &mut BUFFER };
+			let result = if request == REQUEST_READ {
+				sdcard::read_blocks(lba, buffer)
+			} else {
+				sdcard::write_blocks(lba, buffer)
+			};
+			REQUEST.store(
+				if result.is_ok() {
+					REQUEST_DONE
+				} else {
+					REQUEST_FAILED
+				},
+				Ordering::Release,
+			);
+			pac::NVIC::pend(pac::Interrupt::USBCTRL_IRQ);
+			last_request = crate::ticks_ms();
+		} else if now.wrapping_sub(last_request) >= POLL_WAIT_MS {
+			break;
+		}
+		if now.wrapping_sub(start) >= POLL_BUDGET_MS {
+			break;
+		}
+	}
+}
+
+impl<'a, B: UsbBus> MassStorage<'a, B> {
+	/// Make a new Mass Storage class, allocating its interface and endpoints.
+	pub fn new(alloc: &'a UsbBusAllocator<B>) -> MassStorage<'a, B> {
+		MassStorage {
+			interface: alloc.interface(),
+			ep_in: alloc.bulk(PACKET_LEN as u16),
+			ep_out: alloc.bulk(PACKET_LEN as u16),
+			stage: Stage::Command,
+			tag: 0,
+			expected: 0,
+			transferred: 0,
+			data_in: false,
+			failed: false,
+			sense: SENSE_NONE,
+			lba: 0,
+			blocks_left: 0,
+			buffer_len: 0,
+			buffer_pos: 0,
+			discard_left: 0,
+		}
+	}
+
+	/// Move the current command along as far as we can.
+	///
+	/// Call this from the USBCTRL interrupt, after polling the USB device.
+	pub fn process(&mut self) {
+		while self.step() {}
+	}
+
+	/// Take one step through the current command. Returns `false` if we
+	/// have to wait for the host, or for `poll`.
+	fn step(&mut self) -> bool {
+		match self.stage {
+			Stage::Command => {
+				match REQUEST.load(Ordering::Acquire) {
+					// After a reset, `poll` might still be busy with the
+					// buffer
+					REQUEST_READ | REQUEST_WRITE => return false,
+					REQUEST_DONE | REQUEST_FAILED => REQUEST.store(REQUEST_NONE, Ordering::Relaxed),
+					_ => {}
+				}
+				let mut packet = [0u8; PACKET_LEN];
+				match self.ep_out.read(&mut packet) {
+					Ok(CBW_LEN) => {
+						self.command(&packet[0..CBW_LEN]);
+						true
+					}
+					// Not a valid CBW, so ignore it
+					Ok(_) => true,
+					Err(_) => false,
+				}
+			}
+			Stage::DataIn => {
+				// Note (safety): We own `BUFFER`, as no request is pending.
+				let buffer = unsafe { &BUFFER };
+				let end = (self.buffer_pos + PACKET_LEN).min(self.buffer_len);
+				match self.ep_in.write(&buffer[self.buffer_pos..end]) {
+					Ok(count) => {
+						self.buffer_pos += count;
+						self.transferred += count as u32;
+						if self.buffer_pos == self.buffer_len {
+							if self.blocks_left != 0 {
+								self.request(REQUEST_READ);
+							} else {
+								self.end_data_in();
+							}
+						}
+						true
+					}
+					Err(_) => false,
+				}
+			}
+			Stage::ZeroLength => match self.ep_in.write(&[]) {
+				Ok(_) => {
+					self.stage = Stage::Status;
+					true
+				}
+				Err(_) => false,
+			},
+			Stage::WaitRead => match REQUEST.load(Ordering::Acquire) {
+				REQUEST_DONE => {
+					REQUEST.store(REQUEST_NONE, Ordering::Relaxed);
+					self.lba += 1;
+					self.blocks_left -= 1;
+					self.buffer_pos = 0;
+					self.buffer_len = BLOCK_SIZE;
+					self.stage = Stage::DataIn;
+					true
+				}
+				REQUEST_FAILED => {
+					REQUEST.store(REQUEST_NONE, Ordering::Relaxed);
+					self.fail(SENSE_READ_ERROR);
+					true
+				}
+				_ => false,
+			},
+			Stage::DataOut => {
+				// Note (safety): We own `BUFFER`, as no request is pending.
+				let buffer = unsafe { &mut BUFFER };
+				match self.ep_out.read(&mut buffer[self.buffer_pos..]) {
+					Ok(count) => {
+						self.buffer_pos += count;
+						self.transferred += count as u32;
+						if self.buffer_pos == BLOCK_SIZE {
+							self.request(REQUEST_WRITE);
+						}
+						true
+					}
+					Err(_) => false,
+				}
+			}
+			Stage::WaitWrite => match REQUEST.load(Ordering::Acquire) {
+				REQUEST_DONE => {
+					REQUEST.store(REQUEST_NONE, Ordering::Relaxed);
+					self.lba += 1;
+					self.blocks_left -= 1;
+					self.buffer_pos = 0;
+					self.stage = if self.blocks_left == 0 {
+						Stage::Status
+					} else {
+						Stage::DataOut
+					};
+					true
+				}
+				REQUEST_FAILED => {
+					REQUEST.store(REQUEST_NONE, Ordering::Relaxed);
+					self.fail(SENSE_WRITE_ERROR);
+					true
+				}
+				_ => false,
+			},
+			Stage::Discard => {
+				if self.discard_left == 0 {
+					self.stage = Stage::Status;
+					return true;
+				}
+				let mut packet = [0u8; PACKET_LEN];
+				match self.ep_out.read(&mut packet) {
+					Ok(count) => {
+						self.discard_left = self.discard_left.saturating_sub(count as u32);
+						true
+					}
+					Err(_) => false,
+				}
+			}
+			Stage::Status => {
+				let mut csw = [0u8; 13];
+				csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+				csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+				let residue = self.expected.saturating_sub(self.transferred);
+				csw[8..12].copy_from_slice(&residue.to_le_bytes());
+				csw[12] = u8::from(self.failed);
+				match self.ep_in.write(&csw) {
+					Ok(_) => {
+						self.stage = Stage::Command;
+						true
+					}
+					Err(_) => false,
+				}
+			}
+		}
+	}
+
+	/// Start a new command, from its Command Block Wrapper.
+	fn command(&mut self, cbw: &[u8]) {
+		let word = |offset: usize| {
+			u32::from_le_bytes([
+				cbw[offset],
+				cbw[offset + 1],
+				cbw[offset + 2],
+				cbw[offset + 3],
+			])
+		};
+		if word(0) != CBW_SIGNATURE {
+			return;
+		}
+		self.tag = word(4);
+		self.expected = word(8);
+		self.data_in = (cbw[12] & 0x80) != 0;
+		self.transferred = 0;
+		self.failed = false;
+		self.buffer_pos = 0;
+		let cb = &cbw[15..31];
+		debug!("SCSI command 0x{:02x}", cb[0]);
+		match self.scsi(cb) {
+			Outcome::Data(len) => {
+				self.buffer_len = len.min(self.expected as usize);
+				self.stage = Stage::DataIn;
+				if self.buffer_len == 0 {
+					self.end_data();
+				}
+			}
+			Outcome::Started => {}
+			Outcome::Failed(sense) => self.fail(sense),
+		}
+	}
+
+	/// Run a SCSI command.
+	fn scsi(&mut self, cb: &[u8]) -> Outcome {
+		// Note (safety): We own `BUFFER`, as no request is pending.
+		let buffer = unsafe { &mut BUFFER };
+		let num_blocks = NUM_BLOCKS.load(Ordering::Relaxed);
+		let be_u32 = |offset: usize| {
+			u32::from_be_bytes([cb[offset], cb[offset + 1], cb[offset + 2], cb[offset + 3]])
+		};
+		match cb[0] {
+			SCSI_TEST_UNIT_READY => {
+				if MEDIUM_CHANGED.load(Ordering::Relaxed) {
+					MEDIUM_CHANGED.store(false, Ordering::Relaxed);
+					Outcome::Failed(SENSE_MEDIUM_CHANGED)
+				} else if num_blocks == 0 {
+					Outcome::Failed(SENSE_NO_MEDIUM)
+				} else {
+					Outcome::Data(0)
+				}
+			}
+			SCSI_REQUEST_SENSE => {
+				let Sense(key, asc, ascq) = self.sense;
+				buffer[0..18].copy_from_slice(&[
+					0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, asc, ascq, 0, 0, 0, 0,
+				]);
+				self.sense = SENSE_NONE;
+				Outcome::Data(usize::from(cb[4]).min(18))
+			}
+			SCSI_INQUIRY => {
+				buffer[0..INQUIRY_DATA.len()].copy_from_slice(&INQUIRY_DATA);
+				let alloc_len = usize::from(u16::from_be_bytes([cb[3], cb[4]]));
+				Outcome::Data(alloc_len.min(INQUIRY_DATA.len()))
+			}
+			SCSI_MODE_SENSE_6 => {
+				// No mode pages, and not write-protected
+				buffer[0..4].copy_from_slice(&[3, 0, 0, 0]);
+				Outcome::Data(usize::from(cb[4]).min(4))
+			}
+			SCSI_START_STOP_UNIT | SCSI_PREVENT_ALLOW_REMOVAL | SCSI_VERIFY_10 => Outcome::Data(0),
+			SCSI_READ_FORMAT_CAPACITIES => {
+				if num_blocks == 0 {
+					return Outcome::Failed(SENSE_NO_MEDIUM);
+				}
+				buffer[0..4].copy_from_slice(&[0, 0, 0, 8]);
+				buffer[4..8].copy_from_slice(&num_blocks.to_be_bytes());
+				// Formatted media, with our block size
+				buffer[8..12].copy_from_slice(&(0x0200_0000 | BLOCK_SIZE as u32).to_be_bytes());
+				let alloc_len = usize::from(u16::from_be_bytes([cb[7], cb[8]]));
+				Outcome::Data(alloc_len.min(12))
+			}
+			SCSI_READ_CAPACITY_10 => {
+				if num_blocks == 0 {
+					return Outcome::Failed(SENSE_NO_MEDIUM);
+				}
+				buffer[0..4].copy_from_slice(&(num_blocks - 1).to_be_bytes());
+				buffer[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+				Outcome::Data(8)
+			}
+			SCSI_READ_10 | SCSI_WRITE_10 => {
+				if num_blocks == 0 {
+					return Outcome::Failed(SENSE_NO_MEDIUM);
+				}
+				let lba = be_u32(2);
+				let count = u32::from(u16::from_be_bytes([cb[7], cb[8]]));
+				match lba.checked_add(count) {
+					Some(end) if end <= num_blocks => {}
+					_ => return Outcome::Failed(SENSE_OUT_OF_RANGE),
+				}
+				self.lba = lba;
+				self.blocks_left = count;
+				if count == 0 {
+					self.stage = Stage::Status;
+				} else if cb[0] == SCSI_READ_10 {
+					self.request(REQUEST_READ);
+				} else {
+					self.buffer_pos = 0;
+					self.stage = Stage::DataOut;
+				}
+				Outcome::Started
+			}
+			_ => Outcome::Failed(SENSE_INVALID_COMMAND),
+		}
+	}
+
+	/// Ask `poll` to read or write the next block.
+	fn request(&mut self, request: u8) {
+		REQUEST_LBA.store(self.lba, Ordering::Relaxed);
+		self.stage = if request == REQUEST_READ {
+			Stage::WaitRead
+		} else {
+			Stage::WaitWrite
+		};
+		REQUEST.store(request, Ordering::Release);
+	}
+
+	/// Fail the current command, and finish the data phase.
+	fn fail(&mut self, sense: Sense) {
+		self.failed = true;
+		self.sense = sense;
+		self.end_data();
+	}
+
+	/// Finish the data phase, dealing with any data the host expects that
+	/// we don't have.
+	fn end_data(&mut self) {
+		if self.data_in {
+			self.end_data_in();
+		} else if self.transferred < self.expected {
+			self.discard_left = self.expected - self.transferred;
+			self.stage = Stage::Discard;
+		} else {
+			self.stage = Stage::Status;
+		}
+	}
+
+	/// Finish a data phase to the host. If we sent less than it expected,
+	/// and our last packet wasn't a short one, the host needs a zero-length
+	/// packet to know we've stopped.
+	fn end_data_in(&mut self) {
+		self.stage =
+			if self.transferred < self.expected && (self.transferred as usize % PACKET_LEN) == 0 {
+				Stage::ZeroLength
+			} else {
+				Stage::Status
+			};
+	}
+}
+
+impl<B: UsbBus> UsbClass<B> for MassStorage<'_, B> {
+	fn get_configuration_descriptors(
+		&self,
+		writer: &mut DescriptorWriter,
+	) -> usb_device::Result<()> {
+		writer.interface(self.interface, CLASS_MSC, SUBCLASS_SCSI, PROTOCOL_BOT)?;
+		writer.endpoint(&self.ep_in)?;
+		writer.endpoint(&self.ep_out)?;
+		Ok(())
+	}
+
+	fn reset(&mut self) {
+		self.stage = Stage::Command;
+	}
+
+	fn control_in(&mut self, xfer: ControlIn<B>) {
+		let req = xfer.request();
+		if req.request_type == RequestType::Class
+			&& req.recipient == Recipient::Interface
+			&& req.index == u16::from(u8::from(self.interface))
+			&& req.request == REQ_GET_MAX_LUN
+		{
+			// Just the one logical unit
+			let _ = xfer.accept_with(&[0]);
+		}
+	}
+
+	fn control_out(&mut self, xfer: ControlOut<B>) {
+		let req = xfer.request();
+		if req.request_type == RequestType::Class
+			&& req.recipient == Recipient::Interface
+			&& req.index == u16::from(u8::from(self.interface))
+			&& req.request == REQ_BOT_RESET
+		{
+			self.stage = Stage::Command;
+			let _ = xfer.accept();
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,234 @@
+//! # Character encodings for the Neotron Pico text console
+//!
+//! Text modes show 256 glyphs from the current font. The bottom half is
+//! always ASCII, but what the top half looks like depends on the font. A
+//! `CharMap` says which Unicode Scalar Value each glyph in the top half
+//! shows, so we can turn a Rust `char` into the right glyph.
+//!
+//! The built-in fonts are Code Page 850, so that is the default. Code Page
+//! 437 and ISO-8859-1 (Latin-1) are provided for fonts laid out that way, and
+//! the OS can supply its own table for anything else.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use super::Glyph;
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The character encodings we know about.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Encoding {
+	/// IBM Code Page 850 (Western Europe), which matches the built-in fonts
+	Cp850 = 0,
+	/// IBM Code Page 437 (the original IBM PC character set)
+	Cp437 = 1,
+	/// ISO-8859-1, where each glyph number is the Unicode code point
+	Latin1 = 2,
+	/// The table given to `set_user_map`
+	User = 3,
+}
+
+/// The Unicode code point shown by each of glyphs 128 to 255.
+///
+/// Only code points in the Basic Multilingual Plane can be used.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct CharMap {
+	upper: [u16; 128],
+}
+
+/// The ways in which a user-supplied table can be bad.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The table wasn't exactly `USER_MAP_LEN` bytes long
+	BadLength,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How long a user-supplied table is - 128 little-endian `u16` code points,
+/// for glyphs 128 to 255.
+pub const USER_MAP_LEN: usize = 256;
+
+/// IBM Code Page 850
+#[rustfmt::skip]
+const CP850: CharMap = CharMap {
+	upper: [
+		0x00C7, 0x00FC, 0x00E9, 0x00E2, 0x00E4, 0x00E0, 0x00E5, 0x00E7, // 0x80
+		0x00EA, 0x00EB, 0x00E8, 0x00EF, 0x00EE, 0x00EC, 0x00C4, 0x00C5, // 0x88
+		0x00C9, 0x00E6, 0x00C6, 0x00F4, 0x00F6, 0x00F2, 0x00FB, 0x00F9, // 0x90
+		0x00FF, 0x00D6, 0x00DC, 0x00F8, 0x00A3, 0x00D8, 0x00D7, 0x0192, // 0x98
+		0x00E1, 0x00ED, 0x00F3, 0x00FA, 0x00F1, 0x00D1, 0x00AA, 0x00BA, // 0xA0
+		0x00BF, 0x00AE, 0x00AC, 0x00BD, 0x00BC, 0x00A1, 0x00AB, 0x00BB, // 0xA8
+		0x2591, 0x2592, 0x2593, 0x2502, 0x2524, 0x00C1, 0x00C2, 0x00C0, // 0xB0
+		0x00A9, 0x2563, 0x2551, 0x2557, 0x255D, 0x00A2, 0x00A5, 0x2510, // 0xB8
+		0x2514, 0x2534, 0x252C, 0x251C, 0x2500, 0x253C, 0x00E3, 0x00C3, // 0xC0
+		0x255A, 0x2554, 0x2569, 0x2566, 0x2560, 0x2550, 0x256C, 0x00A4, // 0xC8
+		0x00F0, 0x00D0, 0x00CA, 0x00CB, 0x00C8, 0x0131, 0x00CD, 0x00CE, // 0xD0
+		0x00CF, 0x2518, 0x250C, 0x2588, 0x2584, 0x00A6, 0x00CC, 0x2580, // 0xD8
+		0x00D3, 0x00DF, 0x00D4, 0x00D2, 0x00F5, 0x00D5, 0x00B5, 0x00FE, // 0xE0
+		0x00DE, 0x00DA, 0x00DB, 0x00D9, 0x00FD, 0x00DD, 0x00AF, 0x00B4, // 0xE8
+		0x00AD, 0x00B1, 0x2017, 0x00BE, 0x00B6, 0x00A7, 0x00F7, 0x00B8, // 0xF0
+		0x00B0, 0x00A8, 0x00B7, 0x00B9, 0x00B3, 0x00B2, 0x25A0, 0x00A0, // 0xF8
+	],
+};
+
+/// IBM Code Page 437
+#[rustfmt::skip]
+const CP437: CharMap = CharMap {
+	upper: [
+		0x00C7, 0x00FC, 0x00E9, 0x00E2, 0x00E4, 0x00E0, 0x00E5, 0x00E7, // 0x80
+		0x00EA, 0x00EB, 0x00E8, 0x00EF, 0x00EE, 0x00EC, 0x00C4, 0x00C5, // 0x88
+		0x00C9, 0x00E6, 0x00C6, 0x00F4, 0x00F6, 0x00F2, 0x00FB, 0x00F9, // 0x90
+		0x00FF, 0x00D6, 0x00DC, 0x00A2, 0x00A3, 0x00A5, 0x20A7, 0x0192, // 0x98
+		0x00E1, 0x00ED, 0x00F3, 0x00FA, 0x00F1, 0x00D1, 0x00AA, 0x00BA, // 0xA0
+		0x00BF, 0x2310, 0x00AC, 0x00BD, 0x00BC, 0x00A1, 0x00AB, 0x00BB, // 0xA8
+		0x2591, 0x2592, 0x2593, 0x2502, 0x2524, 0x2561, 0x2562, 0x2556, // 0xB0
+		0x2555, 0x2563, 0x2551, 0x2557, 0x255D, 0x255C, 0x255B, 0x2510, // 0xB8
+		0x2514, 0x2534, 0x252C, 0x251C, 0x2500, 0x253C, 0x255E, 0x255F, // 0xC0
+		0x255A, 0x2554, 0x2569, 0x2566, 0x2560, 0x2550, 0x256C, 0x2567, // 0xC8
+		0x2568, 0x2564, 0x2565, 0x2559, 0x2558, 0x2552, 0x2553, 0x256B, // 0xD0
+		0x256A, 0x2518, 0x250C, 0x2588, 0x2584, 0x258C, 0x2590, 0x2580, // 0xD8
+		0x03B1, 0x00DF, 0x0393, 0x03C0, 0x03A3, 0x03C3, 0x00B5, 0x03C4, // 0xE0
+		0x03A6, 0x0398, 0x03A9, 0x03B4, 0x221E, 0x03C6, 0x03B5, 0x2229, // 0xE8
+		0x2261, 0x00B1, 0x2265, 0x2264, 0x2320, 0x2321, 0x00F7, 0x2248, // 0xF0
+		0x00B0, 0x2219, 0x00B7, 0x221A, 0x207F, 0x00B2, 0x25A0, 0x00A0, // 0xF8
+	],
+};
+
+/// ISO-8859-1
+const LATIN1: CharMap = CharMap::latin1();
+
+/// The table the OS gave us. Starts off as a copy of Code Page 850.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut USER_MAP: CharMap = CP850;
+
+/// The table used to convert text for the console.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut CURRENT_ENCODING: Encoding = Encoding::Cp850;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Choose the table used to convert text for the console.
+pub fn set_encoding(encoding: Encoding) {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe {
+		CURRENT_ENCODING = encoding;
+	}
+}
+
+/// Which table is used to convert text for the console?
+pub fn encoding() -> Encoding {
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	unsafe { CURRENT_ENCODING }
+}
+
+/// Load the `Encoding::User` table.
+///
+/// The data is 128 little-endian `u16` Unicode code points, for glyphs 128
+/// to 255 in order. Use U+FFFD for a glyph that shows nothing in particular.
+pub fn set_user_map(data: &[u8]) -> Result<(), Error> {
+	if data.len() != USER_MAP_LEN {
+		return Err(Error::BadLength);
+	}
+	// Note (safety): Only called from Core 0, and never from an interrupt.
+	let user_map = unsafe { &mut USER_MAP };
+	for (entry, bytes) in user_map.upper.iter_mut().zip(data.chunks_exact(2)) {
+		*entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+	}
+	Ok(())
+}
+
+/// Convert a Unicode Scalar Value to a font glyph, using the current table.
+///
+/// Anything the table doesn't have becomes a `?`.
+pub fn char_to_glyph(input: char) -> Glyph {
+	current_map().char_to_glyph(input)
+}
+
+/// Get the table for the current encoding.
+fn current_map() -> &'static CharMap {
+	match encoding() {
+		Encoding::Cp850 => &CP850,
+		Encoding::Cp437 => &CP437,
+		Encoding::Latin1 => &LATIN1,
+		// Note (safety): Only called from Core 0, and never from an interrupt.
+		Encoding::User => unsafe { &USER_MAP },
+	}
+}
+
+impl Encoding {
+	/// Convert from the number stored in the configuration.
+	pub const fn from_u8(value: u8) -> Option<Encoding> {
+		match value {
+			0 => Some(Encoding::Cp850),
+			1 => Some(Encoding::Cp437),
+			2 => Some(Encoding::Latin1),
+			3 => Some(Encoding::User),
+			_ => None,
+		}
+	}
+}
+
+impl CharMap {
+	/// Build the ISO-8859-1 table, where every glyph is its own code point.
+	const fn latin1() -> CharMap {
+		let mut upper = [0u16; 128];
+		let mut idx = 0;
+		while idx < upper.len() {
+			upper[idx] = 128 + idx as u16;
+			idx += 1;
+		}
+		CharMap { upper }
+	}
+
+	/// Find the glyph for a Unicode Scalar Value.
+	///
+	/// ASCII is passed straight through. Everything else needs a search of
+	/// the table, but the table is small and this is only used on Core 0.
+	fn char_to_glyph(&self, input: char) -> Glyph {
+		if input.is_ascii() {
+			return Glyph(input as u8);
+		}
+		let code_point = input as u32;
+		for (idx, entry) in self.upper.iter().enumerate() {
+			if u32::from(*entry) == code_point {
+				return Glyph(128 + idx as u8);
+			}
+		}
+		Glyph(b'?')
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -3,9 +3,16 @@
 //! VGA output on the Neotron Pico uses 14 GPIO pins and two PIO state machines.
 //!
 //! It can generate 640x480@60Hz and 640x400@70Hz standard VGA video, with a
-//! 25.2 MHz pixel clock. The spec is 25.175 MHz, so we are 0.1% off). The
-//! assumption is that the CPU is clocked at 126 MHz, i.e. 5x the pixel
-//! clock. All of the PIO code relies on this assumption!
+//! 25.2 MHz pixel clock. The spec is 25.175 MHz, so we are 0.1% off). It
+//! can also generate 800x600@56Hz SVGA video, with a 36 MHz pixel clock, for
+//! a 100x37 text mode.
+//!
+//! The PIO must run at between five and eight times the pixel clock - we
+//! rewrite the delays in the pixel program to suit - so the system clock
+//! must be a whole multiple of one of those speeds (see `sysclock`).
+//! Anything faster is brought down with a whole-number PIO clock divider,
+//! worked out by `pio_clocking`. Only the 252 MHz clock profile can make
+//! 36 MHz, so 800x600 isn't offered at the other speeds.
 //!
 //! Currently only an 80x25 two-colour text-mode is supported. Other modes will be
 //! added in the future.
@@ -33,15 +40,20 @@
 // Sub-modules
 // -----------------------------------------------------------------------------
 
+pub mod charmap;
 mod font16;
 mod font8;
+pub mod sprites;
 
 // -----------------------------------------------------------------------------
 // Imports
 // -----------------------------------------------------------------------------
 
-use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicUsize, Ordering};
-use defmt::{debug, trace};
+use crate::memdma;
+use core::sync::atomic::{
+	AtomicBool, AtomicPtr, AtomicU16, AtomicU32, AtomicU8, AtomicUsize, Ordering,
+};
+use defmt::{debug, trace, warn};
 use rp_pico::hal::pio::PIOExt;
 
 // -----------------------------------------------------------------------------
@@ -55,10 +67,44 @@ use rp_pico::hal::pio::PIOExt;
 struct RenderEngine {
 	/// How many frames have been drawn
 	frame_count: u32,
+	/// How many frames have gone by since `VIDEO_DIRTY` was last set
+	idle_frames: u32,
+	/// How many of the two scan-line buffers have been blanked since the
+	/// screen was blanked
+	blanked_buffers: u8,
+	/// Where the screen saver logo is, in pixel pairs from the left
+	logo_x: u16,
+	/// Where the screen saver logo is, in scan-lines from the top
+	logo_y: u16,
+	/// Which way the screen saver logo is moving (right, down)
+	logo_direction: (bool, bool),
 	/// Look-up table mapping two 1-bpp pixels to two 12-bit RGB values (packed into one 32-bit word).
 	///
 	/// You can adjust this table to convert text to different colours.
 	lookup: [RGBPair; 4],
+	/// The font for the 8x8 text modes
+	font8: Font<'static>,
+	/// The font for the 8x16 text modes
+	font16: Font<'static>,
+	/// The second bank of 8x8 glyphs, for cells with `ATTR_FONT_BANK` set
+	font8_alt: Font<'static>,
+	/// The second bank of 8x16 glyphs, for cells with `ATTR_FONT_BANK` set
+	font16_alt: Font<'static>,
+	/// The text buffer we are drawing this frame from, so a page flip
+	/// never happens part-way down the screen
+	frame_text_buffer: *const GlyphAttr,
+	/// The `SCROLL_OFFSET` for this frame
+	frame_scroll_offset: u16,
+	/// Our copy of `PENDING_PALETTE_CHANGES`
+	palette_changes: [PaletteChange; MAX_PALETTE_CHANGES],
+	/// How many of `palette_changes` are in use
+	num_palette_changes: usize,
+	/// The next entry in `palette_changes` to apply this frame
+	next_palette_change: usize,
+	/// The longest `render_scanline` has taken so far this frame
+	frame_render_time: u32,
+	/// The `frame_count` when we last warned about a render overrun
+	last_overrun_warning: u32,
 }
 
 /// A font
@@ -70,21 +116,61 @@ pub struct Font<'a> {
 /// Holds some data necessary to present a text console.
 ///
 /// Used by Core 0 to control writes to a shared text-buffer.
+///
+/// Understands a small set of ANSI/VT100 escape sequences: cursor movement
+/// (`CSI n A/B/C/D`, `CSI r;c H`), clearing (`CSI n J`, `CSI n K`) and
+/// colours (`CSI n m`). Anything else is swallowed. An ASCII BEL beeps (see
+/// `audio::beep`).
 pub struct TextConsole {
 	current_col: AtomicU16,
 	current_row: AtomicU16,
+	current_attr: AtomicU8,
 	text_buffer: AtomicPtr<GlyphAttr>,
+	/// An `AnsiParser`, packed with `AnsiParser::pack`, as an escape
+	/// sequence can be split across calls to `write_str`
+	ansi_parser: AtomicU32,
+}
+
+/// Where we are in an ANSI escape sequence.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum AnsiState {
+	/// Not in an escape sequence
+	Normal,
+	/// We've had an ESC
+	Escape,
+	/// We've had an ESC and a `[`, and are collecting numbers
+	Csi,
+}
+
+/// Collects the parts of an ANSI escape sequence.
+#[derive(Copy, Clone)]
+struct AnsiParser {
+	state: AnsiState,
+	/// Which of `params` we are collecting
+	index: u8,
+	/// The numeric parameters, each saturating at 255
+	params: [u8; ANSI_MAX_PARAMS],
 }
 
 /// Describes one scan-line's worth of pixels, including the length word required by the Pixel FIFO.
 #[repr(C, align(16))]
 struct LineBuffer {
-	/// Must be one less than the number of pixel-pairs in `pixels`
+	/// Must be one less than the number of pixel-pairs we send from `pixels`
+	/// (i.e. `NUM_PIXEL_PAIRS_PER_LINE`)
 	length: u32,
 	/// Pixels to be displayed, grouped into pairs (to save FIFO space and reduce DMA bandwidth)
 	pixels: [RGBPair; MAX_NUM_PIXEL_PAIRS_PER_LINE],
 }
 
+/// A scan-line with only one pixel-pair in it. See `BLANK_LINE`.
+#[repr(C, align(8))]
+struct BlankLineBuffer {
+	/// Always zero, for one pixel-pair
+	length: u32,
+	/// The pixel-pair, which is black
+	pixels: [RGBPair; 1],
+}
+
 /// Describes the polarity of a sync pulse.
 ///
 /// Some pulses are positive (active-high), some are negative (active-low).
@@ -121,6 +207,12 @@ struct TimingBuffer {
 	sync_pulse_ends_at: u16,
 	/// The last scan-line of the back-porch (and the frame)
 	back_porch_ends_at: u16,
+	/// The pixel clock these timings are for
+	pixel_clock_hz: u32,
+	/// How many PIO clocks each pixel takes
+	cycles_per_pixel: u32,
+	/// How many pixel pairs are on each visible scan-line
+	pixel_pairs: u16,
 }
 
 /// Represents a 12-bit colour value.
@@ -128,6 +220,8 @@ struct TimingBuffer {
 /// Each channel has four-bits, and they are packed in `GBR` format. This is
 /// so the PIO can shift them out right-first, and we have RED0 assigned to
 /// the lowest GPIO pin.
+///
+/// As a `u16`, this is `0x0BGR`.
 #[repr(transparent)]
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct RGBColour(u16);
@@ -150,6 +244,224 @@ pub struct Glyph(u8);
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Attr(u8);
 
+/// A small 8x8 bitmap, one palette index per pixel, stored row by row.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Icon {
+	/// The pixels, top-left first
+	pub pixels: [u8; ICON_SIZE * ICON_SIZE],
+}
+
+/// Counters which show how well the video is keeping up, for
+/// `video_get_stats`.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct VideoStats {
+	/// How many frames have been sent to the monitor (see `vblank_count`)
+	pub frames: u32,
+	/// The longest time Core 1 spent drawing a scan-line in the last
+	/// frame, in system clock cycles (see `render_time`)
+	pub render_time: u32,
+	/// How many scan-lines Core 1 finished drawing too late, after the
+	/// monitor had started showing them
+	pub clashes: u32,
+	/// How many frames had the pixel FIFO run dry part-way along a
+	/// scan-line
+	pub pixel_underruns: u32,
+	/// How many frames had the timing FIFO run dry, which upsets the sync
+	/// signals
+	pub timing_underruns: u32,
+}
+
+/// What Core 1 needs to do the DMA interrupt's job while the flash chip is
+/// busy (see `flash`).
+///
+/// It's all worked out in advance, as the code that uses it runs from RAM,
+/// and can't call anything in flash. Every field is an address, or a
+/// scan-line number.
+pub struct DmaHold {
+	/// The DMA's `INTS0` register
+	ints0: u32,
+	/// The timing channel's `AL3_READ_ADDR_TRIG` register
+	timing_trigger: u32,
+	/// The pixel channel's `AL3_READ_ADDR_TRIG` register
+	pixel_trigger: u32,
+	/// The pixel channel's `TRANS_COUNT` register
+	pixel_count: u32,
+	/// `CURRENT_TIMING_LINE`
+	timing_line: u32,
+	/// `CURRENT_DISPLAY_LINE`
+	display_line: u32,
+	/// `VBLANK_COUNT`
+	vblank_count: u32,
+	/// The timing words for a visible line
+	visible_line: u32,
+	/// The timing words for a porch line
+	vblank_porch: u32,
+	/// The timing words for a sync line
+	vblank_sync: u32,
+	/// `BLANK_LINE`, which we keep sending
+	pixels: u32,
+	/// Copied from `TIMING_BUFFER`
+	visible_lines_ends_at: u16,
+	/// Copied from `TIMING_BUFFER`
+	front_porch_end_at: u16,
+	/// Copied from `TIMING_BUFFER`
+	sync_pulse_ends_at: u16,
+	/// Copied from `TIMING_BUFFER`
+	back_porch_ends_at: u16,
+}
+
+/// A change to one palette entry, made part-way down the screen.
+///
+/// A list of these gives the copper-style effects (raster bars, and so on)
+/// of older machines. See `set_palette_changes`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct PaletteChange {
+	/// The visible scan-line on which the new colour starts
+	pub line: u16,
+	/// Which palette entry to change
+	pub index: u8,
+	/// The new colour
+	pub colour: RGBColour,
+}
+
+/// A complete set of monitor timings, like an X11 mode-line, for monitors
+/// that none of the standard timings suit (e.g. odd LCD panels, or 15 kHz
+/// CRTs).
+///
+/// Horizontal values are in pixels, and vertical values are in scan-lines.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct ModeLine {
+	/// The pixel clock. The system clock must be a whole multiple of five to
+	/// eight times this.
+	pub pixel_clock_hz: u32,
+	/// Visible pixels on each scan-line - a multiple of 8, up to 800
+	pub h_visible: u16,
+	/// Pixels between the visible portion and the H-Sync pulse
+	pub h_front_porch: u16,
+	/// The length of the H-Sync pulse
+	pub h_sync: u16,
+	/// Pixels between the H-Sync pulse and the visible portion
+	pub h_back_porch: u16,
+	/// Visible scan-lines
+	pub v_visible: u16,
+	/// Scan-lines between the visible portion and the V-Sync pulse
+	pub v_front_porch: u16,
+	/// The length of the V-Sync pulse
+	pub v_sync: u16,
+	/// Scan-lines between the V-Sync pulse and the visible portion
+	pub v_back_porch: u16,
+	/// Any of the `MODE_LINE_xxx` flags
+	pub flags: u8,
+}
+
+/// The timings we know work with particular kinds of monitor, so the OS
+/// (or the BIOS configuration) doesn't have to work out a `ModeLine`.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum TimingPreset {
+	/// The standard VGA and SVGA timings, chosen by each video mode
+	Standard = 0,
+	/// 320x240 progressive, at 15.6 kHz and 60 Hz, with composite sync
+	Rgb15kHz240 = 1,
+	/// 320x256 progressive, at 15.6 kHz and 50 Hz, with composite sync
+	Rgb15kHz256 = 2,
+}
+
+/// The ways in which a `ModeLine` can be unsuitable.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum TimingError {
+	/// We can't make the pixel clock from the system clock
+	BadClock,
+	/// The visible width isn't a multiple of 8, or is too wide, or one of
+	/// the horizontal periods is too short or too long for the timing state
+	/// machine
+	BadHorizontal,
+	/// There are no visible lines, no V-Sync pulse, or too many lines
+	BadVertical,
+	/// The current video mode doesn't fit these timings
+	BadMode,
+}
+
+/// The ways in which starting the video can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum InitError {
+	/// The PIO programs didn't fit
+	PioProgram,
+	/// The timing DMA channel didn't complete any transfers
+	NoTimingDma,
+	/// The pixel DMA channel didn't complete any transfers
+	NoPixelDma,
+	/// The system clock isn't a speed we can make a pixel clock from
+	BadClock,
+}
+
+/// The ways in which a framebuffer from the OS can be unsuitable.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum FramebufferError {
+	/// The framebuffer (at the size the current mode needs) doesn't fit
+	/// inside the RAM the OS is allowed to use
+	OutsideRam,
+	/// The framebuffer isn't aligned correctly for the current mode
+	Misaligned,
+}
+
+/// The ways in which an icon blit can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum BlitError {
+	/// The current video mode doesn't have a bitmap framebuffer
+	NotBitmapMode,
+	/// The OS hasn't supplied a framebuffer for the current video mode
+	NoFramebuffer,
+}
+
+/// What the screen saver shows.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum ScreenSaverStyle {
+	/// A black screen. Core 1 stops drawing, which saves the most power.
+	Blank = 0,
+	/// A logo bouncing around a black screen
+	Logo = 1,
+}
+
+/// How the OS wants the screen saver to behave.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum ScreenSaverOverride {
+	/// Start the screen saver after the configured idle time
+	Auto = 0,
+	/// Never start the screen saver (e.g. while playing a video)
+	Inhibit = 1,
+	/// Start the screen saver now. The next input (or change to the screen)
+	/// stops it, and puts us back to `Auto`.
+	Activate = 2,
+}
+
+/// The monitor power states, as in VESA's Display Power Management
+/// Signalling (DPMS).
+///
+/// In every state except `On` the screen is black and Core 1 stops drawing.
+/// A DPMS monitor notices which sync signals have stopped, and powers down
+/// further the more of them are missing.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum PowerState {
+	/// A normal picture
+	On = 0,
+	/// A black picture, with both sync signals running
+	Blank = 1,
+	/// No H-Sync
+	Standby = 2,
+	/// No V-Sync
+	Suspend = 3,
+	/// No H-Sync or V-Sync
+	Off = 4,
+}
+
 /// Represents a glyph/attribute pair. This is what out text console is made
 /// out of. They work in exactly the same way as IBM PC VGA.
 #[repr(transparent)]
@@ -160,27 +472,33 @@ pub struct GlyphAttr(u16);
 // Static and Const Data
 // -----------------------------------------------------------------------------
 
-/// How many pixels per scan-line.
+/// The most pixels on any scan-line (in the 800x600 mode).
 ///
-/// Adjust the pixel PIO program to run at the right speed to the screen is
-/// filled. For example, if this is only 320 but you are aiming at 640x480,
-/// make the pixel PIO take twice as long per pixel.
-const MAX_NUM_PIXELS_PER_LINE: usize = 640;
+/// Our line buffers are this big, but we only send as many pixels as the
+/// current mode needs (see `NUM_PIXEL_PAIRS_PER_LINE`).
+const MAX_NUM_PIXELS_PER_LINE: usize = 800;
 
-/// Maximum number of lines on screen.
-const MAX_NUM_LINES: usize = 480;
-
-/// How many pixel pairs we send out.
+/// The most pixel pairs we send out on any scan-line.
 ///
 /// Each pixel is two 12-bit values packed into one 32-bit word(an `RGBPair`).
 /// This is to make more efficient use of DMA and FIFO resources.
 const MAX_NUM_PIXEL_PAIRS_PER_LINE: usize = MAX_NUM_PIXELS_PER_LINE / 2;
 
+/// The width and height of an `Icon`, in pixels.
+pub const ICON_SIZE: usize = 8;
+
 /// The highest number of columns in any text mode.
 pub const MAX_TEXT_COLS: usize = MAX_NUM_PIXELS_PER_LINE / 8;
 
-/// The highest number of rows in any text mode.
-pub const MAX_TEXT_ROWS: usize = MAX_NUM_LINES as usize / 8;
+/// The highest number of rows in any text mode (80x60).
+pub const MAX_TEXT_ROWS: usize = 60;
+
+/// How many glyphs fit in our own text buffer.
+///
+/// This is enough for 80x60, which is the biggest text mode. It isn't
+/// `MAX_TEXT_COLS * MAX_TEXT_ROWS`, as the widest mode (100x37) isn't also
+/// the tallest, and we don't have the RAM to spare.
+pub const TEXT_BUFFER_CELLS: usize = 80 * 60;
 
 /// Current number of visible columns.
 ///
@@ -190,13 +508,198 @@ pub static NUM_TEXT_COLS: AtomicUsize = AtomicUsize::new(80);
 /// Current number of visible rows.
 ///
 /// Must be `<= MAX_TEXT_ROWS`
-pub static NUM_TEXT_ROWS: AtomicUsize = AtomicUsize::new(25);
+pub static NUM_TEXT_ROWS: AtomicUsize = AtomicUsize::new(30);
+
+/// Every text mode we can display: 80x30, 80x60, 80x25, 80x50 and 100x37.
+///
+/// The last one is only available if the system clock can make the SVGA
+/// pixel clock (see `is_valid_mode`). These are the sizes before pixel
+/// doubling: each can also be shown with double-width or double-height
+/// pixels (or both), which gives 40x25 in 320x200, 40x30 in 320x240, and so
+/// on.
+pub const TEXT_MODES: [crate::common::video::Mode; 5] = [
+	crate::common::video::Mode::new(
+		crate::common::video::Timing::T640x480,
+		crate::common::video::Format::Text8x16,
+	),
+	crate::common::video::Mode::new(
+		crate::common::video::Timing::T640x480,
+		crate::common::video::Format::Text8x8,
+	),
+	crate::common::video::Mode::new(
+		crate::common::video::Timing::T640x400,
+		crate::common::video::Format::Text8x16,
+	),
+	crate::common::video::Mode::new(
+		crate::common::video::Timing::T640x400,
+		crate::common::video::Format::Text8x8,
+	),
+	crate::common::video::Mode::new(
+		crate::common::video::Timing::T800x600,
+		crate::common::video::Format::Text8x16,
+	),
+];
+
+/// The most virtual consoles we offer.
+pub const MAX_CONSOLES: u8 = 3;
+
+/// Which virtual console is on screen.
+///
+/// The virtual consoles share `GLYPH_ATTR_ARRAY`, one after the other, so
+/// how many there are depends on the size of the current text mode.
+static ACTIVE_CONSOLE: AtomicU8 = AtomicU8::new(0);
+
+/// Which virtual console to put on screen at the start of the next frame,
+/// or `NO_FLIP`.
+///
+/// Set by Core 0, and taken by `RenderEngine` running on Core 1.
+static PENDING_CONSOLE: AtomicU8 = AtomicU8::new(NO_FLIP);
+
+/// A `PENDING_CONSOLE` that means no flip is waiting.
+const NO_FLIP: u8 = 0xFF;
+
+/// How many scan-lines the DMA interrupt sends each pixel buffer for (1, 2
+/// or 4), so Core 1 only renders one scan-line in that many.
+///
+/// The modes which double every line (e.g. 640x240) send each buffer twice,
+/// and interlacing doubles that again. Neither applies if the scan-line
+/// effect needs each line drawn separately.
+static LINE_REPEAT: AtomicU8 = AtomicU8::new(1);
+
+/// Set if the OS has asked for interlacing (see `set_interlace`).
+static INTERLACE: AtomicBool = AtomicBool::new(false);
+
+/// Which half of the scan-lines Core 1 draws this frame, when interlacing.
+///
+/// Flipped by the DMA interrupt at the start of every frame.
+static INTERLACE_FIELD: AtomicBool = AtomicBool::new(false);
+
+/// Set when the DMA is sending `PIXEL_DATA_BUFFER_ODD`, so Core 1 knows to
+/// draw into the other one.
+///
+/// Only written by the DMA interrupt.
+static SENDING_ODD_BUFFER: AtomicBool = AtomicBool::new(false);
+
+/// The VGA pixel clock, for the 640 pixel wide modes
+const VGA_PIXEL_CLOCK_HZ: u32 = 25_200_000;
+
+/// The SVGA pixel clock, for the 800x600 mode
+const SVGA_PIXEL_CLOCK_HZ: u32 = 36_000_000;
+
+/// The fewest PIO clocks a pixel can take.
+///
+/// The pixel program needs a few clocks at the start of each line, which
+/// the back porch allows for (see `ScanlineTimingBuffer::new_v_visible`).
+/// At five clocks per pixel the pixel DMA has to move one word every ten
+/// PIO clocks, which leaves plenty of bus time for everything else.
+const MIN_CYCLES_PER_PIXEL: u32 = 5;
+
+/// The most PIO clocks a pixel can take.
+///
+/// This gets us 36 MHz from 252 MHz (at seven) with a bit to spare. The
+/// delay field in each instruction would allow up to 32.
+const MAX_CYCLES_PER_PIXEL: u32 = 8;
+
+/// The system clock speed, which we need to work out the PIO clocking for
+/// each mode. Set by `init`.
+static SYS_CLOCK_HZ: AtomicU32 = AtomicU32::new(0);
+
+/// Where the pixel program was loaded, so we can rewrite its delays when
+/// the pixel clock changes. Set by `init`.
+static PIXEL_PROGRAM_OFFSET: AtomicU8 = AtomicU8::new(0);
+
+/// How many pixel pairs are on each scan-line in the current mode. We start
+/// in a 640 pixel wide mode.
+///
+/// Must be `<= MAX_NUM_PIXEL_PAIRS_PER_LINE`
+static NUM_PIXEL_PAIRS_PER_LINE: AtomicUsize = AtomicUsize::new(320);
 
 /// Used to signal when Core 1 has started
 static CORE1_START_FLAG: AtomicBool = AtomicBool::new(false);
 
+/// The bottom of Core 1's stack, where we put `CORE1_STACK_CANARY`, or null
+/// if Core 1 hasn't been started.
+static CORE1_STACK_BOTTOM: AtomicPtr<usize> = AtomicPtr::new(core::ptr::null_mut());
+
+/// A pattern we write at the bottom of Core 1's stack. If it ever changes,
+/// the stack has overflowed.
+const CORE1_STACK_CANARY: usize = 0xC0DE_57AC;
+
+/// How many words of `CORE1_STACK_CANARY` we write.
+///
+/// A function with a big stack frame could jump right over a single word
+/// without writing it, so we use a few.
+const CORE1_STACK_CANARY_WORDS: usize = 16;
+
 /// Stores our timing data which we DMA into the timing PIO State Machine
-static mut TIMING_BUFFER: TimingBuffer = TimingBuffer::make_640x480();
+static mut TIMING_BUFFER: TimingBuffer = TimingBuffer::make_640x480(5);
+
+/// Timings from the OS, which we use instead of the standard timings for
+/// every mode, if set.
+///
+/// Only accessed from Core 0, and never from an interrupt.
+static mut CUSTOM_TIMING: Option<ModeLine> = None;
+
+/// A `ModeLine` flag for an active-high H-Sync pulse
+pub const MODE_LINE_HSYNC_POSITIVE: u8 = 1 << 0;
+
+/// A `ModeLine` flag for an active-high V-Sync pulse
+pub const MODE_LINE_VSYNC_POSITIVE: u8 = 1 << 1;
+
+/// A `ModeLine` flag to put composite sync (H-Sync and V-Sync combined) on
+/// the H-Sync pin, for SCART and arcade monitors. During the V-Sync pulse
+/// the H-Sync pin is inverted, which is what most monitors expect. The
+/// V-Sync pin carries V-Sync as usual.
+pub const MODE_LINE_CSYNC: u8 = 1 << 2;
+
+/// The pixel clock for the 15 kHz presets. This is a quarter of the VGA
+/// pixel clock, so every clock profile can make it.
+const RGB_15KHZ_PIXEL_CLOCK_HZ: u32 = VGA_PIXEL_CLOCK_HZ / 4;
+
+/// 320x240 at 60 Hz for 15 kHz RGB monitors - like a games console.
+///
+/// Each line is 403 pixels, or 15.63 kHz - half the VGA line rate. Each
+/// frame is 262 lines, which gives 59.7 Hz.
+const MODE_LINE_320X240_15KHZ: ModeLine = ModeLine {
+	pixel_clock_hz: RGB_15KHZ_PIXEL_CLOCK_HZ,
+	h_visible: 320,
+	h_front_porch: 17,
+	h_sync: 30,
+	h_back_porch: 36,
+	v_visible: 240,
+	v_front_porch: 4,
+	v_sync: 3,
+	v_back_porch: 15,
+	flags: MODE_LINE_CSYNC,
+};
+
+/// 320x256 at 50 Hz for 15 kHz RGB monitors - like a European home
+/// computer.
+///
+/// The lines are as for `MODE_LINE_320X240_15KHZ`. Each frame is 312 lines,
+/// which gives 50.1 Hz, with the picture in the middle of the 288 lines a
+/// PAL TV shows.
+const MODE_LINE_320X256_15KHZ: ModeLine = ModeLine {
+	pixel_clock_hz: RGB_15KHZ_PIXEL_CLOCK_HZ,
+	h_visible: 320,
+	h_front_porch: 17,
+	h_sync: 30,
+	h_back_porch: 36,
+	v_visible: 256,
+	v_front_porch: 18,
+	v_sync: 3,
+	v_back_porch: 35,
+	flags: MODE_LINE_CSYNC,
+};
+
+/// The fewest PIO clocks in any portion of a scan-line. The timing state
+/// machine takes six, and we borrow five from the back porch (see
+/// `ScanlineTimingBuffer::new_v_visible`).
+const MIN_TIMING_PERIOD: u32 = 12;
+
+/// The most PIO clocks in any portion of a scan-line, as the timing state
+/// machine only has a 14-bit counter.
+const MAX_TIMING_PERIOD: u32 = (1 << 14) + 6 - 1;
 
 /// Stores which mode we are in
 static mut VIDEO_MODE: crate::common::video::Mode = crate::common::video::Mode::new(
@@ -207,6 +710,26 @@ static mut VIDEO_MODE: crate::common::video::Mode = crate::common::video::Mode::
 /// Tracks which scan-line we are currently on (for timing purposes => it goes 0..`TIMING_BUFFER.back_porch_ends_at`)
 static CURRENT_TIMING_LINE: AtomicU16 = AtomicU16::new(0);
 
+/// Set once the DMA interrupt is running, so Core 0 gets woken at least
+/// once per scan-line (see `crate::idle`).
+static IRQ_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// How many frames have been sent to the monitor. Goes up by one at the
+/// start of each vertical blanking interval.
+///
+/// Only written by the DMA interrupt, on Core 0.
+static VBLANK_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// The timing line `wait_for_raster` waits for, or `NO_RASTER_LINE`.
+static RASTER_LINE: AtomicU16 = AtomicU16::new(NO_RASTER_LINE);
+
+/// A `RASTER_LINE` that means nobody is waiting.
+const NO_RASTER_LINE: u16 = 0xFFFF;
+
+/// Set by the DMA interrupt when the beam reaches `RASTER_LINE`, and cleared
+/// by `wait_for_raster`.
+static RASTER_HIT: AtomicBool = AtomicBool::new(false);
+
 /// Tracks which scan-line we are currently on (for pixel purposes => it goes 0..NUM_LINES)
 static CURRENT_DISPLAY_LINE: AtomicU16 = AtomicU16::new(0);
 
@@ -222,13 +745,221 @@ const TIMING_DMA_CHAN: usize = 0;
 /// DMA channel for the pixel FIFO
 const PIXEL_DMA_CHAN: usize = 1;
 
+/// The framebuffer supplied by the OS, if any.
+///
+/// This is set back to null by a mode change if the new mode needs more
+/// memory than `FRAMEBUFFER_LEN`. In a text mode, null means we use our own
+/// `GLYPH_ATTR_ARRAY`. We don't have enough RAM of our own for any
+/// of the bitmap modes, so in a bitmap mode, null means we draw black until
+/// the OS supplies some memory.
+static FRAMEBUFFER: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+
+/// How many bytes `FRAMEBUFFER` has room for - what the mode needed when the
+/// OS supplied it.
+static FRAMEBUFFER_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The colours used by the indexed-colour bitmap modes.
+///
+/// The first 16 entries match the text mode colours. Next is a 6x6x6 colour
+/// cube, then a 24-step grey ramp.
+///
+/// Only touched by `RenderEngine` running on Core 1. Core 0 changes
+/// `PENDING_PALETTE` instead, and Core 1 copies it over during the vertical
+/// blanking interval, so the colours never change part-way down the screen.
+static mut VIDEO_PALETTE: [RGBColour; 256] = make_default_palette();
+
+/// The palette the OS has asked for, which will be used from the next frame.
+///
+/// Written to by Core 0, and read from by `RenderEngine` running on Core 1.
+static mut PENDING_PALETTE: [RGBColour; 256] = make_default_palette();
+
+/// Set by Core 0 when `PENDING_PALETTE` has changed.
+static PALETTE_DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// The 8x8 font the OS has asked for, or null for the built-in one.
+static PENDING_FONT8: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The 8x16 font the OS has asked for, or null for the built-in one.
+static PENDING_FONT16: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The second bank of 8x8 glyphs the OS has asked for, or null to use the
+/// first bank.
+static PENDING_FONT8_ALT: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The second bank of 8x16 glyphs the OS has asked for, or null to use the
+/// first bank.
+static PENDING_FONT16_ALT: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+
+/// A text cell with this bit set in its attribute is drawn from the second
+/// font bank, like VGA's character set select. It is the top bit of the
+/// foreground colour.
+pub const ATTR_FONT_BANK: u8 = 1 << 3;
+
+/// The most palette changes the OS can ask for in each frame.
+pub const MAX_PALETTE_CHANGES: usize = 64;
+
+/// A palette change which does nothing, for filling unused entries.
+const NO_PALETTE_CHANGE: PaletteChange = PaletteChange {
+	line: u16::MAX,
+	index: 0,
+	colour: colours::BLACK,
+};
+
+/// The palette changes the OS has asked for, which will be used from the
+/// next frame.
+///
+/// Written to by Core 0, and read from by `RenderEngine` running on Core 1.
+static mut PENDING_PALETTE_CHANGES: [PaletteChange; MAX_PALETTE_CHANGES] =
+	[NO_PALETTE_CHANGE; MAX_PALETTE_CHANGES];
+
+/// How many of `PENDING_PALETTE_CHANGES` are in use.
+static NUM_PENDING_PALETTE_CHANGES: AtomicUsize = AtomicUsize::new(0);
+
+/// The longest Core 1 spent drawing any one scan-line in the last frame, in
+/// system clock cycles.
+///
+/// Compare it with the length of a scan-line (e.g. 800 pixels of 5 clocks
+/// each at 640x480, with the standard clock profile) to see how much time
+/// is left over.
+static RENDER_TIME: AtomicU32 = AtomicU32::new(0);
+
+/// How many frames had a scan-line which took longer to draw than the
+/// scan-line lasts.
+///
+/// Only written by `RenderEngine` running on Core 1.
+static RENDER_OVERRUN_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// The fewest frames between two warnings about render overruns (about five
+/// seconds at 60 Hz), so a mode that's too slow doesn't flood the log.
+const OVERRUN_WARNING_FRAMES: u32 = 300;
+
+/// How many scan-lines Core 1 has finished drawing after the DMA had
+/// already started sending them.
+///
+/// Only written by `RenderEngine` running on Core 1.
+static CLASHED_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// How many frames in which the pixel state machine ran out of pixels.
+///
+/// Only written by the DMA interrupt.
+static PIXEL_UNDERRUN_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// How many frames in which the timing state machine ran out of timing
+/// words.
+///
+/// Only written by the DMA interrupt.
+static TIMING_UNDERRUN_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// The `FDEBUG.TXSTALL` bit for the timing state machine (SM0)
+const TIMING_SM_TXSTALL: u32 = 1 << 24;
+
+/// The `FDEBUG.TXSTALL` bit for the pixel state machine (SM1)
+const PIXEL_SM_TXSTALL: u32 = 1 << 25;
+
+/// Set by Core 0 when `PENDING_PALETTE_CHANGES` has changed.
+static PALETTE_CHANGES_DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Set by Core 0 when any of the `PENDING_FONTxxx` have changed.
+static FONT_DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Set by Core 0 whenever something on screen might have changed.
+///
+/// Used to blank the screen (and stop rendering) when nothing has changed
+/// for a while. See `set_blank_timeout`.
+static VIDEO_DIRTY: AtomicBool = AtomicBool::new(true);
+
+/// How many frames without any change before we blank the screen. Zero means
+/// never blank the screen.
+static BLANK_AFTER_FRAMES: AtomicU32 = AtomicU32::new(0);
+
+/// A `PowerState`, saying whether the monitor should be on
+static POWER_STATE: AtomicU8 = AtomicU8::new(PowerState::On as u8);
+
+/// A `ScreenSaverStyle`, saying what the screen saver shows
+static SCREEN_SAVER_STYLE: AtomicU8 = AtomicU8::new(ScreenSaverStyle::Blank as u8);
+
+/// A `ScreenSaverOverride`, saying whether the OS has taken over the
+/// screen saver
+static SCREEN_SAVER_OVERRIDE: AtomicU8 = AtomicU8::new(ScreenSaverOverride::Auto as u8);
+
+/// The text the screen saver bounces around the screen
+const SCREEN_SAVER_LOGO: &[u8] = b"Neotron";
+
+/// Turns two pixels of the screen saver logo into an `RGBPair`
+const SCREEN_SAVER_LOOKUP: [RGBPair; 4] = [
+	RGBPair::from_pixels(colours::BLACK, colours::BLACK),
+	RGBPair::from_pixels(colours::BLACK, colours::WHITE),
+	RGBPair::from_pixels(colours::WHITE, colours::BLACK),
+	RGBPair::from_pixels(colours::WHITE, colours::WHITE),
+];
+
+/// Which post-processing effects to apply to each scan-line. A combination
+/// of the `EFFECT_xxx` bits.
+static VIDEO_EFFECTS: AtomicU8 = AtomicU8::new(0);
+
+/// Darken every other scan-line, like the gaps between the lines on a CRT.
+pub const EFFECT_SCANLINES: u8 = 1 << 0;
+
+/// Blend each pixel with its neighbour to the left, like a composite video
+/// signal. Only used in the pixel-doubled bitmap modes, where there is time
+/// for it.
+pub const EFFECT_BLUR: u8 = 1 << 1;
+
+/// All the effects we know about
+pub const EFFECT_ALL: u8 = EFFECT_SCANLINES | EFFECT_BLUR;
+
+/// How many scan-lines down the text buffer the top of the screen is, in
+/// the text modes. The text wraps around from the bottom of the buffer to
+/// the top.
+static SCROLL_OFFSET: AtomicU16 = AtomicU16::new(0);
+
+/// Where the text cursor is, as `(row << 16) | column`.
+static CURSOR_POSITION: AtomicU32 = AtomicU32::new(0);
+
+/// What the text cursor looks like, as `(flags << 16) | (end << 8) | start`,
+/// where `start` and `end` are the first and last lines of the character
+/// cell to invert, and `flags` are the `CURSOR_xxx` bits.
+static CURSOR_SHAPE: AtomicU32 = AtomicU32::new(0);
+
+/// Draw the text cursor
+pub const CURSOR_VISIBLE: u8 = 1 << 0;
+
+/// Flash the text cursor on and off
+pub const CURSOR_BLINK: u8 = 1 << 1;
+
+/// All the cursor flags we know about
+pub const CURSOR_ALL: u8 = CURSOR_VISIBLE | CURSOR_BLINK;
+
+/// How many frames a blinking cursor spends on (and then off)
+const CURSOR_BLINK_FRAMES: u32 = 16;
+
+/// How many frames blinking text spends on, and then off
+const TEXT_BLINK_FRAMES: u32 = 32;
+
+/// A text cell with this bit set in its attribute blinks, unless
+/// `TEXT_BLINK` is off (in which case it means a bright background, as on
+/// VGA).
+pub const ATTR_BLINK: u8 = 1 << 7;
+
+/// Does `ATTR_BLINK` make text blink?
+static TEXT_BLINK: AtomicBool = AtomicBool::new(true);
+
+/// Roughly how many frames we draw per second (it's 70 in the 400-line modes)
+const FRAMES_PER_SECOND: u32 = 60;
+
+/// At start-up, we wait for this many scan-lines from each DMA channel...
+const STARTUP_LINES: u32 = 16;
+
+/// ...within this many milliseconds (which is about three frames)
+const STARTUP_TIMEOUT_MS: u32 = 50;
+
 /// One scan-line's worth of 12-bit pixels, used for the even scan-lines (0, 2, 4 ... NUM_LINES-2).
 ///
 /// Gets read by DMA, which pushes them into the pixel state machine's FIFO.
 ///
 /// Gets written to by `RenderEngine` running on Core 1.
 static mut PIXEL_DATA_BUFFER_EVEN: LineBuffer = LineBuffer {
-	length: (MAX_NUM_PIXEL_PAIRS_PER_LINE as u32) - 1,
+	length: 320 - 1,
 	pixels: [RGBPair::from_pixels(colours::WHITE, colours::BLACK); MAX_NUM_PIXEL_PAIRS_PER_LINE],
 };
 
@@ -238,18 +969,39 @@ static mut PIXEL_DATA_BUFFER_EVEN: LineBuffer = LineBuffer {
 ///
 /// Gets written to by `RenderEngine` running on Core 1.
 static mut PIXEL_DATA_BUFFER_ODD: LineBuffer = LineBuffer {
-	length: (MAX_NUM_PIXEL_PAIRS_PER_LINE as u32) - 1,
+	length: 320 - 1,
 	pixels: [RGBPair::from_pixels(colours::BLACK, colours::WHITE); MAX_NUM_PIXEL_PAIRS_PER_LINE],
 };
 
+/// What the DMA sends instead of a whole scan-line buffer while the screen
+/// is blanked.
+///
+/// The pixel state machine sends this one black pixel-pair, and then holds
+/// the colour pins low for the rest of the line, so the monitor sees a black
+/// line just as before. The DMA moves two words per line instead of hundreds,
+/// and the pixel state machine spends most of each line waiting. It's in RAM
+/// (although nothing writes to it) so the DMA can still read it while the
+/// flash chip is busy.
+static mut BLANK_LINE: BlankLineBuffer = BlankLineBuffer {
+	length: 0,
+	pixels: [RGBPair::from_pixels(colours::BLACK, colours::BLACK)],
+};
+
+/// How many words the DMA sends from `BLANK_LINE`.
+const BLANK_LINE_WORDS: u32 = 2;
+
+/// Set by `RenderEngine` once both scan-line buffers are black and the
+/// screen is going to stay blank, so the DMA interrupt sends `BLANK_LINE`
+/// instead of them.
+static PIXELS_IDLE: AtomicBool = AtomicBool::new(false);
+
 /// This is our text buffer.
 ///
 /// This is arranged as `NUM_TEXT_ROWS` rows of `NUM_TEXT_COLS` columns. Each
 /// item is an index into `font16::FONT_DATA` plus an 8-bit attribute.
 ///
 /// Written to by Core 0, and read from by `RenderEngine` running on Core 1.
-pub static mut GLYPH_ATTR_ARRAY: [GlyphAttr; MAX_TEXT_COLS * MAX_TEXT_ROWS] =
-	[GlyphAttr(0); MAX_TEXT_COLS * MAX_TEXT_ROWS];
+pub static mut GLYPH_ATTR_ARRAY: [GlyphAttr; TEXT_BUFFER_CELLS] = [GlyphAttr(0); TEXT_BUFFER_CELLS];
 
 /// Core 1 entry function.
 ///
@@ -267,6 +1019,18 @@ static CORE1_ENTRY_FUNCTION: [u16; 2] = [
 	0x46c0, // nop - pad this out to 32-bits long
 ];
 
+/// The attribute text is written with unless you ask for something else.
+///
+/// This is bright white on blue.
+pub const DEFAULT_ATTR: Attr = Attr(0x1F);
+
+/// The most numeric parameters we keep from an ANSI escape sequence. Any
+/// more are ignored.
+const ANSI_MAX_PARAMS: usize = 3;
+
+/// Maps ANSI colour numbers (red is 1) to VGA colour numbers (blue is 1).
+const ANSI_TO_VGA: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
 /// A set of useful constants representing common RGB colours.
 pub mod colours {
 	/// The colour white
@@ -293,6 +1057,9 @@ pub mod colours {
 ///
 /// We need to keep `pio` and `dma` to run the video. We need `resets` to set
 /// things up, so we only borrow that.
+///
+/// Each stage is checked before we move on to the next, and Core 1 is only
+/// started once both DMA channels have been seen to work.
 pub fn init(
 	pio: super::pac::PIO0,
 	dma: super::pac::DMA,
@@ -300,7 +1067,34 @@ pub fn init(
 	ppb: &mut crate::pac::PPB,
 	fifo: &mut rp_pico::hal::sio::SioFifo,
 	psm: &mut crate::pac::PSM,
-) {
+	sys_clock_hz: u32,
+) -> Result<(), InitError> {
+	let (pio_divider, cycles_per_pixel) =
+		pio_clocking(sys_clock_hz, VGA_PIXEL_CLOCK_HZ).ok_or(InitError::BadClock)?;
+	debug!(
+		"VGA PIO divider {}, {} clocks per pixel",
+		pio_divider, cycles_per_pixel
+	);
+	SYS_CLOCK_HZ.store(sys_clock_hz, Ordering::Relaxed);
+	// Note (safety): The video isn't running yet.
+	unsafe {
+		TIMING_BUFFER = TimingBuffer::make_640x480(cycles_per_pixel);
+	}
+
+	// Start from a known state. After a cold boot everything is in reset
+	// anyway, but after a restart from the debugger the DMA engine and the
+	// PIO may still be running.
+	resets
+		.reset
+		.modify(|_r, w| w.dma().set_bit().pio0().set_bit());
+	cortex_m::asm::nop();
+	resets
+		.reset
+		.modify(|_r, w| w.dma().clear_bit().pio0().clear_bit());
+	while resets.reset_done.read().dma().bit_is_clear()
+		|| resets.reset_done.read().pio0().bit_is_clear()
+	{}
+
 	// Grab PIO0 and the state machines it contains
 	let (mut pio, sm0, sm1, _sm2, _sm3) = pio.split(resets);
 
@@ -344,6 +1138,9 @@ pub fn init(
 	// we read the length from the FIFO as well, all hell breaks loose.
 	//
 	// Note autopull should be set to 32-bits, OSR is set to shift right.
+	//
+	// The delays here are for five clocks per pixel. For any other speed,
+	// `write_pixel_delays` rewrites them once the program is loaded.
 	let pixel_program = pio_proc::pio_asm!(
 		".wrap_target"
 		// Wait for timing state machine to start visible line
@@ -387,7 +1184,9 @@ pub fn init(
 	// https://gregchadwick.co.uk/blog/playing-with-the-pico-pt5/ who had a
 	// very similar idea to me, but wrote it up far better than I ever could.
 
-	let timing_installed = pio.install(&timing_program.program).unwrap();
+	let timing_installed = pio
+		.install(&timing_program.program)
+		.map_err(|_e| InitError::PioProgram)?;
 	let (mut timing_sm, _, timing_fifo) =
 		rp_pico::hal::pio::PIOBuilder::from_program(timing_installed)
 			.buffers(rp_pico::hal::pio::Buffers::OnlyTx)
@@ -395,6 +1194,7 @@ pub fn init(
 			.autopull(true)
 			.out_shift_direction(rp_pico::hal::pio::ShiftDirection::Right)
 			.pull_threshold(32)
+			.clock_divisor(f32::from(pio_divider))
 			.build(sm0);
 	timing_sm.set_pindirs([
 		(0, rp_pico::hal::pio::PinDir::Output),
@@ -403,12 +1203,17 @@ pub fn init(
 
 	// Important notes!
 	//
-	// You must not set a clock_divider (other than 1.0) on the pixel state
+	// You must not set a fractional clock_divider on the pixel state
 	// machine. You might want the pixels to be twice as wide (or mode), but
-	// enabling a clock divider adds a lot of jitter (i.e. the start each
-	// each line differs by some number of 126 MHz clock cycles).
-
-	let pixels_installed = pio.install(&pixel_program.program).unwrap();
+	// a fractional clock divider adds a lot of jitter (i.e. the start each
+	// each line differs by some number of system clock cycles). A whole
+	// number divider is fine, as long as both state machines use the same
+	// one, and their dividers are started together.
+
+	let pixels_installed = pio
+		.install(&pixel_program.program)
+		.map_err(|_e| InitError::PioProgram)?;
+	PIXEL_PROGRAM_OFFSET.store(pixels_installed.offset(), Ordering::Relaxed);
 	let (mut pixel_sm, _, pixel_fifo) =
 		rp_pico::hal::pio::PIOBuilder::from_program(pixels_installed)
 			.buffers(rp_pico::hal::pio::Buffers::OnlyTx)
@@ -416,6 +1221,7 @@ pub fn init(
 			.autopull(true)
 			.out_shift_direction(rp_pico::hal::pio::ShiftDirection::Right)
 			.pull_threshold(32) // We read all 32-bits in each FIFO word
+			.clock_divisor(f32::from(pio_divider))
 			.build(sm1);
 	pixel_sm.set_pindirs((2..=13).map(|x| (x, rp_pico::hal::pio::PinDir::Output)));
 
@@ -469,7 +1275,7 @@ pub fn init(
 		.write(|w| unsafe { w.bits(pixel_fifo.fifo_address() as usize as u32) });
 	dma.ch[PIXEL_DMA_CHAN]
 		.ch_trans_count
-		.write(|w| unsafe { w.bits(PIXEL_DATA_BUFFER_EVEN.pixels.len() as u32 + 1) });
+		.write(|w| unsafe { w.bits(NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed) as u32 + 1) });
 	dma.inte0.write(|w| unsafe {
 		w.inte0()
 			.bits((1 << PIXEL_DMA_CHAN) | (1 << TIMING_DMA_CHAN))
@@ -490,16 +1296,32 @@ pub fn init(
 		crate::pac::NVIC::unpend(crate::pac::Interrupt::DMA_IRQ_0);
 		crate::pac::NVIC::unmask(crate::pac::Interrupt::DMA_IRQ_0);
 	}
+	IRQ_RUNNING.store(true, Ordering::Relaxed);
 
 	debug!("IRQs enabled");
 
 	debug!("DMA set-up complete");
 
+	// Put both clock dividers in step, so the pixels always start on the
+	// same PIO clock after the timing IRQ.
+	// Note (safety): We own PIO0, and these are our two state machines.
+	let pio0 = unsafe { &*crate::pac::PIO0::ptr() };
+	write_pixel_delays(pio0, cycles_per_pixel);
+	pio0.ctrl
+		.modify(|_r, w| unsafe { w.clkdiv_restart().bits(0b0011) });
+
 	timing_sm.start();
 	pixel_sm.start();
 
 	debug!("State Machines running");
 
+	// Check each DMA channel is completing transfers before we rely on it.
+	// The pixel DMA can't run unless the timing DMA is running.
+	wait_for_lines(&CURRENT_TIMING_LINE, InitError::NoTimingDma)?;
+	wait_for_lines(&CURRENT_DISPLAY_LINE, InitError::NoPixelDma)?;
+
+	debug!("DMA running");
+
 	// We drop our state-machine and PIO objects here - this means the video
 	// cannot be reconfigured at a later time, but they do keep on running
 	// as-is.
@@ -524,6 +1346,88 @@ pub fn init(
 	multicore_launch_core1_with_stack(core1_main, core1_stack, ppb, fifo, psm);
 
 	debug!("Core 1 running");
+
+	Ok(())
+}
+
+/// Rewrite the two `out pins, 16` instructions in the pixel program, so each
+/// pixel takes the given number of PIO clocks.
+///
+/// The pixel state machine must be stopped.
+fn write_pixel_delays(pio0: &crate::pac::pio0::RegisterBlock, cycles_per_pixel: u32) {
+	// This is `out pins, 16` with no delay. Like `make_timing`, we cheat
+	// rather than encode it at run-time.
+	const OUT_PINS_16: u32 = 0x6010;
+	// The loop starts two instructions into the program
+	let first = usize::from(PIXEL_PROGRAM_OFFSET.load(Ordering::Relaxed)) + 2;
+	// The first pixel takes the whole time, the second loses one clock to
+	// the jump.
+	let first_delay = cycles_per_pixel - 1;
+	let second_delay = cycles_per_pixel - 2;
+	pio0.instr_mem[first].write(|w| unsafe { w.bits(OUT_PINS_16 | (first_delay << 8)) });
+	pio0.instr_mem[first + 1].write(|w| unsafe { w.bits(OUT_PINS_16 | (second_delay << 8)) });
+}
+
+/// Change the PIO clock divider and the pixel program's speed, for a mode
+/// with a different pixel clock.
+///
+/// Both state machines stop while we do this, so the monitor will have to
+/// find the new timings anyway. We start them with their clock dividers in
+/// step, as `init` does.
+fn set_pio_clocking(pio_divider: u16, cycles_per_pixel: u32) {
+	debug!(
+		"VGA PIO divider {}, {} clocks per pixel",
+		pio_divider, cycles_per_pixel
+	);
+	// Note (safety): We own PIO0, and only touch our two state machines.
+	let pio0 = unsafe { &*crate::pac::PIO0::ptr() };
+	pio0.ctrl
+		.modify(|r, w| unsafe { w.sm_enable().bits(r.sm_enable().bits() & !0b0011) });
+	for sm in pio0.sm.iter().take(2) {
+		sm.sm_clkdiv
+			.write(|w| unsafe { w.int().bits(pio_divider).frac().bits(0) });
+	}
+	write_pixel_delays(pio0, cycles_per_pixel);
+	pio0.ctrl.modify(|r, w| unsafe {
+		w.sm_enable()
+			.bits(r.sm_enable().bits() | 0b0011)
+			.clkdiv_restart()
+			.bits(0b0011)
+	});
+}
+
+/// Change how many pixel pairs we send on each scan-line.
+///
+/// # Safety
+///
+/// Only call this with interrupts disabled, as it shares the DMA controller
+/// with the DMA interrupt.
+unsafe fn set_pixel_pairs(pixel_pairs: u16) {
+	NUM_PIXEL_PAIRS_PER_LINE.store(usize::from(pixel_pairs), Ordering::SeqCst);
+	PIXEL_DATA_BUFFER_EVEN.length = u32::from(pixel_pairs) - 1;
+	PIXEL_DATA_BUFFER_ODD.length = u32::from(pixel_pairs) - 1;
+	// The DMA interrupt sets the transfer count to match, when it starts
+	// the next line's buffer.
+}
+
+/// Wait for the DMA interrupt to move the given scan-line counter on
+/// `STARTUP_LINES` times, or give up after `STARTUP_TIMEOUT_MS`.
+fn wait_for_lines(counter: &AtomicU16, error: InitError) -> Result<(), InitError> {
+	let start = crate::ticks_ms();
+	let mut last = counter.load(Ordering::Relaxed);
+	let mut changes = 0;
+	while changes < STARTUP_LINES {
+		let line = counter.load(Ordering::Relaxed);
+		if line != last {
+			last = line;
+			changes += 1;
+		}
+		if crate::ticks_ms().wrapping_sub(start) > STARTUP_TIMEOUT_MS {
+			defmt::error!("Video start-up failed: {}", error);
+			return Err(error);
+		}
+	}
+	Ok(())
 }
 
 /// The bootrom code will call this function on core1 to perform any set-up, before the
@@ -550,6 +1454,13 @@ fn multicore_launch_core1_with_stack(
 
 	debug!("Setting up stack...");
 
+	// Fill the bottom of the stack with a pattern, so `check_core1_stack`
+	// can spot it being overwritten.
+	for word in stack.iter_mut().take(CORE1_STACK_CANARY_WORDS) {
+		*word = CORE1_STACK_CANARY;
+	}
+	CORE1_STACK_BOTTOM.store(stack.as_mut_ptr(), Ordering::SeqCst);
+
 	// Gets popped into `r0` by CORE1_ENTRY_FUNCTION. This is the `main`
 	// function we want to run. It appears in the call to `core1_wrapper` as
 	// the first argument.
@@ -628,6 +1539,32 @@ fn multicore_launch_core1_with_stack(
 	debug!("Core 1 started!!");
 }
 
+/// Check Core 1 hasn't overflowed its stack, and panic if it has.
+///
+/// The DMA interrupt calls this once a frame. Core 1 can't check for
+/// itself, as it may have trampled on something it needs, and we'd rather
+/// have a crash report than a garbled screen.
+fn check_core1_stack() {
+	let bottom = CORE1_STACK_BOTTOM.load(Ordering::Relaxed);
+	if bottom.is_null() {
+		return;
+	}
+	for idx in 0..CORE1_STACK_CANARY_WORDS {
+		// Note (safety): The canary words are at the bottom of the stack we
+		// gave to Core 1. It's still using the stack, so we don't take a
+		// reference.
+		let word = unsafe { core::ptr::read_volatile(bottom.add(idx)) };
+		if word != CORE1_STACK_CANARY {
+			panic!("Core 1 stack overflow");
+		}
+	}
+}
+
+/// Has Core 1 started drawing the screen?
+pub fn is_running() -> bool {
+	CORE1_START_FLAG.load(Ordering::Relaxed)
+}
+
 /// Gets the current video mode
 pub fn get_video_mode() -> crate::common::video::Mode {
 	unsafe { VIDEO_MODE }
@@ -635,77 +1572,932 @@ pub fn get_video_mode() -> crate::common::video::Mode {
 
 /// Sets the current video mode
 pub fn set_video_mode(mode: crate::common::video::Mode) -> bool {
+	let mut timing_buffer = match timing_for_mode(mode) {
+		Some(timing_buffer) => timing_buffer,
+		None => return false,
+	};
+	timing_buffer.apply_power_state(get_power());
 	cortex_m::interrupt::disable();
-	let mode_ok = match (
-		mode.timing(),
-		mode.format(),
-		mode.is_horiz_2x(),
-		mode.is_vert_2x(),
-	) {
-		(
-			crate::common::video::Timing::T640x480,
-			crate::common::video::Format::Text8x16 | crate::common::video::Format::Text8x8,
-			false,
-			false,
-		) => {
-			unsafe {
-				VIDEO_MODE = mode;
-				TIMING_BUFFER = TimingBuffer::make_640x480();
-			}
-			true
+	// Note (safety): Interrupts are off, so the DMA interrupt can't be
+	// looking at the timing buffer, or the DMA controller, while we change
+	// them.
+	unsafe {
+		if timing_buffer.pixel_clock_hz != TIMING_BUFFER.pixel_clock_hz {
+			set_pio_clocking(
+				timing_buffer.pio_divider(SYS_CLOCK_HZ.load(Ordering::Relaxed)),
+				timing_buffer.cycles_per_pixel,
+			);
 		}
-		(
-			crate::common::video::Timing::T640x400,
-			crate::common::video::Format::Text8x16 | crate::common::video::Format::Text8x8,
-			false,
-			false,
-		) => {
-			unsafe {
-				VIDEO_MODE = mode;
-				TIMING_BUFFER = TimingBuffer::make_640x400();
-			}
-			true
+		let pixel_pairs = timing_buffer.pixel_pairs;
+		if usize::from(pixel_pairs) != NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::SeqCst) {
+			set_pixel_pairs(pixel_pairs);
 		}
-		_ => false,
-	};
-	if mode_ok {
-		NUM_TEXT_COLS.store(mode.text_width().unwrap_or(0) as usize, Ordering::SeqCst);
-		NUM_TEXT_ROWS.store(mode.text_height().unwrap_or(0) as usize, Ordering::SeqCst);
+		VIDEO_MODE = mode;
+		TIMING_BUFFER = timing_buffer;
+	}
+	// Keep the OS's framebuffer if the new mode fits in it
+	let fb = FRAMEBUFFER.load(Ordering::SeqCst) as usize;
+	let misaligned = !mode_needs_vram(mode) && (fb % core::mem::align_of::<GlyphAttr>()) != 0;
+	if framebuffer_size(mode) > FRAMEBUFFER_LEN.load(Ordering::SeqCst) || misaligned {
+		FRAMEBUFFER.store(core::ptr::null_mut(), Ordering::SeqCst);
+	}
+	// The consoles move around in the buffer when the mode changes
+	ACTIVE_CONSOLE.store(0, Ordering::SeqCst);
+	PENDING_CONSOLE.store(NO_FLIP, Ordering::SeqCst);
+	SCROLL_OFFSET.store(0, Ordering::SeqCst);
+	if RASTER_LINE.load(Ordering::SeqCst) >= get_num_timing_lines() {
+		RASTER_LINE.store(NO_RASTER_LINE, Ordering::SeqCst);
 	}
+	update_line_repeat();
+	mark_dirty();
+	let (cols, rows) = text_dimensions(mode).unwrap_or((0, 0));
+	NUM_TEXT_COLS.store(cols, Ordering::SeqCst);
+	NUM_TEXT_ROWS.store(rows, Ordering::SeqCst);
 	unsafe {
 		cortex_m::interrupt::enable();
 	}
-	mode_ok
+	true
 }
 
-/// Get the current scan line.
-pub fn get_scan_line() -> u16 {
-	CURRENT_DISPLAY_LINE.load(Ordering::Relaxed)
+/// Use the given timings instead of the standard ones, for every mode, or
+/// go back to the standard timings if `mode_line` is `None`.
+///
+/// The current mode is set up again with the new timings, as if the OS had
+/// changed mode. If the current mode doesn't fit the new timings, nothing
+/// changes.
+pub fn set_custom_timing(mode_line: Option<ModeLine>) -> Result<(), TimingError> {
+	if let Some(mode_line) = mode_line.as_ref() {
+		TimingBuffer::from_mode_line(mode_line, SYS_CLOCK_HZ.load(Ordering::Relaxed))?;
+	}
+	// Note (safety): Only accessed from Core 0, and never from an interrupt.
+	let old_mode_line = unsafe { core::mem::replace(&mut CUSTOM_TIMING, mode_line) };
+	if set_video_mode(get_video_mode()) {
+		debug!("Custom timing {}", mode_line);
+		Ok(())
+	} else {
+		// Note (safety): As above.
+		unsafe {
+			CUSTOM_TIMING = old_mode_line;
+		}
+		Err(TimingError::BadMode)
+	}
 }
 
-/// Get how many visible lines there currently are
-pub fn get_num_scan_lines() -> u16 {
-	let mode = get_video_mode();
-	mode.vertical_lines()
+/// Can we display the given video mode?
+pub fn is_valid_mode(mode: crate::common::video::Mode) -> bool {
+	timing_for_mode(mode).is_some()
 }
 
-/// This function runs the video processing loop on Core 1.
+/// Does the OS need to supply a framebuffer for this mode?
 ///
-/// It keeps the odd/even scan-line buffers updated, as per the contents of
-/// the text buffer.
+/// We only have enough RAM of our own for the text modes.
+pub fn mode_needs_vram(mode: crate::common::video::Mode) -> bool {
+	!matches!(
+		mode.format(),
+		crate::common::video::Format::Text8x16 | crate::common::video::Format::Text8x8
+	)
+}
+
+/// Tell us where the OS has put the framebuffer for the current mode.
+///
+/// The whole framebuffer (`framebuffer_size` bytes for the current mode) must
+/// fit within `ram`. Passing null goes back to the BIOS's own text buffer
+/// (in a text mode) or a blank screen (in a bitmap mode).
+///
+/// The framebuffer is kept when the mode changes, as long as the new mode
+/// needs no more than `framebuffer_size` bytes for this mode (and, for a text
+/// mode, it's suitably aligned). Otherwise the mode change forgets it.
 ///
 /// # Safety
 ///
-/// Only run this function on Core 1.
-unsafe extern "C" fn core1_main() -> u32 {
-	CORE1_START_FLAG.store(true, Ordering::Relaxed);
+/// The memory must stay valid until it is replaced or forgotten, and nothing
+/// else may use it for anything else.
+pub unsafe fn set_framebuffer(
+	buffer: *mut u8,
+	ram: core::ops::Range<usize>,
+) -> Result<(), FramebufferError> {
+	if !buffer.is_null() {
+		let mode = get_video_mode();
+		let start = buffer as usize;
+		let end = start
+			.checked_add(framebuffer_size(mode))
+			.ok_or(FramebufferError::OutsideRam)?;
+		if start < ram.start || end > ram.end {
+			return Err(FramebufferError::OutsideRam);
+		}
+		if !mode_needs_vram(mode) && (start % core::mem::align_of::<GlyphAttr>()) != 0 {
+			return Err(FramebufferError::Misaligned);
+		}
+	}
+	let len = if buffer.is_null() {
+		0
+	} else {
+		framebuffer_size(get_video_mode())
+	};
+	FRAMEBUFFER.store(buffer, Ordering::SeqCst);
+	FRAMEBUFFER_LEN.store(len, Ordering::SeqCst);
+	mark_dirty();
+	Ok(())
+}
 
-	let mut video = RenderEngine::new();
+/// Get the framebuffer the current mode is using.
+///
+/// This is null if we are in a bitmap mode and the OS hasn't given us a
+/// framebuffer.
+pub fn get_framebuffer() -> *mut u8 {
+	let fb = FRAMEBUFFER.load(Ordering::Relaxed);
+	if fb.is_null() && !mode_needs_vram(get_video_mode()) {
+		// Note (safety): We're only taking the address.
+		unsafe { GLYPH_ATTR_ARRAY.as_mut_ptr() as *mut u8 }
+	} else {
+		fb
+	}
+}
+
+/// How many virtual consoles fit in our text buffer, in the current mode.
+///
+/// There is only one if the OS has supplied its own framebuffer, or we are
+/// in a bitmap mode.
+pub fn num_consoles() -> u8 {
+	if !FRAMEBUFFER.load(Ordering::Relaxed).is_null() || mode_needs_vram(get_video_mode()) {
+		return 1;
+	}
+	let cells = NUM_TEXT_COLS.load(Ordering::Relaxed) * NUM_TEXT_ROWS.load(Ordering::Relaxed);
+	if cells == 0 {
+		return 1;
+	}
+	(TEXT_BUFFER_CELLS / cells).clamp(1, usize::from(MAX_CONSOLES)) as u8
+}
+
+/// Get the text buffer for the given virtual console.
+///
+/// Console 0 is the one `get_framebuffer` returns. Returns `None` if there is
+/// no such console in the current mode.
+pub fn get_console_buffer(index: u8) -> Option<*mut GlyphAttr> {
+	if index >= num_consoles() {
+		return None;
+	}
+	let cells = NUM_TEXT_COLS.load(Ordering::Relaxed) * NUM_TEXT_ROWS.load(Ordering::Relaxed);
+	if index == 0 {
+		Some(get_framebuffer() as *mut GlyphAttr)
+	} else {
+		// Note (safety): `num_consoles` checked this console fits in the
+		// buffer, and we're only taking the address.
+		Some(unsafe {
+			GLYPH_ATTR_ARRAY
+				.as_mut_ptr()
+				.add(usize::from(index) * cells)
+		})
+	}
+}
+
+/// Write a line of plain ASCII over the bottom row of our text buffer,
+/// padded out with spaces.
+///
+/// This is for the boot-time debug row (see `logger`). Does nothing unless
+/// the screen is showing our own text buffer.
+pub fn write_status_row(text: &[u8], attr: Attr) {
+	if !FRAMEBUFFER.load(Ordering::Relaxed).is_null() || mode_needs_vram(get_video_mode()) {
+		return;
+	}
+	let num_rows = NUM_TEXT_ROWS.load(Ordering::Relaxed);
+	let num_cols = NUM_TEXT_COLS.load(Ordering::Relaxed);
+	if num_rows == 0 {
+		return;
+	}
+	let row_start = (num_rows - 1) * num_cols;
+	for col in 0..num_cols {
+		let glyph = Glyph(text.get(col).copied().unwrap_or(b' '));
+		// Note (safety): The mode's rows and columns always fit in the
+		// buffer. Core 1 only reads it.
+		unsafe {
+			GLYPH_ATTR_ARRAY
+				.as_mut_ptr()
+				.add(row_start + col)
+				.write_volatile(GlyphAttr::new(glyph, attr));
+		}
+	}
+	mark_dirty();
+}
+
+/// Choose which virtual console is on screen.
+///
+/// Returns false if there is no such console in the current mode.
+pub fn set_active_console(index: u8) -> bool {
+	if index >= num_consoles() {
+		return false;
+	}
+	debug!("Showing console {}", index);
+	ACTIVE_CONSOLE.store(index, Ordering::Relaxed);
+	mark_dirty();
+	true
+}
+
+/// Put the given virtual console on screen at the start of the next frame.
+///
+/// The consoles double as pages of text, so the OS can draw the whole of one
+/// while showing another, then flip between them without tearing. Returns
+/// false if there is no such console in the current mode.
+pub fn flip_console(index: u8) -> bool {
+	if index >= num_consoles() {
+		return false;
+	}
+	PENDING_CONSOLE.store(index, Ordering::Relaxed);
+	mark_dirty();
+	true
+}
+
+/// Is a flip from `flip_console` still waiting for the next frame?
+pub fn flip_pending() -> bool {
+	PENDING_CONSOLE.load(Ordering::Relaxed) != NO_FLIP
+}
+
+/// Find out which virtual console is on screen.
+pub fn active_console() -> u8 {
+	ACTIVE_CONSOLE.load(Ordering::Relaxed)
+}
+
+/// How many bytes of framebuffer the given mode needs.
+pub fn framebuffer_size(mode: crate::common::video::Mode) -> usize {
+	let (width, height) = bitmap_dimensions(mode);
+	match mode.format() {
+		crate::common::video::Format::Chunky8 => width * height,
+		crate::common::video::Format::Chunky4 => (width * height) / 2,
+		crate::common::video::Format::Chunky1 => (width * height) / 8,
+		_ => {
+			let (cols, rows) = text_dimensions(mode).unwrap_or((0, 0));
+			cols * rows * core::mem::size_of::<GlyphAttr>()
+		}
+	}
+}
+
+/// Work out which timing buffer the given mode needs, or `None` if we can't
+/// display that mode.
+fn timing_for_mode(mode: crate::common::video::Mode) -> Option<TimingBuffer> {
+	let format_ok = match (mode.format(), mode.is_horiz_2x(), mode.is_vert_2x()) {
+		// Any text mode can have its pixels doubled either way
+		(crate::common::video::Format::Text8x16 | crate::common::video::Format::Text8x8, ..) => {
+			TEXT_MODES.iter().any(|m| {
+				m.timing() as u8 == mode.timing() as u8 && m.format() as u8 == mode.format() as u8
+			})
+		}
+		// We only have time to render 8bpp if every pixel is doubled, or
+		// if we only draw half the lines on each frame
+		(crate::common::video::Format::Chunky8 | crate::common::video::Format::Chunky4, ..) => {
+			!mode_needs_interlace(mode) || INTERLACE.load(Ordering::Relaxed)
+		}
+		(crate::common::video::Format::Chunky1, ..) => true,
+		_ => false,
+	};
+	if !format_ok {
+		return None;
+	}
+	// Note (safety): Only accessed from Core 0, and never from an interrupt.
+	if let Some(mode_line) = unsafe { CUSTOM_TIMING } {
+		return custom_timing_for_mode(mode, &mode_line);
+	}
+	// The bitmap modes are only offered 640 pixels wide
+	let is_svga = matches!(mode.timing(), crate::common::video::Timing::T800x600);
+	if is_svga && mode_needs_vram(mode) {
+		return None;
+	}
+	let (pixel_clock_hz, make): (u32, fn(u32) -> TimingBuffer) = match mode.timing() {
+		crate::common::video::Timing::T640x480 => (VGA_PIXEL_CLOCK_HZ, TimingBuffer::make_640x480),
+		crate::common::video::Timing::T640x400 => (VGA_PIXEL_CLOCK_HZ, TimingBuffer::make_640x400),
+		crate::common::video::Timing::T800x600 => (SVGA_PIXEL_CLOCK_HZ, TimingBuffer::make_800x600),
+	};
+	let (_divider, cycles_per_pixel) =
+		pio_clocking(SYS_CLOCK_HZ.load(Ordering::Relaxed), pixel_clock_hz)?;
+	Some(make(cycles_per_pixel))
+}
+
+/// Work out the timing buffer for the given mode, using the OS's timings
+/// rather than the mode's own, or `None` if the mode doesn't fit them.
+///
+/// The text modes have to fit in our text buffer. We've only checked that
+/// Core 1 keeps up with the bitmap modes at up to 640 pixels wide and the
+/// VGA pixel clock, so we don't offer them any faster.
+fn custom_timing_for_mode(
+	mode: crate::common::video::Mode,
+	mode_line: &ModeLine,
+) -> Option<TimingBuffer> {
+	let fits = if mode_needs_vram(mode) {
+		mode_line.h_visible <= 640 && mode_line.pixel_clock_hz <= VGA_PIXEL_CLOCK_HZ
+	} else {
+		let (cols, rows) = text_dimensions(mode).unwrap_or((0, 0));
+		rows != 0
+			&& cols <= MAX_TEXT_COLS
+			&& rows <= MAX_TEXT_ROWS
+			&& (cols * rows) <= TEXT_BUFFER_CELLS
+	};
+	if !fits {
+		return None;
+	}
+	TimingBuffer::from_mode_line(mode_line, SYS_CLOCK_HZ.load(Ordering::Relaxed)).ok()
+}
+
+/// Work out the PIO clock divider, and how many PIO clocks each pixel
+/// takes, for the given system clock and pixel clock.
+///
+/// Only whole-number dividers will do (see `init`). We'd rather have fewer
+/// clocks per pixel, and a bigger divider, as that leaves the PIO clock
+/// furthest from the system clock.
+fn pio_clocking(sys_clock_hz: u32, pixel_clock_hz: u32) -> Option<(u16, u32)> {
+	(MIN_CYCLES_PER_PIXEL..=MAX_CYCLES_PER_PIXEL).find_map(|cycles_per_pixel| {
+		let pio_clock_hz = pixel_clock_hz * cycles_per_pixel;
+		if sys_clock_hz >= pio_clock_hz && (sys_clock_hz % pio_clock_hz) == 0 {
+			Some(((sys_clock_hz / pio_clock_hz) as u16, cycles_per_pixel))
+		} else {
+			None
+		}
+	})
+}
+
+/// Get the (columns, rows) of the given text mode, or `None` if it isn't a
+/// text mode.
+///
+/// We work this out from the lines we actually draw, so the 400-line modes
+/// get 25 or 50 rows rather than the 30 or 60 of a 480-line mode.
+fn text_dimensions(mode: crate::common::video::Mode) -> Option<(usize, usize)> {
+	let font_height = match mode.format() {
+		crate::common::video::Format::Text8x16 => 16,
+		crate::common::video::Format::Text8x8 => 8,
+		_ => return None,
+	};
+	let (width, height) = bitmap_dimensions(mode);
+	Some((width / 8, height / font_height))
+}
+
+/// Get the (width, height) in pixels of the given mode, taking pixel
+/// doubling into account.
+fn bitmap_dimensions(mode: crate::common::video::Mode) -> (usize, usize) {
+	// Note (safety): Only accessed from Core 0, and never from an interrupt.
+	let (pixels, lines) = match unsafe { CUSTOM_TIMING } {
+		Some(mode_line) => (
+			usize::from(mode_line.h_visible),
+			usize::from(mode_line.v_visible),
+		),
+		None => match mode.timing() {
+			crate::common::video::Timing::T640x480 => (640, 480),
+			crate::common::video::Timing::T640x400 => (640, 400),
+			crate::common::video::Timing::T800x600 => (800, 600),
+		},
+	};
+	let width = if mode.is_horiz_2x() {
+		pixels / 2
+	} else {
+		pixels
+	};
+	let height = if mode.is_vert_2x() { lines / 2 } else { lines };
+	(width, height)
+}
+
+/// Draw an `Icon` into the framebuffer, with its top-left corner at the
+/// given pixel position.
+///
+/// Pixels with the value `transparent` are skipped, leaving whatever was
+/// underneath. Any part of the icon which falls off the edge of the screen
+/// is clipped.
+///
+/// In 256-colour modes each run of visible pixels is copied by the DMA
+/// engine (see `memdma`). The packed 16-colour and monochrome formats share
+/// each byte between pixels, so the CPU draws those one pixel at a time.
+pub fn blit_icon(x: u16, y: u16, icon: &Icon, transparent: u8) -> Result<(), BlitError> {
+	let mode = get_video_mode();
+	let (width, height) = bitmap_dimensions(mode);
+	// The packed formats have their own way of writing one pixel. The pixel
+	// offset is `(y * width) + x`.
+	let put_pixel: Option<unsafe fn(*mut u8, usize, u8)> = match mode.format() {
+		crate::common::video::Format::Chunky8 => None,
+		crate::common::video::Format::Chunky4 => Some(put_pixel_chunky4),
+		crate::common::video::Format::Chunky1 => Some(put_pixel_chunky1),
+		_ => return Err(BlitError::NotBitmapMode),
+	};
+	let fb = FRAMEBUFFER.load(Ordering::Relaxed);
+	if fb.is_null() {
+		return Err(BlitError::NoFramebuffer);
+	}
+	let x = usize::from(x);
+	let y = usize::from(y);
+	let visible_cols = width.saturating_sub(x).min(ICON_SIZE);
+	let visible_rows = height.saturating_sub(y);
+	for (row, pixels) in icon
+		.pixels
+		.chunks_exact(ICON_SIZE)
+		.take(visible_rows)
+		.enumerate()
+	{
+		let pixels = &pixels[0..visible_cols];
+		let offset = ((y + row) * width) + x;
+		match put_pixel {
+			Some(put_pixel) => {
+				for (col, &pixel) in pixels.iter().enumerate() {
+					if pixel != transparent {
+						// Note (safety): We checked x and y against the size
+						// of the mode, and the OS promised us the framebuffer
+						// was big enough for the mode.
+						unsafe { put_pixel(fb, offset + col, pixel) };
+					}
+				}
+			}
+			None => {
+				for run in pixels.split(|&pixel| pixel == transparent) {
+					if run.is_empty() {
+						continue;
+					}
+					let col = run.as_ptr() as usize - pixels.as_ptr() as usize;
+					// Note (safety): As above for the framebuffer. The DMA
+					// engine reads from `icon`, which we hold on to until
+					// the last copy has finished.
+					unsafe { memdma::start_copy(fb.add(offset + col), run.as_ptr(), run.len()) };
+				}
+			}
+		}
+	}
+	memdma::wait();
+	mark_dirty();
+	Ok(())
+}
+
+/// Write one pixel into a 16-colour framebuffer. Only the bottom four bits of
+/// `pixel` are used. The left-most pixel is the top nibble of each byte.
+///
+/// # Safety
+///
+/// `offset` must be within the framebuffer.
+unsafe fn put_pixel_chunky4(fb: *mut u8, offset: usize, pixel: u8) {
+	let byte = fb.add(offset / 2);
+	let old = byte.read_volatile();
+	let new = if (offset % 2) == 0 {
+		(old & 0x0F) | (pixel << 4)
+	} else {
+		(old & 0xF0) | (pixel & 0x0F)
+	};
+	byte.write_volatile(new);
+}
+
+/// Write one pixel into a monochrome framebuffer. Only the bottom bit of
+/// `pixel` is used. The left-most pixel is the top bit of each byte.
+///
+/// # Safety
+///
+/// `offset` must be within the framebuffer.
+unsafe fn put_pixel_chunky1(fb: *mut u8, offset: usize, pixel: u8) {
+	let byte = fb.add(offset / 8);
+	let mask = 0x80 >> (offset % 8);
+	let old = byte.read_volatile();
+	let new = if (pixel & 1) != 0 {
+		old | mask
+	} else {
+		old & !mask
+	};
+	byte.write_volatile(new);
+}
+
+/// Tell the video system something on screen may have changed.
+///
+/// This un-blanks the screen if it has been blanked, and restarts the blank
+/// timeout.
+pub fn mark_dirty() {
+	VIDEO_DIRTY.store(true, Ordering::Relaxed);
+	if SCREEN_SAVER_OVERRIDE.load(Ordering::Relaxed) == ScreenSaverOverride::Activate as u8 {
+		SCREEN_SAVER_OVERRIDE.store(ScreenSaverOverride::Auto as u8, Ordering::Relaxed);
+	}
+}
+
+/// Choose what the screen saver shows.
+pub fn set_screen_saver_style(style: ScreenSaverStyle) {
+	SCREEN_SAVER_STYLE.store(style as u8, Ordering::Relaxed);
+}
+
+/// Let the OS stop the screen saver from starting, or start it right away.
+pub fn set_screen_saver_override(control: ScreenSaverOverride) {
+	SCREEN_SAVER_OVERRIDE.store(control as u8, Ordering::Relaxed);
+}
+
+/// Blank the screen after this many seconds without a call to `mark_dirty`.
+/// Zero means never blank the screen.
+///
+/// While the screen is blank, Core 1 stops rendering and sleeps, and the DMA
+/// and pixel state machine send only one black pixel-pair per scan-line
+/// (see `BLANK_LINE`), which saves power on battery-powered builds. The sync
+/// signals carry on, so the monitor stays in sync.
+pub fn set_blank_timeout(seconds: u16) {
+	BLANK_AFTER_FRAMES.store(u32::from(seconds) * FRAMES_PER_SECOND, Ordering::Relaxed);
+	mark_dirty();
+}
+
+/// Change the monitor's power state.
+///
+/// We stop the sync signals by changing the timing buffer, so the timing
+/// state machine keeps running (and the pixel state machine stays in step
+/// with it), it just stops moving the sync pins.
+pub fn set_power(state: PowerState) {
+	// Note (safety): Only written by `set_video_mode` on Core 0.
+	let mode = unsafe { VIDEO_MODE };
+	let mut timing_buffer = match timing_for_mode(mode) {
+		Some(timing_buffer) => timing_buffer,
+		None => return,
+	};
+	timing_buffer.apply_power_state(state);
+	cortex_m::interrupt::free(|_cs| {
+		POWER_STATE.store(state as u8, Ordering::Relaxed);
+		// Note (safety): Interrupts are off, so the DMA interrupt can't be
+		// looking at the timing buffer while we change it. The timings
+		// themselves are the same, so the pixel clock and line length don't
+		// change.
+		unsafe {
+			TIMING_BUFFER = timing_buffer;
+		}
+	});
+	mark_dirty();
+}
+
+/// Get the monitor's power state.
+pub fn get_power() -> PowerState {
+	match POWER_STATE.load(Ordering::Relaxed) {
+		1 => PowerState::Blank,
+		2 => PowerState::Standby,
+		3 => PowerState::Suspend,
+		4 => PowerState::Off,
+		_ => PowerState::On,
+	}
+}
+
+/// Choose which post-processing effects to apply (a combination of the
+/// `EFFECT_xxx` bits). Unknown bits are ignored.
+pub fn set_effects(effects: u8) {
+	VIDEO_EFFECTS.store(effects & EFFECT_ALL, Ordering::Relaxed);
+	update_line_repeat();
+	mark_dirty();
+}
+
+/// Work out how often the DMA interrupt should repeat each scan-line, from
+/// the video mode, the effects and the interlace setting.
+fn update_line_repeat() {
+	let scanlines = (VIDEO_EFFECTS.load(Ordering::Relaxed) & EFFECT_SCANLINES) != 0;
+	// Note (safety): Only written by `set_video_mode` on Core 0.
+	let vert_2x = unsafe { VIDEO_MODE.is_vert_2x() };
+	let repeat = match (scanlines, vert_2x, INTERLACE.load(Ordering::Relaxed)) {
+		(true, ..) => 1,
+		(false, false, false) => 1,
+		(false, true, false) | (false, false, true) => 2,
+		(false, true, true) => 4,
+	};
+	LINE_REPEAT.store(repeat, Ordering::Relaxed);
+}
+
+/// Turn interlacing on or off.
+///
+/// When interlacing, Core 1 only draws every other line of the picture on
+/// each frame (the even lines on one frame and the odd lines on the next),
+/// and each line it draws is shown twice. That halves the drawing work, at
+/// the cost of some flicker, which makes time for the full-width 16 and 256
+/// colour bitmap modes (see `is_valid_mode`).
+///
+/// Returns `false` if the current mode can't be shown without interlacing,
+/// in which case nothing changes.
+pub fn set_interlace(enabled: bool) -> bool {
+	// Note (safety): Only written by `set_video_mode` on Core 0.
+	let mode = unsafe { VIDEO_MODE };
+	if !enabled && mode_needs_interlace(mode) {
+		return false;
+	}
+	INTERLACE.store(enabled, Ordering::Relaxed);
+	update_line_repeat();
+	mark_dirty();
+	true
+}
+
+/// Does this mode take too long to draw unless we interlace?
+fn mode_needs_interlace(mode: crate::common::video::Mode) -> bool {
+	matches!(
+		mode.format(),
+		crate::common::video::Format::Chunky8 | crate::common::video::Format::Chunky4
+	) && !(mode.is_horiz_2x() && mode.is_vert_2x())
+}
+
+/// Replace the font used by the 8x8 or 8x16 text modes, from the next frame.
+///
+/// `data` is 256 glyphs of `height` bytes each (one byte per row, with the
+/// left-most pixel in the top bit), in glyph order. A null `data` puts the
+/// built-in font back. Returns `false` if there are no text modes of that
+/// height.
+///
+/// # Safety
+///
+/// `data` must point to `256 * height` bytes, which must not change or move
+/// until another font of the same height is set.
+pub unsafe fn set_font(height: u8, data: *const u8) -> bool {
+	set_font_bank(0, height, data)
+}
+
+/// Replace one bank of the font used by the 8x8 or 8x16 text modes, from
+/// the next frame.
+///
+/// Bank 0 is the font `set_font` sets. Cells with `ATTR_FONT_BANK` set in
+/// their attribute use bank 1 instead, which is the same as bank 0 until the
+/// OS gives it some glyphs of its own. A null `data` puts the default back.
+/// Returns `false` if there is no such bank, or no text modes of that height.
+///
+/// # Safety
+///
+/// As for `set_font`.
+pub unsafe fn set_font_bank(bank: u8, height: u8, data: *const u8) -> bool {
+	let pending = match (bank, height) {
+		(0, 8) => &PENDING_FONT8,
+		(0, 16) => &PENDING_FONT16,
+		(1, 8) => &PENDING_FONT8_ALT,
+		(1, 16) => &PENDING_FONT16_ALT,
+		_ => return false,
+	};
+	pending.store(data as *mut u8, Ordering::Relaxed);
+	FONT_DIRTY.store(true, Ordering::Release);
+	mark_dirty();
+	true
+}
+
+/// Scroll the text modes, so the top of the screen shows the given
+/// scan-line of the text buffer, from the next frame.
+///
+/// Rows scrolled off the top come back in at the bottom, so a terminal can
+/// scroll smoothly by writing its new line over the old top row and moving
+/// the offset down, without copying the buffer. Offsets past the end of the
+/// buffer wrap around. Changing the video mode puts this back to zero.
+pub fn set_scroll_offset(lines: u16) {
+	SCROLL_OFFSET.store(lines, Ordering::Relaxed);
+	mark_dirty();
+}
+
+/// Get the scroll offset set by `set_scroll_offset`.
+pub fn scroll_offset() -> u16 {
+	SCROLL_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Choose whether the top bit of a text attribute makes the text blink (the
+/// default), or selects a bright background colour, like VGA's Attribute
+/// Mode Control register.
+pub fn set_text_blink(blink: bool) {
+	TEXT_BLINK.store(blink, Ordering::Relaxed);
+	mark_dirty();
+}
+
+/// Move the text cursor to the given column and row.
+///
+/// A cursor that is off the screen isn't drawn.
+pub fn set_cursor_position(col: u16, row: u16) {
+	CURSOR_POSITION.store((u32::from(row) << 16) | u32::from(col), Ordering::Relaxed);
+	mark_dirty();
+}
+
+/// Change the shape of the text cursor.
+///
+/// The cursor inverts lines `start..=end` of the character cell (so `14`
+/// and `15` give an underline in an 8x16 mode). `flags` are the
+/// `CURSOR_xxx` bits. Returns `false` if the flags or lines make no sense.
+pub fn set_cursor_shape(start: u8, end: u8, flags: u8) -> bool {
+	if (flags & !CURSOR_ALL) != 0 || start > end {
+		return false;
+	}
+	CURSOR_SHAPE.store(
+		(u32::from(flags) << 16) | (u32::from(end) << 8) | u32::from(start),
+		Ordering::Relaxed,
+	);
+	mark_dirty();
+	true
+}
+
+/// Get a palette entry.
+///
+/// This is the colour most recently set, even if it won't be on screen
+/// until the next frame.
+pub fn get_palette(index: u8) -> RGBColour {
+	// Note (safety): Only Core 0 writes to `PENDING_PALETTE`.
+	unsafe { PENDING_PALETTE[index as usize] }
+}
+
+/// Change some palette entries, starting at `start`.
+///
+/// The new colours appear at the start of the next frame. Returns `false`
+/// (and changes nothing) if the entries would run off the end of the
+/// palette.
+pub fn set_palette(start: u8, colours: &[RGBColour]) -> bool {
+	let start = start as usize;
+	let end = start + colours.len();
+	// Note (safety): Only Core 0 writes to `PENDING_PALETTE`. If Core 1 is
+	// copying it right now, it will copy it again next frame because we set
+	// the dirty flag afterwards.
+	match unsafe { PENDING_PALETTE.get_mut(start..end) } {
+		Some(entries) => {
+			entries.copy_from_slice(colours);
+			PALETTE_DIRTY.store(true, Ordering::Release);
+			mark_dirty();
+			true
+		}
+		None => false,
+	}
+}
+
+/// Replace the list of palette changes made part-way down each frame.
+///
+/// The changes must be in order of scan-line. On each frame, every entry
+/// they mention starts off with its usual colour, and takes its new colour
+/// from the given scan-line down (until a later change). The new list is
+/// used from the next frame. Returns `false` (and changes nothing) if there
+/// are too many changes, or they are out of order.
+pub fn set_palette_changes(changes: &[PaletteChange]) -> bool {
+	if changes.len() > MAX_PALETTE_CHANGES {
+		return false;
+	}
+	if changes.windows(2).any(|pair| pair[1].line < pair[0].line) {
+		return false;
+	}
+	// Note (safety): Only Core 0 writes to `PENDING_PALETTE_CHANGES`. If Core
+	// 1 is copying it right now, it will copy it again next frame because we
+	// set the dirty flag afterwards.
+	unsafe {
+		PENDING_PALETTE_CHANGES[0..changes.len()].copy_from_slice(changes);
+	}
+	NUM_PENDING_PALETTE_CHANGES.store(changes.len(), Ordering::Relaxed);
+	PALETTE_CHANGES_DIRTY.store(true, Ordering::Release);
+	mark_dirty();
+	true
+}
+
+/// Is the DMA interrupt running? If so, it interrupts Core 0 on every
+/// scan-line.
+pub fn is_irq_running() -> bool {
+	IRQ_RUNNING.load(Ordering::Relaxed)
+}
+
+/// Get the current scan line.
+pub fn get_scan_line() -> u16 {
+	CURRENT_DISPLAY_LINE.load(Ordering::Relaxed)
+}
+
+/// Get how many lines there are in each frame, including the ones in the
+/// vertical blanking interval.
+pub fn get_num_timing_lines() -> u16 {
+	// Note (safety): Only written by `set_video_mode`, on Core 0.
+	unsafe { TIMING_BUFFER.back_porch_ends_at + 1 }
+}
+
+/// Choose a line (counting from the first visible line, and carrying on
+/// into the vertical blanking interval) for `wait_for_raster` to wait for.
+///
+/// `None` stops us watching for one. Returns false if the line is past the
+/// end of the frame.
+pub fn set_raster_line(line: Option<u16>) -> bool {
+	let line = match line {
+		Some(line) if line >= get_num_timing_lines() => return false,
+		Some(line) => line,
+		None => NO_RASTER_LINE,
+	};
+	RASTER_LINE.store(line, Ordering::Relaxed);
+	RASTER_HIT.store(false, Ordering::Relaxed);
+	true
+}
+
+/// Sleep until the beam next reaches the line given to `set_raster_line`.
+///
+/// The DMA interrupt wakes us up on every line, so we only check the flag
+/// once per line rather than spinning. Returns false
+/// if there is no raster line set (or a mode change took it away).
+pub fn wait_for_raster() -> bool {
+	loop {
+		if RASTER_HIT.load(Ordering::Relaxed) {
+			RASTER_HIT.store(false, Ordering::Relaxed);
+			return true;
+		}
+		if RASTER_LINE.load(Ordering::Relaxed) == NO_RASTER_LINE {
+			return false;
+		}
+		cortex_m::asm::wfe();
+	}
+}
+
+/// How many frames have been sent to the monitor since start-up (wrapping
+/// around).
+///
+/// If this has gone up by more than one since you last looked, you missed a
+/// frame.
+pub fn vblank_count() -> u32 {
+	VBLANK_COUNT.load(Ordering::Relaxed)
+}
+
+/// Get the longest time Core 1 spent drawing a scan-line in the last frame,
+/// in system clock cycles.
+pub fn render_time() -> u32 {
+	RENDER_TIME.load(Ordering::Relaxed)
+}
+
+/// Get all the video performance counters.
+pub fn stats() -> VideoStats {
+	VideoStats {
+		frames: vblank_count(),
+		render_time: render_time(),
+		clashes: CLASHED_COUNT.load(Ordering::Relaxed),
+		pixel_underruns: PIXEL_UNDERRUN_COUNT.load(Ordering::Relaxed),
+		timing_underruns: TIMING_UNDERRUN_COUNT.load(Ordering::Relaxed),
+	}
+}
+
+/// Sleep until the start of the next vertical blanking interval, and return
+/// the new `vblank_count`.
+///
+/// Returns straight away if the video isn't running.
+pub fn wait_for_vblank() -> u32 {
+	let start = vblank_count();
+	if !is_running() {
+		return start;
+	}
+	loop {
+		let now = vblank_count();
+		if now != start {
+			return now;
+		}
+		cortex_m::asm::wfe();
+	}
+}
+
+/// Get how many visible lines there currently are
+pub fn get_num_scan_lines() -> u16 {
+	// Note (safety): Only written by `set_video_mode`, on Core 0.
+	unsafe { TIMING_BUFFER.visible_lines_ends_at + 1 }
+}
+
+/// This function runs the video processing loop on Core 1.
+///
+/// It keeps the odd/even scan-line buffers updated, as per the contents of
+/// the text buffer.
+///
+/// # Safety
+///
+/// Only run this function on Core 1.
+unsafe extern "C" fn core1_main() -> u32 {
+	CORE1_START_FLAG.store(true, Ordering::Relaxed);
+
+	// Each core has its own SysTick, so Core 1 can use its one to time how
+	// long it takes to draw each scan-line.
+	let mut syst = cortex_m::Peripherals::steal().SYST;
+	syst.set_clock_source(cortex_m::peripheral::syst::SystClkSource::Core);
+	syst.set_reload(SYST_MAX);
+	syst.clear_current();
+	syst.enable_counter();
+
+	let mut video = RenderEngine::new();
 
 	loop {
 		// This function currently consumes about 70% CPU (or rather, 90% CPU
 		// on each of 400 lines, and 0% CPU on the other 50 lines)
 		video.poll();
+		crate::mailbox::core1_poll();
+		crate::flash::core1_poll();
+		// Sleep until the DMA interrupt on Core 0 sends an event. If it
+		// already has, this returns straight away.
+		cortex_m::asm::wfe();
+	}
+}
+
+/// The biggest value the 24-bit SysTick counter can count down from
+const SYST_MAX: u32 = 0x00FF_FFFF;
+
+/// Shift a visible scan-line number so the groups of `LINE_REPEAT` lines
+/// start where they should.
+///
+/// When interlacing, the groups move down by half a group on every other
+/// frame, so the lines drawn on one frame fall between the lines drawn on
+/// the frame before.
+fn repeat_phase(line: u16) -> u16 {
+	if INTERLACE_FIELD.load(Ordering::Relaxed) {
+		line + u16::from(LINE_REPEAT.load(Ordering::Relaxed) / 2)
+	} else {
+		line
+	}
+}
+
+/// Where the interpolator registers start, from the start of the SIO block.
+///
+/// The registers we use are all within reach of an `ldr` or `str` with an
+/// immediate offset from here: interpolator 0's accumulator 0 is at 0x00 and
+/// its lanes' results are at 0x20 and 0x24, and interpolator 1's are at
+/// 0x40, 0x60 and 0x64.
+#[cfg(feature = "asm-text-render")]
+const INTERP_REGS_OFFSET: u32 = 0x80;
+
+/// Set in an interpolator lane's control register to make the lane take its
+/// input from the other lane's accumulator.
+const INTERP_CROSS_INPUT: u32 = 1 << 16;
+
+/// Make the value for an interpolator lane's control register, which
+/// shifts its input right by `shift` and then keeps bits
+/// `mask_lsb..=mask_msb`, before adding the lane's base.
+const fn interp_ctrl(shift: u32, mask_lsb: u32, mask_msb: u32, cross_input: bool) -> u32 {
+	let value = shift | (mask_lsb << 5) | (mask_msb << 10);
+	if cross_input {
+		value | INTERP_CROSS_INPUT
+	} else {
+		value
 	}
 }
 
@@ -745,6 +2537,39 @@ pub unsafe fn irq() {
 			old_timing_line + 1
 		};
 		CURRENT_TIMING_LINE.store(next_timing_line, Ordering::Relaxed);
+		if next_timing_line == TIMING_BUFFER.visible_lines_ends_at + 1 {
+			VBLANK_COUNT.store(
+				VBLANK_COUNT.load(Ordering::Relaxed).wrapping_add(1),
+				Ordering::Relaxed,
+			);
+			check_core1_stack();
+			// Once a frame, see if either FIFO ran dry (which makes its
+			// state machine stall), and clear the flags for next time.
+			// Note (safety): The FDEBUG flags are write-one-to-clear, and
+			// we only clear our own.
+			let pio0 = &*crate::pac::PIO0::ptr();
+			let stalls = pio0.fdebug.read().bits() & (TIMING_SM_TXSTALL | PIXEL_SM_TXSTALL);
+			if stalls != 0 {
+				pio0.fdebug.write(|w| w.bits(stalls));
+				if (stalls & PIXEL_SM_TXSTALL) != 0 {
+					PIXEL_UNDERRUN_COUNT.store(
+						PIXEL_UNDERRUN_COUNT.load(Ordering::Relaxed).wrapping_add(1),
+						Ordering::Relaxed,
+					);
+				}
+				if (stalls & TIMING_SM_TXSTALL) != 0 {
+					TIMING_UNDERRUN_COUNT.store(
+						TIMING_UNDERRUN_COUNT
+							.load(Ordering::Relaxed)
+							.wrapping_add(1),
+						Ordering::Relaxed,
+					);
+				}
+			}
+		}
+		if next_timing_line == RASTER_LINE.load(Ordering::Relaxed) {
+			RASTER_HIT.store(true, Ordering::Relaxed);
+		}
 
 		let buffer = if next_timing_line <= TIMING_BUFFER.visible_lines_ends_at {
 			// Visible lines
@@ -764,136 +2589,1050 @@ pub unsafe fn irq() {
 			.write(|w| w.bits(buffer as *const _ as usize as u32))
 	}
 
-	if pixel_dma_chan_irq {
-		dma.ints0.write(|w| w.bits(1 << PIXEL_DMA_CHAN));
-
-		// A pixel DMA transfer is now complete. This only fires on visible lines.
+	if pixel_dma_chan_irq {
+		dma.ints0.write(|w| w.bits(1 << PIXEL_DMA_CHAN));
+
+		// A pixel DMA transfer is now complete. This only fires on visible lines.
+
+		let mut next_display_line = CURRENT_DISPLAY_LINE.load(Ordering::Relaxed) + 1;
+		if next_display_line > TIMING_BUFFER.visible_lines_ends_at {
+			next_display_line = 0;
+			if INTERLACE.load(Ordering::Relaxed) {
+				let field = INTERLACE_FIELD.load(Ordering::Relaxed);
+				INTERLACE_FIELD.store(!field, Ordering::Relaxed);
+			}
+		};
+
+		// Each buffer is sent for a group of `LINE_REPEAT` lines, and we
+		// swap buffers (and Core 1 draws the next group) at the start of
+		// each group.
+		let group_start = next_display_line == 0
+			|| (repeat_phase(next_display_line) % u16::from(LINE_REPEAT.load(Ordering::Relaxed)))
+				== 0;
+		let send_odd = SENDING_ODD_BUFFER.load(Ordering::Relaxed) != group_start;
+
+		// Set the DMA load address according to which buffer we are on. We
+		// use the 'trigger' alias to restart the DMA at the same time as we
+		// write the new read address. The DMA had stopped because the
+		// previous line was transferred completely, so the count we write
+		// first is used for this transfer.
+		let (buffer, count) = if PIXELS_IDLE.load(Ordering::Relaxed) {
+			(BLANK_LINE.as_ptr(), BLANK_LINE_WORDS)
+		} else if send_odd {
+			(
+				PIXEL_DATA_BUFFER_ODD.as_ptr(),
+				NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed) as u32 + 1,
+			)
+		} else {
+			(
+				PIXEL_DATA_BUFFER_EVEN.as_ptr(),
+				NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed) as u32 + 1,
+			)
+		};
+		dma.ch[PIXEL_DMA_CHAN]
+			.ch_trans_count
+			.write(|w| w.bits(count));
+		dma.ch[PIXEL_DMA_CHAN]
+			.ch_al3_read_addr_trig
+			.write(|w| w.bits(buffer));
+		SENDING_ODD_BUFFER.store(send_odd, Ordering::Relaxed);
+
+		CURRENT_DISPLAY_LINE.store(next_display_line, Ordering::Relaxed);
+		if group_start {
+			DMA_READY.store(true, Ordering::Relaxed);
+		}
+	}
+
+	// Wake Core 1, which sleeps between scan-lines
+	cortex_m::asm::sev();
+}
+
+/// Get ready for Core 1 to run the video DMA from RAM, while Core 0 has the
+/// flash chip.
+///
+/// Nothing can be drawn until the flash chip is back, so we keep sending
+/// `BLANK_LINE`. We blank both scan-line buffers too, so nothing stale is
+/// shown when the DMA interrupt takes over again. Returns `None` if the
+/// video isn't running yet.
+///
+/// # Safety
+///
+/// Only call this on Core 1, in between scan-lines.
+pub unsafe fn dma_hold() -> Option<DmaHold> {
+	if !IRQ_RUNNING.load(Ordering::Relaxed) {
+		return None;
+	}
+	let black = RGBPair::from_pixels(colours::BLACK, colours::BLACK);
+	for pair in PIXEL_DATA_BUFFER_EVEN.pixels.iter_mut() {
+		*pair = black;
+	}
+	for pair in PIXEL_DATA_BUFFER_ODD.pixels.iter_mut() {
+		*pair = black;
+	}
+	let dma = &*super::pac::DMA::ptr();
+	Some(DmaHold {
+		ints0: &dma.ints0 as *const _ as usize as u32,
+		timing_trigger: &dma.ch[TIMING_DMA_CHAN].ch_al3_read_addr_trig as *const _ as usize as u32,
+		pixel_trigger: &dma.ch[PIXEL_DMA_CHAN].ch_al3_read_addr_trig as *const _ as usize as u32,
+		pixel_count: &dma.ch[PIXEL_DMA_CHAN].ch_trans_count as *const _ as usize as u32,
+		timing_line: &CURRENT_TIMING_LINE as *const _ as usize as u32,
+		display_line: &CURRENT_DISPLAY_LINE as *const _ as usize as u32,
+		vblank_count: &VBLANK_COUNT as *const _ as usize as u32,
+		visible_line: &TIMING_BUFFER.visible_line as *const _ as usize as u32,
+		vblank_porch: &TIMING_BUFFER.vblank_porch_buffer as *const _ as usize as u32,
+		vblank_sync: &TIMING_BUFFER.vblank_sync_buffer as *const _ as usize as u32,
+		pixels: BLANK_LINE.as_ptr(),
+		visible_lines_ends_at: TIMING_BUFFER.visible_lines_ends_at,
+		front_porch_end_at: TIMING_BUFFER.front_porch_end_at,
+		sync_pulse_ends_at: TIMING_BUFFER.sync_pulse_ends_at,
+		back_porch_ends_at: TIMING_BUFFER.back_porch_ends_at,
+	})
+}
+
+/// Do what `irq` would, if the DMA has finished a transfer, but without
+/// drawing anything. Runs from RAM, on Core 1, while Core 0 has its
+/// interrupts off.
+///
+/// Only uses the `flash::ram_*` functions, and no arithmetic that could
+/// overflow, so nothing here can call into flash.
+///
+/// # Safety
+///
+/// Only call this from `flash`, as above.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+pub unsafe fn ram_dma_hold_step(hold: &DmaHold) {
+	use crate::flash::{ram_read16, ram_read32, ram_write16, ram_write32};
+	let status = ram_read32(hold.ints0);
+	if (status & (1 << TIMING_DMA_CHAN)) != 0 {
+		ram_write32(hold.ints0, 1 << TIMING_DMA_CHAN);
+		let old_timing_line = ram_read16(hold.timing_line);
+		let next_timing_line = if old_timing_line >= hold.back_porch_ends_at {
+			0
+		} else {
+			old_timing_line + 1
+		};
+		ram_write16(hold.timing_line, next_timing_line);
+		if next_timing_line == hold.visible_lines_ends_at + 1 {
+			let count = ram_read32(hold.vblank_count);
+			let count = if count == u32::MAX { 0 } else { count + 1 };
+			ram_write32(hold.vblank_count, count);
+		}
+		let buffer = if next_timing_line <= hold.visible_lines_ends_at {
+			hold.visible_line
+		} else if next_timing_line <= hold.front_porch_end_at {
+			hold.vblank_porch
+		} else if next_timing_line <= hold.sync_pulse_ends_at {
+			hold.vblank_sync
+		} else {
+			hold.vblank_porch
+		};
+		ram_write32(hold.timing_trigger, buffer);
+	}
+	if (status & (1 << PIXEL_DMA_CHAN)) != 0 {
+		ram_write32(hold.ints0, 1 << PIXEL_DMA_CHAN);
+		let old_display_line = ram_read16(hold.display_line);
+		let next_display_line = if old_display_line >= hold.visible_lines_ends_at {
+			0
+		} else {
+			old_display_line + 1
+		};
+		ram_write16(hold.display_line, next_display_line);
+		ram_write32(hold.pixel_count, BLANK_LINE_WORDS);
+		ram_write32(hold.pixel_trigger, hold.pixels);
+	}
+}
+
+impl RenderEngine {
+	// Initialise the main-thread resources
+	pub fn new() -> RenderEngine {
+		RenderEngine {
+			frame_count: 0,
+			idle_frames: 0,
+			blanked_buffers: 0,
+			logo_x: 0,
+			logo_y: 0,
+			logo_direction: (true, true),
+			lookup: [
+				RGBPair::from_pixels(colours::BLUE, colours::BLUE),
+				RGBPair::from_pixels(colours::BLUE, colours::WHITE),
+				RGBPair::from_pixels(colours::WHITE, colours::BLUE),
+				RGBPair::from_pixels(colours::WHITE, colours::WHITE),
+			],
+			font8: Font {
+				height: font8::FONT.height,
+				data: font8::FONT.data,
+			},
+			font16: Font {
+				height: font16::FONT.height,
+				data: font16::FONT.data,
+			},
+			font8_alt: Font {
+				height: font8::FONT.height,
+				data: font8::FONT.data,
+			},
+			font16_alt: Font {
+				height: font16::FONT.height,
+				data: font16::FONT.data,
+			},
+			frame_text_buffer: Self::text_buffer(),
+			frame_scroll_offset: 0,
+			palette_changes: [NO_PALETTE_CHANGE; MAX_PALETTE_CHANGES],
+			num_palette_changes: 0,
+			next_palette_change: 0,
+			frame_render_time: 0,
+			last_overrun_warning: 0u32.wrapping_sub(OVERRUN_WARNING_FRAMES),
+		}
+	}
+
+	pub fn poll(&mut self) {
+		if DMA_READY.load(Ordering::Relaxed) {
+			DMA_READY.store(false, Ordering::Relaxed);
+			let current_line_num = CURRENT_DISPLAY_LINE.load(Ordering::Relaxed);
+			if current_line_num == 0 {
+				trace!("Frame {}", self.frame_count);
+				self.frame_count += 1;
+				RENDER_TIME.store(self.frame_render_time, Ordering::Relaxed);
+				// Note (safety): Only written by `set_video_mode` on Core 0.
+				let budget =
+					unsafe { TIMING_BUFFER.line_clocks(SYS_CLOCK_HZ.load(Ordering::Relaxed)) }
+						* u32::from(LINE_REPEAT.load(Ordering::Relaxed));
+				if self.frame_render_time >= budget {
+					let overruns = RENDER_OVERRUN_COUNT.load(Ordering::Relaxed).wrapping_add(1);
+					RENDER_OVERRUN_COUNT.store(overruns, Ordering::Relaxed);
+					if self.frame_count.wrapping_sub(self.last_overrun_warning)
+						>= OVERRUN_WARNING_FRAMES
+					{
+						warn!(
+							"Scan-line took {} clocks, but we only have {} ({} overruns)",
+							self.frame_render_time, budget, overruns
+						);
+						self.last_overrun_warning = self.frame_count;
+					}
+				}
+				self.frame_render_time = 0;
+				let pending = PENDING_CONSOLE.load(Ordering::Relaxed);
+				if pending != NO_FLIP {
+					ACTIVE_CONSOLE.store(pending, Ordering::Relaxed);
+					PENDING_CONSOLE.store(NO_FLIP, Ordering::Relaxed);
+				}
+				self.frame_text_buffer = Self::text_buffer();
+				self.frame_scroll_offset = SCROLL_OFFSET.load(Ordering::Relaxed);
+				self.undo_palette_changes();
+				if VIDEO_DIRTY.load(Ordering::Relaxed) {
+					VIDEO_DIRTY.store(false, Ordering::Relaxed);
+					self.idle_frames = 0;
+				} else {
+					self.idle_frames = self.idle_frames.saturating_add(1);
+				}
+			}
+
+			// new line - pick a buffer to draw into (not the one that is currently rendering!)
+			let scan_line_buffer = unsafe {
+				if SENDING_ODD_BUFFER.load(Ordering::Relaxed) {
+					&mut PIXEL_DATA_BUFFER_EVEN
+				} else {
+					&mut PIXEL_DATA_BUFFER_ODD
+				}
+			};
+			// We draw the last line of this group of repeated lines, which
+			// is what makes interlacing alternate between the even and odd
+			// lines.
+			let draw_line_num = Self::last_line_of_group(current_line_num);
+			self.apply_palette_changes(draw_line_num);
+
+			let blank_after = BLANK_AFTER_FRAMES.load(Ordering::Relaxed);
+			let screen_saver = match SCREEN_SAVER_OVERRIDE.load(Ordering::Relaxed) {
+				1 => false,
+				2 => true,
+				_ => blank_after != 0 && self.idle_frames >= blank_after,
+			};
+			let powered_down = POWER_STATE.load(Ordering::Relaxed) != PowerState::On as u8;
+			if powered_down {
+				// The monitor is (or may be) off, so there's nothing to see
+				self.render_scanline_blanked(scan_line_buffer);
+			} else if screen_saver
+				&& SCREEN_SAVER_STYLE.load(Ordering::Relaxed) == ScreenSaverStyle::Logo as u8
+			{
+				if current_line_num == 0 {
+					self.move_logo();
+				}
+				self.unblank();
+				self.render_scanline_logo(draw_line_num, scan_line_buffer);
+			} else if screen_saver {
+				// Nothing has changed for a while, so blank the screen
+				self.render_scanline_blanked(scan_line_buffer);
+			} else {
+				self.unblank();
+				let start = cortex_m::peripheral::SYST::get_current();
+				self.render_scanline(draw_line_num, scan_line_buffer);
+				// SysTick counts down, and wraps around at zero
+				let elapsed =
+					start.wrapping_sub(cortex_m::peripheral::SYST::get_current()) & SYST_MAX;
+				self.frame_render_time = self.frame_render_time.max(elapsed);
+				if DMA_READY.load(Ordering::Relaxed) {
+					// The next line has already started, so the DMA was
+					// sending this buffer while we were drawing in it.
+					CLASHED_COUNT.store(
+						CLASHED_COUNT.load(Ordering::Relaxed).wrapping_add(1),
+						Ordering::Relaxed,
+					);
+				}
+			}
+		} else if PALETTE_DIRTY.load(Ordering::Acquire) && Self::in_vblank() {
+			// Clear the flag first, so a change made while we copy isn't lost
+			PALETTE_DIRTY.store(false, Ordering::Relaxed);
+			// Note (safety): Only Core 1 touches `VIDEO_PALETTE`, and
+			// nothing is being drawn during the vertical blanking interval.
+			unsafe {
+				VIDEO_PALETTE = PENDING_PALETTE;
+			}
+		} else if PALETTE_CHANGES_DIRTY.load(Ordering::Acquire) && Self::in_vblank() {
+			// As with the palette, clear the flag before we copy
+			PALETTE_CHANGES_DIRTY.store(false, Ordering::Relaxed);
+			self.undo_palette_changes();
+			let count = NUM_PENDING_PALETTE_CHANGES.load(Ordering::Relaxed);
+			// Note (safety): Core 0 only writes the list before it sets the
+			// dirty flag.
+			self.palette_changes[0..count]
+				.copy_from_slice(unsafe { &PENDING_PALETTE_CHANGES[0..count] });
+			self.num_palette_changes = count;
+		} else if FONT_DIRTY.load(Ordering::Acquire) && Self::in_vblank() {
+			// Swap fonts between frames, so we never draw half the screen
+			// in each.
+			FONT_DIRTY.store(false, Ordering::Relaxed);
+			self.font8 = Self::load_font(&PENDING_FONT8, &font8::FONT);
+			self.font16 = Self::load_font(&PENDING_FONT16, &font16::FONT);
+			self.font8_alt = Self::load_font(&PENDING_FONT8_ALT, &self.font8);
+			self.font16_alt = Self::load_font(&PENDING_FONT16_ALT, &self.font16);
+		}
+	}
+
+	/// Put back the usual colour of every palette entry that
+	/// `palette_changes` changes, ready for a new frame.
+	fn undo_palette_changes(&mut self) {
+		for change in self.palette_changes[0..self.num_palette_changes].iter() {
+			// Note (safety): Only Core 1 touches `VIDEO_PALETTE`, and Core 0
+			// only writes to `PENDING_PALETTE`.
+			unsafe {
+				VIDEO_PALETTE[change.index as usize] = PENDING_PALETTE[change.index as usize];
+			}
+		}
+		self.next_palette_change = 0;
+	}
+
+	/// Make any palette changes which are due by the given scan-line.
+	fn apply_palette_changes(&mut self, line: u16) {
+		while let Some(change) =
+			self.palette_changes[0..self.num_palette_changes].get(self.next_palette_change)
+		{
+			if change.line > line {
+				break;
+			}
+			// Note (safety): Only Core 1 touches `VIDEO_PALETTE`.
+			unsafe {
+				VIDEO_PALETTE[change.index as usize] = change.colour;
+			}
+			self.next_palette_change += 1;
+		}
+	}
+
+	/// Get the font the OS asked for, or the default one if it hasn't
+	/// asked for one.
+	fn load_font(pending: &AtomicPtr<u8>, built_in: &Font<'static>) -> Font<'static> {
+		let data = pending.load(Ordering::Relaxed);
+		if data.is_null() {
+			Font {
+				height: built_in.height,
+				data: built_in.data,
+			}
+		} else {
+			// Note (safety): `set_font` makes the OS promise the data is the
+			// right size, and stays put until it asks for another font.
+			Font {
+				height: built_in.height,
+				data: unsafe { core::slice::from_raw_parts(data, 256 * built_in.height) },
+			}
+		}
+	}
+
+	/// Find the text buffer for the virtual console on screen.
+	fn text_buffer() -> *mut GlyphAttr {
+		let console = ACTIVE_CONSOLE.load(Ordering::Relaxed);
+		get_console_buffer(console).unwrap_or_else(|| get_framebuffer() as *mut GlyphAttr)
+	}
+
+	/// Find the last scan-line in the group of `LINE_REPEAT` lines which
+	/// starts at `line`.
+	fn last_line_of_group(line: u16) -> u16 {
+		let repeat = u16::from(LINE_REPEAT.load(Ordering::Relaxed));
+		if repeat == 1 {
+			return line;
+		}
+		let shift = repeat_phase(0);
+		let last = (((line + shift) / repeat) * repeat) + (repeat - 1) - shift;
+		// Note (safety): Only written by `set_video_mode` on Core 0.
+		last.min(unsafe { TIMING_BUFFER.visible_lines_ends_at })
+	}
+
+	/// Is the beam in the vertical blanking interval (i.e. past the last
+	/// visible line)?
+	fn in_vblank() -> bool {
+		CURRENT_TIMING_LINE.load(Ordering::Relaxed) > unsafe { TIMING_BUFFER.visible_lines_ends_at }
+	}
+
+	/// Draw one scan-line into the given buffer, in whatever format the
+	/// current video mode uses.
+	fn render_scanline(&mut self, current_line_num: u16, scan_line_buffer: &mut LineBuffer) {
+		// Note (safety): Only written by `set_video_mode` on Core 0.
+		let mode = unsafe { VIDEO_MODE };
+		let format = mode.format();
+		// The renderers work in the mode's own lines, so each one is drawn
+		// twice if the mode doubles them.
+		let mode_line = if mode.is_vert_2x() {
+			current_line_num / 2
+		} else {
+			current_line_num
+		};
+		match format {
+			crate::common::video::Format::Text8x16 => self.render_scanline_text(
+				&self.font16,
+				&self.font16_alt,
+				mode_line,
+				scan_line_buffer,
+			),
+			crate::common::video::Format::Text8x8 => {
+				self.render_scanline_text(&self.font8, &self.font8_alt, mode_line, scan_line_buffer)
+			}
+			crate::common::video::Format::Chunky8 if mode.is_horiz_2x() => {
+				self.render_scanline_chunky8(mode_line, scan_line_buffer)
+			}
+			crate::common::video::Format::Chunky8 => {
+				self.render_scanline_chunky8_full(mode_line, scan_line_buffer)
+			}
+			crate::common::video::Format::Chunky4 if mode.is_horiz_2x() => {
+				self.render_scanline_chunky4(mode_line, scan_line_buffer)
+			}
+			crate::common::video::Format::Chunky4 => {
+				self.render_scanline_chunky4_full(mode_line, scan_line_buffer)
+			}
+			crate::common::video::Format::Chunky1 => {
+				self.render_scanline_chunky1(mode_line, scan_line_buffer)
+			}
+			_ => {}
+		}
+
+		// The 256 and 16 colour renderers double each pixel as they go (if
+		// they need to). The others draw the left half of the line, which we
+		// spread out.
+		let self_doubled = matches!(
+			format,
+			crate::common::video::Format::Chunky8 | crate::common::video::Format::Chunky4
+		);
+		if mode.is_horiz_2x() && !self_doubled {
+			Self::double_pixels(
+				scan_line_buffer,
+				NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed) / 2,
+			);
+		}
+
+		sprites::render(mode_line, mode.is_horiz_2x(), scan_line_buffer);
+
+		let effects = VIDEO_EFFECTS.load(Ordering::Relaxed);
+		if effects != 0 {
+			let doubled = mode.is_horiz_2x();
+			if doubled && (effects & EFFECT_BLUR) != 0 {
+				Self::apply_blur(scan_line_buffer);
+			}
+			if (effects & EFFECT_SCANLINES) != 0 && (current_line_num & 1) == 1 {
+				Self::apply_scanline_darken(scan_line_buffer);
+			}
+		}
+	}
+
+	/// Spread the first `pairs` pixel pairs out across twice as many, so
+	/// every pixel is twice as wide.
+	///
+	/// We work from the end backwards, so we never overwrite a pair we
+	/// haven't read yet.
+	fn double_pixels(scan_line_buffer: &mut LineBuffer, pairs: usize) {
+		for idx in (0..pairs).rev() {
+			let pair = scan_line_buffer.pixels[idx].0;
+			let left = pair & 0xFFFF;
+			let right = pair >> 16;
+			scan_line_buffer.pixels[idx * 2] = RGBPair(left | (left << 16));
+			scan_line_buffer.pixels[(idx * 2) + 1] = RGBPair(right | (right << 16));
+		}
+	}
+
+	/// Take a quarter off the brightness of every pixel on a scan-line.
+	fn apply_scanline_darken(scan_line_buffer: &mut LineBuffer) {
+		for pair in scan_line_buffer.pixels.iter_mut() {
+			// Each 4-bit channel loses a quarter of itself, which can't
+			// borrow from the channel above.
+			pair.0 -= (pair.0 >> 2) & 0x0333_0333;
+		}
+	}
+
+	/// Blend the left-hand pixel of each pair with the pixel before it.
+	///
+	/// In the pixel-doubled modes, the two pixels in a pair are the same, so
+	/// this softens every edge by one (real) pixel.
+	fn apply_blur(scan_line_buffer: &mut LineBuffer) {
+		let mut previous = scan_line_buffer.pixels[0].0 & 0xFFFF;
+		for pair in scan_line_buffer.pixels.iter_mut() {
+			let left = pair.0 & 0xFFFF;
+			// Average each 4-bit channel, without carrying into the next
+			let blended = (left & previous) + (((left ^ previous) >> 1) & 0x0777);
+			previous = pair.0 >> 16;
+			pair.0 = (pair.0 & 0xFFFF_0000) | blended;
+		}
+	}
+
+	/// Draw one line of a text mode, using the given font (or the
+	/// alternative font, for cells which select the second font bank).
+	///
+	/// `mode_line` counts the mode's own lines, which are two scan-lines
+	/// each if the mode doubles them. If the mode doubles every pixel, we
+	/// only draw the left half of the scan-line (see `double_pixels`).
+	fn render_scanline_text(
+		&self,
+		font: &Font,
+		alt_font: &Font,
+		mode_line: u16,
+		scan_line_buffer: &mut LineBuffer,
+	) {
+		let num_rows = NUM_TEXT_ROWS.load(Ordering::Relaxed);
+		let num_cols = NUM_TEXT_COLS.load(Ordering::Relaxed);
+
+		// Convert our position in scan-lines to a text row, and a line within
+		// each glyph on that row, allowing for any scrolling
+		let buffer_lines = num_rows * font.height;
+		if mode_line as usize >= buffer_lines {
+			// The rows don't always fill the screen (e.g. 37 rows of 16
+			// lines at 800x600), so blank whatever is left over.
+			Self::render_scanline_black(scan_line_buffer);
+			return;
+		}
+		let buffer_line = (mode_line as usize + self.frame_scroll_offset as usize) % buffer_lines;
+		let text_row = buffer_line / font.height;
+		let font_row = buffer_line % font.height;
+
+		if text_row < num_rows {
+			// Note (unsafe): We could stash the char array inside `self`
+			// but at some point we are going to need one CPU rendering
+			// the text, and the other CPU running code and writing to
+			// the buffer. This might be Undefined Behaviour, but
+			// unfortunately real-time video is all about shared mutable
+			// state. At least our platform is fixed, so we can simply
+			// test if it works, for some given version of the Rust compiler.
+			//
+			// The OS promised us the framebuffer was big enough for this
+			// mode when it gave it to us.
+			let row_slice = unsafe {
+				core::slice::from_raw_parts(
+					self.frame_text_buffer.add(text_row * num_cols),
+					num_cols,
+				)
+			};
+			// Every font look-up we are about to do for this row will
+			// involve offsetting by the row within each glyph. As this
+			// is the same for every glyph on this row, we calculate a
+			// new pointer once, in advance, and save ourselves an
+			// addition each time around the loop.
+			let font_ptr = unsafe { font.data.as_ptr().add(font_row) };
+			let alt_font_ptr = unsafe { alt_font.data.as_ptr().add(font_row) };
+
+			// Blinking text spends every other `TEXT_BLINK_FRAMES` frames in
+			// its background colour
+			let hidden_attr = if TEXT_BLINK.load(Ordering::Relaxed)
+				&& ((self.frame_count / TEXT_BLINK_FRAMES) & 1) != 0
+			{
+				ATTR_BLINK
+			} else {
+				0
+			};
+
+			// We let Core 1's interpolators do the address arithmetic.
+			// Interpolator 0 turns a glyph/attribute pair (shifted up to
+			// multiply the glyph by the font height) into the address of
+			// this row of the glyph, in each font bank. Interpolator 1 turns
+			// the font byte into the addresses of the look-up table entries
+			// for its top four pixels (or, shifted up by four, its bottom
+			// four pixels).
+			//
+			// Note (safety): Only Core 1 can see its interpolators, and
+			// nothing else on Core 1 uses them.
+			let sio = unsafe { &*crate::pac::SIO::ptr() };
+			let font_shift = font.height.trailing_zeros();
+			let lookup_ptr = self.lookup.as_ptr() as u32;
+			unsafe {
+				sio.interp0_ctrl_lane0
+					.write(|w| w.bits(interp_ctrl(0, font_shift, font_shift + 7, false)));
+				sio.interp0_ctrl_lane1
+					.write(|w| w.bits(interp_ctrl(0, font_shift, font_shift + 7, true)));
+				sio.interp0_base0.write(|w| w.bits(font_ptr as u32));
+				sio.interp0_base1.write(|w| w.bits(alt_font_ptr as u32));
+				sio.interp1_ctrl_lane0
+					.write(|w| w.bits(interp_ctrl(4, 2, 3, false)));
+				sio.interp1_ctrl_lane1
+					.write(|w| w.bits(interp_ctrl(2, 2, 3, true)));
+				sio.interp1_base0.write(|w| w.bits(lookup_ptr));
+				sio.interp1_base1.write(|w| w.bits(lookup_ptr));
+			}
+
+			Self::render_glyphs(row_slice, hidden_attr, font_shift, scan_line_buffer);
+
+			self.render_cursor(text_row, font_row, num_cols, scan_line_buffer);
+		}
+	}
+
+	/// Convert a row of characters to coloured pixels, using the font as a
+	/// look-up table, and the interpolators as set up by
+	/// `render_scanline_text`.
+	#[cfg(not(feature = "asm-text-render"))]
+	fn render_glyphs(
+		row_slice: &[GlyphAttr],
+		hidden_attr: u8,
+		font_shift: u32,
+		scan_line_buffer: &mut LineBuffer,
+	) {
+		// Note (safety): Only Core 1 can see its interpolators.
+		let sio = unsafe { &*crate::pac::SIO::ptr() };
+		// Get a pointer into our scan-line buffer
+		let scan_line_buffer_ptr = scan_line_buffer.pixels.as_mut_ptr();
+		let mut px_idx = 0;
+		for glyphattr in row_slice.iter() {
+			let attr = glyphattr.attr().0;
+			// Note (unsafe): We use pointer arithmetic here because we
+			// can't afford a bounds-check on an array. This is safe
+			// because the font is `256 * width` bytes long and the mask
+			// means we can't index more than `255 * width` bytes into
+			// it. Likewise, the look-up table addresses are masked to
+			// stay within its four entries.
+			unsafe {
+				sio.interp0_accum0
+					.write(|w| w.bits(u32::from(glyphattr.0) << font_shift));
+				let mono_pixels = if (attr & hidden_attr) != 0 {
+					0
+				} else if (attr & ATTR_FONT_BANK) != 0 {
+					*(sio.interp0_peek_lane1.read().bits() as *const u8)
+				} else {
+					*(sio.interp0_peek_lane0.read().bits() as *const u8)
+				};
+				// Convert from eight mono pixels in one byte to four RGB
+				// pairs.
+				sio.interp1_accum0.write(|w| w.bits(u32::from(mono_pixels)));
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.offset(px_idx),
+					*(sio.interp1_peek_lane0.read().bits() as *const RGBPair),
+				);
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.offset(px_idx + 1),
+					*(sio.interp1_peek_lane1.read().bits() as *const RGBPair),
+				);
+				sio.interp1_accum0
+					.write(|w| w.bits(u32::from(mono_pixels) << 4));
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.offset(px_idx + 2),
+					*(sio.interp1_peek_lane0.read().bits() as *const RGBPair),
+				);
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.offset(px_idx + 3),
+					*(sio.interp1_peek_lane1.read().bits() as *const RGBPair),
+				);
+			}
+			px_idx += 4;
+		}
+	}
+
+	/// Convert a row of characters to coloured pixels, as above, but in
+	/// hand-scheduled assembly.
+	///
+	/// The Cortex-M0+ only has the Thumb instructions of ARMv6-M, and only
+	/// eight registers most of them can use. This loop keeps everything in
+	/// registers, and picks the font bank without a branch (the bank bit
+	/// selects which interpolator lane we read). Use `video_get_render_time`
+	/// to compare it with the Rust version.
+	///
+	/// Rust won't let us name r6 or r7 as operands, so we save them
+	/// ourselves, and pass the values they need in r4 and r5.
+	#[cfg(feature = "asm-text-render")]
+	fn render_glyphs(
+		row_slice: &[GlyphAttr],
+		hidden_attr: u8,
+		font_shift: u32,
+		scan_line_buffer: &mut LineBuffer,
+	) {
+		if row_slice.is_empty() {
+			return;
+		}
+		let row_range = row_slice.as_ptr_range();
+		let interp_base = crate::pac::SIO::ptr() as u32 + INTERP_REGS_OFFSET;
+		// Note (safety): The same as the Rust version above. The loop reads
+		// `row_slice` and writes four pixel pairs per character, which
+		// `NUM_TEXT_COLS` makes sure fits in the scan-line buffer.
+		unsafe {
+			core::arch::asm!(
+				"push {{r6, r7}}",
+				"mov r6, r4",
+				"mov r7, r5",
+				"2:",
+				// r4 = glyph/attribute pair
+				"ldrh r4, [r0]",
+				"adds r0, #2",
+				// Interpolator 0 wants the glyph multiplied by the font
+				// height
+				"movs r5, r4",
+				"lsls r5, r6",
+				"str r5, [r2, #0x00]",
+				// Read from lane 0 (0x20) or lane 1 (0x24), as the font bank
+				// bit (bit 11) says
+				"lsls r5, r4, #20",
+				"lsrs r5, r5, #31",
+				"lsls r5, r5, #2",
+				"adds r5, #0x20",
+				"ldr r5, [r2, r5]",
+				"ldrb r5, [r5]",
+				// Hidden characters are all background
+				"tst r4, r3",
+				"beq 3f",
+				"movs r5, #0",
+				"3:",
+				// The top four pixels
+				"str r5, [r2, #0x40]",
+				"ldr r4, [r2, #0x60]",
+				"ldr r4, [r4]",
+				"str r4, [r1, #0]",
+				"ldr r4, [r2, #0x64]",
+				"ldr r4, [r4]",
+				"str r4, [r1, #4]",
+				// The bottom four pixels
+				"lsls r5, r5, #4",
+				"str r5, [r2, #0x40]",
+				"ldr r4, [r2, #0x60]",
+				"ldr r4, [r4]",
+				"str r4, [r1, #8]",
+				"ldr r4, [r2, #0x64]",
+				"ldr r4, [r4]",
+				"str r4, [r1, #12]",
+				"adds r1, #16",
+				"cmp r0, r7",
+				"bne 2b",
+				"pop {{r6, r7}}",
+				inout("r0") row_range.start => _,
+				inout("r1") scan_line_buffer.pixels.as_mut_ptr() => _,
+				in("r2") interp_base,
+				in("r3") u32::from(hidden_attr) << 8,
+				inout("r4") font_shift => _,
+				inout("r5") row_range.end => _,
+			);
+		}
+	}
+
+	/// Invert the pixels under the text cursor, if it is on this scan-line
+	/// (and hasn't blinked off).
+	fn render_cursor(
+		&self,
+		text_row: usize,
+		font_row: usize,
+		num_cols: usize,
+		scan_line_buffer: &mut LineBuffer,
+	) {
+		let shape = CURSOR_SHAPE.load(Ordering::Relaxed);
+		let flags = (shape >> 16) as u8;
+		if (flags & CURSOR_VISIBLE) == 0 {
+			return;
+		}
+		if (flags & CURSOR_BLINK) != 0 && ((self.frame_count / CURSOR_BLINK_FRAMES) & 1) != 0 {
+			return;
+		}
+		let start = (shape & 0xFF) as usize;
+		let end = ((shape >> 8) & 0xFF) as usize;
+		let position = CURSOR_POSITION.load(Ordering::Relaxed);
+		let row = (position >> 16) as usize;
+		let col = (position & 0xFFFF) as usize;
+		if row != text_row || col >= num_cols || font_row < start || font_row > end {
+			return;
+		}
+		// Each character cell is four pixel pairs wide
+		for pair in scan_line_buffer.pixels[col * 4..(col + 1) * 4].iter_mut() {
+			pair.0 ^= 0x0FFF_0FFF;
+		}
+	}
+
+	/// Move the screen saver logo along by one step, bouncing off the edges.
+	fn move_logo(&mut self) {
+		let max_x = (NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed)
+			- (SCREEN_SAVER_LOGO.len() * 4)) as u16;
+		let max_y = get_num_scan_lines().saturating_sub(font16::FONT.height as u16);
+		let (right, down) = self.logo_direction;
+		self.logo_x = if right {
+			self.logo_x + 1
+		} else {
+			self.logo_x.saturating_sub(1)
+		}
+		.min(max_x);
+		self.logo_y = if down {
+			self.logo_y + 1
+		} else {
+			self.logo_y.saturating_sub(1)
+		}
+		.min(max_y);
+		if self.logo_x == 0 || self.logo_x == max_x {
+			self.logo_direction.0 = self.logo_x == 0;
+		}
+		if self.logo_y == 0 || self.logo_y == max_y {
+			self.logo_direction.1 = self.logo_y == 0;
+		}
+	}
 
-		let mut next_display_line = CURRENT_DISPLAY_LINE.load(Ordering::Relaxed) + 1;
-		if next_display_line > TIMING_BUFFER.visible_lines_ends_at {
-			next_display_line = 0;
+	/// Draw one scan-line of the screen saver - black, except where it
+	/// crosses the logo.
+	fn render_scanline_logo(&mut self, current_line_num: u16, scan_line_buffer: &mut LineBuffer) {
+		Self::render_scanline_black(scan_line_buffer);
+		let font = &font16::FONT;
+		let font_row = match current_line_num.checked_sub(self.logo_y) {
+			Some(row) if (row as usize) < font.height => row as usize,
+			_ => return,
 		};
+		let mut px_idx = self.logo_x as usize;
+		for &glyph in SCREEN_SAVER_LOGO.iter() {
+			let mono_pixels = font.data[(glyph as usize * font.height) + font_row] as usize;
+			for shift in [6, 4, 2, 0].iter() {
+				scan_line_buffer.pixels[px_idx] = SCREEN_SAVER_LOOKUP[(mono_pixels >> shift) & 3];
+				px_idx += 1;
+			}
+		}
+	}
 
-		// Set the DMA load address according to which line we are on. We use
-		// the 'trigger' alias to restart the DMA at the same time as we
-		// write the new read address. The DMA had stopped because the
-		// previous line was transferred completely.
-		if (next_display_line & 1) == 1 {
-			// Odd visible line is next
-			dma.ch[PIXEL_DMA_CHAN]
-				.ch_al3_read_addr_trig
-				.write(|w| w.bits(PIXEL_DATA_BUFFER_ODD.as_ptr()))
+	/// Draw a scan-line of a blanked screen.
+	///
+	/// Once both buffers are black we stop drawing altogether, and have the
+	/// DMA interrupt send `BLANK_LINE` instead. The sync signals carry on as
+	/// normal.
+	fn render_scanline_blanked(&mut self, scan_line_buffer: &mut LineBuffer) {
+		if self.blanked_buffers < 2 {
+			Self::render_scanline_black(scan_line_buffer);
+			self.blanked_buffers += 1;
 		} else {
-			// Even visible line is next
-			dma.ch[PIXEL_DMA_CHAN]
-				.ch_al3_read_addr_trig
-				.write(|w| w.bits(PIXEL_DATA_BUFFER_EVEN.as_ptr()))
+			PIXELS_IDLE.store(true, Ordering::Relaxed);
 		}
+	}
 
-		CURRENT_DISPLAY_LINE.store(next_display_line, Ordering::Relaxed);
-		DMA_READY.store(true, Ordering::Relaxed);
+	/// Go back to sending the scan-line buffers, after
+	/// `render_scanline_blanked`.
+	///
+	/// Both buffers are black, so whichever the DMA sends first, there is
+	/// nothing stale on the screen.
+	fn unblank(&mut self) {
+		self.blanked_buffers = 0;
+		PIXELS_IDLE.store(false, Ordering::Relaxed);
 	}
-}
 
-impl RenderEngine {
-	// Initialise the main-thread resources
-	pub fn new() -> RenderEngine {
-		RenderEngine {
-			frame_count: 0,
-			lookup: [
-				RGBPair::from_pixels(colours::BLUE, colours::BLUE),
-				RGBPair::from_pixels(colours::BLUE, colours::WHITE),
-				RGBPair::from_pixels(colours::WHITE, colours::BLUE),
-				RGBPair::from_pixels(colours::WHITE, colours::WHITE),
-			],
+	/// Fill a scan-line with black.
+	fn render_scanline_black(scan_line_buffer: &mut LineBuffer) {
+		let scan_line_buffer_ptr = scan_line_buffer.pixels.as_mut_ptr();
+		let black = RGBPair::from_pixels(colours::BLACK, colours::BLACK);
+		for px_idx in 0..NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed) {
+			unsafe {
+				core::ptr::write_volatile(scan_line_buffer_ptr.add(px_idx), black);
+			}
 		}
 	}
 
-	pub fn poll(&mut self) {
-		if DMA_READY.load(Ordering::Relaxed) {
-			DMA_READY.store(false, Ordering::Relaxed);
-			let current_line_num = CURRENT_DISPLAY_LINE.load(Ordering::Relaxed);
-			if current_line_num == 0 {
-				trace!("Frame {}", self.frame_count);
-				self.frame_count += 1;
+	/// Draw one scan-line of a full-width monochrome bitmap.
+	///
+	/// Each framebuffer byte is eight pixels, left-most in the top bit. A
+	/// clear bit is drawn in palette entry 0 (the background) and a set bit
+	/// in palette entry 1 (the foreground). `mode_line` is the framebuffer
+	/// row. If the mode doubles every pixel, we only draw the left half of
+	/// the scan-line (see `double_pixels`).
+	fn render_scanline_chunky1(&mut self, mode_line: u16, scan_line_buffer: &mut LineBuffer) {
+		let fb = FRAMEBUFFER.load(Ordering::Relaxed);
+		if fb.is_null() {
+			// Nothing to show yet
+			Self::render_scanline_black(scan_line_buffer);
+			return;
+		}
+		// Same trick as the text modes - convert two pixels at a time with
+		// a look-up table, except this table follows the palette.
+		let (bg, fg) = unsafe { (VIDEO_PALETTE[0], VIDEO_PALETTE[1]) };
+		let lookup = [
+			RGBPair::from_pixels(bg, bg),
+			RGBPair::from_pixels(bg, fg),
+			RGBPair::from_pixels(fg, bg),
+			RGBPair::from_pixels(fg, fg),
+		];
+		// Four pixel pairs per byte
+		let bytes_per_line = if unsafe { VIDEO_MODE.is_horiz_2x() } {
+			NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed) / 8
+		} else {
+			NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed) / 4
+		};
+		// Note (unsafe): The OS promised us the framebuffer was big enough
+		// for this mode.
+		let row_ptr = unsafe { fb.add(mode_line as usize * bytes_per_line) };
+		let scan_line_buffer_ptr = scan_line_buffer.pixels.as_mut_ptr();
+		let mut px_idx = 0;
+		for byte_idx in 0..bytes_per_line {
+			let mono_pixels = unsafe { *row_ptr.add(byte_idx) } as usize;
+			unsafe {
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.add(px_idx),
+					lookup[(mono_pixels >> 6) & 3],
+				);
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.add(px_idx + 1),
+					lookup[(mono_pixels >> 4) & 3],
+				);
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.add(px_idx + 2),
+					lookup[(mono_pixels >> 2) & 3],
+				);
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.add(px_idx + 3),
+					lookup[mono_pixels & 3],
+				);
 			}
+			px_idx += 4;
+		}
+	}
 
-			// new line - pick a buffer to draw into (not the one that is currently rendering!)
-			let scan_line_buffer = unsafe {
-				if (current_line_num & 1) == 0 {
-					&mut PIXEL_DATA_BUFFER_ODD
-				} else {
-					&mut PIXEL_DATA_BUFFER_EVEN
-				}
-			};
+	/// Draw one scan-line of a 16-colour bitmap with every pixel doubled.
+	///
+	/// Each framebuffer byte is two pixels, left-most in the top nibble. Each
+	/// nibble is looked up in the first 16 entries of `VIDEO_PALETTE`. A
+	/// pixel pair is exactly one doubled pixel, so each byte is two
+	/// `RGBPair`s.
+	fn render_scanline_chunky4(&mut self, mode_line: u16, scan_line_buffer: &mut LineBuffer) {
+		let fb = FRAMEBUFFER.load(Ordering::Relaxed);
+		if fb.is_null() {
+			// Nothing to show yet
+			Self::render_scanline_black(scan_line_buffer);
+			return;
+		}
+		// Doubling the pixels here means the inner loop is just two look-ups
+		// per byte.
+		let mut lookup = [RGBPair::from_pixels(colours::BLACK, colours::BLACK); 16];
+		for (pair, colour) in lookup.iter_mut().zip(unsafe { VIDEO_PALETTE.iter() }) {
+			*pair = RGBPair::from_pixels(*colour, *colour);
+		}
+		let bytes_per_line = NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed) / 2;
+		let row_offset = mode_line as usize * bytes_per_line;
+		// Note (unsafe): The OS promised us the framebuffer was big enough
+		// for this mode.
+		let row_ptr = unsafe { fb.add(row_offset) };
+		let scan_line_buffer_ptr = scan_line_buffer.pixels.as_mut_ptr();
+		let mut px_idx = 0;
+		for byte_idx in 0..bytes_per_line {
+			let pixels = unsafe { *row_ptr.add(byte_idx) } as usize;
+			// Hopefully the `& 15` elides the panic calls.
+			unsafe {
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.add(px_idx),
+					lookup[(pixels >> 4) & 15],
+				);
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.add(px_idx + 1),
+					lookup[pixels & 15],
+				);
+			}
+			px_idx += 2;
+		}
+	}
 
-			let font = match unsafe { VIDEO_MODE.format() } {
-				crate::common::video::Format::Text8x16 => &font16::FONT,
-				crate::common::video::Format::Text8x8 => &font8::FONT,
-				_ => {
-					return;
-				}
-			};
+	/// Draw one scan-line of a full-width 16-colour bitmap.
+	///
+	/// As `render_scanline_chunky4`, except each byte is a single pixel
+	/// pair. This is only offered when interlacing, as it takes too long to
+	/// draw on every scan-line.
+	fn render_scanline_chunky4_full(&mut self, mode_line: u16, scan_line_buffer: &mut LineBuffer) {
+		let fb = FRAMEBUFFER.load(Ordering::Relaxed);
+		if fb.is_null() {
+			// Nothing to show yet
+			Self::render_scanline_black(scan_line_buffer);
+			return;
+		}
+		let scan_line_buffer_ptr = scan_line_buffer.pixels.as_mut_ptr();
+		let pixel_pairs = NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed);
+		let row_offset = mode_line as usize * pixel_pairs;
+		// Note (unsafe): The OS promised us the framebuffer was big enough
+		// for this mode.
+		let row_ptr = unsafe { fb.add(row_offset) };
+		for px_idx in 0..pixel_pairs {
+			unsafe {
+				let pixels = *row_ptr.add(px_idx) as usize;
+				// Hopefully the `& 15` elides the panic calls.
+				let left = VIDEO_PALETTE[(pixels >> 4) & 15];
+				let right = VIDEO_PALETTE[pixels & 15];
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.add(px_idx),
+					RGBPair::from_pixels(left, right),
+				);
+			}
+		}
+	}
 
-			let num_rows = NUM_TEXT_ROWS.load(Ordering::Relaxed);
-			let num_cols = NUM_TEXT_COLS.load(Ordering::Relaxed);
-
-			// Convert our position in scan-lines to a text row, and a line within each glyph on that row
-			let text_row = current_line_num as usize / font.height;
-			let font_row = current_line_num as usize % font.height;
-
-			if text_row < num_rows {
-				// Note (unsafe): We could stash the char array inside `self`
-				// but at some point we are going to need one CPU rendering
-				// the text, and the other CPU running code and writing to
-				// the buffer. This might be Undefined Behaviour, but
-				// unfortunately real-time video is all about shared mutable
-				// state. At least our platform is fixed, so we can simply
-				// test if it works, for some given version of the Rust compiler.
-				let row_slice = unsafe {
-					&GLYPH_ATTR_ARRAY[(text_row * num_cols)..((text_row + 1) * num_cols)]
-				};
-				// Every font look-up we are about to do for this row will
-				// involve offsetting by the row within each glyph. As this
-				// is the same for every glyph on this row, we calculate a
-				// new pointer once, in advance, and save ourselves an
-				// addition each time around the loop.
-				let font_ptr = unsafe { font.data.as_ptr().add(font_row) };
-
-				// Get a pointer into our scan-line buffer
-				let scan_line_buffer_ptr = scan_line_buffer.pixels.as_mut_ptr();
-				let mut px_idx = 0;
-
-				// Convert from characters to coloured pixels, using the font as a look-up table.
-				for glyphattr in row_slice.iter() {
-					let index = (glyphattr.glyph().0 as isize) * font.height as isize;
-					// Note (unsafe): We use pointer arithmetic here because we
-					// can't afford a bounds-check on an array. This is safe
-					// because the font is `256 * width` bytes long and we can't
-					// index more than `255 * width` bytes into it.
-					let mono_pixels = unsafe { *font_ptr.offset(index) } as usize;
-					// Convert from eight mono pixels in one byte to four RGB
-					// pairs. Hopefully the `& 3` elides the panic calls.
-					unsafe {
-						core::ptr::write_volatile(
-							scan_line_buffer_ptr.offset(px_idx),
-							self.lookup[(mono_pixels >> 6) & 3],
-						);
-						core::ptr::write_volatile(
-							scan_line_buffer_ptr.offset(px_idx + 1),
-							self.lookup[(mono_pixels >> 4) & 3],
-						);
-						core::ptr::write_volatile(
-							scan_line_buffer_ptr.offset(px_idx + 2),
-							self.lookup[(mono_pixels >> 2) & 3],
-						);
-						core::ptr::write_volatile(
-							scan_line_buffer_ptr.offset(px_idx + 3),
-							self.lookup[mono_pixels & 3],
-						);
-					}
-					px_idx += 4;
-				}
+	/// Draw one scan-line of a 256-colour bitmap with every pixel doubled.
+	///
+	/// Each framebuffer byte is one pixel, which we look up in
+	/// `VIDEO_PALETTE`. A pixel pair is exactly one doubled pixel, so there is
+	/// one `RGBPair` per byte.
+	fn render_scanline_chunky8(&mut self, mode_line: u16, scan_line_buffer: &mut LineBuffer) {
+		let fb = FRAMEBUFFER.load(Ordering::Relaxed);
+		if fb.is_null() {
+			// Nothing to show yet
+			Self::render_scanline_black(scan_line_buffer);
+			return;
+		}
+		let scan_line_buffer_ptr = scan_line_buffer.pixels.as_mut_ptr();
+		let pixel_pairs = NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed);
+		let row_offset = mode_line as usize * pixel_pairs;
+		// Note (unsafe): The OS promised us the framebuffer was big enough
+		// for this mode. As with the text buffer, we accept that Core 0 may
+		// be writing to it while we read it.
+		let row_ptr = unsafe { fb.add(row_offset) };
+		for px_idx in 0..pixel_pairs {
+			unsafe {
+				// A `u8` index into a 256 entry array needs no bounds check
+				let colour = VIDEO_PALETTE[*row_ptr.add(px_idx) as usize];
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.add(px_idx),
+					RGBPair::from_pixels(colour, colour),
+				);
+			}
+		}
+	}
+
+	/// Draw one scan-line of a full-width 256-colour bitmap.
+	///
+	/// As `render_scanline_chunky8`, except it takes two bytes to make each
+	/// pixel pair. This is only offered when interlacing, as it takes too
+	/// long to draw on every scan-line.
+	fn render_scanline_chunky8_full(&mut self, mode_line: u16, scan_line_buffer: &mut LineBuffer) {
+		let fb = FRAMEBUFFER.load(Ordering::Relaxed);
+		if fb.is_null() {
+			// Nothing to show yet
+			Self::render_scanline_black(scan_line_buffer);
+			return;
+		}
+		let scan_line_buffer_ptr = scan_line_buffer.pixels.as_mut_ptr();
+		let pixel_pairs = NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed);
+		let row_offset = mode_line as usize * pixel_pairs * 2;
+		// Note (unsafe): The OS promised us the framebuffer was big enough
+		// for this mode. As with the text buffer, we accept that Core 0 may
+		// be writing to it while we read it.
+		let row_ptr = unsafe { fb.add(row_offset) };
+		for px_idx in 0..pixel_pairs {
+			unsafe {
+				// A `u8` index into a 256 entry array needs no bounds check
+				let left = VIDEO_PALETTE[*row_ptr.add(px_idx * 2) as usize];
+				let right = VIDEO_PALETTE[*row_ptr.add((px_idx * 2) + 1) as usize];
+				core::ptr::write_volatile(
+					scan_line_buffer_ptr.add(px_idx),
+					RGBPair::from_pixels(left, right),
+				);
 			}
 		}
 	}
@@ -913,22 +3652,21 @@ impl TextConsole {
 		TextConsole {
 			current_row: AtomicU16::new(0),
 			current_col: AtomicU16::new(0),
+			current_attr: AtomicU8::new(DEFAULT_ATTR.0),
 			text_buffer: AtomicPtr::new(core::ptr::null_mut()),
+			ansi_parser: AtomicU32::new(0),
 		}
 	}
 
 	/// Update the text buffer we are using.
 	///
 	/// Will reset the cursor. The screen is not cleared.
-	pub fn set_text_buffer(
-		&self,
-		text_buffer: &'static mut [GlyphAttr; MAX_TEXT_ROWS * MAX_TEXT_COLS],
-	) {
+	pub fn set_text_buffer(&self, text_buffer: &'static mut [GlyphAttr; TEXT_BUFFER_CELLS]) {
 		self.text_buffer
 			.store(text_buffer.as_mut_ptr(), Ordering::Relaxed)
 	}
 
-	/// Place a single Code Page 850 encoded 8-bit character on the screen.
+	/// Place a single glyph from the current font on the screen.
 	///
 	/// Adjusts the current row and column automatically. Also understands
 	/// Carriage Return and New Line bytes.
@@ -946,6 +3684,16 @@ impl TextConsole {
 		}
 	}
 
+	/// Set the attribute used for any text written from now on.
+	pub fn set_attr(&self, attr: Attr) {
+		self.current_attr.store(attr.0, Ordering::Relaxed);
+	}
+
+	/// Get the attribute used for any text written from now on.
+	pub fn attr(&self) -> Attr {
+		Attr(self.current_attr.load(Ordering::Relaxed))
+	}
+
 	/// Moves the text cursor to the specified row and column.
 	///
 	/// If a value is out of bounds, the cursor is not moved in that axis.
@@ -958,155 +3706,98 @@ impl TextConsole {
 		}
 	}
 
-	/// Convert a Unicode Scalar Value to a font glyph.
-	///
-	/// Zero-width and modifier Unicode Scalar Values (e.g. `U+0301 COMBINING,
-	/// ACCENT`) are not supported. Normalise your Unicode before calling
-	/// this function.
-	fn map_char_to_glyph(input: char) -> Glyph {
-		// This fixed table only works for the default font. When we support
-		// changing font, we will need to plug-in a different table for each font.
-		let index = match input {
-			'\u{0000}'..='\u{007F}' => input as u8,
-			'\u{00A0}' => 255, // NBSP
-			'\u{00A1}' => 173, // ¡
-			'\u{00A2}' => 189, // ¢
-			'\u{00A3}' => 156, // £
-			'\u{00A4}' => 207, // ¤
-			'\u{00A5}' => 190, // ¥
-			'\u{00A6}' => 221, // ¦
-			'\u{00A7}' => 245, // §
-			'\u{00A8}' => 249, // ¨
-			'\u{00A9}' => 184, // ©
-			'\u{00AA}' => 166, // ª
-			'\u{00AB}' => 174, // «
-			'\u{00AC}' => 170, // ¬
-			'\u{00AD}' => 240, // SHY
-			'\u{00AE}' => 169, // ®
-			'\u{00AF}' => 238, // ¯
-			'\u{00B0}' => 248, // °
-			'\u{00B1}' => 241, // ±
-			'\u{00B2}' => 253, // ²
-			'\u{00B3}' => 252, // ³
-			'\u{00B4}' => 239, // ´
-			'\u{00B5}' => 230, // µ
-			'\u{00B6}' => 244, // ¶
-			'\u{00B7}' => 250, // ·
-			'\u{00B8}' => 247, // ¸
-			'\u{00B9}' => 251, // ¹
-			'\u{00BA}' => 167, // º
-			'\u{00BB}' => 175, // »
-			'\u{00BC}' => 172, // ¼
-			'\u{00BD}' => 171, // ½
-			'\u{00BE}' => 243, // ¾
-			'\u{00BF}' => 168, // ¿
-			'\u{00C0}' => 183, // À
-			'\u{00C1}' => 181, // Á
-			'\u{00C2}' => 182, // Â
-			'\u{00C3}' => 199, // Ã
-			'\u{00C4}' => 142, // Ä
-			'\u{00C5}' => 143, // Å
-			'\u{00C6}' => 146, // Æ
-			'\u{00C7}' => 128, // Ç
-			'\u{00C8}' => 212, // È
-			'\u{00C9}' => 144, // É
-			'\u{00CA}' => 210, // Ê
-			'\u{00CB}' => 211, // Ë
-			'\u{00CC}' => 222, // Ì
-			'\u{00CD}' => 214, // Í
-			'\u{00CE}' => 215, // Î
-			'\u{00CF}' => 216, // Ï
-			'\u{00D0}' => 209, // Ð
-			'\u{00D1}' => 165, // Ñ
-			'\u{00D2}' => 227, // Ò
-			'\u{00D3}' => 224, // Ó
-			'\u{00D4}' => 226, // Ô
-			'\u{00D5}' => 229, // Õ
-			'\u{00D6}' => 153, // Ö
-			'\u{00D7}' => 158, // ×
-			'\u{00D8}' => 157, // Ø
-			'\u{00D9}' => 235, // Ù
-			'\u{00DA}' => 233, // Ú
-			'\u{00DB}' => 234, // Û
-			'\u{00DC}' => 154, // Ü
-			'\u{00DD}' => 237, // Ý
-			'\u{00DE}' => 232, // Þ
-			'\u{00DF}' => 225, // ß
-			'\u{00E0}' => 133, // à
-			'\u{00E1}' => 160, // á
-			'\u{00E2}' => 131, // â
-			'\u{00E3}' => 198, // ã
-			'\u{00E4}' => 132, // ä
-			'\u{00E5}' => 134, // å
-			'\u{00E6}' => 145, // æ
-			'\u{00E7}' => 135, // ç
-			'\u{00E8}' => 138, // è
-			'\u{00E9}' => 130, // é
-			'\u{00EA}' => 136, // ê
-			'\u{00EB}' => 137, // ë
-			'\u{00EC}' => 141, // ì
-			'\u{00ED}' => 161, // í
-			'\u{00EE}' => 140, // î
-			'\u{00EF}' => 139, // ï
-			'\u{00F0}' => 208, // ð
-			'\u{00F1}' => 164, // ñ
-			'\u{00F2}' => 149, // ò
-			'\u{00F3}' => 162, // ó
-			'\u{00F4}' => 147, // ô
-			'\u{00F5}' => 228, // õ
-			'\u{00F6}' => 148, // ö
-			'\u{00F7}' => 246, // ÷
-			'\u{00F8}' => 155, // ø
-			'\u{00F9}' => 151, // ù
-			'\u{00FA}' => 163, // ú
-			'\u{00FB}' => 150, // û
-			'\u{00FC}' => 129, // ü
-			'\u{00FD}' => 236, // ý
-			'\u{00FE}' => 231, // þ
-			'\u{00FF}' => 152, // ÿ
-			'\u{0131}' => 213, // ı
-			'\u{0192}' => 159, // ƒ
-			'\u{2017}' => 242, // ‗
-			'\u{2500}' => 196, // ─
-			'\u{2502}' => 179, // │
-			'\u{250C}' => 218, // ┌
-			'\u{2510}' => 191, // ┐
-			'\u{2514}' => 192, // └
-			'\u{2518}' => 217, // ┘
-			'\u{251C}' => 195, // ├
-			'\u{2524}' => 180, // ┤
-			'\u{252C}' => 194, // ┬
-			'\u{2534}' => 193, // ┴
-			'\u{253C}' => 197, // ┼
-			'\u{2550}' => 205, // ═
-			'\u{2551}' => 186, // ║
-			'\u{2554}' => 201, // ╔
-			'\u{2557}' => 187, // ╗
-			'\u{255A}' => 200, // ╚
-			'\u{255D}' => 188, // ╝
-			'\u{2560}' => 204, // ╠
-			'\u{2563}' => 185, // ╣
-			'\u{2566}' => 203, // ╦
-			'\u{2569}' => 202, // ╩
-			'\u{256C}' => 206, // ╬
-			'\u{2580}' => 223, // ▀
-			'\u{2584}' => 220, // ▄
-			'\u{2588}' => 219, // █
-			'\u{2591}' => 176, // ░
-			'\u{2592}' => 177, // ▒
-			'\u{2593}' => 178, // ▓
-			'\u{25A0}' => 254, // ■
-			_ => b'?',
-		};
-		Glyph(index)
+	/// Carry out a complete ANSI Control Sequence, which ended with
+	/// `final_byte`.
+	fn handle_csi(
+		&self,
+		final_byte: char,
+		params: &[u8],
+		buffer: *mut GlyphAttr,
+		row: &mut u16,
+		col: &mut u16,
+	) {
+		let num_rows = NUM_TEXT_ROWS.load(Ordering::Relaxed) as u16;
+		let num_cols = NUM_TEXT_COLS.load(Ordering::Relaxed) as u16;
+		if num_rows == 0 || num_cols == 0 {
+			return;
+		}
+		// The mode may have changed under us
+		*row = (*row).min(num_rows - 1);
+		*col = (*col).min(num_cols - 1);
+		let first = u16::from(params[0]);
+		// Movements count from 1, and zero means one
+		let count = first.max(1);
+		let cursor = usize::from(*row) * usize::from(num_cols) + usize::from(*col);
+		let row_start = usize::from(*row) * usize::from(num_cols);
+		let row_end = row_start + usize::from(num_cols);
+		let screen_end = usize::from(num_rows) * usize::from(num_cols);
+		match final_byte {
+			'A' => *row = row.saturating_sub(count),
+			'B' => *row = (*row + count).min(num_rows - 1),
+			'C' => *col = (*col + count).min(num_cols - 1),
+			'D' => *col = col.saturating_sub(count),
+			'H' | 'f' => {
+				*row = (count - 1).min(num_rows - 1);
+				*col = (u16::from(params[1]).max(1) - 1).min(num_cols - 1);
+			}
+			'J' => match first {
+				0 => self.blank(buffer, cursor..screen_end),
+				1 => self.blank(buffer, 0..cursor + 1),
+				_ => self.blank(buffer, 0..screen_end),
+			},
+			'K' => match first {
+				0 => self.blank(buffer, cursor..row_end),
+				1 => self.blank(buffer, row_start..cursor + 1),
+				_ => self.blank(buffer, row_start..row_end),
+			},
+			'm' => {
+				let mut attr = self.attr().0;
+				for param in params.iter() {
+					attr = Self::apply_sgr(attr, *param);
+				}
+				self.set_attr(Attr(attr));
+			}
+			_ => {}
+		}
+	}
+
+	/// Apply one parameter of an ANSI Select Graphic Rendition sequence to a
+	/// VGA attribute byte.
+	fn apply_sgr(attr: u8, param: u8) -> u8 {
+		match param {
+			0 => DEFAULT_ATTR.0,
+			1 => attr | 0x08,
+			22 => attr & !0x08,
+			5 => attr | ATTR_BLINK,
+			25 => attr & !ATTR_BLINK,
+			7 => (attr & (ATTR_BLINK | 0x08)) | ((attr & 0x07) << 4) | ((attr >> 4) & 0x07),
+			30..=37 => (attr & 0xF8) | ANSI_TO_VGA[usize::from(param - 30)],
+			39 => (attr & 0xF8) | (DEFAULT_ATTR.0 & 0x07),
+			40..=47 => (attr & 0x8F) | (ANSI_TO_VGA[usize::from(param - 40)] << 4),
+			49 => (attr & 0x8F) | (DEFAULT_ATTR.0 & 0x70),
+			90..=97 => (attr & 0xF0) | 0x08 | ANSI_TO_VGA[usize::from(param - 90)],
+			_ => attr,
+		}
+	}
+
+	/// Fill the given cells with spaces, in the current attribute.
+	fn blank(&self, buffer: *mut GlyphAttr, cells: core::ops::Range<usize>) {
+		mark_dirty();
+		let blank = GlyphAttr::new(Glyph(b' '), self.attr());
+		for offset in cells {
+			// Note (safety): The callers only give us cells on the screen.
+			unsafe { buffer.add(offset).write_volatile(blank) };
+		}
 	}
 
 	/// Put a single character at a specified point on screen.
 	///
 	/// The character is relative to the current font.
 	fn write_at(&self, glyph: Glyph, buffer: *mut GlyphAttr, row: &mut u16, col: &mut u16) {
+		mark_dirty();
 		let num_rows = NUM_TEXT_ROWS.load(Ordering::Relaxed);
 		let num_cols = NUM_TEXT_COLS.load(Ordering::Relaxed);
+		let attr = self.attr();
 
 		if glyph.0 == b'\r' {
 			*col = 0;
@@ -1119,7 +3810,7 @@ impl TextConsole {
 			unsafe {
 				buffer
 					.add(offset)
-					.write_volatile(GlyphAttr::new(glyph, Attr(0)))
+					.write_volatile(GlyphAttr::new(glyph, attr))
 			};
 			*col += 1;
 		}
@@ -1145,7 +3836,7 @@ impl TextConsole {
 				unsafe {
 					buffer
 						.add(offset)
-						.write_volatile(GlyphAttr::new(Glyph(b' '), Attr(0)))
+						.write_volatile(GlyphAttr::new(Glyph(b' '), attr))
 				};
 			}
 		}
@@ -1163,10 +3854,38 @@ impl core::fmt::Write for &TextConsole {
 		let buffer = self.text_buffer.load(Ordering::Relaxed);
 
 		if !buffer.is_null() {
+			let mut parser = AnsiParser::unpack(self.ansi_parser.load(Ordering::Relaxed));
 			for ch in s.chars() {
-				let b = TextConsole::map_char_to_glyph(ch);
-				self.write_at(b, buffer, &mut row, &mut col);
+				match parser.state {
+					AnsiState::Normal if ch == '\x1b' => parser.state = AnsiState::Escape,
+					AnsiState::Normal if ch == '\x07' => crate::audio::beep(),
+					AnsiState::Normal => {
+						let b = charmap::char_to_glyph(ch);
+						self.write_at(b, buffer, &mut row, &mut col);
+					}
+					AnsiState::Escape if ch == '[' => parser = AnsiParser::csi(),
+					// We don't do any other kind of escape sequence
+					AnsiState::Escape => parser.state = AnsiState::Normal,
+					AnsiState::Csi => match ch {
+						'0'..='9' => parser.push_digit(ch as u8 - b'0'),
+						';' => parser.index = parser.index.saturating_add(1),
+						'\x40'..='\x7E' => {
+							let count = (usize::from(parser.index) + 1).min(ANSI_MAX_PARAMS);
+							self.handle_csi(
+								ch,
+								&parser.params[0..count],
+								buffer,
+								&mut row,
+								&mut col,
+							);
+							parser.state = AnsiState::Normal;
+						}
+						// Private and intermediate bytes (e.g. `?`) are ignored
+						_ => {}
+					},
+				}
 			}
+			self.ansi_parser.store(parser.pack(), Ordering::Relaxed);
 
 			// Push back to global state
 			self.current_row.store(row as u16, Ordering::Relaxed);
@@ -1177,6 +3896,55 @@ impl core::fmt::Write for &TextConsole {
 	}
 }
 
+impl AnsiParser {
+	/// A parser at the start of a Control Sequence, with no parameters yet.
+	const fn csi() -> AnsiParser {
+		AnsiParser {
+			state: AnsiState::Csi,
+			index: 0,
+			params: [0; ANSI_MAX_PARAMS],
+		}
+	}
+
+	/// Add a decimal digit to the current parameter.
+	fn push_digit(&mut self, digit: u8) {
+		if let Some(param) = self.params.get_mut(usize::from(self.index)) {
+			*param = param.saturating_mul(10).saturating_add(digit);
+		}
+	}
+
+	/// Squash the parser into a `u32`, as `[params..., index << 2 | state]`.
+	fn pack(&self) -> u32 {
+		let state = match self.state {
+			AnsiState::Normal => 0,
+			AnsiState::Escape => 1,
+			AnsiState::Csi => 2,
+		};
+		let mut value = u32::from((self.index.min(63) << 2) | state);
+		for (idx, param) in self.params.iter().enumerate() {
+			value |= u32::from(*param) << (8 * (idx + 1));
+		}
+		value
+	}
+
+	/// Undo `pack`.
+	fn unpack(value: u32) -> AnsiParser {
+		let mut params = [0; ANSI_MAX_PARAMS];
+		for (idx, param) in params.iter_mut().enumerate() {
+			*param = (value >> (8 * (idx + 1))) as u8;
+		}
+		AnsiParser {
+			state: match value & 3 {
+				1 => AnsiState::Escape,
+				2 => AnsiState::Csi,
+				_ => AnsiState::Normal,
+			},
+			index: ((value >> 2) & 0x3F) as u8,
+			params,
+		}
+	}
+}
+
 impl LineBuffer {
 	/// Convert the line buffer to a 32-bit address that the DMA engine understands.
 	fn as_ptr(&self) -> u32 {
@@ -1184,7 +3952,44 @@ impl LineBuffer {
 	}
 }
 
+impl BlankLineBuffer {
+	/// Convert the line buffer to a 32-bit address that the DMA engine understands.
+	fn as_ptr(&self) -> u32 {
+		self as *const _ as usize as u32
+	}
+}
+
+impl TimingPreset {
+	/// Convert from the value stored in the configuration.
+	pub const fn from_u8(value: u8) -> Option<TimingPreset> {
+		match value {
+			0 => Some(TimingPreset::Standard),
+			1 => Some(TimingPreset::Rgb15kHz240),
+			2 => Some(TimingPreset::Rgb15kHz256),
+			_ => None,
+		}
+	}
+
+	/// The timings for this preset, to give to `set_custom_timing`, or
+	/// `None` for the standard timings.
+	pub const fn mode_line(self) -> Option<ModeLine> {
+		match self {
+			TimingPreset::Standard => None,
+			TimingPreset::Rgb15kHz240 => Some(MODE_LINE_320X240_15KHZ),
+			TimingPreset::Rgb15kHz256 => Some(MODE_LINE_320X256_15KHZ),
+		}
+	}
+}
+
 impl SyncPolarity {
+	const fn from_flag(positive: bool) -> SyncPolarity {
+		if positive {
+			SyncPolarity::Positive
+		} else {
+			SyncPolarity::Negative
+		}
+	}
+
 	const fn enabled(&self) -> bool {
 		match self {
 			SyncPolarity::Positive => true,
@@ -1204,21 +4009,33 @@ impl ScanlineTimingBuffer {
 	/// Create a timing buffer for each scan-line in the V-Sync visible portion.
 	///
 	/// The timings are in the order (front-porch, sync, back-porch, visible) and are in pixel clocks.
+	/// Each pixel clock is `cycles_per_pixel` PIO clocks.
 	const fn new_v_visible(
 		hsync: SyncPolarity,
 		vsync: SyncPolarity,
 		timings: (u32, u32, u32, u32),
+		cycles_per_pixel: u32,
 	) -> ScanlineTimingBuffer {
 		ScanlineTimingBuffer {
 			data: [
 				// Front porch (as per the spec)
-				Self::make_timing(timings.0 * 5, hsync.disabled(), vsync.disabled(), false),
+				Self::make_timing(
+					timings.0 * cycles_per_pixel,
+					hsync.disabled(),
+					vsync.disabled(),
+					false,
+				),
 				// Sync pulse (as per the spec)
-				Self::make_timing(timings.1 * 5, hsync.enabled(), vsync.disabled(), false),
+				Self::make_timing(
+					timings.1 * cycles_per_pixel,
+					hsync.enabled(),
+					vsync.disabled(),
+					false,
+				),
 				// Back porch. Adjusted by a few clocks to account for interrupt +
 				// PIO SM start latency.
 				Self::make_timing(
-					(timings.2 * 5) - 5,
+					(timings.2 * cycles_per_pixel) - 5,
 					hsync.disabled(),
 					vsync.disabled(),
 					false,
@@ -1227,7 +4044,7 @@ impl ScanlineTimingBuffer {
 				// moving. Adjusted to compensate for changes made to previous
 				// period to ensure scan-line remains at correct length.
 				Self::make_timing(
-					(timings.3 * 5) + 5,
+					(timings.3 * cycles_per_pixel) + 5,
 					hsync.disabled(),
 					vsync.disabled(),
 					true,
@@ -1241,17 +4058,38 @@ impl ScanlineTimingBuffer {
 		hsync: SyncPolarity,
 		vsync: SyncPolarity,
 		timings: (u32, u32, u32, u32),
+		cycles_per_pixel: u32,
 	) -> ScanlineTimingBuffer {
 		ScanlineTimingBuffer {
 			data: [
 				// Front porch (as per the spec)
-				Self::make_timing(timings.0 * 5, hsync.disabled(), vsync.disabled(), false),
+				Self::make_timing(
+					timings.0 * cycles_per_pixel,
+					hsync.disabled(),
+					vsync.disabled(),
+					false,
+				),
 				// Sync pulse (as per the spec)
-				Self::make_timing(timings.1 * 5, hsync.enabled(), vsync.disabled(), false),
+				Self::make_timing(
+					timings.1 * cycles_per_pixel,
+					hsync.enabled(),
+					vsync.disabled(),
+					false,
+				),
 				// Back porch.
-				Self::make_timing(timings.2 * 5, hsync.disabled(), vsync.disabled(), false),
+				Self::make_timing(
+					timings.2 * cycles_per_pixel,
+					hsync.disabled(),
+					vsync.disabled(),
+					false,
+				),
 				// Visible portion.
-				Self::make_timing(timings.3 * 5, hsync.disabled(), vsync.disabled(), false),
+				Self::make_timing(
+					timings.3 * cycles_per_pixel,
+					hsync.disabled(),
+					vsync.disabled(),
+					false,
+				),
 			],
 		}
 	}
@@ -1261,24 +4099,45 @@ impl ScanlineTimingBuffer {
 		hsync: SyncPolarity,
 		vsync: SyncPolarity,
 		timings: (u32, u32, u32, u32),
+		cycles_per_pixel: u32,
 	) -> ScanlineTimingBuffer {
 		ScanlineTimingBuffer {
 			data: [
 				// Front porch (as per the spec)
-				Self::make_timing(timings.0 * 5, hsync.disabled(), vsync.enabled(), false),
+				Self::make_timing(
+					timings.0 * cycles_per_pixel,
+					hsync.disabled(),
+					vsync.enabled(),
+					false,
+				),
 				// Sync pulse (as per the spec)
-				Self::make_timing(timings.1 * 5, hsync.enabled(), vsync.enabled(), false),
+				Self::make_timing(
+					timings.1 * cycles_per_pixel,
+					hsync.enabled(),
+					vsync.enabled(),
+					false,
+				),
 				// Back porch.
-				Self::make_timing(timings.2 * 5, hsync.disabled(), vsync.enabled(), false),
+				Self::make_timing(
+					timings.2 * cycles_per_pixel,
+					hsync.disabled(),
+					vsync.enabled(),
+					false,
+				),
 				// Visible portion.
-				Self::make_timing(timings.3 * 5, hsync.disabled(), vsync.enabled(), false),
+				Self::make_timing(
+					timings.3 * cycles_per_pixel,
+					hsync.disabled(),
+					vsync.enabled(),
+					false,
+				),
 			],
 		}
 	}
 
 	/// Generate a 32-bit value we can send to the Timing FIFO.
 	///
-	/// * `period` - The length of this portion of the scan-line, in system clock ticks
+	/// * `period` - The length of this portion of the scan-line, in PIO clock ticks
 	/// * `hsync` - true if the H-Sync pin should be high during this period, else false
 	/// * `vsync` - true if the H-Sync pin should be high during this period, else false
 	/// * `raise_irq` - true the timing statemachine should raise an IRQ at the start of this period
@@ -1326,64 +4185,295 @@ impl ScanlineTimingBuffer {
 }
 
 impl TimingBuffer {
+	/// Hold the sync pins still, if the power state says so.
+	///
+	/// Each sync pin is held at the level it has at the start of a visible
+	/// line, which is its idle level (even with composite sync).
+	fn apply_power_state(&mut self, state: PowerState) {
+		let (stop_hsync, stop_vsync) = match state {
+			PowerState::On | PowerState::Blank => (false, false),
+			PowerState::Standby => (true, false),
+			PowerState::Suspend => (false, true),
+			PowerState::Off => (true, true),
+		};
+		let mut mask = 0;
+		if stop_hsync {
+			mask |= 1 << 0;
+		}
+		if stop_vsync {
+			mask |= 1 << 1;
+		}
+		let idle = self.visible_line.data[0] & mask;
+		for buffer in [
+			&mut self.visible_line,
+			&mut self.vblank_porch_buffer,
+			&mut self.vblank_sync_buffer,
+		]
+		.iter_mut()
+		{
+			for word in buffer.data.iter_mut() {
+				*word = (*word & !mask) | idle;
+			}
+		}
+	}
+
 	/// Make a timing buffer suitable for 640 x 400 @ 70 Hz
-	pub const fn make_640x400() -> TimingBuffer {
+	pub const fn make_640x400(cycles_per_pixel: u32) -> TimingBuffer {
 		TimingBuffer {
 			visible_line: ScanlineTimingBuffer::new_v_visible(
 				SyncPolarity::Negative,
 				SyncPolarity::Positive,
 				(16, 96, 48, 640),
+				cycles_per_pixel,
 			),
 			vblank_porch_buffer: ScanlineTimingBuffer::new_v_porch(
 				SyncPolarity::Negative,
 				SyncPolarity::Positive,
 				(16, 96, 48, 640),
+				cycles_per_pixel,
 			),
 			vblank_sync_buffer: ScanlineTimingBuffer::new_v_pulse(
 				SyncPolarity::Negative,
 				SyncPolarity::Positive,
 				(16, 96, 48, 640),
+				cycles_per_pixel,
 			),
 			visible_lines_ends_at: 399,
 			front_porch_end_at: 399 + 12,
 			sync_pulse_ends_at: 399 + 12 + 2,
 			back_porch_ends_at: 399 + 12 + 2 + 35,
+			pixel_clock_hz: VGA_PIXEL_CLOCK_HZ,
+			cycles_per_pixel,
+			pixel_pairs: 640 / 2,
 		}
 	}
 
 	/// Make a timing buffer suitable for 640 x 480 @ 60 Hz
-	pub const fn make_640x480() -> TimingBuffer {
+	pub const fn make_640x480(cycles_per_pixel: u32) -> TimingBuffer {
 		TimingBuffer {
 			visible_line: ScanlineTimingBuffer::new_v_visible(
 				SyncPolarity::Negative,
 				SyncPolarity::Negative,
 				(16, 96, 48, 640),
+				cycles_per_pixel,
 			),
 			vblank_porch_buffer: ScanlineTimingBuffer::new_v_porch(
 				SyncPolarity::Negative,
 				SyncPolarity::Negative,
 				(16, 96, 48, 640),
+				cycles_per_pixel,
 			),
 			vblank_sync_buffer: ScanlineTimingBuffer::new_v_pulse(
 				SyncPolarity::Negative,
 				SyncPolarity::Negative,
 				(16, 96, 48, 640),
+				cycles_per_pixel,
 			),
 			visible_lines_ends_at: 479,
 			front_porch_end_at: 479 + 10,
 			sync_pulse_ends_at: 479 + 10 + 2,
 			back_porch_ends_at: 479 + 10 + 2 + 33,
+			pixel_clock_hz: VGA_PIXEL_CLOCK_HZ,
+			cycles_per_pixel,
+			pixel_pairs: 640 / 2,
+		}
+	}
+
+	/// Make a timing buffer suitable for 800 x 600 @ 56 Hz
+	///
+	/// This is the VESA mode with a 36 MHz pixel clock, rather than the more
+	/// common 60 Hz mode, which needs 40 MHz.
+	pub const fn make_800x600(cycles_per_pixel: u32) -> TimingBuffer {
+		TimingBuffer {
+			visible_line: ScanlineTimingBuffer::new_v_visible(
+				SyncPolarity::Positive,
+				SyncPolarity::Positive,
+				(24, 72, 128, 800),
+				cycles_per_pixel,
+			),
+			vblank_porch_buffer: ScanlineTimingBuffer::new_v_porch(
+				SyncPolarity::Positive,
+				SyncPolarity::Positive,
+				(24, 72, 128, 800),
+				cycles_per_pixel,
+			),
+			vblank_sync_buffer: ScanlineTimingBuffer::new_v_pulse(
+				SyncPolarity::Positive,
+				SyncPolarity::Positive,
+				(24, 72, 128, 800),
+				cycles_per_pixel,
+			),
+			visible_lines_ends_at: 599,
+			front_porch_end_at: 599 + 1,
+			sync_pulse_ends_at: 599 + 1 + 2,
+			back_porch_ends_at: 599 + 1 + 2 + 22,
+			pixel_clock_hz: SVGA_PIXEL_CLOCK_HZ,
+			cycles_per_pixel,
+			pixel_pairs: 800 / 2,
+		}
+	}
+
+	/// Make a timing buffer from the OS's timings, checking they are
+	/// something we can generate at the given system clock.
+	fn from_mode_line(
+		mode_line: &ModeLine,
+		sys_clock_hz: u32,
+	) -> Result<TimingBuffer, TimingError> {
+		let (_divider, cycles_per_pixel) =
+			pio_clocking(sys_clock_hz, mode_line.pixel_clock_hz).ok_or(TimingError::BadClock)?;
+		let timings = (
+			u32::from(mode_line.h_front_porch),
+			u32::from(mode_line.h_sync),
+			u32::from(mode_line.h_back_porch),
+			u32::from(mode_line.h_visible),
+		);
+		// The visible portion gets the five clocks the back porch loses
+		let periods = [
+			timings.0 * cycles_per_pixel,
+			timings.1 * cycles_per_pixel,
+			timings.2 * cycles_per_pixel,
+			(timings.3 * cycles_per_pixel) + 5,
+		];
+		let periods_ok = periods
+			.iter()
+			.all(|period| (MIN_TIMING_PERIOD..=MAX_TIMING_PERIOD).contains(period));
+		if !periods_ok
+			|| (mode_line.h_visible % 8) != 0
+			|| usize::from(mode_line.h_visible) > MAX_NUM_PIXELS_PER_LINE
+		{
+			return Err(TimingError::BadHorizontal);
 		}
+		if mode_line.v_visible == 0 || mode_line.v_sync == 0 {
+			return Err(TimingError::BadVertical);
+		}
+		let visible_lines_ends_at = mode_line.v_visible - 1;
+		let front_porch_end_at = visible_lines_ends_at
+			.checked_add(mode_line.v_front_porch)
+			.ok_or(TimingError::BadVertical)?;
+		let sync_pulse_ends_at = front_porch_end_at
+			.checked_add(mode_line.v_sync)
+			.ok_or(TimingError::BadVertical)?;
+		let back_porch_ends_at = sync_pulse_ends_at
+			.checked_add(mode_line.v_back_porch)
+			// `get_num_timing_lines` adds one to this
+			.filter(|&line| line != u16::MAX)
+			.ok_or(TimingError::BadVertical)?;
+		let hsync_positive = (mode_line.flags & MODE_LINE_HSYNC_POSITIVE) != 0;
+		let hsync = || SyncPolarity::from_flag(hsync_positive);
+		let vsync = || SyncPolarity::from_flag((mode_line.flags & MODE_LINE_VSYNC_POSITIVE) != 0);
+		// Composite sync is H-Sync exclusive-or V-Sync, so during the V-Sync
+		// pulse we just flip the H-Sync polarity.
+		let csync = (mode_line.flags & MODE_LINE_CSYNC) != 0;
+		let pulse_hsync = SyncPolarity::from_flag(hsync_positive != csync);
+		Ok(TimingBuffer {
+			visible_line: ScanlineTimingBuffer::new_v_visible(
+				hsync(),
+				vsync(),
+				timings,
+				cycles_per_pixel,
+			),
+			vblank_porch_buffer: ScanlineTimingBuffer::new_v_porch(
+				hsync(),
+				vsync(),
+				timings,
+				cycles_per_pixel,
+			),
+			vblank_sync_buffer: ScanlineTimingBuffer::new_v_pulse(
+				pulse_hsync,
+				vsync(),
+				timings,
+				cycles_per_pixel,
+			),
+			visible_lines_ends_at,
+			front_porch_end_at,
+			sync_pulse_ends_at,
+			back_porch_ends_at,
+			pixel_clock_hz: mode_line.pixel_clock_hz,
+			cycles_per_pixel,
+			pixel_pairs: mode_line.h_visible / 2,
+		})
+	}
+
+	/// How many system clocks each scan-line takes, at the given system
+	/// clock.
+	///
+	/// This is how long Core 1 has to draw a scan-line.
+	const fn line_clocks(&self, sys_clock_hz: u32) -> u32 {
+		let mut pio_clocks = 0;
+		let mut idx = 0;
+		while idx < self.visible_line.data.len() {
+			// See `ScanlineTimingBuffer::make_timing`
+			pio_clocks += ((self.visible_line.data[idx] >> 2) & 0x3FFF) + 6;
+			idx += 1;
+		}
+		pio_clocks * self.pio_divider(sys_clock_hz) as u32
+	}
+
+	/// The PIO clock divider these timings need, at the given system clock.
+	///
+	/// `timing_for_mode` has already checked this is a whole number.
+	const fn pio_divider(&self, sys_clock_hz: u32) -> u16 {
+		(sys_clock_hz / (self.pixel_clock_hz * self.cycles_per_pixel)) as u16
 	}
 }
 
 impl RGBColour {
+	/// Make a colour from 8-bit red, green and blue values. Only the top four
+	/// bits of each are used.
 	pub const fn from_24bit(red: u8, green: u8, blue: u8) -> RGBColour {
-		let red: u16 = (red as u16) & 0x00F;
-		let green: u16 = (green as u16) & 0x00F;
-		let blue: u16 = (blue as u16) & 0x00F;
-		RGBColour((blue << 12) | (green << 4) | red)
+		let red: u16 = (red as u16) >> 4;
+		let green: u16 = (green as u16) >> 4;
+		let blue: u16 = (blue as u16) >> 4;
+		RGBColour((blue << 8) | (green << 4) | red)
+	}
+}
+
+/// Build the palette that `VIDEO_PALETTE` starts with.
+const fn make_default_palette() -> [RGBColour; 256] {
+	/// The 16 standard VGA text colours, as 24-bit RGB
+	const TEXT_COLOURS: [(u8, u8, u8); 16] = [
+		(0x00, 0x00, 0x00),
+		(0x00, 0x00, 0xAA),
+		(0x00, 0xAA, 0x00),
+		(0x00, 0xAA, 0xAA),
+		(0xAA, 0x00, 0x00),
+		(0xAA, 0x00, 0xAA),
+		(0xAA, 0x55, 0x00),
+		(0xAA, 0xAA, 0xAA),
+		(0x55, 0x55, 0x55),
+		(0x55, 0x55, 0xFF),
+		(0x55, 0xFF, 0x55),
+		(0x55, 0xFF, 0xFF),
+		(0xFF, 0x55, 0x55),
+		(0xFF, 0x55, 0xFF),
+		(0xFF, 0xFF, 0x55),
+		(0xFF, 0xFF, 0xFF),
+	];
+	/// The levels used for each channel of the colour cube
+	const CUBE_LEVELS: [u8; 6] = [0x00, 0x33, 0x66, 0x99, 0xCC, 0xFF];
+
+	let mut palette = [colours::BLACK; 256];
+	let mut idx = 0;
+	while idx < 16 {
+		let (red, green, blue) = TEXT_COLOURS[idx];
+		palette[idx] = RGBColour::from_24bit(red, green, blue);
+		idx += 1;
+	}
+	while idx < 232 {
+		let cube_idx = idx - 16;
+		palette[idx] = RGBColour::from_24bit(
+			CUBE_LEVELS[cube_idx / 36],
+			CUBE_LEVELS[(cube_idx / 6) % 6],
+			CUBE_LEVELS[cube_idx % 6],
+		);
+		idx += 1;
+	}
+	while idx < 256 {
+		let level = (8 + ((idx - 232) * 10)) as u8;
+		palette[idx] = RGBColour::from_24bit(level, level, level);
+		idx += 1;
 	}
+	palette
 }
 
 impl RGBPair {
@@ -1394,6 +4484,36 @@ impl RGBPair {
 	}
 }
 
+impl Attr {
+	/// Make an attribute from a VGA-style attribute byte.
+	///
+	/// The bottom four bits are the foreground colour, the next three bits
+	/// are the background colour, and the top bit is blink.
+	pub const fn from_u8(value: u8) -> Attr {
+		Attr(value)
+	}
+
+	/// Get the VGA-style attribute byte.
+	pub const fn as_u8(self) -> u8 {
+		self.0
+	}
+
+	/// Get the foreground colour (0 to 15, in VGA order).
+	pub const fn fg(self) -> u8 {
+		self.0 & 0x0F
+	}
+
+	/// Get the background colour (0 to 7, in VGA order).
+	pub const fn bg(self) -> u8 {
+		(self.0 >> 4) & 0x07
+	}
+
+	/// Is the blink (or bright background) bit set?
+	pub const fn blink(self) -> bool {
+		(self.0 & ATTR_BLINK) != 0
+	}
+}
+
 impl GlyphAttr {
 	/// Make a new glyph/attribute pair.
 	pub const fn new(glyph: Glyph, attr: Attr) -> GlyphAttr {
@@ -0,0 +1,320 @@
+//! # Sprites for the Neotron Pico video output
+//!
+//! Core 1 can draw up to `MAX_SPRITES` small images over the top of whatever
+//! the current video mode shows - text or bitmap. Each is `SPRITE_SIZE`
+//! pixels square, in `RGBColour` format, and any pixel that matches the
+//! transparent colour is left alone. Later sprites are drawn over earlier
+//! ones.
+//!
+//! We don't have the RAM to keep the images ourselves, so (like a replacement
+//! font) the OS gives us a pointer to each image, and must leave it where it
+//! is while the sprite uses it.
+//!
+//! Sprite positions are in the pixels of the current mode, so in a mode with
+//! doubled pixels the sprites are doubled too. Positions can be negative, or
+//! past the edge of the screen, to slide a sprite off it.
+//!
+//! On top of the sprites, we can draw a mouse pointer. The HID code moves it
+//! whenever the PS/2 mouse moves, so every OS program gets a pointer for
+//! free, once the OS turns it on.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use super::{LineBuffer, RGBColour, NUM_PIXEL_PAIRS_PER_LINE};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU32, Ordering};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Everything we know about one sprite.
+///
+/// Written by Core 0, and read by `RenderEngine` running on Core 1.
+struct Sprite {
+	/// Is it on screen?
+	enabled: AtomicBool,
+	/// Where its top-left corner is, as `(y << 16) | x`, each as an `i16`
+	position: AtomicU32,
+	/// `SPRITE_SIZE * SPRITE_SIZE` pixels, a row at a time, or null
+	image: AtomicPtr<RGBColour>,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How many sprites we can draw.
+pub const MAX_SPRITES: usize = 8;
+
+/// The width and height of every sprite, in pixels.
+pub const SPRITE_SIZE: usize = 16;
+
+/// A sprite that is off, with no image.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SPRITE: Sprite = Sprite {
+	enabled: AtomicBool::new(false),
+	position: AtomicU32::new(0),
+	image: AtomicPtr::new(core::ptr::null_mut()),
+};
+
+/// All our sprites.
+static SPRITES: [Sprite; MAX_SPRITES] = [EMPTY_SPRITE; MAX_SPRITES];
+
+/// Sprite pixels of this colour aren't drawn. Magenta, by default.
+static TRANSPARENT: AtomicU16 = AtomicU16::new(0x0F0F);
+
+/// Set if any sprite is on, so Core 1 can skip them all quickly.
+static ANY_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Is the mouse pointer on screen?
+static POINTER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Where the tip of the mouse pointer is, as `(y << 16) | x`.
+static POINTER_POSITION: AtomicU32 = AtomicU32::new(0);
+
+/// The pixels of the mouse pointer which are drawn in black, a row at a
+/// time with the left-most pixel in the top bit.
+const POINTER_OUTLINE: [u16; SPRITE_SIZE] = [
+	0b1000_0000_0000_0000,
+	0b1100_0000_0000_0000,
+	0b1010_0000_0000_0000,
+	0b1001_0000_0000_0000,
+	0b1000_1000_0000_0000,
+	0b1000_0100_0000_0000,
+	0b1000_0010_0000_0000,
+	0b1000_0001_0000_0000,
+	0b1000_0000_1000_0000,
+	0b1000_0111_1100_0000,
+	0b1001_0100_0000_0000,
+	0b1010_1010_0000_0000,
+	0b1100_1010_0000_0000,
+	0b1000_0101_0000_0000,
+	0b0000_0101_0000_0000,
+	0b0000_0010_0000_0000,
+];
+
+/// The pixels of the mouse pointer which are drawn in white.
+const POINTER_FILL: [u16; SPRITE_SIZE] = [
+	0b0000_0000_0000_0000,
+	0b0000_0000_0000_0000,
+	0b0100_0000_0000_0000,
+	0b0110_0000_0000_0000,
+	0b0111_0000_0000_0000,
+	0b0111_1000_0000_0000,
+	0b0111_1100_0000_0000,
+	0b0111_1110_0000_0000,
+	0b0111_1111_0000_0000,
+	0b0111_1000_0000_0000,
+	0b0110_1000_0000_0000,
+	0b0100_0100_0000_0000,
+	0b0000_0100_0000_0000,
+	0b0000_0010_0000_0000,
+	0b0000_0010_0000_0000,
+	0b0000_0000_0000_0000,
+];
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Give a sprite its image.
+///
+/// Null takes the image away, which stops the sprite being drawn. Returns
+/// false if there is no such sprite.
+///
+/// # Safety
+///
+/// `image` must point to `SPRITE_SIZE * SPRITE_SIZE` colours, which must
+/// not move until the sprite is given another image. The OS may change the
+/// colours whenever it likes.
+pub unsafe fn set_image(index: u8, image: *const RGBColour) -> bool {
+	match SPRITES.get(usize::from(index)) {
+		Some(sprite) => {
+			sprite
+				.image
+				.store(image as *mut RGBColour, Ordering::Relaxed);
+			super::mark_dirty();
+			true
+		}
+		None => false,
+	}
+}
+
+/// Move a sprite, so its top-left corner is at the given pixel.
+///
+/// Returns false if there is no such sprite.
+pub fn set_position(index: u8, x: i16, y: i16) -> bool {
+	match SPRITES.get(usize::from(index)) {
+		Some(sprite) => {
+			let position = (u32::from(y as u16) << 16) | u32::from(x as u16);
+			sprite.position.store(position, Ordering::Relaxed);
+			super::mark_dirty();
+			true
+		}
+		None => false,
+	}
+}
+
+/// Turn a sprite on or off.
+///
+/// Returns false if there is no such sprite.
+pub fn set_enabled(index: u8, enabled: bool) -> bool {
+	match SPRITES.get(usize::from(index)) {
+		Some(sprite) => {
+			sprite.enabled.store(enabled, Ordering::Relaxed);
+			let any = SPRITES.iter().any(|s| s.enabled.load(Ordering::Relaxed));
+			ANY_ENABLED.store(any, Ordering::Relaxed);
+			super::mark_dirty();
+			true
+		}
+		None => false,
+	}
+}
+
+/// Choose the colour that isn't drawn, in every sprite.
+pub fn set_transparent(colour: RGBColour) {
+	TRANSPARENT.store(colour.0, Ordering::Relaxed);
+	super::mark_dirty();
+}
+
+/// Show or hide the mouse pointer.
+pub fn set_pointer_enabled(enabled: bool) {
+	POINTER_ENABLED.store(enabled, Ordering::Relaxed);
+	super::mark_dirty();
+}
+
+/// Put the tip of the mouse pointer at the given pixel, keeping it on the
+/// screen.
+pub fn set_pointer_position(x: i16, y: i16) {
+	let (width, height) = super::bitmap_dimensions(super::get_video_mode());
+	let x = x.clamp(0, width as i16 - 1) as u16;
+	let y = y.clamp(0, height as i16 - 1) as u16;
+	POINTER_POSITION.store((u32::from(y) << 16) | u32::from(x), Ordering::Relaxed);
+	if POINTER_ENABLED.load(Ordering::Relaxed) {
+		super::mark_dirty();
+	}
+}
+
+/// Find out where the tip of the mouse pointer is, as `(x, y)`.
+pub fn pointer_position() -> (i16, i16) {
+	let position = POINTER_POSITION.load(Ordering::Relaxed);
+	((position & 0xFFFF) as i16, (position >> 16) as i16)
+}
+
+/// Move the mouse pointer by the given number of pixels, keeping it on the
+/// screen.
+pub fn move_pointer(dx: i16, dy: i16) {
+	let (x, y) = pointer_position();
+	set_pointer_position(x.saturating_add(dx), y.saturating_add(dy));
+}
+
+/// Draw any sprites (and the mouse pointer) on this (mode) scan-line over
+/// the top of the pixels already in the buffer.
+///
+/// If `horiz_2x` is set, each sprite pixel fills a whole pixel pair.
+pub(super) fn render(line: u16, horiz_2x: bool, scan_line_buffer: &mut LineBuffer) {
+	if ANY_ENABLED.load(Ordering::Relaxed) {
+		render_sprites(line, horiz_2x, scan_line_buffer);
+	}
+	if POINTER_ENABLED.load(Ordering::Relaxed) {
+		render_pointer(line, horiz_2x, scan_line_buffer);
+	}
+}
+
+/// Draw the enabled sprites on this scan-line.
+fn render_sprites(line: u16, horiz_2x: bool, scan_line_buffer: &mut LineBuffer) {
+	let transparent = TRANSPARENT.load(Ordering::Relaxed);
+	for sprite in SPRITES.iter() {
+		let image = sprite.image.load(Ordering::Relaxed);
+		if !sprite.enabled.load(Ordering::Relaxed) || image.is_null() {
+			continue;
+		}
+		let position = sprite.position.load(Ordering::Relaxed);
+		let x = i32::from(position as u16 as i16);
+		let y = i32::from((position >> 16) as u16 as i16);
+		let row = i32::from(line) - y;
+		if row < 0 || row >= SPRITE_SIZE as i32 {
+			continue;
+		}
+		// Note (safety): `set_image` made the OS promise the image is big
+		// enough, and `row` is within it.
+		let pixels = unsafe {
+			core::slice::from_raw_parts(image.add(row as usize * SPRITE_SIZE), SPRITE_SIZE)
+		};
+		for (idx, pixel) in pixels.iter().enumerate() {
+			if pixel.0 != transparent {
+				put_pixel(x + idx as i32, *pixel, horiz_2x, scan_line_buffer);
+			}
+		}
+	}
+}
+
+/// Draw the mouse pointer, if it is on this scan-line.
+fn render_pointer(line: u16, horiz_2x: bool, scan_line_buffer: &mut LineBuffer) {
+	let (x, y) = pointer_position();
+	let row = i32::from(line) - i32::from(y);
+	if row < 0 || row >= SPRITE_SIZE as i32 {
+		return;
+	}
+	let outline = POINTER_OUTLINE[row as usize];
+	let fill = POINTER_FILL[row as usize];
+	for idx in 0..SPRITE_SIZE {
+		let bit = 0x8000 >> idx;
+		let pixel_x = i32::from(x) + idx as i32;
+		if (outline & bit) != 0 {
+			put_pixel(pixel_x, super::colours::BLACK, horiz_2x, scan_line_buffer);
+		} else if (fill & bit) != 0 {
+			put_pixel(pixel_x, super::colours::WHITE, horiz_2x, scan_line_buffer);
+		}
+	}
+}
+
+/// Set one pixel on the scan-line, if it is on the screen.
+fn put_pixel(x: i32, colour: RGBColour, horiz_2x: bool, scan_line_buffer: &mut LineBuffer) {
+	let pixel_pairs = NUM_PIXEL_PAIRS_PER_LINE.load(Ordering::Relaxed) as i32;
+	let width = if horiz_2x {
+		pixel_pairs
+	} else {
+		pixel_pairs * 2
+	};
+	if x < 0 || x >= width {
+		return;
+	}
+	let colour = u32::from(colour.0);
+	if horiz_2x {
+		scan_line_buffer.pixels[x as usize].0 = colour | (colour << 16);
+	} else {
+		let pair = &mut scan_line_buffer.pixels[x as usize / 2];
+		// The first pixel of each pair is in the bottom half
+		pair.0 = if (x & 1) == 0 {
+			(pair.0 & 0xFFFF_0000) | colour
+		} else {
+			(pair.0 & 0x0000_FFFF) | (colour << 16)
+		};
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,286 @@
+//! # Watchdog and reset reasons for the Neotron Pico
+//!
+//! The RP2040's watchdog is off until the OS first calls `watchdog_feed`,
+//! so an OS that has never heard of it isn't reset. After that, the BIOS
+//! feeds it whenever the OS polls for input, calls `system_poll` or
+//! `power_idle`, or calls `watchdog_feed` again - so an OS that locks up
+//! and stops calling those gets reset after `TIMEOUT_MS`. Just reading the
+//! time, or waiting for the video or a serial port, doesn't count, as an OS
+//! stuck waiting for something that never happens has locked up too.
+//!
+//! The OS can also ask us to reboot, or to reset into the boot ROM's USB
+//! bootloader, so a new BIOS can be copied on without opening the case to get
+//! at the BOOTSEL button.
+//!
+//! At start-up we work out why we were reset, and we count the crashes
+//! (watchdog resets, panics and HardFaults) since the last power-on or RUN
+//! pin reset, in `WATCHDOG.SCRATCH1`. That survives everything except a
+//! power-on or RUN pin reset. When we reset ourselves, we go through
+//! `reboot` and change the marker in the top half, so at start-up a forced
+//! watchdog reset with that marker is one of ours. We put the normal marker
+//! back straight away, so a later debugger reset isn't mistaken for one.
+//!
+//! ## Scratch registers
+//!
+//! The watchdog's eight scratch registers are the only place we can leave
+//! a note for the next boot. This is who uses which:
+//!
+//! | Register | Used by                                                |
+//! |:---------|:-------------------------------------------------------|
+//! | 0        | Nobody                                                 |
+//! | 1        | This module, for the crash count and reset marker      |
+//! | 2        | `boot_error`, to count start-up retries                |
+//! | 3        | Nobody                                                 |
+//! | 4 to 7   | The boot ROM, when the watchdog reboots the chip       |
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use defmt::info;
+use rp_pico::hal::{self, pac};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// Why the chip last reset.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum ResetReason {
+	/// The power was turned on (or browned out)
+	PowerOn = 0,
+	/// Someone pressed the reset button on the RUN pin
+	RunPin = 1,
+	/// The BIOS reset itself (e.g. after a crash)
+	Soft = 2,
+	/// The watchdog wasn't fed in time
+	Watchdog = 3,
+	/// A debugger reset us
+	Debugger = 4,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// How long the OS can go without feeding the watchdog
+pub const TIMEOUT_MS: u32 = 8_000;
+
+/// The top half of `WATCHDOG.SCRATCH1`, so we can tell our crash count from
+/// whatever was in there before.
+const CRASH_COUNT_MAGIC: u32 = 0x4E57_0000;
+
+/// Replaces `CRASH_COUNT_MAGIC` in the top half of `WATCHDOG.SCRATCH1` when
+/// we reset ourselves on purpose.
+const SOFT_RESET_MAGIC: u32 = 0x4E53_0000;
+
+/// Why we last reset, as a `ResetReason`.
+static RESET_REASON: AtomicU8 = AtomicU8::new(ResetReason::PowerOn as u8);
+
+/// The boot ROM blinks the Pico's on-board LED (on GPIO25) while it is in the
+/// USB bootloader.
+const BOOTSEL_ACTIVITY_PIN_MASK: u32 = 1 << 25;
+
+/// What we load the watchdog counter with each time it is fed. The counter
+/// goes down by two for every microsecond tick (erratum RP2040-E1).
+static LOAD_VALUE: AtomicU32 = AtomicU32::new(0);
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Work out why we reset, update the crash count, and stop the watchdog.
+///
+/// A soft reset doesn't stop the watchdog, so it could still be running from
+/// before. Call this early, before anything else resets the chip.
+pub fn init() {
+	// Note (safety): The HAL only uses the watchdog for the tick generator,
+	// which we don't touch, and only we use `SCRATCH1`.
+	let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+	watchdog.ctrl.modify(|_r, w| w.enable().clear_bit());
+	let chip_reset = unsafe { &*pac::VREG_AND_CHIP_RESET::ptr() }
+		.chip_reset
+		.read();
+	let scratch = watchdog.scratch1.read().bits();
+	let soft_marked = (scratch & 0xFFFF_0000) == SOFT_RESET_MAGIC;
+	let marked = soft_marked || (scratch & 0xFFFF_0000) == CRASH_COUNT_MAGIC;
+	let watchdog_reason = watchdog.reason.read();
+	let reason = if watchdog_reason.timer().bit_is_set() {
+		ResetReason::Watchdog
+	} else if soft_marked && watchdog_reason.force().bit_is_set() {
+		ResetReason::Soft
+	} else if chip_reset.had_run().bit_is_set() {
+		ResetReason::RunPin
+	} else if chip_reset.had_psm_restart().bit_is_set() {
+		ResetReason::Debugger
+	} else {
+		ResetReason::PowerOn
+	};
+	RESET_REASON.store(reason as u8, Ordering::Relaxed);
+
+	let mut count = if marked { scratch & 0xFFFF } else { 0 };
+	if reason == ResetReason::Watchdog {
+		count = (count + 1).min(0xFFFF);
+	}
+	// This also clears any soft reset marker
+	watchdog
+		.scratch1
+		.write(|w| unsafe { w.bits(CRASH_COUNT_MAGIC | count) });
+	info!("Reset reason: {}, {} crashes", reason, count);
+}
+
+/// Why did the chip last reset?
+pub fn reset_reason() -> ResetReason {
+	match RESET_REASON.load(Ordering::Relaxed) {
+		1 => ResetReason::RunPin,
+		2 => ResetReason::Soft,
+		3 => ResetReason::Watchdog,
+		4 => ResetReason::Debugger,
+		_ => ResetReason::PowerOn,
+	}
+}
+
+/// How many times have we crashed since the power came on (or the reset
+/// button was pressed)?
+pub fn crash_count() -> u16 {
+	// Note (safety): Only we use `SCRATCH1`, and we only read it here.
+	let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+	(watchdog.scratch1.read().bits() & 0xFFFF) as u16
+}
+
+/// Count a crash, just before we reset.
+pub fn record_crash() {
+	// Note (safety): Only we use `SCRATCH1`. A crash handler is the last
+	// thing to run before a reset.
+	let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+	let count = (u32::from(crash_count()) + 1).min(0xFFFF);
+	watchdog
+		.scratch1
+		.write(|w| unsafe { w.bits(CRASH_COUNT_MAGIC | count) });
+}
+
+/// Start the watchdog. If it isn't fed within `timeout_ms`, the whole chip
+/// (apart from the oscillators) is reset.
+///
+/// The watchdog counter is 24 bits, so the longest timeout is about 8.3
+/// seconds.
+pub fn start(timeout_ms: u32) {
+	// Note (safety): The HAL only used the watchdog to start the tick
+	// generator, which we don't touch, and nothing else uses the PSM once
+	// the cores are running.
+	let psm = unsafe { &*pac::PSM::ptr() };
+	let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+	psm.wdsel
+		.write(|w| unsafe { w.bits(0x0001_FFFF).xosc().clear_bit().rosc().clear_bit() });
+	LOAD_VALUE.store(
+		timeout_ms.saturating_mul(2_000).min(0x00FF_FFFF),
+		Ordering::Relaxed,
+	);
+	feed();
+	watchdog.ctrl.modify(|_r, w| {
+		w.pause_dbg0()
+			.set_bit()
+			.pause_dbg1()
+			.set_bit()
+			.pause_jtag()
+			.set_bit()
+			.enable()
+			.set_bit()
+	});
+}
+
+/// Has the watchdog been started?
+pub fn is_started() -> bool {
+	LOAD_VALUE.load(Ordering::Relaxed) != 0
+}
+
+/// Put the watchdog's count back to the start.
+///
+/// Does nothing if the watchdog hasn't been started.
+pub fn feed() {
+	let load = LOAD_VALUE.load(Ordering::Relaxed);
+	if load != 0 {
+		// Note (safety): Writing LOAD has no effect other than resetting
+		// the count.
+		let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+		watchdog.load.write(|w| unsafe { w.load().bits(load) });
+	}
+}
+
+/// Reset the whole chip (apart from the oscillators), straight away.
+///
+/// We use the watchdog rather than `SCB::sys_reset`, which would only reset
+/// the cores and leave the peripherals running. This counts as a soft
+/// reset, not a crash (so call `record_crash` first if it was one).
+pub fn reboot() -> ! {
+	info!("Rebooting");
+	cortex_m::interrupt::disable();
+	// Note (safety): We're about to reset, so nothing else needs the PSM or
+	// the watchdog, and only we use `SCRATCH1`.
+	let psm = unsafe { &*pac::PSM::ptr() };
+	let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+	let count = u32::from(crash_count());
+	watchdog
+		.scratch1
+		.write(|w| unsafe { w.bits(SOFT_RESET_MAGIC | count) });
+	psm.wdsel
+		.write(|w| unsafe { w.bits(0x0001_FFFF).xosc().clear_bit().rosc().clear_bit() });
+	watchdog.ctrl.modify(|_r, w| w.trigger().set_bit());
+	loop {
+		cortex_m::asm::nop();
+	}
+}
+
+/// Reset into the boot ROM's USB bootloader, as if BOOTSEL had been held
+/// down.
+///
+/// The Pico then appears on USB as a mass storage device (and as a PICOBOOT
+/// device for `picotool`), ready for a new BIOS to be copied on as a UF2
+/// file. This doesn't count as a crash.
+pub fn reset_to_usb_boot() -> ! {
+	info!("Resetting to USB bootloader");
+	cortex_m::interrupt::disable();
+	hal::rom_data::reset_to_usb_boot(BOOTSEL_ACTIVITY_PIN_MASK, 0);
+	// The boot ROM uses the watchdog to reset us, which takes a moment
+	loop {
+		cortex_m::asm::nop();
+	}
+}
+
+impl ResetReason {
+	/// A human readable description of the reset reason.
+	pub fn description(self) -> &'static str {
+		match self {
+			ResetReason::PowerOn => "power-on",
+			ResetReason::RunPin => "reset button",
+			ResetReason::Soft => "soft reset",
+			ResetReason::Watchdog => "watchdog",
+			ResetReason::Debugger => "debugger",
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------
@@ -0,0 +1,324 @@
+//! # YMODEM receiver for the Neotron Pico
+//!
+//! So you can try out a new OS without a debug probe, the BIOS can receive
+//! an OS image over the serial port, with YMODEM (or XMODEM-CRC, which is
+//! the same without the file header block). Most terminal programs can send
+//! YMODEM, as can `sz --ymodem`.
+//!
+//! We only receive one file. It goes into a RAM buffer (the OS RAM - nothing
+//! else is using it yet), and the caller decides what to do with it. We
+//! support 128 and 1024 byte blocks, with a CRC-16; we don't do the older
+//! 8-bit checksum.
+
+// -----------------------------------------------------------------------------
+// Licence Statement
+// -----------------------------------------------------------------------------
+// Copyright (c) Jonathan 'theJPster' Pallant and the Neotron Developers, 2022
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+// -----------------------------------------------------------------------------
+
+// -----------------------------------------------------------------------------
+// Imports
+// -----------------------------------------------------------------------------
+
+use crate::serial;
+use defmt::{debug, info};
+
+// -----------------------------------------------------------------------------
+// Types
+// -----------------------------------------------------------------------------
+
+/// The ways in which receiving a file can fail.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// Nobody started sending within `START_TIMEOUT_MS`
+	NoSender,
+	/// The sender stopped sending part-way through
+	Timeout,
+	/// The sender cancelled the transfer
+	Cancelled,
+	/// The file doesn't fit in the buffer
+	TooBig,
+	/// Too many blocks arrived damaged, or out of order
+	BadBlocks,
+}
+
+/// What arrived when we waited for a block.
+enum Packet {
+	/// A block with the given number, whose data is now in the block buffer
+	Block { number: u8, len: usize },
+	/// The end of the file
+	EndOfFile,
+	/// The sender cancelled
+	Cancel,
+	/// Something arrived, but it was damaged
+	Damaged,
+	/// Nothing arrived in time, or the sender stopped part-way through a
+	/// block
+	TimedOut,
+}
+
+// -----------------------------------------------------------------------------
+// Static and Const Data
+// -----------------------------------------------------------------------------
+
+/// Start of a 128 byte block
+const SOH: u8 = 0x01;
+
+/// Start of a 1024 byte block
+const STX: u8 = 0x02;
+
+/// End of transmission
+const EOT: u8 = 0x04;
+
+/// Block received OK
+const ACK: u8 = 0x06;
+
+/// Block damaged - please send it again
+const NAK: u8 = 0x15;
+
+/// Cancel the transfer (sent twice)
+const CAN: u8 = 0x18;
+
+/// Asks the sender to start, with CRC-16 blocks
+const CRC_MODE: u8 = b'C';
+
+/// How long we keep asking the sender to start
+const START_TIMEOUT_MS: u32 = 60_000;
+
+/// How often we ask the sender to start
+const POLL_INTERVAL_MS: u32 = 3_000;
+
+/// How long we wait for each byte, once a transfer has started
+const BYTE_TIMEOUT_MS: u32 = 1_000;
+
+/// How many damaged or missing blocks in a row we put up with
+const MAX_RETRIES: u8 = 10;
+
+/// The most data a block can hold
+const MAX_BLOCK_LEN: usize = 1024;
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+/// Receive one file into `buffer`, returning its length.
+///
+/// If the sender gave us the file size (in the YMODEM header) we use it,
+/// otherwise the length includes the padding on the end of the last block.
+pub fn receive(buffer: &mut [u8]) -> Result<usize, Error> {
+	let mut block = [0u8; MAX_BLOCK_LEN];
+	// The block numbers on the wire wrap at 256, so we count them ourselves
+	let mut expected: u32 = 1;
+	let mut header_seen = false;
+	let mut received: usize = 0;
+	let mut file_size: Option<usize> = None;
+	let mut retries = 0;
+	let mut started = false;
+	let start = crate::ticks_ms();
+
+	flush_input();
+	serial::write_blocking(&[CRC_MODE]);
+	loop {
+		let packet = if started {
+			read_packet(&mut block)
+		} else {
+			// Keep asking until the sender starts
+			match read_byte(POLL_INTERVAL_MS) {
+				Ok(first) => {
+					started = true;
+					read_packet_after_first(first, &mut block).unwrap_or(Packet::TimedOut)
+				}
+				Err(_) if crate::ticks_ms().wrapping_sub(start) >= START_TIMEOUT_MS => {
+					return Err(Error::NoSender);
+				}
+				Err(_) => {
+					serial::write_blocking(&[CRC_MODE]);
+					continue;
+				}
+			}
+		};
+		match packet {
+			Packet::Block { number: 0, len } if !header_seen && received == 0 => {
+				// The YMODEM header: a file name, then the size in decimal. An
+				// empty name means there are no more files. Later blocks
+				// numbered 0 are just block 256, 512, and so on.
+				if block[0] == 0 {
+					serial::write_blocking(&[ACK]);
+					return Ok(received);
+				}
+				file_size = parse_file_size(&block[0..len]);
+				info!("YMODEM header: size {}", file_size);
+				if file_size.unwrap_or(0) > buffer.len() {
+					cancel();
+					return Err(Error::TooBig);
+				}
+				header_seen = true;
+				retries = 0;
+				serial::write_blocking(&[ACK, CRC_MODE]);
+			}
+			Packet::Block { number, len } if number == expected as u8 => {
+				// A block 1 with no header first means XMODEM
+				let end = received + len;
+				if end > buffer.len() {
+					cancel();
+					return Err(Error::TooBig);
+				}
+				buffer[received..end].copy_from_slice(&block[0..len]);
+				received = end;
+				expected += 1;
+				retries = 0;
+				serial::write_blocking(&[ACK]);
+			}
+			Packet::Block { number, .. } if number == (expected - 1) as u8 => {
+				// Our ACK got lost, so they sent it again
+				serial::write_blocking(&[ACK]);
+			}
+			Packet::EndOfFile => {
+				// YMODEM wants the first EOT NAKed, to be sure it really was
+				// one. We don't bother, as we already check every block.
+				serial::write_blocking(&[ACK]);
+				let len = match file_size {
+					Some(size) => size.min(received),
+					None => received,
+				};
+				debug!("Received {} bytes", len);
+				// A YMODEM sender now sends an empty header, to say the batch
+				// is over. An XMODEM sender has already finished.
+				if header_seen && file_size.is_some() {
+					serial::write_blocking(&[CRC_MODE]);
+					let _ = read_packet(&mut block);
+					serial::write_blocking(&[ACK]);
+				}
+				return Ok(len);
+			}
+			Packet::Cancel => return Err(Error::Cancelled),
+			other => {
+				retries += 1;
+				if retries >= MAX_RETRIES {
+					cancel();
+					return Err(match other {
+						Packet::TimedOut => Error::Timeout,
+						_ => Error::BadBlocks,
+					});
+				}
+				flush_input();
+				serial::write_blocking(&[NAK]);
+			}
+		}
+	}
+}
+
+/// Wait for the next packet, and read it.
+fn read_packet(block: &mut [u8; MAX_BLOCK_LEN]) -> Packet {
+	read_byte(BYTE_TIMEOUT_MS)
+		.and_then(|first| read_packet_after_first(first, block))
+		.unwrap_or(Packet::TimedOut)
+}
+
+/// Read the rest of a packet, given the byte it started with.
+fn read_packet_after_first(first: u8, block: &mut [u8; MAX_BLOCK_LEN]) -> Result<Packet, Error> {
+	let len = match first {
+		SOH => 128,
+		STX => MAX_BLOCK_LEN,
+		EOT => return Ok(Packet::EndOfFile),
+		CAN => return Ok(Packet::Cancel),
+		_ => return Ok(Packet::Damaged),
+	};
+	let number = read_byte(BYTE_TIMEOUT_MS)?;
+	let inverse = read_byte(BYTE_TIMEOUT_MS)?;
+	for slot in block[0..len].iter_mut() {
+		*slot = read_byte(BYTE_TIMEOUT_MS)?;
+	}
+	let crc = u16::from_be_bytes([read_byte(BYTE_TIMEOUT_MS)?, read_byte(BYTE_TIMEOUT_MS)?]);
+	if number != !inverse || crc != crc16(&block[0..len]) {
+		return Ok(Packet::Damaged);
+	}
+	Ok(Packet::Block { number, len })
+}
+
+/// Read one byte from the serial port, waiting up to `timeout_ms`.
+fn read_byte(timeout_ms: u32) -> Result<u8, Error> {
+	let mut byte = [0u8; 1];
+	if serial::read(&mut byte, Some(timeout_ms)) == 0 {
+		return Err(Error::Timeout);
+	}
+	Ok(byte[0])
+}
+
+/// Throw away anything waiting in the serial port.
+fn flush_input() {
+	let mut byte = [0u8; 1];
+	while serial::read(&mut byte, Some(0)) != 0 {}
+}
+
+/// Tell the sender we're giving up.
+fn cancel() {
+	serial::write_blocking(&[CAN, CAN]);
+}
+
+/// Get the file size from a YMODEM header block: a null-terminated file
+/// name, then the size in decimal (followed by a space or a null).
+fn parse_file_size(header: &[u8]) -> Option<usize> {
+	let name_end = header.iter().position(|b| *b == 0)?;
+	let rest = &header[name_end + 1..];
+	let digits = rest.iter().take_while(|b| b.is_ascii_digit());
+	let mut size: usize = 0;
+	let mut any = false;
+	for digit in digits {
+		size = size
+			.checked_mul(10)?
+			.checked_add(usize::from(digit - b'0'))?;
+		any = true;
+	}
+	if any {
+		Some(size)
+	} else {
+		None
+	}
+}
+
+/// The CRC-16/XMODEM of some bytes (polynomial 0x1021, starting at zero).
+fn crc16(data: &[u8]) -> u16 {
+	let mut crc: u16 = 0;
+	for byte in data {
+		crc ^= u16::from(*byte) << 8;
+		for _bit in 0..8 {
+			crc = if (crc & 0x8000) != 0 {
+				(crc << 1) ^ 0x1021
+			} else {
+				crc << 1
+			};
+		}
+	}
+	crc
+}
+
+impl Error {
+	/// A human readable description of the error.
+	pub fn description(self) -> &'static str {
+		match self {
+			Error::NoSender => "nothing was sent",
+			Error::Timeout => "the sender stopped",
+			Error::Cancelled => "cancelled",
+			Error::TooBig => "the image is too big",
+			Error::BadBlocks => "too many errors",
+		}
+	}
+}
+
+// -----------------------------------------------------------------------------
+// End of file
+// -----------------------------------------------------------------------------